@@ -0,0 +1,79 @@
+//! Setup shared by the benchmarks. Roots are created in the system temp dir, or in `SBDB_BENCH_DIR` when
+//! it is set, which allows comparing filesystems with and without reflink support.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use sbdb::{Client, puuid};
+
+pub struct BenchRoot {
+    pub client: Client,
+    root: PathBuf,
+}
+
+impl BenchRoot {
+    pub fn new(name: &str) -> Self {
+        let base = std::env::var_os("SBDB_BENCH_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        let root = base.join(format!("sbdb-bench-{}-{}", name, puuid()));
+        BenchRoot {
+            client: Client::new(&root).unwrap(),
+            root,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Drop for BenchRoot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Label for the filesystem the roots live on, included in benchmark names.
+pub fn fs_label() -> &'static str {
+    if std::env::var_os("SBDB_BENCH_DIR").is_some() {
+        "bench_dir"
+    } else {
+        "temp_dir"
+    }
+}
+
+pub fn write_file(path: &Path, len: usize) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(path, vec![0x5a; len]).unwrap();
+}
+
+/// Fills `dir` with `files` small files spread over subdirectories of at most 100 entries.
+pub fn write_tree(dir: &Path, files: usize) {
+    for i in 0..files {
+        write_file(
+            &dir.join(format!("{}", i / 100)).join(format!("{}.txt", i)),
+            64,
+        );
+    }
+}
+
+/// Removes the lock and queue files below `dir`, so that the next acquisition has to create them.
+pub fn remove_lock_files(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type().unwrap().is_dir() {
+            remove_lock_files(&entry.path());
+        } else if name.ends_with(".lock.sbdb") || name.ends_with(".queue.sbdb") {
+            fs::remove_file(entry.path()).unwrap();
+        }
+    }
+}
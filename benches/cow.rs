@@ -0,0 +1,89 @@
+//! Copy-on-write copies and their commits. Whether files are reflinked depends on the filesystem the
+//! roots are created on, see `SBDB_BENCH_DIR`. The 400MB case is skipped with the `skip-large-benches`
+//! feature.
+
+mod common;
+
+use common::{BenchRoot, fs_label, write_file, write_tree};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+const KB: usize = 1024;
+const MB: usize = 1024 * KB;
+
+fn file_cow(c: &mut Criterion) {
+    let mut sizes = vec![("4KB", 4 * KB), ("4MB", 4 * MB)];
+    if !cfg!(feature = "skip-large-benches") {
+        sizes.push(("400MB", 400 * MB));
+    }
+
+    let mut group = c.benchmark_group(format!("file_cow_commit/{}", fs_label()));
+    for (label, len) in sizes {
+        let root = BenchRoot::new("file_cow");
+        write_file(&root.path().join("file.bin"), len);
+        let db = &root.client;
+        if len >= 100 * MB {
+            group.sample_size(10);
+        }
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| {
+                let gaurd = db.write_file("file.bin").unwrap();
+                gaurd.cow().unwrap().commit().unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Full rewrites, which write to an unnamed temporary file on linux unless they are larger than 1MB.
+fn write_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("write_bytes/{}", fs_label()));
+    for (label, len) in [("4KB", 4 * KB), ("2MB", 2 * MB)] {
+        let root = BenchRoot::new("write_bytes");
+        let db = &root.client;
+        let bytes = vec![0x5a; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| db.write_bytes("file.bin", &bytes).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn dir_cow(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("dir_cow_commit/{}", fs_label()));
+    group.sample_size(10);
+    for files in [100, 10_000] {
+        let root = BenchRoot::new("dir_cow");
+        write_tree(&root.path().join("plain"), files);
+        let db = &root.client;
+        db.write_dir("")
+            .unwrap()
+            .create_dir_atomic("atomic", false)
+            .unwrap();
+        {
+            let gaurd = db.write_dir("atomic").unwrap();
+            let copy = gaurd.cow_atomic().unwrap();
+            write_tree(&copy.path, files);
+            copy.commit().unwrap();
+        }
+
+        group.throughput(Throughput::Elements(files as u64));
+        group.bench_with_input(BenchmarkId::new("dir_cow", files), &files, |b, _| {
+            b.iter(|| {
+                let gaurd = db.write_dir("plain").unwrap();
+                gaurd.cow().unwrap().commit().unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("dir_cow_atomic", files), &files, |b, _| {
+            b.iter(|| {
+                let gaurd = db.write_dir("atomic").unwrap();
+                gaurd.cow_atomic().unwrap().commit().unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, file_cow, write_bytes, dir_cow);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Garbage collection of a tree littered with what interrupted operations leave behind.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{BenchRoot, write_file, write_tree};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use sbdb::puuid;
+
+/// Lock files for every entry plus a leftover copy and backup in every directory.
+fn litter(root: &BenchRoot) {
+    fn visit(db: &sbdb::Client, root: &Path, dir: &Path) {
+        for entry in fs::read_dir(root.join(dir)).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+            if name.to_string_lossy().ends_with(".sbdb") {
+                continue;
+            }
+            let rpath = dir.join(&name);
+            if entry.file_type().unwrap().is_dir() {
+                visit(db, root, &rpath);
+            } else {
+                drop(db.read_file(&rpath).unwrap());
+            }
+        }
+        let path = root.join(dir);
+        write_file(&path.join(".leftover.tmp.sbdb"), 64);
+        write_file(
+            &path
+                .join(format!(".leftover.{}.bak.sbdb", puuid()))
+                .join("file.txt"),
+            64,
+        );
+        write_file(&path.join("leftover"), 64);
+    }
+    visit(&root.client, root.path(), Path::new("data"));
+}
+
+fn gc(c: &mut Criterion) {
+    let root = BenchRoot::new("gc");
+    write_tree(&root.path().join("data"), 1000);
+    let db = &root.client;
+
+    let mut group = c.benchmark_group("gc");
+    group.sample_size(20);
+    group.bench_function("dirty_1k_files", |b| {
+        b.iter_batched(|| litter(&root), |()| db.gc(), BatchSize::PerIteration)
+    });
+    group.bench_function("clean_1k_files", |b| {
+        db.gc();
+        b.iter(|| db.gc())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, gc);
+criterion_main!(benches);
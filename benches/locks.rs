@@ -0,0 +1,100 @@
+//! Cost of acquiring guards and transactions, with lock files that already exist (warm) and with lock
+//! files that have to be created first (cold). The cached variants reuse opened lock files, see
+//! `ClientBuilder::lock_file_cache`.
+
+mod common;
+
+use std::{hint::black_box, path::PathBuf};
+
+use common::{BenchRoot, remove_lock_files, write_file};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use sbdb::Client;
+
+fn single_file(c: &mut Criterion) {
+    let root = BenchRoot::new("single_file");
+    write_file(&root.path().join("file.txt"), 16);
+    let db = &root.client;
+
+    let mut group = c.benchmark_group("single_file");
+    group.bench_function("read_warm", |b| {
+        b.iter(|| black_box(db.read_file("file.txt").unwrap()))
+    });
+    group.bench_function("write_warm", |b| {
+        b.iter(|| black_box(db.write_file("file.txt").unwrap()))
+    });
+    group.bench_function("read_cold", |b| {
+        b.iter_batched(
+            || remove_lock_files(root.path()),
+            |()| black_box(db.read_file("file.txt").unwrap()),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("write_cold", |b| {
+        b.iter_batched(
+            || remove_lock_files(root.path()),
+            |()| black_box(db.write_file("file.txt").unwrap()),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn deep_path(c: &mut Criterion) {
+    let root = BenchRoot::new("deep_path");
+    let rpath = PathBuf::from("1/2/3/4/5/6/7/file.txt");
+    write_file(&root.path().join(&rpath), 16);
+    let db = &root.client;
+
+    let mut group = c.benchmark_group("deep_path");
+    group.bench_function("read", |b| {
+        b.iter(|| black_box(db.read_file(&rpath).unwrap()))
+    });
+    group.bench_function("write", |b| {
+        b.iter(|| black_box(db.write_file(&rpath).unwrap()))
+    });
+
+    let cached = Client::builder(root.path())
+        .lock_file_cache(64)
+        .build()
+        .unwrap();
+    group.bench_function("read_cached", |b| {
+        b.iter(|| black_box(cached.read_file(&rpath).unwrap()))
+    });
+    group.bench_function("write_cached", |b| {
+        b.iter(|| black_box(cached.write_file(&rpath).unwrap()))
+    });
+    group.finish();
+}
+
+fn tx_begin(c: &mut Criterion) {
+    let root = BenchRoot::new("tx_begin");
+    // 1000 paths spread over 10 directories, half of them written
+    let rpaths = (0..1000)
+        .map(|i| PathBuf::from(format!("{}/{}.txt", i % 10, i)))
+        .collect::<Vec<_>>();
+    for rpath in &rpaths {
+        write_file(&root.path().join(rpath), 16);
+    }
+    let db = &root.client;
+
+    c.bench_function("tx_begin_1k_paths", |b| {
+        b.iter(|| {
+            let tx = rpaths
+                .iter()
+                .enumerate()
+                .fold(db.tx(), |tx, (i, rpath)| {
+                    if i % 2 == 0 {
+                        tx.read(rpath)
+                    } else {
+                        tx.write(rpath)
+                    }
+                })
+                .begin()
+                .unwrap();
+            black_box(tx)
+        })
+    });
+}
+
+criterion_group!(benches, single_file, deep_path, tx_begin);
+criterion_main!(benches);
@@ -0,0 +1,134 @@
+//! Aliases are symbolic links to other entries of the database, such as `runs/latest` pointing at the most
+//! recent run, that are switched from one target to the next atomically. They are relative, so that the
+//! database can be moved, and told apart from atomic directories by their targets, which can not be named
+//! like sbdb's own files.
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    Client, Error, Result, check_nested, check_unreserved, error::IoResultExt, is_atomic_dir_link,
+    replace_with_link,
+};
+
+impl Client {
+    /// Point the alias `alias_rpath` at the existing entry `target_rpath`, creating the alias or switching
+    /// it over from its previous target. Paths opened through the alias resolve to either target, never to
+    /// a missing entry. Fails if there is an entry other than an alias at `alias_rpath`, and with
+    /// [`Error::AliasesUnsupported`] where symbolic links can not be created, see
+    /// [`Client::atomic_dirs_supported`].
+    pub fn set_alias<A: AsRef<Path>, T: AsRef<Path>>(
+        &self,
+        alias_rpath: A,
+        target_rpath: T,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let alias_rpath = check_nested(alias_rpath.as_ref())?;
+            let target_rpath = check_nested(target_rpath.as_ref())?;
+            check_unreserved(self.root(), &target_rpath)?;
+            if alias_rpath.starts_with(&target_rpath) || target_rpath.starts_with(&alias_rpath) {
+                return Err(Error::invalid_path(
+                    &target_rpath,
+                    "alias can not point at itself or a directory containing it",
+                ));
+            }
+            let gaurd = self.write_file(&alias_rpath)?;
+            if !self.atomic_dirs_supported() {
+                return Err(Error::AliasesUnsupported { path: gaurd.path });
+            }
+            let target = self.root().join(&target_rpath);
+            let metadata = target.metadata().at("read metadata of", &target)?;
+            if let Ok(existing) = gaurd.path.symlink_metadata()
+                && (!existing.is_symlink() || is_atomic_dir_link(&gaurd.path)?)
+            {
+                return Err(Error::invalid_path(&gaurd.path, "entry is not an alias"));
+            }
+            replace_with_link(
+                &*gaurd.ctx.vfs,
+                &relative_link(&alias_rpath, &target_rpath),
+                &gaurd.path,
+                metadata.is_dir(),
+            )
+        })
+    }
+
+    /// The entry that the alias `alias_rpath` points at, relative to the root, `None` if there is no alias
+    /// there. The target may have been removed since the alias was set.
+    pub fn read_alias<P: AsRef<Path>>(&self, alias_rpath: P) -> Result<Option<PathBuf>> {
+        self.redacted(|| {
+            let alias_rpath = check_nested(alias_rpath.as_ref())?;
+            let gaurd = self.read_file(&alias_rpath)?;
+            read(&alias_rpath, &gaurd.path)
+        })
+    }
+
+    /// Remove the alias `alias_rpath`, but not its target. Returns false if there is no alias there.
+    pub fn remove_alias<P: AsRef<Path>>(&self, alias_rpath: P) -> Result<bool> {
+        self.redacted(|| {
+            let alias_rpath = check_nested(alias_rpath.as_ref())?;
+            let gaurd = self.write_file(&alias_rpath)?;
+            if read(&alias_rpath, &gaurd.path)?.is_none() {
+                return Ok(false);
+            }
+            // does not follow the link, and removes links to directories, which are directories on windows
+            gaurd
+                .ctx
+                .vfs
+                .remove_dir_all(&gaurd.path)
+                .at("remove alias", &gaurd.path)?;
+            Ok(true)
+        })
+    }
+}
+
+/// Target of the alias `alias_rpath` at `path`, `None` if it is not a symbolic link, a link of an atomic
+/// directory or one that leads out of the database.
+fn read(alias_rpath: &Path, path: &Path) -> Result<Option<PathBuf>> {
+    let link = match path.read_link() {
+        Ok(link) => link,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::InvalidInput
+            ) =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(Error::io("read link", path, e)),
+    };
+    if is_atomic_dir_link(path)? {
+        return Ok(None);
+    }
+    let mut target = alias_rpath.parent().unwrap_or(Path::new("")).to_path_buf();
+    for component in link.components() {
+        match component {
+            Component::Normal(name) => target.push(name),
+            Component::CurDir => {}
+            Component::ParentDir if target.pop() => {}
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(target).filter(|target| !target.as_os_str().is_empty()))
+}
+
+/// Path of `target_rpath` relative to the directory containing `alias_rpath`, both relative to the root.
+fn relative_link(alias_rpath: &Path, target_rpath: &Path) -> PathBuf {
+    let parent: Vec<_> = alias_rpath
+        .parent()
+        .map(|parent| parent.components().collect())
+        .unwrap_or_default();
+    let target: Vec<_> = target_rpath.components().collect();
+    let common = parent
+        .iter()
+        .zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut link = PathBuf::new();
+    for _ in common..parent.len() {
+        link.push("..");
+    }
+    link.extend(&target[common..]);
+    link
+}
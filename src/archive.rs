@@ -0,0 +1,89 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, Result, artifact, check_unreserved, commit_dir_with, copy_visible,
+    error::IoResultExt, path_hidden_with_extension, scratch::SCRATCH_DIR, unused_child,
+};
+
+/// Controls what [`Client::export_tar`] writes.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct TarOptions {
+    /// Only export the subtree at this path. Entries in the archive are named relative to it.
+    pub prefix: PathBuf,
+    /// Record modification times and permissions instead of fixed placeholder values.
+    pub preserve_metadata: bool,
+}
+
+impl Client {
+    /// Write the database, or the subtree selected by [`TarOptions::prefix`], to a tar archive. The
+    /// subtree is first copied while write locked, so writers are only blocked for the duration of the
+    /// copy (which is cheap on filesystems supporting reflinks) and the archive is a consistent
+    /// point-in-time view. Internal files are left out and atomic directories are exported as plain
+    /// directories. The maintenance lock is held for reading meanwhile, see [`Client::maintenance_read`].
+    pub fn export_tar<W: Write>(&self, writer: W, options: &TarOptions) -> Result<W> {
+        self.redacted(|| {
+            let _maintenance = self.maintenance_read()?;
+            let internal = self.inner.root.join(INTERNAL_DIR);
+            let staging = unused_child(&internal.join(SCRATCH_DIR));
+            let result = self.stage(&staging, options).and_then(|_| {
+                let mut builder = tar::Builder::new(writer);
+                builder.follow_symlinks(false);
+                if !options.preserve_metadata {
+                    builder.mode(tar::HeaderMode::Deterministic);
+                }
+                builder
+                    .append_dir_all(".", &staging)
+                    .at("archive", &staging)?;
+                builder.into_inner().at("archive", &staging)
+            });
+            if let Err(e) = fs::remove_dir_all(&staging)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                log::warn!(path:? = self.inner.redact.path(&staging), operation = "cleanup"; "failed to remove export staging dir: {}", e);
+            }
+            result
+        })
+    }
+
+    /// Replace the directory at `rpath` with the contents of a tar archive. The archive is unpacked next
+    /// to the directory and swapped in with the same strategy as [`CowDirGaurd::commit`], so either the
+    /// whole archive is imported or nothing changes.
+    pub fn import_tar<R: Read, P: AsRef<Path>>(&self, reader: R, rpath: P) -> Result<()> {
+        self.redacted(|| {
+            if rpath.as_ref().as_os_str().is_empty() {
+                return Err(Error::invalid_path(
+                    &self.inner.root,
+                    "cannot import over the root",
+                ));
+            }
+            check_unreserved(&self.inner.root, rpath.as_ref())?;
+            let gaurd = self.write_dir(rpath)?;
+            let tmp = path_hidden_with_extension(&gaurd.path, artifact::TMP)?;
+            match fs::remove_dir_all(&tmp) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::io("remove stale copy", &tmp, e));
+                }
+                _ => (),
+            }
+
+            if let Err(e) = tar::Archive::new(reader).unpack(&tmp) {
+                let _ = fs::remove_dir_all(&tmp);
+                return Err(Error::io("unpack archive into", &tmp, e));
+            }
+
+            commit_dir_with(tmp, gaurd.path.clone(), gaurd.ctx.clone())
+        })
+    }
+
+    fn stage(&self, staging: &Path, options: &TarOptions) -> Result<()> {
+        let internal = self.inner.root.join(INTERNAL_DIR);
+        let gaurd = self.write_dir(&options.prefix)?;
+        let ctx = self.ctx();
+        ctx.copy(|stats| copy_visible(&gaurd.path, staging, &internal, &ctx, stats))
+    }
+}
@@ -0,0 +1,142 @@
+//! Names of the files and directories that sbdb keeps next to the entries of a database, such as
+//! `.name.lock.sbdb` or `.name.<puuid>.dir.sbdb`. Tools that walk a database on their own, like a backup
+//! filter or a janitor, can tell them apart from data with [`classify`] rather than by their suffixes,
+//! which grow as sbdb does.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+};
+
+use crate::{Case, PUUID_LEN, parse_puuid};
+
+/// Lock file of an entry, see [`crate::LockBackend::Flock`].
+pub const LOCK: &str = ".lock.sbdb";
+/// Lock directory of an entry, see [`crate::LockBackend::AtomicLockDir`].
+pub const LOCK_DIR: &str = ".lockd.sbdb";
+/// Directory that guards taking over a stale [`LOCK_DIR`].
+pub const LOCK_BREAK: &str = ".lockbrk.sbdb";
+/// File that orders the waiters for a [`LOCK`].
+pub const QUEUE: &str = ".queue.sbdb";
+/// Copy of an entry that is not committed yet.
+pub const TMP: &str = ".tmp.sbdb";
+/// Link that replaces the link of an atomic directory once it is renamed over it.
+pub const TMP_LINK: &str = ".tmplnk.sbdb";
+/// Directory moved aside by a commit, preceded by a puuid.
+pub const BACKUP: &str = ".bak.sbdb";
+/// Payload of an atomic directory, preceded by a puuid.
+pub const ATOMIC_DIR: &str = ".dir.sbdb";
+/// Generation counter, see [`crate::Client::subtree_generation`].
+pub const GENERATION: &str = ".gen.sbdb";
+/// Expiry of an entry, see [`crate::Client::write_with_ttl`].
+pub const TTL: &str = ".ttl.sbdb";
+/// Metadata of an entry, see [`crate::Client::set_meta`].
+pub const META: &str = ".meta.sbdb";
+/// Totals of a directory, see [`crate::Client::rollup`].
+pub const ROLLUP: &str = ".rollup.sbdb";
+
+/// What an internal file or directory is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ArtifactKind {
+    Lock,
+    Queue,
+    /// A lock held with [`crate::LockBackend::AtomicLockDir`], or the directory that guards taking over a
+    /// stale one.
+    LockDir,
+    Tmp,
+    TmpLink,
+    Backup,
+    AtomicDir,
+    Generation,
+    Ttl,
+    Meta,
+    Rollup,
+}
+
+/// Suffix, kind and whether a puuid precedes the suffix, of every kind of internal file.
+pub(crate) const SUFFIXES: [(&str, ArtifactKind, bool); 12] = [
+    (LOCK, ArtifactKind::Lock, false),
+    (LOCK_DIR, ArtifactKind::LockDir, false),
+    (LOCK_BREAK, ArtifactKind::LockDir, false),
+    (QUEUE, ArtifactKind::Queue, false),
+    (TMP, ArtifactKind::Tmp, false),
+    (TMP_LINK, ArtifactKind::TmpLink, false),
+    (BACKUP, ArtifactKind::Backup, true),
+    (ATOMIC_DIR, ArtifactKind::AtomicDir, true),
+    (GENERATION, ArtifactKind::Generation, false),
+    (TTL, ArtifactKind::Ttl, false),
+    (META, ArtifactKind::Meta, false),
+    (ROLLUP, ArtifactKind::Rollup, false),
+];
+
+/// Endings of the names of sbdb's own files, which are hidden from listings and cleaned up by gc. Entries
+/// named like this can not be written, see [`crate::Error::ReservedName`].
+pub const RESERVED_SUFFIXES: [&str; SUFFIXES.len()] = {
+    let mut suffixes = [""; SUFFIXES.len()];
+    let mut i = 0;
+    while i < suffixes.len() {
+        suffixes[i] = SUFFIXES[i].0;
+        i += 1;
+    }
+    suffixes
+};
+
+/// Kind of the internal file named `name`, `None` if it is not named like one.
+pub fn classify(name: &OsStr) -> Option<ArtifactKind> {
+    parse(name).map(|(kind, _)| kind)
+}
+
+/// Name of the entry that the internal file named `name` belongs to, which may itself contain dots.
+pub fn original_name(name: &OsStr) -> Option<OsString> {
+    parse(name).map(|(_, orig)| orig)
+}
+
+/// Splits an internal file name into its kind and the name of the entry it belongs to, see [`classify`] and
+/// [`original_name`].
+pub(crate) fn parse(name: &OsStr) -> Option<(ArtifactKind, OsString)> {
+    let name = name.to_str()?;
+    let rest = name.strip_prefix('.')?;
+    for (suffix, kind, has_puuid) in SUFFIXES {
+        let Some(rest) = rest.strip_suffix(suffix) else {
+            continue;
+        };
+        let orig = if has_puuid {
+            let (orig, id) = rest.rsplit_once('.')?;
+            if id.len() != PUUID_LEN || parse_puuid(id) != Some(Case::Upper) {
+                return None;
+            }
+            orig
+        } else {
+            rest
+        };
+        if orig.is_empty() {
+            return None;
+        }
+        return Some((kind, OsString::from(orig)));
+    }
+    None
+}
+
+/// Whether an entry named like an artifact of `kind` can have been created by sbdb. Queue files are never
+/// written to and lock files at most hold the stamp of a reader, see [`crate::ClientBuilder::max_read_hold`],
+/// so one with other contents belongs to someone else. Temporaries are links once an atomic directory was
+/// removed, see [`crate::Client::remove_prefix`].
+pub(crate) fn is_own(kind: ArtifactKind, metadata: &fs::Metadata) -> bool {
+    let file_type = metadata.file_type();
+    match kind {
+        ArtifactKind::Lock => {
+            file_type.is_file() && matches!(metadata.len(), 0 | crate::read_hold::STAMP_LEN)
+        }
+        ArtifactKind::Queue => file_type.is_file() && metadata.len() == 0,
+        ArtifactKind::Generation
+        | ArtifactKind::Ttl
+        | ArtifactKind::Meta
+        | ArtifactKind::Rollup => file_type.is_file(),
+        ArtifactKind::Tmp => file_type.is_file() || file_type.is_dir() || file_type.is_symlink(),
+        ArtifactKind::TmpLink => file_type.is_symlink(),
+        ArtifactKind::Backup | ArtifactKind::AtomicDir | ArtifactKind::LockDir => {
+            file_type.is_dir()
+        }
+    }
+}
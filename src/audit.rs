@@ -0,0 +1,206 @@
+//! Opt-in record of every commit, appended to `.sbdb/audit.log` as one JSON object per line. When the log
+//! grows past [`AuditOptions::rotate_len`] it is renamed to `.sbdb/audit.<timestamp>.log` and a new one
+//! is started; rotated logs are never removed by sbdb.
+//!
+//! Lock ordering: the log has its own write lock, which is only taken after a commit has completed and is
+//! released before returning to the committer. Nothing is ever locked while it is held, so it can not
+//! take part in a deadlock with the locks of the data being committed.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde_json::{Value, json};
+
+use crate::{
+    Client, CommitKind, Ctx, Error, INTERNAL_DIR, ReadLock, Result, WriteLock, error::IoResultExt,
+};
+
+const LOG: &str = "audit.log";
+
+/// Configures the audit log, installed with [`crate::ClientBuilder::audit`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AuditOptions {
+    /// Caller supplied identifier stored with every record written by this client.
+    pub tag: String,
+    pub enabled: bool,
+    /// Size in bytes after which the log is rotated.
+    pub rotate_len: u64,
+}
+
+impl Default for AuditOptions {
+    fn default() -> Self {
+        AuditOptions {
+            tag: String::new(),
+            enabled: true,
+            rotate_len: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AuditOp {
+    Create,
+    Replace,
+    Delete,
+}
+
+impl AuditOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditOp::Create => "create",
+            AuditOp::Replace => "replace",
+            AuditOp::Delete => "delete",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "create" => Some(AuditOp::Create),
+            "replace" => Some(AuditOp::Replace),
+            "delete" => Some(AuditOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// A single entry of the audit log, see [`Client::read_audit`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    pub tag: String,
+    pub op: AuditOp,
+    pub kind: CommitKind,
+    /// Path of the committed entry relative to the database root.
+    pub path: PathBuf,
+}
+
+pub(crate) struct Audit {
+    root: PathBuf,
+    options: AuditOptions,
+}
+
+impl Audit {
+    pub(crate) fn new(root: PathBuf, options: AuditOptions) -> Self {
+        Audit { root, options }
+    }
+
+    /// Appends a record for a completed commit of `orig`.
+    pub(crate) fn record(
+        &self,
+        kind: CommitKind,
+        orig: &Path,
+        op: AuditOp,
+        ctx: &Ctx,
+    ) -> Result<()> {
+        let rpath = orig.strip_prefix(&self.root).unwrap_or(orig);
+        let internal = self.root.join(INTERNAL_DIR);
+        fs::create_dir_all(&internal).at("create directory", &internal)?;
+        let log_rpath = Path::new(INTERNAL_DIR).join(LOG);
+        let _lock = WriteLock::acquire(&self.root, &log_rpath, ctx)?;
+        // taken under the lock, so that records and rotated logs are in the same order as their times
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = json!({
+            "time_us": time.as_micros() as u64,
+            "tag": self.options.tag,
+            "op": op.as_str(),
+            "kind": kind.as_str(),
+            "path": rpath.to_string_lossy(),
+        })
+        .to_string();
+        line.push('\n');
+
+        let log = self.root.join(&log_rpath);
+        if fs::metadata(&log).is_ok_and(|m| m.len() >= self.options.rotate_len) {
+            let rotated = internal.join(format!("audit.{:020}.log", time.as_nanos()));
+            fs::rename(&log, &rotated).at("rotate", &log)?;
+        }
+        // a single write to a file opened for appending, so a crash can at most leave a partial last line
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .at("append to", &log)
+    }
+}
+
+fn parse_record(path: &Path, line: &str) -> Result<AuditRecord> {
+    let invalid = || Error::WrongFormat {
+        path: path.to_path_buf(),
+    };
+    let value: Value = serde_json::from_str(line).map_err(|e| Error::Decode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })?;
+    let time_us = value["time_us"].as_u64().ok_or_else(invalid)?;
+    Ok(AuditRecord {
+        time: SystemTime::UNIX_EPOCH + Duration::from_micros(time_us),
+        tag: value["tag"].as_str().ok_or_else(invalid)?.to_string(),
+        op: value["op"]
+            .as_str()
+            .and_then(AuditOp::parse)
+            .ok_or_else(invalid)?,
+        kind: value["kind"]
+            .as_str()
+            .and_then(CommitKind::parse)
+            .ok_or_else(invalid)?,
+        path: PathBuf::from(value["path"].as_str().ok_or_else(invalid)?),
+    })
+}
+
+impl Client {
+    /// Audit records with a time inside `range`, oldest first, including those in rotated logs. A
+    /// trailing partial line left by a crash is ignored.
+    pub fn read_audit<R: RangeBounds<SystemTime>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = AuditRecord>> {
+        self.redacted(|| {
+            let internal = self.inner.root.join(INTERNAL_DIR);
+            if !internal.exists() {
+                return Ok(Vec::new().into_iter());
+            }
+            let _lock = ReadLock::acquire(
+                &self.inner.root,
+                &Path::new(INTERNAL_DIR).join(LOG),
+                &self.ctx(),
+            )?;
+
+            let mut logs = Vec::new();
+            for entry in fs::read_dir(&internal).at("read directory", &internal)? {
+                let name = entry.at("read directory", &internal)?.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with("audit.") && name.ends_with(".log") && name != LOG {
+                    logs.push(internal.join(&*name));
+                }
+            }
+            logs.sort();
+            logs.push(internal.join(LOG));
+
+            let mut records = Vec::new();
+            for log in logs {
+                let contents = match fs::read_to_string(&log) {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(Error::io("read", &log, e)),
+                };
+                let complete = contents.rfind('\n').map_or("", |end| &contents[..end]);
+                for line in complete.lines() {
+                    let record = parse_record(&log, line)?;
+                    if range.contains(&record.time) {
+                        records.push(record);
+                    }
+                }
+            }
+            Ok(records.into_iter())
+        })
+    }
+}
@@ -0,0 +1,310 @@
+//! Incremental copies of the database into a directory outside of it. What was copied by the previous run
+//! is remembered in a [`BackupState`], so that later runs only copy files whose size, modification time or
+//! generation changed, and remove the ones that no longer exist.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, ReadLock, Result, artifact, copy_file, error::IoResultExt,
+    is_atomic_dir_link, path_hidden_with_extension,
+};
+
+const STATE_HEADER: &str = "sbdb-backup-state 1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry {
+    kind: EntryKind,
+    len: u64,
+    mtime: u64,
+    generation: u64,
+}
+
+/// What [`Client::backup_incremental`] copied so far. Persist it with [`BackupState::save`] between runs;
+/// starting from an empty state copies everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupState {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl BackupState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a state written by [`BackupState::save`]. A missing file is an empty state.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::io("read", path, e)),
+        };
+        let wrong_format = || Error::WrongFormat {
+            path: path.to_path_buf(),
+        };
+        let mut lines = contents.lines();
+        if lines.next() != Some(STATE_HEADER) {
+            return Err(wrong_format());
+        }
+        let mut entries = BTreeMap::new();
+        for line in lines {
+            let mut fields = line.splitn(5, '\t');
+            let mut next = || fields.next().ok_or_else(wrong_format);
+            let kind = match next()? {
+                "f" => EntryKind::File,
+                "d" => EntryKind::Dir,
+                "l" => EntryKind::Symlink,
+                _ => return Err(wrong_format()),
+            };
+            let mut number = || next()?.parse::<u64>().map_err(|_| wrong_format());
+            let entry = Entry {
+                kind,
+                len: number()?,
+                mtime: number()?,
+                generation: number()?,
+            };
+            let rpath = unescape(next()?).ok_or_else(wrong_format)?;
+            entries.insert(PathBuf::from(rpath), entry);
+        }
+        Ok(BackupState { entries })
+    }
+
+    /// Atomically write the state to `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut contents = String::new();
+        contents.push_str(STATE_HEADER);
+        contents.push('\n');
+        for (rpath, entry) in &self.entries {
+            let rpath_str = rpath
+                .to_str()
+                .ok_or_else(|| Error::invalid_path(rpath, "path is not valid utf-8"))?;
+            let kind = match entry.kind {
+                EntryKind::File => 'f',
+                EntryKind::Dir => 'd',
+                EntryKind::Symlink => 'l',
+            };
+            let _ = writeln!(
+                contents,
+                "{}\t{}\t{}\t{}\t{}",
+                kind,
+                entry.len,
+                entry.mtime,
+                entry.generation,
+                escape(rpath_str)
+            );
+        }
+        let tmp = path_hidden_with_extension(path, artifact::TMP)?;
+        fs::write(&tmp, contents).at("write", &tmp)?;
+        fs::rename(&tmp, path).at("replace", path)
+    }
+}
+
+/// Escapes the characters that separate fields and lines of the state file.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | '\t' | '\n' | '\r' => {
+                let _ = write!(escaped, "%{:02X}", c as u8);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Outcome of [`Client::backup_incremental`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BackupSummary {
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    /// Files and symbolic links removed from the destination because they no longer exist.
+    pub files_deleted: usize,
+    pub files_unchanged: usize,
+}
+
+fn remove_entry(path: &Path, kind: EntryKind) -> Result<()> {
+    let result = match kind {
+        EntryKind::Dir => fs::remove_dir_all(path),
+        EntryKind::File | EntryKind::Symlink => fs::remove_file(path),
+    };
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io("remove", path, e)),
+        _ => Ok(()),
+    }
+}
+
+impl Client {
+    /// Bring `dest` up to date with the database, copying only what changed since the run that produced
+    /// `state`. Directories are read locked while they are listed and files while they are copied, but
+    /// the database as a whole is not frozen, so a backup taken while other clients commit is not a
+    /// point-in-time copy. Atomic directories are stored as plain directories, internal files are left
+    /// out. Files are copied with reflinks where the filesystem supports them. The maintenance lock is held
+    /// for reading throughout, see [`Client::maintenance_read`].
+    pub fn backup_incremental(
+        &self,
+        dest: &Path,
+        state: &mut BackupState,
+    ) -> Result<BackupSummary> {
+        self.redacted(|| {
+            let root = std::path::absolute(&self.inner.root).at("resolve", &self.inner.root)?;
+            if std::path::absolute(dest)
+                .at("resolve", dest)?
+                .starts_with(&root)
+            {
+                return Err(Error::invalid_path(
+                    dest,
+                    "backup destination is inside the database",
+                ));
+            }
+            fs::create_dir_all(dest).at("create directory", dest)?;
+
+            let _maintenance = self.maintenance_read()?;
+            let ctx = self.ctx();
+            let mut summary = BackupSummary::default();
+            let mut seen = BTreeMap::new();
+            let mut pending = vec![PathBuf::new()];
+            ctx.copy(|stats| {
+                while let Some(rpath) = pending.pop() {
+                    let gaurd = self.read_dir(&rpath)?;
+                    for entry in fs::read_dir(&gaurd.path).at("read directory", &gaurd.path)? {
+                        let entry = entry.at("read directory", &gaurd.path)?;
+                        let name = entry.file_name();
+                        let child_path = entry.path();
+                        if (rpath.as_os_str().is_empty() && name == INTERNAL_DIR)
+                            || artifact::parse(&name).is_some()
+                        {
+                            continue;
+                        }
+                        let child = rpath.join(&name);
+                        let metadata = fs::symlink_metadata(&child_path)
+                            .at("read metadata of", &child_path)?;
+                        let file_type = metadata.file_type();
+                        let kind = if file_type.is_dir()
+                            || (file_type.is_symlink() && is_atomic_dir_link(&child_path)?)
+                        {
+                            EntryKind::Dir
+                        } else if file_type.is_symlink() {
+                            EntryKind::Symlink
+                        } else if file_type.is_file() {
+                            EntryKind::File
+                        } else {
+                            continue;
+                        };
+                        let record = match kind {
+                            EntryKind::Dir => Entry {
+                                kind,
+                                len: 0,
+                                mtime: 0,
+                                generation: 0,
+                            },
+                            _ => Entry {
+                                kind,
+                                len: metadata.len(),
+                                mtime: metadata
+                                    .modified()
+                                    .ok()
+                                    .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+                                    .map_or(0, |d| d.as_nanos() as u64),
+                                generation: match &self.inner.generations {
+                                    Some(_) => self.subtree_generation(&child)?,
+                                    None => 0,
+                                },
+                            },
+                        };
+
+                        let target = dest.join(&child);
+                        let previous = state.entries.get(&child);
+                        if let Some(previous) = previous
+                            && previous.kind != kind
+                        {
+                            remove_entry(&target, previous.kind)?;
+                        }
+                        seen.insert(child.clone(), record);
+                        if kind == EntryKind::Dir {
+                            fs::create_dir_all(&target).at("create directory", &target)?;
+                            pending.push(child);
+                            continue;
+                        }
+                        if previous == Some(&record) && fs::symlink_metadata(&target).is_ok() {
+                            summary.files_unchanged += 1;
+                            continue;
+                        }
+                        if kind == EntryKind::Symlink {
+                            remove_entry(&target, kind)?;
+                            let link = fs::read_link(&child_path).at("read link", &child_path)?;
+                            #[cfg(unix)]
+                            std::os::unix::fs::symlink(&link, &target)
+                                .map_err(|e| Error::copy(&child_path, &target, e))?;
+                            #[cfg(windows)]
+                            std::os::windows::fs::symlink_dir(&link, &target)
+                                .map_err(|e| Error::copy(&child_path, &target, e))?;
+                            continue;
+                        }
+
+                        // copied next to the target first, so an interrupted run leaves no partial files
+                        let _lock = ReadLock::acquire(&self.inner.root, &child, &ctx)?;
+                        let tmp = path_hidden_with_extension(&target, artifact::TMP)?;
+                        let before = stats.bytes;
+                        copy_file(&child_path, &tmp, &ctx, stats)?;
+                        fs::rename(&tmp, &target).at("replace", &target)?;
+                        summary.files_copied += 1;
+                        summary.bytes_copied += stats.bytes - before;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let vanished = state
+                .entries
+                .iter()
+                .filter(|(rpath, _)| !seen.contains_key(*rpath))
+                .collect::<BTreeMap<_, _>>();
+            for (rpath, entry) in &vanished {
+                if entry.kind != EntryKind::Dir {
+                    summary.files_deleted += 1;
+                }
+                // removing a vanished directory takes everything below it along
+                let covered = rpath
+                    .ancestors()
+                    .skip(1)
+                    .any(|ancestor| vanished.contains_key(&ancestor.to_path_buf()));
+                if !covered {
+                    remove_entry(&dest.join(rpath), entry.kind)?;
+                }
+            }
+
+            state.entries = seen;
+            Ok(summary)
+        })
+    }
+}
@@ -0,0 +1,463 @@
+//! Command line access to sbdb roots, built with the `cli` feature.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use sbdb::{
+    Client, GcOptions, HashAlgorithm, Lock, LockStatus, RetentionPolicy, StatsOptions,
+    VerifyOptions,
+};
+
+const USAGE: &str = "\
+usage: sbdb <command> <root> [args]
+
+commands:
+    gc <root> [--dry-run] [--min-age <duration>] [--expire-ttl] [--json]
+        [--keep-backups <n>] [--keep-backups-younger-than <duration>] [--max-backup-bytes <n>]
+        (each entry keeps the backups within all of the given limits)
+    verify <root> [--min-age <duration>] [--json]
+    ls <root> [rpath]
+    cat <root> <rpath>
+    put <root> <rpath> [file|-]    (reads stdin by default, creates missing directories)
+    locks <root> [--json]
+    stats <root> [rpath] [--top <n>] [--json]
+    hash <root> [rpath] [--algo <sha256|blake3>]    (blake3 needs the blake3 feature)
+
+durations are a number followed by ms, s, m or h.
+
+exit codes:
+    0  success
+    1  error
+    2  invalid arguments
+    3  a lock is held (locks)
+    4  issues that gc can not repair were found (verify)";
+
+const EXIT_ERROR: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_LOCK_HELD: u8 = 3;
+const EXIT_NEEDS_ATTENTION: u8 = 4;
+
+enum CliError {
+    Usage(String),
+    Failed(Box<dyn std::error::Error>),
+}
+
+impl<E: std::error::Error + 'static> From<E> for CliError {
+    fn from(e: E) -> Self {
+        CliError::Failed(Box::new(e))
+    }
+}
+
+type CliResult<T> = std::result::Result<T, CliError>;
+
+fn usage<T>(message: impl Into<String>) -> CliResult<T> {
+    Err(CliError::Usage(message.into()))
+}
+
+/// Positional arguments and flags of a single command.
+struct Args {
+    positional: Vec<OsString>,
+    flags: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    /// `with_value` lists the flags that take a value.
+    fn parse(mut args: impl Iterator<Item = OsString>, with_value: &[&str]) -> CliResult<Self> {
+        let mut positional = Vec::new();
+        let mut flags = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                // a lone dash is stdin, not a flag
+                Some(flag) if flag.starts_with("--") => {
+                    let name = flag.trim_start_matches("--").to_string();
+                    let value = if with_value.contains(&name.as_str()) {
+                        match args.next().and_then(|v| v.into_string().ok()) {
+                            Some(value) => Some(value),
+                            None => return usage(format!("--{} needs a value", name)),
+                        }
+                    } else {
+                        None
+                    };
+                    flags.push((name, value));
+                }
+                _ => positional.push(arg),
+            }
+        }
+        Ok(Args { positional, flags })
+    }
+
+    fn expect(&self, allowed: &[&str], min: usize, max: usize) -> CliResult<()> {
+        if let Some((name, _)) = self
+            .flags
+            .iter()
+            .find(|(name, _)| !allowed.contains(&name.as_str()))
+        {
+            return usage(format!("unknown flag --{}", name));
+        }
+        if self.positional.len() < min || self.positional.len() > max {
+            return usage("wrong number of arguments");
+        }
+        Ok(())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|(flag, _)| flag == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .find(|(flag, _)| flag == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> CliResult<Option<T>> {
+        match self.value(name) {
+            Some(value) => match value.parse() {
+                Ok(number) => Ok(Some(number)),
+                Err(_) => usage(format!("invalid number {}", value)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn min_age(&self) -> CliResult<Duration> {
+        match self.value("min-age") {
+            Some(value) => parse_duration(value),
+            None => Ok(Duration::ZERO),
+        }
+    }
+
+    fn client(&self) -> CliResult<Client> {
+        let root = Path::new(&self.positional[0]);
+        if !root.is_dir() {
+            return usage(format!("{} is not a directory", root.display()));
+        }
+        Ok(Client::new(root)?)
+    }
+
+    fn rpath(&self, i: usize) -> PathBuf {
+        self.positional
+            .get(i)
+            .map(PathBuf::from)
+            .unwrap_or_default()
+    }
+}
+
+fn parse_duration(value: &str) -> CliResult<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let Ok(number) = number.parse::<u64>() else {
+        return usage(format!("invalid duration {}", value));
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" | "" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        _ => usage(format!("invalid duration {}", value)),
+    }
+}
+
+fn read(path: &Path) -> CliResult<Vec<u8>> {
+    fs::read(path)
+        .map_err(|e| CliError::Failed(format!("failed to read {}: {}", path.display(), e).into()))
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> CliResult<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn gc(args: Args) -> CliResult<u8> {
+    args.expect(
+        &[
+            "dry-run",
+            "min-age",
+            "expire-ttl",
+            "json",
+            "keep-backups",
+            "keep-backups-younger-than",
+            "max-backup-bytes",
+        ],
+        1,
+        1,
+    )?;
+    let mut policy = RetentionPolicy::default();
+    policy.keep_last = args.number("keep-backups")?;
+    policy.keep_younger_than = args
+        .value("keep-backups-younger-than")
+        .map(parse_duration)
+        .transpose()?;
+    policy.max_bytes = args.number("max-backup-bytes")?;
+    let mut options = GcOptions::default();
+    options.dry_run = args.flag("dry-run");
+    options.min_age = args.min_age()?;
+    options.expire_ttl = args.flag("expire-ttl");
+    options.vacuum_backups = (policy != RetentionPolicy::default()).then_some(policy);
+    let report = args.client()?.gc_with(&options);
+    if args.flag("json") {
+        print_json(&report)?;
+    } else {
+        for (name, value) in [
+            ("dirs scanned", report.dirs_scanned),
+            ("lock files removed", report.lock_files_removed),
+            ("temps removed", report.temps_removed),
+            ("backups removed", report.backups_removed),
+            ("backups restored", report.backups_restored),
+            ("payloads removed", report.payloads_removed),
+            ("generations removed", report.generations_removed),
+            ("entries expired", report.entries_expired),
+            ("expiries removed", report.expiries_removed),
+            ("metadata removed", report.metadata_removed),
+            ("rollups removed", report.rollups_removed),
+            ("symlinks skipped", report.symlinks_skipped),
+            ("dangling atomic dirs", report.dangling_atomic_dirs),
+            ("backups vacuumed", report.backups_vacuumed),
+            ("errors", report.errors),
+        ] {
+            println!("{:<20} {:>8}", name, value);
+        }
+    }
+    Ok(if report.errors > 0 { EXIT_ERROR } else { 0 })
+}
+
+fn verify(args: Args) -> CliResult<u8> {
+    args.expect(&["min-age", "json"], 1, 1)?;
+    let mut options = VerifyOptions::default();
+    options.min_age = args.min_age()?;
+    let report = args.client()?.verify("", &options)?;
+    if args.flag("json") {
+        print_json(&report)?;
+    } else {
+        for issue in &report.issues {
+            let repair = if issue.repairable { "gc" } else { "manual" };
+            println!("{:<6} {:?} {}", repair, issue.kind, issue.path.display());
+        }
+        println!(
+            "{} directories scanned, {} issues",
+            report.dirs_scanned,
+            report.issues.len()
+        );
+    }
+    Ok(if report.needs_attention() {
+        EXIT_NEEDS_ATTENTION
+    } else {
+        0
+    })
+}
+
+fn ls(args: Args) -> CliResult<u8> {
+    args.expect(&[], 1, 2)?;
+    let client = args.client()?;
+    let rpath = args.rpath(1);
+    for name in client.list(&rpath)? {
+        let suffix = if client.root().join(&rpath).join(&name).is_dir() {
+            "/"
+        } else {
+            ""
+        };
+        println!("{}{}", Path::new(&name).display(), suffix);
+    }
+    Ok(0)
+}
+
+fn cat(args: Args) -> CliResult<u8> {
+    args.expect(&[], 2, 2)?;
+    let gaurd = args.client()?.read_file(args.rpath(1))?;
+    let bytes = read(&gaurd.path)?;
+    gaurd.release()?;
+    io::stdout().write_all(&bytes)?;
+    Ok(0)
+}
+
+fn put(args: Args) -> CliResult<u8> {
+    args.expect(&[], 2, 3)?;
+    let bytes = match args.positional.get(2) {
+        Some(source) if source != "-" => read(Path::new(source))?,
+        _ => {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            bytes
+        }
+    };
+    let client = args.client()?;
+    let rpath = args.rpath(1);
+    // like mkdir -p, creating a directory is atomic so it needs no lock
+    if let Some(parent) = client.root().join(&rpath).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    client.write_bytes(&rpath, &bytes)?;
+    Ok(0)
+}
+
+#[derive(serde::Serialize)]
+struct LockInfo {
+    path: PathBuf,
+    status: &'static str,
+}
+
+fn status_name(status: LockStatus) -> &'static str {
+    match status {
+        LockStatus::Free => "free",
+        LockStatus::Read => "read",
+        LockStatus::Write => "write",
+    }
+}
+
+fn locks(args: Args) -> CliResult<u8> {
+    args.expect(&["json"], 1, 1)?;
+    let client = args.client()?;
+    let root = client.root();
+    // the root's lock lives next to it rather than inside it
+    let mut found = vec![LockInfo {
+        path: PathBuf::new(),
+        status: status_name(Lock::probe(root)?),
+    }];
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if entry.file_type()?.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            let Some(orig) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix('.'))
+                .and_then(|name| name.strip_suffix(sbdb::artifact::LOCK))
+            else {
+                continue;
+            };
+            let orig = dir.join(orig);
+            let status = status_name(Lock::probe(&orig)?);
+            let path = orig.strip_prefix(root).unwrap_or(&orig).to_path_buf();
+            found.push(LockInfo { path, status });
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if args.flag("json") {
+        print_json(&found)?;
+    } else {
+        for lock in &found {
+            let path = if lock.path.as_os_str().is_empty() {
+                Path::new("/")
+            } else {
+                &lock.path
+            };
+            println!("{:<6} {}", lock.status, path.display());
+        }
+    }
+    Ok(if found.iter().any(|lock| lock.status != "free") {
+        EXIT_LOCK_HELD
+    } else {
+        0
+    })
+}
+
+fn stats(args: Args) -> CliResult<u8> {
+    args.expect(&["top", "json"], 1, 2)?;
+    let mut options = StatsOptions::default();
+    if let Some(top) = args.value("top") {
+        let Ok(top) = top.parse() else {
+            return usage(format!("invalid number {}", top));
+        };
+        options.largest = top;
+    }
+    let stats = args.client()?.stats_with(args.rpath(1), &options)?;
+    if args.flag("json") {
+        print_json(&stats)?;
+        return Ok(0);
+    }
+    println!("{:<20} {:>8} {:>14}", "", "entries", "bytes");
+    println!(
+        "{:<20} {:>8} {:>14}",
+        "files", stats.files, stats.file_bytes
+    );
+    println!("{:<20} {:>8}", "directories", stats.dirs);
+    for (suffix, artifact) in &stats.artifacts {
+        println!(
+            "{:<20} {:>8} {:>14}",
+            suffix, artifact.count, artifact.bytes
+        );
+    }
+    println!(
+        "{:<20} {:>8} {:>14}",
+        ".sbdb", stats.internal.count, stats.internal.bytes
+    );
+    println!("{:<20} {:>8}", "max depth", stats.max_depth);
+    for file in &stats.largest {
+        println!("{:>14} {}", file.bytes, file.path.display());
+    }
+    Ok(0)
+}
+
+fn hash(args: Args) -> CliResult<u8> {
+    args.expect(&["algo"], 1, 2)?;
+    let algorithm = match args.value("algo") {
+        None | Some("sha256") => HashAlgorithm::Sha256,
+        #[cfg(feature = "blake3")]
+        Some("blake3") => HashAlgorithm::Blake3,
+        Some(algo) => return usage(format!("unsupported algorithm {}", algo)),
+    };
+    let rpath = args.rpath(1);
+    let digest = args.client()?.hash_tree(&rpath, algorithm)?;
+    let path = if rpath.as_os_str().is_empty() {
+        Path::new("/")
+    } else {
+        &rpath
+    };
+    println!("{}  {}", digest, path.display());
+    Ok(0)
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args_os().skip(1);
+    let command = args.next().and_then(|command| command.into_string().ok());
+    let result = match command.as_deref() {
+        Some("gc") => Args::parse(
+            args,
+            &[
+                "min-age",
+                "keep-backups",
+                "keep-backups-younger-than",
+                "max-backup-bytes",
+            ],
+        )
+        .and_then(gc),
+        Some("verify") => Args::parse(args, &["min-age"]).and_then(verify),
+        Some("ls") => Args::parse(args, &[]).and_then(ls),
+        Some("cat") => Args::parse(args, &[]).and_then(cat),
+        Some("put") => Args::parse(args, &[]).and_then(put),
+        Some("locks") => Args::parse(args, &[]).and_then(locks),
+        Some("stats") => Args::parse(args, &["top"]).and_then(stats),
+        Some("hash") => Args::parse(args, &["algo"]).and_then(hash),
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        Some(command) => usage(format!("unknown command {}", command)),
+        None => usage("missing command"),
+    };
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(CliError::Usage(message)) => {
+            eprintln!("sbdb: {}\n\n{}", message, USAGE);
+            ExitCode::from(EXIT_USAGE)
+        }
+        Err(CliError::Failed(e)) => {
+            eprintln!("sbdb: {}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Content addressed storage of immutable blobs, see [`BlobStore`].
+
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    Client, CowFileGaurd, Digest, Error, FileReadGaurd, HashAlgorithm, ReplicatedOp, Result,
+    check_unreserved, error::IoResultExt, hash, quota,
+};
+
+/// Identifies a blob by the SHA-256 digest of its contents. Displayed and parsed as lowercase hex, the name
+/// of the blob's file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlobId(Digest);
+
+impl BlobId {
+    pub fn digest(&self) -> Digest {
+        self.0
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for BlobId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Digest::from_hex(HashAlgorithm::Sha256, s)
+            .map(BlobId)
+            .ok_or_else(|| Error::InvalidKey {
+                key: s.to_string(),
+                reason: "not 64 lowercase hex digits",
+            })
+    }
+}
+
+/// A directory of blobs stored under the digest of their contents, so that storing the same contents twice
+/// keeps one copy, and a blob's name tells whether it is intact. A blob is stored at `ab/cd/<id>` below the
+/// directory, `ab` and `cd` being the first two bytes of its id, so that no directory grows too large to
+/// list. Blobs are never changed once stored, only removed by [`BlobStore::remove_unreferenced`].
+pub struct BlobStore {
+    client: Client,
+    rpath: PathBuf,
+}
+
+impl Client {
+    /// Open the blob store in the directory `rpath`. The directory is created by the first put.
+    pub fn blobs<P: AsRef<Path>>(&self, rpath: P) -> BlobStore {
+        BlobStore {
+            client: self.clone(),
+            rpath: rpath.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl BlobStore {
+    /// Store everything `reader` holds, returning its id. The contents are streamed into the scratch area
+    /// while they are hashed, and moved into place under the blob's write lock, which also creates the
+    /// directories of a new blob. Contents that are stored already, e.g. by a concurrent put of the same,
+    /// are only checked to have the same size and left as they are.
+    pub fn put<R: Read>(&self, mut reader: R) -> Result<BlobId> {
+        check_unreserved(self.client.root(), &self.rpath)?;
+        let scratch = self.client.scratch()?;
+        let tmp = scratch.path.join("blob");
+        let mut file = File::create(&tmp).at("create", &tmp)?;
+        let (digest, len) =
+            hash::copy_hashing(&mut reader, &mut file, HashAlgorithm::Sha256).at("write", &tmp)?;
+        drop(file);
+        let id = BlobId(digest);
+
+        let rpath = self.blob_rpath(&id);
+        let tx = self.client.tx().write(&rpath).begin()?;
+        let path = self.client.root().join(&rpath);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() == len => {}
+            // a blob that was cut short is replaced
+            _ => {
+                tx.ctx.set_artifact_permissions(&tmp, false)?;
+                CowFileGaurd {
+                    path: tmp,
+                    orig: path,
+                    ctx: tx.ctx.clone(),
+                }
+                .commit()?;
+            }
+        }
+        tx.release()?;
+        Ok(id)
+    }
+
+    /// Read lock the blob `id`, failing with [`Error::NotFound`] if it is not stored.
+    pub fn get(&self, id: &BlobId) -> Result<FileReadGaurd> {
+        match self.read(id)? {
+            Some(gaurd) => Ok(gaurd),
+            None => Err(Error::NotFound {
+                operation: "read blob",
+                path: self.client.root().join(self.blob_rpath(id)),
+            }),
+        }
+    }
+
+    pub fn contains(&self, id: &BlobId) -> Result<bool> {
+        Ok(self.read(id)?.is_some())
+    }
+
+    /// Remove the blobs whose ids are not among `referenced`, returning how many were removed. The whole
+    /// store is write locked meanwhile. The directories of the blobs are left, see
+    /// [`Client::prune_empty_dirs`], and files that are not named like blobs are left alone.
+    pub fn remove_unreferenced<I: IntoIterator<Item = BlobId>>(
+        &self,
+        referenced: I,
+    ) -> Result<usize> {
+        if !self.client.root().join(&self.rpath).is_dir() {
+            return Ok(0);
+        }
+        let referenced = referenced.into_iter().collect::<HashSet<_>>();
+        let gaurd = self.client.write_dir(&self.rpath)?;
+        let mut removed = 0;
+        for first in shards(&gaurd.path)? {
+            for second in shards(&first)? {
+                for entry in fs::read_dir(&second).at("read directory", &second)? {
+                    let path = entry.at("read directory", &second)?.path();
+                    let id = path
+                        .file_name()
+                        .and_then(|name| name.to_str()?.parse().ok());
+                    if id.is_none_or(|id: BlobId| referenced.contains(&id)) || !path.is_file() {
+                        continue;
+                    }
+                    gaurd.ctx.check_writable(&path)?;
+                    let bytes = quota::data_size(&path);
+                    let before = gaurd.ctx.usage_before(&path);
+                    self.client
+                        .inner
+                        .vfs
+                        .remove_file(&path)
+                        .at("remove blob", &path)?;
+                    gaurd.ctx.release_quota(bytes);
+                    gaurd.ctx.replicate(ReplicatedOp::Remove, &path);
+                    gaurd.ctx.roll_up(&path, before);
+                    removed += 1;
+                }
+            }
+        }
+        gaurd.release()?;
+        Ok(removed)
+    }
+
+    /// Read lock the blob `id`, `None` if it is not stored.
+    fn read(&self, id: &BlobId) -> Result<Option<FileReadGaurd>> {
+        let rpath = self.blob_rpath(id);
+        // the lock files of a blob are in its directory, which the first blob in it creates
+        if !self
+            .client
+            .root()
+            .join(&rpath)
+            .parent()
+            .is_some_and(Path::is_dir)
+        {
+            return Ok(None);
+        }
+        let gaurd = self.client.read_file(&rpath)?;
+        Ok(gaurd.path.is_file().then_some(gaurd))
+    }
+
+    fn blob_rpath(&self, id: &BlobId) -> PathBuf {
+        let hex = id.to_string();
+        self.rpath.join(&hex[..2]).join(&hex[2..4]).join(hex)
+    }
+}
+
+/// The directories in `dir` named like a byte of a blob id.
+fn shards(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut shards = Vec::new();
+    for entry in fs::read_dir(dir).at("read directory", dir)? {
+        let entry = entry.at("read directory", dir)?;
+        let name = entry.file_name();
+        let is_shard = name.to_str().is_some_and(|name| {
+            name.len() == 2 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        });
+        if is_shard && entry.path().is_dir() {
+            shards.push(entry.path());
+        }
+    }
+    Ok(shards)
+}
@@ -0,0 +1,97 @@
+//! Optimistic updates of single files: read a file along with its version, and write it back only if its
+//! contents are still those that were read. The write lock is only held while the version is compared and
+//! the new contents are committed, not while they are computed.
+
+use std::{fs, path::Path};
+
+use crate::{Client, Error, Result, error::IoResultExt, file_replace_with, key};
+
+/// Identifies the contents of a file, as returned by [`Client::read_versioned`]. Files with the same
+/// contents have the same version, however they were written, and a missing file has its own version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ContentVersion(Option<(u64, u128)>);
+
+impl ContentVersion {
+    /// Version of a file that does not exist.
+    pub const ABSENT: ContentVersion = ContentVersion(None);
+
+    fn of(bytes: &[u8]) -> Self {
+        ContentVersion(Some((bytes.len() as u64, key::fnv1a_128(bytes))))
+    }
+
+    pub fn is_absent(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+/// Why [`Client::write_if_unchanged`] did not write.
+#[derive(Debug, thiserror::Error)]
+pub enum CasError {
+    /// The file no longer has the expected version, it is at version `current` now.
+    #[error("contents changed since they were read")]
+    Conflict { current: ContentVersion },
+    #[error(transparent)]
+    Failed(#[from] Error),
+}
+
+impl Client {
+    /// Contents of the file at `rpath` and their version. A missing file is read as empty, with version
+    /// [`ContentVersion::ABSENT`].
+    pub fn read_versioned<P: AsRef<Path>>(&self, rpath: P) -> Result<(Vec<u8>, ContentVersion)> {
+        self.redacted(|| {
+            let gaurd = self.read_file(rpath)?;
+            read(&gaurd.path)
+        })
+    }
+
+    /// Atomically replace the file at `rpath` with `bytes` if it is still at version `expected`, which
+    /// creates it if `expected` is [`ContentVersion::ABSENT`]. Otherwise nothing is written and the
+    /// current version is returned in [`CasError::Conflict`].
+    pub fn write_if_unchanged<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        expected: &ContentVersion,
+        bytes: &[u8],
+    ) -> std::result::Result<(), CasError> {
+        let gaurd = self.write_file(rpath)?;
+        let (_, current) = self.redacted(|| read(&gaurd.path))?;
+        if current != *expected {
+            return Err(CasError::Conflict { current });
+        }
+        self.redacted(|| file_replace_with(&gaurd.path, bytes, false, gaurd.ctx.clone()))?;
+        Ok(())
+    }
+
+    /// Replace the contents of the file at `rpath` with what `f` makes of them, retrying up to
+    /// `max_retries` times if another writer got in between. A missing file is passed to `f` as empty.
+    /// Returns the contents that were written.
+    pub fn update<P: AsRef<Path>, F: Fn(&[u8]) -> Vec<u8>>(
+        &self,
+        rpath: P,
+        f: F,
+        max_retries: usize,
+    ) -> std::result::Result<Vec<u8>, CasError> {
+        let rpath = rpath.as_ref();
+        let mut retries = 0;
+        loop {
+            let (bytes, version) = self.read_versioned(rpath)?;
+            let updated = f(&bytes);
+            match self.write_if_unchanged(rpath, &version, &updated) {
+                Ok(()) => return Ok(updated),
+                Err(CasError::Conflict { .. }) if retries < max_retries => retries += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<(Vec<u8>, ContentVersion)> {
+    match fs::read(path).at("read", path) {
+        Ok(bytes) => {
+            let version = ContentVersion::of(&bytes);
+            Ok((bytes, version))
+        }
+        Err(Error::NotFound { .. }) => Ok((Vec::new(), ContentVersion::ABSENT)),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,487 @@
+//! Changing several files in one transaction, see [`Client::apply`]. Every change is staged next to its
+//! file while all of them are write locked, and only then are they moved into place one after the other.
+//! What they replace or delete is moved to a scratch directory rather than removed until the last one is
+//! in place, so that a failure part way can put back what the earlier ones changed.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    Client, CommitKind, Ctx, Error, INTERNAL_DIR, ReplicatedOp, Result, artifact, copy_file,
+    error::IoResultExt, normalize_rpath, path_hidden_with_extension, quota, scratch::SCRATCH_DIR,
+    unused_child, write_tmp,
+};
+
+/// Changes of files to be made together by [`Client::apply`], in the order they were added. Every entry
+/// may be changed only once, and not along with an entry above or below it, which is reported as
+/// [`Error::ConflictingChanges`] by [`Client::apply`] before anything is locked, like paths that are not
+/// plain relative ones.
+#[derive(Debug, Default)]
+pub struct Changeset {
+    changes: Vec<Change>,
+    /// Entries changed so far, with the role they play in the change.
+    touched: HashMap<PathBuf, Role>,
+    /// First change that could not be added, reported by [`Client::apply`].
+    invalid: Option<Error>,
+}
+
+#[derive(Debug)]
+enum Change {
+    Put { rpath: PathBuf, bytes: Vec<u8> },
+    PutFileFrom { rpath: PathBuf, external: PathBuf },
+    Delete { rpath: PathBuf },
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Put,
+    Delete,
+    RenameFrom,
+    RenameTo,
+}
+
+/// What a change of a [`Changeset`] did, see [`ApplyReport`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChangeOutcome {
+    /// A put created the file.
+    Created,
+    /// A put replaced the file.
+    Replaced,
+    /// A delete removed the file.
+    Deleted,
+    /// A delete found no file to remove.
+    Missing,
+    /// A rename moved the file, replacing one at the destination if `replaced`.
+    Renamed { replaced: bool },
+}
+
+/// Outcome of [`Client::apply`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ApplyReport {
+    /// What each change did, in the order the changes were added.
+    pub outcomes: Vec<ChangeOutcome>,
+}
+
+impl Changeset {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write `bytes` to the file at `rpath`, creating or replacing it.
+    pub fn put<P: AsRef<Path>>(mut self, rpath: P, bytes: impl Into<Vec<u8>>) -> Self {
+        if let Some(rpath) = self.touch(rpath.as_ref(), Role::Put) {
+            self.changes.push(Change::Put {
+                rpath,
+                bytes: bytes.into(),
+            });
+        }
+        self
+    }
+
+    /// Copy the file at `external`, which is outside the database and not locked, to the file at `rpath`,
+    /// creating or replacing it. The copy is made when the changeset is applied.
+    pub fn put_file_from<P: AsRef<Path>, Q: AsRef<Path>>(mut self, rpath: P, external: Q) -> Self {
+        if let Some(rpath) = self.touch(rpath.as_ref(), Role::Put) {
+            self.changes.push(Change::PutFileFrom {
+                rpath,
+                external: external.as_ref().to_path_buf(),
+            });
+        }
+        self
+    }
+
+    /// Remove the file at `rpath`, if there is one.
+    pub fn delete<P: AsRef<Path>>(mut self, rpath: P) -> Self {
+        if let Some(rpath) = self.touch(rpath.as_ref(), Role::Delete) {
+            self.changes.push(Change::Delete { rpath });
+        }
+        self
+    }
+
+    /// Move the file at `from`, which has to exist when the changeset is applied, to `to`, replacing a file
+    /// there.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(mut self, from: P, to: Q) -> Self {
+        if let (Some(from), Some(to)) = (
+            self.touch(from.as_ref(), Role::RenameFrom),
+            self.touch(to.as_ref(), Role::RenameTo),
+        ) {
+            self.changes.push(Change::Rename { from, to });
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Records that the entry at `rpath` is changed in the `role`, returning it normalized unless that
+    /// conflicts with an earlier change, or an earlier change could not be added.
+    fn touch(&mut self, rpath: &Path, role: Role) -> Option<PathBuf> {
+        if self.invalid.is_some() {
+            return None;
+        }
+        let conflict = match normalize_rpath(rpath) {
+            Err(e) => Err(e),
+            Ok(rpath) => match self.conflict(&rpath, role) {
+                Some(reason) => Err(Error::ConflictingChanges {
+                    path: rpath,
+                    reason,
+                }),
+                None => Ok(rpath),
+            },
+        };
+        match conflict {
+            Ok(rpath) => {
+                self.touched.insert(rpath.clone(), role);
+                Some(rpath)
+            }
+            Err(e) => {
+                self.invalid = Some(e);
+                None
+            }
+        }
+    }
+
+    /// Why changing the entry at `rpath` in the `role` conflicts with the changes so far, if it does.
+    fn conflict(&self, rpath: &Path, role: Role) -> Option<&'static str> {
+        if let Some(earlier) = self.touched.get(rpath) {
+            return Some(match (*earlier, role) {
+                (Role::Put, Role::Put) => "put twice",
+                (Role::Delete, Role::Delete) => "deleted twice",
+                (Role::RenameFrom, Role::RenameTo) => "renamed onto itself",
+                (Role::RenameFrom, Role::RenameFrom) => "renamed twice",
+                (Role::RenameFrom, Role::Delete) | (Role::Delete, Role::RenameFrom) => {
+                    "renamed and deleted"
+                }
+                (Role::RenameFrom, Role::Put) | (Role::Put, Role::RenameFrom) => "renamed and put",
+                _ => "changed twice",
+            });
+        }
+        if rpath
+            .ancestors()
+            .skip(1)
+            .any(|a| self.touched.contains_key(a))
+            || self.touched.keys().any(|t| t.starts_with(rpath))
+        {
+            return Some("changed along with an entry above or below it");
+        }
+        None
+    }
+
+    /// Every entry the changes touch.
+    fn rpaths(&self) -> impl Iterator<Item = &Path> {
+        self.touched.keys().map(PathBuf::as_path)
+    }
+}
+
+/// A change, ready to be moved into place.
+enum Staged {
+    /// The temporary file `tmp` is to replace the file at `path`, which `existed`.
+    Put {
+        tmp: PathBuf,
+        path: PathBuf,
+        existed: bool,
+    },
+    Delete {
+        path: PathBuf,
+        existed: bool,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        replaced: bool,
+    },
+}
+
+/// A step that puts back part of a change that was moved into place.
+enum Undo {
+    /// Move the entry at `from` back to `to`.
+    Move { from: PathBuf, to: PathBuf },
+    /// Remove the file a put created at `path`.
+    Remove { path: PathBuf },
+}
+
+impl Client {
+    /// Make all changes of `changeset` in one transaction, which write locks every entry they touch in the
+    /// same order any transaction does, see [`Client::tx`]. Puts are first written to temporary files and
+    /// renamed sources checked to exist, so that most failures leave the database as it was. The changes are
+    /// then moved into place one by one: readers may see some of them before the others, but should one
+    /// fail, those made before it are undone, and [`Error::CommitFailed`] is returned. If undoing fails too,
+    /// its `backup` is the scratch directory holding what the earlier changes replaced, which gc removes
+    /// after [`crate::SCRATCH_GRACE`]. A crash part way through is not undone.
+    ///
+    /// Changes are only made to files, changing a directory is refused with [`Error::InvalidPath`].
+    pub fn apply(&self, changeset: Changeset) -> Result<ApplyReport> {
+        self.redacted(|| {
+            if let Some(e) = changeset.invalid {
+                return Err(e);
+            }
+            let tx = changeset
+                .rpaths()
+                .fold(self.tx(), |tx, rpath| tx.write(rpath))
+                .begin()?;
+            let ctx = &tx.ctx;
+            ctx.check_writable(&self.inner.root)?;
+
+            let mut staged = Vec::with_capacity(changeset.changes.len());
+            for change in changeset.changes.iter() {
+                match self.stage_change(change, ctx) {
+                    Ok(change) => staged.push(change),
+                    Err(e) => {
+                        remove_temps(&staged, ctx);
+                        return Err(e);
+                    }
+                }
+            }
+
+            let mut charged = 0;
+            for change in staged.iter() {
+                if let Staged::Put { tmp, path, .. } = change {
+                    match ctx.charge_quota(tmp, path) {
+                        Ok(charge) => charged += charge,
+                        Err(e) => {
+                            ctx.refund_quota(charged);
+                            remove_temps(&staged, ctx);
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            let mut released = 0;
+            for change in staged.iter() {
+                match change {
+                    Staged::Delete {
+                        path,
+                        existed: true,
+                    }
+                    | Staged::Rename {
+                        to: path,
+                        replaced: true,
+                        ..
+                    } => released += quota::data_size(path),
+                    _ => {}
+                }
+            }
+            // what each change replaces, for the rollups
+            let before = staged
+                .iter()
+                .map(|change| match change {
+                    Staged::Put { path, .. } | Staged::Delete { path, .. } => {
+                        [ctx.usage_before(path), None]
+                    }
+                    Staged::Rename { from, to, .. } => [ctx.usage_before(from), ctx.usage_before(to)],
+                })
+                .collect::<Vec<_>>();
+
+            let start = Instant::now();
+            let backups = unused_child(&self.inner.root.join(INTERNAL_DIR).join(SCRATCH_DIR));
+            let committed = self.move_into_place(&staged, &backups, ctx);
+            if let Err(e) = committed {
+                ctx.refund_quota(charged);
+                remove_temps(&staged, ctx);
+                return Err(e);
+            }
+            if let Err(e) = fs::remove_dir_all(&backups)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                log::warn!(path:? = ctx.redact.path(&backups), operation = "cleanup"; "failed to remove scratch directory: {}", e);
+            }
+            ctx.release_quota(released);
+
+            let mut outcomes = Vec::with_capacity(staged.len());
+            for (change, [before, before_to]) in staged.iter().zip(before) {
+                outcomes.push(match change {
+                    Staged::Put { path, existed, .. } => {
+                        ctx.committed(CommitKind::File, path, *existed, start);
+                        ctx.roll_up(path, before);
+                        match existed {
+                            true => ChangeOutcome::Replaced,
+                            false => ChangeOutcome::Created,
+                        }
+                    }
+                    Staged::Delete { path, existed } => {
+                        if !existed {
+                            ChangeOutcome::Missing
+                        } else {
+                            ctx.bump_generation(path);
+                            ctx.replicate(ReplicatedOp::Remove, path);
+                            ctx.roll_up(path, before);
+                            ChangeOutcome::Deleted
+                        }
+                    }
+                    Staged::Rename { from, to, replaced } => {
+                        ctx.bump_generation(from);
+                        ctx.replicate(ReplicatedOp::Remove, from);
+                        ctx.roll_up(from, before);
+                        ctx.committed(CommitKind::File, to, *replaced, start);
+                        ctx.roll_up(to, before_to);
+                        ChangeOutcome::Renamed {
+                            replaced: *replaced,
+                        }
+                    }
+                });
+            }
+            tx.release()?;
+            Ok(ApplyReport { outcomes })
+        })
+    }
+
+    /// Writes the temporary file of a put, or checks what a delete or rename will find.
+    fn stage_change(&self, change: &Change, ctx: &Ctx) -> Result<Staged> {
+        Ok(match change {
+            Change::Put { rpath, bytes } => {
+                let path = self.inner.root.join(rpath);
+                let existed = file_exists(&path)?;
+                let tmp = path_hidden_with_extension(&path, artifact::TMP)?;
+                write_tmp(&path, &tmp, bytes, false, ctx)?;
+                Staged::Put { tmp, path, existed }
+            }
+            Change::PutFileFrom { rpath, external } => {
+                let path = self.inner.root.join(rpath);
+                let existed = file_exists(&path)?;
+                let tmp = path_hidden_with_extension(&path, artifact::TMP)?;
+                // left behind by an earlier commit that failed
+                let _ = ctx.vfs.remove_file(&tmp);
+                ctx.copy_new(external, &tmp, |stats| {
+                    copy_file(external, &tmp, ctx, stats)
+                })?;
+                ctx.set_artifact_permissions(&tmp, false)?;
+                Staged::Put { tmp, path, existed }
+            }
+            Change::Delete { rpath } => {
+                let path = self.inner.root.join(rpath);
+                let existed = file_exists(&path)?;
+                Staged::Delete { path, existed }
+            }
+            Change::Rename { from, to } => {
+                let from = self.inner.root.join(from);
+                if !file_exists(&from)? {
+                    return Err(Error::NotFound {
+                        operation: "rename",
+                        path: from,
+                    });
+                }
+                let to = self.inner.root.join(to);
+                let replaced = file_exists(&to)?;
+                Staged::Rename { from, to, replaced }
+            }
+        })
+    }
+
+    /// Moves the `staged` changes into place, and what they replace into the directory `backups`, undoing
+    /// the changes already made if one fails.
+    fn move_into_place(&self, staged: &[Staged], backups: &Path, ctx: &Ctx) -> Result<()> {
+        if let Some(generations) = &ctx.generations {
+            for change in staged {
+                match change {
+                    Staged::Put { path, .. } | Staged::Delete { path, .. } => {
+                        generations.bump(path)?
+                    }
+                    Staged::Rename { from, to, .. } => {
+                        generations.bump(from)?;
+                        generations.bump(to)?;
+                    }
+                }
+            }
+        }
+        let mut undo = Vec::new();
+        let mut made = Ok(());
+        for (i, change) in staged.iter().enumerate() {
+            made = self.move_one(change, &backups.join(i.to_string()), &mut undo, ctx);
+            if made.is_err() {
+                break;
+            }
+        }
+        let Err(source) = made else {
+            return Ok(());
+        };
+        let mut backup = None;
+        for step in undo.into_iter().rev() {
+            let undone = match &step {
+                Undo::Move { from, to } => ctx.vfs.rename(from, to),
+                Undo::Remove { path } => ctx.vfs.remove_file(path),
+            };
+            if let Err(e) = undone {
+                log::warn!(operation = "rollback"; "failed to undo change: {}", e);
+                backup = Some(backups.to_path_buf());
+            }
+        }
+        if backup.is_none() {
+            let _ = fs::remove_dir_all(backups);
+        }
+        Err(Error::CommitFailed {
+            backup,
+            source: Box::new(source),
+        })
+    }
+
+    /// Moves one staged change into place, with `backup` the path to move what it replaces to, and records
+    /// how to undo each step that succeeded in `undo`.
+    fn move_one(
+        &self,
+        change: &Staged,
+        backup: &Path,
+        undo: &mut Vec<Undo>,
+        ctx: &Ctx,
+    ) -> Result<()> {
+        let (src, dst, replaced) = match change {
+            Staged::Put { tmp, path, existed } => (Some(tmp), path, *existed),
+            Staged::Delete { path, existed } => (None, path, *existed),
+            Staged::Rename { from, to, replaced } => (Some(from), to, *replaced),
+        };
+        if replaced {
+            let backups = backup.parent().unwrap_or(backup);
+            fs::create_dir_all(backups).at("create directory", backups)?;
+            ctx.vfs.rename(dst, backup).at("move aside", dst)?;
+            undo.push(Undo::Move {
+                from: backup.to_path_buf(),
+                to: dst.clone(),
+            });
+        }
+        let Some(src) = src else {
+            return Ok(());
+        };
+        ctx.vfs.rename(src, dst).at("commit change to", dst)?;
+        undo.push(match change {
+            Staged::Rename { from, to, .. } => Undo::Move {
+                from: to.clone(),
+                to: from.clone(),
+            },
+            _ => Undo::Remove { path: dst.clone() },
+        });
+        Ok(())
+    }
+}
+
+/// Whether there is a file at `path`, failing if it is a directory.
+fn file_exists(path: &Path) -> Result<bool> {
+    match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::io("read metadata of", path, e)),
+        Ok(_) if fs::metadata(path).is_ok_and(|m| m.is_dir()) => Err(Error::invalid_path(
+            path,
+            "is a directory, changesets only change files",
+        )),
+        Ok(_) => Ok(true),
+    }
+}
+
+/// Removes the temporary files of puts that were not moved into place.
+fn remove_temps(staged: &[Staged], ctx: &Ctx) {
+    for change in staged {
+        if let Staged::Put { tmp, .. } = change {
+            let _ = ctx.vfs.remove_file(tmp);
+        }
+    }
+}
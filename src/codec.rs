@@ -0,0 +1,152 @@
+//! Binary value storage. Every file starts with a small header made of the codec's magic bytes and
+//! format version, so that a file written by one codec is rejected by another instead of being
+//! misinterpreted.
+
+use std::path::Path;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{Client, Error, FileReadGaurd, Result, Tx, file_replace_with, read_value_file};
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A serialization format for values stored by [`Client::write_value`] and friends.
+pub trait ValueCodec {
+    /// Identifies files written by this codec.
+    const MAGIC: [u8; 4];
+    /// Bumped whenever the encoding changes incompatibly.
+    const VERSION: u8;
+
+    fn encode<T: Serialize>(value: &T) -> std::result::Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> std::result::Result<T, CodecError>;
+
+    /// Decode a value from the start of `bytes`, returning it along with how many bytes it took, which
+    /// [`crate::Collection::iter_streaming`] needs to find where a record's value begins. Fails by default,
+    /// for codecs that can not tell where a value ends.
+    fn decode_prefix<T: DeserializeOwned>(
+        bytes: &[u8],
+    ) -> std::result::Result<(T, usize), CodecError> {
+        let _ = bytes;
+        Err("the codec can not decode a prefix".into())
+    }
+}
+
+/// The default codec, based on `postcard`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Postcard;
+
+impl ValueCodec for Postcard {
+    const MAGIC: [u8; 4] = *b"SBPC";
+    const VERSION: u8 = 1;
+
+    fn encode<T: Serialize>(value: &T) -> std::result::Result<Vec<u8>, CodecError> {
+        Ok(postcard::to_stdvec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> std::result::Result<T, CodecError> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+
+    fn decode_prefix<T: DeserializeOwned>(
+        bytes: &[u8],
+    ) -> std::result::Result<(T, usize), CodecError> {
+        let (value, rest) = postcard::take_from_bytes(bytes)?;
+        Ok((value, bytes.len() - rest.len()))
+    }
+}
+
+pub(crate) const HEADER_LEN: usize = 5;
+
+pub(crate) fn encode<C: ValueCodec, T: Serialize>(path: &Path, value: &T) -> Result<Vec<u8>> {
+    let payload = C::encode(value).map_err(|source| Error::Encode {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&C::MAGIC);
+    bytes.push(C::VERSION);
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Fails with [`Error::WrongFormat`] unless `bytes` start with the header of `C`.
+pub(crate) fn check_header<C: ValueCodec>(path: &Path, bytes: &[u8]) -> Result<()> {
+    if bytes.len() < HEADER_LEN || bytes[..4] != C::MAGIC || bytes[4] != C::VERSION {
+        return Err(Error::WrongFormat {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn decode<C: ValueCodec, T: DeserializeOwned>(path: &Path, bytes: &[u8]) -> Result<T> {
+    check_header::<C>(path, bytes)?;
+    C::decode(&bytes[HEADER_LEN..]).map_err(|source| Error::Decode {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+pub(crate) fn from_file<C: ValueCodec, T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = read_value_file(path)?;
+    decode::<C, T>(path, &bytes)
+}
+
+impl Client {
+    pub fn read_bin<T: DeserializeOwned, P: AsRef<Path>>(&self, rpath: P) -> Result<T> {
+        self.redacted(|| self.read_value::<Postcard, T, P>(rpath))
+    }
+
+    /// Atomically replace the file at `rpath` with `value`, creating it if it does not exist yet.
+    pub fn write_bin<T: Serialize, P: AsRef<Path>>(&self, rpath: P, value: &T) -> Result<()> {
+        self.redacted(|| self.write_value::<Postcard, T, P>(rpath, value))
+    }
+
+    pub fn read_value<C: ValueCodec, T: DeserializeOwned, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+    ) -> Result<T> {
+        self.redacted(|| self.read_file(rpath)?.value::<C, T>())
+    }
+
+    pub fn write_value<C: ValueCodec, T: Serialize, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        value: &T,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            let bytes = encode::<C, T>(&gaurd.path, value)?;
+            file_replace_with(&gaurd.path, &bytes, false, gaurd.ctx.clone())
+        })
+    }
+}
+
+impl FileReadGaurd {
+    pub fn value<C: ValueCodec, T: DeserializeOwned>(&self) -> Result<T> {
+        self.check_revoked()?;
+        from_file::<C, T>(&self.path)
+    }
+}
+
+impl Tx {
+    /// Read a value from a path that was declared as either a read or a write of this transaction.
+    pub fn read_value<C: ValueCodec, T: DeserializeOwned, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+    ) -> Result<T> {
+        self.check_read(rpath.as_ref())?;
+        from_file::<C, T>(&self.root.join(rpath))
+    }
+
+    pub fn write_value<C: ValueCodec, T: Serialize, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        value: &T,
+    ) -> Result<()> {
+        self.check_write(rpath.as_ref())?;
+        let path = self.root.join(rpath);
+        let bytes = encode::<C, T>(&path, value)?;
+        file_replace_with(&path, &bytes, false, self.ctx.clone())
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Chain, Cursor, Read},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    Client, Ctx, DbExclusiveGaurd, DirReadGaurd, Error, FileReadGaurd, Postcard, ReplicatedOp,
+    Result, ValueCodec, check_unreserved, codec, error::IoResultExt, file_replace_with, key, quota,
+};
+
+/// Longest start of a record that is read to find its key, so that a corrupt record is not read whole.
+const MAX_KEY_PREFIX_LEN: usize = 1 << 20;
+
+/// A directory where every file holds one record, named after its key as encoded by [`crate::key`].
+/// Each operation locks only the records it touches, so operations on different keys do not contend.
+///
+/// Records store their key next to the value, since overlong keys cannot be recovered from file names.
+pub struct Collection<T, C = Postcard> {
+    client: Client,
+    rpath: PathBuf,
+    _marker: PhantomData<fn() -> (T, C)>,
+}
+
+impl Client {
+    /// Open the collection stored in the directory `rpath`. The directory is created by the first write.
+    pub fn collection<T: Serialize + DeserializeOwned, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+    ) -> Collection<T> {
+        Collection::new(self.clone(), rpath)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: ValueCodec> Collection<T, C> {
+    pub fn new<P: AsRef<Path>>(client: Client, rpath: P) -> Self {
+        Self {
+            client,
+            rpath: rpath.as_ref().to_path_buf(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<T>> {
+        let rpath = self.record_rpath(key);
+        if !self.client.root().join(&self.rpath).is_dir() {
+            return Ok(None);
+        }
+        let gaurd = self.client.read_file(&rpath)?;
+        Ok(read_record::<T, C>(&gaurd.path)?.map(|(_, value)| value))
+    }
+
+    pub fn put(&self, key: &str, value: &T) -> Result<()> {
+        let rpath = self.record_rpath(key);
+        self.create_dir()?;
+        let gaurd = self.client.write_file(&rpath)?;
+        write_record::<T, C>(&gaurd.path, key, value, gaurd.ctx.clone())
+    }
+
+    /// Remove the record stored under `key`, returning whether it existed.
+    pub fn remove(&self, key: &str) -> Result<bool> {
+        let rpath = self.record_rpath(key);
+        if !self.client.root().join(&self.rpath).is_dir() {
+            return Ok(false);
+        }
+        let gaurd = self.client.write_file(&rpath)?;
+        remove_record(&gaurd.path, &gaurd.ctx)
+    }
+
+    /// Read-modify-write the record stored under `key` while holding its write lock. Returning `None`
+    /// from `f` removes the record.
+    pub fn update<F: FnOnce(Option<T>) -> Option<T>>(&self, key: &str, f: F) -> Result<()> {
+        let rpath = self.record_rpath(key);
+        self.create_dir()?;
+        let gaurd = self.client.write_file(&rpath)?;
+        let current = read_record::<T, C>(&gaurd.path)?.map(|(_, value)| value);
+        match f(current) {
+            Some(value) => write_record::<T, C>(&gaurd.path, key, &value, gaurd.ctx.clone()),
+            None => remove_record(&gaurd.path, &gaurd.ctx).map(|_| ()),
+        }
+    }
+
+    /// Iterate over all records. The collection directory stays read locked until the iterator is
+    /// dropped, and each record is read under its own read lock.
+    pub fn iter(&self) -> Result<CollectionIter<'_, T, C>> {
+        let (gaurd, names) = self.list()?;
+        Ok(CollectionIter {
+            _collection: PhantomData,
+            gaurd,
+            names: names.into_iter(),
+        })
+    }
+
+    /// Iterate over all records like [`Collection::iter`], yielding a reader of each value rather than the
+    /// value, so that values too large to hold in memory can be read. Readers yield the value as encoded by
+    /// `C`, which for [`Postcard`] encodes bytes as their varint length followed by the bytes, through a
+    /// buffer of [`crate::ClientBuilder::stream_buffer_len`] bytes.
+    ///
+    /// The collection directory stays read locked until the iterator is dropped. Each record's read lock
+    /// is taken when it is yielded and held by its reader, so a record can be written again as soon as its
+    /// reader is dropped, while readers that are kept around keep their records locked.
+    pub fn iter_streaming(&self) -> Result<CollectionStreamIter<'_, T, C>> {
+        let (gaurd, names) = self.list()?;
+        Ok(CollectionStreamIter {
+            _collection: PhantomData,
+            gaurd,
+            names: names.into_iter(),
+            buffer_len: self.client.inner.stream_buffer_len,
+        })
+    }
+
+    /// The read locked collection directory and the names of its records, nothing if it does not exist.
+    fn list(&self) -> Result<(Option<DirReadGaurd>, Vec<String>)> {
+        if !self.client.root().join(&self.rpath).is_dir() {
+            return Ok((None, Vec::new()));
+        }
+        let gaurd = self.client.read_dir(&self.rpath)?;
+        let names = record_names(&gaurd.path)?;
+        Ok((Some(gaurd), names))
+    }
+
+    fn create_dir(&self) -> Result<()> {
+        check_unreserved(self.client.root(), &self.rpath)?;
+        let dir = self.client.root().join(&self.rpath);
+        fs::create_dir_all(&dir).at("create directory", &dir)
+    }
+
+    fn record_rpath(&self, key: &str) -> PathBuf {
+        self.rpath.join(key::encode(key))
+    }
+}
+
+pub struct CollectionIter<'a, T, C> {
+    /// Records are read relative to `gaurd`, the collection only has to outlive the iterator.
+    _collection: PhantomData<&'a Collection<T, C>>,
+    gaurd: Option<DirReadGaurd>,
+    names: std::vec::IntoIter<String>,
+}
+
+impl<T: Serialize + DeserializeOwned, C: ValueCodec> Iterator for CollectionIter<'_, T, C> {
+    type Item = Result<(String, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let name = self.names.next()?;
+            // below the directory's lock, which taking the ancestors' locks again could deadlock on
+            let record = self
+                .gaurd
+                .as_ref()?
+                .read_file(&name)
+                .and_then(|gaurd| read_record::<T, C>(&gaurd.path));
+            match record {
+                Ok(Some(record)) => return Some(Ok(record)),
+                // removed since the directory was listed
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+pub struct CollectionStreamIter<'a, T, C> {
+    /// Records are read relative to `gaurd`, the collection only has to outlive the iterator.
+    _collection: PhantomData<&'a Collection<T, C>>,
+    gaurd: Option<DirReadGaurd>,
+    names: std::vec::IntoIter<String>,
+    buffer_len: usize,
+}
+
+impl<T, C: ValueCodec> Iterator for CollectionStreamIter<'_, T, C> {
+    type Item = Result<(String, RecordReader)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let name = self.names.next()?;
+            // below the directory's lock, which taking the ancestors' locks again could deadlock on
+            let record = self
+                .gaurd
+                .as_ref()?
+                .read_file(&name)
+                .and_then(|gaurd| open_record::<C>(gaurd, self.buffer_len));
+            match record {
+                Ok(Some(record)) => return Some(Ok(record)),
+                // removed since the directory was listed
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Reader of the value of a record, see [`Collection::iter_streaming`]. The record stays read locked until
+/// it is dropped.
+pub struct RecordReader {
+    /// The part of the value that was read along with the key, followed by the rest of the file.
+    reader: BufReader<Chain<Cursor<Vec<u8>>, File>>,
+    _gaurd: FileReadGaurd,
+}
+
+impl Read for RecordReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl BufRead for RecordReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount)
+    }
+}
+
+impl DbExclusiveGaurd {
+    /// Store every record of `collection` under the key that `f` maps its key to, returning how many records
+    /// moved. The records are moved in a copy of the collection's directory that is committed as a whole, see
+    /// [`crate::DirWriteGaurd::cow`], so the collection is never seen with only some of them moved. Fails with
+    /// [`Error::AlreadyExists`] before anything is committed if two records would be stored under the same
+    /// key.
+    pub fn rekey_collection<T, C, F>(
+        &self,
+        collection: &Collection<T, C>,
+        mut f: F,
+    ) -> Result<usize>
+    where
+        T: Serialize + DeserializeOwned,
+        C: ValueCodec,
+        F: FnMut(&str) -> String,
+    {
+        let client = &self.client;
+        if !client.root().join(&collection.rpath).is_dir() {
+            return Ok(0);
+        }
+        let gaurd = client.write_dir(&collection.rpath)?;
+        let cow = gaurd.cow()?;
+        let mut names = HashSet::new();
+        let mut moves = Vec::new();
+        for name in record_names(&cow.path)? {
+            let Some((key, value)) = read_record::<T, C>(&cow.path.join(&name))? else {
+                continue;
+            };
+            let new_key = f(&key);
+            let new_name = key::encode(&new_key);
+            if !names.insert(new_name.clone()) {
+                return Err(Error::AlreadyExists {
+                    path: gaurd.path.join(new_name),
+                });
+            }
+            if new_key != key {
+                moves.push((name, new_name, new_key, value));
+            }
+        }
+        // every record is removed before any is written, one may move to the name another moves away from
+        for (name, ..) in moves.iter() {
+            let path = cow.path.join(name);
+            fs::remove_file(&path).at("remove", &path)?;
+        }
+        for (_, new_name, new_key, value) in moves.iter() {
+            let path = cow.path.join(new_name);
+            let bytes = codec::encode::<C, _>(&path, &(new_key, value))?;
+            fs::write(&path, bytes).at("write", &path)?;
+        }
+        cow.commit()?;
+        Ok(moves.len())
+    }
+}
+
+/// Sorted names of the records in the collection directory at `dir`.
+fn record_names(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir).at("read directory", dir)? {
+        let entry = entry.at("read directory", dir)?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        // locks and temporaries are hidden, encoded keys never are
+        if name.starts_with('.') || !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        names.push(name);
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Opens the record locked by `gaurd` and reads its key, `None` if it was removed since the directory was
+/// listed. Only the start of the record is read, growing until the key can be decoded from it.
+fn open_record<C: ValueCodec>(
+    gaurd: FileReadGaurd,
+    buffer_len: usize,
+) -> Result<Option<(String, RecordReader)>> {
+    gaurd.check_revoked()?;
+    let path = gaurd.path.clone();
+    let mut file = match File::open(&path).at("open", &path) {
+        Ok(file) => file,
+        Err(Error::NotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut prefix = Vec::new();
+    let mut chunk_len = 256;
+    loop {
+        let read = (&mut file)
+            .take(chunk_len as u64)
+            .read_to_end(&mut prefix)
+            .at("read", &path)?;
+        let eof = read < chunk_len;
+        codec::check_header::<C>(&path, &prefix)?;
+        match C::decode_prefix::<String>(&prefix[codec::HEADER_LEN..]) {
+            Ok((key, len)) => {
+                let value = prefix.split_off(codec::HEADER_LEN + len);
+                let reader = BufReader::with_capacity(buffer_len, Cursor::new(value).chain(file));
+                return Ok(Some((
+                    key,
+                    RecordReader {
+                        reader,
+                        _gaurd: gaurd,
+                    },
+                )));
+            }
+            Err(source) if eof || prefix.len() >= MAX_KEY_PREFIX_LEN => {
+                return Err(Error::Decode { path, source });
+            }
+            // the key goes on past what was read so far
+            Err(_) => chunk_len = prefix.len(),
+        }
+    }
+}
+
+fn read_record<T: DeserializeOwned, C: ValueCodec>(path: &Path) -> Result<Option<(String, T)>> {
+    match codec::from_file::<C, (String, T)>(path) {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_record<T: Serialize, C: ValueCodec>(
+    path: &Path,
+    key: &str,
+    value: &T,
+    ctx: Ctx,
+) -> Result<()> {
+    let bytes = codec::encode::<C, _>(path, &(key, value))?;
+    file_replace_with(path, &bytes, false, ctx)
+}
+
+fn remove_record(path: &Path, ctx: &Ctx) -> Result<bool> {
+    ctx.check_writable(path)?;
+    let bytes = quota::data_size(path);
+    let before = ctx.usage_before(path);
+    match fs::remove_file(path).at("remove", path) {
+        Ok(()) => {
+            ctx.release_quota(bytes);
+            ctx.replicate(ReplicatedOp::Remove, path);
+            ctx.roll_up(path, before);
+            Ok(true)
+        }
+        Err(Error::NotFound { .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
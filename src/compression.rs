@@ -0,0 +1,118 @@
+//! Transparent zstd compression of stored values. Compressed files start with a header holding a magic
+//! number, the algorithm and the uncompressed length, followed by the compressed frame. Files without
+//! the header are returned as is, so compressed and uncompressed values can live side by side.
+
+use std::{fs, path::Path};
+
+use crate::{
+    Client, Error, FileReadGaurd, FileWriteGaurd, Result, error::IoResultExt, file_replace_with,
+};
+
+/// Largest uncompressed length that will be decompressed, protecting readers from decompression bombs.
+pub const MAX_DECOMPRESSED_LEN: u64 = 1 << 30;
+
+const MAGIC: [u8; 4] = *b"SBZ\0";
+const ZSTD: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+pub(crate) fn compress(path: &Path, bytes: &[u8], level: i32) -> Result<Vec<u8>> {
+    let frame = zstd::bulk::compress(bytes, level).map_err(|e| Error::Encode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })?;
+    let mut result = Vec::with_capacity(HEADER_LEN + frame.len());
+    result.extend_from_slice(&MAGIC);
+    result.push(ZSTD);
+    result.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    result.extend_from_slice(&frame);
+    Ok(result)
+}
+
+/// Returns `bytes` unchanged unless they start with the compression header.
+pub(crate) fn decompress(path: &Path, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Ok(bytes);
+    }
+    let corrupt = |source: Box<dyn std::error::Error + Send + Sync>| Error::Decode {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    let (header, frame) = bytes.split_at(HEADER_LEN);
+    if header[MAGIC.len()] != ZSTD {
+        return Err(corrupt("unknown compression algorithm".into()));
+    }
+    let len = u64::from_le_bytes(header[MAGIC.len() + 1..].try_into().unwrap());
+    if len > MAX_DECOMPRESSED_LEN {
+        return Err(corrupt(
+            format!("decompressed length {len} exceeds the limit of {MAX_DECOMPRESSED_LEN}").into(),
+        ));
+    }
+    // the capacity bounds the output, so a frame lying about its length cannot exhaust memory
+    let result = zstd::bulk::decompress(frame, len as usize).map_err(|e| corrupt(Box::new(e)))?;
+    if result.len() as u64 != len {
+        return Err(corrupt("decompressed length does not match header".into()));
+    }
+    Ok(result)
+}
+
+impl Client {
+    /// Atomically replace the file at `rpath` with `bytes` compressed at the given zstd `level`,
+    /// creating it if it does not exist yet.
+    pub fn write_compressed<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        bytes: &[u8],
+        level: i32,
+    ) -> Result<()> {
+        self.redacted(|| self.write_file(rpath)?.write_compressed(bytes, level))
+    }
+
+    /// Read the file at `rpath`, decompressing it if it was written by [`Client::write_compressed`].
+    pub fn read_maybe_compressed<P: AsRef<Path>>(&self, rpath: P) -> Result<Vec<u8>> {
+        self.redacted(|| self.read_file(rpath)?.read_maybe_compressed())
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn write_json_compressed<T: serde::Serialize, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        value: &T,
+        level: i32,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            let bytes = crate::json::to_vec(&gaurd.path, value, &Default::default())?;
+            gaurd.write_compressed(&bytes, level)
+        })
+    }
+
+    #[cfg(feature = "binary")]
+    pub fn write_bin_compressed<T: serde::Serialize, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        value: &T,
+        level: i32,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            let bytes = crate::codec::encode::<crate::Postcard, T>(&gaurd.path, value)?;
+            gaurd.write_compressed(&bytes, level)
+        })
+    }
+}
+
+impl FileWriteGaurd {
+    pub fn write_compressed(&self, bytes: &[u8], level: i32) -> Result<()> {
+        let compressed = compress(&self.path, bytes, level)?;
+        file_replace_with(&self.path, &compressed, false, self.ctx.clone())
+    }
+}
+
+impl FileReadGaurd {
+    pub fn read_maybe_compressed(&self) -> Result<Vec<u8>> {
+        self.check_revoked()?;
+        let bytes = fs::read(&self.path).at("read", &self.path)?;
+        decompress(&self.path, bytes)
+    }
+}
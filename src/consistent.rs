@@ -0,0 +1,68 @@
+//! Reading several entries as of the same moment, see [`Client::read_consistent`]. Each entry read on its
+//! own can be from before a transaction that changes several of them, and the next from after it, while
+//! holding all of their locks at once excludes such a transaction until every entry was read.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    Client, FileReadGaurd, Lock, ReadLock, Result, check_lock_order, error::IoResultExt,
+    normalize_rpath,
+};
+
+impl Client {
+    /// Read lock the entries at `rpaths` together and return a guard for each of them, in the same order.
+    /// The locks are taken in the order a [`TxBuilder::begin`](crate::TxBuilder::begin) would take them,
+    /// so that a transaction that writes any of the entries either commits before all of them are read or
+    /// waits until all of the guards are dropped. An entry that appears more than once, or lies above
+    /// another, is locked only once, and its lock is released along with the last guard that relies on it.
+    pub fn read_consistent<P: AsRef<Path>>(&self, rpaths: &[P]) -> Result<Vec<FileReadGaurd>> {
+        self.redacted(|| {
+            let rpaths = rpaths
+                .iter()
+                .map(|rpath| normalize_rpath(rpath.as_ref()))
+                .collect::<Result<Vec<_>>>()?;
+            let root = &self.inner.root;
+            let ctx = self.ctx();
+            let entries: BTreeSet<&Path> =
+                rpaths.iter().flat_map(|rpath| rpath.ancestors()).collect();
+            let mut locks = BTreeMap::new();
+            for entry in entries {
+                check_lock_order(root, entry)?;
+                let lock = Lock::Read(ReadLock::acquire(root, entry, &ctx)?);
+                locks.insert(entry, Arc::new(lock));
+            }
+            Ok(rpaths
+                .iter()
+                .map(|rpath| FileReadGaurd {
+                    path: root.join(rpath),
+                    lock: Vec::new(),
+                    shared: rpath.ancestors().map(|anc| locks[anc].clone()).collect(),
+                })
+                .collect())
+        })
+    }
+
+    /// Read the files at `rpaths` as of the same moment, see [`Client::read_consistent`], releasing their
+    /// locks before returning.
+    pub fn read_all_to_vec<P: AsRef<Path>>(&self, rpaths: &[P]) -> Result<Vec<Vec<u8>>> {
+        self.redacted(|| {
+            let gaurds = self.read_consistent(rpaths)?;
+            let contents = gaurds
+                .iter()
+                .map(|gaurd| {
+                    gaurd.check_revoked()?;
+                    fs::read(&gaurd.path).at("read", &gaurd.path)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for gaurd in gaurds {
+                gaurd.release()?;
+            }
+            Ok(contents)
+        })
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{Client, Error, Result, file_replace_with};
+
+/// A number stored in a single file, for handing out ids that are unique across threads and processes.
+/// The file holds the last value handed out as decimal text and is missing until the first one is.
+///
+/// Every change is made under the file's write lock and flushed to disk before it returns, so a value is
+/// never handed out twice, even across a crash.
+#[derive(Clone, Debug)]
+pub struct Counter {
+    client: Client,
+    rpath: PathBuf,
+}
+
+impl Client {
+    /// Open the counter stored in the file at `rpath`.
+    pub fn counter<P: AsRef<Path>>(&self, rpath: P) -> Counter {
+        Counter {
+            client: self.clone(),
+            rpath: rpath.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Counter {
+    /// The last value handed out, zero if there was none yet.
+    pub fn get(&self) -> Result<u64> {
+        let gaurd = self.client.read_file(&self.rpath)?;
+        read(&gaurd.path)
+    }
+
+    /// Hand out the next value, starting at one.
+    pub fn next(&self) -> Result<u64> {
+        self.add(1)
+    }
+
+    /// Advance the counter by `n` and return the new value.
+    pub fn add(&self, n: u64) -> Result<u64> {
+        Ok(self.next_batch(n)?.end - 1)
+    }
+
+    /// Reserve the next `n` values at once, they are the returned range. Values of a batch that are not
+    /// used are skipped for good.
+    pub fn next_batch(&self, n: u64) -> Result<Range<u64>> {
+        let gaurd = self.client.write_file(&self.rpath)?;
+        let current = read(&gaurd.path)?;
+        let end = current
+            .checked_add(n)
+            .and_then(|last| last.checked_add(1))
+            .ok_or_else(|| Error::CounterOverflow {
+                path: gaurd.path.clone(),
+            })?;
+        file_replace_with(
+            &gaurd.path,
+            format!("{}\n", end - 1).as_bytes(),
+            true,
+            gaurd.ctx.clone(),
+        )?;
+        Ok(current + 1..end)
+    }
+}
+
+fn read(path: &Path) -> Result<u64> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            return Err(Error::Decode {
+                path: path.to_path_buf(),
+                source: Box::new(e),
+            });
+        }
+        Err(e) => return Err(Error::io("read counter", path, e)),
+    };
+    text.trim().parse().map_err(|e| Error::Decode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })
+}
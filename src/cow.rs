@@ -0,0 +1,845 @@
+//! Copy-on-write guards, which prepare a change in a copy of an entry and replace the entry with it when
+//! committed, see [`FileWriteGaurd::cow`] and friends, and the copying they rely on.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Weak,
+};
+
+use crate::{
+    ArtifactKind, CommitKind, Ctx, Deadline, Error, Result, Vfs, VisitedDirs, artifact,
+    check_depth, check_nested, check_unreserved, create_backup_ext, deadline, error::IoResultExt,
+    is_atomic_dir_link, path_hidden_with_extension, puuid, reflink, strip_trailing_slash,
+    unused_hidden_path,
+};
+
+#[derive(Default)]
+pub(crate) struct CopyStats {
+    pub(crate) files: u64,
+    pub(crate) bytes: u64,
+    /// Of `bytes`, those that were copied rather than reflinked.
+    pub(crate) copied: u64,
+    /// Filesystem of the directory that was last copied into.
+    pub(crate) device: Option<(PathBuf, Option<u64>)>,
+    /// First copy of every file with several names, by device and inode of the original.
+    pub(crate) hard_links: HashMap<(u64, u64), PathBuf>,
+    pub(crate) deadline: Option<Deadline>,
+}
+
+impl CopyStats {
+    /// Filesystem that `dst` is copied to, looked up once per directory.
+    fn device_of(&mut self, dst: &Path) -> Option<u64> {
+        let dir = dst.parent()?;
+        match &self.device {
+            Some((cached, device)) if cached == dir => *device,
+            _ => {
+                let device = reflink::device(dir);
+                self.device = Some((dir.to_path_buf(), device));
+                device
+            }
+        }
+    }
+}
+
+/// Writes of up to this many bytes are first made to an unnamed file, see [`write_tmpfile`].
+pub(crate) const TMPFILE_MAX_LEN: usize = 1 << 20;
+
+/// Replaces the contents of `orig` with `bytes` through a temporary file, so readers never observe a
+/// partially written file. Unlike [`file_cow`], `orig` does not need to exist.
+pub(crate) fn file_replace_with(orig: &Path, bytes: &[u8], sync: bool, ctx: Ctx) -> Result<()> {
+    let path = path_hidden_with_extension(orig, artifact::TMP)?;
+    write_tmp(orig, &path, bytes, sync, &ctx)?;
+    let vfs = ctx.vfs.clone();
+    let committed = CowFileGaurd {
+        path: path.clone(),
+        orig: orig.to_path_buf(),
+        ctx,
+    }
+    .commit();
+    if committed.is_err() {
+        // nothing else knows about the temporary file, e.g. when the commit was over the quota
+        let _ = vfs.remove_file(&path);
+    }
+    committed?;
+    #[cfg(unix)]
+    if sync && let Some(parent) = orig.parent() {
+        // the rename is only durable once the directory entry is
+        File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .at("sync", parent)?;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to the temporary file at `path` that is to replace `orig`, within the client's
+/// [deadline](ClientBuilder::io_deadline).
+pub(crate) fn write_tmp(
+    orig: &Path,
+    path: &Path,
+    bytes: &[u8],
+    sync: bool,
+    ctx: &Ctx,
+) -> Result<()> {
+    ctx.preflight(path, bytes.len() as u64)?;
+    let deadline = ctx.io_deadline.map(Deadline::start);
+    if bytes.len() > TMPFILE_MAX_LEN
+        || !write_tmpfile(orig, path, bytes, sync, deadline.as_ref(), ctx)?
+    {
+        let file = File::create(path).at("create", path)?;
+        if let Some(permissions) = ctx.artifact_permissions(false) {
+            file.set_permissions(permissions)
+                .at("set permissions of", path)?;
+        }
+        // a partial write, e.g. one that ran out of time or space, is not left behind
+        let written =
+            deadline::write(&file, path, bytes, deadline.as_ref()).and_then(|()| match sync {
+                true => file.sync_all().at("sync", path),
+                false => Ok(()),
+            });
+        if written.is_err() {
+            let _ = ctx.vfs.remove_file(path);
+        }
+        written?;
+    }
+    Ok(())
+}
+
+/// Flushes every file and directory below `path` to disk.
+pub(crate) fn sync_tree(path: &Path) -> Result<()> {
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).at("read directory", &dir)? {
+            let entry = entry.at("read directory", &dir)?;
+            let file_type = entry.file_type().at("read metadata of", entry.path())?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                File::open(entry.path())
+                    .and_then(|file| file.sync_all())
+                    .at("sync", entry.path())?;
+            }
+        }
+        #[cfg(unix)]
+        File::open(&dir)
+            .and_then(|dir| dir.sync_all())
+            .at("sync", &dir)?;
+    }
+    Ok(())
+}
+
+/// Flushes the directory containing `path` to disk, which makes a rename to `path` durable.
+pub(crate) fn sync_parent(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .at("sync", parent)?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Writes `bytes` to an unnamed file in the directory of `orig` and only then links it at the temporary
+/// `path`, so the temporary file is never seen partially written and a crash while writing leaves nothing
+/// behind. Returns false if unnamed files are not available, in which case nothing was written.
+fn write_tmpfile(
+    orig: &Path,
+    path: &Path,
+    bytes: &[u8],
+    sync: bool,
+    deadline: Option<&Deadline>,
+    ctx: &Ctx,
+) -> Result<bool> {
+    let dir = match orig.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // any error opening it is reported again by the named file, which also covers missing directories
+    let Ok(file) = ctx.vfs.open_tmpfile(dir) else {
+        return Ok(false);
+    };
+    if let Some(permissions) = ctx.artifact_permissions(false) {
+        file.set_permissions(permissions)
+            .at("set permissions of", path)?;
+    }
+    // the file is gone once it is closed, should the deadline pass
+    deadline::write(&file, path, bytes, deadline)?;
+    if sync {
+        file.sync_all().at("sync", path)?;
+    }
+    let linked = match ctx.vfs.link_tmpfile(&file, path) {
+        // left behind by an earlier write that failed
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            ctx.vfs.remove_file(path).at("remove", path)?;
+            ctx.vfs.link_tmpfile(&file, path)
+        }
+        linked => linked,
+    };
+    Ok(linked.is_ok())
+}
+
+/// Reads a file written by one of the value helpers, undoing compression if it was applied.
+#[cfg(any(feature = "serde_json", feature = "binary"))]
+pub(crate) fn read_value_file(path: &Path) -> Result<Vec<u8>> {
+    let bytes = fs::read(path).at("read", path)?;
+    #[cfg(feature = "compression")]
+    let bytes = crate::compression::decompress(path, bytes)?;
+    Ok(bytes)
+}
+
+/// Fails unless this process holds a write lock on `path` or one of its ancestors, with the `strict-locking`
+/// feature. Copies of private copies, and the free functions in [`unlocked`], are never checked.
+pub(crate) fn check_locked(path: &Path) -> Result<()> {
+    #[cfg(feature = "strict-locking")]
+    crate::strict::check(path)?;
+    #[cfg(not(feature = "strict-locking"))]
+    let _ = path;
+    Ok(())
+}
+
+/// Copy the file at `orig` without locking it. With the `strict-locking` feature this process has to hold a
+/// write lock covering it, see [`unlocked::file_cow_unchecked`](crate::unlocked::file_cow_unchecked)
+/// otherwise.
+pub fn file_cow<P: AsRef<Path>>(orig: P) -> Result<CowFileGaurd> {
+    check_locked(orig.as_ref())?;
+    file_cow_with(orig, Ctx::detached())
+}
+
+pub(crate) fn file_cow_with<P: AsRef<Path>>(orig: P, ctx: Ctx) -> Result<CowFileGaurd> {
+    let path = path_hidden_with_extension(&orig, artifact::TMP)?;
+    ctx.copy_new(orig.as_ref(), &path, |stats| {
+        copy_file(orig.as_ref(), &path, &ctx, stats)
+    })?;
+    ctx.set_artifact_permissions(&path, false)?;
+    Ok(CowFileGaurd {
+        path,
+        orig: orig.as_ref().to_path_buf(),
+        ctx,
+    })
+}
+
+/// Can be sent to and committed on another thread, see [`Tx`](crate::Tx).
+pub struct CowFileGaurd {
+    pub path: PathBuf,
+    pub(crate) orig: PathBuf,
+    pub(crate) ctx: Ctx,
+}
+
+impl CowFileGaurd {
+    /// If the client keeps versions of `orig`, the file being replaced is linked into the versions area
+    /// first, and removed from it again should the commit fail.
+    pub fn commit(self) -> Result<()> {
+        let result = self
+            .ctx
+            .commit(CommitKind::File, &self.orig, || self.commit_inner());
+        self.ctx.discard_on_no_space(&self.path, &result);
+        result
+    }
+
+    fn commit_inner(&self) -> Result<()> {
+        let charged = self.ctx.charge_quota(&self.path, &self.orig)?;
+        let versioning = self.ctx.versioning.as_deref();
+        let version = match versioning {
+            Some(versioning) => versioning.preserve(&self.orig),
+            None => Ok(None),
+        }
+        .inspect_err(|_| self.ctx.refund_quota(charged))?;
+        #[cfg(feature = "testkit")]
+        crate::testkit::pause(crate::testkit::PausePoint::FileCommit);
+        #[cfg(feature = "failpoints")]
+        crate::failpoint::hit(crate::failpoint::FILE_COW_COMMIT_BEFORE_RENAME, &self.orig)
+            .inspect_err(|_| self.ctx.refund_quota(charged))?;
+        if let Err(e) = self.ctx.vfs.rename(&self.path, &self.orig) {
+            if let Some(version) = &version {
+                let _ = fs::remove_file(version);
+            }
+            self.ctx.refund_quota(charged);
+            return Err(Error::io("commit copy", &self.path, e));
+        }
+        if let (Some(versioning), Some(dir)) =
+            (versioning, version.as_ref().and_then(|v| v.parent()))
+        {
+            versioning.prune(dir);
+        }
+        Ok(())
+    }
+}
+
+/// Make `rel` below `dir` an atomic directory, see [`DirWriteGaurd::create_dir_atomic`].
+pub(crate) fn create_atomic_below(
+    dir: &Path,
+    rel: &Path,
+    convert: bool,
+    ctx: &Ctx,
+) -> Result<AtomicDirCreation> {
+    let rel = check_nested(rel)?;
+    check_unreserved(dir, &rel)?;
+    let path = dir.join(rel);
+    let creation = match fs::symlink_metadata(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => AtomicDirCreation::Created,
+        Err(e) => return Err(Error::io("read metadata of", &path, e)),
+        Ok(metadata) if metadata.is_symlink() && is_atomic_dir_link(&path)? => {
+            return Ok(AtomicDirCreation::Existed);
+        }
+        Ok(metadata) if metadata.is_dir() && convert => AtomicDirCreation::Converted,
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(Error::AlreadyExistsAsPlainDir { path });
+        }
+        Ok(_) => return Err(Error::AlreadyExists { path }),
+    };
+    dir_cow_atomic_with(path, ctx.clone())?.commit()?;
+    Ok(creation)
+}
+
+/// What [`DirWriteGaurd::create_dir_atomic`](crate::DirWriteGaurd::create_dir_atomic) found and did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AtomicDirCreation {
+    /// Nothing existed and an empty atomic directory was created.
+    Created,
+    /// An atomic directory existed already and was left as it was.
+    Existed,
+    /// A plain directory was converted into an atomic directory with the same contents.
+    Converted,
+}
+
+/// Controls how [`Tx::dir_cow_atomic_with`](crate::Tx::dir_cow_atomic_with) copies a directory.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct AtomicCowOptions {
+    /// Copy a plain directory as well, which is converted into an atomic directory when the copy is
+    /// committed, instead of failing with [`Error::NotAtomicDir`].
+    pub convert_if_plain: bool,
+}
+
+/// Copy the directory at `orig` without locking it, see [`file_cow`]. Committing the copy moves the
+/// directory away for a moment, which only readers that lock it exclude themselves from, see
+/// [`CowDirGaurd::commit`]. Unlike the copies of guards and transactions, nothing checks that a lock on it is
+/// held until then.
+pub fn dir_cow<P: AsRef<Path>>(orig: P) -> Result<CowDirGaurd> {
+    check_locked(orig.as_ref())?;
+    dir_cow_with(orig, None, Ctx::detached())
+}
+
+/// A copy of a directory that does not exist starts out empty, and creates the directory when it is
+/// committed.
+pub(crate) fn dir_cow_with<P: AsRef<Path>>(
+    orig: P,
+    lock: Option<Weak<()>>,
+    ctx: Ctx,
+) -> Result<CowDirGaurd> {
+    let path = path_hidden_with_extension(&orig, artifact::TMP)?;
+    match fs::symlink_metadata(orig.as_ref()) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            ctx.vfs
+                .create_dir_all(&path)
+                .at("create directory", &path)?;
+        }
+        _ => ctx.copy_new(orig.as_ref(), &path, |stats| {
+            copy_recursive(&orig, &path, &ctx, stats)
+        })?,
+    }
+    ctx.set_artifact_permissions(&path, true)?;
+    Ok(CowDirGaurd {
+        path,
+        orig: orig.as_ref().to_path_buf(),
+        lock,
+        ctx,
+    })
+}
+
+/// Copy the atomic directory at `current` without locking it, see [`file_cow`]. Atomic directories inside
+/// a copy that is being prepared are created with [`CowDirGaurd::create_nested_atomic`] and
+/// [`CowAtomicDirGaurd::create_nested_atomic`] instead.
+pub fn dir_cow_atomic<P: AsRef<Path>>(current: P) -> Result<CowAtomicDirGaurd> {
+    check_locked(current.as_ref())?;
+    dir_cow_atomic_with(current, Ctx::detached())
+}
+
+pub(crate) fn dir_cow_atomic_with<P: AsRef<Path>>(
+    current: P,
+    ctx: Ctx,
+) -> Result<CowAtomicDirGaurd> {
+    dir_cow_atomic_from(current, None, ctx)
+}
+
+/// Copy the atomic directory at `current`, whose link was already read to lead to `payload` if that is set.
+pub(crate) fn dir_cow_atomic_from<P: AsRef<Path>>(
+    current: P,
+    payload: Option<&Path>,
+    ctx: Ctx,
+) -> Result<CowAtomicDirGaurd> {
+    let current = strip_trailing_slash(current.as_ref().to_path_buf());
+    if let Some(atomic_dirs) = &ctx.atomic_dirs
+        && !atomic_dirs.supported(&*ctx.vfs)
+    {
+        return Err(Error::AtomicDirsUnsupported { path: current });
+    }
+    let parent = current
+        .parent()
+        .ok_or_else(|| Error::invalid_path(&current, "missing parent"))?
+        .to_path_buf();
+
+    let mut name = String::new();
+    name.push('.');
+    name.push_str(
+        current
+            .file_name()
+            .ok_or_else(|| Error::invalid_path(&current, "missing file name"))?
+            .to_str()
+            .ok_or_else(|| Error::invalid_path(&current, "file name is not unicode"))?,
+    );
+    name.push('.');
+    let prefix = name.len();
+    let path = loop {
+        name.truncate(prefix);
+        name.push_str(&puuid());
+        name.push_str(artifact::ATOMIC_DIR);
+        let path = parent.join(&name);
+        if fs::symlink_metadata(&path).is_err() {
+            break path;
+        }
+    };
+    if current.exists() {
+        if current.is_symlink() {
+            let orig = match payload {
+                Some(payload) => payload.to_path_buf(),
+                None if is_atomic_dir_link(&current)? => {
+                    parent.join(fs::read_link(&current).at("read link", &current)?)
+                }
+                None => {
+                    return Err(Error::invalid_path(
+                        &current,
+                        "symbolic link is not an atomic directory",
+                    ));
+                }
+            };
+            ctx.copy_new(&orig, &path, |stats| {
+                copy_recursive(&orig, &path, &ctx, stats)
+            })?;
+            ctx.set_artifact_permissions(&path, true)?;
+            Ok(CowAtomicDirGaurd {
+                current,
+                name,
+                path,
+                orig: Some(orig),
+                ctx,
+            })
+        } else {
+            ctx.copy_new(&current, &path, |stats| {
+                copy_recursive(&current, &path, &ctx, stats)
+            })?;
+            ctx.set_artifact_permissions(&path, true)?;
+            Ok(CowAtomicDirGaurd {
+                current,
+                name,
+                path,
+                orig: None,
+                ctx,
+            })
+        }
+    } else {
+        fs::create_dir_all(&path).at("create directory", &path)?;
+        ctx.set_artifact_permissions(&path, true)?;
+        Ok(CowAtomicDirGaurd {
+            current,
+            name,
+            path,
+            orig: None,
+            ctx,
+        })
+    }
+}
+
+/// Can be sent to and committed on another thread, see [`Tx`](crate::Tx).
+pub struct CowDirGaurd {
+    pub path: PathBuf,
+    orig: PathBuf,
+    /// The write lock covering `orig` that the copy was made under, which has to be held until it is
+    /// committed. None for copies that were made without one.
+    lock: Option<Weak<()>>,
+    ctx: Ctx,
+}
+
+impl CowDirGaurd {
+    /// Make `rel` inside the copy an atomic directory, converting a plain directory there. The copy is not
+    /// locked by anyone and nothing is reported until it is committed as a whole.
+    pub fn create_nested_atomic<P: AsRef<Path>>(&self, rel: P) -> Result<AtomicDirCreation> {
+        create_atomic_below(&self.path, rel.as_ref(), true, &self.ctx.private())
+    }
+
+    /// Directory commits are not strictly atomic because rename cannot be used to target a
+    /// non-empty directory. This means commits are implemented as two rename operations, first
+    /// the target is renamed as a backup, then the copy is renamed to place at the original
+    /// location. The only way for the database to be left in an inconsistent state is if a
+    /// catastrophic failure occurs between these two renames. A directory that did not exist is created
+    /// by renaming the copy into place alone.
+    ///
+    /// Renames take no room for data, everything a commit writes, such as the generation of the directory,
+    /// is written before the first of them. A filesystem that runs out of space fails the commit before
+    /// the original is moved, or while the backup can still be moved back.
+    ///
+    /// While the original is moved aside, nothing exists at its name, nor at the names of the entries below
+    /// it. Every guard locks all ancestors of its entry, so the write lock the copy was made under keeps
+    /// them all out until the commit is done, and it has to be held until then. Debug builds panic if a
+    /// copy made by a [`DirWriteGaurd`](crate::DirWriteGaurd) or a [`Tx`](crate::Tx) is committed after
+    /// that lock was released.
+    pub fn commit(self) -> Result<()> {
+        debug_assert!(
+            self.lock
+                .as_ref()
+                .is_none_or(|lock| lock.strong_count() > 0),
+            "{} committed after the write lock it was copied under was released",
+            self.orig.display()
+        );
+        let result = self
+            .ctx
+            .commit(CommitKind::Dir, &self.orig, || self.commit_inner());
+        self.ctx.discard_on_no_space(&self.path, &result);
+        result
+    }
+
+    fn commit_inner(&self) -> Result<()> {
+        if fs::symlink_metadata(&self.orig).is_err() {
+            let charged = self.ctx.charge_quota(&self.path, &self.orig)?;
+            return self
+                .ctx
+                .vfs
+                .rename(&self.path, &self.orig)
+                .at("commit copy", &self.path)
+                .inspect_err(|_| self.ctx.refund_quota(charged));
+        }
+        let bak = unused_hidden_path(&self.orig, create_backup_ext)?;
+        let charged = self.ctx.charge_quota(&self.path, &self.orig)?;
+
+        let vfs = &self.ctx.vfs;
+        vfs.rename(&self.orig, &bak)
+            .at("back up", &self.orig)
+            .inspect_err(|_| self.ctx.refund_quota(charged))?;
+        #[cfg(feature = "testkit")]
+        crate::testkit::pause(crate::testkit::PausePoint::DirCommit);
+        #[cfg(feature = "failpoints")]
+        crate::failpoint::hit(
+            crate::failpoint::COW_DIR_COMMIT_AFTER_BAK_RENAME,
+            &self.orig,
+        )
+        .inspect_err(|_| self.ctx.refund_quota(charged))?;
+        if let Err(e) = vfs.rename(&self.path, &self.orig) {
+            let source = Box::new(Error::io("commit copy", &self.path, e));
+            let backup = vfs.rename(&bak, &self.orig).err().map(|_| bak);
+            self.ctx.refund_quota(charged);
+            return Err(Error::CommitFailed { backup, source });
+        }
+        if let Err(e) = vfs.remove_dir_all(&bak) {
+            // swallow error since it does not indicate failed commit
+            log::warn!(path:? = self.ctx.redact.path(&bak), operation = "cleanup"; "failed to remove backup: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Moves the directory at `path` to `orig`, swapping it with the existing directory if there is one.
+pub(crate) fn commit_dir_with(path: PathBuf, orig: PathBuf, ctx: Ctx) -> Result<()> {
+    CowDirGaurd {
+        path,
+        orig,
+        lock: None,
+        ctx,
+    }
+    .commit()
+}
+
+/// Can be sent to and committed on another thread, see [`Tx`](crate::Tx).
+pub struct CowAtomicDirGaurd {
+    current: PathBuf,
+    name: String,
+    pub path: PathBuf,
+    orig: Option<PathBuf>,
+    ctx: Ctx,
+}
+
+impl CowAtomicDirGaurd {
+    /// Make `rel` inside the copy an atomic directory, see [`CowDirGaurd::create_nested_atomic`].
+    pub fn create_nested_atomic<P: AsRef<Path>>(&self, rel: P) -> Result<AtomicDirCreation> {
+        create_atomic_below(&self.path, rel.as_ref(), true, &self.ctx.private())
+    }
+
+    /// Switches the link to the copy with a single rename. The temporary link is created before anything
+    /// else is touched, and a plain directory that is being converted is moved to a backup that is moved
+    /// back should the switch fail, see [`Error::CommitFailed`].
+    pub fn commit(self) -> Result<()> {
+        let result = self
+            .ctx
+            .commit(CommitKind::AtomicDir, &self.current, || self.commit_inner());
+        self.ctx.discard_on_no_space(&self.path, &result);
+        result
+    }
+
+    fn commit_inner(&self) -> Result<()> {
+        // the previous payload, or the plain directory being converted
+        let old = self.orig.as_deref().unwrap_or(&self.current);
+        let charged = self.ctx.charge_quota(&self.path, old)?;
+        self.switch_link()
+            .inspect_err(|_| self.ctx.refund_quota(charged))
+    }
+
+    fn switch_link(&self) -> Result<()> {
+        // the link is created first, where that is not permitted the commit fails before the original is
+        // moved away
+        let vfs = &self.ctx.vfs;
+        let current_tmp = create_tmp_link(&**vfs, Path::new(&self.name), &self.current, true)?;
+        #[cfg(feature = "failpoints")]
+        crate::failpoint::hit(
+            crate::failpoint::COW_ATOMIC_COMMIT_AFTER_TMPLNK,
+            &self.current,
+        )?;
+
+        // the link of an atomic directory is replaced by the rename, only a plain one is moved aside
+        let converting = fs::symlink_metadata(&self.current).is_ok_and(|m| m.is_dir());
+        let bak = if converting {
+            let bak = unused_hidden_path(&self.current, create_backup_ext)
+                .and_then(|bak| {
+                    vfs.rename(&self.current, &bak)
+                        .at("back up", &self.current)
+                        .map(|()| bak)
+                })
+                .inspect_err(|_| {
+                    let _ = vfs.remove_dir_all(&current_tmp);
+                })?;
+            Some(bak)
+        } else {
+            None
+        };
+
+        // atomic commit, a plain directory that was moved aside is put back if it fails
+        if let Err(e) = vfs.rename(&current_tmp, &self.current) {
+            let source = Box::new(Error::io("replace", &self.current, e));
+            let backup = bak.and_then(|bak| vfs.rename(&bak, &self.current).err().map(|_| bak));
+            let _ = vfs.remove_dir_all(&current_tmp);
+            return Err(Error::CommitFailed { backup, source });
+        }
+
+        if let Some(orig) = &self.orig
+            && let Err(e) = vfs.remove_dir_all(orig)
+        {
+            // swallow error since it does not indicate failed commit
+            log::warn!(path:? = self.ctx.redact.path(orig), operation = "cleanup"; "failed to remove previous dir: {}", e);
+        }
+        if let Some(bak) = bak
+            && let Err(e) = vfs.remove_dir_all(&bak)
+        {
+            // swallow error since it does not indicate failed commit
+            log::warn!(path:? = self.ctx.redact.path(&bak), operation = "cleanup"; "failed to remove backup: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Create a symbolic link to `target` at the temporary name of `link`, `.name.tmplnk.sbdb`, which is then
+/// renamed over `link` to replace it atomically. Must be called under the write lock of `link`, so that a
+/// temporary link that exists already was left behind by a crash and can be replaced.
+fn create_tmp_link(vfs: &dyn Vfs, target: &Path, link: &Path, dir: bool) -> Result<PathBuf> {
+    let tmp = path_hidden_with_extension(link, artifact::TMP_LINK)?;
+    let create = || match dir {
+        true => vfs.symlink_dir(target, &tmp),
+        false => vfs.symlink_file(target, &tmp),
+    };
+    match create() {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            vfs.remove_dir_all(&tmp).at("remove", &tmp)?;
+            create()
+        }
+        result => result,
+    }
+    .at("create symlink", &tmp)?;
+    Ok(tmp)
+}
+
+/// Atomically make `link` a symbolic link to `target`, replacing whatever link is there, see
+/// [`create_tmp_link`].
+pub(crate) fn replace_with_link(
+    vfs: &dyn Vfs,
+    target: &Path,
+    link: &Path,
+    dir: bool,
+) -> Result<()> {
+    let tmp = create_tmp_link(vfs, target, link, dir)?;
+    vfs.rename(&tmp, link).at("replace", link)
+}
+
+pub(crate) fn copy_file(src: &Path, dst: &Path, ctx: &Ctx, stats: &mut CopyStats) -> Result<()> {
+    let inode = if ctx.preserve_hardlinks {
+        linked_inode(src)?
+    } else {
+        None
+    };
+    if let Some(first) = inode.and_then(|inode| stats.hard_links.get(&inode)) {
+        return ctx
+            .vfs
+            .hard_link(first, dst)
+            .map_err(|e| Error::copy(src, dst, e));
+    }
+
+    let device = stats.device_of(dst);
+    let skip = device.is_some_and(|device| ctx.reflink.skip(device));
+    let copied = match &stats.deadline {
+        // a reflink can not be interrupted, it is only attempted while there is time left
+        Some(deadline) => {
+            deadline.check(src)?;
+            let reflinked = !skip
+                && match ctx.vfs.reflink(src, dst) {
+                    Ok(()) => true,
+                    // the failures that reflink_or_copy does not fall back to copying on either
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            std::io::ErrorKind::NotFound
+                                | std::io::ErrorKind::PermissionDenied
+                                | std::io::ErrorKind::AlreadyExists
+                        ) =>
+                    {
+                        return Err(Error::copy(src, dst, e));
+                    }
+                    Err(_) => false,
+                };
+            match reflinked {
+                true => None,
+                false => Some(deadline::copy_file(&*ctx.vfs, src, dst, deadline)?),
+            }
+        }
+        None => if skip {
+            ctx.vfs.copy(src, dst).map(Some)
+        } else {
+            ctx.vfs.reflink_or_copy(src, dst)
+        }
+        .map_err(|e| Error::copy(src, dst, e))?,
+    };
+    if let (Some(device), false) = (device, skip) {
+        ctx.reflink.record(device, copied.is_none());
+    }
+    // reflink_or_copy only reports a byte count when it had to fall back to copying
+    let (bytes, reflinked) = match copied {
+        Some(bytes) => (bytes, false),
+        None => (fs::metadata(dst).at("read metadata of", dst)?.len(), true),
+    };
+    ctx.metrics.cow_copied(bytes, reflinked);
+    stats.files += 1;
+    stats.bytes += bytes;
+    if !reflinked {
+        stats.copied += bytes;
+    }
+    if let Some(inode) = inode {
+        stats.hard_links.insert(inode, dst.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Device and inode of the file at `path` if it has more than one name.
+#[cfg(unix)]
+fn linked_inode(path: &Path) -> Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path).at("read metadata of", path)?;
+    Ok((metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino())))
+}
+
+/// The standard library can not identify a file on other platforms.
+#[cfg(not(unix))]
+fn linked_inode(_path: &Path) -> Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
+/// Copies `src` to `dst`, with symbolic links copied as links. Directories are walked with an explicit
+/// stack instead of recursion, so a tree that is too deep ends in [`Error::TreeTooDeep`] rather than a stack
+/// overflow.
+pub(crate) fn copy_recursive(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    ctx: &Ctx,
+    stats: &mut CopyStats,
+) -> Result<()> {
+    let mut pending = vec![(src.as_ref().to_path_buf(), dst.as_ref().to_path_buf(), 0)];
+    while let Some((src, dst, depth)) = pending.pop() {
+        check_depth(&src, depth, ctx.max_depth)?;
+        ctx.vfs.create_dir_all(&dst).at("create directory", &dst)?;
+
+        for entry in ctx.vfs.read_dir(&src).at("read directory", &src)? {
+            let entry = entry.at("read directory", &src)?;
+            let entry_path = entry.path();
+            let file_name = entry.file_name();
+            // counters and rollups describe the original, a copy that gets committed is tracked by its parents'
+            // counters and gets its rollups rebuilt. Lock directories belong to whoever holds them, a copy of
+            // one would never be released.
+            if let Some((
+                ArtifactKind::Generation | ArtifactKind::Rollup | ArtifactKind::LockDir,
+                _,
+            )) = artifact::parse(&file_name)
+            {
+                continue;
+            }
+            let dest_path = dst.join(file_name);
+
+            let file_type = entry.file_type().at("read metadata of", &entry_path)?;
+
+            if file_type.is_dir() {
+                pending.push((entry_path, dest_path, depth + 1));
+            } else if file_type.is_file() {
+                copy_file(&entry_path, &dest_path, ctx, stats)?;
+            } else if file_type.is_symlink() {
+                let link_target = fs::read_link(&entry_path).at("read link", &entry_path)?;
+                ctx.vfs
+                    .symlink_dir(&link_target, &dest_path)
+                    .map_err(|e| Error::copy(&entry_path, &dest_path, e))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the user visible contents of `src`: internal files are skipped, except for the metadata of
+/// entries which travels with them, and atomic directories are copied as plain directories.
+pub(crate) fn copy_visible(
+    src: &Path,
+    dst: &Path,
+    internal: &Path,
+    ctx: &Ctx,
+    stats: &mut CopyStats,
+) -> Result<()> {
+    let mut visited = VisitedDirs::default();
+    let mut pending = vec![(src.to_path_buf(), dst.to_path_buf(), 0)];
+    while let Some((src, dst, depth)) = pending.pop() {
+        check_depth(&src, depth, ctx.max_depth)?;
+        visited.enter(&src)?;
+        ctx.vfs.create_dir_all(&dst).at("create directory", &dst)?;
+
+        for entry in ctx.vfs.read_dir(&src).at("read directory", &src)? {
+            let entry = entry.at("read directory", &src)?;
+            let entry_path = entry.path();
+            let file_type = entry.file_type().at("read metadata of", &entry_path)?;
+            let internal_file = match artifact::parse(&entry.file_name()) {
+                Some((ArtifactKind::Meta, _)) => !file_type.is_file(),
+                kind => kind.is_some(),
+            };
+            if entry_path == internal || internal_file {
+                continue;
+            }
+            let dest_path = dst.join(entry.file_name());
+
+            if file_type.is_dir() || (file_type.is_symlink() && is_atomic_dir_link(&entry_path)?) {
+                pending.push((entry_path, dest_path, depth + 1));
+            } else if file_type.is_file() {
+                copy_file(&entry_path, &dest_path, ctx, stats)?;
+            } else if file_type.is_symlink() {
+                let link_target = fs::read_link(&entry_path).at("read link", &entry_path)?;
+                ctx.vfs
+                    .symlink_dir(&link_target, &dest_path)
+                    .map_err(|e| Error::copy(&entry_path, &dest_path, e))?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Bounds on how long a single copy or write may take, see [`crate::ClientBuilder::io_deadline`]. Files are
+//! then copied and written in chunks, with the deadline checked before each of them, rather than in one
+//! call that an unresponsive filesystem can keep from returning for as long as it likes.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{Error, Result, Vfs, error::IoResultExt};
+
+/// Bytes copied or written between two checks of a deadline.
+pub(crate) const CHUNK_LEN: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline {
+    start: Instant,
+    limit: Duration,
+}
+
+impl Deadline {
+    /// Deadline of an operation starting now.
+    pub(crate) fn start(limit: Duration) -> Self {
+        Deadline {
+            start: Instant::now(),
+            limit,
+        }
+    }
+
+    /// Fails with [`Error::IoTimeout`] once the deadline has passed, a limit of zero always does.
+    pub(crate) fn check(&self, path: &Path) -> Result<()> {
+        let elapsed = self.start.elapsed();
+        if elapsed >= self.limit {
+            return Err(Error::IoTimeout {
+                path: path.to_path_buf(),
+                elapsed,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Copies the file `src` to `dst`, which must not exist yet, in chunks, returning the number of bytes
+/// copied. A copy that runs out of time is removed again.
+pub(crate) fn copy_file(vfs: &dyn Vfs, src: &Path, dst: &Path, deadline: &Deadline) -> Result<u64> {
+    let copied = copy_chunks(vfs, src, dst, deadline);
+    if let Err(Error::IoTimeout { .. }) = copied {
+        let _ = vfs.remove_file(dst);
+    }
+    copied
+}
+
+fn copy_chunks(vfs: &dyn Vfs, src: &Path, dst: &Path, deadline: &Deadline) -> Result<u64> {
+    let from = File::open(src).map_err(|e| Error::copy(src, dst, e))?;
+    let to = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)
+        .map_err(|e| Error::copy(src, dst, e))?;
+    let mut bytes = 0;
+    loop {
+        deadline.check(src)?;
+        let copied = vfs
+            .copy_chunk(&from, &to, CHUNK_LEN as u64)
+            .map_err(|e| Error::copy(src, dst, e))?;
+        if copied == 0 {
+            break;
+        }
+        bytes += copied;
+    }
+    let permissions = from.metadata().at("read metadata of", src)?.permissions();
+    to.set_permissions(permissions)
+        .at("set permissions of", dst)?;
+    Ok(bytes)
+}
+
+/// Writes `bytes` to `file` at `path`, in chunks if there is a deadline.
+pub(crate) fn write(
+    mut file: &File,
+    path: &Path,
+    bytes: &[u8],
+    deadline: Option<&Deadline>,
+) -> Result<()> {
+    let Some(deadline) = deadline else {
+        return file.write_all(bytes).at("write", path);
+    };
+    for chunk in bytes.chunks(CHUNK_LEN) {
+        deadline.check(path)?;
+        file.write_all(chunk).at("write", path)?;
+    }
+    Ok(())
+}
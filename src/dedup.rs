@@ -0,0 +1,259 @@
+//! Sharing the storage of identical files, see [`Client::dedup`]. Candidates are found by size and then
+//! by digest, and compared byte for byte once they are write locked, so that files that changed in the
+//! meantime are left alone.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Client, CowFileGaurd, Digest, HashAlgorithm, Result, artifact, error::IoResultExt, hash,
+    normalize_rpath, path_hidden_with_extension, reflink,
+};
+
+/// Files smaller than this are left alone by [`Client::dedup`] by default, they take up at most a block
+/// either way.
+pub const DEFAULT_DEDUP_MIN_BYTES: u64 = 4096;
+
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DedupOptions {
+    /// Digest that candidates of the same size are grouped by.
+    pub algorithm: HashAlgorithm,
+    /// Files smaller than this are left alone, [`DEFAULT_DEDUP_MIN_BYTES`] by default.
+    pub min_bytes: u64,
+    /// Where files can not be reflinked, replace duplicates with hard links to the copy that is kept
+    /// instead, false by default. The files are then one and the same until one of them is committed
+    /// again: a change made in place through one name shows through all of them.
+    pub hard_link: bool,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        DedupOptions {
+            algorithm: HashAlgorithm::default(),
+            min_bytes: DEFAULT_DEDUP_MIN_BYTES,
+            hard_link: false,
+        }
+    }
+}
+
+/// What [`Client::dedup`] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DedupReport {
+    /// Sets of two or more files with the same contents that were found.
+    pub groups: u64,
+    /// Files replaced with a reflink or hard link of another one.
+    pub files_rewritten: u64,
+    /// Sum of the sizes of the rewritten files. Files that shared their storage already are counted again,
+    /// the filesystem does not tell.
+    pub bytes_reclaimed: u64,
+    /// Files that changed after they were hashed, and were left alone.
+    pub files_changed: u64,
+    /// Whether the filesystem can not reflink files, in which case nothing was done, unless
+    /// [`DedupOptions::hard_link`] allowed hard links instead.
+    pub reflink_unsupported: bool,
+}
+
+impl Client {
+    /// Replace files below `rpath` that have the same contents as another one with a reflink of it, so that
+    /// they share their storage on filesystems such as btrfs and XFS. The subtree is read locked while it is
+    /// walked and each candidate while it is hashed, see [`Client::hash_tree`]. Every set of identical files
+    /// is then locked in one transaction, the file that sorts first for reading and the others for writing,
+    /// and compared again byte for byte before each of the others is committed as a reflink of the first,
+    /// like a copy-on-write copy, so readers see either file but never a mix. Names of the same file,
+    /// hard links, count as one.
+    ///
+    /// Does nothing on filesystems that can not reflink, which the report tells, unless
+    /// [`DedupOptions::hard_link`] is set.
+    pub fn dedup<P: AsRef<Path>>(&self, rpath: P, options: &DedupOptions) -> Result<DedupReport> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let mut report = DedupReport::default();
+            let reflinks = self.probe_reflink()?;
+            if !reflinks {
+                report.reflink_unsupported = true;
+                if !options.hard_link {
+                    return Ok(report);
+                }
+            }
+
+            for group in self.identical_files(&rpath, options)? {
+                report.groups += 1;
+                self.dedup_group(&group, reflinks, &mut report)?;
+            }
+            Ok(report)
+        })
+    }
+
+    /// Whether files in the database can be reflinked, found by reflinking a file in the scratch area.
+    fn probe_reflink(&self) -> Result<bool> {
+        let scratch = self.scratch()?;
+        let probe = scratch.path.join("probe");
+        fs::write(&probe, b"sbdb").at("write", &probe)?;
+        let reflinked = self
+            .inner
+            .vfs
+            .reflink(&probe, &scratch.path.join("clone"))
+            .is_ok();
+        if let Some(device) = reflink::device(&scratch.path) {
+            self.inner.reflink.record(device, reflinked);
+        }
+        Ok(reflinked)
+    }
+
+    /// Sets of files below `rpath`, relative to the root and sorted, whose contents hash the same. Only
+    /// one name of every file is considered.
+    fn identical_files(&self, rpath: &Path, options: &DedupOptions) -> Result<Vec<Vec<PathBuf>>> {
+        let gaurd = self.read_dir(rpath)?;
+        let mut by_len: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut files = self.data_files(&gaurd.path)?;
+        files.sort();
+        for rel in files {
+            let path = gaurd.path.join(&rel);
+            let metadata = fs::metadata(&path).at("read metadata of", &path)?;
+            if metadata.len() < options.min_bytes.max(1)
+                || file_id(&metadata).is_some_and(|id| !seen.insert(id))
+            {
+                continue;
+            }
+            by_len.entry(metadata.len()).or_default().push(rel);
+        }
+
+        let mut groups: HashMap<(u64, Digest), Vec<PathBuf>> = HashMap::new();
+        for (len, candidates) in by_len {
+            if candidates.len() < 2 {
+                continue;
+            }
+            for rel in candidates {
+                let file = gaurd.read_file(&rel)?;
+                let (digest, _) = hash::hash_file(&file.path, options.algorithm)?;
+                file.release()?;
+                groups
+                    .entry((len, digest))
+                    .or_default()
+                    .push(rpath.join(rel));
+            }
+        }
+        gaurd.release()?;
+        let mut groups = groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .collect::<Vec<_>>();
+        for group in groups.iter_mut() {
+            group.sort();
+        }
+        groups.sort();
+        Ok(groups)
+    }
+
+    /// Replaces every file of `group` but the first with a reflink, or a hard link unless `reflinks`, of the
+    /// first, under the locks of all of them.
+    fn dedup_group(
+        &self,
+        group: &[PathBuf],
+        reflinks: bool,
+        report: &mut DedupReport,
+    ) -> Result<()> {
+        let (kept, duplicates) = group.split_first().expect("groups have several files");
+        let tx = duplicates
+            .iter()
+            .fold(self.tx().read(kept), |tx, rpath| tx.write(rpath))
+            .begin()?;
+        let kept = self.inner.root.join(kept);
+        for rpath in duplicates {
+            let path = self.inner.root.join(rpath);
+            if !same_contents(&kept, &path)? {
+                report.files_changed += 1;
+                continue;
+            }
+            let len = fs::metadata(&path).at("read metadata of", &path)?.len();
+            let tmp = path_hidden_with_extension(&path, artifact::TMP)?;
+            // left behind by an earlier commit that failed
+            let _ = self.inner.vfs.remove_file(&tmp);
+            let linked = match reflinks {
+                true => self
+                    .inner
+                    .vfs
+                    .reflink(&kept, &tmp)
+                    .at("reflink", &tmp)
+                    .and_then(|()| {
+                        // a reflink has the permissions of the file it was made of
+                        let permissions = fs::metadata(&path)
+                            .at("read metadata of", &path)?
+                            .permissions();
+                        fs::set_permissions(&tmp, permissions).at("set permissions of", &tmp)
+                    }),
+                false => self.inner.vfs.hard_link(&kept, &tmp).at("link", &tmp),
+            };
+            let committed = linked.and_then(|()| {
+                CowFileGaurd {
+                    path: tmp.clone(),
+                    orig: path,
+                    ctx: tx.ctx.clone(),
+                }
+                .commit()
+            });
+            if let Err(e) = committed {
+                let _ = self.inner.vfs.remove_file(&tmp);
+                return Err(e);
+            }
+            report.files_rewritten += 1;
+            report.bytes_reclaimed += len;
+        }
+        tx.release()
+    }
+}
+
+/// Device and inode of a file, which its hard links share.
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// The standard library can not identify a file on other platforms.
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether the files at `a` and `b` hold the same bytes.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let (mut file_a, mut file_b) = (File::open(a).at("open", a)?, File::open(b).at("open", b)?);
+    let len = |file: &File, path| Ok(file.metadata().at("read metadata of", path)?.len());
+    if len(&file_a, a)? != len(&file_b, b)? {
+        return Ok(false);
+    }
+    let (mut buf_a, mut buf_b) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+    loop {
+        let read = read_full(&mut file_a, &mut buf_a).at("read", a)?;
+        if read != read_full(&mut file_b, &mut buf_b[..read]).at("read", b)? {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Reads into all of `buf` unless the end of `file` comes first, returning how much was read.
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,111 @@
+//! Small key-value metadata attached to entries, such as a content type or where the data came from. The
+//! metadata of an entry is kept next to it in a sidecar file, `.name.meta.sbdb`, holding a JSON object of
+//! strings that is replaced as a whole on every change, while the entry is write locked.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Client, Ctx, Error, Result, artifact,
+    error::IoResultExt,
+    file_replace_with,
+    meta::{format_object, parse_object},
+    path_hidden_with_extension,
+};
+
+/// Largest size of the encoded metadata of an entry, see [`Error::MetaTooLarge`].
+pub const MAX_META_BYTES: usize = 64 * 1024;
+
+impl Client {
+    /// Set `key` in the metadata of the entry at `rpath` to `value`. The entry has to exist. Writing the
+    /// entry keeps its metadata, while copies of its directory, exports, archives and snapshots carry it
+    /// along.
+    pub fn set_meta<P: AsRef<Path>>(&self, rpath: P, key: &str, value: &str) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            fs::symlink_metadata(&gaurd.path).at("set metadata of", &gaurd.path)?;
+            let mut meta = read(&sidecar(&gaurd.path)?)?;
+            meta.insert(key.to_string(), value.to_string());
+            write(&gaurd.path, &meta)
+        })
+    }
+
+    /// Value of `key` in the metadata of the entry at `rpath`.
+    pub fn get_meta<P: AsRef<Path>>(&self, rpath: P, key: &str) -> Result<Option<String>> {
+        self.redacted(|| {
+            let gaurd = self.read_file(rpath)?;
+            Ok(read(&sidecar(&gaurd.path)?)?.remove(key))
+        })
+    }
+
+    /// Remove `key` from the metadata of the entry at `rpath`, returning its value. The sidecar file goes
+    /// once the last key is removed.
+    pub fn remove_meta<P: AsRef<Path>>(&self, rpath: P, key: &str) -> Result<Option<String>> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            let mut meta = read(&sidecar(&gaurd.path)?)?;
+            let removed = meta.remove(key);
+            if removed.is_some() {
+                write(&gaurd.path, &meta)?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// All metadata of the entry at `rpath`, empty if it has none.
+    pub fn list_meta<P: AsRef<Path>>(&self, rpath: P) -> Result<BTreeMap<String, String>> {
+        self.redacted(|| {
+            let gaurd = self.read_file(rpath)?;
+            read(&sidecar(&gaurd.path)?)
+        })
+    }
+}
+
+/// Path of the file holding the metadata of the entry at `path`.
+pub(crate) fn sidecar(path: &Path) -> Result<PathBuf> {
+    path_hidden_with_extension(path, artifact::META)
+}
+
+/// Removes the metadata of the entry at `path`, which was removed itself.
+pub(crate) fn remove(path: &Path) -> Result<()> {
+    let sidecar = sidecar(path)?;
+    match fs::remove_file(&sidecar) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(Error::io("remove metadata", &sidecar, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, String>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(Error::io("read metadata", path, e)),
+    };
+    parse_object(&text).map_err(|message| Error::Decode {
+        path: path.to_path_buf(),
+        source: message.into(),
+    })
+}
+
+/// Sidecars are not commits of the entry, so they are written without the client's versioning,
+/// generations and quota.
+fn write(entry: &Path, meta: &BTreeMap<String, String>) -> Result<()> {
+    let sidecar = sidecar(entry)?;
+    if meta.is_empty() {
+        return fs::remove_file(&sidecar).at("remove metadata", &sidecar);
+    }
+    let text = format_object(meta);
+    if text.len() > MAX_META_BYTES {
+        return Err(Error::MetaTooLarge {
+            path: entry.to_path_buf(),
+            size: text.len(),
+            limit: MAX_META_BYTES,
+        });
+    }
+    file_replace_with(&sidecar, text.as_bytes(), false, Ctx::detached())
+}
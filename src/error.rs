@@ -0,0 +1,453 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// An io operation failed. `operation` describes what was being done to `path`.
+    #[error("failed to {operation} {}", path.display())]
+    Io {
+        operation: &'static str,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Copying `src` to `dst` failed while making a copy-on-write copy.
+    #[error("failed to copy {} to {}", src.display(), dst.display())]
+    Copy {
+        src: PathBuf,
+        dst: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A copy or write of `path` was given up after `elapsed`, see [`crate::ClientBuilder::io_deadline`].
+    #[error("gave up on {} after {elapsed:?}", path.display())]
+    IoTimeout { path: PathBuf, elapsed: Duration },
+
+    /// A non-blocking lock acquisition found the lock already held.
+    #[error("lock on {} is held elsewhere", path.display())]
+    WouldBlock { path: PathBuf },
+
+    /// A lock could not be acquired within the configured time limit.
+    #[error("timed out waiting for lock on {}", path.display())]
+    Timeout { path: PathBuf },
+
+    #[error("invalid path {}: {reason}", path.display())]
+    InvalidPath { path: PathBuf, reason: &'static str },
+
+    #[error("failed to {operation} {}: does not exist", path.display())]
+    NotFound {
+        operation: &'static str,
+        path: PathBuf,
+    },
+
+    /// The original of a copy was modified after the copy was made.
+    #[error("{} changed since it was copied", path.display())]
+    StaleOriginal { path: PathBuf },
+
+    /// A transaction attempted to modify a path it did not declare as a write.
+    #[error("{} was not declared as a write in this transaction", path.display())]
+    UndeclaredWrite { path: PathBuf },
+
+    /// A copy-on-write copy of `path` was to be made without this process holding a write lock covering it,
+    /// which is checked with the `strict-locking` feature, see [`crate::unlocked`].
+    #[error("{} is not write locked by this process", path.display())]
+    NotWriteLocked { path: PathBuf },
+
+    /// An entry declared by a transaction changed while its locks were released, see
+    /// [`crate::Tx::checkpoint`].
+    #[error("{} changed while the transaction's locks were released", path.display())]
+    Conflict { path: PathBuf },
+
+    /// Two changes of a [`crate::Changeset`] touch the entry at `path`, relative to the root, or one touches
+    /// an entry below another.
+    #[error("conflicting changes of {}: {reason}", path.display())]
+    ConflictingChanges { path: PathBuf, reason: &'static str },
+
+    /// The lock of `acquiring` was to be taken by a thread that holds the lock of `held`, which sorts after
+    /// it without being its ancestor, checked with the `lock-order-check` feature. Another thread taking the
+    /// two the other way around would deadlock with this one.
+    #[error("lock on {} taken while holding the later lock on {}", acquiring.display(), held.display())]
+    LockOrderViolation { held: PathBuf, acquiring: PathBuf },
+
+    /// A transaction attempted to read a path it did not declare.
+    #[error("{} was not declared as a read in this transaction", path.display())]
+    UndeclaredRead { path: PathBuf },
+
+    /// A file name could not be decoded into a key, see [`crate::key::decode`].
+    #[error("invalid key {key:?}: {reason}")]
+    InvalidKey { key: String, reason: &'static str },
+
+    /// The contents of `path` could not be decoded.
+    #[error("failed to decode {}", path.display())]
+    Decode {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// The file at `path` was not written by the codec used to read it, or by an incompatible version
+    /// of it.
+    #[error("{} was not written in the expected format", path.display())]
+    WrongFormat { path: PathBuf },
+
+    /// The value at `path` was written at schema version `found`, newer than the `supported` one that
+    /// values are migrated to, see [`crate::MigrationChain`].
+    #[error("{} was written at schema version {found}, newer than {supported}", path.display())]
+    VersionTooNew {
+        path: PathBuf,
+        found: u32,
+        supported: u32,
+    },
+
+    /// The value at `path` could not be migrated from schema version `from` to the next one.
+    #[error("failed to migrate {} from schema version {from}", path.display())]
+    MigrationFailed {
+        path: PathBuf,
+        from: u32,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// A value could not be encoded before being written to `path`.
+    #[error("failed to encode value for {}", path.display())]
+    Encode {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// A directory tree was nested more than `max_depth` directories deep below the entry being walked,
+    /// see [`crate::ClientBuilder::max_depth`].
+    #[error("{} is nested more than {max_depth} directories deep", path.display())]
+    TreeTooDeep { path: PathBuf, max_depth: usize },
+
+    /// A directory was reached again through a symbolic link while walking the tree it is part of.
+    #[error("{} leads back to a directory that was already walked", path.display())]
+    SymlinkLoop { path: PathBuf },
+
+    /// Atomic directories are symbolic links, which can not be created next to `path`, e.g. on windows
+    /// without developer mode. See [`crate::Client::atomic_dirs_supported`].
+    #[error("can not make {} an atomic directory: symbolic links are not supported", path.display())]
+    AtomicDirsUnsupported { path: PathBuf },
+
+    /// Aliases are symbolic links, which can not be created next to `path`, for the same reasons as
+    /// atomic directories can not.
+    #[error("can not make {} an alias: symbolic links are not supported", path.display())]
+    AliasesUnsupported { path: PathBuf },
+
+    /// The entry at `path` can not be written, or read in a transaction, because its name, or that of one of
+    /// its ancestors, ends in `suffix`, one of the [`crate::RESERVED_SUFFIXES`] of sbdb's own files, or because
+    /// it lies in `.sbdb`, the directory at the root that holds sbdb's own data, which is then the `suffix`.
+    #[error("{} is named like an internal file ending in {suffix}", path.display())]
+    ReservedName { path: PathBuf, suffix: &'static str },
+
+    /// An entry exists at `path` already and was not to be overwritten.
+    #[error("{} exists already", path.display())]
+    AlreadyExists { path: PathBuf },
+
+    /// A plain directory exists at `path` where an atomic directory was to be created, see
+    /// [`crate::DirWriteGaurd::create_dir_atomic`].
+    #[error("{} exists already as a plain directory", path.display())]
+    AlreadyExistsAsPlainDir { path: PathBuf },
+
+    /// A plain directory exists at `path` where an atomic directory was to be copied, see
+    /// [`crate::Tx::dir_cow_atomic`]. It is converted with [`crate::DirWriteGaurd::create_dir_atomic`], or
+    /// as part of the copy with [`crate::AtomicCowOptions::convert_if_plain`].
+    #[error(
+        "{} is a plain directory, not an atomic one: convert it with create_dir_atomic or convert_if_plain",
+        path.display()
+    )]
+    NotAtomicDir { path: PathBuf },
+
+    /// Advancing the counter at `path` would take it past the largest value it can hand out.
+    #[error("counter {} overflowed", path.display())]
+    CounterOverflow { path: PathBuf },
+
+    /// A commit would have taken the data in the database past its quota of `quota` bytes, see
+    /// [`crate::ClientBuilder::quota`]. `used` is the usage before the commit and `attempted` the bytes it
+    /// would have added.
+    #[error("quota of {quota} bytes exceeded: {used} bytes used, commit adds {attempted}")]
+    QuotaExceeded {
+        used: u64,
+        quota: u64,
+        attempted: u64,
+    },
+
+    /// The metadata of the entry at `path` would take `size` bytes, more than the `limit` of
+    /// [`crate::MAX_META_BYTES`].
+    #[error("metadata of {} would take {size} bytes, more than {limit}", path.display())]
+    MetaTooLarge {
+        path: PathBuf,
+        size: usize,
+        limit: usize,
+    },
+
+    /// File locks in the database at `path` do not exclude each other, see
+    /// [`crate::ClientBuilder::verify_locking`].
+    #[error("locks are not enforced in {}", path.display())]
+    LockingNotEnforced { path: PathBuf },
+
+    /// The database holds evidence of interrupted operations, see [`crate::OpenPolicy`].
+    #[error("database needs recovery, {} interrupted operations found", findings.len())]
+    NeedsRecovery { findings: Vec<crate::Issue> },
+
+    /// There is no database at `path` to open, see [`crate::Client::open`].
+    #[error("no database at {}", path.display())]
+    RootNotFound { path: PathBuf },
+
+    /// The root of a client is inside the root `outer` of another database, whose locks it would not take,
+    /// see [`crate::ClientBuilder::allow_nested`].
+    #[error("root is inside the database at {}", outer.display())]
+    NestedRoot { outer: PathBuf },
+
+    /// The database was written by a newer version of sbdb, with format `found` where this one supports up to
+    /// `supported`, or uses layout features this version does not know.
+    #[error("database format {found} is not supported, up to {supported} is{}", match unknown_features.is_empty() {
+        true => String::new(),
+        false => format!(", unknown features {}", unknown_features.join(", ")),
+    })]
+    IncompatibleFormat {
+        found: u32,
+        supported: u32,
+        unknown_features: Vec<String>,
+    },
+
+    /// The database is a replica, which only changes through [`crate::Client::apply_commits`], so the entry
+    /// at `path` can not be committed.
+    #[error("can not commit {}, the database is a read only replica", path.display())]
+    ReadOnlyReplica { path: PathBuf },
+
+    /// A replication log or replica was to continue at sequence number `expected`, but the next record is
+    /// `found`. The records in between were truncated, or have to be applied first.
+    #[error("replication record {expected} is missing, next is {found}")]
+    ReplicationGap { expected: u64, found: u64 },
+
+    /// The read guard of `path` was revoked after holding its lock for longer than
+    /// [`crate::ClientBuilder::max_read_hold`]. It keeps the lock until it is dropped.
+    #[error("read guard of {} was revoked", path.display())]
+    LeaseRevoked { path: PathBuf },
+
+    /// A lease was taken over by another owner after it expired.
+    #[error("lease at {} was taken over", path.display())]
+    LeaseLost { path: PathBuf },
+
+    /// The filesystem holding `path` ran out of space while it was written, or, with
+    /// [`crate::ClientBuilder::preflight_space`], has `available` bytes where a temporary copy or write of
+    /// `needed` bytes was to be made there. The temporary is removed again, so nothing was committed.
+    #[error("no space left for {}{}", path.display(), match (needed, available) {
+        (Some(needed), Some(available)) => format!(": {needed} bytes needed, {available} available"),
+        _ => String::new(),
+    })]
+    NoSpace {
+        path: PathBuf,
+        needed: Option<u64>,
+        available: Option<u64>,
+    },
+
+    /// A commit did not complete. If `backup` is set, the original could not be restored and is still
+    /// located at that path.
+    #[error("commit failed{}", backup.as_ref().map(|b| format!(", original left at {}", b.display())).unwrap_or_default())]
+    CommitFailed {
+        backup: Option<PathBuf>,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    pub(crate) fn io<P: AsRef<Path>>(operation: &'static str, path: P, source: io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        match source.kind() {
+            io::ErrorKind::NotFound => Error::NotFound { operation, path },
+            io::ErrorKind::StorageFull => Error::no_space(path),
+            _ => Error::Io {
+                operation,
+                path,
+                source,
+            },
+        }
+    }
+
+    /// Copies are always made next to their source, so a missing path is reported as the source not
+    /// existing.
+    pub(crate) fn copy<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, source: io::Error) -> Self {
+        if source.kind() == io::ErrorKind::NotFound {
+            return Error::NotFound {
+                operation: "copy",
+                path: src.as_ref().to_path_buf(),
+            };
+        }
+        if source.kind() == io::ErrorKind::StorageFull {
+            return Error::no_space(dst.as_ref());
+        }
+        Error::Copy {
+            src: src.as_ref().to_path_buf(),
+            dst: dst.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    fn no_space<P: AsRef<Path>>(path: P) -> Self {
+        Error::NoSpace {
+            path: path.as_ref().to_path_buf(),
+            needed: None,
+            available: None,
+        }
+    }
+
+    /// Whether a commit failed for want of space, without leaving the original at a backup.
+    pub(crate) fn is_no_space(&self) -> bool {
+        match self {
+            Error::NoSpace { .. } => true,
+            Error::CommitFailed {
+                backup: None,
+                source,
+            } => source.is_no_space(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn invalid_path<P: AsRef<Path>>(path: P, reason: &'static str) -> Self {
+        Error::InvalidPath {
+            path: path.as_ref().to_path_buf(),
+            reason,
+        }
+    }
+
+    /// The error with every path it names replaced with `f` of it, see
+    /// [`crate::ClientBuilder::redact_paths`].
+    pub(crate) fn map_paths(self, f: &dyn Fn(PathBuf) -> PathBuf) -> Self {
+        match self {
+            Error::Io {
+                operation,
+                path,
+                source,
+            } => Error::Io {
+                operation,
+                path: f(path),
+                source,
+            },
+            Error::Copy { src, dst, source } => Error::Copy {
+                src: f(src),
+                dst: f(dst),
+                source,
+            },
+            Error::IoTimeout { path, elapsed } => Error::IoTimeout {
+                path: f(path),
+                elapsed,
+            },
+            Error::WouldBlock { path } => Error::WouldBlock { path: f(path) },
+            Error::Timeout { path } => Error::Timeout { path: f(path) },
+            Error::InvalidPath { path, reason } => Error::InvalidPath {
+                path: f(path),
+                reason,
+            },
+            Error::NotFound { operation, path } => Error::NotFound {
+                operation,
+                path: f(path),
+            },
+            Error::StaleOriginal { path } => Error::StaleOriginal { path: f(path) },
+            Error::UndeclaredWrite { path } => Error::UndeclaredWrite { path: f(path) },
+            Error::NotWriteLocked { path } => Error::NotWriteLocked { path: f(path) },
+            Error::Conflict { path } => Error::Conflict { path: f(path) },
+            Error::ConflictingChanges { path, reason } => Error::ConflictingChanges {
+                path: f(path),
+                reason,
+            },
+            Error::LockOrderViolation { held, acquiring } => Error::LockOrderViolation {
+                held: f(held),
+                acquiring: f(acquiring),
+            },
+            Error::UndeclaredRead { path } => Error::UndeclaredRead { path: f(path) },
+            Error::Decode { path, source } => Error::Decode {
+                path: f(path),
+                source,
+            },
+            Error::WrongFormat { path } => Error::WrongFormat { path: f(path) },
+            Error::VersionTooNew {
+                path,
+                found,
+                supported,
+            } => Error::VersionTooNew {
+                path: f(path),
+                found,
+                supported,
+            },
+            Error::MigrationFailed { path, from, source } => Error::MigrationFailed {
+                path: f(path),
+                from,
+                source,
+            },
+            Error::Encode { path, source } => Error::Encode {
+                path: f(path),
+                source,
+            },
+            Error::TreeTooDeep { path, max_depth } => Error::TreeTooDeep {
+                path: f(path),
+                max_depth,
+            },
+            Error::SymlinkLoop { path } => Error::SymlinkLoop { path: f(path) },
+            Error::AtomicDirsUnsupported { path } => Error::AtomicDirsUnsupported { path: f(path) },
+            Error::AliasesUnsupported { path } => Error::AliasesUnsupported { path: f(path) },
+            Error::ReservedName { path, suffix } => Error::ReservedName {
+                path: f(path),
+                suffix,
+            },
+            Error::AlreadyExists { path } => Error::AlreadyExists { path: f(path) },
+            Error::AlreadyExistsAsPlainDir { path } => {
+                Error::AlreadyExistsAsPlainDir { path: f(path) }
+            }
+            Error::NotAtomicDir { path } => Error::NotAtomicDir { path: f(path) },
+            Error::CounterOverflow { path } => Error::CounterOverflow { path: f(path) },
+            Error::MetaTooLarge { path, size, limit } => Error::MetaTooLarge {
+                path: f(path),
+                size,
+                limit,
+            },
+            Error::LockingNotEnforced { path } => Error::LockingNotEnforced { path: f(path) },
+            Error::RootNotFound { path } => Error::RootNotFound { path: f(path) },
+            Error::NestedRoot { outer } => Error::NestedRoot { outer: f(outer) },
+            Error::ReadOnlyReplica { path } => Error::ReadOnlyReplica { path: f(path) },
+            Error::LeaseRevoked { path } => Error::LeaseRevoked { path: f(path) },
+            Error::LeaseLost { path } => Error::LeaseLost { path: f(path) },
+            Error::NoSpace {
+                path,
+                needed,
+                available,
+            } => Error::NoSpace {
+                path: f(path),
+                needed,
+                available,
+            },
+            Error::CommitFailed { backup, source } => Error::CommitFailed {
+                backup: backup.map(f),
+                source: Box::new(source.map_paths(f)),
+            },
+            // name no paths
+            error @ (Error::InvalidKey { .. }
+            | Error::QuotaExceeded { .. }
+            | Error::NeedsRecovery { .. }
+            | Error::IncompatibleFormat { .. }
+            | Error::ReplicationGap { .. }) => error,
+        }
+    }
+}
+
+/// Attaches what an io operation was doing and the path it was working on.
+pub(crate) trait IoResultExt<T> {
+    fn at<P: AsRef<Path>>(self, operation: &'static str, path: P) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn at<P: AsRef<Path>>(self, operation: &'static str, path: P) -> Result<T> {
+        self.map_err(|source| Error::io(operation, path, source))
+    }
+}
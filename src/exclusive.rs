@@ -0,0 +1,201 @@
+//! Exclusive access to a whole database, see [`Client::lock_all`]. The guard holds the maintenance lock and
+//! the root's write lock, which every other client waits for before it touches anything.
+//!
+//! The thread that took the guard may keep using the client it was taken with, and its clones, while it is
+//! held: their locks are covered by the guard and are not taken at all, so that they do not wait for the
+//! guard. Other threads, and other clients of the same root such as [`Client::detach`]ed ones, wait for it
+//! like other processes do.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+use crate::{
+    Client, Ctx, Error, GcOptions, GcReport, INTERNAL_DIR, Lock, Result, artifact, check_depth,
+    create_backup_ext, create_write_file_locks, error::IoResultExt, is_atomic_dir_link,
+    release_all, unused_hidden_path,
+};
+
+/// Threads that hold the database of a client exclusively, shared by its clones.
+#[derive(Default)]
+pub(crate) struct ExclusiveOwners {
+    owners: Mutex<Vec<ThreadId>>,
+    /// Length of `owners`, so that taking a lock does not have to lock it while the database is not held.
+    count: AtomicUsize,
+}
+
+impl ExclusiveOwners {
+    fn register(&self, owner: ThreadId) {
+        let mut owners = self.owners.lock().unwrap_or_else(|e| e.into_inner());
+        owners.push(owner);
+        self.count.store(owners.len(), Ordering::Release);
+    }
+
+    fn unregister(&self, owner: ThreadId) {
+        let mut owners = self.owners.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = owners.iter().rposition(|held_by| *held_by == owner) {
+            owners.remove(i);
+        }
+        self.count.store(owners.len(), Ordering::Release);
+    }
+}
+
+/// Whether the entry at `path` lies below the root of a client whose database the current thread holds
+/// exclusively, so that its lock must not be taken again.
+pub(crate) fn covers(ctx: &Ctx, path: &Path) -> bool {
+    let Some(exclusive) = &ctx.exclusive else {
+        return false;
+    };
+    if exclusive.count.load(Ordering::Acquire) == 0 || ctx.rpath(path).is_none() {
+        return false;
+    }
+    let current = thread::current().id();
+    let owners = exclusive.owners.lock().unwrap_or_else(|e| e.into_inner());
+    owners.contains(&current)
+}
+
+impl Client {
+    /// Take the maintenance lock and the root's write lock, so that no other client reads or writes anything
+    /// in the database until the returned guard is dropped. The maintenance lock is taken first, see
+    /// [`Client::maintenance_write`], so this waits for gc, exports, backups and stats, and for every guard
+    /// and transaction of other clients.
+    ///
+    /// Operations of this client and its clones on the thread that took the guard do not take locks while it
+    /// is held, so they neither wait for it nor for each other's guards: those must not outlive the guard,
+    /// and the guard must not be moved to another thread while they are used.
+    pub fn lock_all(&self) -> Result<DbExclusiveGaurd> {
+        self.redacted(|| {
+            let maintenance = self.maintenance_write()?;
+            let mut lock =
+                create_write_file_locks(&self.inner.root, Path::new(""), None, &self.ctx())?;
+            lock.push(Lock::Write(maintenance));
+            let owner = thread::current().id();
+            self.inner.exclusive.register(owner);
+            Ok(DbExclusiveGaurd {
+                client: self.clone(),
+                lock,
+                owner,
+                released: false,
+            })
+        })
+    }
+}
+
+/// Exclusive access to a whole database, see [`Client::lock_all`].
+pub struct DbExclusiveGaurd {
+    /// Client the guard was taken with, whose operations on the owner's thread take no locks.
+    pub(crate) client: Client,
+    lock: Vec<Lock>,
+    /// Thread that took the guard, whose locks below the root it covers.
+    owner: ThreadId,
+    released: bool,
+}
+
+/// What [`DbExclusiveGaurd::compact`] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompactReport {
+    pub gc: GcReport,
+    /// Directories that held no entries and were removed, see [`Client::prune_empty_dirs`].
+    pub dirs_removed: usize,
+}
+
+impl DbExclusiveGaurd {
+    pub fn root(&self) -> &Path {
+        &self.client.inner.root
+    }
+
+    /// Run `f` with the root of the database, during which no other client reads or writes it.
+    pub fn run<T, F: FnOnce(&Path) -> T>(&self, f: F) -> T {
+        f(&self.client.inner.root)
+    }
+
+    /// Restore the backups of interrupted commits and remove every temporary copy, backup and lock file that
+    /// is no longer needed regardless of its age, like [`Client::recover`] with a `min_age` of zero, which is
+    /// only safe while no one else can be committing. Directories left without entries are then removed.
+    ///
+    /// Files are not rewritten to undo their fragmentation. Whether a file is fragmented can not be told
+    /// portably, and rewriting every file would change the modification times that pollers and watchers go
+    /// by without changing any contents, so that is left to the filesystem's own tools.
+    pub fn compact(&self) -> Result<CompactReport> {
+        let gc = self.client.gc_with(&GcOptions {
+            min_age: Duration::ZERO,
+            restore_backups: true,
+            ..Default::default()
+        });
+        let dirs_removed = self.client.prune_empty_dirs("")?;
+        Ok(CompactReport { gc, dirs_removed })
+    }
+
+    /// Replace every atomic directory in the database with a plain directory holding its payload, without
+    /// copying anything, returning how many were replaced. Atomic directories inside them are replaced too.
+    ///
+    /// Each one is replaced by moving its payload aside as a backup, removing the link and moving the backup
+    /// into its place, so that a crash in between leaves the payload as a backup that [`Client::verify`]
+    /// reports, rather than one that gc removes for having no link.
+    pub fn rewrite_atomic_dirs_to_plain(&self) -> Result<usize> {
+        let client = &self.client;
+        let mut rewritten = 0;
+        let mut pending = vec![PathBuf::new()];
+        while let Some(rpath) = pending.pop() {
+            let dir = client.inner.root.join(&rpath);
+            check_depth(&dir, rpath.components().count(), client.inner.max_depth)?;
+            for entry in fs::read_dir(&dir).at("read directory", &dir)? {
+                let entry = entry.at("read directory", &dir)?;
+                let name = entry.file_name();
+                if (rpath.as_os_str().is_empty() && name == INTERNAL_DIR)
+                    || artifact::parse(&name).is_some()
+                {
+                    continue;
+                }
+                let path = entry.path();
+                let file_type = entry.file_type().at("read metadata of", &path)?;
+                if file_type.is_symlink() && is_atomic_dir_link(&path)? {
+                    self.rewrite_to_plain(&path)?;
+                    rewritten += 1;
+                } else if !file_type.is_dir() {
+                    continue;
+                }
+                pending.push(rpath.join(name));
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Replace the atomic directory whose link is at `link` with its payload.
+    fn rewrite_to_plain(&self, link: &Path) -> Result<()> {
+        let vfs = &self.client.inner.vfs;
+        let parent = link
+            .parent()
+            .ok_or_else(|| Error::invalid_path(link, "missing parent"))?;
+        let payload = parent.join(fs::read_link(link).at("read link", link)?);
+        let bak = unused_hidden_path(link, create_backup_ext)?;
+        vfs.rename(&payload, &bak).at("back up", &payload)?;
+        // does not follow the link, and removes links to directories, which are directories on windows
+        vfs.remove_dir_all(link).at("remove", link)?;
+        vfs.rename(&bak, link).at("restore backup", &bak)
+    }
+
+    /// Release the guard's locks, reporting the first failure instead of logging it on drop.
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.client.inner.exclusive.unregister(self.owner);
+        release_all(std::mem::take(&mut self.lock))
+    }
+}
+
+impl Drop for DbExclusiveGaurd {
+    fn drop(&mut self) {
+        // before the locks are released along with the fields
+        if !self.released {
+            self.client.inner.exclusive.unregister(self.owner);
+        }
+    }
+}
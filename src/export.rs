@@ -0,0 +1,136 @@
+use std::{fs, path::Path};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, Result, copy_file, copy_visible, error::IoResultExt, puuid,
+    sync_parent, sync_tree, unused_hidden_path,
+};
+
+/// What [`Client::export_dir`] does when its destination exists already.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingDest {
+    /// Fail with [`Error::AlreadyExists`].
+    #[default]
+    Fail,
+    /// Add the exported entries to the existing directory, replacing those with the same names and
+    /// keeping the others.
+    Merge,
+    /// Swap the existing directory for the export once it is complete.
+    Replace,
+}
+
+/// Controls how [`Client::export_dir`] writes its destination.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ExportOptions {
+    pub existing: ExistingDest,
+    /// Flush the exported tree to disk before it is moved into place.
+    pub sync: bool,
+}
+
+impl Client {
+    /// Copy the directory at `rpath` to `dest` outside the database, while it is read locked. Internal
+    /// files are left out and atomic directories are exported as plain directories. The export is
+    /// copied next to `dest` first and then moved into place, so `dest` never holds a partial export
+    /// unless it is merged into an existing directory.
+    ///
+    /// Writers of the directory or one of its ancestors wait for the export. The entries below are only
+    /// consistent with each other if they are changed through a copy of the directory, see
+    /// [`crate::DirWriteGaurd::cow`], since writing one of them does not write lock the directory. The
+    /// maintenance lock is held for reading first, see [`Client::maintenance_read`].
+    pub fn export_dir<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        dest: &Path,
+        options: &ExportOptions,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let _maintenance = self.maintenance_read()?;
+            let gaurd = self.read_dir(rpath)?;
+            let existing = fs::symlink_metadata(dest).is_ok();
+            if existing && options.existing == ExistingDest::Fail {
+                return Err(Error::AlreadyExists {
+                    path: dest.to_path_buf(),
+                });
+            }
+            let tmp = unused_hidden_path(dest, || format!(".{}.tmp", puuid()))?;
+            let internal = self.inner.root.join(INTERNAL_DIR);
+            let ctx = &gaurd.ctx;
+            let result = ctx
+                .copy(|stats| copy_visible(&gaurd.path, &tmp, &internal, ctx, stats))
+                .and_then(|()| match options.sync {
+                    true => sync_tree(&tmp),
+                    false => Ok(()),
+                })
+                .and_then(|()| match (existing, options.existing) {
+                    (true, ExistingDest::Merge) => merge(&tmp, dest),
+                    (true, ExistingDest::Replace) => replace(&tmp, dest),
+                    _ => fs::rename(&tmp, dest).at("move export to", dest),
+                })
+                .and_then(|()| match options.sync {
+                    true => sync_parent(dest),
+                    false => Ok(()),
+                });
+            if result.is_err() {
+                let _ = fs::remove_dir_all(&tmp);
+            }
+            result
+        })
+    }
+
+    /// Copy the file at `rpath` to `dest` outside the database, while it is read locked. An existing file
+    /// at `dest` is replaced atomically.
+    pub fn export_file<P: AsRef<Path>>(&self, rpath: P, dest: &Path) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.read_file(rpath)?;
+            let ctx = self.ctx();
+            let tmp = unused_hidden_path(dest, || format!(".{}.tmp", puuid()))?;
+            let result = ctx
+                .copy(|stats| copy_file(&gaurd.path, &tmp, &ctx, stats))
+                .and_then(|()| fs::rename(&tmp, dest).at("move export to", dest));
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            result
+        })
+    }
+}
+
+/// Moves the entries of the directory `src` into the directory `dest`, replacing entries of the same
+/// names unless both are directories, which are merged in turn.
+fn merge(src: &Path, dest: &Path) -> Result<()> {
+    let mut pending = vec![(src.to_path_buf(), dest.to_path_buf())];
+    while let Some((src, dest)) = pending.pop() {
+        for entry in fs::read_dir(&src).at("read directory", &src)? {
+            let entry = entry.at("read directory", &src)?;
+            let from = entry.path();
+            let to = dest.join(entry.file_name());
+            let is_dir = entry.file_type().at("read metadata of", &from)?.is_dir();
+            match fs::symlink_metadata(&to) {
+                Ok(existing) if is_dir && existing.is_dir() => {
+                    pending.push((from, to));
+                    continue;
+                }
+                Ok(existing) if existing.is_dir() => fs::remove_dir_all(&to).at("replace", &to)?,
+                Ok(_) if is_dir => fs::remove_file(&to).at("replace", &to)?,
+                _ => {}
+            }
+            fs::rename(&from, &to).at("move export to", &to)?;
+        }
+    }
+    fs::remove_dir_all(src).at("remove", src)
+}
+
+/// Swaps the existing entry at `dest` for `src`, putting it back if that fails.
+fn replace(src: &Path, dest: &Path) -> Result<()> {
+    let old = unused_hidden_path(dest, || format!(".{}.old", puuid()))?;
+    fs::rename(dest, &old).at("move aside", dest)?;
+    if let Err(e) = fs::rename(src, dest) {
+        let _ = fs::rename(&old, dest);
+        return Err(Error::io("move export to", dest, e));
+    }
+    match fs::symlink_metadata(&old) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&old),
+        _ => fs::remove_file(&old),
+    }
+    .at("remove", &old)
+}
@@ -0,0 +1,105 @@
+//! Named points in the commit paths where a failure can be injected, enabled with the `failpoints` feature.
+//! They sit in the windows in which a crash leaves evidence of an interrupted commit behind, so tests can
+//! check that [`crate::Client::recover`] brings the database back to a consistent state. Without the
+//! feature the points compile to nothing.
+//!
+//! Points are configured per process, either with [`set`] or through the [`FAILPOINTS_ENV`] variable, which
+//! is how tests configure a child process that is expected to crash.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{Error, Result};
+
+/// Actions as a comma separated list of `point=action`, e.g. `cow_dir_commit::after_bak_rename=abort`.
+pub const FAILPOINTS_ENV: &str = "SBDB_FAILPOINTS";
+
+/// After the original directory was renamed to its backup, before the copy takes its place.
+pub const COW_DIR_COMMIT_AFTER_BAK_RENAME: &str = "cow_dir_commit::after_bak_rename";
+/// After the temporary symlink to the new payload was created, before it replaces the current one.
+pub const COW_ATOMIC_COMMIT_AFTER_TMPLNK: &str = "cow_atomic_commit::after_tmplnk";
+/// Before the copy of a file is renamed over the original.
+pub const FILE_COW_COMMIT_BEFORE_RENAME: &str = "file_cow_commit::before_rename";
+
+pub const ALL: [&str; 3] = [
+    COW_DIR_COMMIT_AFTER_BAK_RENAME,
+    COW_ATOMIC_COMMIT_AFTER_TMPLNK,
+    FILE_COW_COMMIT_BEFORE_RENAME,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// The commit returns an error, without undoing what it did so far.
+    Error,
+    /// The process aborts, like it would on a crash.
+    Abort,
+}
+
+impl Action {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(Action::Error),
+            "abort" => Some(Action::Abort),
+            _ => None,
+        }
+    }
+}
+
+fn actions() -> &'static Mutex<HashMap<&'static str, Action>> {
+    static ACTIONS: OnceLock<Mutex<HashMap<&'static str, Action>>> = OnceLock::new();
+    ACTIONS.get_or_init(|| {
+        let mut actions = HashMap::new();
+        if let Ok(spec) = std::env::var(FAILPOINTS_ENV) {
+            for entry in spec.split(',').filter(|entry| !entry.is_empty()) {
+                let parsed = entry.split_once('=').and_then(|(point, action)| {
+                    Some((
+                        ALL.into_iter().find(|name| *name == point)?,
+                        Action::parse(action)?,
+                    ))
+                });
+                match parsed {
+                    Some((point, action)) => {
+                        actions.insert(point, action);
+                    }
+                    None => log::warn!(operation = "failpoints"; "invalid fail point: {}", entry),
+                }
+            }
+        }
+        Mutex::new(actions)
+    })
+}
+
+/// Take `action` whenever this process passes the point `name`, or nothing if `action` is `None`.
+///
+/// # Panics
+///
+/// If `name` is not one of [`ALL`].
+pub fn set(name: &str, action: Option<Action>) {
+    let name = ALL
+        .into_iter()
+        .find(|point| *point == name)
+        .unwrap_or_else(|| panic!("unknown fail point {}", name));
+    let mut actions = actions().lock().unwrap();
+    match action {
+        Some(action) => actions.insert(name, action),
+        None => actions.remove(name),
+    };
+}
+
+/// Called at the point `name` of a commit of `path`.
+pub(crate) fn hit(name: &'static str, path: &Path) -> Result<()> {
+    let action = actions().lock().unwrap().get(name).copied();
+    match action {
+        Some(Action::Error) => Err(Error::io(
+            "fail point",
+            path,
+            io::Error::other(format!("fail point {} triggered", name)),
+        )),
+        Some(Action::Abort) => std::process::abort(),
+        None => Ok(()),
+    }
+}
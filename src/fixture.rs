@@ -0,0 +1,212 @@
+//! Fixtures for tests of code built on sbdb, exported through [`crate::testkit`]: databases in temporary
+//! directories, trees described up front and compared afterwards, and the states that crashes leave behind.
+//!
+//! ```
+//! use sbdb::testkit::{TestClient, TreeBuilder, assert_tree_eq};
+//!
+//! let test = TestClient::new("fixture_example")?;
+//! let tree = TreeBuilder::new()
+//!     .dir("a", TreeBuilder::new().file("b.txt", "contents"))
+//!     .atomic_dir("c", TreeBuilder::new().file("d.txt", "more"));
+//! tree.build(&test.root)?;
+//! test.client.write_file("a/b.txt")?.cow()?.commit()?;
+//! assert_tree_eq(&test.root, &tree);
+//! # Ok::<(), sbdb::Error>(())
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, Result, artifact, create_backup_ext, error::IoResultExt,
+    is_atomic_dir_link, path_hidden_with_extension, puuid, unlocked, vfs,
+};
+
+/// A database in a fresh directory below the system's temporary directory, which is deleted with
+/// everything in it when dropped.
+pub struct TestClient {
+    pub client: Client,
+    pub root: PathBuf,
+}
+
+impl TestClient {
+    /// The directory is named `name`, followed by a puuid so that tests can run side by side.
+    pub fn new(name: &str) -> Result<Self> {
+        let root = std::env::temp_dir().join(name.to_string() + "-" + &puuid());
+        Ok(TestClient {
+            client: Client::new(&root)?,
+            root,
+        })
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.root) {
+            log::warn!(path:? = self.root, operation = "cleanup"; "failed to delete test db: {}", e);
+        }
+    }
+}
+
+/// Entries of a directory, either to be created by [`TreeBuilder::build`] or as found by
+/// [`TreeBuilder::read`]. sbdb's own files are never part of a tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeBuilder {
+    entries: BTreeMap<OsString, Node>,
+}
+
+/// An entry of a [`TreeBuilder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    File(Vec<u8>),
+    Dir(TreeBuilder),
+    /// A directory behind a link that is switched atomically, see [`crate::dir_cow_atomic`].
+    AtomicDir(TreeBuilder),
+    Symlink(PathBuf),
+}
+
+impl TreeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the file `name` holding `contents`.
+    pub fn file<N: Into<OsString>, C: AsRef<[u8]>>(self, name: N, contents: C) -> Self {
+        self.entry(name, Node::File(contents.as_ref().to_vec()))
+    }
+
+    pub fn dir<N: Into<OsString>>(self, name: N, tree: TreeBuilder) -> Self {
+        self.entry(name, Node::Dir(tree))
+    }
+
+    pub fn atomic_dir<N: Into<OsString>>(self, name: N, tree: TreeBuilder) -> Self {
+        self.entry(name, Node::AtomicDir(tree))
+    }
+
+    /// Add a symbolic link to a file at `target`, relative to the directory of the link.
+    pub fn symlink<N: Into<OsString>, P: Into<PathBuf>>(self, name: N, target: P) -> Self {
+        self.entry(name, Node::Symlink(target.into()))
+    }
+
+    /// Add `node` as `name`, replacing an entry of the same name.
+    pub fn entry<N: Into<OsString>>(mut self, name: N, node: Node) -> Self {
+        self.entries.insert(name.into(), node);
+        self
+    }
+
+    pub fn entries(&self) -> &BTreeMap<OsString, Node> {
+        &self.entries
+    }
+
+    /// Create the tree in `dir`, which is created if it is missing. Existing entries of the same names are
+    /// overwritten, others are left alone. Nothing is locked.
+    pub fn build<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).at("create directory", dir)?;
+        for (name, node) in &self.entries {
+            let path = dir.join(name);
+            match node {
+                Node::File(contents) => fs::write(&path, contents).at("write", &path)?,
+                Node::Dir(tree) => tree.build(&path)?,
+                Node::AtomicDir(tree) => {
+                    let copy = unlocked::dir_cow_atomic_unchecked(&path)?;
+                    tree.build(&copy.path)?;
+                    copy.commit()?;
+                }
+                Node::Symlink(target) => vfs::std()
+                    .symlink_file(target, &path)
+                    .at("create symlink", &path)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The tree found in `dir`, leaving out sbdb's own files and, at the root of a database, its internal
+    /// directory.
+    pub fn read<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut tree = TreeBuilder::new();
+        for entry in fs::read_dir(dir).at("read directory", dir)? {
+            let entry = entry.at("read directory", dir)?;
+            let name = entry.file_name();
+            let path = entry.path();
+            if name == INTERNAL_DIR || artifact::parse(&name).is_some() {
+                continue;
+            }
+            let file_type = entry.file_type().at("read metadata of", &path)?;
+            let node = if file_type.is_symlink() {
+                if is_atomic_dir_link(&path)? {
+                    Node::AtomicDir(Self::read(&path)?)
+                } else {
+                    Node::Symlink(fs::read_link(&path).at("read link", &path)?)
+                }
+            } else if file_type.is_dir() {
+                Node::Dir(Self::read(&path)?)
+            } else {
+                Node::File(fs::read(&path).at("read", &path)?)
+            };
+            tree.entries.insert(name, node);
+        }
+        Ok(tree)
+    }
+}
+
+/// Panics unless the tree in `dir` is `expected`, see [`TreeBuilder::read`].
+#[track_caller]
+pub fn assert_tree_eq<P: AsRef<Path>>(dir: P, expected: &TreeBuilder) {
+    let dir = dir.as_ref();
+    match TreeBuilder::read(dir) {
+        Ok(actual) => assert_eq!(expected, &actual, "tree in {}", dir.display()),
+        Err(e) => panic!("failed to read tree in {}: {}", dir.display(), e),
+    }
+}
+
+/// Leave a copy of the entry at `path` holding `contents` behind, as a process that crashes before it
+/// commits a file does. Returns the path of the copy.
+pub fn orphaned_temp<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<PathBuf> {
+    let tmp = path_hidden_with_extension(&path, artifact::TMP)?;
+    fs::write(&tmp, contents).at("write", &tmp)?;
+    Ok(tmp)
+}
+
+/// Make `path` the link of an atomic directory whose payload is missing, as if it had been deleted by hand.
+/// Returns the path of the missing payload.
+pub fn dangling_atomic_dir<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let payload =
+        path_hidden_with_extension(path, &format!(".{}{}", puuid(), artifact::ATOMIC_DIR))?;
+    let target = payload
+        .file_name()
+        .ok_or_else(|| Error::invalid_path(path, "missing file name"))?;
+    vfs::std()
+        .symlink_dir(Path::new(target), path)
+        .at("create symlink", path)?;
+    Ok(payload)
+}
+
+/// Where [`interrupted_dir_commit`] left the two halves of a directory commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterruptedCommit {
+    /// The directory as it was before the commit.
+    pub backup: PathBuf,
+    /// The copy that was about to replace it.
+    pub copy: PathBuf,
+}
+
+/// Leave the directory at `path` as a crash between the two renames of [`crate::CowDirGaurd::commit`]
+/// does: moved to a backup, with a copy holding `tree` not yet moved to its place.
+pub fn interrupted_dir_commit<P: AsRef<Path>>(
+    path: P,
+    tree: &TreeBuilder,
+) -> Result<InterruptedCommit> {
+    let path = path.as_ref();
+    let backup = path_hidden_with_extension(path, &create_backup_ext())?;
+    let copy = path_hidden_with_extension(path, artifact::TMP)?;
+    tree.build(&copy)?;
+    fs::rename(path, &backup).at("back up", path)?;
+    Ok(InterruptedCommit { backup, copy })
+}
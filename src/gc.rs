@@ -0,0 +1,503 @@
+//! Removing what interrupted commits and removed entries leave behind, see [`Client::gc`].
+
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    ArtifactKind, Client, Error, INTERNAL_DIR, Lock, Result, RetentionPolicy, VisitedDirs, Warning,
+    artifact, check_depth, error::IoResultExt, is_atomic_dir_link, ttl,
+};
+
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct GcOptions {
+    /// Only report what would be removed.
+    pub dry_run: bool,
+    /// Artifacts modified more recently than this are left alone.
+    pub min_age: Duration,
+    /// Rename backups whose original is missing back into place, see [`Client::recover`].
+    pub restore_backups: bool,
+    /// Remove entries whose expiry has passed, see [`Client::write_with_ttl`].
+    pub expire_ttl: bool,
+    /// Remove the backups that the policy does not keep once everything else is done, see
+    /// [`Client::vacuum_backups`]. Without one, the backups of entries that exist are removed with the other
+    /// artifacts.
+    pub vacuum_backups: Option<RetentionPolicy>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+#[non_exhaustive]
+pub struct GcReport {
+    pub dirs_scanned: usize,
+    pub lock_files_removed: usize,
+    pub temps_removed: usize,
+    pub backups_removed: usize,
+    pub backups_restored: usize,
+    pub payloads_removed: usize,
+    /// Generation counters of entries that no longer exist, see [`Client::subtree_generation`].
+    pub generations_removed: usize,
+    /// Entries removed because they expired, see [`GcOptions::expire_ttl`].
+    pub entries_expired: usize,
+    /// Expiries of entries that no longer exist.
+    pub expiries_removed: usize,
+    /// Metadata of entries that no longer exist, see [`Client::set_meta`].
+    pub metadata_removed: usize,
+    /// Rollups of directories that no longer exist, see [`Client::rollup`].
+    pub rollups_removed: usize,
+    /// Symbolic links to directories that are not atomic dirs. These are never traversed or modified.
+    pub symlinks_skipped: usize,
+    /// Atomic directories whose payload is gone, which are left for [`Client::repair`].
+    pub dangling_atomic_dirs: usize,
+    /// Backups removed by [`GcOptions::vacuum_backups`].
+    pub backups_vacuumed: usize,
+    pub errors: usize,
+}
+
+/// Handle to a background gc thread started by [`Client::spawn_gc`]. Dropping the handle stops the thread.
+pub struct GcHandle {
+    stop: mpsc::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+    last_report: Arc<Mutex<Option<GcReport>>>,
+    runs: Arc<AtomicU64>,
+}
+
+impl GcHandle {
+    /// Signals the background thread and waits for any in progress run to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    pub fn last_report(&self) -> Option<GcReport> {
+        self.last_report
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Number of completed gc runs.
+    pub fn runs(&self) -> u64 {
+        self.runs.load(Ordering::Acquire)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            log::error!(operation = "gc"; "gc thread panicked");
+        }
+    }
+}
+
+impl Drop for GcHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+pub(crate) fn is_older_than(path: &Path, age: Duration) -> bool {
+    if age.is_zero() {
+        return true;
+    }
+    fs::symlink_metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.elapsed().ok())
+        .is_some_and(|elapsed| elapsed >= age)
+}
+
+impl Client {
+    /// After lots of modifications have happened in the database, its possible for lock files, temporary files
+    /// and backups to accumulate. This dynamically scans the database structure and safely removes files that
+    /// are no longer needed. If this is scanning a very large database, it may take a long time. It is recomended
+    /// that this procedure be run on a background thread/proccess, see [`Client::spawn_gc`].
+    pub fn gc(&self) -> GcReport {
+        self.gc_with(&GcOptions::default())
+    }
+
+    /// Brings the database back to a consistent state after a process crashed while committing. Every
+    /// directory commit that was interrupted between its two renames is rolled back by restoring the backup,
+    /// then the copies that were never committed are removed like [`Client::gc`] does.
+    pub fn recover(&self) -> GcReport {
+        self.gc_with(&GcOptions {
+            restore_backups: true,
+            ..Default::default()
+        })
+    }
+
+    /// Same as [`Client::gc`] but with explicit options. Each directory is scanned while holding its read
+    /// lock, and write locked again for each artifact that is removed, so no other client can be using it
+    /// then. Errors do not abort the scan, they are counted in the report and passed to the warning
+    /// callback, which includes directories nested deeper than
+    /// [`ClientBuilder::max_depth`](crate::ClientBuilder::max_depth) and links that lead back to a directory
+    /// already scanned.
+    ///
+    /// The maintenance lock is held for writing throughout, for reading on a dry run, see
+    /// [`Client::maintenance_write`]. Nothing is scanned if it can not be taken in time.
+    pub fn gc_with(&self, options: &GcOptions) -> GcReport {
+        let mut report = GcReport::default();
+        let maintenance = match options.dry_run {
+            true => self.maintenance_read().map(Lock::Read),
+            false => self.maintenance_write().map(Lock::Write),
+        };
+        let _maintenance = match maintenance {
+            Ok(lock) => lock,
+            Err(error) => {
+                report.errors += 1;
+                self.warn(Warning::Gc {
+                    path: self.maintenance_lock_path(),
+                    error,
+                });
+                return report;
+            }
+        };
+        let mut visited = VisitedDirs::default();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(rpath) = pending.pop() {
+            let path = self.inner.root.join(&rpath);
+            let result = check_depth(&path, rpath.components().count(), self.inner.max_depth)
+                .and_then(|()| self.gc_dir(&rpath, options, &mut visited, &mut report));
+            match result {
+                Ok(children) => pending.extend(children),
+                Err(error) => {
+                    report.errors += 1;
+                    self.warn(Warning::Gc { path, error });
+                }
+            }
+        }
+        if let Some(policy) = &options.vacuum_backups {
+            match self.vacuum(Path::new(""), policy, options.dry_run) {
+                Ok(vacuum) => report.backups_vacuumed += vacuum.removed.len(),
+                Err(error) => {
+                    report.errors += 1;
+                    self.warn(Warning::Gc {
+                        path: self.inner.root.clone(),
+                        error,
+                    });
+                }
+            }
+        }
+        self.gc_scratch(options.min_age, options.dry_run, &mut report);
+        self.inner.metrics.gc_run(&report);
+        report
+    }
+
+    fn gc_dir(
+        &self,
+        rpath: &Path,
+        options: &GcOptions,
+        visited: &mut VisitedDirs,
+        report: &mut GcReport,
+    ) -> Result<Vec<PathBuf>> {
+        let mut children = Vec::new();
+        let mut expired = Vec::new();
+        let mut artifacts = Vec::new();
+        let gaurd = self.read_dir(rpath)?;
+        let path = gaurd.path.clone();
+        // checked under the lock, until it is taken a commit may have moved the directory away
+        visited.enter(&path)?;
+        report.dirs_scanned += 1;
+
+        for entry in self.inner.vfs.read_dir(&path).at("read directory", &path)? {
+            let entry = entry.at("read directory", &path)?;
+            let name = entry.file_name();
+            let child_path = entry.path();
+            let metadata = match fs::symlink_metadata(&child_path) {
+                Ok(metadata) => metadata,
+                // removed already, the name goes once the last handle to it is closed
+                #[cfg(windows)]
+                Err(_) if crate::winfs::is_delete_pending(&child_path) => continue,
+                Err(e) => return Err(Error::io("read metadata of", &child_path, e)),
+            };
+            let file_type = metadata.file_type();
+
+            if rpath.as_os_str().is_empty() && name == INTERNAL_DIR {
+                continue;
+            }
+
+            let Some((kind, orig_name)) = artifact::parse(&name) else {
+                if file_type.is_dir() {
+                    children.push(rpath.join(&name));
+                } else if file_type.is_symlink() {
+                    // only atomic dirs are followed, anything else could lead outside of the database
+                    if is_atomic_dir_link(&child_path)? {
+                        match child_path.is_dir() {
+                            true => children.push(rpath.join(&name)),
+                            false => report.dangling_atomic_dirs += 1,
+                        }
+                    } else if child_path.is_dir() {
+                        report.symlinks_skipped += 1;
+                    }
+                }
+                continue;
+            };
+            // named like an artifact by someone else, reported by verify
+            if !artifact::is_own(kind, &metadata) {
+                continue;
+            }
+
+            if kind == ArtifactKind::Ttl && options.expire_ttl && path.join(&orig_name).exists() {
+                match ttl::read_expiry(&child_path) {
+                    Ok(Some(expires)) if expires <= SystemTime::now() => expired.push(orig_name),
+                    Ok(_) => {}
+                    Err(error) => {
+                        report.errors += 1;
+                        self.warn(Warning::Gc {
+                            path: child_path,
+                            error,
+                        });
+                    }
+                }
+                continue;
+            }
+
+            if let Some(action) = action(&path, &name, kind, &orig_name, options) {
+                artifacts.push((name, kind, orig_name, action));
+            }
+        }
+        // readers of the directory only wait for the artifacts that are removed, not for the whole scan
+        drop(gaurd);
+
+        for (name, kind, orig_name, action) in artifacts {
+            let child_path = path.join(&name);
+            let orig_path = path.join(&orig_name);
+            let is_dir = fs::symlink_metadata(&child_path).is_ok_and(|m| m.is_dir());
+            let done = match options.dry_run {
+                true => Some(action),
+                false => match self.gc_artifact(rpath, &name, kind, &orig_name, options) {
+                    Ok(done) => done,
+                    Err(error) => {
+                        report.errors += 1;
+                        self.warn(Warning::Gc {
+                            path: child_path,
+                            error,
+                        });
+                        continue;
+                    }
+                },
+            };
+            match done {
+                None => {}
+                Some(Action::Restore) => {
+                    report.backups_restored += 1;
+                    if is_dir {
+                        children.push(rpath.join(&orig_name));
+                    }
+                }
+                Some(Action::Remove) => {
+                    if let (Some(cache), ArtifactKind::Lock | ArtifactKind::Queue) =
+                        (&self.inner.lock_cache, kind)
+                    {
+                        cache.evict(&orig_path);
+                    }
+                    match kind {
+                        ArtifactKind::Lock | ArtifactKind::Queue | ArtifactKind::LockDir => {
+                            report.lock_files_removed += 1
+                        }
+                        ArtifactKind::Tmp | ArtifactKind::TmpLink => report.temps_removed += 1,
+                        ArtifactKind::Backup => report.backups_removed += 1,
+                        ArtifactKind::AtomicDir => report.payloads_removed += 1,
+                        ArtifactKind::Generation => report.generations_removed += 1,
+                        ArtifactKind::Ttl => report.expiries_removed += 1,
+                        ArtifactKind::Meta => report.metadata_removed += 1,
+                        ArtifactKind::Rollup => report.rollups_removed += 1,
+                    }
+                }
+            }
+        }
+
+        // removed once everything else was looked at, since their lock files may have been listed already
+        for name in expired {
+            let locks_removed = match options.dry_run {
+                true => Ok(Some(0)),
+                false => self.gc_expired(rpath, &name),
+            };
+            match locks_removed {
+                Ok(None) => {}
+                Ok(Some(locks_removed)) => {
+                    report.entries_expired += 1;
+                    report.lock_files_removed += locks_removed;
+                    let rpath = rpath.join(&name);
+                    children.retain(|child| *child != rpath);
+                }
+                Err(error) => {
+                    report.errors += 1;
+                    self.warn(Warning::Gc {
+                        path: path.join(&name),
+                        error,
+                    });
+                }
+            }
+        }
+
+        Ok(children)
+    }
+
+    /// Restore or remove the artifact `name` of the directory at `rpath` while the directory is write
+    /// locked, if it still is what the scan found. Returns what was done with it, `None` if it was left.
+    fn gc_artifact(
+        &self,
+        rpath: &Path,
+        name: &OsStr,
+        kind: ArtifactKind,
+        orig_name: &OsStr,
+        options: &GcOptions,
+    ) -> Result<Option<Action>> {
+        let gaurd = self.write_dir(rpath)?;
+        let path = &gaurd.path;
+        let child_path = path.join(name);
+        // restored, removed or replaced since the directory was scanned
+        let Ok(metadata) = fs::symlink_metadata(&child_path) else {
+            return Ok(None);
+        };
+        if !artifact::is_own(kind, &metadata) {
+            return Ok(None);
+        }
+        let Some(action) = action(path, name, kind, orig_name, options) else {
+            return Ok(None);
+        };
+
+        #[cfg(feature = "testkit")]
+        crate::testkit::pause(crate::testkit::PausePoint::GcRemove);
+        match action {
+            Action::Restore => {
+                let orig_path = path.join(orig_name);
+                self.inner
+                    .vfs
+                    .rename(&child_path, &orig_path)
+                    .at("restore backup", &child_path)?;
+            }
+            Action::Remove => match metadata.is_dir() {
+                true => self.inner.vfs.remove_dir_all(&child_path),
+                false => self.inner.vfs.remove_file(&child_path),
+            }
+            .at("remove", &child_path)?,
+        }
+        gaurd.release()?;
+        Ok(Some(action))
+    }
+
+    /// Remove the expired entry `name` of the directory at `rpath` while the directory is write locked,
+    /// unless its expiry was pushed back since the directory was scanned. Returns the number of lock files
+    /// removed along with it, `None` if it was left.
+    fn gc_expired(&self, rpath: &Path, name: &OsStr) -> Result<Option<usize>> {
+        let gaurd = self.write_dir(rpath)?;
+        let path = gaurd.path.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        match ttl::read_expiry(&ttl::sidecar(&path)?)? {
+            Some(expires) if expires <= SystemTime::now() => {}
+            _ => return Ok(None),
+        }
+        let locks_removed = self.expire(&gaurd, name)?;
+        gaurd.release()?;
+        Ok(Some(locks_removed))
+    }
+
+    /// Runs [`Client::gc_with`] on a background thread every `interval`. If a run takes longer than the
+    /// interval, the missed cycles are skipped rather than queued up. The returned handle owns a clone of this
+    /// `Client` (and therefore its callbacks), which lives until the handle is stopped or dropped.
+    pub fn spawn_gc(&self, interval: Duration, options: GcOptions) -> GcHandle {
+        let client = self.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let last_report = Arc::new(Mutex::new(None));
+        let runs = Arc::new(AtomicU64::new(0));
+
+        let thread = {
+            let last_report = last_report.clone();
+            let runs = runs.clone();
+            thread::spawn(move || {
+                let mut next = Instant::now() + interval;
+                loop {
+                    let timeout = next.saturating_duration_since(Instant::now());
+                    match stopped.recv_timeout(timeout) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => (),
+                        _ => break,
+                    }
+
+                    let report = client.gc_with(&options);
+                    *last_report.lock().unwrap_or_else(|e| e.into_inner()) = Some(report);
+                    runs.fetch_add(1, Ordering::AcqRel);
+
+                    next += interval;
+                    let now = Instant::now();
+                    if next < now {
+                        let missed =
+                            ((now - next).as_nanos() / interval.as_nanos().max(1)) as u32 + 1;
+                        next += interval * missed;
+                        client.warn(Warning::GcCycleSkipped { missed });
+                    }
+                }
+            })
+        };
+
+        GcHandle {
+            stop,
+            thread: Some(thread),
+            last_report,
+            runs,
+        }
+    }
+}
+
+/// What gc does with an artifact.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    /// Rename a backup back into the place of its missing original.
+    Restore,
+    Remove,
+}
+
+/// What gc does with the artifact `name` of `kind` in the directory at `dir`, `None` if it is left alone.
+fn action(
+    dir: &Path,
+    name: &OsStr,
+    kind: ArtifactKind,
+    orig_name: &OsStr,
+    options: &GcOptions,
+) -> Option<Action> {
+    let child_path = dir.join(name);
+    let orig_path = dir.join(orig_name);
+    if kind == ArtifactKind::Backup
+        && options.restore_backups
+        && fs::symlink_metadata(&orig_path).is_err()
+        && is_older_than(&child_path, options.min_age)
+    {
+        return Some(Action::Restore);
+    }
+
+    let remove = match kind {
+        ArtifactKind::Lock
+        | ArtifactKind::Queue
+        | ArtifactKind::Generation
+        | ArtifactKind::Ttl
+        | ArtifactKind::Meta
+        | ArtifactKind::Rollup => !orig_path.exists(),
+        ArtifactKind::Tmp | ArtifactKind::TmpLink => true,
+        // held locks, a stale one is taken over by the next process that wants it
+        ArtifactKind::LockDir => false,
+        // a backup without its original is evidence of an interrupted commit, leave it for recovery, and
+        // the policy decides which backups are kept, those of entries that exist among them
+        ArtifactKind::Backup => options.vacuum_backups.is_none() && orig_path.exists(),
+        ArtifactKind::AtomicDir => match fs::read_link(&orig_path) {
+            Ok(target) => target != Path::new(name),
+            Err(_) => true,
+        },
+    };
+    (remove && is_older_than(&child_path, options.min_age)).then_some(Action::Remove)
+}
@@ -0,0 +1,143 @@
+//! Generation counters for cheap change detection, enabled with
+//! [`crate::ClientBuilder::track_generations`]. Every commit bumps the counter of the committed entry and
+//! of each of its ancestors, so comparing the generation of a path with an earlier one tells whether
+//! anything below it may have changed.
+//!
+//! The counter of an entry is stored next to it in `.name.gen.sbdb`, the root's in `.sbdb/generation`. An
+//! entry without a counter uses its parent's. Counters are bumped to at least the current time in
+//! nanoseconds, so a counter that is lost and recreated does not repeat an earlier value. They are bumped
+//! once before a commit's final rename, so that a crash can only over-report, and once after it, so that a
+//! reader which saw the first bump and the previous contents still sees a change afterwards.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+#[cfg(windows)]
+use std::os::windows::prelude::*;
+
+use crate::{
+    Client, INTERNAL_DIR, Result, artifact, error::IoResultExt, normalize_rpath,
+    path_hidden_with_extension,
+};
+
+#[cfg(windows)]
+use crate::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+pub(crate) struct Generations {
+    root: PathBuf,
+}
+
+impl Generations {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        Generations { root }
+    }
+
+    /// Bumps the counters of `orig` and all of its ancestors. Must be called while `orig` is write locked.
+    pub(crate) fn bump(&self, orig: &Path) -> Result<()> {
+        let Ok(rpath) = orig.strip_prefix(&self.root) else {
+            return Ok(());
+        };
+        if rpath.starts_with(INTERNAL_DIR) {
+            return Ok(());
+        }
+        let internal = self.root.join(INTERNAL_DIR);
+        fs::create_dir_all(&internal).at("create directory", &internal)?;
+        for ancestor in rpath.ancestors() {
+            let path = counter_path(&self.root, ancestor)?;
+            let file = open_counter(&path)?;
+            file.lock().at("lock", &path)?;
+            let result = (|| {
+                let current = match read_counter(&file, &path)? {
+                    Some(current) => current,
+                    None => parent_generation(&self.root, ancestor)?,
+                };
+                let next = now().max(current.saturating_add(1));
+                write_counter(&file, &path, next)
+            })();
+            file.unlock().at("unlock", &path)?;
+            result?;
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn counter_path(root: &Path, rpath: &Path) -> Result<PathBuf> {
+    if rpath.as_os_str().is_empty() {
+        Ok(root.join(INTERNAL_DIR).join("generation"))
+    } else {
+        path_hidden_with_extension(root.join(rpath), artifact::GENERATION)
+    }
+}
+
+fn open_counter(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(windows)]
+    options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+    options.open(path).at("open generation counter", path)
+}
+
+/// A counter left unreadable by a crash is treated as missing, which can only over-report.
+fn read_counter(mut file: &File, path: &Path) -> Result<Option<u64>> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).at("read", path)?;
+    file.read_to_string(&mut contents).at("read", path)?;
+    Ok(contents.trim().parse().ok())
+}
+
+fn write_counter(mut file: &File, path: &Path, value: u64) -> Result<()> {
+    // fixed width, so the new value always overwrites the previous one completely
+    file.seek(SeekFrom::Start(0)).at("write", path)?;
+    file.write_all(format!("{:020}", value).as_bytes())
+        .at("write", path)
+}
+
+pub(crate) fn generation(root: &Path, rpath: &Path) -> Result<u64> {
+    let path = counter_path(root, rpath)?;
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return parent_generation(root, rpath);
+        }
+        Err(e) => return Err(crate::Error::io("open generation counter", &path, e)),
+    };
+    file.lock_shared().at("lock", &path)?;
+    let result = read_counter(&file, &path);
+    file.unlock().at("unlock", &path)?;
+    match result? {
+        Some(value) => Ok(value),
+        None => parent_generation(root, rpath),
+    }
+}
+
+fn parent_generation(root: &Path, rpath: &Path) -> Result<u64> {
+    match rpath.parent() {
+        Some(parent) => generation(root, parent),
+        None => Ok(0),
+    }
+}
+
+impl Client {
+    /// A value that changes whenever something at or below `rpath` is committed by a client that tracks
+    /// generations. Values are only meaningful for comparing with earlier values of the same path.
+    pub fn subtree_generation<P: AsRef<Path>>(&self, rpath: P) -> Result<u64> {
+        self.redacted(|| generation(&self.inner.root, &normalize_rpath(rpath.as_ref())?))
+    }
+
+    /// Whether anything at or below `rpath` may have been committed since [`Client::subtree_generation`]
+    /// returned `last_seen`. May report changes that did not happen, but never misses one.
+    pub fn changed_since<P: AsRef<Path>>(&self, rpath: P, last_seen: u64) -> Result<bool> {
+        self.redacted(|| Ok(self.subtree_generation(rpath)? != last_seen))
+    }
+}
@@ -0,0 +1,290 @@
+//! Content digests of files and subtrees, computed under read locks so that no writer can change what is
+//! being hashed.
+//!
+//! The digest of a file is the digest of its contents. The digest of a subtree is defined as
+//!
+//! ```text
+//! H("sbdb-tree-v1\n" || path_1 || 0x00 || digest_1 || path_2 || 0x00 || digest_2 || ...)
+//! ```
+//!
+//! over every file below the subtree, where `path_i` is the file's path relative to the subtree as UTF-8
+//! with its components joined by `/` whatever the platform, `digest_i` is the raw digest of the file, and
+//! the files are ordered by the bytes of their paths. `H` is the same algorithm for files and the tree.
+//! Atomic directories count as the directories they stand for, while sbdb's internal directory and own
+//! files, other symbolic links and empty directories are left out. A file hashed on its own is a tree
+//! with a single file at the empty path.
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    Client, Error, FileReadGaurd, INTERNAL_DIR, Result, VisitedDirs, artifact, check_depth,
+    error::IoResultExt, is_atomic_dir_link,
+};
+
+const TREE_PREFIX: &[u8] = b"sbdb-tree-v1\n";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Considerably faster than SHA-256 on large files, built with the `blake3` feature.
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(<sha2::Sha256 as sha2::Digest>::new()),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Digest of a file's contents, both algorithms produce 32 bytes. Displayed as lowercase hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: HashAlgorithm,
+    bytes: [u8; 32],
+}
+
+impl Digest {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    /// Digest displayed as `hex`, `None` unless that is 64 lowercase hex digits.
+    pub(crate) fn from_hex(algorithm: HashAlgorithm, hex: &str) -> Option<Digest> {
+        if hex.len() != 64 || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return None;
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(Digest { algorithm, bytes })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+/// Digest of a subtree as defined in the [module documentation](self), with what went into it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TreeDigest {
+    pub digest: Digest,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for TreeDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.digest.fmt(f)
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => sha2::Digest::update(hasher, bytes),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    fn finish(self) -> Digest {
+        match self {
+            Hasher::Sha256(hasher) => Digest {
+                algorithm: HashAlgorithm::Sha256,
+                bytes: sha2::Digest::finalize(hasher).into(),
+            },
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => Digest {
+                algorithm: HashAlgorithm::Blake3,
+                bytes: *hasher.finalize().as_bytes(),
+            },
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileReadGaurd {
+    /// Digest of the contents of the locked file.
+    pub fn hash(&self, algorithm: HashAlgorithm) -> Result<Digest> {
+        self.check_revoked()?;
+        Ok(hash_file(&self.path, algorithm)?.0)
+    }
+}
+
+impl Client {
+    /// Digest of the subtree at `rpath`, see the [module documentation](self) for its definition. The
+    /// subtree is read locked for the whole walk and every file is read locked while it is hashed, so each
+    /// file is hashed in a committed state. As with [`Client::export_dir`], files are only consistent with
+    /// each other if they are changed through a copy of the directory.
+    pub fn hash_tree<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        algorithm: HashAlgorithm,
+    ) -> Result<TreeDigest> {
+        self.redacted(|| {
+            let gaurd = self.read_dir(rpath)?;
+            let mut tree = algorithm.hasher();
+            tree.update(TREE_PREFIX);
+            let metadata = fs::metadata(&gaurd.path).at("read metadata of", &gaurd.path)?;
+            if metadata.is_file() {
+                let (digest, bytes) = hash_file(&gaurd.path, algorithm)?;
+                tree.update(&[0]);
+                tree.update(&digest.bytes);
+                return Ok(TreeDigest {
+                    digest: tree.finish(),
+                    files: 1,
+                    bytes,
+                });
+            }
+
+            let mut files = self
+                .data_files(&gaurd.path)?
+                .into_iter()
+                .map(|rel| Ok((portable_path(&rel)?, rel)))
+                .collect::<Result<Vec<_>>>()?;
+            files.sort();
+
+            let mut total = 0;
+            for (name, rel) in &files {
+                let file = gaurd.read_file(rel)?;
+                let (digest, bytes) = hash_file(&file.path, algorithm)?;
+                file.release()?;
+                tree.update(name.as_bytes());
+                tree.update(&[0]);
+                tree.update(&digest.bytes);
+                total += bytes;
+            }
+            Ok(TreeDigest {
+                digest: tree.finish(),
+                files: files.len() as u64,
+                bytes: total,
+            })
+        })
+    }
+
+    /// Paths relative to the directory `dir`, which the caller read locks, of the files below it that hash
+    /// into its digest, in no particular order.
+    pub(crate) fn data_files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let internal = self.inner.root.join(INTERNAL_DIR);
+        let mut visited = VisitedDirs::default();
+        let mut files = Vec::new();
+        let mut pending = vec![(dir.to_path_buf(), 0)];
+        while let Some((current, depth)) = pending.pop() {
+            check_depth(&current, depth, self.inner.max_depth)?;
+            visited.enter(&current)?;
+            for entry in self
+                .inner
+                .vfs
+                .read_dir(&current)
+                .at("read directory", &current)?
+            {
+                let entry = entry.at("read directory", &current)?;
+                let path = entry.path();
+                if path == internal || artifact::parse(&entry.file_name()).is_some() {
+                    continue;
+                }
+                let file_type = entry.file_type().at("read metadata of", &path)?;
+                if file_type.is_dir() || (file_type.is_symlink() && is_atomic_dir_link(&path)?) {
+                    pending.push((path, depth + 1));
+                } else if file_type.is_file() {
+                    files.push(path.strip_prefix(dir).unwrap_or(&path).to_path_buf());
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Digest of the file at `path` and its length.
+pub(crate) fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<(Digest, u64)> {
+    let mut file = fs::File::open(path).at("open", path)?;
+    let mut hasher = algorithm.hasher();
+    let bytes = io::copy(&mut file, &mut hasher).at("read", path)?;
+    Ok((hasher.finish(), bytes))
+}
+
+/// Copy everything `reader` holds to `writer`, returning its digest and length.
+pub(crate) fn copy_hashing<R: io::Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    algorithm: HashAlgorithm,
+) -> io::Result<(Digest, u64)> {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; 1 << 16];
+    let mut len = 0;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Ok((hasher.finish(), len)),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..read]);
+        writer.write_all(&buf[..read])?;
+        len += read as u64;
+    }
+}
+
+/// `rel` with its components joined by `/`, the same on every platform.
+pub(crate) fn portable_path(rel: &Path) -> Result<String> {
+    let mut portable = String::new();
+    for component in rel.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        let Some(name) = name.to_str() else {
+            return Err(Error::InvalidPath {
+                path: rel.to_path_buf(),
+                reason: "can not be hashed, it is not valid UTF-8",
+            });
+        };
+        if !portable.is_empty() {
+            portable.push('/');
+        }
+        portable.push_str(name);
+    }
+    Ok(portable)
+}
@@ -0,0 +1,64 @@
+//! Locks held through a client and its clones, see [`Client::held_locks`]. Every lock a client's guards and
+//! transactions take is recorded until it is released, whichever clone or thread took it.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{Client, LockMode};
+
+/// A lock held through a client, as listed by [`Client::held_locks`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct HeldLock {
+    /// The locked entry, relative to the root.
+    pub path: PathBuf,
+    pub mode: LockMode,
+}
+
+/// Locks held through the clones of a client, in the order they were taken.
+#[derive(Default)]
+pub(crate) struct HeldLocks {
+    locks: Mutex<Vec<(PathBuf, LockMode)>>,
+}
+
+impl HeldLocks {
+    pub(crate) fn acquired(&self, path: &Path, mode: LockMode) {
+        self.lock().push((path.to_path_buf(), mode));
+    }
+
+    pub(crate) fn released(&self, path: &Path, mode: LockMode) {
+        let mut locks = self.lock();
+        if let Some(i) = locks.iter().rposition(|(p, m)| p == path && *m == mode) {
+            locks.remove(i);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(PathBuf, LockMode)>> {
+        self.locks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Client {
+    /// Locks currently held by the guards and transactions of this client and its clones, sorted by path. A
+    /// lock taken several times is listed as often. Locks of other processes, and of detached clients, see
+    /// [`Client::detach`], are not.
+    pub fn held_locks(&self) -> Vec<HeldLock> {
+        let mut held: Vec<HeldLock> = self
+            .inner
+            .held
+            .lock()
+            .iter()
+            .map(|(path, mode)| HeldLock {
+                path: path
+                    .strip_prefix(&self.inner.root)
+                    .unwrap_or(path)
+                    .to_path_buf(),
+                mode: *mode,
+            })
+            .collect();
+        held.sort_by(|a, b| a.path.cmp(&b.path));
+        held
+    }
+}
@@ -0,0 +1,180 @@
+//! Lock wait histograms, a [`Metrics`] implementation for alerting on how long locks are waited for rather
+//! than on totals. Waits are labelled by mode and by how deep the entry is below the root, never by path,
+//! so the number of series stays the same however many entries a database holds.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{LockMode, Metrics};
+
+/// Upper bounds of the buckets of every histogram in nanoseconds, from a microsecond growing fourfold up
+/// to about 16 seconds. Longer waits are only counted in the `+Inf` bucket.
+pub const LOCK_WAIT_BUCKETS_NANOS: [u64; 13] = {
+    let mut bounds = [0; 13];
+    let mut i = 0;
+    while i < bounds.len() {
+        bounds[i] = 1_000 << (2 * i);
+        i += 1;
+    }
+    bounds
+};
+
+/// Entries this deep below the root or deeper share the last depth label, rendered as `4+`.
+pub const MAX_DEPTH_LABEL: usize = 4;
+
+const MODES: [LockMode; 2] = [LockMode::Read, LockMode::Write];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Waits in each bucket alone, the last one for those longer than every bound.
+    buckets: [AtomicU64; LOCK_WAIT_BUCKETS_NANOS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, wait: Duration) {
+        let nanos = wait.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = LOCK_WAIT_BUCKETS_NANOS.partition_point(|&bound| bound < nanos);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// [`Metrics`] implementation that keeps a histogram of lock waits for every lock mode and depth, see
+/// [`HistogramMetrics::snapshot`] and [`HistogramMetrics::render_prometheus`]. Recording a wait takes two
+/// atomic additions.
+#[derive(Debug, Default)]
+pub struct HistogramMetrics {
+    /// By mode, then by depth.
+    lock_waits: [[Histogram; MAX_DEPTH_LABEL + 1]; MODES.len()],
+}
+
+impl HistogramMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The histograms as they are now. Waits recorded while the snapshot is taken may be counted in some
+    /// of its figures and not yet in others.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut lock_waits = Vec::new();
+        for (mode, by_depth) in MODES.iter().zip(self.lock_waits.iter()) {
+            for (depth, histogram) in by_depth.iter().enumerate() {
+                let mut count = 0;
+                let mut buckets = Vec::with_capacity(LOCK_WAIT_BUCKETS_NANOS.len());
+                for (bound, bucket) in LOCK_WAIT_BUCKETS_NANOS.iter().zip(&histogram.buckets) {
+                    count += bucket.load(Ordering::Relaxed);
+                    buckets.push(HistogramBucket {
+                        le_seconds: *bound as f64 / 1e9,
+                        count,
+                    });
+                }
+                count += histogram.buckets[LOCK_WAIT_BUCKETS_NANOS.len()].load(Ordering::Relaxed);
+                lock_waits.push(LockWaitHistogram {
+                    mode: *mode,
+                    depth: depth_label(depth),
+                    buckets,
+                    count,
+                    sum_seconds: histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+                });
+            }
+        }
+        MetricsSnapshot { lock_waits }
+    }
+
+    /// The histograms in the Prometheus text exposition format, see [`MetricsSnapshot::render_prometheus`].
+    pub fn render_prometheus(&self) -> String {
+        self.snapshot().render_prometheus()
+    }
+}
+
+impl Metrics for HistogramMetrics {
+    fn lock_wait(&self, mode: LockMode, depth: usize, wait: Duration) {
+        let mode = match mode {
+            LockMode::Read => 0,
+            LockMode::Write => 1,
+        };
+        self.lock_waits[mode][depth.min(MAX_DEPTH_LABEL)].observe(wait);
+    }
+}
+
+fn depth_label(depth: usize) -> String {
+    match depth {
+        MAX_DEPTH_LABEL => format!("{MAX_DEPTH_LABEL}+"),
+        depth => depth.to_string(),
+    }
+}
+
+/// Histograms of a [`HistogramMetrics`] at one point in time.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[non_exhaustive]
+pub struct MetricsSnapshot {
+    /// One histogram for every lock mode and depth label, including those without any waits.
+    pub lock_waits: Vec<LockWaitHistogram>,
+}
+
+/// Waits for locks of one mode on entries at one depth below the root.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[non_exhaustive]
+pub struct LockWaitHistogram {
+    pub mode: LockMode,
+    /// Components between the root and the entry, `0` for the root itself, and [`MAX_DEPTH_LABEL`]
+    /// followed by `+` for everything that deep or deeper.
+    pub depth: String,
+    /// Cumulative like Prometheus buckets: each counts the waits up to its bound, so the counts never
+    /// decrease. Waits longer than the last bound are only part of `count`.
+    pub buckets: Vec<HistogramBucket>,
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[non_exhaustive]
+pub struct HistogramBucket {
+    /// Upper bound of the bucket, inclusive.
+    pub le_seconds: f64,
+    pub count: u64,
+}
+
+impl MetricsSnapshot {
+    /// The histograms as `sbdb_lock_wait_seconds` in the Prometheus text exposition format, to be served
+    /// from an endpoint of the application.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP sbdb_lock_wait_seconds Time spent waiting to acquire locks.\n");
+        out.push_str("# TYPE sbdb_lock_wait_seconds histogram\n");
+        for histogram in &self.lock_waits {
+            let mode = match histogram.mode {
+                LockMode::Read => "read",
+                LockMode::Write => "write",
+            };
+            let labels = format!("mode=\"{mode}\",depth=\"{}\"", histogram.depth);
+            for bucket in &histogram.buckets {
+                let _ = writeln!(
+                    out,
+                    "sbdb_lock_wait_seconds_bucket{{{labels},le=\"{}\"}} {}",
+                    bucket.le_seconds, bucket.count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "sbdb_lock_wait_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "sbdb_lock_wait_seconds_sum{{{labels}}} {}",
+                histogram.sum_seconds
+            );
+            let _ = writeln!(
+                out,
+                "sbdb_lock_wait_seconds_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+        out
+    }
+}
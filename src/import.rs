@@ -0,0 +1,197 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use crate::{
+    Client, CowFileGaurd, Ctx, Error, Result, artifact, check_nested, check_unreserved,
+    commit_dir_with, copy_file, copy_recursive, error::IoResultExt, path_hidden_with_extension,
+    sync_parent, sync_tree,
+};
+
+/// How [`Client::import_file`] and [`Client::import_dir`] bring the source into the database. Either
+/// fails with [`Error::AlreadyExists`] if the destination exists, unless `overwrite` is set, and flushes
+/// the imported entry to disk before committing it if `sync` is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Copy the source, reflinked where the filesystem supports it, and leave it in place.
+    Copy { overwrite: bool, sync: bool },
+    /// Rename the source into the database. A source on another filesystem is copied instead and removed
+    /// once the copy was committed.
+    Move { overwrite: bool, sync: bool },
+}
+
+impl ImportMode {
+    fn overwrite(self) -> bool {
+        match self {
+            ImportMode::Copy { overwrite, .. } | ImportMode::Move { overwrite, .. } => overwrite,
+        }
+    }
+
+    fn sync(self) -> bool {
+        match self {
+            ImportMode::Copy { sync, .. } | ImportMode::Move { sync, .. } => sync,
+        }
+    }
+}
+
+/// How the source ended up at the temporary path.
+enum Staged {
+    /// Copied, the source is left as it is.
+    Copied,
+    /// Renamed, it can be renamed back should the commit fail.
+    Renamed,
+    /// Copied because it is on another filesystem, it is removed once the copy is committed.
+    CopiedForMove,
+}
+
+impl Client {
+    /// Bring the file `src` from outside the database in at `dest_rpath`. It is staged next to the
+    /// destination and committed by a rename under the destination's write lock, so readers see either
+    /// the previous contents or all of the imported ones.
+    pub fn import_file<P: AsRef<Path>>(
+        &self,
+        src: &Path,
+        dest_rpath: P,
+        mode: ImportMode,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let dest_rpath = check_nested(dest_rpath.as_ref())?;
+            let gaurd = self.write_file(&dest_rpath)?;
+            let (dest, ctx) = (&gaurd.path, &gaurd.ctx);
+            check_destination(dest, mode)?;
+            let tmp = path_hidden_with_extension(dest, artifact::TMP)?;
+            remove_stale(&tmp)?;
+
+            let staged = stage(src, &tmp, mode, ctx, |src, tmp| {
+                ctx.copy(|stats| copy_file(src, tmp, ctx, stats))?;
+                ctx.set_artifact_permissions(tmp, false)
+            })?;
+            let committed = match mode.sync() {
+                true => File::open(&tmp)
+                    .and_then(|file| file.sync_all())
+                    .at("sync", &tmp),
+                false => Ok(()),
+            }
+            .and_then(|()| {
+                CowFileGaurd {
+                    path: tmp.clone(),
+                    orig: dest.clone(),
+                    ctx: ctx.clone(),
+                }
+                .commit()
+            });
+            finish(src, &tmp, dest, staged, committed, mode, ctx)
+        })
+    }
+
+    /// Bring the directory tree `src` from outside the database in at `dest_rpath`, like
+    /// [`Client::import_file`]. An existing directory is swapped out like [`crate::DirWriteGaurd::cow`]
+    /// commits do. The destination can not be an atomic directory.
+    pub fn import_dir<P: AsRef<Path>>(
+        &self,
+        src: &Path,
+        dest_rpath: P,
+        mode: ImportMode,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let dest_rpath = check_nested(dest_rpath.as_ref())?;
+            check_unreserved(self.root(), &dest_rpath)?;
+            let gaurd = self.write_dir(&dest_rpath)?;
+            let (dest, ctx) = (&gaurd.path, &gaurd.ctx);
+            check_destination(dest, mode)?;
+            if dest.is_symlink() {
+                return Err(Error::invalid_path(
+                    dest,
+                    "can not import over a symbolic link",
+                ));
+            }
+            let tmp = path_hidden_with_extension(dest, artifact::TMP)?;
+            remove_stale(&tmp)?;
+
+            let staged = stage(src, &tmp, mode, ctx, |src, tmp| {
+                ctx.copy(|stats| copy_recursive(src, tmp, ctx, stats))?;
+                ctx.set_artifact_permissions(tmp, true)
+            })?;
+            let committed = match mode.sync() {
+                true => sync_tree(&tmp),
+                false => Ok(()),
+            }
+            .and_then(|()| commit_dir_with(tmp.clone(), dest.clone(), ctx.clone()));
+            finish(src, &tmp, dest, staged, committed, mode, ctx)
+        })
+    }
+}
+
+fn check_destination(dest: &Path, mode: ImportMode) -> Result<()> {
+    if !mode.overwrite() && fs::symlink_metadata(dest).is_ok() {
+        return Err(Error::AlreadyExists {
+            path: dest.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+/// Removes a temporary copy left behind by a crash, which can not be in use while the write lock is held.
+fn remove_stale(tmp: &Path) -> Result<()> {
+    match fs::symlink_metadata(tmp) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(tmp),
+        Ok(_) => fs::remove_file(tmp),
+        Err(_) => Ok(()),
+    }
+    .at("remove stale copy", tmp)
+}
+
+/// Puts the source at `tmp`, with `copy` unless it is moved within the same filesystem. A copy that
+/// fails, e.g. because the source disappeared while it was copied, is removed again.
+fn stage<F: FnOnce(&Path, &Path) -> Result<()>>(
+    src: &Path,
+    tmp: &Path,
+    mode: ImportMode,
+    ctx: &Ctx,
+    copy: F,
+) -> Result<Staged> {
+    let staged = match mode {
+        ImportMode::Move { .. } => match ctx.vfs.rename(src, tmp) {
+            Ok(()) => return Ok(Staged::Renamed),
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => Staged::CopiedForMove,
+            Err(e) => return Err(Error::io("move", src, e)),
+        },
+        ImportMode::Copy { .. } => Staged::Copied,
+    };
+    if let Err(e) = copy(src, tmp) {
+        let _ = remove_stale(tmp);
+        return Err(e);
+    }
+    Ok(staged)
+}
+
+fn finish(
+    src: &Path,
+    tmp: &Path,
+    dest: &Path,
+    staged: Staged,
+    committed: Result<()>,
+    mode: ImportMode,
+    ctx: &Ctx,
+) -> Result<()> {
+    if let Err(e) = committed {
+        // a renamed source is moved back where it came from, copies are dropped
+        if !matches!(staged, Staged::Renamed) || ctx.vfs.rename(tmp, src).is_err() {
+            let _ = remove_stale(tmp);
+        }
+        return Err(e);
+    }
+    if mode.sync() {
+        sync_parent(dest)?;
+    }
+    if let Staged::CopiedForMove = staged {
+        match fs::symlink_metadata(src) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(src),
+            _ => fs::remove_file(src),
+        }
+        .at("remove moved source", src)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{Client, Error, FileReadGaurd, Result, Tx, file_replace_with, read_value_file};
+
+/// Controls how [`Client::write_json_with`] and [`Tx::write_json_with`] write values.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct JsonOptions {
+    /// Indent the output instead of writing it on a single line.
+    pub pretty: bool,
+    /// Flush the file and its directory to disk before returning.
+    pub sync: bool,
+}
+
+impl Client {
+    pub fn read_json<T: DeserializeOwned, P: AsRef<Path>>(&self, rpath: P) -> Result<T> {
+        self.redacted(|| self.read_file(rpath)?.json())
+    }
+
+    /// Atomically replace the file at `rpath` with `value`, creating it if it does not exist yet.
+    pub fn write_json<T: Serialize, P: AsRef<Path>>(&self, rpath: P, value: &T) -> Result<()> {
+        self.redacted(|| self.write_json_with(rpath, value, &JsonOptions::default()))
+    }
+
+    pub fn write_json_with<T: Serialize, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        value: &T,
+        options: &JsonOptions,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            let bytes = to_vec(&gaurd.path, value, options)?;
+            file_replace_with(&gaurd.path, &bytes, options.sync, gaurd.ctx.clone())
+        })
+    }
+}
+
+impl FileReadGaurd {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        self.check_revoked()?;
+        from_file(&self.path)
+    }
+}
+
+impl Tx {
+    /// Read a value from a path that was declared as either a read or a write of this transaction.
+    pub fn read_json<T: DeserializeOwned, P: AsRef<Path>>(&self, rpath: P) -> Result<T> {
+        self.check_read(rpath.as_ref())?;
+        from_file(&self.root.join(rpath))
+    }
+
+    pub fn write_json<T: Serialize, P: AsRef<Path>>(&self, rpath: P, value: &T) -> Result<()> {
+        self.write_json_with(rpath, value, &JsonOptions::default())
+    }
+
+    pub fn write_json_with<T: Serialize, P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        value: &T,
+        options: &JsonOptions,
+    ) -> Result<()> {
+        self.check_write(rpath.as_ref())?;
+        let path = self.root.join(rpath);
+        let bytes = to_vec(&path, value, options)?;
+        file_replace_with(&path, &bytes, options.sync, self.ctx.clone())
+    }
+}
+
+fn from_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = read_value_file(path)?;
+    serde_json::from_slice(&bytes).map_err(|e| Error::Decode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })
+}
+
+pub(crate) fn to_vec<T: Serialize>(
+    path: &Path,
+    value: &T,
+    options: &JsonOptions,
+) -> Result<Vec<u8>> {
+    let result = if options.pretty {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    };
+    result.map_err(|e| Error::Encode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })
+}
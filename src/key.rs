@@ -0,0 +1,135 @@
+//! Reversible encoding of arbitrary string keys into file names that are safe to use inside the
+//! database on every supported platform.
+//!
+//! ASCII letters, digits, `-`, `_` and `.` are kept as is, everything else is written as `%XX` escapes of
+//! its UTF-8 bytes. On top of that, names never start with a dot (those are reserved for sbdb's own
+//! files), never end with a dot or with `.sbdb`, and never match a reserved Windows device name. Note
+//! that keys differing only in case still map to names that collide on case-insensitive filesystems.
+//!
+//! Names are kept short enough that sbdb's lock, temporary and backup files derived from them still fit
+//! within common file name limits. Longer keys are truncated and suffixed with a hash of the full key,
+//! which makes them irreversible: [`decode`] rejects such names, so the full key has to be stored
+//! elsewhere, for example inside the file as [`crate::Collection`] does.
+
+use std::path::{Path, PathBuf};
+
+use crate::{Client, Error, Result};
+
+/// Longest name produced by [`encode`], leaving room for the prefix and suffixes of internal files.
+pub const MAX_NAME_LEN: usize = 200;
+
+const HASH_LEN: usize = 32;
+
+/// Separates the truncated name from the hash of the full key. Never produced by escaping.
+const HASH_SEPARATOR: char = '~';
+
+/// Name of the empty key. Not a valid escape sequence, so it cannot collide with any other key.
+const EMPTY: &str = "%";
+
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+pub fn encode(key: &str) -> String {
+    if key.is_empty() {
+        return EMPTY.to_string();
+    }
+
+    let mut name = String::with_capacity(key.len());
+    for b in key.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.' {
+            name.push(b as char);
+        } else {
+            push_escaped(&mut name, b);
+        }
+    }
+
+    if name.starts_with('.') {
+        name.replace_range(..1, "%2E");
+    }
+    if name.ends_with('.') {
+        name.replace_range(name.len() - 1.., "%2E");
+    }
+    if name.ends_with(".sbdb") {
+        let dot = name.len() - ".sbdb".len();
+        name.replace_range(dot..dot + 1, "%2E");
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|r| stem.eq_ignore_ascii_case(r)) {
+        let first = name.as_bytes()[0];
+        name.replace_range(..1, "");
+        let mut escaped = String::new();
+        push_escaped(&mut escaped, first);
+        name.insert_str(0, &escaped);
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        let mut cut = MAX_NAME_LEN - HASH_LEN - HASH_SEPARATOR.len_utf8();
+        // do not split an escape sequence
+        if let Some(i) = name[cut.saturating_sub(2)..cut].find('%') {
+            cut = cut - 2 + i;
+        }
+        name.truncate(cut);
+        name.push(HASH_SEPARATOR);
+        name.push_str(&format!("{:032x}", fnv1a_128(key.as_bytes())));
+    }
+
+    name
+}
+
+/// Recover the key a name was produced from by [`encode`].
+pub fn decode(name: &str) -> Result<String> {
+    let invalid = |reason| Error::InvalidKey {
+        key: name.to_string(),
+        reason,
+    };
+
+    if name == EMPTY {
+        return Ok(String::new());
+    }
+    if name.contains(HASH_SEPARATOR) {
+        return Err(invalid("name was truncated and cannot be decoded"));
+    }
+
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let byte = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| invalid("malformed escape sequence"))?;
+            bytes.push(byte);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("escapes do not form valid utf-8"))
+}
+
+impl Client {
+    /// Path, relative to the root, of the file holding `key` inside `dir`. See [`encode`].
+    pub fn path_for_key<P: AsRef<Path>>(&self, dir: P, key: &str) -> PathBuf {
+        dir.as_ref().join(encode(key))
+    }
+}
+
+fn push_escaped(name: &mut String, b: u8) {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    name.push('%');
+    name.push(HEX[(b >> 4) as usize] as char);
+    name.push(HEX[(b & 0xf) as usize] as char);
+}
+
+/// Stable across platforms and compiler versions, unlike the hashers in std.
+pub(crate) fn fnv1a_128(bytes: &[u8]) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    bytes
+        .iter()
+        .fold(OFFSET, |hash, &b| (hash ^ b as u128).wrapping_mul(PRIME))
+}
@@ -0,0 +1,130 @@
+//! Ownership that outlives the process holding it, unlike locks. A lease is a file holding its expiry and
+//! its owner, and belongs to that owner until it expires, so an owner that restarts or briefly loses
+//! access can pick its leases back up as long as it renews them in time.
+//!
+//! Expiry is judged by the clocks of the processes that want to take a lease over, which may be ahead of
+//! the owner's, e.g. on different hosts sharing a network filesystem. They only take it over once it is
+//! expired by more than [`crate::ClientBuilder::lease_clock_skew`], so an owner that renews before its
+//! own clock reaches the expiry keeps the lease as long as the clocks are no further apart than that.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Client, Error, Result, error::IoResultExt, file_replace_with};
+
+/// How far apart the clocks of the processes sharing leases may be by default.
+pub const DEFAULT_LEASE_CLOCK_SKEW: Duration = Duration::from_secs(1);
+
+/// A lease acquired with [`Client::acquire_lease`]. Dropping it does not release it, it stays with its
+/// owner until it expires or is released with [`Lease::release`].
+#[derive(Debug)]
+pub struct Lease {
+    client: Client,
+    rpath: PathBuf,
+    owner: String,
+}
+
+impl Client {
+    /// Take the lease stored in the file at `rpath` for `owner` until `ttl` from now, `None` if another
+    /// owner holds it and it did not expire yet. A lease that `owner` holds already is renewed.
+    pub fn acquire_lease<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<Option<Lease>> {
+        self.redacted(|| {
+            let gaurd = self.write_file(&rpath)?;
+            if let Some(current) = read(&gaurd.path)?
+                && current.owner != owner
+            {
+                if !current.expired(self.inner.lease_clock_skew) {
+                    return Ok(None);
+                }
+                log::info!(path:? = gaurd.ctx.redact.path(&gaurd.path), operation = "acquire lease"; "taking over lease of {} that expired", current.owner);
+            }
+            write(&gaurd.path, owner, ttl, &gaurd.ctx)?;
+            Ok(Some(Lease {
+                client: self.clone(),
+                rpath: rpath.as_ref().to_path_buf(),
+                owner: owner.to_string(),
+            }))
+        })
+    }
+}
+
+impl Lease {
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// Extend the lease until `ttl` from now. Fails with [`Error::LeaseLost`] if it was taken over, which
+    /// only happens once it expired.
+    pub fn renew(&self, ttl: Duration) -> Result<()> {
+        let gaurd = self.client.write_file(&self.rpath)?;
+        match read(&gaurd.path)? {
+            Some(current) if current.owner == self.owner => {
+                write(&gaurd.path, &self.owner, ttl, &gaurd.ctx)
+            }
+            _ => Err(Error::LeaseLost { path: gaurd.path }),
+        }
+    }
+
+    /// Give up the lease, so that other owners can take it right away. Nothing happens if it was taken
+    /// over already.
+    pub fn release(self) -> Result<()> {
+        let gaurd = self.client.write_file(&self.rpath)?;
+        match read(&gaurd.path)? {
+            Some(current) if current.owner == self.owner => {
+                fs::remove_file(&gaurd.path).at("remove lease", &gaurd.path)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+struct LeaseFile {
+    owner: String,
+    expires: SystemTime,
+}
+
+impl LeaseFile {
+    fn expired(&self, skew: Duration) -> bool {
+        self.expires
+            .checked_add(skew)
+            .is_none_or(|expires| expires < SystemTime::now())
+    }
+}
+
+/// The lease at `path`, written as its expiry in milliseconds since the epoch on the first line and the
+/// owner on the rest.
+fn read(path: &Path) -> Result<Option<LeaseFile>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io("read lease", path, e)),
+    };
+    let decode = |source: Box<dyn std::error::Error + Send + Sync>| Error::Decode {
+        path: path.to_path_buf(),
+        source,
+    };
+    let (expires, owner) = text
+        .split_once('\n')
+        .ok_or_else(|| decode("missing owner".into()))?;
+    let expires: u64 = expires.parse().map_err(|e| decode(Box::new(e)))?;
+    Ok(Some(LeaseFile {
+        owner: owner.to_string(),
+        expires: UNIX_EPOCH + Duration::from_millis(expires),
+    }))
+}
+
+fn write(path: &Path, owner: &str, ttl: Duration, ctx: &crate::Ctx) -> Result<()> {
+    let expires = (SystemTime::now() + ttl)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    let text = format!("{}\n{}", expires, owner);
+    file_replace_with(path, text.as_bytes(), true, ctx.clone())
+}
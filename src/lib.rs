@@ -1,643 +1,1655 @@
 use std::{
-    collections::HashSet,
+    cell::RefCell,
     ffi::OsString,
-    fs::{self, File, OpenOptions},
+    fmt, fs,
     path::{Path, PathBuf},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, anyhow};
+#[cfg(unix)]
+use std::collections::HashSet;
+
 use rand::{Rng, SeedableRng, distr::Uniform, rngs::StdRng};
-use reflink_copy::reflink_or_copy;
 
+mod alias;
+#[cfg(feature = "tar")]
+mod archive;
+pub mod artifact;
+#[cfg(feature = "serde_json")]
+mod audit;
+mod backup;
+mod blob;
+mod cas;
+mod changeset;
+#[cfg(feature = "binary")]
+mod codec;
+#[cfg(feature = "binary")]
+mod collection;
+#[cfg(feature = "compression")]
+mod compression;
+mod consistent;
+mod counter;
+mod cow;
+mod deadline;
+mod dedup;
+mod entry_meta;
+mod error;
+mod exclusive;
+mod export;
+#[cfg(feature = "failpoints")]
+pub mod failpoint;
+#[cfg(any(test, feature = "testkit"))]
+#[cfg_attr(not(feature = "testkit"), allow(dead_code))]
+mod fixture;
+mod gc;
+mod generation;
+mod hash;
+mod held;
+#[cfg(feature = "metrics")]
+mod histogram;
+mod import;
+#[cfg(feature = "serde_json")]
+mod json;
+pub mod key;
+mod lease;
+mod lock;
+mod lock_cache;
+#[cfg(feature = "lock-order-check")]
+mod lock_order;
+mod lockdir;
+mod maintenance;
+mod marker;
+pub mod merge;
+mod meta;
+mod metrics;
+mod page;
+mod path;
+mod poll;
+pub mod prelude;
+mod prune;
+mod queue;
+mod quota;
+mod read_hold;
+mod redact;
+mod reflink;
+mod remove;
+mod repair;
+mod replication;
+mod retry;
+mod rollup;
+#[cfg(feature = "serde_json")]
+mod schema;
+mod scratch;
+mod snapshot;
+mod stats;
+mod stream;
+#[cfg(feature = "strict-locking")]
+mod strict;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+mod trace;
+mod ttl;
+mod tx;
+pub mod unlocked;
+mod vacuum;
+mod verify;
+mod version;
+mod vfs;
+#[cfg(feature = "watch")]
+mod watch;
 #[cfg(windows)]
-use std::os::windows::prelude::*;
-
-#[derive(Clone, Debug)]
+mod winfs;
+
+#[cfg(feature = "tar")]
+pub use archive::TarOptions;
+pub use artifact::RESERVED_SUFFIXES;
+#[cfg(feature = "serde_json")]
+pub use audit::{AuditOp, AuditOptions, AuditRecord};
+pub use backup::{BackupState, BackupSummary};
+pub use blob::{BlobId, BlobStore};
+pub use cas::{CasError, ContentVersion};
+pub use changeset::{ApplyReport, ChangeOutcome, Changeset};
+#[cfg(feature = "binary")]
+pub use codec::{CodecError, Postcard, ValueCodec};
+#[cfg(feature = "binary")]
+pub use collection::{Collection, CollectionIter, CollectionStreamIter, RecordReader};
+#[cfg(feature = "compression")]
+pub use compression::MAX_DECOMPRESSED_LEN;
+pub use counter::Counter;
+pub use cow::{
+    AtomicCowOptions, AtomicDirCreation, CowAtomicDirGaurd, CowDirGaurd, CowFileGaurd, dir_cow,
+    dir_cow_atomic, file_cow,
+};
+pub use dedup::{DEFAULT_DEDUP_MIN_BYTES, DedupOptions, DedupReport};
+pub use entry_meta::MAX_META_BYTES;
+pub use error::{Error, Result};
+pub use exclusive::{CompactReport, DbExclusiveGaurd};
+pub use export::{ExistingDest, ExportOptions};
+pub use gc::{GcHandle, GcOptions, GcReport};
+pub use hash::{Digest, HashAlgorithm, TreeDigest};
+pub use held::HeldLock;
+#[cfg(feature = "metrics")]
+pub use histogram::{
+    HistogramBucket, HistogramMetrics, LOCK_WAIT_BUCKETS_NANOS, LockWaitHistogram, MAX_DEPTH_LABEL,
+    MetricsSnapshot,
+};
+pub use import::ImportMode;
+#[cfg(feature = "serde_json")]
+pub use json::JsonOptions;
+pub use lease::{DEFAULT_LEASE_CLOCK_SKEW, Lease};
+pub use lock::{
+    DirReadGaurd, DirWriteGaurd, FileReadGaurd, FileWriteGaurd, Lock, LockStatus, ReadLock,
+    WriteLock, open_lock_and_queue, open_lock_file,
+};
+pub use lockdir::{DEFAULT_LOCK_LEASE, LockBackend, LockingFidelity, NetworkFsPolicy};
+pub use maintenance::DEFAULT_MAINTENANCE_WAIT;
+pub use meta::{FORMAT_VERSION, Migration};
+pub use metrics::{AtomicMetrics, CommitKind, LockMode, Metrics, NoopMetrics};
+pub use page::Page;
+pub use path::create_backup_ext;
+pub use poll::{ChangedPath, DEFAULT_MAX_POLL_INTERVAL, MIN_POLL_INTERVAL, PollState};
+pub use queue::{DbQueue, QueueItemId};
+pub use read_hold::{StaleReader, StaleReaderCallback};
+pub use redact::PathDisplay;
+pub use remove::RemoveReport;
+pub use repair::{RepairAction, RepairReport};
+pub use replication::{CommitRecord, ReplicatedOp};
+pub use retry::{TryPolicy, TryTxError};
+pub use rollup::Rollup;
+#[cfg(feature = "serde_json")]
+pub use schema::{MigrationChain, MigrationError, VersionedCodec};
+pub use scratch::{ReadSnapshot, SCRATCH_GRACE, ScratchDir};
+pub use snapshot::SnapshotId;
+pub use stats::{DEFAULT_LARGEST, DbStats, EntryStats, EntryTtl, FileSize, StatsOptions};
+pub use stream::DEFAULT_STREAM_BUFFER_LEN;
+pub use tx::{Tx, TxBuilder, TxEntry, TxEntryKind, TxStats};
+pub use vacuum::{RetentionPolicy, VacuumReport};
+pub use verify::{Issue, IssueKind, VerifyOptions, VerifyReport};
+pub use version::{PathMatcher, VersionInfo, VersioningPolicy};
+#[cfg(feature = "testkit")]
+pub use vfs::{StdVfs, Vfs};
+#[cfg(feature = "watch")]
+pub use watch::{ChangeKind, Watcher};
+
+use artifact::ArtifactKind;
+#[cfg(feature = "serde_json")]
+use audit::Audit;
+#[cfg(any(feature = "serde_json", feature = "binary"))]
+use cow::read_value_file;
+use cow::{
+    CopyStats, check_locked, commit_dir_with, copy_file, copy_recursive, copy_visible,
+    create_atomic_below, dir_cow_atomic_from, dir_cow_atomic_with, dir_cow_with, file_cow_with,
+    file_replace_with, replace_with_link, sync_parent, sync_tree, write_tmp,
+};
+use deadline::Deadline;
+use error::IoResultExt;
+use exclusive::ExclusiveOwners;
+use gc::is_older_than;
+use generation::Generations;
+use held::HeldLocks;
+#[cfg(windows)]
+use lock::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+use lock::{
+    Held, check_lock_order, covering_write_lock, create_read_file_locks, create_write_file_locks,
+    lock_write_file, release_all,
+};
+use lock_cache::{LockFileCache, LockFiles};
+use path::{
+    check_depth, check_nested, check_unreserved, normalize_rpath, path_hidden_with_extension,
+    strip_trailing_slash, unused_child, unused_hidden_path,
+};
+use quota::Quota;
+use read_hold::ReadHold;
+use redact::Redact;
+use reflink::ReflinkSupport;
+use replication::ReplicationLog;
+use rollup::{Rollups, Usage};
+use version::Versioning;
+#[cfg(not(feature = "testkit"))]
+use vfs::{StdVfs, Vfs};
+
+/// Directory at the root of the database holding sbdb's own data. It is never garbage collected.
+const INTERNAL_DIR: &str = ".sbdb";
+
+/// How many directories deep below an entry copies and gc descend by default, see
+/// [`ClientBuilder::max_depth`].
+pub const DEFAULT_MAX_DEPTH: usize = 4096;
+
+/// Handle to a database. Clones are cheap and share everything the client holds, its configuration,
+/// callbacks, caches and the locks it tracks, so that they act as one client, see [`Client::detach`] for
+/// one that does not share its caches.
+#[derive(Clone)]
 pub struct Client {
-    root: PathBuf,
+    inner: Arc<ClientInner>,
 }
 
-impl Client {
-    pub fn new<P: AsRef<Path>>(root: P) -> anyhow::Result<Self> {
-        let root = root.as_ref().to_path_buf();
-        fs::create_dir_all(&root)?;
-        Ok(Self { root })
-    }
-
-    pub fn root(&self) -> &PathBuf {
-        &self.root
-    }
-
-    pub fn read_file<P: AsRef<Path>>(&self, rpath: P) -> anyhow::Result<FileReadGaurd> {
-        let path = self.root.join(rpath.as_ref());
-        let lock = create_read_file_locks(&self.root, rpath)?;
-        Ok(FileReadGaurd { path, lock })
-    }
-
-    pub fn read_dir<P: AsRef<Path>>(&self, rpath: P) -> anyhow::Result<DirReadGaurd> {
-        let path = self.root.join(rpath.as_ref());
-        let lock = create_read_file_locks(&self.root, rpath)?;
-        Ok(DirReadGaurd { path, lock })
-    }
+/// What the clones of a [`Client`] share.
+#[derive(Clone)]
+struct ClientInner {
+    root: PathBuf,
+    on_warning: Option<WarningCallback>,
+    metrics: Arc<dyn Metrics>,
+    versioning: Option<Arc<Versioning>>,
+    #[cfg(feature = "serde_json")]
+    audit: Option<Arc<Audit>>,
+    generations: Option<Arc<Generations>>,
+    rollups: Option<Arc<Rollups>>,
+    vfs: Arc<dyn Vfs>,
+    lock_cache: Option<Arc<LockFileCache>>,
+    reflink: Arc<ReflinkSupport>,
+    max_depth: usize,
+    preserve_hardlinks: bool,
+    atomic_dirs: Arc<AtomicDirSupport>,
+    lock_backend: LockBackend,
+    artifact_mode: Option<u32>,
+    lock_mode: Option<u32>,
+    lease_clock_skew: Duration,
+    maintenance_wait: Duration,
+    stream_buffer_len: usize,
+    io_deadline: Option<Duration>,
+    preflight_space: bool,
+    quota: Option<Arc<Quota>>,
+    replog: Option<Arc<ReplicationLog>>,
+    /// Set while the root is a replica, see [`Client::mark_replica`].
+    read_only: Arc<AtomicBool>,
+    read_hold: Option<Arc<ReadHold>>,
+    held: Arc<HeldLocks>,
+    exclusive: Arc<ExclusiveOwners>,
+    redact: Redact,
+}
 
-    pub fn write_file<P: AsRef<Path>>(&self, rpath: P) -> anyhow::Result<FileWriteGaurd> {
-        let path = self.root.join(rpath.as_ref());
-        let lock = create_write_file_locks(&self.root, rpath)?;
-        Ok(FileWriteGaurd { path, lock })
-    }
+pub type WarningCallback = Arc<dyn Fn(Warning) + Send + Sync>;
 
-    pub fn write_dir<P: AsRef<Path>>(&self, rpath: P) -> anyhow::Result<DirWriteGaurd> {
-        let path = self.root.join(rpath.as_ref());
-        let lock = create_write_file_locks(&self.root, rpath)?;
-        Ok(DirWriteGaurd { path, lock })
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("root", &self.inner.root)
+            .finish()
     }
+}
 
-    pub fn tx(&self) -> TxBuilder {
-        TxBuilder::new(self.root.clone())
-    }
+/// Problems that do not cause an operation to fail, but that an application may still want
+/// to know about.
+#[derive(Debug)]
+pub enum Warning {
+    /// An error was encountered while garbage collecting the given path.
+    Gc { path: PathBuf, error: Error },
+    /// A background gc cycle was skipped because the previous one was still running.
+    GcCycleSkipped { missed: u32 },
+    /// A lock could not be released when its guard was dropped. Use the guard's `release` method to
+    /// observe this as an error instead.
+    Unlock { path: PathBuf, error: Error },
+    /// A commit of `path` succeeded but could not be recorded in the audit log.
+    Audit { path: PathBuf, error: Error },
+    /// A commit of `path` succeeded but the generation counters could not be bumped a second time.
+    Generation { path: PathBuf, error: Error },
+    /// A commit or removal of `path` succeeded but could not be recorded in the rollups of the directories
+    /// above it, see [`Client::rollup`].
+    Rollup { path: PathBuf, error: Error },
+    /// A commit of `path` succeeded but could not be recorded in the replication log, so replicas miss it.
+    Replication { path: PathBuf, error: Error },
+    /// The root at `path` is on a network filesystem, where the default locks may not exclude each other.
+    /// Only reported when asked for with [`ClientBuilder::network_fs`].
+    NetworkFilesystem {
+        path: PathBuf,
+        filesystem: &'static str,
+    },
+    /// The root at `path` was opened for the first time and contains the roots `inner` of other databases,
+    /// whose clients do not take this one's locks. Only checked when a root is first opened.
+    InnerRoots { path: PathBuf, inner: Vec<PathBuf> },
+    /// The directory at `path` could not be reflinked into a [`ReadSnapshot`], which took a full copy of
+    /// `bytes` instead.
+    FullCopy { path: PathBuf, bytes: u64 },
+}
 
-    /// After lots of modifications have happened in the database, its possible for lock files, temporary files
-    /// and backups to accumulate. This dynamically scans the database structure and safely removes files that
-    /// are no longer needed. If this is scanning a very large database, it may take a long time. It is recomended
-    /// that this procedure be run on a background thread/proccess.
-    pub fn gc(&self) {
-        fn gc(client: &Client, path: &PathBuf) -> anyhow::Result<()> {
-            let mut children = Vec::new();
-            {
-                let _gaurd = client.read_dir(path)?;
-                for entry in fs::read_dir(path)? {
-                    let entry = entry?;
-                    let child_path = entry.path();
-                    let name = entry
-                        .file_name()
-                        .into_string()
-                        .map_err(|_| anyhow!("failed to convert"))?;
-                    if name.ends_with(".lock.sbdb") || name.ends_with(".queue.sbdb") {
-                        let orig_name: String =
-                            name.chars().skip(1).take_while(|c| *c != '.').collect();
-                        let orig_path = path.join(orig_name);
-                        if !orig_path.exists() {
-                            if let Err(e) = fs::remove_file(name) {
-                                // swallow error
-                                eprintln!("failed to remove file: {}", e);
-                            }
-                        }
-                    } else if name.ends_with(".dir.sbdb") {
-                        let orig_name: String =
-                            name.chars().skip(1).take_while(|c| *c != '.').collect();
-                        let orig_path = path.join(orig_name);
-                        if !orig_path.exists() || !orig_path.is_symlink() {
-                            if let Err(e) = fs::remove_dir_all(orig_path) {
-                                // swallow error
-                                eprintln!("failed to remove file: {}", e);
-                            }
-                        }
-                    } else if child_path.is_dir() {
-                        children.push(child_path.clone());
-                    }
-                    // TODO: handle non-atomic directory backups using write lock
-                }
+impl Warning {
+    fn log(&self) {
+        match self {
+            Warning::Gc { path, error } => {
+                log::warn!(path:? = path, operation = "gc"; "gc failed: {}", error)
             }
-
-            for child in children {
-                if let Err(e) = gc(client, &child) {
-                    eprintln!("error occured during gc: {}", e);
-                }
+            Warning::GcCycleSkipped { missed } => {
+                log::warn!(operation = "gc", missed = missed; "skipped {} gc cycles", missed)
+            }
+            Warning::Unlock { path, error } => {
+                log::warn!(path:? = path, operation = "unlock"; "failed to unlock: {}", error)
+            }
+            Warning::Audit { path, error } => {
+                log::warn!(path:? = path, operation = "audit"; "failed to record commit: {}", error)
+            }
+            Warning::Generation { path, error } => {
+                log::warn!(path:? = path, operation = "bump generation"; "failed to bump generation: {}", error)
+            }
+            Warning::Rollup { path, error } => {
+                log::warn!(path:? = path, operation = "roll up"; "failed to record change in rollups: {}", error)
+            }
+            Warning::Replication { path, error } => {
+                log::warn!(path:? = path, operation = "replicate"; "failed to record commit for replicas: {}", error)
+            }
+            Warning::NetworkFilesystem { path, filesystem } => {
+                log::warn!(path:? = path, operation = "open"; "database is on a {} filesystem, locks may not be reliable", filesystem)
+            }
+            Warning::InnerRoots { path, inner } => {
+                log::warn!(path:? = path, operation = "open"; "database contains {} other databases, e.g. {}", inner.len(), inner[0].display())
+            }
+            Warning::FullCopy { path, bytes } => {
+                log::warn!(path:? = path, operation = "snapshot", bytes = bytes; "copied {} bytes that could not be reflinked", bytes)
             }
-
-            Ok(())
         }
+    }
 
-        if let Err(e) = gc(&self, &self.root) {
-            eprintln!("error occured during gc: {}", e);
+    /// The warning with every path it names replaced with `f` of it, see
+    /// [`ClientBuilder::redact_paths`].
+    fn map_paths(self, f: &dyn Fn(PathBuf) -> PathBuf) -> Self {
+        match self {
+            Warning::Gc { path, error } => Warning::Gc {
+                path: f(path),
+                error: error.map_paths(f),
+            },
+            Warning::GcCycleSkipped { missed } => Warning::GcCycleSkipped { missed },
+            Warning::Unlock { path, error } => Warning::Unlock {
+                path: f(path),
+                error: error.map_paths(f),
+            },
+            Warning::Audit { path, error } => Warning::Audit {
+                path: f(path),
+                error: error.map_paths(f),
+            },
+            Warning::Generation { path, error } => Warning::Generation {
+                path: f(path),
+                error: error.map_paths(f),
+            },
+            Warning::Rollup { path, error } => Warning::Rollup {
+                path: f(path),
+                error: error.map_paths(f),
+            },
+            Warning::Replication { path, error } => Warning::Replication {
+                path: f(path),
+                error: error.map_paths(f),
+            },
+            Warning::NetworkFilesystem { path, filesystem } => Warning::NetworkFilesystem {
+                path: f(path),
+                filesystem,
+            },
+            Warning::InnerRoots { path, inner } => Warning::InnerRoots {
+                path: f(path),
+                inner: inner.into_iter().map(f).collect(),
+            },
+            Warning::FullCopy { path, bytes } => Warning::FullCopy {
+                path: f(path),
+                bytes,
+            },
         }
     }
 }
 
-pub enum TxEntryKind {
-    Read,
-    Write,
+fn report_warning(on_warning: Option<&WarningCallback>, redact: &Redact, warning: Warning) {
+    let warning = redact.warning(warning);
+    warning.log();
+    if let Some(f) = on_warning {
+        f(warning);
+    }
+}
+
+pub struct ClientBuilder {
+    root: PathBuf,
+    on_warning: Option<WarningCallback>,
+    metrics: Arc<dyn Metrics>,
+    versioning: Option<VersioningPolicy>,
+    #[cfg(feature = "serde_json")]
+    audit: Option<AuditOptions>,
+    generations: bool,
+    rollups: bool,
+    replication_log: bool,
+    vfs: Arc<dyn Vfs>,
+    lock_file_cache: usize,
+    max_depth: usize,
+    preserve_hardlinks: bool,
+    lock_backend: LockBackend,
+    network_fs: NetworkFsPolicy,
+    artifact_permissions: Option<u32>,
+    group_writable_locks: bool,
+    lease_clock_skew: Duration,
+    maintenance_wait: Duration,
+    stream_buffer_len: usize,
+    io_deadline: Option<Duration>,
+    preflight_space: bool,
+    quota: Option<u64>,
+    allow_nested: bool,
+    migrations: Vec<Arc<dyn Migration>>,
+    open_mode: OpenMode,
+    open_policy: OpenPolicy,
+    verify_locking: bool,
+    max_read_hold: Option<Duration>,
+    on_stale_reader: Option<StaleReaderCallback>,
+    redact_paths: PathDisplay,
 }
 
-pub struct TxEntry {
-    kind: TxEntryKind,
-    path: PathBuf,
+/// Whether building a client creates its root, see [`ClientBuilder::open_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OpenMode {
+    /// Create the root unless it exists, see [`Client::new`].
+    #[default]
+    Create,
+    /// Fail with [`Error::RootNotFound`] unless the root exists already, see [`Client::open`].
+    Existing,
+    /// Fail with [`Error::AlreadyExists`] if anything exists at the root, see [`Client::create_new`].
+    CreateNew,
 }
 
-pub struct TxBuilder {
-    root: PathBuf,
-    reads: HashSet<PathBuf>,
-    writes: HashSet<PathBuf>,
+/// What building a client does about evidence of interrupted operations, see [`ClientBuilder::open_policy`].
+/// That evidence is a backup that a directory commit moved the directory to before it was interrupted,
+/// which leaves the directory missing until the backup is restored, and an atomic directory whose payload
+/// is gone. Looking for it walks the whole database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OpenPolicy {
+    /// Open the database as it is, interrupted operations are recovered from by [`Client::recover`].
+    #[default]
+    Lenient,
+    /// Run [`Client::recover`], then remove atomic directories whose payload is gone, which could not be read
+    /// anyway. Fails with [`Error::NeedsRecovery`] if anything is left that recovery could not fix.
+    Recover,
+    /// Fail with [`Error::NeedsRecovery`] listing the evidence, without changing anything, so that an
+    /// operator or the application can decide what to do.
+    Strict,
 }
 
-impl TxBuilder {
-    pub fn new(root: PathBuf) -> Self {
+impl ClientBuilder {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
         Self {
-            root,
-            reads: HashSet::new(),
-            writes: HashSet::new(),
+            root: root.as_ref().to_path_buf(),
+            on_warning: None,
+            metrics: metrics::noop(),
+            versioning: None,
+            #[cfg(feature = "serde_json")]
+            audit: None,
+            generations: false,
+            rollups: false,
+            replication_log: false,
+            vfs: vfs::std(),
+            lock_file_cache: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            preserve_hardlinks: false,
+            lock_backend: LockBackend::Flock,
+            network_fs: NetworkFsPolicy::Ignore,
+            artifact_permissions: None,
+            group_writable_locks: false,
+            lease_clock_skew: DEFAULT_LEASE_CLOCK_SKEW,
+            maintenance_wait: DEFAULT_MAINTENANCE_WAIT,
+            stream_buffer_len: DEFAULT_STREAM_BUFFER_LEN,
+            io_deadline: None,
+            preflight_space: false,
+            quota: None,
+            allow_nested: false,
+            migrations: Vec::new(),
+            open_mode: OpenMode::Create,
+            open_policy: OpenPolicy::Lenient,
+            verify_locking: false,
+            max_read_hold: None,
+            on_stale_reader: None,
+            redact_paths: PathDisplay::Absolute,
         }
     }
 
-    pub fn read<P: AsRef<Path>>(mut self, path: P) -> Self {
-        for anscestor in path.as_ref().ancestors() {
-            self.reads.insert(anscestor.to_path_buf());
-        }
+    /// Install instrumentation hooks, see [`Metrics`].
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
         self
     }
 
-    pub fn write<P: AsRef<Path>>(mut self, path: P) -> Self {
-        self.writes.insert(path.as_ref().to_path_buf());
-        for anscestor in path.as_ref().ancestors().skip(1) {
-            self.reads.insert(anscestor.to_path_buf());
-        }
+    /// Register a callback that receives non-fatal problems. Warnings are always logged through the `log`
+    /// facade as well, this is for applications that want to react to them programmatically.
+    pub fn on_warning<F: Fn(Warning) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_warning = Some(Arc::new(f));
         self
     }
 
-    pub fn begin(mut self) -> anyhow::Result<Tx> {
-        let mut remove_writes = Vec::new();
-        for write in self.writes.iter() {
-            for anscestor in write.ancestors().skip(1) {
-                if self.writes.contains(anscestor) {
-                    remove_writes.push(write.clone());
-                    break;
-                }
-            }
-        }
-        for remove in remove_writes {
-            self.writes.remove(&remove);
-        }
-
-        self.reads.retain(|p| {
-            p.ancestors()
-                .all(|anscestor| !self.writes.contains(anscestor))
-        });
-
-        let mut entries = Vec::new();
-
-        for path in self.reads {
-            entries.push(TxEntry {
-                kind: TxEntryKind::Read,
-                path,
-            });
-        }
-        for path in self.writes {
-            entries.push(TxEntry {
-                kind: TxEntryKind::Write,
-                path,
-            });
-        }
-
-        entries.sort_by(|e1, e2| e1.path.cmp(&e2.path));
-
-        let mut lock = Vec::with_capacity(entries.len());
-
-        for e in entries {
-            lock.push(match e.kind {
-                TxEntryKind::Read => Lock::Read(ReadLock::new(self.root.join(e.path))?),
-                TxEntryKind::Write => Lock::Write(WriteLock::new(self.root.join(e.path))?),
-            });
-        }
-
-        lock.reverse();
-
-        Ok(Tx {
-            root: self.root.clone(),
-            lock,
-        })
+    /// Keep previous versions of the files selected by `policy` whenever a commit replaces them, see
+    /// [`Client::versions`].
+    pub fn versioning(mut self, policy: VersioningPolicy) -> Self {
+        self.versioning = Some(policy);
+        self
     }
-}
-
-pub struct Tx {
-    root: PathBuf,
-    #[allow(dead_code)]
-    lock: Vec<Lock>,
-}
 
-impl Tx {
-    pub fn file_cow<P: AsRef<Path>>(&self, orig: P) -> anyhow::Result<CowFileGaurd> {
-        file_cow(self.root.join(orig))
+    /// Record every commit made through this client in the audit log, see [`Client::read_audit`].
+    #[cfg(feature = "serde_json")]
+    pub fn audit(mut self, options: AuditOptions) -> Self {
+        self.audit = Some(options).filter(|options| options.enabled);
+        self
     }
 
-    pub fn dir_cow<P: AsRef<Path>>(&self, orig: P) -> anyhow::Result<CowDirGaurd> {
-        dir_cow(self.root.join(orig))
+    /// Bump generation counters on every commit, see [`Client::subtree_generation`]. Every client that
+    /// writes to the database must enable this, commits made without it are not detected.
+    pub fn track_generations(mut self) -> Self {
+        self.generations = true;
+        self
     }
 
-    pub fn dir_cow_atomic<P: AsRef<Path>>(&self, orig: P) -> anyhow::Result<CowAtomicDirGaurd> {
-        dir_cow_atomic(self.root.join(orig))
+    /// Record every commit and removal in the rollups of the directories above it, see
+    /// [`Client::rollup`]. Every client that writes to the database should enable this, the sizes drift
+    /// with the commits made without it until [`Client::rebuild_rollups`] is called, which a database that
+    /// holds data already needs once.
+    pub fn track_rollups(mut self) -> Self {
+        self.rollups = true;
+        self
     }
-}
 
-fn create_read_file_locks<P: AsRef<Path>>(root: &PathBuf, rpath: P) -> anyhow::Result<Vec<Lock>> {
-    let mut result = Vec::new();
-
-    for anc in rpath
-        .as_ref()
-        .ancestors()
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev()
-    {
-        let path = root.join(anc);
-        result.push(Lock::Read(ReadLock::new(path)?))
+    /// Record every commit in the replication log, from which replicas are brought up to date, see
+    /// [`Client::read_commits_since`]. Every client that writes to the database must enable this, replicas
+    /// miss the commits made without it.
+    pub fn replication_log(mut self) -> Self {
+        self.replication_log = true;
+        self
     }
 
-    result.reverse();
-
-    Ok(result)
-}
+    /// Route locking, copying and committing through `vfs` instead of the real filesystem, see
+    /// [`testkit::FaultVfs`].
+    #[cfg(feature = "testkit")]
+    pub fn vfs(mut self, vfs: Arc<dyn Vfs>) -> Self {
+        self.vfs = vfs;
+        self
+    }
 
-fn create_write_file_locks<P: AsRef<Path>>(root: &PathBuf, rpath: P) -> anyhow::Result<Vec<Lock>> {
-    let mut result = Vec::new();
+    /// Keep the lock files of up to `capacity` recently locked entries open, instead of opening them again
+    /// on every acquisition. Zero, the default, disables the cache. It is only available on unix, where a
+    /// handle can be checked against the path before it is reused, and ignored elsewhere.
+    pub fn lock_file_cache(mut self, capacity: usize) -> Self {
+        self.lock_file_cache = capacity;
+        self
+    }
 
-    for anc in rpath
-        .as_ref()
-        .ancestors()
-        .skip(1)
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev()
-    {
-        let path = root.join(anc);
-        result.push(Lock::Read(ReadLock::new(path)?))
+    /// How many directories deep below an entry copies and gc descend before giving up with
+    /// [`Error::TreeTooDeep`], [`DEFAULT_MAX_DEPTH`] by default.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
     }
 
-    let path = root.join(rpath);
-    eprintln!("{:?}", path);
-    result.push(Lock::Write(WriteLock::new(path)?));
+    /// Copy files that have several names within the copied tree once, and give the copy the same names
+    /// as hard links, instead of copying every name separately. Off by default. Files in a copy that share
+    /// an inode also share their contents, so writing one of them in place changes the others, while
+    /// replacing it through a commit does not. It is only available on unix and ignored elsewhere.
+    pub fn preserve_hardlinks(mut self, preserve: bool) -> Self {
+        self.preserve_hardlinks = preserve;
+        self
+    }
 
-    result.reverse();
+    /// How entries are locked, [`LockBackend::Flock`] by default. Every client of a database has to use
+    /// the same backend.
+    pub fn lock_backend(mut self, backend: LockBackend) -> Self {
+        self.lock_backend = backend;
+        self
+    }
 
-    Ok(result)
-}
+    /// Check that file locks exclude each other in the root when the client is built, failing with
+    /// [`Error::LockingNotEnforced`] if they do not, see [`Client::check_locking`]. Only done with
+    /// [`LockBackend::Flock`], and not by default since the check waits for a lock to be refused.
+    pub fn verify_locking(mut self, verify: bool) -> Self {
+        self.verify_locking = verify;
+        self
+    }
 
-pub struct FileReadGaurd {
-    pub path: PathBuf,
-    #[allow(dead_code)]
-    lock: Vec<Lock>,
-}
+    /// Check whether the root is on a network filesystem when the client is built, see
+    /// [`NetworkFsPolicy`]. Not checked by default.
+    pub fn network_fs(mut self, policy: NetworkFsPolicy) -> Self {
+        self.network_fs = policy;
+        self
+    }
 
-pub struct FileWriteGaurd {
-    pub path: PathBuf,
-    #[allow(dead_code)]
-    lock: Vec<Lock>,
-}
+    /// Create lock files and temporary copies with the unix permissions `mode`, e.g. `0o600`, instead of
+    /// whatever the umask and the original leave them with. Directories also get the execute bits of the
+    /// classes that can read them. A committed copy keeps these permissions, and lock files that exist
+    /// already keep theirs. Ignored on windows.
+    pub fn artifact_permissions(mut self, mode: u32) -> Self {
+        self.artifact_permissions = Some(mode);
+        self
+    }
 
-impl FileWriteGaurd {
-    pub fn cow(&self) -> anyhow::Result<CowFileGaurd> {
-        file_cow(&self.path)
+    /// Create lock files readable and writable by their group, so that several users of a group can lock
+    /// the same database. Combined with [`ClientBuilder::artifact_permissions`] if set, otherwise lock files
+    /// are created with `0o664`. Ignored on windows.
+    pub fn group_writable_locks(mut self, group_writable: bool) -> Self {
+        self.group_writable_locks = group_writable;
+        self
     }
-}
 
-pub fn file_cow<P: AsRef<Path>>(orig: P) -> anyhow::Result<CowFileGaurd> {
-    let path = path_hidden_with_extension(&orig, ".tmp.sbdb")?;
-    reflink_or_copy(&orig, &path)?;
-    Ok(CowFileGaurd {
-        path,
-        orig: orig.as_ref().to_path_buf(),
-    })
-}
+    /// How far the clocks of the processes sharing leases may be apart, [`DEFAULT_LEASE_CLOCK_SKEW`] by
+    /// default. A lease is only taken over from another owner once it is expired by more than this, see
+    /// [`Client::acquire_lease`].
+    pub fn lease_clock_skew(mut self, skew: Duration) -> Self {
+        self.lease_clock_skew = skew;
+        self
+    }
 
-pub struct CowFileGaurd {
-    pub path: PathBuf,
-    orig: PathBuf,
-}
+    /// How long gc, exports, backups and stats wait for the maintenance lock, [`DEFAULT_MAINTENANCE_WAIT`]
+    /// by default, see [`Client::maintenance_write`].
+    pub fn maintenance_wait(mut self, wait: Duration) -> Self {
+        self.maintenance_wait = wait;
+        self
+    }
 
-impl CowFileGaurd {
-    pub fn commit(self) -> anyhow::Result<()> {
-        fs::rename(&self.path, &self.orig)?;
-        Ok(())
+    /// Size of the buffer that [`Client::copy_to`] and [`Collection::iter_streaming`] read files through,
+    /// [`DEFAULT_STREAM_BUFFER_LEN`] by default.
+    pub fn stream_buffer_len(mut self, len: usize) -> Self {
+        self.stream_buffer_len = len;
+        self
     }
-}
 
-pub struct DirReadGaurd {
-    pub path: PathBuf,
-    #[allow(dead_code)]
-    lock: Vec<Lock>,
-}
+    /// Bound how long a single copy-on-write copy, or write of [`Client::write_bytes`], may take, so that an
+    /// unresponsive filesystem, such as a hung network mount, ends in [`Error::IoTimeout`] rather than
+    /// blocking the caller indefinitely. Time spent waiting for locks is not counted. Files are then copied
+    /// and written in chunks, with the deadline checked before each, and a reflink, which can not be
+    /// interrupted, is only attempted while there is time left. A call that hangs is only noticed once it
+    /// returns.
+    ///
+    /// A copy or write that runs out of time is removed again, so nothing is committed and the entry, and
+    /// the locks held on it, are as they were before. Commits themselves are renames and are not bounded.
+    pub fn io_deadline(mut self, deadline: Duration) -> Self {
+        self.io_deadline = Some(deadline);
+        self
+    }
 
-pub struct DirWriteGaurd {
-    pub path: PathBuf,
-    #[allow(dead_code)]
-    lock: Vec<Lock>,
-}
+    /// Check that the filesystem has room for a copy-on-write copy, or a write of [`Client::write_bytes`],
+    /// before it is made, failing with [`Error::NoSpace`] otherwise, rather than fill the disk and fail
+    /// part of the way through. Copies count the data of their original, which for a directory walks it,
+    /// unless the filesystem is known to reflink them. The check is made before the temporary is written,
+    /// since by the time it is committed it takes up its room already, and only where the free space of
+    /// a filesystem is known, which is on linux.
+    ///
+    /// Without it, running out of space ends in [`Error::NoSpace`] all the same. Partially written copies
+    /// and writes are removed again either way, as is a complete copy whose commit ran out of space, so
+    /// the entry is as it was before and nothing is left for gc.
+    pub fn preflight_space(mut self, preflight: bool) -> Self {
+        self.preflight_space = preflight;
+        self
+    }
 
-impl DirWriteGaurd {
-    pub fn cow(&self) -> anyhow::Result<CowDirGaurd> {
-        // TODO: convert atomic to normal
-        dir_cow(&self.path)
+    /// Have writers that wait for a lock report its readers once they hold it for longer than `max`, so that
+    /// a read guard that leaked, e.g. into a long-lived cache, can be found and revoked instead of blocking
+    /// writers until the process exits. The readers are reported to [`ClientBuilder::on_stale_reader`] and
+    /// logged, see [`StaleReader`]. Only locks of the [`LockBackend::Flock`] backend are checked, and only
+    /// readers in processes that set a budget are seen, so every process sharing the database should.
+    ///
+    /// This is cooperative. Locks of the operating system can not be taken away from their holder, a
+    /// revoked guard only fails its reads with [`Error::LeaseRevoked`] and keeps the lock until it is
+    /// dropped. Writers that have to wait poll for the lock instead of blocking on it.
+    pub fn max_read_hold(mut self, max: Duration) -> Self {
+        self.max_read_hold = Some(max);
+        self
     }
 
-    /// platform specific behavior:
+    /// Show the paths of entries in the database with `display` rather than as they are, e.g. so that logs
+    /// shipped elsewhere do not reveal where the database is kept. This covers the messages of errors
+    /// returned by building the client, by its methods and by the locking, copying and committing of its
+    /// guards, the [`Warning`]s it reports and logs, its other log records, and the paths it passes to
+    /// [`Metrics`] and records in traces. Each client shows paths below its own root, as it was given and
+    /// once it was resolved, and only those; the free functions, which know no root, show paths as they are.
     ///
-    /// This feature uses symbolic links, which windows supports, but only in developer mode
-    /// or with escalated privlages. For that reason it should probably be avoided if you would
-    /// like to have cross-platform support.
-    pub fn cow_atomic(&self) -> anyhow::Result<CowAtomicDirGaurd> {
-        dir_cow_atomic(&self.path)
+    /// The errors and [`Warning`]s covered hold the paths as they are shown, rather than the paths
+    /// themselves. Reports such as [`VerifyReport`] hold paths relative to the root anyway.
+    pub fn redact_paths(mut self, display: PathDisplay) -> Self {
+        self.redact_paths = display;
+        self
     }
 
-    pub fn create_dir_atomic<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
-        dir_cow_atomic(self.path.join(path))?.commit()?;
-        Ok(())
+    /// Call `f` from a waiting writer with readers that hold a lock for longer than
+    /// [`ClientBuilder::max_read_hold`], once for every reader that acquired the lock last. The application
+    /// decides whether to [`StaleReader::revoke`] them.
+    pub fn on_stale_reader<F: Fn(&StaleReader) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_stale_reader = Some(Arc::new(f));
+        self
     }
-}
 
-pub fn dir_cow<P: AsRef<Path>>(orig: P) -> anyhow::Result<CowDirGaurd> {
-    let path = path_hidden_with_extension(&orig, ".tmp.sbdb")?;
-    copy_recursive(&orig, &path)?;
-    Ok(CowDirGaurd {
-        path,
-        orig: orig.as_ref().to_path_buf(),
-    })
-}
+    /// Cap the bytes of data in the database at `bytes`, not counting sbdb's own files. Commits made
+    /// through the client that would take the usage past it fail with [`Error::QuotaExceeded`] before
+    /// anything is replaced. The usage is counted when the client is built, which walks the whole
+    /// database, and kept up to date by the client's own commits and removals, see
+    /// [`Client::recompute_usage`].
+    pub fn quota(mut self, bytes: u64) -> Self {
+        self.quota = Some(bytes);
+        self
+    }
 
-fn strip_trailing_slash(path: PathBuf) -> PathBuf {
-    path.components().as_path().to_path_buf()
-}
+    /// Open a root inside the root of another database instead of failing with [`Error::NestedRoot`]. Off
+    /// by default, since the locks of the inner database do not reach the outer one, so a client of the
+    /// outer database can change or replace entries while the inner one has them locked.
+    pub fn allow_nested(mut self, allow: bool) -> Self {
+        self.allow_nested = allow;
+        self
+    }
 
-pub fn dir_cow_atomic<P: AsRef<Path>>(current: P) -> anyhow::Result<CowAtomicDirGaurd> {
-    let current = strip_trailing_slash(current.as_ref().to_path_buf());
-    let parent = current.parent().context("missing parent")?.to_path_buf();
-
-    let mut name = String::new();
-    name.push('.');
-    name.push_str(
-        current
-            .file_name()
-            .context("missing_filename")?
-            .to_str()
-            .context("could not convert os string")?,
-    );
-    name.push('.');
-    name.push_str(&puuid());
-    name.push_str(".dir.sbdb");
-
-    let path = parent.join(&name);
-    if current.exists() {
-        if current.is_symlink() {
-            let orig = parent.join(fs::read_link(&current)?);
-            copy_recursive(&orig, &path)?;
-            Ok(CowAtomicDirGaurd {
-                current,
-                name,
-                path,
-                orig: Some(orig),
-            })
-        } else {
-            copy_recursive(&current, &path)?;
-            Ok(CowAtomicDirGaurd {
-                current,
-                name,
-                path,
-                orig: None,
-            })
-        }
-    } else {
-        fs::create_dir_all(&path)?;
-        Ok(CowAtomicDirGaurd {
-            current,
-            name,
-            path,
-            orig: None,
-        })
+    /// Whether the root is created when the client is built. Whichever it is, the root has to be a directory
+    /// that this process can create files in.
+    pub fn open_mode(mut self, mode: OpenMode) -> Self {
+        self.open_mode = mode;
+        self
     }
-}
 
-pub fn create_backup_ext() -> String {
-    let mut ext = String::new();
-    ext.push('.');
-    ext.push_str(&puuid());
-    ext.push_str(".bak.sbdb");
-    ext
-}
+    /// What to do about evidence of interrupted operations in the database when the client is built.
+    pub fn open_policy(mut self, policy: OpenPolicy) -> Self {
+        self.open_policy = policy;
+        self
+    }
 
-pub struct CowDirGaurd {
-    pub path: PathBuf,
-    orig: PathBuf,
-}
+    /// Run `migration` on roots of an older format when the client is built, see [`Migration`]. Roots of a
+    /// newer format than [`FORMAT_VERSION`] fail to open with [`Error::IncompatibleFormat`].
+    pub fn migration(mut self, migration: Arc<dyn Migration>) -> Self {
+        self.migrations.push(migration);
+        self
+    }
 
-impl CowDirGaurd {
-    /// Directory commits are not strictly atomic because rename cannot be used to target a
-    /// non-empty directory. This means commits are implemented as two rename operations, first
-    /// the target is renamed as a backup, then the copy is renamed to place at the original
-    /// location. The only way for the database to be left in an inconsistent state is if a
-    /// catastrophic failure occurs between these two renames.
-    pub fn commit(self) -> anyhow::Result<()> {
-        let bak = path_hidden_with_extension(&self.path, &create_backup_ext())?;
+    pub fn build(self) -> Result<Client> {
+        let (root, display) = (self.root.clone(), self.redact_paths);
+        self.build_client().map_err(|e| {
+            // paths are below the root as it was given, or below the resolved root once it was resolved
+            let e = Redact::new(&root, display).error(e);
+            match fs::canonicalize(&root) {
+                Ok(resolved) => Redact::new(&resolved, display).error(e),
+                Err(_) => e,
+            }
+        })
+    }
 
-        fs::rename(&self.orig, &bak)?;
-        if let Err(e) = fs::rename(&self.path, &self.orig) {
-            fs::rename(&bak, &self.orig)?;
-            return Err(anyhow!(e));
+    fn build_client(mut self) -> Result<Client> {
+        self.open_root()?;
+        // so that every clone spells the paths it locks alike
+        self.root = fs::canonicalize(&self.root).at("resolve", &self.root)?;
+        if !self.allow_nested
+            && let Some(outer) = marker::outer_root(&self.root)?
+        {
+            return Err(Error::NestedRoot { outer });
         }
-        if let Err(e) = fs::remove_dir_all(&bak) {
-            // swallow error since it does not indicate failed commit
-            eprintln!("failed to cleanup dir {:?}, error: {:?}", bak, e)
+        let marked = marker::mark(&self.root)?;
+        marker::check_writable(&self.root)?;
+        if marked {
+            let inner = marker::inner_roots(&self.root, self.max_depth);
+            if !inner.is_empty() {
+                report_warning(
+                    self.on_warning.as_ref(),
+                    &Redact::new(&self.root, self.redact_paths),
+                    Warning::InnerRoots {
+                        path: self.root.clone(),
+                        inner,
+                    },
+                );
+            }
         }
-        Ok(())
-    }
-}
-
-pub struct CowAtomicDirGaurd {
-    current: PathBuf,
-    name: String,
-    pub path: PathBuf,
-    orig: Option<PathBuf>,
-}
-
-impl CowAtomicDirGaurd {
-    pub fn commit(self) -> anyhow::Result<()> {
-        let current_tmp = path_hidden_with_extension(&self.current, ".tmplnk.sbdb")?;
-        let current_rel = PathBuf::from(self.name);
-
-        #[cfg(unix)]
+        let versioning = self
+            .versioning
+            .map(|policy| Arc::new(Versioning::new(self.root.clone(), policy)));
+        #[cfg(feature = "serde_json")]
+        let audit = self
+            .audit
+            .map(|options| Arc::new(Audit::new(self.root.clone(), options)));
+        let generations = self
+            .generations
+            .then(|| Arc::new(Generations::new(self.root.clone())));
+        let rollups = self
+            .rollups
+            .then(|| Arc::new(Rollups::new(self.root.clone())));
+        let mut lock_backend = self.lock_backend;
+        if self.network_fs != NetworkFsPolicy::Ignore
+            && let Some(filesystem) = lockdir::network_filesystem(&self.root)
         {
-            std::os::unix::fs::symlink(&current_rel, &current_tmp)?;
+            match self.network_fs {
+                NetworkFsPolicy::UseLockDirs
+                    if !matches!(lock_backend, LockBackend::AtomicLockDir { .. }) =>
+                {
+                    lock_backend = LockBackend::atomic_lock_dir()
+                }
+                NetworkFsPolicy::UseLockDirs => {}
+                _ => report_warning(
+                    self.on_warning.as_ref(),
+                    &Redact::new(&self.root, self.redact_paths),
+                    Warning::NetworkFilesystem {
+                        path: self.root.clone(),
+                        filesystem,
+                    },
+                ),
+            }
         }
-
-        #[cfg(windows)]
+        if self.verify_locking
+            && lock_backend == LockBackend::Flock
+            && lockdir::check_locking(&self.vfs, &self.root.join(INTERNAL_DIR))
+                == LockingFidelity::NotEnforced
         {
-            std::os::windows::fs::symlink_dir(&current_rel, &current_tmp)?;
+            return Err(Error::LockingNotEnforced {
+                path: self.root.clone(),
+            });
         }
-
-        let converting = self.current.exists() && self.current.is_dir();
-        let bak = if converting {
-            let bak = path_hidden_with_extension(&self.path, &create_backup_ext())?;
-            fs::rename(&self.current, &bak)?;
-            Some(bak)
-        } else {
-            None
-        };
-
-        // atomic commit
-        fs::rename(&current_tmp, self.current)?;
-
-        if let Some(orig) = self.orig {
-            if let Err(e) = fs::remove_dir_all(&orig) {
-                // swallow error since it does not indicate failed commit
-                eprintln!("failed to cleanup dir {:?}, error: {:?}", orig, e)
+        let lock_cache = (cfg!(unix) && self.lock_file_cache > 0).then(|| {
+            Arc::new(LockFileCache::new(
+                u32::try_from(self.lock_file_cache).unwrap_or(u32::MAX),
+            ))
+        });
+        let migrations = self.migrations;
+        let open_policy = self.open_policy;
+        let replog = self
+            .replication_log
+            .then(|| Arc::new(ReplicationLog::new(self.root.clone())));
+        let read_only = Arc::new(AtomicBool::new(replication::is_replica(&self.root)));
+        let read_hold = self
+            .max_read_hold
+            .map(|max| Arc::new(ReadHold::new(max, self.on_stale_reader)));
+        let client = Client::with_inner(ClientInner {
+            on_warning: self.on_warning,
+            metrics: self.metrics,
+            versioning,
+            #[cfg(feature = "serde_json")]
+            audit,
+            generations,
+            rollups,
+            vfs: self.vfs,
+            lock_cache,
+            reflink: Arc::new(ReflinkSupport::default()),
+            max_depth: self.max_depth,
+            preserve_hardlinks: self.preserve_hardlinks,
+            atomic_dirs: Arc::new(AtomicDirSupport::new(self.root.join(INTERNAL_DIR))),
+            lock_backend,
+            artifact_mode: self.artifact_permissions,
+            lock_mode: match self.group_writable_locks {
+                true => Some(self.artifact_permissions.unwrap_or(0o644) | 0o060),
+                false => self.artifact_permissions,
+            },
+            lease_clock_skew: self.lease_clock_skew,
+            maintenance_wait: self.maintenance_wait,
+            stream_buffer_len: self.stream_buffer_len,
+            io_deadline: self.io_deadline,
+            preflight_space: self.preflight_space,
+            quota: self.quota.map(|limit| Arc::new(Quota::new(limit))),
+            replog,
+            read_only,
+            read_hold,
+            held: Arc::default(),
+            exclusive: Arc::default(),
+            redact: Redact::new(&self.root, self.redact_paths),
+            root: self.root,
+        });
+        client.open_format(&migrations)?;
+        match open_policy {
+            OpenPolicy::Lenient => {}
+            OpenPolicy::Recover => client.recover_on_open()?,
+            OpenPolicy::Strict => {
+                let findings = client.recovery_findings()?;
+                if !findings.is_empty() {
+                    return Err(Error::NeedsRecovery { findings });
+                }
             }
         }
-        if let Some(bak) = bak {
-            if let Err(e) = fs::remove_dir_all(&bak) {
-                // swallow error since it does not indicate failed commit
-                eprintln!("failed to cleanup dir {:?}, error: {:?}", bak, e)
+        if client.inner.quota.is_some() {
+            client.recompute_usage()?;
+        }
+        Ok(client)
+    }
+
+    /// Create the root as the open mode asks for and check that it is a directory. That it can be written is
+    /// checked once it is marked.
+    fn open_root(&self) -> Result<()> {
+        let root = &self.root;
+        match self.open_mode {
+            OpenMode::Create => fs::create_dir_all(root).at("create root directory", root)?,
+            OpenMode::Existing => match fs::metadata(root) {
+                Ok(metadata) if !metadata.is_dir() => {
+                    return Err(Error::invalid_path(root, "not a directory"));
+                }
+                // a directory that is not a database is as good as missing
+                Ok(_) if !root.join(INTERNAL_DIR).is_dir() => {
+                    return Err(Error::RootNotFound { path: root.clone() });
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(Error::RootNotFound { path: root.clone() });
+                }
+                Err(e) => return Err(Error::io("read metadata of", root, e)),
+            },
+            OpenMode::CreateNew => {
+                if let Some(parent) = root.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent).at("create directory", parent)?;
+                }
+                match fs::create_dir(root) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        return Err(Error::AlreadyExists { path: root.clone() });
+                    }
+                    Err(e) => return Err(Error::io("create root directory", root, e)),
+                }
             }
         }
+        if !root.is_dir() {
+            return Err(Error::invalid_path(root, "not a directory"));
+        }
         Ok(())
     }
 }
 
-fn path_hidden_with_extension<P: AsRef<Path>>(path: P, ext: &str) -> anyhow::Result<PathBuf> {
-    path_modify_filename(path, |name| {
-        let mut result = OsString::new();
-        result.push(".");
-        result.push(&name);
-        result.push(ext);
-        name.clear();
-        name.push(result);
-    })
-}
-
-fn path_modify_filename<P: AsRef<Path>, F: FnOnce(&mut OsString)>(
-    path: P,
-    modify: F,
-) -> anyhow::Result<PathBuf> {
-    let mut name = path
-        .as_ref()
-        .file_name()
-        .context("not a valid path")?
-        .to_os_string();
-    modify(&mut name);
-    let parent = path.as_ref().parent().context("needs a parent")?;
-    Ok(parent.join(name))
-}
-
-#[cfg(windows)]
-const FILE_SHARE_READ: u32 = 0x00000001;
-#[cfg(windows)]
-const FILE_SHARE_WRITE: u32 = 0x00000002;
-#[cfg(windows)]
-const FILE_SHARE_DELETE: u32 = 0x00000004;
+impl Client {
+    /// Open the database at `root`, creating it if it does not exist yet.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        ClientBuilder::new(root).build()
+    }
 
-#[cfg(windows)]
-pub fn open_lock_file<P: AsRef<Path>>(path: P) -> anyhow::Result<File> {
-    OpenOptions::new()
-        .write(true)
-        .create(true)
-        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
-        .open(path)
-        .context("could not open lock file")
-}
+    /// Open the database at `root`, failing with [`Error::RootNotFound`] if there is none, so that a mistyped
+    /// path is not mistaken for an empty database.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        ClientBuilder::new(root)
+            .open_mode(OpenMode::Existing)
+            .build()
+    }
 
-#[cfg(unix)]
-pub fn open_lock_file<P: AsRef<Path>>(path: P) -> anyhow::Result<File> {
-    OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(path)
-        .context("could not open lock file")
-}
+    /// Create a database at `root`, failing with [`Error::AlreadyExists`] if anything is there already.
+    pub fn create_new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        ClientBuilder::new(root)
+            .open_mode(OpenMode::CreateNew)
+            .build()
+    }
 
-pub fn open_lock_and_queue<P: AsRef<Path>>(path: P) -> anyhow::Result<(File, File)> {
-    let path_lock = path_hidden_with_extension(&path, ".lock.sbdb")?;
-    let path_queue = path_hidden_with_extension(&path, ".queue.sbdb")?;
+    pub fn builder<P: AsRef<Path>>(root: P) -> ClientBuilder {
+        ClientBuilder::new(root)
+    }
 
-    let lock = open_lock_file(path_lock)?;
-    let queue = open_lock_file(path_queue)?;
+    /// Root of the database, absolute and with symbolic links resolved when the client was built.
+    pub fn root(&self) -> &PathBuf {
+        &self.inner.root
+    }
 
-    Ok((lock, queue))
-}
+    fn with_inner(inner: ClientInner) -> Self {
+        Client {
+            inner: Arc::new(inner),
+        }
+    }
 
-pub enum Lock {
-    Read(ReadLock),
-    Write(WriteLock),
-}
+    /// A client of the same root with the same configuration and callbacks, which does not share the caches
+    /// of this one: its lock files, what it found out about reflinks and atomic directories, and the locks
+    /// it lists in [`Client::held_locks`], and the guard of [`Client::lock_all`], which it waits for like
+    /// other clients. The usage counted against a quota is the database's and stays shared.
+    pub fn detach(&self) -> Client {
+        Client::with_inner(ClientInner {
+            lock_cache: self
+                .inner
+                .lock_cache
+                .as_ref()
+                .map(|cache| Arc::new(cache.fresh())),
+            reflink: Arc::new(ReflinkSupport::default()),
+            atomic_dirs: Arc::new(AtomicDirSupport::new(self.inner.root.join(INTERNAL_DIR))),
+            held: Arc::default(),
+            exclusive: Arc::default(),
+            ..(*self.inner).clone()
+        })
+    }
 
-pub struct ReadLock {
-    lock: File,
-}
+    fn ctx(&self) -> Ctx {
+        Ctx {
+            metrics: self.inner.metrics.clone(),
+            on_warning: self.inner.on_warning.clone(),
+            versioning: self.inner.versioning.clone(),
+            #[cfg(feature = "serde_json")]
+            audit: self.inner.audit.clone(),
+            generations: self.inner.generations.clone(),
+            rollups: self.inner.rollups.clone(),
+            vfs: self.inner.vfs.clone(),
+            lock_cache: self.inner.lock_cache.clone(),
+            reflink: self.inner.reflink.clone(),
+            max_depth: self.inner.max_depth,
+            preserve_hardlinks: self.inner.preserve_hardlinks,
+            atomic_dirs: Some(self.inner.atomic_dirs.clone()),
+            lock_backend: self.inner.lock_backend,
+            artifact_mode: self.inner.artifact_mode,
+            lock_mode: self.inner.lock_mode,
+            io_deadline: self.inner.io_deadline,
+            preflight_space: self.inner.preflight_space,
+            quota: self.inner.quota.clone(),
+            replog: self.inner.replog.clone(),
+            read_only: self.inner.read_only.load(Ordering::Relaxed),
+            read_hold: self.inner.read_hold.clone(),
+            held: Some(self.inner.held.clone()),
+            exclusive: Some(self.inner.exclusive.clone()),
+            root: Some(self.inner.root.clone()),
+            redact: self.inner.redact.clone(),
+            span: trace::Span::current(),
+        }
+    }
 
-impl ReadLock {
-    fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let (lock, queue) = open_lock_and_queue(path)?;
+    /// Whether copies of files on the root's filesystem are reflinked, `None` until the first copy on it was
+    /// made. Copies skip the attempt on filesystems where it failed, trying again every minute.
+    pub fn reflink_supported(&self) -> Option<bool> {
+        reflink::device(&self.inner.root).and_then(|device| self.inner.reflink.supported(device))
+    }
 
-        queue.lock()?;
-        lock.lock_shared()?;
-        queue.unlock()?;
+    /// How this client locks entries, see [`ClientBuilder::lock_backend`] and [`ClientBuilder::network_fs`].
+    pub fn lock_backend(&self) -> LockBackend {
+        self.inner.lock_backend
+    }
 
-        Ok(Self { lock })
+    /// Whether atomic directories can be created in this database. They are symbolic links, which need
+    /// developer mode or the privilege to create them on windows and are not supported by every filesystem.
+    /// The first call probes by creating a link in `<root>/.sbdb`, later ones return the same answer. Where
+    /// this is false, creating or copying an atomic directory fails with [`Error::AtomicDirsUnsupported`]
+    /// before anything is touched.
+    pub fn atomic_dirs_supported(&self) -> bool {
+        self.inner.atomic_dirs.supported(&*self.inner.vfs)
     }
-}
 
-impl Drop for ReadLock {
-    fn drop(&mut self) {
-        if let Err(e) = self.lock.unlock().context("failed to unlock") {
-            eprint!("{:?}", e);
-        }
+    /// Whether file locks in this database exclude each other, found by taking an exclusive lock on a file
+    /// in `<root>/.sbdb` and checking that a second one on the same file waits for it. Some filesystems,
+    /// such as a few container mounts, grant every lock. Only this process is checked, a filesystem that
+    /// enforces locks locally may still not share them with another machine or the host of a container.
+    pub fn check_locking(&self) -> LockingFidelity {
+        lockdir::check_locking(&self.inner.vfs, &self.inner.root.join(INTERNAL_DIR))
     }
-}
 
-pub struct WriteLock {
-    lock: File,
-}
+    /// Runs `f`, showing the paths of the error it fails with as [`ClientBuilder::redact_paths`] says.
+    fn redacted<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        f().map_err(|e| self.inner.redact.error(e))
+    }
 
-impl WriteLock {
-    fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let (lock, queue) = open_lock_and_queue(path)?;
+    fn warn(&self, warning: Warning) {
+        report_warning(self.inner.on_warning.as_ref(), &self.inner.redact, warning);
+    }
 
-        queue.lock()?;
-        lock.lock()?;
-        queue.unlock()?;
+    pub fn read_file<P: AsRef<Path>>(&self, rpath: P) -> Result<FileReadGaurd> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let path = self.inner.root.join(&rpath);
+            let lock = create_read_file_locks(&self.inner.root, rpath, None, &self.ctx())?;
+            Ok(FileReadGaurd {
+                path,
+                lock,
+                shared: Vec::new(),
+            })
+        })
+    }
 
-        Ok(Self { lock })
+    pub fn read_dir<P: AsRef<Path>>(&self, rpath: P) -> Result<DirReadGaurd> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let path = self.inner.root.join(&rpath);
+            let ctx = self.ctx();
+            let lock = create_read_file_locks(&self.inner.root, rpath, None, &ctx)?;
+            let resolved = resolve_atomic_dir(&path)?;
+            Ok(DirReadGaurd {
+                path,
+                resolved,
+                lock,
+                ctx,
+            })
+        })
     }
-}
 
-impl Drop for WriteLock {
-    fn drop(&mut self) {
-        if let Err(e) = self.lock.unlock().context("failed to unlock") {
-            eprintln!("{:?}", e);
-        }
+    pub fn write_file<P: AsRef<Path>>(&self, rpath: P) -> Result<FileWriteGaurd> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            check_unreserved(&self.inner.root, &rpath)?;
+            let path = self.inner.root.join(&rpath);
+            let ctx = self.ctx();
+            let lock = create_write_file_locks(&self.inner.root, rpath, None, &ctx)?;
+            Ok(FileWriteGaurd { path, lock, ctx })
+        })
     }
-}
 
-fn copy_recursive(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> anyhow::Result<()> {
-    let src = src.as_ref();
-    let dst = dst.as_ref();
+    pub fn write_dir<P: AsRef<Path>>(&self, rpath: P) -> Result<DirWriteGaurd> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            check_unreserved(&self.inner.root, &rpath)?;
+            let path = self.inner.root.join(&rpath);
+            let ctx = self.ctx();
+            let lock = create_write_file_locks(&self.inner.root, rpath, None, &ctx)?;
+            let resolved = resolve_atomic_dir(&path)?;
+            Ok(DirWriteGaurd {
+                path,
+                resolved,
+                lock,
+                ctx,
+            })
+        })
+    }
 
-    // Create destination directory if it doesn't exist
-    fs::create_dir_all(dst)?;
+    /// Atomically replace the file at `rpath` with `bytes`, creating it if it does not exist yet.
+    pub fn write_bytes<P: AsRef<Path>>(&self, rpath: P, bytes: &[u8]) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            file_replace_with(&gaurd.path, bytes, false, gaurd.ctx.clone())
+        })
+    }
 
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let entry_path = entry.path();
-        let file_name = entry.file_name();
-        let dest_path = dst.join(file_name);
+    /// Names of the entries of the directory at `rpath`, sorted and without sbdb's own files. Aliases are
+    /// listed under their own names, [`Client::read_alias`] tells them apart.
+    pub fn list<P: AsRef<Path>>(&self, rpath: P) -> Result<Vec<OsString>> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let gaurd = self.read_dir(&rpath)?;
+            let mut names = Vec::new();
+            let dir = gaurd.resolved_path();
+            for entry in fs::read_dir(dir).at("read directory", dir)? {
+                let name = entry.at("read directory", dir)?.file_name();
+                if (rpath.as_os_str().is_empty() && name == INTERNAL_DIR)
+                    || artifact::parse(&name).is_some()
+                {
+                    continue;
+                }
+                #[cfg(windows)]
+                if winfs::is_delete_pending(&dir.join(&name)) {
+                    continue;
+                }
+                names.push(name);
+            }
+            names.sort();
+            Ok(names)
+        })
+    }
 
-        let file_type = entry.file_type()?;
+    pub fn tx(&self) -> TxBuilder {
+        let mut builder = TxBuilder::new(self.inner.root.clone());
+        builder.ctx = self.ctx();
+        builder
+    }
+}
 
-        if file_type.is_dir() {
-            copy_recursive(&entry_path, &dest_path)?;
-        } else if file_type.is_file() {
-            reflink_or_copy(&entry_path, &dest_path)?;
-        } else if file_type.is_symlink() {
-            let link_target = fs::read_link(&entry_path)?;
+/// The payload that the atomic directory at `path` links to, or `path` itself if it is anything else or
+/// nothing at all.
+fn resolve_atomic_dir(path: &Path) -> Result<PathBuf> {
+    let target = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_symlink() => fs::read_link(path).at("read link", path)?,
+        Ok(_) => return Ok(path.to_path_buf()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(path.to_path_buf()),
+        Err(e) => return Err(Error::io("read metadata of", path, e)),
+    };
+    match (is_atomic_dir_target(path, &target), path.parent()) {
+        (true, Some(parent)) => Ok(parent.join(target)),
+        _ => Ok(path.to_path_buf()),
+    }
+}
 
-            #[cfg(unix)]
-            {
-                std::os::unix::fs::symlink(&link_target, &dest_path)?;
+/// Whether `link` is a symbolic link created by [`CowAtomicDirGaurd::commit`], meaning it is relative and
+/// points at a sibling `.dir.sbdb` payload belonging to the link's name.
+fn is_atomic_dir_link(link: &Path) -> Result<bool> {
+    let target = fs::read_link(link).at("read link", link)?;
+    Ok(is_atomic_dir_target(link, &target))
+}
+
+/// Whether `target` is what an atomic directory at `link` links to, see [`is_atomic_dir_link`].
+fn is_atomic_dir_target(link: &Path, target: &Path) -> bool {
+    let mut components = target.components();
+    let (Some(std::path::Component::Normal(target_name)), None) =
+        (components.next(), components.next())
+    else {
+        return false;
+    };
+    match artifact::parse(target_name) {
+        Some((ArtifactKind::AtomicDir, orig)) => Some(orig.as_os_str()) == link.file_name(),
+        _ => false,
+    }
+}
+
+/// State that copy-on-write guards carry from whoever created them, so that copies and commits are
+/// reported to the right place.
+#[derive(Clone)]
+struct Ctx {
+    metrics: Arc<dyn Metrics>,
+    on_warning: Option<WarningCallback>,
+    versioning: Option<Arc<Versioning>>,
+    #[cfg(feature = "serde_json")]
+    audit: Option<Arc<Audit>>,
+    generations: Option<Arc<Generations>>,
+    rollups: Option<Arc<Rollups>>,
+    vfs: Arc<dyn Vfs>,
+    lock_cache: Option<Arc<LockFileCache>>,
+    reflink: Arc<ReflinkSupport>,
+    max_depth: usize,
+    preserve_hardlinks: bool,
+    /// Checked before atomic directories are copied, not at all by the free functions.
+    atomic_dirs: Option<Arc<AtomicDirSupport>>,
+    lock_backend: LockBackend,
+    /// Permissions of temporary copies, see [`ClientBuilder::artifact_permissions`].
+    artifact_mode: Option<u32>,
+    /// Permissions of lock files, see [`ClientBuilder::group_writable_locks`].
+    lock_mode: Option<u32>,
+    /// Limit of each copy and write, see [`ClientBuilder::io_deadline`].
+    io_deadline: Option<Duration>,
+    /// Checked before copies and writes, not inside copies, see [`ClientBuilder::preflight_space`].
+    preflight_space: bool,
+    quota: Option<Arc<Quota>>,
+    replog: Option<Arc<ReplicationLog>>,
+    /// Commits fail while set, see [`Client::mark_replica`].
+    read_only: bool,
+    /// Budget of read locks, see [`ClientBuilder::max_read_hold`].
+    read_hold: Option<Arc<ReadHold>>,
+    /// Where locks are recorded while they are held, see [`Client::held_locks`].
+    held: Option<Arc<HeldLocks>>,
+    /// Threads whose locks are covered by [`Client::lock_all`].
+    exclusive: Option<Arc<ExclusiveOwners>>,
+    /// Root of the client, `None` for the free functions, whose paths are below no root.
+    root: Option<PathBuf>,
+    /// How paths are shown in errors, warnings, log records, metrics and traces, see
+    /// [`ClientBuilder::redact_paths`].
+    redact: Redact,
+    span: trace::Span,
+}
+
+impl Ctx {
+    /// Context for the free functions, which are not associated with a [`Client`].
+    fn detached() -> Self {
+        Ctx {
+            metrics: metrics::noop(),
+            on_warning: None,
+            versioning: None,
+            #[cfg(feature = "serde_json")]
+            audit: None,
+            generations: None,
+            rollups: None,
+            vfs: vfs::std(),
+            lock_cache: None,
+            reflink: reflink::global(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            preserve_hardlinks: false,
+            atomic_dirs: None,
+            lock_backend: LockBackend::Flock,
+            artifact_mode: None,
+            lock_mode: None,
+            io_deadline: None,
+            preflight_space: false,
+            quota: None,
+            replog: None,
+            read_only: false,
+            read_hold: None,
+            held: None,
+            exclusive: None,
+            root: None,
+            redact: Redact::default(),
+            span: trace::Span::current(),
+        }
+    }
+
+    /// Context for work inside a copy that is not committed yet, which is reported, versioned and charged
+    /// along with the copy rather than on its own.
+    fn private(&self) -> Self {
+        Ctx {
+            vfs: self.vfs.clone(),
+            reflink: self.reflink.clone(),
+            max_depth: self.max_depth,
+            preserve_hardlinks: self.preserve_hardlinks,
+            atomic_dirs: self.atomic_dirs.clone(),
+            lock_backend: self.lock_backend,
+            artifact_mode: self.artifact_mode,
+            lock_mode: self.lock_mode,
+            io_deadline: self.io_deadline,
+            redact: self.redact.clone(),
+            span: self.span.clone(),
+            ..Ctx::detached()
+        }
+    }
+
+    /// `path` relative to the root of the client, `None` if it is not below it or there is no root.
+    fn rpath<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(self.root.as_deref()?).ok()
+    }
+
+    /// Permissions that a temporary copy gets, `None` if it keeps what it was created with.
+    fn artifact_permissions(&self, dir: bool) -> Option<fs::Permissions> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            // directories can only be entered by those who may also search them
+            self.artifact_mode
+                .map(|mode| {
+                    if dir {
+                        mode | (mode & 0o444) >> 2
+                    } else {
+                        mode
+                    }
+                })
+                .map(fs::Permissions::from_mode)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = dir;
+            None
+        }
+    }
+
+    /// Give the temporary copy at `path` the configured permissions.
+    fn set_artifact_permissions(&self, path: &Path, dir: bool) -> Result<()> {
+        match self.artifact_permissions(dir) {
+            Some(permissions) => {
+                fs::set_permissions(path, permissions).at("set permissions of", path)
             }
+            None => Ok(()),
+        }
+    }
 
-            #[cfg(windows)]
-            {
-                std::os::windows::fs::symlink_dir(&link_target, &dest_path)?;
+    fn copy<F: FnOnce(&mut CopyStats) -> Result<()>>(&self, f: F) -> Result<()> {
+        let span = trace::copy_span(&self.span);
+        let _enter = span.enter();
+        let mut stats = CopyStats {
+            deadline: self.io_deadline.map(Deadline::start),
+            ..CopyStats::default()
+        };
+        let result = f(&mut stats);
+        span.record("files", stats.files);
+        span.record("bytes", stats.bytes);
+        result
+    }
+
+    /// Like [`Ctx::copy`], for a copy of `src` at `dst` that nothing else knows about yet, which is removed
+    /// again if it fails part of the way through, such as when it runs out of time, see
+    /// [`ClientBuilder::io_deadline`], or space.
+    fn copy_new<F: FnOnce(&mut CopyStats) -> Result<()>>(
+        &self,
+        src: &Path,
+        dst: &Path,
+        f: F,
+    ) -> Result<()> {
+        self.preflight_copy(src, dst)
+            .map_err(|e| self.redact.error(e))?;
+        let result = self.copy(f);
+        if result.is_err() {
+            self.discard(dst);
+        }
+        result.map_err(|e| self.redact.error(e))
+    }
+
+    /// Removes the temporary file or directory at `path`, which nothing else knows about.
+    fn discard(&self, path: &Path) {
+        let _ = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => self.vfs.remove_dir_all(path),
+            _ => self.vfs.remove_file(path),
+        };
+    }
+
+    /// Removes the copy at `path` of a commit that ran out of space, see [`Error::is_no_space`], rather
+    /// than leave it taking up room until gc.
+    fn discard_on_no_space(&self, path: &Path, result: &Result<()>) {
+        if let Err(e) = result
+            && e.is_no_space()
+        {
+            self.discard(path);
+        }
+    }
+
+    /// Fails with [`Error::NoSpace`] if the filesystem that the temporary `path` is to be written to has
+    /// room for fewer than `needed` bytes, see [`ClientBuilder::preflight_space`].
+    fn preflight(&self, path: &Path, needed: u64) -> Result<()> {
+        if !self.preflight_space {
+            return Ok(());
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        match quota::available_space(dir) {
+            Some(available) if available < needed => Err(Error::NoSpace {
+                path: path.to_path_buf(),
+                needed: Some(needed),
+                available: Some(available),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// [`Ctx::preflight`] for a copy of `src` at `dst`, which takes no room if it is reflinked.
+    fn preflight_copy(&self, src: &Path, dst: &Path) -> Result<()> {
+        if !self.preflight_space
+            || dst
+                .parent()
+                .and_then(reflink::device)
+                .is_some_and(|device| self.reflink.supported(device) == Some(true))
+        {
+            return Ok(());
+        }
+        self.preflight(dst, quota::data_size(src))
+    }
+
+    /// Account for a commit replacing `old` with `new`, see [`Quota::charge`]. Charges nothing without a
+    /// quota, such as for the free functions.
+    fn charge_quota(&self, new: &Path, old: &Path) -> Result<i64> {
+        match &self.quota {
+            Some(quota) => quota.charge(new, old),
+            None => Ok(0),
+        }
+    }
+
+    fn refund_quota(&self, charged: i64) {
+        if let Some(quota) = &self.quota {
+            quota.refund(charged);
+        }
+    }
+
+    /// Account for `bytes` of data having been removed under a write lock.
+    fn release_quota(&self, bytes: u64) {
+        if let Some(quota) = &self.quota {
+            quota.release(bytes);
+        }
+    }
+
+    /// Fails if the database is a replica, which only changes through [`Client::apply_commits`].
+    fn check_writable(&self, orig: &Path) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnlyReplica {
+                path: orig.to_path_buf(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Record a change of the entry at `orig`, which is still write locked, in the replication log.
+    fn replicate(&self, op: ReplicatedOp, orig: &Path) {
+        if let Some(replog) = &self.replog
+            && let Err(error) = replog.record(op, orig, self)
+        {
+            report_warning(
+                self.on_warning.as_ref(),
+                &self.redact,
+                Warning::Replication {
+                    path: orig.to_path_buf(),
+                    error,
+                },
+            );
+        }
+    }
+
+    /// Runs the commit `f` of the entry at `orig`.
+    fn commit<F: FnOnce() -> Result<()>>(&self, kind: CommitKind, orig: &Path, f: F) -> Result<()> {
+        self.check_writable(orig)
+            .map_err(|e| self.redact.error(e))?;
+        let span = trace::commit_span(&self.span, kind);
+        let _enter = span.enter();
+        // only the audit log tells creations apart
+        let existed = cfg!(feature = "serde_json") && fs::symlink_metadata(orig).is_ok();
+        let before = self.usage_before(orig);
+        let start = Instant::now();
+        if let Some(generations) = &self.generations {
+            generations.bump(orig).map_err(|e| self.redact.error(e))?;
+        }
+        let result = f();
+        // the lock files below a replaced directory were moved away with it
+        if let Some(cache) = &self.lock_cache
+            && kind != CommitKind::File
+        {
+            cache.evict_under(orig);
+        }
+        match result {
+            Ok(()) => {
+                span.record("outcome", "committed");
+                self.committed(kind, orig, existed, start);
+                self.roll_up(orig, before);
+            }
+            Err(_) => {
+                span.record("outcome", "failed");
+            }
+        }
+        result.map_err(|e| self.redact.error(e))
+    }
+
+    /// Reports the commit of the entry at `orig` that began at `start`, which replaced an entry if
+    /// `existed` and created one otherwise.
+    fn committed(&self, kind: CommitKind, orig: &Path, existed: bool, start: Instant) {
+        self.metrics.commit(kind, start.elapsed());
+        self.bump_generation(orig);
+        #[cfg(feature = "serde_json")]
+        if let Some(audit) = &self.audit {
+            let op = match existed {
+                true => AuditOp::Replace,
+                false => AuditOp::Create,
+            };
+            if let Err(error) = audit.record(kind, orig, op, self) {
+                report_warning(
+                    self.on_warning.as_ref(),
+                    &self.redact,
+                    Warning::Audit {
+                        path: orig.to_path_buf(),
+                        error,
+                    },
+                );
+            }
+        }
+        #[cfg(not(feature = "serde_json"))]
+        let _ = existed;
+        self.replicate(ReplicatedOp::Write(kind), orig);
+    }
+
+    /// What the entry at `orig` holds before it is changed, for [`Ctx::roll_up`]. Only counted if the
+    /// client tracks rollups.
+    fn usage_before(&self, orig: &Path) -> Option<Usage> {
+        self.rollups.as_ref().map(|_| Usage::of(orig))
+    }
+
+    /// Records the change of the entry at `orig`, which held `before`, in the rollups once it is complete,
+    /// which is only reported if it fails, like [`Ctx::bump_generation`].
+    fn roll_up(&self, orig: &Path, before: Option<Usage>) {
+        if let (Some(rollups), Some(before)) = (&self.rollups, before)
+            && let Err(error) = rollups.changed(orig, before)
+        {
+            report_warning(
+                self.on_warning.as_ref(),
+                &self.redact,
+                Warning::Rollup {
+                    path: orig.to_path_buf(),
+                    error,
+                },
+            );
+        }
+    }
+
+    /// Advances the generation of `orig` once a change of it is complete, which is only reported if it
+    /// fails, the change has been made either way.
+    fn bump_generation(&self, orig: &Path) {
+        if let Some(generations) = &self.generations
+            && let Err(error) = generations.bump(orig)
+        {
+            report_warning(
+                self.on_warning.as_ref(),
+                &self.redact,
+                Warning::Generation {
+                    path: orig.to_path_buf(),
+                    error,
+                },
+            );
+        }
+    }
+}
+
+// Fails to build should a lock or guard stop being safe to hand to another thread.
+const _: () = {
+    fn send_sync<T: Send + Sync>() {}
+    let _ = send_sync::<Tx>;
+    let _ = send_sync::<FileReadGaurd>;
+    let _ = send_sync::<FileWriteGaurd>;
+    let _ = send_sync::<DirReadGaurd>;
+    let _ = send_sync::<DirWriteGaurd>;
+    let _ = send_sync::<CowFileGaurd>;
+    let _ = send_sync::<CowDirGaurd>;
+    let _ = send_sync::<CowAtomicDirGaurd>;
+    let _ = send_sync::<ReadLock>;
+    let _ = send_sync::<WriteLock>;
+};
+
+/// Whether symbolic links, and with them atomic directories, can be created in a database, probed once.
+struct AtomicDirSupport {
+    /// Where the probe creates its link, so that it is never seen as part of the data.
+    internal: PathBuf,
+    supported: OnceLock<bool>,
+}
+
+impl AtomicDirSupport {
+    fn new(internal: PathBuf) -> Self {
+        AtomicDirSupport {
+            internal,
+            supported: OnceLock::new(),
+        }
+    }
+
+    fn supported(&self, vfs: &dyn Vfs) -> bool {
+        *self.supported.get_or_init(|| self.probe(vfs))
+    }
+
+    fn probe(&self, vfs: &dyn Vfs) -> bool {
+        let link = self.internal.join(format!("symlink-probe-{}", puuid()));
+        let created = vfs
+            .create_dir_all(&self.internal)
+            .and_then(|()| vfs.symlink_dir(Path::new("."), &link));
+        if let Err(e) = created {
+            log::warn!(path:? = link, operation = "probe"; "atomic directories are not supported: {}", e);
+            return false;
+        }
+        // a link to a directory is removed like a directory on windows
+        #[cfg(windows)]
+        let removed = fs::remove_dir(&link);
+        #[cfg(not(windows))]
+        let removed = fs::remove_file(&link);
+        if let Err(e) = removed {
+            log::warn!(path:? = link, operation = "cleanup"; "failed to remove probe link: {}", e);
+        }
+        true
+    }
+}
+
+/// Directories entered by a walk that follows symbolic links, so that a link back to one of them is
+/// reported as [`Error::SymlinkLoop`] instead of being walked until the depth limit.
+#[derive(Default)]
+struct VisitedDirs {
+    #[cfg(unix)]
+    dirs: HashSet<(u64, u64)>,
+}
+
+impl VisitedDirs {
+    /// The standard library can only identify a directory on unix, elsewhere loops are bounded by the
+    /// depth limit alone.
+    fn enter(&mut self, dir: &Path) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let metadata = fs::metadata(dir).at("read metadata of", dir)?;
+            if !self.dirs.insert((metadata.dev(), metadata.ino())) {
+                return Err(Error::SymlinkLoop {
+                    path: dir.to_path_buf(),
+                });
             }
         }
+        #[cfg(not(unix))]
+        let _ = dir;
+        Ok(())
     }
+}
+
+const PUUID_LEN: usize = 24;
+
+/// Letter case of the identifiers made by [`puuid_with`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Case {
+    #[default]
+    Upper,
+    Lower,
+}
 
-    Ok(())
+thread_local! {
+    /// Seeded once per thread, and again after a fork so that a child does not repeat its parent.
+    static PUUID_RNG: RefCell<(u32, StdRng)> =
+        RefCell::new((std::process::id(), StdRng::from_os_rng()));
 }
 
 /// Path-UUID
@@ -646,72 +1658,93 @@ fn copy_recursive(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> anyhow::Resul
 /// specifically for usage in directory paths, meaning that they are shorter and
 /// more information dense than a standard hexidecimal UUID.
 pub fn puuid() -> String {
-    const LEN: usize = 24;
-    fn to_base_36(n: u8) -> char {
-        if n < 26 {
-            (b'A' + n) as char
-        } else {
-            (b'0' + (n - 26)) as char
+    puuid_with(PUUID_LEN, Case::Upper)
+}
+
+/// A puuid of `len` characters, each of the 26 letters in `case` and the 10 digits being equally likely.
+/// Shorter identifiers make shorter names, but collide sooner.
+pub fn puuid_with(len: usize, case: Case) -> String {
+    let letters = match case {
+        Case::Upper => b'A',
+        Case::Lower => b'a',
+    };
+    let range = Uniform::new(0u8, 36u8).unwrap();
+    PUUID_RNG.with_borrow_mut(|(pid, rand)| {
+        if *pid != std::process::id() {
+            *pid = std::process::id();
+            *rand = StdRng::from_os_rng();
         }
-    }
+        (0..len)
+            .map(|_| match rand.sample(range) {
+                n if n < 26 => (letters + n) as char,
+                n => (b'0' + (n - 26)) as char,
+            })
+            .collect()
+    })
+}
 
-    let range = Uniform::new(0 as u8, 36 as u8).unwrap();
-    let mut rand = StdRng::from_os_rng();
-    let mut res = String::with_capacity(LEN);
-    for _ in 0..LEN {
-        res.push(to_base_36(rand.sample(&range)));
+/// Case of the puuid `id`, or `None` if it is not one. Identifiers of only digits are taken as upper
+/// case.
+pub fn parse_puuid(id: &str) -> Option<Case> {
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return None;
+    }
+    match (
+        id.bytes().any(|b| b.is_ascii_uppercase()),
+        id.bytes().any(|b| b.is_ascii_lowercase()),
+    ) {
+        (_, false) => Some(Case::Upper),
+        (false, true) => Some(Case::Lower),
+        (true, true) => None,
     }
-    res
 }
 
 #[cfg(test)]
 mod test {
     use std::{
+        ffi::OsString,
         fs::{self, File},
-        path::PathBuf,
+        path::{Path, PathBuf},
         sync::{
-            Arc, Mutex,
+            Arc, Mutex, Once,
             atomic::{AtomicU64, Ordering},
         },
         thread,
-        time::Duration,
+        time::{Duration, Instant},
     };
 
     use anyhow::Context;
     use path_dsl::path;
     use rand::{Rng, SeedableRng, rngs::SmallRng};
 
-    use crate::{Client, ReadLock, WriteLock, puuid};
-
-    struct TestClient {
-        pub client: Client,
-        root: PathBuf,
-    }
+    use crate::{
+        AtomicCowOptions, AtomicDirCreation, AtomicMetrics, BlobId, ChangeOutcome, Changeset,
+        Client, CommitKind, Ctx, DedupOptions, Error, GcOptions, HashAlgorithm, LockBackend,
+        OpenMode, PathDisplay, ReadLock, ReplicatedOp, Warning, WarningCallback, WriteLock,
+        artifact::{self, ArtifactKind},
+        fixture::TestClient,
+        puuid, unlocked,
+    };
 
-    impl TestClient {
-        pub fn new(name: &str) -> anyhow::Result<Self> {
-            let root = std::env::temp_dir().join(name.to_string() + "-" + &puuid());
-            Ok(TestClient {
-                client: Client::new(&root)?,
-                root,
-            })
-        }
+    #[test]
+    fn fuzz_test_mixed_locking() {
+        fuzz_mixed_locking("my_temp_file.txt", LockBackend::Flock);
     }
 
-    impl Drop for TestClient {
-        fn drop(&mut self) {
-            if let Err(e) = fs::remove_dir_all(&self.root) {
-                eprintln!("failed to delete test db: {:?}", e);
-            }
-        }
+    #[test]
+    fn fuzz_test_mixed_locking_lock_dirs() {
+        fuzz_mixed_locking("my_temp_file_lockd.txt", LockBackend::atomic_lock_dir());
     }
 
-    #[test]
     #[allow(unused_variables)]
-    fn fuzz_test_mixed_locking() {
+    fn fuzz_mixed_locking(name: &str, lock_backend: LockBackend) {
         let mut threads = Vec::new();
         let tmp_dir = std::env::temp_dir();
-        let tmp_file_path_orig = tmp_dir.join("my_temp_file.txt");
+        let tmp_file_path_orig = tmp_dir.join(name);
+        let ctx = Ctx {
+            lock_backend,
+            ..Ctx::detached()
+        };
         let rcnt_orig = Arc::new(AtomicU64::new(0));
         let wcnt_orig = Arc::new(AtomicU64::new(0));
         let rec_orig = Arc::new(Mutex::new(String::new()));
@@ -721,11 +1754,12 @@ mod test {
             let rcnt = rcnt_orig.clone();
             let wcnt = wcnt_orig.clone();
             let rec = rec_orig.clone();
+            let ctx = ctx.clone();
             threads.push(thread::spawn(move || {
                 let mut rng = SmallRng::from_os_rng();
                 if rng.random_bool(0.5) {
                     thread::sleep(Duration::from_millis(rng.random_range(1..=10)));
-                    let _gaurd = ReadLock::new(tmp_file_path).unwrap();
+                    let _gaurd = ReadLock::new(tmp_file_path, &ctx).unwrap();
                     // rec.lock().unwrap().push('r');
                     rcnt.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
                     if wcnt.load(Ordering::Acquire) > 0 {
@@ -735,7 +1769,7 @@ mod test {
                     rcnt.fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
                 } else {
                     thread::sleep(Duration::from_millis(rng.random_range(1..=10)));
-                    let _gaurd = WriteLock::new(tmp_file_path).unwrap();
+                    let _gaurd = WriteLock::new(tmp_file_path, &ctx).unwrap();
                     // rec.lock().unwrap().push('w');
                     let wcnt_sn = wcnt.fetch_add(1, Ordering::AcqRel);
                     if wcnt_sn > 0 {
@@ -771,7 +1805,6 @@ mod test {
         let created = fs::metadata(some_dir)
             .context("could not get metadata")?
             .created()?;
-        eprint!("{:?}", db.root().join("test_write.txt"));
         File::create(db.root().join("test_write.txt"))?;
         File::create(db.root().join("collatz_in.txt"))?;
         fs::write(db.root().join("collatz_in.txt"), "500")?;
@@ -804,7 +1837,7 @@ mod test {
                 .read("collatz_in.txt")
                 .write("collatz_out.txt")
                 .begin()?;
-            let n = fs::read_to_string(db.root.join("collatz_in.txt"))?
+            let n = fs::read_to_string(db.inner.root.join("collatz_in.txt"))?
                 .trim()
                 .parse::<i64>()?;
             if n > 1 {
@@ -878,11 +1911,46 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_dir_cow_commit_after_release() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_dir_cow_commit_after_release")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("dir")?;
+        let panics = |cow: crate::CowDirGaurd| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cow.commit())).is_err()
+        };
+
+        // readers of entries below the directory are only kept out while the lock is held
+        let gaurd = db.write_dir("dir")?;
+        let cow = gaurd.cow()?;
+        gaurd.release()?;
+        assert!(panics(cow));
+
+        // a transaction's copy relies on the lock of the declared write that covers it
+        let tx = db.tx().write("dir").begin()?;
+        let cow = tx.dir_cow("dir/sub")?;
+        fs::write(cow.path.join("a.txt"), "a")?;
+        let kept = tx.dir_cow("dir/kept")?;
+        tx.release()?;
+        assert!(panics(cow));
+        assert!(panics(kept));
+
+        // while it is held the copy commits, and copies made without a lock are not checked
+        let gaurd = db.write_dir("dir")?;
+        let cow = gaurd.cow()?;
+        fs::write(cow.path.join("b.txt"), "b")?;
+        cow.commit()?;
+        gaurd.release()?;
+        crate::unlocked::dir_cow_unchecked(db.root().join("dir"))?.commit()?;
+        assert_eq!("b", fs::read_to_string(db.root().join("dir/b.txt"))?);
+        assert!(!db.root().join("dir/sub").exists());
+        Ok(())
+    }
+
     #[test]
     #[cfg(unix)]
     fn test_dir_cow_atomic() -> anyhow::Result<()> {
-        use crate::dir_cow_atomic;
-
         let test_client = TestClient::new("test_dir_cow_atomic")?;
         let db = &test_client.client;
 
@@ -891,7 +1959,7 @@ mod test {
             let dir = gaurd.cow_atomic()?;
             let nested_path = dir.path.join("nested");
             fs::create_dir(&nested_path)?;
-            dir_cow_atomic(&nested_path)?.commit()?;
+            dir.create_nested_atomic("nested")?;
             let test_path = nested_path.join("test.txt");
             File::create(&test_path)?;
             fs::write(&test_path, "test1")?;
@@ -919,6 +1987,63 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_tx_create_dir() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_tx_create_dir")?;
+        let db = &test_client.client;
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        // a reader never sees the new directory with only some of its files
+        let reader = {
+            let db = db.clone();
+            let done = done.clone();
+            std::thread::spawn(move || -> anyhow::Result<usize> {
+                let mut seen = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let Ok(gaurd) = db.read_dir("reports/2025") else {
+                        continue;
+                    };
+                    if let Ok(entries) = fs::read_dir(&gaurd.path) {
+                        let files = entries
+                            .map(|e| e.map(|e| e.file_name()))
+                            .collect::<std::io::Result<Vec<_>>>()?
+                            .into_iter()
+                            .filter(|name| !name.to_string_lossy().starts_with('.'))
+                            .count();
+                        assert_eq!(3, files);
+                        seen += 1;
+                    }
+                }
+                Ok(seen)
+            })
+        };
+
+        let tx = db.tx().write("reports/2025").begin()?;
+        let dir = tx.dir_cow("reports/2025")?;
+        for month in ["01", "02", "03"] {
+            fs::write(dir.path.join(month), month)?;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        dir.commit()?;
+        tx.release()?;
+        std::thread::sleep(Duration::from_millis(10));
+        done.store(true, Ordering::SeqCst);
+        assert!(reader.join().unwrap()? > 0);
+        assert_eq!("02", fs::read_to_string(db.root().join("reports/2025/02"))?);
+
+        // and as an atomic directory
+        let tx = db.tx().write("atomic/reports").begin()?;
+        let dir = tx.dir_cow_atomic("atomic/reports")?;
+        fs::write(dir.path.join("01"), "01")?;
+        dir.commit()?;
+        tx.release()?;
+        assert_eq!(
+            "01",
+            fs::read_to_string(db.root().join("atomic/reports/01"))?
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_tx_operations() -> anyhow::Result<()> {
         let test_client = TestClient::new("test_tx_operations")?;
@@ -980,68 +2105,6616 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_spawn_gc() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_spawn_gc")?;
+        let db = &test_client.client;
+
+        {
+            let gaurd = db.write_file("gone.txt")?;
+            fs::write(&gaurd.path, "content")?;
+        }
+        fs::remove_file(db.root().join("gone.txt"))?;
+
+        let handle = db.spawn_gc(Duration::from_millis(10), GcOptions::default());
+        let start = Instant::now();
+        while handle.runs() < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "gc never ran twice"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        let report = handle.last_report().context("missing report")?;
+        handle.stop();
+
+        assert_eq!(0, report.errors);
+        assert!(!db.root().join(".gone.txt.lock.sbdb").exists());
+        assert!(!db.root().join(".gone.txt.queue.sbdb").exists());
+
+        // directories are only read locked while they are scanned, so readers do not hold gc up
+        let reading = db.read_dir("")?;
+        let (done, finished) = std::sync::mpsc::channel();
+        let gc = {
+            let db = db.clone();
+            thread::spawn(move || done.send(db.gc()))
+        };
+        let report = finished
+            .recv_timeout(Duration::from_secs(10))
+            .context("gc waited for a reader")?;
+        assert_eq!(0, report.errors);
+        reading.release()?;
+        gc.join().unwrap()?;
+
+        Ok(())
+    }
+
     #[test]
     #[cfg(unix)]
-    fn test_tx_operations_atomic_cow() -> anyhow::Result<()> {
-        use crate::dir_cow_atomic;
+    fn test_gc_skips_foreign_symlinks() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_gc_skips_foreign_symlinks")?;
+        let db = &test_client.client;
 
-        let test_client = TestClient::new("test_tx_operations_atomic_cow")?;
+        let outside = TestClient::new("test_gc_skips_foreign_symlinks_outside")?;
+        let outside_root = outside.client.root();
+        fs::create_dir(outside_root.join("sub"))?;
+        fs::write(outside_root.join(".orphan.lock.sbdb"), "")?;
+        fs::write(outside_root.join(".orphan.tmp.sbdb"), "")?;
+        let list = |path: &PathBuf| -> anyhow::Result<Vec<_>> {
+            let mut names = fs::read_dir(path)?
+                .map(|e| e.map(|e| e.file_name()))
+                .collect::<Result<Vec<_>, _>>()?;
+            names.sort();
+            Ok(names)
+        };
+        let before = list(outside_root)?;
+
+        std::os::unix::fs::symlink(outside_root, db.root().join("escape"))?;
+        db.write_dir("")?.create_dir_atomic("atomic", false)?;
+
+        let report = db.gc();
+        assert_eq!(1, report.symlinks_skipped);
+        assert_eq!(0, report.payloads_removed);
+        assert_eq!(0, report.errors);
+        assert!(db.root().join("atomic").is_dir());
+        assert_eq!(before, list(outside_root)?);
+        assert_eq!(Vec::<OsString>::new(), list(&outside_root.join("sub"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_variants() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_error_variants")?;
         let db = &test_client.client;
 
         {
-            let gaurd = db.write_dir("")?;
-            let cp = gaurd.cow()?;
-            let nested = cp.path.join("nested");
-            let read = nested.join("read.txt");
-            let writes = nested.join("writes");
-            let write1 = writes.join("write1.txt");
-            let write2 = writes.join("write2.txt");
-            fs::create_dir_all(&nested)?;
-            File::create(&read)?;
-            fs::create_dir(&writes)?;
-            dir_cow_atomic(writes)?.commit()?;
-            File::create(&write1)?;
-            File::create(&write2)?;
-            fs::write(&read, "1")?;
-            fs::write(&write1, "0")?;
-            fs::write(&write2, "0")?;
-            cp.commit()?;
+            let gaurd = db.write_file("missing.txt")?;
+            let err = gaurd.cow().err().context("cow of missing file succeeded")?;
+            assert!(
+                matches!(err, Error::NotFound { path, .. } if path == db.root().join("missing.txt"))
+            );
         }
 
         {
-            let tx = db
-                .tx()
-                .read("nested/read.txt")
-                .write("nested/writes/write1.txt")
-                .write("nested/writes/write2.txt")
-                .write("nested/writes") // purposefully add more write protection than neccessary
-                .begin()?;
-            let cp = tx.dir_cow_atomic("nested/writes")?;
-            let write1 = cp.path.join("write1.txt");
-            let write2 = cp.path.join("write2.txt");
+            let err = unlocked::dir_cow_atomic_unchecked("/")
+                .err()
+                .context("cow of root succeeded")?;
+            assert!(matches!(err, Error::InvalidPath { .. }));
+        }
 
-            let n = fs::read_to_string(db.root().join("nested/read.txt"))?
-                .trim()
-                .parse::<i64>()?;
+        {
+            fs::write(db.root().join("declared.txt"), "")?;
+            fs::write(db.root().join("undeclared.txt"), "")?;
+            let tx = db.tx().write("declared.txt").begin()?;
+            tx.file_cow("declared.txt")?;
+            let err = tx
+                .file_cow("undeclared.txt")
+                .err()
+                .context("undeclared write")?;
+            assert!(
+                matches!(err, Error::UndeclaredWrite { path } if path == db.root().join("undeclared.txt"))
+            );
+        }
 
-            fs::write(write1, (n + 1).to_string())?;
-            fs::write(write2, (n + 2).to_string())?;
+        {
+            fs::create_dir(db.root().join("dir"))?;
+            fs::write(db.root().join("dir/file.txt"), "original")?;
+            let gaurd = db.write_dir("dir")?;
+            let cp = gaurd.cow()?;
+            fs::remove_dir_all(&cp.path)?;
+            let err = cp
+                .commit()
+                .err()
+                .context("commit of removed copy succeeded")?;
+            assert!(matches!(err, Error::CommitFailed { backup: None, .. }));
+            assert_eq!(
+                "original",
+                fs::read_to_string(db.root().join("dir/file.txt"))?
+            );
+        }
 
-            cp.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_context() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_error_context")?;
+        let db = &test_client.client;
+        let render = |err: Error| format!("{:#}", anyhow::Error::from(err));
+
+        {
+            // the parent of the lock file is a regular file
+            fs::write(db.root().join("file.txt"), "")?;
+            let err = db
+                .write_file("file.txt/child.txt")
+                .err()
+                .context("locked a child of a file")?;
+            let lock_file = db.root().join("file.txt/.child.txt.lock.sbdb");
+            assert!(
+                matches!(&err, Error::Io { operation: "open lock file", path, .. } if *path == lock_file)
+            );
+            assert!(render(err).contains(&lock_file.display().to_string()));
         }
 
         {
-            let gaurd = db.read_file("nested/writes/write1.txt")?;
-            let n = fs::read_to_string(gaurd.path)?.trim().parse::<i64>()?;
-            assert_eq!(2, n);
+            // a stale copy is in the way of one of the entries
+            fs::create_dir_all(db.root().join("dir"))?;
+            fs::write(db.root().join("dir/entry.txt"), "")?;
+            fs::create_dir_all(db.root().join(".dir.tmp.sbdb/entry.txt"))?;
+            let err = db
+                .write_dir("dir")?
+                .cow()
+                .err()
+                .context("copied over a directory")?;
+            let rendered = render(err);
+            assert!(rendered.contains(&db.root().join("dir/entry.txt").display().to_string()));
+            assert!(
+                rendered.contains(
+                    &db.root()
+                        .join(".dir.tmp.sbdb/entry.txt")
+                        .display()
+                        .to_string()
+                )
+            );
+            // the stale copy is removed along with what the failed copy added to it
+            assert!(!db.root().join(".dir.tmp.sbdb").exists());
         }
 
         {
-            let gaurd = db.read_file("nested/writes/write2.txt")?;
-            let n = fs::read_to_string(gaurd.path)?.trim().parse::<i64>()?;
-            assert_eq!(3, n);
+            let gaurd = db.write_dir("dir")?;
+            let cp = gaurd.cow()?;
+            fs::remove_dir_all(&cp.path)?;
+            let copy = cp.path.clone();
+            let err = cp
+                .commit()
+                .err()
+                .context("commit of removed copy succeeded")?;
+            let rendered = render(err);
+            assert!(rendered.contains("commit copy"));
+            assert!(rendered.contains(&copy.display().to_string()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_paths() -> anyhow::Result<()> {
+        install_capturing_logger();
+        let rpath = Path::new("nested/writes/write1.txt");
+        for (name, display) in [
+            ("test_redact_paths_absolute", PathDisplay::Absolute),
+            ("test_redact_paths_relative", PathDisplay::RootRelative),
+            ("test_redact_paths_hashed", PathDisplay::Hashed),
+        ] {
+            let test_client = TestClient::new(name)?;
+            let warnings = Arc::new(Mutex::new(Vec::new()));
+            let db = Client::builder(&test_client.root)
+                .redact_paths(display)
+                .on_warning({
+                    let warnings = warnings.clone();
+                    move |warning| warnings.lock().unwrap().push(warning)
+                })
+                .build()?;
+            fs::create_dir_all(db.root().join("nested/writes"))?;
+            let path = db.root().join(rpath);
+            let (shown, root) = match display {
+                PathDisplay::Absolute => {
+                    (path.display().to_string(), db.root().display().to_string())
+                }
+                PathDisplay::RootRelative => {
+                    ("nested/writes/write1.txt".to_string(), ".".to_string())
+                }
+                // the first 12 hex digits of the SHA-256 of the path, and of the empty path
+                PathDisplay::Hashed => ("1fcc0c2e6e95".to_string(), "e3b0c44298fc".to_string()),
+            };
+
+            let err = db
+                .write_file(rpath)?
+                .cow()
+                .err()
+                .context("copied a missing file")?;
+            assert_eq!(
+                format!("failed to copy {shown}: does not exist"),
+                err.to_string()
+            );
+            let gaurd = db.write_file(rpath)?;
+            // on another thread, since this one would lock the root out of order below the guard
+            let err = thread::scope(|s| {
+                s.spawn(|| db.tx().write(rpath).try_begin().err())
+                    .join()
+                    .unwrap()
+            })
+            .context("locked a held file")?;
+            assert_eq!(
+                format!("lock on {shown} is held elsewhere"),
+                err.to_string()
+            );
+            // the free functions know no root, so they show paths as they are
+            let err = crate::file_cow(&path)
+                .err()
+                .context("copied a missing file")?;
+            assert_eq!(
+                format!("failed to copy {}: does not exist", path.display()),
+                err.to_string()
+            );
+            gaurd.release()?;
+            let err = Client::builder(db.root().join("nested"))
+                .open_mode(OpenMode::Existing)
+                .redact_paths(display)
+                .build()
+                .err()
+                .context("opened a plain directory")?;
+            assert_eq!(
+                match display {
+                    PathDisplay::Absolute =>
+                        format!("no database at {}", db.root().join("nested").display()),
+                    PathDisplay::RootRelative => "no database at .".to_string(),
+                    PathDisplay::Hashed => "no database at e3b0c44298fc".to_string(),
+                },
+                err.to_string()
+            );
+
+            let err = db
+                .read_all_to_vec(&[rpath])
+                .err()
+                .context("read a missing file")?;
+            assert_eq!(
+                format!("failed to read {shown}: does not exist"),
+                err.to_string()
+            );
+            fs::write(&path, "")?;
+            #[cfg(feature = "serde_json")]
+            {
+                let err = db
+                    .read_json::<u32, _>(rpath)
+                    .err()
+                    .context("decoded an empty file")?;
+                assert_eq!(format!("failed to decode {shown}"), err.to_string());
+            }
+            #[cfg(feature = "binary")]
+            {
+                let err = db
+                    .read_bin::<u32, _>(rpath)
+                    .err()
+                    .context("decoded an empty file")?;
+                assert_eq!(
+                    format!("{shown} was not written in the expected format"),
+                    err.to_string()
+                );
+            }
+
+            db.warn(Warning::Gc {
+                path: path.clone(),
+                error: Error::RootNotFound {
+                    path: db.root().clone(),
+                },
+            });
+            let warning = warnings.lock().unwrap().pop().context("no warning")?;
+            assert!(matches!(
+                warning,
+                Warning::Gc { path, error } if path == Path::new(&shown)
+                    && error.to_string() == format!("no database at {root}")
+            ));
+            let expected = format!("{shown:?}");
+            assert!(CAPTURED.lock().unwrap().iter().any(
+                |c| c.operation.as_deref() == Some("gc") && c.path.as_ref() == Some(&expected)
+            ));
         }
+        Ok(())
+    }
+
+    #[cfg(any(feature = "serde_json", feature = "binary"))]
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Account {
+        name: String,
+        balance: i64,
+        owner: Owner,
+        tags: Vec<String>,
+    }
+
+    #[cfg(any(feature = "serde_json", feature = "binary"))]
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Owner {
+        id: u64,
+        email: Option<String>,
+    }
 
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_json() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_json")?;
+        let db = &test_client.client;
+        let account = Account {
+            name: "checking".to_string(),
+            balance: 100,
+            owner: Owner {
+                id: 7,
+                email: Some("owner@example.com".to_string()),
+            },
+            tags: vec!["a".to_string(), "b".to_string()],
+        };
+
+        db.write_json("account.json", &account)?;
+        assert_eq!(account, db.read_json("account.json")?);
+        assert_eq!(account, db.read_file("account.json")?.json::<Account>()?);
+
+        let options = crate::JsonOptions {
+            pretty: true,
+            sync: true,
+        };
+        db.write_json_with("account.json", &account, &options)?;
+        assert!(fs::read_to_string(db.root().join("account.json"))?.contains('\n'));
+        assert_eq!(account, db.read_json("account.json")?);
+
+        {
+            let tx = db.tx().read("account.json").write("other.json").begin()?;
+            let mut read: Account = tx.read_json("account.json")?;
+            read.balance += 1;
+            tx.write_json("other.json", &read)?;
+            assert!(matches!(
+                tx.write_json("account.json", &read),
+                Err(Error::UndeclaredWrite { .. })
+            ));
+            assert!(matches!(
+                tx.read_json::<Account, _>("missing.json"),
+                Err(Error::UndeclaredRead { .. })
+            ));
+        }
+        assert_eq!(101, db.read_json::<Account, _>("other.json")?.balance);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_json_malformed() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_json_malformed")?;
+        let db = &test_client.client;
+        let path = db.root().join("account.json");
+        fs::write(&path, "{\"name\": ")?;
+
+        let err = db
+            .read_json::<Account, _>("account.json")
+            .err()
+            .context("parsed malformed json")?;
+        assert!(matches!(&err, Error::Decode { path: p, .. } if *p == path));
+        assert!(format!("{:#}", anyhow::Error::from(err)).contains(&path.display().to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_versioned_codec() -> anyhow::Result<()> {
+        use crate::MigrationChain;
+
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct V1 {
+            name: String,
+        }
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct V2 {
+            name: String,
+            tags: Vec<String>,
+        }
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct V3 {
+            first: String,
+            last: String,
+            tags: Vec<String>,
+        }
+
+        let test_client = TestClient::new("test_versioned_codec")?;
+        let db = &test_client.client;
+        let v1 = V1 {
+            name: "Ada Lovelace".to_string(),
+        };
+        db.versioned_codec::<V1>().write_versioned("user", &v1, 1)?;
+        let chain = || {
+            MigrationChain::<V3>::new(1)
+                .then(|mut value| {
+                    value["tags"] = serde_json::json!([]);
+                    Ok(value)
+                })
+                .then_typed(|v2: V2| {
+                    let (first, last) = v2.name.split_once(' ').ok_or("no last name")?;
+                    Ok(V3 {
+                        first: first.to_string(),
+                        last: last.to_string(),
+                        tags: v2.tags,
+                    })
+                })
+        };
+        let expected = V3 {
+            first: "Ada".to_string(),
+            last: "Lovelace".to_string(),
+            tags: vec![],
+        };
+        let codec = db.versioned_codec::<V3>();
+        assert_eq!(3, chain().current());
+        assert_eq!(expected, codec.read_versioned("user", &chain())?);
+        let version = |bytes: Vec<u8>| u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        assert_eq!(1, version(fs::read(db.root().join("user"))?));
+
+        // the upgrade persists, and needs no migrations afterwards
+        let upgrading = chain().upgrade_in_place(true);
+        assert_eq!(expected, codec.read_versioned("user", &upgrading)?);
+        assert_eq!(3, version(fs::read(db.root().join("user"))?));
+        assert_eq!(
+            expected,
+            codec.read_versioned("user", &MigrationChain::new(3))?
+        );
+
+        assert!(matches!(
+            codec.read_versioned("user", &MigrationChain::new(1)),
+            Err(Error::VersionTooNew {
+                found: 3,
+                supported: 1,
+                ..
+            })
+        ));
+        db.versioned_codec::<V1>().write_versioned(
+            "single",
+            &V1 {
+                name: "Ada".to_string(),
+            },
+            1,
+        )?;
+        assert!(matches!(
+            codec.read_versioned("single", &chain()),
+            Err(Error::MigrationFailed { from: 2, .. })
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "binary")]
+    fn test_account() -> Account {
+        Account {
+            name: "savings".to_string(),
+            balance: -20,
+            owner: Owner { id: 3, email: None },
+            tags: vec!["c".to_string()],
+        }
+    }
+
+    /// Same encoding as [`crate::Postcard`] but with a different header.
+    #[cfg(feature = "binary")]
+    struct OtherCodec;
+
+    #[cfg(feature = "binary")]
+    impl crate::ValueCodec for OtherCodec {
+        const MAGIC: [u8; 4] = *b"TEST";
+        const VERSION: u8 = 1;
+
+        fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, crate::CodecError> {
+            <crate::Postcard as crate::ValueCodec>::encode(value)
+        }
+
+        fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, crate::CodecError> {
+            <crate::Postcard as crate::ValueCodec>::decode(bytes)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "binary")]
+    fn test_bin() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_bin")?;
+        let db = &test_client.client;
+        let account = test_account();
+
+        db.write_bin("account.bin", &account)?;
+        assert_eq!(account, db.read_bin("account.bin")?);
+        assert_eq!(
+            account,
+            db.read_file("account.bin")?
+                .value::<crate::Postcard, Account>()?
+        );
+
+        {
+            let tx = db.tx().write("account.bin").begin()?;
+            let mut read: Account = tx.read_value::<crate::Postcard, _, _>("account.bin")?;
+            read.balance = 0;
+            tx.write_value::<crate::Postcard, _, _>("account.bin", &read)?;
+        }
+        assert_eq!(0, db.read_bin::<Account, _>("account.bin")?.balance);
+
+        db.write_value::<OtherCodec, _, _>("other.bin", &account)?;
+        let err = db
+            .read_bin::<Account, _>("other.bin")
+            .err()
+            .context("read a file written by another codec")?;
+        assert!(matches!(err, Error::WrongFormat { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "binary")]
+    fn test_bin_truncated() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_bin_truncated")?;
+        let db = &test_client.client;
+        let path = db.root().join("account.bin");
+        db.write_bin("account.bin", &test_account())?;
+        let len = fs::metadata(&path)?.len();
+
+        for truncated in (0..len).rev() {
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_len(truncated)?;
+            let err = db
+                .read_bin::<Account, _>("account.bin")
+                .err()
+                .context("read a truncated file")?;
+            if truncated < 5 {
+                assert!(matches!(err, Error::WrongFormat { .. }));
+            } else {
+                assert!(matches!(err, Error::Decode { .. }));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "binary")]
+    fn test_collection() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_collection")?;
+        let db = &test_client.client;
+        let accounts = db.collection::<Account, _>("accounts");
+
+        assert_eq!(None, accounts.get("a")?);
+        assert_eq!(0, accounts.iter()?.count());
+        assert!(!accounts.remove("a")?);
+
+        let account = test_account();
+        accounts.put("a", &account)?;
+        accounts.put("b", &account)?;
+        assert_eq!(Some(&account), accounts.get("a")?.as_ref());
+        accounts.update("b", |b| {
+            b.map(|mut b| {
+                b.balance = 1;
+                b
+            })
+        })?;
+        assert_eq!(1, accounts.get("b")?.context("b missing")?.balance);
+
+        let keys = accounts
+            .iter()?
+            .map(|r| r.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(vec!["a", "b"], keys);
+
+        assert!(accounts.remove("a")?);
+        accounts.update("b", |_| None)?;
+        assert_eq!(0, accounts.iter()?.count());
+
+        let long = "k".repeat(300);
+        for key in ["../escape", ".hidden", "con", &long] {
+            accounts.put(key, &account)?;
+            assert_eq!(Some(&account), accounts.get(key)?.as_ref());
+        }
+        let mut keys = accounts
+            .iter()?
+            .map(|r| r.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()?;
+        keys.sort();
+        assert_eq!(vec!["../escape", ".hidden", "con", &long], keys);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "binary")]
+    fn test_rekey_collection() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_rekey_collection")?;
+        let db = &test_client.client;
+        let accounts = db.collection::<Account, _>("accounts");
+        let mut account = test_account();
+        for (key, balance) in [("a", 1), ("b", 2), ("keep", 3)] {
+            account.balance = balance;
+            accounts.put(key, &account)?;
+        }
+
+        let exclusive = db.lock_all()?;
+        // a and b swap their names
+        let swap = |key: &str| match key {
+            "a" => "b".to_string(),
+            "b" => "a".to_string(),
+            key => key.to_string(),
+        };
+        assert_eq!(2, exclusive.rekey_collection(&accounts, swap)?);
+        assert!(matches!(
+            exclusive.rekey_collection(&accounts, |key| key.replace("keep", "a")),
+            Err(Error::AlreadyExists { .. })
+        ));
+        drop(exclusive);
+
+        let balances = accounts
+            .iter()?
+            .map(|r| r.map(|(key, account)| (key, account.balance)))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            vec![
+                ("a".to_string(), 2),
+                ("b".to_string(), 1),
+                ("keep".to_string(), 3)
+            ],
+            balances
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "binary")]
+    fn test_collection_concurrent_update() -> anyhow::Result<()> {
+        const THREADS: u64 = 8;
+        const UPDATES: u64 = 25;
+
+        let test_client = TestClient::new("test_collection_concurrent_update")?;
+        let counters = test_client.client.collection::<u64, _>("counters");
+
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..UPDATES {
+                        counters
+                            .update("counter", |n| Some(n.unwrap_or(0) + 1))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(Some(THREADS * UPDATES), counters.get("counter")?);
+        Ok(())
+    }
+
+    const ADVERSARIAL_KEYS: [&str; 24] = [
+        "",
+        ".",
+        "..",
+        "a/b",
+        "a\\b",
+        "/",
+        "con",
+        "CON.txt",
+        "lpt9",
+        "Com1.tar.gz",
+        ".hidden",
+        "name.lock.sbdb",
+        ".name.lock.sbdb",
+        "x.sbdb",
+        "trailing.",
+        "trailing ",
+        "100%",
+        "%41",
+        "~",
+        "a~0123",
+        "nul\0byte",
+        "tab\tnewline\n",
+        "ünïcödé ✓",
+        "C:\\windows",
+    ];
+
+    #[test]
+    fn test_key_encoding() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_key_encoding")?;
+        let dir = test_client.client.root();
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let alphabet: Vec<char> = "aZ09-_.%~/\\: ✓\0".chars().collect();
+        let mut keys: Vec<String> = ADVERSARIAL_KEYS.iter().map(|k| k.to_string()).collect();
+        for len in [150, 199, 200, 201, 255, 300, 1000] {
+            keys.push("k".repeat(len));
+            keys.push("%".repeat(len));
+            keys.push("✓".repeat(len));
+        }
+        for _ in 0..2000 {
+            let len = rng.random_range(0..40);
+            keys.push(
+                (0..len)
+                    .map(|_| alphabet[rng.random_range(0..alphabet.len())])
+                    .collect(),
+            );
+        }
+        keys.sort();
+        keys.dedup();
+
+        let mut names = std::collections::HashSet::new();
+        for key in &keys {
+            let name = crate::key::encode(key);
+            assert!(
+                name.len() <= crate::key::MAX_NAME_LEN,
+                "{key:?} -> {name:?}"
+            );
+            assert!(!name.starts_with('.'), "{key:?} -> {name:?}");
+            assert!(
+                !name.ends_with('.') && !name.ends_with(".sbdb"),
+                "{key:?} -> {name:?}"
+            );
+            assert!(
+                name.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.%~".contains(&b)),
+                "{key:?} -> {name:?}"
+            );
+            let stem = name.split('.').next().unwrap_or_default();
+            assert!(
+                !["con", "prn", "aux", "nul", "com1", "lpt9"].contains(&&*stem.to_lowercase()),
+                "{key:?} -> {name:?}"
+            );
+            assert!(names.insert(name.clone()), "{key:?} -> {name:?} collides");
+            if !name.contains('~') {
+                assert_eq!(key, &crate::key::decode(&name)?);
+            }
+            // the file system must accept the name as well as sbdb's artifacts derived from it
+            File::create(dir.join(&name))?;
+            test_client.client.write_file(&name)?.cow()?;
+        }
+
+        assert!(crate::key::decode("%4").is_err());
+        assert!(crate::key::decode("%FF").is_err());
+        assert_eq!(
+            PathBuf::from("dir/a%2Fb"),
+            test_client.client.path_for_key("dir", "a/b")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_compression")?;
+        let db = &test_client.client;
+        let data = "compressible ".repeat(1000).into_bytes();
+
+        db.write_compressed("data", &data, 3)?;
+        assert!(fs::metadata(db.root().join("data"))?.len() < data.len() as u64 / 10);
+        assert_eq!(data, db.read_maybe_compressed("data")?);
+
+        db.write_compressed("empty", &[], 3)?;
+        assert!(db.read_maybe_compressed("empty")?.is_empty());
+
+        fs::write(db.root().join("plain"), "plain")?;
+        assert_eq!(b"plain".to_vec(), db.read_maybe_compressed("plain")?);
+
+        db.write_file("data")?.write_compressed(b"gaurd", 1)?;
+        assert_eq!(
+            b"gaurd".to_vec(),
+            db.read_file("data")?.read_maybe_compressed()?
+        );
+
+        // corrupt the frame, keeping the header intact
+        db.write_compressed("corrupt", &data, 3)?;
+        let path = db.root().join("corrupt");
+        let mut bytes = fs::read(&path)?;
+        for b in bytes.iter_mut().skip(20) {
+            *b = !*b;
+        }
+        fs::write(&path, &bytes)?;
+        assert!(matches!(
+            db.read_maybe_compressed("corrupt"),
+            Err(Error::Decode { .. })
+        ));
+
+        // a header claiming more than the limit is rejected before decompressing
+        bytes[5..13].copy_from_slice(&(crate::MAX_DECOMPRESSED_LEN + 1).to_le_bytes());
+        fs::write(&path, &bytes)?;
+        assert!(matches!(
+            db.read_maybe_compressed("corrupt"),
+            Err(Error::Decode { .. })
+        ));
+
+        #[cfg(feature = "serde_json")]
+        {
+            let account = Account {
+                name: "compressed".to_string(),
+                balance: 1,
+                owner: Owner { id: 1, email: None },
+                tags: Vec::new(),
+            };
+            db.write_json_compressed("account.json", &account, 3)?;
+            assert_eq!(account, db.read_json("account.json")?);
+        }
+
+        #[cfg(feature = "binary")]
+        {
+            db.write_bin_compressed("account.bin", &test_account(), 3)?;
+            assert_eq!(test_account(), db.read_bin("account.bin")?);
+        }
+
+        Ok(())
+    }
+
+    /// Relative paths and contents of every visible file below `dir`, following symlinks.
+    fn tree_contents(dir: &std::path::Path) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+        let mut result = Vec::new();
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(next) = stack.pop() {
+            for entry in fs::read_dir(&next)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                } else if path.is_dir() {
+                    stack.push(path);
+                } else {
+                    result.push((path.strip_prefix(dir)?.to_path_buf(), fs::read(&path)?));
+                }
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    #[test]
+    #[cfg(all(feature = "tar", unix))]
+    fn test_tar_round_trip() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_tar_round_trip")?;
+        let db = &test_client.client;
+
+        fs::create_dir_all(db.root().join("data/nested"))?;
+        fs::write(db.root().join("data/a.txt"), "a")?;
+        fs::write(db.root().join("data/nested/b.txt"), "b")?;
+        db.write_dir("data")?.create_dir_atomic("atomic", false)?;
+        fs::write(db.root().join("data/atomic/c.txt"), "c")?;
+        // leaves lock files behind
+        db.read_file("data/a.txt")?;
+
+        let options = crate::TarOptions {
+            prefix: PathBuf::from("data"),
+            ..Default::default()
+        };
+        let archive = db.export_tar(Vec::new(), &options)?;
+
+        let names = tar::Archive::new(archive.as_slice())
+            .entries()?
+            .map(|e| Ok(e?.path()?.to_string_lossy().into_owned()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert!(names.iter().all(|n| !n.contains(".sbdb")), "{names:?}");
+
+        db.import_tar(archive.as_slice(), "restored")?;
+        let restored = db.root().join("restored");
+        assert_eq!(
+            tree_contents(&db.root().join("data"))?,
+            tree_contents(&restored)?
+        );
+        assert!(!restored.join("atomic").is_symlink());
+        assert!(!db.root().join(".sbdb/tmp").read_dir()?.any(|_| true));
+
+        // importing again replaces the directory, a broken archive leaves it untouched
+        fs::write(restored.join("extra.txt"), "extra")?;
+        db.import_tar(archive.as_slice(), "restored")?;
+        assert!(!restored.join("extra.txt").exists());
+        assert!(
+            db.import_tar(&archive[..archive.len() / 2], "restored")
+                .is_err()
+        );
+        assert_eq!(
+            tree_contents(&db.root().join("data"))?,
+            tree_contents(&restored)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshots() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_snapshots")?;
+        let db = &test_client.client;
+
+        fs::create_dir_all(db.root().join("data/nested"))?;
+        fs::write(db.root().join("data/a.txt"), "a")?;
+        fs::write(db.root().join("data/nested/b.txt"), "b")?;
+        fs::write(db.root().join("top.txt"), "top")?;
+        let data = tree_contents(&db.root().join("data"))?;
+        let root = tree_contents(db.root())?;
+
+        let first = db.snapshot("data", "first")?;
+        let whole = db.snapshot("", "whole")?;
+        assert_eq!(vec![first.clone()], db.list_snapshots("data")?);
+        assert_eq!(vec![whole.clone()], db.list_snapshots("")?);
+
+        // gc must leave snapshots alone
+        db.gc();
+
+        fs::write(db.root().join("data/a.txt"), "changed")?;
+        fs::remove_file(db.root().join("data/nested/b.txt"))?;
+        fs::write(db.root().join("data/new.txt"), "new")?;
+        db.restore_snapshot("data", &first)?;
+        assert_eq!(data, tree_contents(&db.root().join("data"))?);
+
+        fs::remove_dir_all(db.root().join("data"))?;
+        fs::write(db.root().join("top.txt"), "changed")?;
+        fs::write(db.root().join("new.txt"), "new")?;
+        db.restore_snapshot("", &whole)?;
+        assert_eq!(root, tree_contents(db.root())?);
+        assert!(!db.root().join(".sbdb/tmp").read_dir()?.any(|_| true));
+
+        let second = db.snapshot("data", "second")?;
+        assert_eq!(
+            vec![first.clone(), second.clone()],
+            db.list_snapshots("data")?
+        );
+        db.delete_snapshot(&first)?;
+        assert_eq!(vec![second], db.list_snapshots("data")?);
+        assert!(matches!(
+            db.restore_snapshot("data", &first),
+            Err(Error::NotFound { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_versions")?;
+        let db = Client::builder(test_client.client.root())
+            .versioning(crate::VersioningPolicy {
+                keep: 3,
+                paths: crate::PathMatcher::prefix("kept"),
+            })
+            .build()?;
+
+        let write = |rpath: &str, contents: &str| -> anyhow::Result<()> {
+            let gaurd = db.write_file(rpath)?;
+            let cp = gaurd.cow()?;
+            fs::write(&cp.path, contents)?;
+            cp.commit()?;
+            Ok(())
+        };
+
+        fs::create_dir_all(db.root().join("kept"))?;
+        fs::write(db.root().join("kept/file.txt"), "0")?;
+        fs::write(db.root().join("other.txt"), "0")?;
+        for i in 1..=5 {
+            write("kept/file.txt", &i.to_string())?;
+            write("other.txt", &i.to_string())?;
+        }
+
+        let versions = db.versions("kept/file.txt")?;
+        let contents = versions
+            .iter()
+            .map(|v| db.read_version("kept/file.txt", &v.id))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(vec![b"2".to_vec(), b"3".to_vec(), b"4".to_vec()], contents);
+        assert!(versions.windows(2).all(|w| w[0].created <= w[1].created));
+        assert!(db.versions("other.txt")?.is_empty());
+
+        // gc must leave versions alone
+        db.gc();
+        assert_eq!(versions, db.versions("kept/file.txt")?);
+
+        db.restore_version("kept/file.txt", &versions[0].id)?;
+        assert_eq!("2", fs::read_to_string(db.root().join("kept/file.txt"))?);
+        let contents = db
+            .versions("kept/file.txt")?
+            .iter()
+            .map(|v| db.read_version("kept/file.txt", &v.id))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(vec![b"3".to_vec(), b"4".to_vec(), b"5".to_vec()], contents);
+
+        assert!(matches!(
+            db.restore_version("kept/file.txt", &versions[0].id),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(matches!(
+            db.read_version("kept/file.txt", "../other.txt"),
+            Err(Error::InvalidPath { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_watch() -> anyhow::Result<()> {
+        use crate::ChangeKind;
+
+        let test_client = TestClient::new("test_watch")?;
+        let db = &test_client.client;
+        fs::create_dir_all(db.root().join("data/sub"))?;
+        fs::write(db.root().join("data/a.txt"), "a")?;
+        fs::write(db.root().join("data/sub/b.txt"), "b")?;
+        fs::write(db.root().join("other.txt"), "other")?;
+
+        let watcher = db.watch("data", true)?;
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || -> anyhow::Result<()> {
+                let pause = || thread::sleep(Duration::from_millis(200));
+                {
+                    let gaurd = db.write_file("data/a.txt")?;
+                    let cp = gaurd.cow()?;
+                    fs::write(&cp.path, "changed")?;
+                    cp.commit()?;
+                }
+                pause();
+                db.write_file("other.txt")?.cow()?.commit()?;
+                pause();
+                db.write_dir("data/sub")?.cow()?.commit()?;
+                pause();
+                db.write_dir("data")?.create_dir_atomic("atom", false)?;
+                pause();
+                db.write_dir("data/atom")?.cow_atomic()?.commit()?;
+                pause();
+                // the replaced directory is still watched
+                db.write_file("data/sub/b.txt")?.cow()?.commit()?;
+                Ok(())
+            })
+        };
+        writer.join().unwrap()?;
+
+        let mut events = Vec::new();
+        while let Ok(event) = watcher.events().recv_timeout(Duration::from_millis(500)) {
+            events.push(event);
+        }
+        watcher.stop();
+        assert_eq!(
+            vec![
+                (PathBuf::from("data/a.txt"), ChangeKind::Modified),
+                (PathBuf::from("data/sub"), ChangeKind::Committing),
+                (PathBuf::from("data/sub"), ChangeKind::DirCommitted),
+                (PathBuf::from("data/atom"), ChangeKind::DirCommitted),
+                // switched by a single rename
+                (PathBuf::from("data/atom"), ChangeKind::DirCommitted),
+                (PathBuf::from("data/sub/b.txt"), ChangeKind::Modified),
+            ],
+            events
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_incremental() -> anyhow::Result<()> {
+        use crate::BackupState;
+
+        let test_client = TestClient::new("test_backup_incremental")?;
+        let db = &test_client.client;
+        let dest = std::env::temp_dir().join(format!("test_backup_incremental-dest-{}", puuid()));
+        let state_path = dest.with_extension("state");
+
+        fs::create_dir_all(db.root().join("sub"))?;
+        fs::write(db.root().join("a.txt"), "a")?;
+        fs::write(db.root().join("sub/b.txt"), "b")?;
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        fs::write(db.root().join("atom/c.txt"), "c")?;
+
+        let mut state = BackupState::load(&state_path)?;
+        let summary = db.backup_incremental(&dest, &mut state)?;
+        state.save(&state_path)?;
+        assert_eq!(
+            (3, 3, 0),
+            (
+                summary.files_copied,
+                summary.bytes_copied,
+                summary.files_deleted
+            )
+        );
+        assert!(!fs::symlink_metadata(dest.join("atom"))?.is_symlink());
+        assert_eq!(tree_contents(db.root())?, tree_contents(&dest)?);
+
+        {
+            let gaurd = db.write_file("a.txt")?;
+            let cp = gaurd.cow()?;
+            fs::write(&cp.path, "changed")?;
+            cp.commit()?;
+        }
+        fs::remove_file(db.root().join("sub/b.txt"))?;
+        fs::write(db.root().join("sub/new.txt"), "new")?;
+
+        let mut state = BackupState::load(&state_path)?;
+        let summary = db.backup_incremental(&dest, &mut state)?;
+        assert_eq!(2, summary.files_copied);
+        assert_eq!(10, summary.bytes_copied);
+        assert_eq!(1, summary.files_deleted);
+        assert_eq!(1, summary.files_unchanged);
+        assert_eq!(tree_contents(db.root())?, tree_contents(&dest)?);
+
+        fs::remove_dir_all(db.root().join("sub"))?;
+        let summary = db.backup_incremental(&dest, &mut state)?;
+        assert_eq!((0, 1), (summary.files_copied, summary.files_deleted));
+        assert!(!dest.join("sub").exists());
+
+        assert!(matches!(
+            db.backup_incremental(&db.root().join("backup"), &mut state),
+            Err(Error::InvalidPath { .. })
+        ));
+        assert!(!db.root().join("backup").exists());
+
+        fs::remove_dir_all(&dest)?;
+        fs::remove_file(&state_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> anyhow::Result<()> {
+        use crate::{
+            Issue, IssueKind, VerifyOptions,
+            fixture::{dangling_atomic_dir, orphaned_temp},
+        };
+
+        let test_client = TestClient::new("test_verify")?;
+        let db = &test_client.client;
+        let root = db.root();
+
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("sub/a.txt"), "a")?;
+        db.write_file("sub/a.txt")?.cow()?.commit()?;
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        assert_eq!(
+            Vec::<Issue>::new(),
+            db.verify("", &VerifyOptions::default())?.issues
+        );
+
+        dangling_atomic_dir(root.join("broken"))?;
+        let ghost = format!(".ghost.{}.dir.sbdb", puuid());
+        fs::create_dir(root.join(&ghost))?;
+        orphaned_temp(root.join("sub/a.txt"), "tmp")?;
+        let backup = format!("sub/.a.txt.{}.bak.sbdb", puuid());
+        fs::create_dir(root.join(&backup))?;
+        let lost = format!(".gone.{}.bak.sbdb", puuid());
+        fs::create_dir(root.join(&lost))?;
+        fs::write(root.join("sub/.missing.lock.sbdb"), "")?;
+        fs::write(root.join("notes.sbdb"), "")?;
+        fs::create_dir(root.join(".x.lock.sbdb"))?;
+
+        let report = db.verify("", &VerifyOptions::default())?;
+        let mut issues = report.issues.clone();
+        issues.sort_by(|a, b| a.path.cmp(&b.path));
+        let issue = |path: &str, kind, repairable| Issue {
+            path: PathBuf::from(path),
+            kind,
+            repairable,
+        };
+        let mut expected = vec![
+            issue("broken", IssueKind::DanglingAtomicDir, false),
+            issue(&ghost, IssueKind::OrphanedPayload, true),
+            issue("sub/.a.txt.tmp.sbdb", IssueKind::LeftoverTemp, true),
+            issue(
+                &backup,
+                IssueKind::LeftoverBackup {
+                    original_exists: true,
+                },
+                true,
+            ),
+            issue(
+                &lost,
+                IssueKind::LeftoverBackup {
+                    original_exists: false,
+                },
+                false,
+            ),
+            issue("sub/.missing.lock.sbdb", IssueKind::OrphanedLock, true),
+            issue("notes.sbdb", IssueKind::SuffixCollision, false),
+            issue(".x.lock.sbdb", IssueKind::SuffixCollision, false),
+        ];
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(expected, issues);
+        assert!(report.needs_attention());
+
+        // recent artifacts may belong to operations in progress
+        let recent = db.verify(
+            "",
+            &VerifyOptions {
+                min_age: Duration::from_secs(3600),
+            },
+        )?;
+        assert!(recent.issues.iter().all(|issue| !issue.repairable));
+
+        assert_eq!(
+            1,
+            db.verify(
+                "sub",
+                &VerifyOptions {
+                    min_age: Duration::ZERO
+                }
+            )?
+            .issues
+            .iter()
+            .filter(|issue| issue.kind == IssueKind::LeftoverTemp)
+            .count()
+        );
+
+        #[cfg(feature = "serde_json")]
+        {
+            let json = serde_json::to_value(&report)?;
+            assert!(
+                json["issues"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(
+                        |issue| issue["kind"] == "suffix_collision" && issue["repairable"] == false
+                    )
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generations() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_generations")?;
+        let db = Client::builder(test_client.client.root())
+            .track_generations()
+            .build()?;
+
+        fs::create_dir_all(db.root().join("a/b/c"))?;
+        fs::create_dir_all(db.root().join("x"))?;
+        fs::write(db.root().join("a/b/c/file.txt"), "1")?;
+        fs::write(db.root().join("x/file.txt"), "1")?;
+        let seen = |rpaths: &[&str]| -> anyhow::Result<Vec<u64>> {
+            Ok(rpaths
+                .iter()
+                .map(|rpath| db.subtree_generation(rpath))
+                .collect::<crate::Result<_>>()?)
+        };
+        let rpaths = ["", "a", "a/b/c", "a/b/c/file.txt", "x", "x/file.txt"];
+        // entries without a counter of their own report changes of their parent
+        db.write_file("a/b/c/file.txt")?.cow()?.commit()?;
+        db.write_file("x/file.txt")?.cow()?.commit()?;
+
+        let before = seen(&rpaths)?;
+        {
+            let gaurd = db.write_file("a/b/c/file.txt")?;
+            let cp = gaurd.cow()?;
+            fs::write(&cp.path, "2")?;
+            cp.commit()?;
+        }
+        let changed = rpaths
+            .iter()
+            .zip(&before)
+            .map(|(rpath, last)| db.changed_since(rpath, *last))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(vec![true, true, true, true, false, false], changed);
+
+        // nothing committed, nothing changed
+        let before = seen(&rpaths)?;
+        assert_eq!(before, seen(&rpaths)?);
+
+        // counters are not copied, entries replaced by a dir commit fall back to the committed dir
+        {
+            let gaurd = db.write_dir("a")?;
+            let cp = gaurd.cow()?;
+            fs::write(cp.path.join("b/c/file.txt"), "3")?;
+            cp.commit()?;
+        }
+        assert!(db.changed_since("a/b/c/file.txt", before[3])?);
+        assert!(db.changed_since("", before[0])?);
+        assert!(!db.changed_since("x/file.txt", before[5])?);
+
+        // counters of entries that no longer exist are garbage collected
+        db.write_file("x/file.txt")?.cow()?.commit()?;
+        fs::remove_file(db.root().join("x/file.txt"))?;
+        let report = db.gc_with(&GcOptions {
+            min_age: Duration::ZERO,
+            ..Default::default()
+        });
+        assert_eq!(1, report.generations_removed);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn test_audit_log() -> anyhow::Result<()> {
+        use crate::{AuditOp, AuditOptions, CommitKind};
+
+        let test_client = TestClient::new("test_audit_log")?;
+        let db = Client::builder(test_client.client.root())
+            .audit(AuditOptions {
+                tag: "tester".to_string(),
+                rotate_len: 256,
+                ..Default::default()
+            })
+            .build()?;
+
+        db.write_json("a.json", &1)?;
+        db.write_json("a.json", &2)?;
+        fs::create_dir_all(db.root().join("dir"))?;
+        db.write_dir("dir")?.create_dir_atomic("atomic", false)?;
+        db.write_dir("dir/atomic")?.cow_atomic()?.commit()?;
+        db.write_dir("dir")?.cow()?.commit()?;
+        db.write_file("a.json")?.cow()?.commit()?;
+
+        let records = db.read_audit(..)?.collect::<Vec<_>>();
+        assert!(records.iter().all(|r| r.tag == "tester"));
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+        assert_eq!(
+            vec![
+                (AuditOp::Create, CommitKind::File, PathBuf::from("a.json")),
+                (AuditOp::Replace, CommitKind::File, PathBuf::from("a.json")),
+                (
+                    AuditOp::Create,
+                    CommitKind::AtomicDir,
+                    PathBuf::from("dir/atomic")
+                ),
+                (
+                    AuditOp::Replace,
+                    CommitKind::AtomicDir,
+                    PathBuf::from("dir/atomic")
+                ),
+                (AuditOp::Replace, CommitKind::Dir, PathBuf::from("dir")),
+                (AuditOp::Replace, CommitKind::File, PathBuf::from("a.json")),
+            ],
+            records
+                .into_iter()
+                .map(|r| (r.op, r.kind, r.path))
+                .collect::<Vec<_>>()
+        );
+        let rotated = fs::read_dir(db.root().join(".sbdb"))?
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("audit.") && name != "audit.log" && name.ends_with(".log")
+            })
+            .count();
+        assert!(rotated > 0);
+
+        // commits from many threads must neither deadlock nor lose records
+        thread::scope(|s| {
+            for t in 0..4 {
+                let db = &db;
+                s.spawn(move || {
+                    for i in 0..10 {
+                        db.write_json(format!("t{}.json", t), &i).unwrap();
+                    }
+                });
+            }
+        });
+        let records = db.read_audit(..)?.collect::<Vec<_>>();
+        assert_eq!(46, records.len());
+        for t in 0..4 {
+            let path = PathBuf::from(format!("t{}.json", t));
+            let ops = records
+                .iter()
+                .filter(|r| r.path == path)
+                .map(|r| r.op)
+                .collect::<Vec<_>>();
+            assert_eq!(10, ops.len());
+            assert_eq!(AuditOp::Create, ops[0]);
+            assert!(ops[1..].iter().all(|op| *op == AuditOp::Replace));
+        }
+
+        let later = std::time::SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(0, db.read_audit(later..)?.count());
+
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct Captured {
+        operation: Option<String>,
+        path: Option<String>,
+    }
+
+    static CAPTURED: Mutex<Vec<Captured>> = Mutex::new(Vec::new());
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let kvs = record.key_values();
+            CAPTURED.lock().unwrap().push(Captured {
+                operation: kvs.get("operation".into()).map(|v| v.to_string()),
+                path: kvs.get("path".into()).map(|v| v.to_string()),
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn install_capturing_logger() {
+        static ONCE: Once = Once::new();
+        ONCE.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+    }
+
+    // only linux, where a descriptor that can not be unlocked is easily made. Windows is not covered: its lock
+    // files are opened sharing deletion, so deleting one under a held guard leaves the handle, and the lock
+    // on it, in place, and there is no failure to provoke that way.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_unlock_failure_is_logged() -> anyhow::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        install_capturing_logger();
+        let test_client = TestClient::new("test_unlock_failure_is_logged")?;
+        let path = test_client.client.root().join("file.txt");
+        fs::write(&path, "")?;
+
+        // locking operations on an O_PATH descriptor fail with EBADF
+        let open_path = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH)
+                .open(&path)
+        };
+        let warnings = Arc::new(AtomicU64::new(0));
+        let on_warning: WarningCallback = {
+            let warnings = warnings.clone();
+            Arc::new(move |warning| {
+                assert!(matches!(warning, Warning::Unlock { .. }));
+                warnings.fetch_add(1, Ordering::Relaxed);
+            })
+        };
+
+        drop(ReadLock {
+            held: crate::Held::File {
+                lock: Arc::new(open_path()?),
+                cache: None,
+            },
+            path: path.clone(),
+            on_warning: Some(on_warning.clone()),
+            redact: crate::Redact::default(),
+            vfs: crate::vfs::std(),
+            released: false,
+            #[cfg(feature = "lock-order-check")]
+            owner: None,
+            revoked: None,
+            tracked: None,
+        });
+        assert_eq!(1, warnings.load(Ordering::Relaxed));
+
+        {
+            let expected = format!("{:?}", path);
+            let captured = CAPTURED.lock().unwrap();
+            assert!(captured.iter().any(|c| {
+                c.operation.as_deref() == Some("unlock") && c.path.as_deref() == Some(&expected)
+            }));
+        }
+
+        // an explicit release reports the failure to the caller and does not warn again on drop
+        let lock = WriteLock {
+            held: crate::Held::File {
+                lock: Arc::new(open_path()?),
+                cache: None,
+            },
+            path: path.clone(),
+            on_warning: Some(on_warning),
+            redact: crate::Redact::default(),
+            vfs: crate::vfs::std(),
+            released: false,
+            #[cfg(feature = "lock-order-check")]
+            owner: None,
+            tracked: None,
+            alive: Arc::new(()),
+        };
+        let err = lock.release().err().context("release succeeded")?;
+        assert!(matches!(err, Error::Io { path: p, .. } if p == path));
+        assert_eq!(1, warnings.load(Ordering::Relaxed));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "testkit")]
+    #[test]
+    fn test_no_space() -> anyhow::Result<()> {
+        use std::io::ErrorKind;
+
+        use crate::{
+            IssueKind, VerifyOptions,
+            fixture::TreeBuilder,
+            testkit::{FaultVfs, VfsOp},
+        };
+
+        let root = std::env::temp_dir().join("test_no_space-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        let db = Client::builder(&root).vfs(vfs.clone()).build()?;
+        let test_client = TestClient {
+            client: db.clone(),
+            root,
+        };
+        // copies files in chunks
+        let chunked = Client::builder(db.root())
+            .vfs(vfs.clone())
+            .io_deadline(Duration::from_secs(600))
+            .build()?;
+        fs::create_dir(db.root().join("dir"))?;
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(db.root().join("dir").join(name), "old")?;
+        }
+        fs::write(db.root().join("file.txt"), "old")?;
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        fs::write(db.root().join("atom/file.txt"), "old")?;
+        let before = TreeBuilder::read(db.root())?;
+
+        // the disk fills up at every step that writes or renames, which changes nothing and leaves
+        // nothing behind
+        let no_space = |err: &Error| match err {
+            Error::CommitFailed {
+                backup: None,
+                source,
+            } => matches!(**source, Error::NoSpace { .. }),
+            err => matches!(err, Error::NoSpace { .. }),
+        };
+        let check = |err: Error| -> anyhow::Result<()> {
+            assert!(no_space(&err), "{err:?}");
+            assert_eq!(before, TreeBuilder::read(db.root())?, "{err:?}");
+            let report = db.verify("", &VerifyOptions::default())?;
+            let leftovers: Vec<_> = report
+                .issues
+                .iter()
+                .filter(|issue| issue.kind != IssueKind::OrphanedLock)
+                .collect();
+            assert!(leftovers.is_empty(), "{err:?}: {leftovers:?}");
+            Ok(())
+        };
+        let fail =
+            |op: VfsOp, nth: usize| vfs.fail(op, vfs.calls(op) + nth, ErrorKind::StorageFull);
+
+        for client in [&db, &chunked] {
+            let op = match client.inner.io_deadline {
+                Some(_) => VfsOp::CopyChunk,
+                None => VfsOp::Copy,
+            };
+            // copies
+            fail(op, 1);
+            let err = client.write_file("file.txt")?.cow().err();
+            check(err.context("copied")?)?;
+            fail(op, 2);
+            let err = client.write_dir("dir")?.cow().err();
+            check(err.context("copied")?)?;
+            fail(op, 1);
+            let err = client.write_dir("atom")?.cow_atomic().err();
+            check(err.context("copied")?)?;
+        }
+        fail(VfsOp::CreateDirAll, 1);
+        let err = db.write_dir("missing")?.cow().err();
+        check(err.context("created")?)?;
+
+        // commits
+        fail(VfsOp::Rename, 1);
+        check(
+            db.write_bytes("file.txt", b"new")
+                .err()
+                .context("written")?,
+        )?;
+        let err = {
+            let gaurd = db.write_file("file.txt")?;
+            let cp = gaurd.cow()?;
+            fs::write(&cp.path, "new")?;
+            fail(VfsOp::Rename, 1);
+            cp.commit().err()
+        };
+        check(err.context("committed")?)?;
+        for nth in [1, 2] {
+            let err = {
+                let gaurd = db.write_dir("dir")?;
+                let cp = gaurd.cow()?;
+                fs::write(cp.path.join("a.txt"), "new")?;
+                fail(VfsOp::Rename, nth);
+                cp.commit().err()
+            };
+            check(err.context("committed")?)?;
+        }
+        for op in [VfsOp::SymlinkDir, VfsOp::Rename] {
+            let err = {
+                let gaurd = db.write_dir("atom")?;
+                let cp = gaurd.cow_atomic()?;
+                fs::write(cp.path.join("file.txt"), "new")?;
+                fail(op, 1);
+                cp.commit().err()
+            };
+            check(err.context("committed")?)?;
+        }
+        let report = db.gc();
+        assert_eq!((0, 0), (report.temps_removed, report.payloads_removed));
+
+        // the space is checked up front, which takes the size of a sparse file at its word
+        #[cfg(target_os = "linux")]
+        {
+            let db = Client::builder(db.root()).preflight_space(true).build()?;
+            const LEN: u64 = 1 << 40;
+            File::create(db.root().join("huge"))?.set_len(LEN)?;
+            let available = crate::quota::available_space(db.root()).context("free space")?;
+            if available < LEN {
+                let err = db.write_file("huge")?.cow().err().context("copied")?;
+                assert!(
+                    matches!(
+                        err,
+                        Error::NoSpace {
+                            needed: Some(LEN),
+                            available: Some(_),
+                            ..
+                        }
+                    ),
+                    "{err:?}"
+                );
+                assert!(!db.root().join(".huge.tmp.sbdb").exists());
+            }
+            fs::remove_file(db.root().join("huge"))?;
+            db.write_bytes("file.txt", b"new")?;
+            assert_eq!("new", fs::read_to_string(db.root().join("file.txt"))?);
+        }
+
+        drop(test_client);
+        Ok(())
+    }
+
+    #[cfg(feature = "testkit")]
+    #[test]
+    fn test_injected_failures() -> anyhow::Result<()> {
+        use std::io::ErrorKind;
+
+        use crate::{
+            IssueKind, VerifyOptions,
+            fixture::TreeBuilder,
+            testkit::{FaultVfs, VfsOp},
+        };
+
+        let root = std::env::temp_dir().join("test_injected_failures-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        let warnings = Arc::new(AtomicU64::new(0));
+        let db = {
+            let warnings = warnings.clone();
+            Client::builder(&root)
+                .vfs(vfs.clone())
+                .on_warning(move |warning| {
+                    assert!(matches!(warning, Warning::Unlock { .. }));
+                    warnings.fetch_add(1, Ordering::Relaxed);
+                })
+                .build()?
+        };
+        let test_client = TestClient {
+            client: db.clone(),
+            root,
+        };
+        fs::create_dir(db.root().join("dir"))?;
+        fs::write(db.root().join("dir/file.txt"), "old")?;
+        fs::write(db.root().join("file.txt"), "old")?;
+
+        // a failed copy leaves the original alone
+        {
+            let gaurd = db.write_file("file.txt")?;
+            vfs.fail(
+                VfsOp::Copy,
+                vfs.calls(VfsOp::Copy) + 1,
+                ErrorKind::StorageFull,
+            );
+            assert!(matches!(gaurd.cow(), Err(Error::NoSpace { .. })));
+        }
+
+        // a file commit that can not rename, e.g. because the copy ended up on another device
+        {
+            let gaurd = db.write_file("file.txt")?;
+            let cp = gaurd.cow()?;
+            fs::write(&cp.path, "new")?;
+            vfs.fail(
+                VfsOp::Rename,
+                vfs.calls(VfsOp::Rename) + 1,
+                ErrorKind::CrossesDevices,
+            );
+            assert!(cp.commit().is_err());
+            assert_eq!("old", fs::read_to_string(db.root().join("file.txt"))?);
+        }
+
+        // the second rename of a directory commit fails, the backup is moved back
+        {
+            let gaurd = db.write_dir("dir")?;
+            let cp = gaurd.cow()?;
+            fs::write(cp.path.join("file.txt"), "new")?;
+            vfs.fail(
+                VfsOp::Rename,
+                vfs.calls(VfsOp::Rename) + 2,
+                ErrorKind::Other,
+            );
+            let err = cp.commit().err().context("commit succeeded")?;
+            assert!(matches!(err, Error::CommitFailed { backup: None, .. }));
+            assert_eq!("old", fs::read_to_string(db.root().join("dir/file.txt"))?);
+        }
+        // failed commits leave their copies to gc
+        assert_eq!(2, db.gc().temps_removed);
+
+        // moving the backup back fails as well, which leaves the directory missing
+        let backup = {
+            let gaurd = db.write_dir("dir")?;
+            let cp = gaurd.cow()?;
+            let renames = vfs.calls(VfsOp::Rename);
+            vfs.fail(VfsOp::Rename, renames + 2, ErrorKind::Other);
+            vfs.fail(VfsOp::Rename, renames + 3, ErrorKind::Other);
+            match cp.commit() {
+                Err(Error::CommitFailed {
+                    backup: Some(backup),
+                    ..
+                }) => backup,
+                result => anyhow::bail!("unexpected result: {:?}", result),
+            }
+        };
+        assert!(!db.root().join("dir").exists());
+        assert_eq!("old", fs::read_to_string(backup.join("file.txt"))?);
+        let report = db.verify("", &VerifyOptions::default())?;
+        assert!(report.issues.iter().any(|issue| issue.kind
+            == IssueKind::LeftoverBackup {
+                original_exists: false
+            }));
+        assert!(report.needs_attention());
+        assert_eq!(0, db.gc().backups_removed);
+        assert!(backup.exists());
+
+        // converting a directory or switching an atomic one fails at any step, which changes nothing
+        fs::create_dir(db.root().join("plain"))?;
+        fs::write(db.root().join("plain/file.txt"), "old")?;
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        fs::write(db.root().join("atom/file.txt"), "old")?;
+        let before = TreeBuilder::read(db.root())?;
+        let failures = [
+            ("plain", VfsOp::SymlinkDir, 1, false),
+            ("plain", VfsOp::Rename, 1, false),
+            // the plain directory is moved back
+            ("plain", VfsOp::Rename, 2, true),
+            ("atom", VfsOp::SymlinkDir, 1, false),
+            ("atom", VfsOp::Rename, 1, true),
+        ];
+        for (rpath, op, nth, switching) in failures {
+            let gaurd = db.write_dir(rpath)?;
+            let cp = gaurd.cow_atomic()?;
+            fs::write(cp.path.join("file.txt"), "new")?;
+            vfs.fail(op, vfs.calls(op) + nth, ErrorKind::Other);
+            let err = cp.commit().err().context("commit succeeded")?;
+            assert_eq!(
+                switching,
+                matches!(err, Error::CommitFailed { backup: None, .. }),
+                "{err:?}"
+            );
+            assert_eq!(before, TreeBuilder::read(db.root())?, "{err:?}");
+            let tmp_link = db.root().join(format!(".{rpath}.tmplnk.sbdb"));
+            assert!(fs::symlink_metadata(tmp_link).is_err());
+        }
+        // only the copies are left, to gc
+        let report = db.gc();
+        assert_eq!(
+            (0, failures.len()),
+            (report.temps_removed, report.payloads_removed)
+        );
+
+        // unlocking fails when a guard is dropped
+        {
+            let _gaurd = db.read_file("file.txt")?;
+            vfs.fail(
+                VfsOp::Unlock,
+                vfs.calls(VfsOp::Unlock) + 1,
+                ErrorKind::Other,
+            );
+        }
+        assert_eq!(1, warnings.load(Ordering::Relaxed));
+        // closing the lock file released the lock anyway
+        db.write_file("file.txt")?.release()?;
+
+        drop(test_client);
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_locks() -> anyhow::Result<()> {
+        use std::sync::mpsc;
+
+        use crate::{Lock, LockStatus};
+
+        let parent = std::env::temp_dir().join("test_root_locks-".to_string() + &puuid());
+        let root = parent.join("db");
+        let test_client = TestClient {
+            client: Client::new(&root)?,
+            root: parent.clone(),
+        };
+        let db = &test_client.client;
+        fs::create_dir(root.join("a"))?;
+        fs::write(root.join("a/b"), "old")?;
+        let siblings = || -> anyhow::Result<Vec<OsString>> {
+            Ok(fs::read_dir(&parent)?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<std::io::Result<_>>()?)
+        };
+
+        // a write lock on the root keeps out a nested write
+        let tx = db.tx().write("").begin()?;
+        assert_eq!(LockStatus::Write, Lock::probe(&root)?);
+        let (send, recv) = mpsc::channel();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let write = || -> crate::Result<()> {
+                    let cow = db.write_file("a/b")?.cow()?;
+                    fs::write(&cow.path, "new").unwrap();
+                    cow.commit()
+                };
+                let _ = send.send(write());
+            })
+        };
+        assert!(recv.recv_timeout(Duration::from_millis(100)).is_err());
+        let cow = tx.dir_cow("")?;
+        fs::write(cow.path.join("a/b"), "tx")?;
+        cow.commit()?;
+        drop(tx);
+        recv.recv_timeout(Duration::from_secs(10))??;
+        writer.join().unwrap();
+        assert_eq!("new", fs::read_to_string(root.join("a/b"))?);
+
+        // and a read lock is shared
+        let tx = db.tx().read("").begin()?;
+        assert_eq!(LockStatus::Read, Lock::probe(&root)?);
+        drop(db.read_dir("")?);
+        drop(tx);
+        assert_eq!(LockStatus::Free, Lock::probe(&root)?);
+
+        // nothing is ever left next to the root
+        {
+            let gaurd = db.write_dir("")?;
+            gaurd.cow()?.commit()?;
+        }
+        db.read_file("a/b")?;
+        db.write_file("a/b")?.cow()?.commit()?;
+        db.gc();
+        db.recover();
+        db.verify("", &Default::default())?;
+        assert_eq!(vec![OsString::from("db")], siblings()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_and_list() -> anyhow::Result<()> {
+        use crate::{Lock, LockStatus};
+
+        let test_client = TestClient::new("test_probe_and_list")?;
+        let db = &test_client.client;
+        let path = db.root().join("file.txt");
+        assert_eq!(LockStatus::Free, Lock::probe(&path)?);
+        db.write_bytes("file.txt", b"contents")?;
+        assert_eq!("contents", fs::read_to_string(&path)?);
+        fs::create_dir(db.root().join("dir"))?;
+
+        {
+            let _gaurd = db.read_file("file.txt")?;
+            assert_eq!(LockStatus::Read, Lock::probe(&path)?);
+        }
+        {
+            let _gaurd = db.write_file("file.txt")?;
+            assert_eq!(LockStatus::Write, Lock::probe(&path)?);
+            // the root is only read locked
+            assert_eq!(LockStatus::Read, Lock::probe(db.root())?);
+        }
+        assert_eq!(LockStatus::Free, Lock::probe(&path)?);
+
+        // lock files and the copy are left out
+        let _cp = db.write_dir("dir")?.cow()?;
+        assert_eq!(
+            vec![OsString::from("dir"), OsString::from("file.txt")],
+            db.list("")?
+        );
+        assert!(db.list("dir")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explicit_release() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_explicit_release")?;
+        let db = &test_client.client;
+        fs::write(db.root().join("file.txt"), "")?;
+
+        // each acquisition below would deadlock if the previous guard still held its lock
+        let read = db.read_file("file.txt")?;
+        read.release()?;
+        let write = db.write_file("file.txt")?;
+        write.release()?;
+        let tx = db.tx().write("file.txt").begin()?;
+        tx.release()?;
+        db.write_dir("")?.release()?;
+        db.read_dir("")?.release()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> anyhow::Result<()> {
+        let metrics = Arc::new(AtomicMetrics::default());
+        let test_client = TestClient::new("test_metrics")?;
+        let db = Client::builder(test_client.client.root())
+            .metrics(metrics.clone())
+            .build()?;
+
+        {
+            let gaurd = db.write_file("file.txt")?;
+            fs::write(&gaurd.path, "12345")?;
+            let cp = gaurd.cow()?;
+            cp.commit()?;
+        }
+
+        {
+            let _gaurd = db.read_file("file.txt")?;
+        }
+
+        {
+            let gaurd = db.write_dir("")?;
+            let cp = gaurd.cow()?;
+            cp.commit()?;
+        }
+
+        db.gc();
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        // the root plus file.txt for the read, and a read lock per dir scanned by gc, which finds nothing to
+        // write lock it for
+        assert_eq!(4, load(&metrics.read_locks));
+        assert_eq!(2, load(&metrics.write_locks));
+        // the dir copy also includes the empty lock and queue files of file.txt and of the root, the root
+        // marker and the meta file
+        assert_eq!(
+            8,
+            load(&metrics.files_copied) + load(&metrics.files_reflinked)
+        );
+        let meta_len = fs::metadata(db.root().join(".sbdb/meta.json"))?.len();
+        assert_eq!(
+            10 + meta_len,
+            load(&metrics.bytes_copied) + load(&metrics.bytes_reflinked)
+        );
+        assert_eq!(1, load(&metrics.file_commits));
+        assert_eq!(1, load(&metrics.dir_commits));
+        assert_eq!(0, load(&metrics.atomic_dir_commits));
+        assert_eq!(1, load(&metrics.gc_runs));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_histogram_metrics() -> anyhow::Result<()> {
+        use crate::{HistogramMetrics, LockMode, MetricsSnapshot};
+
+        let metrics = Arc::new(HistogramMetrics::new());
+        let test_client = TestClient::new("test_histogram_metrics")?;
+        let db = Client::builder(test_client.client.root())
+            .metrics(metrics.clone())
+            .build()?;
+        db.write_dir("")?.create_dir("dir")?;
+        let count = |snapshot: &MetricsSnapshot, mode: LockMode, depth: &str| {
+            snapshot
+                .lock_waits
+                .iter()
+                .find(|h| h.mode == mode && h.depth == depth)
+                .map_or(0, |h| h.count)
+        };
+        let before = metrics.snapshot();
+
+        // every write takes read locks on the root and the directory and a write lock on the file, for which
+        // the threads wait on each other
+        thread::scope(|s| {
+            let writers = (0..4)
+                .map(|_| {
+                    s.spawn(|| -> crate::Result<()> {
+                        for _ in 0..25 {
+                            let gaurd = db.write_file("dir/file")?;
+                            thread::sleep(Duration::from_micros(100));
+                            gaurd.release()?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            writers
+                .into_iter()
+                .try_for_each(|writer| writer.join().unwrap())
+        })?;
+
+        let after = metrics.snapshot();
+        let added = |mode, depth| count(&after, mode, depth) - count(&before, mode, depth);
+        assert_eq!(100, added(LockMode::Read, "0"));
+        assert_eq!(100, added(LockMode::Read, "1"));
+        assert_eq!(100, added(LockMode::Write, "2"));
+        assert_eq!(0, added(LockMode::Write, "1"));
+        assert_eq!(10, after.lock_waits.len());
+        for histogram in after.lock_waits.iter() {
+            let counts = histogram
+                .buckets
+                .iter()
+                .map(|b| b.count)
+                .collect::<Vec<_>>();
+            assert!(counts.is_sorted(), "{counts:?}");
+            assert!(counts.last().unwrap() <= &histogram.count);
+        }
+        let waited = after
+            .lock_waits
+            .iter()
+            .find(|h| h.mode == LockMode::Write && h.depth == "2")
+            .unwrap();
+        assert!(waited.sum_seconds > 0.0);
+
+        let rendered = metrics.render_prometheus();
+        assert!(rendered.contains("# TYPE sbdb_lock_wait_seconds histogram\n"));
+        assert!(rendered.contains(&format!(
+            "sbdb_lock_wait_seconds_count{{mode=\"write\",depth=\"2\"}} {}\n",
+            waited.count
+        )));
+        assert!(rendered.contains(
+            "sbdb_lock_wait_seconds_bucket{mode=\"read\",depth=\"4+\",le=\"0.000001\"} 0\n"
+        ));
+        // paths are never labels
+        assert!(!rendered.contains("file"));
+
+        // locks taken below a guard are as deep as the same locks taken from the root
+        let before = metrics.snapshot();
+        let dir = db.write_dir("dir")?;
+        dir.write_file("file")?.release()?;
+        dir.release()?;
+        let after = metrics.snapshot();
+        let added = |mode, depth| count(&after, mode, depth) - count(&before, mode, depth);
+        assert_eq!(1, added(LockMode::Write, "1"));
+        assert_eq!(1, added(LockMode::Write, "2"));
+        assert_eq!(0, added(LockMode::Write, "0"));
+        Ok(())
+    }
+
+    // (span or event name, name of its parent span)
+    #[cfg(feature = "tracing")]
+    type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanRecorder {
+        seen: Seen,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            _attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|p| p.name().to_string());
+            self.seen
+                .lock()
+                .unwrap()
+                .push((span.name().to_string(), parent));
+        }
+
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let parent = ctx.event_span(event).map(|p| p.name().to_string());
+            self.seen
+                .lock()
+                .unwrap()
+                .push((event.metadata().name().to_string(), parent));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_tracing_spans() -> anyhow::Result<()> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let test_client = TestClient::new("test_tracing_spans")?;
+        let db = &test_client.client;
+        fs::write(db.root().join("data.txt"), "0")?;
+
+        let recorder = SpanRecorder::default();
+        let seen = recorder.seen.clone();
+        let subscriber = tracing_subscriber::registry().with(recorder);
+        tracing::subscriber::with_default(subscriber, || -> anyhow::Result<()> {
+            let tx = db.tx().read("data.txt").write("data.txt").begin()?;
+            let cp = tx.file_cow("data.txt")?;
+            fs::write(&cp.path, "1")?;
+            cp.commit()?;
+            Ok(())
+        })?;
+
+        let seen = seen.lock().unwrap();
+        let tx = Some("sbdb.tx".to_string());
+        assert!(seen.contains(&("sbdb.tx".to_string(), None)));
+        assert!(seen.contains(&("sbdb.copy".to_string(), tx.clone())));
+        assert!(seen.contains(&("sbdb.commit".to_string(), tx.clone())));
+        assert!(
+            seen.iter()
+                .any(|(name, parent)| name.starts_with("event ") && *parent == tx)
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_tx_operations_atomic_cow() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_tx_operations_atomic_cow")?;
+        let db = &test_client.client;
+
+        {
+            let gaurd = db.write_dir("")?;
+            let cp = gaurd.cow()?;
+            let nested = cp.path.join("nested");
+            let read = nested.join("read.txt");
+            let writes = nested.join("writes");
+            let write1 = writes.join("write1.txt");
+            let write2 = writes.join("write2.txt");
+            fs::create_dir_all(&nested)?;
+            File::create(&read)?;
+            fs::create_dir(&writes)?;
+            File::create(&write1)?;
+            File::create(&write2)?;
+            fs::write(&read, "1")?;
+            fs::write(&write1, "0")?;
+            fs::write(&write2, "0")?;
+            cp.commit()?;
+        }
+
+        {
+            let tx = db
+                .tx()
+                .read("nested/read.txt")
+                .write("nested/writes/write1.txt")
+                .write("nested/writes/write2.txt")
+                .write("nested/writes") // purposefully add more write protection than neccessary
+                .begin()?;
+            let options = AtomicCowOptions {
+                convert_if_plain: true,
+            };
+            let cp = tx.dir_cow_atomic_with("nested/writes", &options)?;
+            let write1 = cp.path.join("write1.txt");
+            let write2 = cp.path.join("write2.txt");
+
+            let n = fs::read_to_string(db.root().join("nested/read.txt"))?
+                .trim()
+                .parse::<i64>()?;
+
+            fs::write(write1, (n + 1).to_string())?;
+            fs::write(write2, (n + 2).to_string())?;
+
+            cp.commit()?;
+        }
+
+        {
+            let gaurd = db.read_file("nested/writes/write1.txt")?;
+            let n = fs::read_to_string(gaurd.path)?.trim().parse::<i64>()?;
+            assert_eq!(2, n);
+        }
+
+        {
+            let gaurd = db.read_file("nested/writes/write2.txt")?;
+            let n = fs::read_to_string(gaurd.path)?.trim().parse::<i64>()?;
+            assert_eq!(3, n);
+        }
+        assert!(db.root().join("nested/writes").is_symlink());
+        let report = db.verify("", &crate::VerifyOptions::default())?;
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_atomic_cow_plain_dir() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_tx_atomic_cow_plain_dir")?;
+        let db = &test_client.client;
+        fs::create_dir_all(db.root().join("plain"))?;
+        fs::write(db.root().join("plain/file.txt"), "0")?;
+
+        // a plain directory is neither copied nor converted by default
+        let tx = db.tx().write("plain").begin()?;
+        let Err(err) = tx.dir_cow_atomic("plain") else {
+            panic!("plain directory was copied");
+        };
+        assert!(
+            matches!(&err, Error::NotAtomicDir { path } if path == &db.root().join("plain")),
+            "{err}"
+        );
+        tx.release()?;
+        assert!(db.root().join("plain").is_dir() && !db.root().join("plain").is_symlink());
+        let report = db.verify("", &crate::VerifyOptions::default())?;
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+
+        // nothing there at all still starts an empty atomic directory
+        let tx = db.tx().write("new").begin()?;
+        tx.dir_cow_atomic("new")?.commit()?;
+        tx.release()?;
+        assert!(db.root().join("new").is_symlink());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_file_cache() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_lock_file_cache")?;
+        let db = Client::builder(test_client.client.root())
+            .lock_file_cache(16)
+            .build()?;
+        let cache = db.inner.lock_cache.clone().context("cache disabled")?;
+        fs::create_dir_all(db.root().join("dir"))?;
+        fs::write(db.root().join("dir/counter"), "0")?;
+
+        // threads share the cached handles, but never while one of them holds the lock
+        let threads = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || -> crate::Result<()> {
+                    for _ in 0..25 {
+                        let gaurd = db.write_file("dir/counter")?;
+                        let value: u64 = fs::read_to_string(&gaurd.path).unwrap().parse().unwrap();
+                        thread::sleep(Duration::from_micros(100));
+                        fs::write(&gaurd.path, (value + 1).to_string()).unwrap();
+                        gaurd.release()?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        assert_eq!("200", fs::read_to_string(db.root().join("dir/counter"))?);
+
+        let path = db.root().join("dir/counter");
+        let files = cache.take(&path).context("handles were not cached")?;
+        cache.put(&path, files);
+
+        // a lock file deleted behind the client's back, as gc in another process would, is not reused
+        fs::remove_file(db.root().join("dir/.counter.lock.sbdb"))?;
+        assert!(cache.take(&path).is_none());
+        db.read_file("dir/counter")?.release()?;
+        assert!(cache.take(&path).is_some());
+
+        // nor are those of entries below a committed directory
+        db.read_file("dir/counter")?.release()?;
+        db.write_dir("dir")?.cow()?.commit()?;
+        assert!(cache.take(&path).is_none());
+        assert!(cache.take(&db.root().join("dir")).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_guards() -> anyhow::Result<()> {
+        use std::{path::Path, sync::mpsc};
+
+        use crate::{LockMode, Metrics};
+
+        /// Records which entries were locked.
+        #[derive(Default)]
+        struct Locked(Mutex<Vec<(PathBuf, LockMode)>>);
+
+        impl Metrics for Locked {
+            fn lock_acquired(&self, path: &Path, mode: LockMode, _wait: Duration) {
+                self.0.lock().unwrap().push((path.to_path_buf(), mode));
+            }
+        }
+
+        let test_client = TestClient::new("test_nested_guards")?;
+        let locked = Arc::new(Locked::default());
+        let db = Client::builder(test_client.client.root())
+            .metrics(locked.clone())
+            .build()?;
+        let root = db.root().clone();
+        fs::create_dir_all(root.join("jobs/42"))?;
+        fs::write(root.join("jobs/42/status"), "queued")?;
+        let take_locked = || std::mem::take(&mut *locked.0.lock().unwrap());
+
+        let jobs = db.write_dir("jobs")?;
+        take_locked();
+        {
+            let status = jobs.read_file("42/status")?;
+            assert_eq!("queued", fs::read_to_string(&status.path)?);
+        }
+        assert_eq!(
+            vec![
+                (root.join("jobs/42"), LockMode::Read),
+                (root.join("jobs/42/status"), LockMode::Read),
+            ],
+            take_locked()
+        );
+        let status = jobs.write_file("42/status")?;
+        assert_eq!(
+            vec![
+                (root.join("jobs/42"), LockMode::Read),
+                (root.join("jobs/42/status"), LockMode::Write),
+            ],
+            take_locked()
+        );
+
+        // relative paths may not leave the guard's directory
+        for rel in ["", "../escape", "42/../../escape", "/abs"] {
+            assert!(matches!(
+                jobs.read_file(rel),
+                Err(Error::InvalidPath { .. })
+            ));
+        }
+
+        // outside writers are still excluded, until both guards are gone
+        let (sender, receiver) = mpsc::channel();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let gaurd = db.write_file("jobs/42/status").unwrap();
+                fs::write(&gaurd.path, "outside").unwrap();
+                sender.send(()).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        fs::write(&status.path, "running")?;
+        status.release()?;
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err());
+        jobs.release()?;
+        receiver.recv_timeout(Duration::from_secs(5))?;
+        writer.join().unwrap();
+        assert_eq!("outside", fs::read_to_string(root.join("jobs/42/status"))?);
+
+        // a directory read lock does not exclude writers of its entries, the nested read lock does
+        let jobs = db.read_dir("jobs")?;
+        let status = jobs.read_file("42/status")?;
+        let (sender, receiver) = mpsc::channel();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                drop(db.write_file("jobs/42/status").unwrap());
+                sender.send(()).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err());
+        status.release()?;
+        receiver.recv_timeout(Duration::from_secs(5))?;
+        writer.join().unwrap();
+        jobs.release()?;
+
+        // a transaction only locks what lies below its declared writes
+        let tx = db.tx().read("jobs/42/status").write("jobs/43").begin()?;
+        take_locked();
+        tx.read_file("jobs/42/status")?.release()?;
+        tx.write_file("jobs/43")?.release()?;
+        assert!(take_locked().is_empty());
+        fs::create_dir_all(root.join("jobs/43"))?;
+        tx.write_file("jobs/43/status")?.release()?;
+        assert_eq!(
+            vec![(root.join("jobs/43/status"), LockMode::Write)],
+            take_locked()
+        );
+        assert!(matches!(
+            tx.write_file("jobs/42/status"),
+            Err(Error::UndeclaredWrite { .. })
+        ));
+        assert!(matches!(
+            tx.read_file("jobs/44"),
+            Err(Error::UndeclaredRead { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "testkit")]
+    #[test]
+    fn test_tmpfile_fallback() -> anyhow::Result<()> {
+        use std::io::ErrorKind;
+
+        use crate::testkit::{FaultVfs, VfsOp};
+
+        let root = std::env::temp_dir().join("test_tmpfile_fallback-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        let db = Client::builder(&root).vfs(vfs.clone()).build()?;
+        let _cleanup = TestClient {
+            client: db.clone(),
+            root: root.clone(),
+        };
+        // everything but lock files and the internal directory
+        let entries = || -> anyhow::Result<Vec<OsString>> {
+            let mut names = Vec::new();
+            for entry in fs::read_dir(&root)? {
+                let name = entry?.file_name();
+                if name != crate::INTERNAL_DIR
+                    && !matches!(
+                        artifact::classify(&name),
+                        Some(ArtifactKind::Lock | ArtifactKind::Queue)
+                    )
+                {
+                    names.push(name);
+                }
+            }
+            names.sort();
+            Ok(names)
+        };
+
+        db.write_bytes("a.txt", b"1")?;
+        assert_eq!("1", fs::read_to_string(root.join("a.txt"))?);
+        assert_eq!(1, vfs.calls(VfsOp::OpenTmpfile));
+        let linked = vfs.calls(VfsOp::LinkTmpfile);
+        assert_eq!(usize::from(cfg!(target_os = "linux")), linked);
+
+        // filesystems without unnamed files fall back to a named temporary file
+        vfs.fail(VfsOp::OpenTmpfile, 2, ErrorKind::Unsupported);
+        db.write_bytes("a.txt", b"2")?;
+        assert_eq!("2", fs::read_to_string(root.join("a.txt"))?);
+        assert_eq!(linked, vfs.calls(VfsOp::LinkTmpfile));
+
+        // as does a failure to link it, e.g. without procfs
+        if cfg!(target_os = "linux") {
+            vfs.fail(VfsOp::LinkTmpfile, linked + 1, ErrorKind::NotFound);
+            db.write_bytes("a.txt", b"3")?;
+            assert_eq!("3", fs::read_to_string(root.join("a.txt"))?);
+
+            // a stale temporary file is replaced
+            fs::write(root.join(".a.txt.tmp.sbdb"), "stale")?;
+            db.write_bytes("a.txt", b"4")?;
+            assert_eq!("4", fs::read_to_string(root.join("a.txt"))?);
+        }
+        assert_eq!(vec![OsString::from("a.txt")], entries()?);
+
+        // large writes always use a named file
+        let opened = vfs.calls(VfsOp::OpenTmpfile);
+        db.write_bytes("a.txt", &vec![0; (1 << 20) + 1])?;
+        assert_eq!(opened, vfs.calls(VfsOp::OpenTmpfile));
+        assert_eq!(vec![OsString::from("a.txt")], entries()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflink_support() -> anyhow::Result<()> {
+        use crate::reflink::ReflinkSupport;
+
+        // filesystems are tracked separately
+        let support = ReflinkSupport::default();
+        assert_eq!(None, support.supported(1));
+        support.record(1, false);
+        support.record(2, true);
+        assert_eq!(Some(false), support.supported(1));
+        assert_eq!(Some(true), support.supported(2));
+        assert!(support.skip(1));
+        assert!(!support.skip(2));
+        assert!(!support.skip(3));
+
+        // a single success settles it, failures afterwards are not held against the filesystem
+        support.record(1, true);
+        support.record(1, false);
+        assert!(!support.skip(1));
+        assert_eq!(Some(true), support.supported(1));
+
+        // once the interval passed, one copy tries again
+        let support = ReflinkSupport::new(Duration::ZERO);
+        support.record(1, false);
+        assert!(!support.skip(1));
+        assert_eq!(Some(false), support.supported(1));
+
+        let test_client = TestClient::new("test_reflink_support")?;
+        let metrics = Arc::new(AtomicMetrics::default());
+        let db = Client::builder(test_client.client.root())
+            .metrics(metrics.clone())
+            .build()?;
+        assert_eq!(None, db.reflink_supported());
+        for i in 0..20 {
+            let dir = db.root().join("dir").join((i % 4).to_string());
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join(i.to_string()), i.to_string())?;
+        }
+        for _ in 0..2 {
+            let gaurd = db.write_dir("dir")?;
+            let copy = gaurd.cow()?;
+            for i in 0..20 {
+                let path = copy.path.join((i % 4).to_string()).join(i.to_string());
+                assert_eq!(i.to_string(), fs::read_to_string(path)?);
+            }
+            copy.commit()?;
+        }
+        let supported = db.reflink_supported().context("no copy was recorded")?;
+        assert_eq!(
+            supported,
+            metrics.files_reflinked.load(Ordering::Relaxed) > 0
+        );
+        assert_eq!(
+            40,
+            metrics.files_copied.load(Ordering::Relaxed)
+                + metrics.files_reflinked.load(Ordering::Relaxed)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_deep_trees() -> anyhow::Result<()> {
+        use std::process::Command;
+
+        let test_client = TestClient::new("test_deep_trees")?;
+        let root = test_client.client.root();
+        // the chain is built bottom up from chunks that are renamed into place, so that it can be longer
+        // than PATH_MAX without any path used to build it being that long
+        let chain = |dir: &str, depth: usize| -> anyhow::Result<()> {
+            const CHUNK: usize = 100;
+            let part = root.join(format!("{}-part", dir));
+            let next = root.join(format!("{}-next", dir));
+            fs::create_dir(&part)?;
+            fs::write(part.join("leaf"), "leaf")?;
+            let mut levels = 0;
+            while levels < depth {
+                let n = CHUNK.min(depth - levels);
+                let bottom = next.join(std::iter::repeat_n("d", n).collect::<PathBuf>());
+                fs::create_dir_all(bottom.parent().unwrap())?;
+                fs::rename(&part, &bottom)?;
+                fs::rename(&next, &part)?;
+                levels += n;
+            }
+            fs::rename(&part, root.join(dir))?;
+            Ok(())
+        };
+
+        chain("shallow", 10)?;
+        let db = Client::builder(root).max_depth(9).build()?;
+        let Err(err) = db.write_dir("shallow")?.cow() else {
+            anyhow::bail!("copied a tree deeper than the limit");
+        };
+        assert!(
+            matches!(err, Error::TreeTooDeep { max_depth: 9, .. }),
+            "{:?}",
+            err
+        );
+        let err = db.snapshot("shallow", "deep").unwrap_err();
+        assert!(matches!(err, Error::TreeTooDeep { .. }), "{:?}", err);
+        // the directories beyond the limit are reported, the partial copy was removed when it failed
+        let report = db.gc();
+        assert_eq!(1, report.errors);
+        assert_eq!(0, report.temps_removed);
+        let copy = Client::builder(root)
+            .max_depth(10)
+            .build()?
+            .write_dir("shallow")?
+            .cow()?;
+        let leaf = copy
+            .path
+            .join(["d"; 10].iter().collect::<PathBuf>())
+            .join("leaf");
+        assert_eq!("leaf", fs::read_to_string(leaf)?);
+        drop(copy);
+
+        // an atomic dir link that leads back to the directory holding it
+        let looped = root.join("looped");
+        fs::create_dir(&looped)?;
+        let payload = format!(".y.{}.dir.sbdb", puuid());
+        std::os::unix::fs::symlink(".", looped.join(&payload))?;
+        std::os::unix::fs::symlink(&payload, looped.join("y"))?;
+        let err = test_client.client.snapshot("looped", "loop").unwrap_err();
+        assert!(matches!(err, Error::SymlinkLoop { .. }), "{:?}", err);
+        assert_eq!(1, test_client.client.gc().errors);
+        fs::remove_dir_all(&looped)?;
+
+        // deeper than PATH_MAX, the copy fails on the path length before it reaches the default limit, but
+        // it must not take the process down with it
+        chain("deep", 10_000)?;
+        assert!(test_client.client.write_dir("deep")?.cow().is_err());
+        // remove_dir_all recurses once per level, rm walks the tree without running out of stack
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg("rm -rf deep .deep.*")
+            .current_dir(root)
+            .status()?;
+        anyhow::ensure!(status.success(), "failed to remove the deep tree");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_preserve_hardlinks() -> anyhow::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let test_client = TestClient::new("test_preserve_hardlinks")?;
+        let root = test_client.client.root();
+        let dir = root.join("dir");
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("a"), "blob")?;
+        fs::hard_link(dir.join("a"), dir.join("sub/b"))?;
+        fs::write(dir.join("c"), "other")?;
+        let ino = |name: &str| -> anyhow::Result<u64> { Ok(fs::metadata(dir.join(name))?.ino()) };
+
+        // by default every name becomes a separate file
+        test_client.client.write_dir("dir")?.cow()?.commit()?;
+        assert_ne!(ino("a")?, ino("sub/b")?);
+        fs::remove_file(dir.join("sub/b"))?;
+        fs::hard_link(dir.join("a"), dir.join("sub/b"))?;
+
+        let metrics = Arc::new(AtomicMetrics::default());
+        let db = Client::builder(root)
+            .preserve_hardlinks(true)
+            .metrics(metrics.clone())
+            .build()?;
+        // the second name of the blob is linked, not copied
+        db.write_dir("dir")?.cow()?.commit()?;
+        assert_eq!(
+            2,
+            metrics.files_copied.load(Ordering::Relaxed)
+                + metrics.files_reflinked.load(Ordering::Relaxed)
+        );
+        assert_eq!(ino("a")?, ino("sub/b")?);
+        assert_ne!(ino("a")?, ino("c")?);
+        assert_eq!(2, fs::metadata(dir.join("a"))?.nlink());
+        db.write_dir("dir")?.cow()?.commit()?;
+        assert_eq!(ino("a")?, ino("sub/b")?);
+
+        // replacing one name leaves the others alone
+        db.write_bytes("dir/a", b"new")?;
+        assert_eq!("new", fs::read_to_string(dir.join("a"))?);
+        assert_eq!("blob", fs::read_to_string(dir.join("sub/b"))?);
+        assert_eq!("other", fs::read_to_string(dir.join("c"))?);
+        assert_eq!(1, fs::metadata(dir.join("sub/b"))?.nlink());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpath_normalization() -> anyhow::Result<()> {
+        use crate::{Lock, LockStatus};
+
+        let test_client = TestClient::new("test_rpath_normalization")?;
+        let db = &test_client.client;
+        let root = db.root();
+        fs::create_dir_all(root.join("a"))?;
+        fs::write(root.join("a/b"), "b")?;
+
+        // every spelling of an entry contends on the same lock
+        #[allow(unused_mut)]
+        let mut spellings = vec!["a/b", "./a//b", "a/./b/"];
+        #[cfg(windows)]
+        spellings.extend(["a\\b", ".\\a\\\\b", "a/b\\"]);
+        for spelling in spellings {
+            let gaurd = db.write_file(spelling)?;
+            assert_eq!(root.join("a").join("b"), gaurd.path);
+            assert_eq!(
+                LockStatus::Write,
+                Lock::probe(root.join("a/b"))?,
+                "{}",
+                spelling
+            );
+            let cow = gaurd.cow()?;
+            fs::write(&cow.path, spelling)?;
+            cow.commit()?;
+        }
+        let tx = db.tx().write("./a/b/").read("a//b").begin()?;
+        tx.write_file("a/./b")?;
+        let cow = tx.file_cow("a//b")?;
+        fs::write(&cow.path, "tx")?;
+        cow.commit()?;
+        drop(tx);
+        assert_eq!("tx", fs::read_to_string(root.join("a/b"))?);
+        assert_eq!(vec![OsString::from("b")], db.list("./a/")?,);
+        db.read_dir("a/")?.read_file("./b")?;
+
+        // nothing may lead out of the root
+        let invalid = |result: crate::Result<()>| matches!(result, Err(Error::InvalidPath { .. }));
+        assert!(invalid(db.read_file("a/../../b").map(drop)));
+        assert!(invalid(db.write_file(root.join("a/b")).map(drop)));
+        assert!(invalid(db.tx().read("a").write("../b").begin().map(drop)));
+        assert!(invalid(db.write_dir("a")?.write_file("./").map(drop)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_overlapping_declarations() -> anyhow::Result<()> {
+        use std::sync::mpsc;
+
+        let test_client = TestClient::new("test_tx_overlapping_declarations")?;
+        let db = &test_client.client;
+        fs::create_dir_all(db.root().join("a/b"))?;
+        fs::write(db.root().join("a/b.txt"), "old")?;
+
+        let locks = |tx: &crate::Tx| {
+            let mut reads = tx.reads.clone();
+            let mut writes = tx.writes.clone();
+            reads.sort();
+            writes.sort();
+            (reads, writes)
+        };
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        let builder = db.tx().read("a/b.txt").write("a/b.txt");
+        assert!(!builder.reads.contains(Path::new("a/b.txt")));
+        let builder = db.tx().write("a/b.txt").read("a/b.txt");
+        assert!(!builder.reads.contains(Path::new("a/b.txt")));
+
+        let cases = [
+            db.tx().read("a/b.txt").write("a/b.txt"),
+            db.tx().write("a/b.txt").read("a/b.txt"),
+            db.tx().read("a/b.txt").write("a/b.txt").read("a/b.txt"),
+        ];
+        for builder in cases {
+            let tx = builder.begin()?;
+            assert_eq!((paths(&["", "a"]), paths(&["a/b.txt"])), locks(&tx));
+        }
+        let tx = db.tx().read("a/b/c").read("a").write("a").begin()?;
+        assert_eq!((paths(&[""]), paths(&["a"])), locks(&tx));
+        drop(tx);
+        let tx = db.tx().write("a/b").write("a").read("a/b.txt").begin()?;
+        assert_eq!((paths(&[""]), paths(&["a"])), locks(&tx));
+        drop(tx);
+
+        // begins and commits rather than waiting for itself
+        let (send, recv) = mpsc::channel();
+        let committer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let commit = || -> crate::Result<()> {
+                    let tx = db.tx().read("a/b.txt").write("a/b.txt").begin()?;
+                    let cow = tx.file_cow("a/b.txt")?;
+                    fs::write(&cow.path, "new").unwrap();
+                    cow.commit()
+                };
+                let _ = send.send(commit());
+            })
+        };
+        recv.recv_timeout(Duration::from_secs(10))??;
+        committer.join().unwrap();
+        assert_eq!("new", fs::read_to_string(db.root().join("a/b.txt"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_counter() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_counter")?;
+        let root = test_client.client.root();
+        let counter = test_client.client.counter("ids");
+        assert_eq!(0, counter.get()?);
+        assert_eq!(1, counter.next()?);
+        assert_eq!(11, counter.add(10)?);
+        assert_eq!(12..15, counter.next_batch(3)?);
+        assert_eq!(14, counter.get()?);
+        assert_eq!("14\n", fs::read_to_string(root.join("ids"))?);
+
+        // threads of several clients get every value exactly once
+        let handed_out = thread::scope(|s| {
+            let workers = (0..8)
+                .map(|i| {
+                    s.spawn(move || -> anyhow::Result<Vec<u64>> {
+                        let counter = Client::new(root)?.counter("ids");
+                        let mut ids = Vec::new();
+                        for _ in 0..25 {
+                            if i % 2 == 0 {
+                                ids.push(counter.next()?);
+                            } else {
+                                ids.extend(counter.next_batch(4)?);
+                            }
+                        }
+                        Ok(ids)
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<anyhow::Result<Vec<_>>>()
+        })?;
+        let mut ids = handed_out.into_iter().flatten().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!((15..15 + 4 * 25 + 4 * 25 * 4).collect::<Vec<_>>(), ids);
+        assert_eq!(14 + 4 * 25 + 4 * 25 * 4, counter.get()?);
+
+        // corrupt contents are reported instead of starting over
+        fs::write(root.join("ids"), "forty two")?;
+        assert!(matches!(counter.next(), Err(Error::Decode { .. })));
+        assert!(matches!(counter.get(), Err(Error::Decode { .. })));
+        fs::write(root.join("ids"), format!("{}", u64::MAX - 2))?;
+        assert_eq!(u64::MAX - 1, counter.next()?);
+        assert!(matches!(counter.next(), Err(Error::CounterOverflow { .. })));
+        assert_eq!(u64::MAX - 1, counter.get()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue() -> anyhow::Result<()> {
+        use std::collections::HashMap;
+
+        let test_client = TestClient::new("test_queue")?;
+        let root = test_client.client.root();
+        let queue = test_client.client.queue("jobs");
+        assert_eq!(None, queue.pop()?);
+        let first = queue.push(b"a")?;
+        queue.push(b"b")?;
+        assert_eq!(Some((first.clone(), b"a".to_vec())), queue.pop()?);
+        assert!(queue.ack(&first)?);
+        assert!(!queue.ack(&first)?);
+
+        // a consumer that crashes before acknowledging leaves its item in flight
+        let (crashed, payload) = queue.pop()?.context("queue is empty")?;
+        assert_eq!(b"b", &payload[..]);
+        assert_eq!(None, queue.pop()?);
+        assert_eq!(0, queue.requeue_stale(Duration::from_secs(3600))?);
+        assert_eq!(1, queue.requeue_stale(Duration::ZERO)?);
+        assert_eq!(Some((crashed.clone(), payload)), queue.pop()?);
+        assert!(queue.ack(&crashed)?);
+        assert_eq!(None, queue.pop()?);
+
+        // producers and consumers of several clients, some of which crash
+        const PRODUCERS: usize = 4;
+        const ITEMS: usize = 50;
+        let produced = AtomicU64::new(0);
+        let consumed = Mutex::new(HashMap::<Vec<u8>, usize>::new());
+        thread::scope(|s| -> anyhow::Result<()> {
+            let producers = (0..PRODUCERS)
+                .map(|p| {
+                    let produced = &produced;
+                    s.spawn(move || -> anyhow::Result<()> {
+                        let queue = Client::new(root)?.queue("jobs");
+                        for i in 0..ITEMS {
+                            queue.push(format!("{}-{}", p, i).as_bytes())?;
+                        }
+                        produced.fetch_add(1, Ordering::Release);
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            let consumers = (0..4)
+                .map(|c| {
+                    let (produced, consumed) = (&produced, &consumed);
+                    s.spawn(move || -> anyhow::Result<()> {
+                        let queue = Client::new(root)?.queue("jobs");
+                        let mut popped = 0;
+                        loop {
+                            let done = produced.load(Ordering::Acquire) == PRODUCERS as u64;
+                            let Some((id, payload)) = queue.pop()? else {
+                                if done {
+                                    return Ok(());
+                                }
+                                thread::yield_now();
+                                continue;
+                            };
+                            popped += 1;
+                            // every seventh item of the first consumer is lost with its consumer
+                            if c == 0 && popped % 7 == 0 {
+                                continue;
+                            }
+                            *consumed.lock().unwrap().entry(payload).or_default() += 1;
+                            assert!(queue.ack(&id)?);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for worker in producers.into_iter().chain(consumers) {
+                worker.join().unwrap()?;
+            }
+            Ok(())
+        })?;
+
+        // what was lost is recovered by requeueing
+        queue.requeue_stale(Duration::ZERO)?;
+        let mut consumed = consumed.into_inner().unwrap();
+        while let Some((id, payload)) = queue.pop()? {
+            *consumed.entry(payload).or_default() += 1;
+            queue.ack(&id)?;
+        }
+        assert_eq!(PRODUCERS * ITEMS, consumed.len());
+        assert!(consumed.values().all(|count| *count == 1));
+        for p in 0..PRODUCERS {
+            for i in 0..ITEMS {
+                assert!(consumed.contains_key(format!("{}-{}", p, i).as_bytes()));
+            }
+        }
+        assert!(fs::read_dir(root.join("jobs/in-flight"))?.next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap() -> anyhow::Result<()> {
+        use crate::{CasError, ContentVersion};
+
+        let test_client = TestClient::new("test_compare_and_swap")?;
+        let db = &test_client.client;
+        let (bytes, absent) = db.read_versioned("n")?;
+        assert!(bytes.is_empty() && absent.is_absent());
+
+        // only one of two racing creators wins
+        db.write_if_unchanged("n", &absent, b"1")?;
+        let Err(CasError::Conflict { current }) = db.write_if_unchanged("n", &absent, b"2") else {
+            panic!("second create succeeded");
+        };
+        let (bytes, version) = db.read_versioned("n")?;
+        assert_eq!((b"1".to_vec(), version), (bytes, current));
+        assert!(!version.is_absent());
+
+        // a stale version conflicts, rewriting the same contents does not change the version
+        db.write_if_unchanged("n", &version, b"3")?;
+        assert!(matches!(
+            db.write_if_unchanged("n", &version, b"4"),
+            Err(CasError::Conflict { .. })
+        ));
+        let (_, three) = db.read_versioned("n")?;
+        db.write_bytes("n", b"3")?;
+        db.write_if_unchanged("n", &three, b"5")?;
+        fs::remove_file(db.root().join("n"))?;
+        let Err(CasError::Conflict { current }) = db.write_if_unchanged("n", &three, b"6") else {
+            panic!("write over a removed file succeeded");
+        };
+        assert_eq!(ContentVersion::ABSENT, current);
+
+        let increment = |bytes: &[u8]| {
+            let n = std::str::from_utf8(bytes)
+                .unwrap()
+                .parse::<u64>()
+                .unwrap_or(0);
+            (n + 1).to_string().into_bytes()
+        };
+        thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| {
+                    for _ in 0..20 {
+                        db.update("counter", increment, 10_000).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!("320", fs::read_to_string(db.root().join("counter"))?);
+
+        // a writer that gets in between every time exhausts the retries
+        let interfere = |bytes: &[u8]| {
+            db.write_bytes("counter", puuid().as_bytes()).unwrap();
+            increment(bytes)
+        };
+        assert!(matches!(
+            db.update("counter", interfere, 3),
+            Err(CasError::Conflict { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliases() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_aliases")?;
+        let db = &test_client.client;
+        if !db.atomic_dirs_supported() {
+            return Ok(());
+        }
+        fs::create_dir_all(db.root().join("runs/0"))?;
+        fs::create_dir_all(db.root().join("deep/er"))?;
+        db.write_bytes("runs/0/result", b"0")?;
+        assert!(db.read_alias("runs/latest")?.is_none());
+        assert!(db.set_alias("runs/latest", "runs/missing").is_err());
+        assert!(db.set_alias("runs/0", "runs").is_err());
+        assert!(db.set_alias("runs/0/result", "runs/0/result").is_err());
+        db.set_alias("runs/latest", "runs/0")?;
+        assert_eq!(Some(PathBuf::from("runs/0")), db.read_alias("runs/latest")?);
+        assert_eq!(
+            std::path::Path::new("0"),
+            fs::read_link(db.root().join("runs/latest"))?
+        );
+
+        // readers resolving the alias always find a target while it is flipped
+        let done = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|s| -> anyhow::Result<()> {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let path = db.root().join("runs/latest/result");
+                    while !done.load(Ordering::Acquire) {
+                        let run: u32 = fs::read_to_string(&path).unwrap().parse().unwrap();
+                        assert!(run < 50);
+                    }
+                });
+            }
+            for i in 1..50 {
+                fs::create_dir(db.root().join(format!("runs/{}", i)))?;
+                db.write_bytes(format!("runs/{}/result", i), i.to_string().as_bytes())?;
+                db.set_alias("runs/latest", format!("runs/{}", i))?;
+            }
+            done.store(true, Ordering::Release);
+            Ok(())
+        })?;
+        assert_eq!(
+            "49",
+            fs::read_to_string(db.root().join("runs/latest/result"))?
+        );
+
+        // aliases may point anywhere in the database, at files too
+        db.set_alias("deep/er/alias", "runs/3/result")?;
+        assert_eq!(
+            std::path::Path::new("../../runs/3/result"),
+            fs::read_link(db.root().join("deep/er/alias"))?
+        );
+        assert_eq!("3", fs::read_to_string(db.root().join("deep/er/alias"))?);
+
+        // aliases are not atomic directories, and atomic directories are not aliases
+        assert!(db.write_dir("runs/latest")?.cow_atomic().is_err());
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        assert!(db.read_alias("atom")?.is_none());
+        assert!(db.set_alias("atom", "runs/0").is_err());
+        assert!(db.set_alias("runs/1", "runs/0").is_err());
+        let report = db.gc();
+        assert_eq!(0, report.errors);
+        assert_eq!(
+            Some(PathBuf::from("runs/49")),
+            db.read_alias("runs/latest")?
+        );
+
+        assert!(db.remove_alias("runs/latest")?);
+        assert!(!db.remove_alias("runs/latest")?);
+        assert!(!db.remove_alias("runs/0")?);
+        assert!(db.root().join("runs/49/result").exists());
+        assert!(!db.root().join("runs/latest").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> anyhow::Result<()> {
+        use crate::ImportMode;
+
+        let test_client = TestClient::new("test_import")?;
+        let db = &test_client.client;
+        let outside = TestClient::new("test_import_outside")?;
+        let src = outside.client.root();
+        let copy = ImportMode::Copy {
+            overwrite: false,
+            sync: true,
+        };
+        let replace = ImportMode::Copy {
+            overwrite: true,
+            sync: false,
+        };
+        let write_tree = |dir: &std::path::Path, version: u32| -> anyhow::Result<()> {
+            fs::create_dir_all(dir.join("sub"))?;
+            fs::write(dir.join("a"), version.to_string())?;
+            fs::write(dir.join("sub/b"), version.to_string().repeat(10_000))?;
+            Ok(())
+        };
+
+        fs::write(src.join("file"), "file")?;
+        db.import_file(&src.join("file"), "file", copy)?;
+        assert_eq!("file", fs::read_to_string(db.root().join("file"))?);
+        assert!(src.join("file").exists());
+        assert!(matches!(
+            db.import_file(&src.join("file"), "file", copy),
+            Err(Error::AlreadyExists { .. })
+        ));
+        fs::write(src.join("file"), "moved")?;
+        db.import_file(
+            &src.join("file"),
+            "file",
+            ImportMode::Move {
+                overwrite: true,
+                sync: false,
+            },
+        )?;
+        assert_eq!("moved", fs::read_to_string(db.root().join("file"))?);
+        assert!(!src.join("file").exists());
+        assert!(matches!(
+            db.import_file(&src.join("file"), "missing", copy),
+            Err(Error::NotFound { .. })
+        ));
+        assert_eq!(vec!["file"], db.list("")?);
+
+        // readers see every tree whole, never a partially imported one
+        write_tree(&src.join("0"), 0)?;
+        db.import_dir(&src.join("0"), "data", copy)?;
+        let done = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|s| -> anyhow::Result<()> {
+            s.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    let gaurd = db.read_dir("data").unwrap();
+                    let a = fs::read_to_string(gaurd.path.join("a")).unwrap();
+                    let b = fs::read_to_string(gaurd.path.join("sub/b")).unwrap();
+                    assert_eq!(a.repeat(10_000), b);
+                }
+            });
+            s.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    match fs::read_to_string(db.root().join("data/sub/b")) {
+                        Ok(b) => assert_eq!(b[..1].repeat(10_000), b),
+                        Err(e) => assert_eq!(std::io::ErrorKind::NotFound, e.kind()),
+                    }
+                }
+            });
+            for i in 1..20 {
+                let dir = src.join(i.to_string());
+                write_tree(&dir, i % 10)?;
+                let mode = match i % 2 {
+                    0 => replace,
+                    _ => ImportMode::Move {
+                        overwrite: true,
+                        sync: true,
+                    },
+                };
+                db.import_dir(&dir, "data", mode)?;
+                assert_eq!(i % 2 == 0, dir.exists());
+            }
+            done.store(true, Ordering::Release);
+            Ok(())
+        })?;
+        assert_eq!("9", fs::read_to_string(db.root().join("data/a"))?);
+        assert!(matches!(
+            db.import_dir(&src.join("0"), "data", copy),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(
+            db.import_dir(&src.join("missing"), "data", replace)
+                .is_err()
+        );
+        assert_eq!("9", fs::read_to_string(db.root().join("data/a"))?);
+        assert_eq!(vec!["data", "file"], db.list("")?);
+        assert_eq!(0, db.gc().temps_removed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export() -> anyhow::Result<()> {
+        use crate::{ExistingDest, ExportOptions, Metrics};
+
+        /// Holds up the first copy, so that a writer can queue up behind the export holding the lock.
+        struct Stall(Mutex<Option<std::sync::mpsc::Sender<()>>>);
+
+        impl Metrics for Stall {
+            fn cow_copied(&self, _bytes: u64, _reflinked: bool) {
+                if let Some(started) = self.0.lock().unwrap().take() {
+                    started.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(200));
+                }
+            }
+        }
+
+        let test_client = TestClient::new("test_export")?;
+        let outside = TestClient::new("test_export_outside")?;
+        let (started, copying) = std::sync::mpsc::channel();
+        let db = Client::builder(test_client.client.root())
+            .metrics(Arc::new(Stall(Mutex::new(Some(started)))))
+            .build()?;
+        let reports = db.root().join("reports/2024");
+        fs::create_dir_all(reports.join("q1"))?;
+        db.write_bytes("reports/2024/summary", b"summary")?;
+        db.write_bytes("reports/2024/q1/sales", b"sales")?;
+        db.write_dir("reports/2024")?
+            .create_dir_atomic("atom", false)?;
+        fs::write(reports.join("atom/inner"), "inner")?;
+        let before = tree_contents(&reports)?;
+
+        // a writer of the subtree waits for the export, which sees the contents from before the write
+        let dest = outside.client.root().join("export");
+        thread::scope(|s| -> anyhow::Result<()> {
+            let export =
+                s.spawn(|| db.export_dir("reports/2024", &dest, &ExportOptions::default()));
+            copying.recv()?;
+            let gaurd = db.write_dir("reports/2024")?;
+            let copy = gaurd.cow()?;
+            fs::write(copy.path.join("summary"), "rewritten")?;
+            copy.commit()?;
+            export.join().unwrap()?;
+            Ok(())
+        })?;
+        assert_eq!(before, tree_contents(&dest)?);
+        assert!(!dest.join("atom").is_symlink());
+        for entry in fs::read_dir(&dest)? {
+            assert!(!entry?.file_name().to_string_lossy().ends_with(".sbdb"));
+        }
+        assert_eq!(b"rewritten".to_vec(), fs::read(reports.join("summary"))?);
+
+        // existing destinations fail unless merged into or replaced
+        assert!(matches!(
+            db.export_dir("reports/2024", &dest, &ExportOptions::default()),
+            Err(Error::AlreadyExists { .. })
+        ));
+        fs::write(dest.join("extra"), "extra")?;
+        fs::write(dest.join("q1/sales"), "stale")?;
+        let merge = ExportOptions {
+            existing: ExistingDest::Merge,
+            sync: true,
+        };
+        db.export_dir("reports/2024", &dest, &merge)?;
+        assert_eq!("extra", fs::read_to_string(dest.join("extra"))?);
+        assert_eq!("sales", fs::read_to_string(dest.join("q1/sales"))?);
+        assert_eq!("rewritten", fs::read_to_string(dest.join("summary"))?);
+        let replace = ExportOptions {
+            existing: ExistingDest::Replace,
+            sync: false,
+        };
+        db.export_dir("reports/2024", &dest, &replace)?;
+        assert_eq!(tree_contents(&reports)?, tree_contents(&dest)?);
+        // the export and the root marker's directory
+        assert_eq!(2, fs::read_dir(outside.client.root())?.count());
+
+        db.export_file("reports/2024/q1/sales", &dest.join("summary"))?;
+        assert_eq!("sales", fs::read_to_string(dest.join("summary"))?);
+        assert!(matches!(
+            db.export_file("reports/2024/missing", &dest.join("missing")),
+            Err(Error::NotFound { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> anyhow::Result<()> {
+        use crate::{DbStats, EntryStats, FileSize, StatsOptions};
+
+        let test_client = TestClient::new("test_stats")?;
+        let db = &test_client.client;
+        let root = db.root();
+        fs::create_dir_all(root.join("d/e"))?;
+        fs::write(root.join("a"), [0; 10])?;
+        fs::write(root.join("d/b"), [0; 100])?;
+        fs::write(root.join("d/e/c"), [0; 1000])?;
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        fs::write(root.join("atom/f"), [0; 5])?;
+        fs::write(root.join(".a.tmp.sbdb"), [0; 7])?;
+        let backup = root.join(format!(".d.{}.bak.sbdb", puuid()));
+        fs::create_dir(&backup)?;
+        fs::write(backup.join("b"), [0; 3])?;
+        let payload = root.join(format!(".atom.{}.dir.sbdb", puuid()));
+        fs::create_dir(&payload)?;
+        fs::write(payload.join("f"), [0; 4])?;
+        fs::write(root.join(".sbdb/internal"), [0; 6])?;
+        // named like a lock file, but with contents it is data
+        fs::write(root.join("d/.b.lock.sbdb"), [0; 1])?;
+
+        let options = StatsOptions { largest: 2 };
+        let stats = db.stats_with("", &options)?;
+        assert_eq!(5, stats.files);
+        assert_eq!(1116, stats.file_bytes);
+        assert_eq!(3, stats.dirs);
+        assert_eq!(2, stats.max_depth);
+        let entry = |count, bytes| EntryStats { count, bytes };
+        assert_eq!(
+            vec![
+                (".bak.sbdb", entry(1, 3)),
+                (".dir.sbdb", entry(1, 4)),
+                (".tmp.sbdb", entry(1, 7)),
+            ],
+            stats.artifacts.clone().into_iter().collect::<Vec<_>>()
+        );
+        // the internal file, the root marker, the root's lock and queue files, the meta file and the
+        // maintenance lock
+        let meta_len = fs::metadata(root.join(".sbdb/meta.json"))?.len();
+        assert_eq!(entry(6, 6 + meta_len), stats.internal);
+        assert_eq!(20 + meta_len, stats.overhead_bytes());
+        assert_eq!(
+            vec![
+                FileSize {
+                    path: PathBuf::from("d/e/c"),
+                    bytes: 1000
+                },
+                FileSize {
+                    path: PathBuf::from("d/b"),
+                    bytes: 100
+                },
+            ],
+            stats.largest
+        );
+
+        // the walk left lock files behind, which are counted from now on
+        let again = db.stats_with("", &options)?;
+        assert_eq!(entry(3, 0), again.artifacts[".lock.sbdb"]);
+        assert_eq!(
+            DbStats {
+                artifacts: stats.artifacts.clone(),
+                ..again.clone()
+            },
+            stats
+        );
+
+        // a subtree on its own, without the internal directory
+        let subtree = db.stats("d")?;
+        assert_eq!(
+            (3, 1101, 1, 1),
+            (
+                subtree.files,
+                subtree.file_bytes,
+                subtree.dirs,
+                subtree.max_depth
+            )
+        );
+        assert_eq!(EntryStats::default(), subtree.internal);
+        assert_eq!(3, subtree.largest.len());
+
+        #[cfg(feature = "serde_json")]
+        {
+            let json = serde_json::to_value(&again)?;
+            assert_eq!(0, json["artifacts"][".lock.sbdb"]["bytes"]);
+            assert_eq!("d/e/c", json["largest"][0]["path"]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_quota")?;
+        let root = test_client.client.root();
+        fs::write(root.join("existing"), [0; 40])?;
+        let db = Client::builder(root).quota(100).build()?;
+        assert_eq!(Some(40), db.usage());
+        assert_eq!(None, test_client.client.usage());
+
+        db.write_bytes("a", &[0; 50])?;
+        assert_eq!(Some(90), db.usage());
+        assert!(matches!(
+            db.write_bytes("b", &[0; 20]),
+            Err(Error::QuotaExceeded {
+                used: 90,
+                quota: 100,
+                attempted: 20
+            })
+        ));
+        assert!(!root.join("b").exists());
+        assert!(!root.join(".b.tmp.sbdb").exists());
+        assert_eq!(Some(90), db.usage());
+
+        // only what a commit adds counts, and a directory is charged for its whole tree
+        db.write_bytes("a", &[0; 55])?;
+        assert_eq!(Some(95), db.usage());
+        fs::create_dir(root.join("d"))?;
+        let gaurd = db.write_dir("d")?;
+        let copy = gaurd.cow()?;
+        fs::write(copy.path.join("f"), [0; 10])?;
+        assert!(matches!(
+            copy.commit(),
+            Err(Error::QuotaExceeded { attempted: 10, .. })
+        ));
+        gaurd.release()?;
+        assert!(!root.join("d/f").exists());
+
+        // shrinking is always allowed, and data removed behind the client's back needs a recount
+        db.write_bytes("a", &[0; 5])?;
+        assert_eq!(Some(45), db.usage());
+        fs::remove_file(root.join("existing"))?;
+        assert_eq!(5, db.recompute_usage()?);
+        db.write_bytes("b", &[0; 20])?;
+        let gaurd = db.write_dir("d")?;
+        let copy = gaurd.cow()?;
+        fs::write(copy.path.join("f"), [0; 10])?;
+        copy.commit()?;
+        gaurd.release()?;
+        assert_eq!(Some(35), db.usage());
+
+        #[cfg(feature = "binary")]
+        {
+            let records = db.collection::<Vec<u8>, _>("records");
+            records.put("x", &vec![0; 30])?;
+            let used = db.usage().unwrap();
+            assert!(used > 65);
+            assert!(matches!(
+                records.put("y", &vec![0; 40]),
+                Err(Error::QuotaExceeded { .. })
+            ));
+            assert!(records.remove("x")?);
+            assert_eq!(Some(35), db.usage());
+            records.put("y", &vec![0; 40])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_ttl")?;
+        let db = &test_client.client;
+        let root = db.root();
+        let hour = Duration::from_secs(60 * 60);
+        fs::create_dir(root.join("s"))?;
+        db.write_with_ttl("s/a", b"a", Duration::ZERO)?;
+        db.write_with_ttl("s/b", b"b", hour)?;
+        db.write_with_ttl("s/c", b"c", Duration::ZERO)?;
+        assert!(db.persist("s/c")?);
+        assert!(!db.persist("s/c")?);
+        assert_eq!(Some(Duration::ZERO), db.ttl("s/a")?);
+        assert!(db.ttl("s/b")?.is_some_and(|ttl| ttl > hour / 2));
+        assert_eq!(None, db.ttl("s/c")?);
+        assert_eq!(vec!["a", "b", "c"], db.list("s")?);
+        assert!(matches!(
+            db.write_bytes("s/d.ttl.sbdb", b""),
+            Err(Error::ReservedName { .. })
+        ));
+        let ttls = db.stats("")?.ttls;
+        assert_eq!(
+            vec![PathBuf::from("s/a"), PathBuf::from("s/b")],
+            ttls.iter().map(|ttl| ttl.path.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(Duration::ZERO, ttls[0].remaining);
+
+        // only expired when asked to
+        assert_eq!(0, db.gc().entries_expired);
+        let expire = GcOptions {
+            expire_ttl: true,
+            ..Default::default()
+        };
+        let dry_run = GcOptions {
+            dry_run: true,
+            ..expire.clone()
+        };
+        assert_eq!(1, db.gc_with(&dry_run).entries_expired);
+        assert!(root.join("s/a").exists());
+
+        // an expiry pushed into the past by hand counts just the same
+        fs::write(root.join("s/.b.ttl.sbdb"), "1000")?;
+        let report = db.gc_with(&expire);
+        assert_eq!(
+            (2, 0, 0),
+            (
+                report.entries_expired,
+                report.expiries_removed,
+                report.errors
+            )
+        );
+        assert_eq!(vec!["c"], db.list("s")?);
+        let mut left: Vec<_> = fs::read_dir(root.join("s"))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        left.sort();
+        assert_eq!(vec![".c.lock.sbdb", ".c.queue.sbdb", "c"], left);
+
+        // expiries outlive entries removed some other way only until the next gc
+        db.write_with_ttl("s/e", b"e", hour)?;
+        fs::remove_file(root.join("s/e"))?;
+        assert_eq!(1, db.gc().expiries_removed);
+        assert!(!root.join("s/.e.ttl.sbdb").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_roots() -> anyhow::Result<()> {
+        // a root inside an existing one
+        let outer = TestClient::new("test_nested_roots")?;
+        let root = outer.client.root();
+        let Err(Error::NestedRoot { outer: found }) = Client::new(root.join("users")) else {
+            anyhow::bail!("opened a root nested in another one");
+        };
+        assert_eq!(fs::canonicalize(root)?, found);
+        let nested = Client::builder(root.join("users"))
+            .allow_nested(true)
+            .build()?;
+        nested.write_bytes("a", b"a")?;
+        assert_eq!("a", fs::read_to_string(root.join("users/a"))?);
+
+        // a root around an existing one is only warned about the first time it is opened
+        let root = std::env::temp_dir().join("test_nested_roots_inner-".to_string() + &puuid());
+        Client::new(root.join("a/b"))?;
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let open = || {
+            let warnings = warnings.clone();
+            Client::builder(&root)
+                .on_warning(move |warning| warnings.lock().unwrap().push(warning))
+                .build()
+        };
+        let _cleanup = TestClient {
+            client: open()?,
+            root: root.clone(),
+        };
+        open()?;
+        let warnings = std::mem::take(&mut *warnings.lock().unwrap());
+        assert!(
+            matches!(&warnings[..], [Warning::InnerRoots { inner, .. }] if *inner == [root.join("a/b")]),
+            "{:?}",
+            warnings
+        );
+        assert!(matches!(
+            Client::new(root.join("a/b")),
+            Err(Error::NestedRoot { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format() -> anyhow::Result<()> {
+        use std::path::Path;
+
+        use crate::{FORMAT_VERSION, Migration, error::IoResultExt};
+
+        struct AddFile(&'static str, u32, Arc<AtomicU64>);
+
+        impl Migration for AddFile {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn version(&self) -> u32 {
+                self.1
+            }
+
+            fn migrate(&self, root: &Path) -> crate::Result<()> {
+                self.2.fetch_add(1, Ordering::SeqCst);
+                let path = root.join(self.0);
+                fs::write(&path, "").at("write", &path)
+            }
+        }
+
+        let test_client = TestClient::new("test_format")?;
+        let db = &test_client.client;
+        let root = db.root();
+        let meta = root.join(".sbdb/meta.json");
+        assert_eq!(FORMAT_VERSION, db.format_version()?);
+
+        // written by a newer version
+        fs::write(
+            &meta,
+            r#"{"format_version": 99, "features": [], "journal": "wal"}"#,
+        )?;
+        let Err(Error::IncompatibleFormat {
+            found: 99,
+            supported: FORMAT_VERSION,
+            ..
+        }) = Client::new(root)
+        else {
+            anyhow::bail!("opened a root of a newer format");
+        };
+        fs::write(&meta, r#"{"format_version": 1, "features": ["journal"]}"#)?;
+        let Err(Error::IncompatibleFormat {
+            unknown_features, ..
+        }) = Client::new(root)
+        else {
+            anyhow::bail!("opened a root with an unknown layout feature");
+        };
+        assert_eq!(vec!["journal"], unknown_features);
+
+        // written by an older version, migrated once
+        fs::write(&meta, r#"{"format_version": 0}"#)?;
+        let runs = Arc::new(AtomicU64::new(0));
+        let open = || {
+            Client::builder(root)
+                .migration(Arc::new(AddFile(
+                    "future",
+                    FORMAT_VERSION + 1,
+                    runs.clone(),
+                )))
+                .migration(Arc::new(AddFile("split_locks", 1, runs.clone())))
+                .track_generations()
+                .build()
+        };
+        let db = open()?;
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+        assert!(root.join("split_locks").exists());
+        assert_eq!(FORMAT_VERSION, db.format_version()?);
+        let text = fs::read_to_string(&meta)?;
+        assert!(
+            text.contains(r#""migrations": ["split_locks"]"#),
+            "{}",
+            text
+        );
+        assert!(text.contains(r#""features": ["generations"]"#), "{}", text);
+        open()?;
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scratch() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_scratch")?;
+        let db = &test_client.client;
+        let root = db.root();
+        let area = root.join(".sbdb/tmp");
+        fs::create_dir(root.join("reports"))?;
+        fs::write(root.join("reports/old"), "old")?;
+
+        // promoted all at once, replacing what was there
+        let scratch = db.scratch()?;
+        assert!(scratch.path.starts_with(&area));
+        fs::create_dir(scratch.path.join("q1"))?;
+        fs::write(scratch.path.join("q1/sales"), "sales")?;
+        fs::write(scratch.path.join("summary"), "summary")?;
+        assert!(db.list("reports")?.iter().all(|name| name != "summary"));
+        scratch.promote("reports")?;
+        assert_eq!(vec!["q1", "summary"], db.list("reports")?);
+        assert_eq!("sales", fs::read_to_string(root.join("reports/q1/sales"))?);
+        assert_eq!(0, fs::read_dir(&area)?.count());
+
+        // dropped without being promoted
+        let scratch = db.scratch()?;
+        fs::write(scratch.path.join("partial"), "partial")?;
+        drop(scratch);
+        assert_eq!(0, fs::read_dir(&area)?.count());
+
+        // left behind by a crash, and removed by gc once it is old enough
+        let scratch = db.scratch()?;
+        let path = scratch.path.clone();
+        std::mem::forget(scratch);
+        assert_eq!(0, db.gc().temps_removed);
+        assert!(path.exists());
+        File::open(&path)?.set_modified(std::time::SystemTime::now() - crate::SCRATCH_GRACE * 2)?;
+        assert_eq!(1, db.gc().temps_removed);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_hash")?;
+        let db = &test_client.client;
+        let root = db.root();
+        for tree in ["a", "b"] {
+            fs::create_dir_all(root.join(tree).join("nested"))?;
+            fs::write(root.join(tree).join("abc"), "abc")?;
+            fs::write(root.join(tree).join("nested/data"), "data")?;
+        }
+
+        let file = db.read_file("a/abc")?;
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            file.hash(HashAlgorithm::Sha256)?.to_string()
+        );
+        file.release()?;
+
+        let a = db.hash_tree("a", HashAlgorithm::Sha256)?;
+        assert_eq!((2, 7), (a.files, a.bytes));
+        assert_eq!(a, db.hash_tree("b", HashAlgorithm::Sha256)?);
+
+        // lock files, expiries and counters are not part of the contents
+        db.write_with_ttl("b/nested/data", b"data", Duration::from_secs(3600))?;
+        db.write_bytes("b/abc", b"abc")?;
+        assert!(root.join("b/.abc.lock.sbdb").exists());
+        assert_eq!(a, db.hash_tree("b", HashAlgorithm::Sha256)?);
+
+        db.write_bytes("b/abc", b"abd")?;
+        assert_ne!(a, db.hash_tree("b", HashAlgorithm::Sha256)?);
+        // moving contents between files changes the digest too
+        db.write_bytes("b/abc", b"data")?;
+        db.write_bytes("b/nested/data", b"abc")?;
+        assert_ne!(a, db.hash_tree("b", HashAlgorithm::Sha256)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_meta() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_entry_meta")?;
+        let db = &test_client.client;
+        let root = db.root();
+        fs::create_dir(root.join("docs"))?;
+        assert!(matches!(
+            db.set_meta("docs/a", "type", "text/plain"),
+            Err(Error::NotFound { .. })
+        ));
+        db.write_bytes("docs/a", b"a")?;
+        db.set_meta("docs/a", "type", "text/plain")?;
+        db.set_meta("docs/a", "origin", "line one\n\"two\" \\ \u{1} \u{e9}")?;
+        assert_eq!(
+            Some("text/plain".to_string()),
+            db.get_meta("docs/a", "type")?
+        );
+        assert_eq!(
+            Some("line one\n\"two\" \\ \u{1} \u{e9}".to_string()),
+            db.get_meta("docs/a", "origin")?
+        );
+        assert_eq!(None, db.get_meta("docs/a", "checksum")?);
+        assert_eq!(vec!["a"], db.list("docs")?);
+
+        // kept by writes of the entry and carried along by exports
+        db.write_bytes("docs/a", b"changed")?;
+        assert_eq!(2, db.list_meta("docs/a")?.len());
+        let outside = TestClient::new("test_entry_meta_outside")?;
+        let dest = outside.client.root().join("export");
+        db.export_dir("docs", &dest, &crate::ExportOptions::default())?;
+        assert!(dest.join(".a.meta.sbdb").is_file());
+        assert!(!dest.join(".a.lock.sbdb").exists());
+
+        assert!(matches!(
+            db.set_meta("docs/a", "big", &"x".repeat(crate::MAX_META_BYTES)),
+            Err(Error::MetaTooLarge { .. })
+        ));
+        assert_eq!(2, db.list_meta("docs/a")?.len());
+
+        // updates of different keys do not lose each other
+        thread::scope(|scope| {
+            for i in 0..8 {
+                scope.spawn(move || db.set_meta("docs/a", &format!("key{}", i), "value"));
+            }
+        });
+        assert_eq!(10, db.list_meta("docs/a")?.len());
+
+        for key in db.list_meta("docs/a")?.keys() {
+            assert!(db.remove_meta("docs/a", key)?.is_some());
+        }
+        assert!(!root.join("docs/.a.meta.sbdb").exists());
+
+        // removed with the entry, by gc if the entry was removed some other way
+        db.set_meta("docs/a", "type", "text/plain")?;
+        fs::remove_file(root.join("docs/a"))?;
+        assert_eq!(1, db.gc().metadata_removed);
+        assert!(!root.join("docs/.a.meta.sbdb").exists());
+        db.write_with_ttl("docs/b", b"b", Duration::ZERO)?;
+        db.set_meta("docs/b", "type", "text/plain")?;
+        db.gc_with(&GcOptions {
+            expire_ttl: true,
+            ..Default::default()
+        });
+        assert!(!root.join("docs/.b.meta.sbdb").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_changes() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_poll_changes")?;
+        let db = Client::builder(test_client.client.root())
+            .track_generations()
+            .build()?;
+        fs::create_dir(db.root().join("a"))?;
+        fs::create_dir(db.root().join("b"))?;
+        db.write_bytes("a/file", b"1")?;
+        db.write_bytes("b/file", b"1")?;
+        let subscriptions = [PathBuf::from("a/file"), PathBuf::from("b/file")];
+        let mut state = crate::PollState::default();
+        assert!(
+            db.poll_changes(&subscriptions, &mut state, Duration::ZERO)?
+                .is_empty()
+        );
+        assert!(state.generation("a/file").is_some());
+
+        let budget = Duration::from_secs(10);
+        let start = Instant::now();
+        let changed = thread::scope(|scope| -> anyhow::Result<_> {
+            let writer = scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                db.write_bytes("b/file", b"2")
+            });
+            let changed = db.poll_changes(&subscriptions, &mut state, budget)?;
+            writer.join().unwrap()?;
+            Ok(changed)
+        })?;
+        assert!(start.elapsed() < budget);
+        assert_eq!(
+            vec![PathBuf::from("b/file")],
+            changed.iter().map(|c| c.path.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(changed[0].generation), state.generation("b/file"));
+
+        // nothing else changed
+        let start = Instant::now();
+        assert!(
+            db.poll_changes(&subscriptions, &mut state, Duration::from_millis(100))?
+                .is_empty()
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replication() -> anyhow::Result<()> {
+        let primary_client = TestClient::new("test_replication_primary")?;
+        let replica_client = TestClient::new("test_replication_replica")?;
+        let primary = Client::builder(primary_client.client.root())
+            .replication_log()
+            .build()?;
+        let replica = &replica_client.client;
+        let digest = |db: &Client| db.hash_tree("", HashAlgorithm::Sha256);
+        replica.mark_replica(primary.replication_cursor()?)?;
+
+        fs::create_dir(primary.root().join("docs"))?;
+        primary.write_bytes("docs/a", b"a")?;
+        primary.write_bytes("docs/b", b"b")?;
+        let scratch = primary.scratch()?;
+        fs::create_dir(scratch.path.join("q1"))?;
+        fs::write(scratch.path.join("q1/sales"), "sales")?;
+        scratch.promote("reports")?;
+        primary.write_with_ttl("docs/tmp", b"tmp", Duration::ZERO)?;
+        primary.gc_with(&GcOptions {
+            expire_ttl: true,
+            ..Default::default()
+        });
+
+        let records = primary.read_commits_since(0)?;
+        assert_eq!(
+            vec![
+                ReplicatedOp::Write(CommitKind::File),
+                ReplicatedOp::Write(CommitKind::File),
+                ReplicatedOp::Write(CommitKind::Dir),
+                ReplicatedOp::Write(CommitKind::File),
+                ReplicatedOp::Remove,
+            ],
+            records.iter().map(|record| record.op).collect::<Vec<_>>()
+        );
+        assert_eq!(5, replica.apply_commits(&records)?);
+        assert_eq!(digest(&primary)?, digest(replica)?);
+        // applying a batch again changes nothing
+        assert_eq!(5, replica.apply_commits(&records)?);
+        assert!(matches!(
+            replica.write_bytes("docs/a", b"local"),
+            Err(Error::ReadOnlyReplica { .. })
+        ));
+
+        primary.write_bytes("docs/a", b"changed")?;
+        assert_ne!(digest(&primary)?, digest(replica)?);
+        let applied = replica.apply_commits(&primary.read_commits_since(5)?)?;
+        assert_eq!((6, Some(6)), (applied, replica.applied_seq()?));
+        assert_eq!(digest(&primary)?, digest(replica)?);
+
+        // truncated once acknowledged
+        assert_eq!(6, primary.truncate_replication_log(applied)?);
+        assert!(primary.read_commits_since(applied)?.is_empty());
+        assert!(matches!(
+            primary.read_commits_since(0),
+            Err(Error::ReplicationGap {
+                expected: 1,
+                found: 7
+            })
+        ));
+
+        // taking over from the primary
+        assert_eq!(Some(6), replica.unmark_replica()?);
+        replica.write_bytes("docs/a", b"local")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> anyhow::Result<()> {
+        use crate::merge::{self, ConflictKind, MergePolicy, Resolution};
+
+        let test_client = TestClient::new("test_merge")?;
+        let root = test_client.client.root();
+        let write = |tree: &str, files: &[(&str, &str)]| -> anyhow::Result<()> {
+            for (rel, data) in files {
+                let path = root.join(tree).join(rel);
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, data)?;
+            }
+            Ok(())
+        };
+        let shared = [("same", "same"), ("dir/file", "d")];
+        write("base", &shared)?;
+        write("ours", &shared)?;
+        write("theirs", &shared)?;
+        write(
+            "base",
+            &[
+                ("mod_ours", "1"),
+                ("mod_theirs", "1"),
+                ("both", "base"),
+                ("rm_ours", "x"),
+                ("rm_vs_mod", "x"),
+                ("kind", "k"),
+            ],
+        )?;
+        write(
+            "ours",
+            &[
+                ("mod_ours", "2"),
+                ("mod_theirs", "1"),
+                ("both", "ours"),
+                ("dir/new_ours", "n"),
+                ("kind/inner", "i"),
+                ("added", "a"),
+                (".same.lock.sbdb", ""),
+            ],
+        )?;
+        write(
+            "theirs",
+            &[
+                ("mod_ours", "1"),
+                ("mod_theirs", "2"),
+                ("both", "theirs"),
+                ("rm_ours", "x"),
+                ("rm_vs_mod", "y"),
+                ("dir/new_theirs", "t"),
+                ("kind", "k2"),
+                ("added", "a"),
+            ],
+        )?;
+        let (base, ours, theirs) = (root.join("base"), root.join("ours"), root.join("theirs"));
+        let conflicts = |report: &merge::MergeReport| {
+            report
+                .conflicts
+                .iter()
+                .map(|c| (c.path.to_str().unwrap().to_string(), c.kind, c.resolution))
+                .collect::<Vec<_>>()
+        };
+
+        let dest = root.join("failed");
+        let report = merge::three_way(&base, &ours, &theirs, &dest, MergePolicy::Fail)?;
+        assert!(!report.resolved());
+        assert_eq!((3, 2), (report.from_ours, report.from_theirs));
+        assert_eq!(
+            vec![
+                (
+                    "both".into(),
+                    ConflictKind::BothChanged,
+                    Resolution::Unresolved
+                ),
+                (
+                    "kind".into(),
+                    ConflictKind::TypeChanged,
+                    Resolution::Unresolved
+                ),
+                (
+                    "rm_vs_mod".into(),
+                    ConflictKind::ChangedAndRemoved,
+                    Resolution::Unresolved
+                ),
+            ],
+            conflicts(&report)
+        );
+        assert!(!dest.exists());
+
+        let dest = root.join("prefer_ours");
+        let report = merge::three_way(&base, &ours, &theirs, &dest, MergePolicy::PreferOurs)?;
+        assert!(report.resolved());
+        let read = |rel: &str| fs::read_to_string(dest.join(rel));
+        assert_eq!("ours", read("both")?);
+        assert_eq!(("2", "2"), (&*read("mod_ours")?, &*read("mod_theirs")?));
+        assert_eq!("i", read("kind/inner")?);
+        assert_eq!(
+            ("n", "t"),
+            (&*read("dir/new_ours")?, &*read("dir/new_theirs")?)
+        );
+        assert_eq!(("same", "a"), (&*read("same")?, &*read("added")?));
+        assert!(!dest.join("rm_ours").exists());
+        assert!(!dest.join("rm_vs_mod").exists());
+        assert!(!dest.join(".same.lock.sbdb").exists());
+
+        let dest = root.join("prefer_theirs");
+        merge::three_way(&base, &ours, &theirs, &dest, MergePolicy::PreferTheirs)?;
+        let read = |rel: &str| fs::read_to_string(dest.join(rel));
+        assert_eq!(
+            ("theirs", "k2", "y"),
+            (&*read("both")?, &*read("kind")?, &*read("rm_vs_mod")?)
+        );
+        assert!(!dest.join("rm_ours").exists());
+
+        // the content merger goes before the policy, and only sees files
+        let dest = root.join("merged");
+        let report = merge::three_way_with(
+            &base,
+            &ours,
+            &theirs,
+            &dest,
+            MergePolicy::PreferTheirs,
+            &|path, base, ours, theirs| {
+                assert_eq!(Path::new("both"), path);
+                assert_eq!(Some(&b"base"[..]), base);
+                Some([ours, b"+", theirs].concat())
+            },
+        )?;
+        assert_eq!(
+            ("both".into(), ConflictKind::BothChanged, Resolution::Merged),
+            conflicts(&report)[0]
+        );
+        assert_eq!(Resolution::Theirs, report.conflicts[1].resolution);
+        assert_eq!("ours+theirs", fs::read_to_string(dest.join("both"))?);
+
+        assert!(matches!(
+            merge::three_way(&base, &ours, &theirs, &dest, MergePolicy::PreferOurs),
+            Err(Error::AlreadyExists { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_dir_atomic() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_create_dir_atomic")?;
+        let db = &test_client.client;
+        let root = db.root();
+        let payloads = || -> anyhow::Result<usize> {
+            let mut count = 0;
+            for entry in fs::read_dir(root)? {
+                count += entry?.file_name().to_string_lossy().ends_with(".dir.sbdb") as usize;
+            }
+            Ok(count)
+        };
+        let gaurd = db.write_dir("")?;
+
+        assert_eq!(
+            AtomicDirCreation::Created,
+            gaurd.create_dir_atomic("atom", false)?
+        );
+        assert_eq!(1, payloads()?);
+        assert!(root.join("atom").is_symlink());
+        fs::write(root.join("atom/file"), "data")?;
+        let target = fs::read_link(root.join("atom"))?;
+        assert_eq!(
+            AtomicDirCreation::Existed,
+            gaurd.create_dir_atomic("atom", true)?
+        );
+        // nothing was copied or swapped
+        assert_eq!(1, payloads()?);
+        assert_eq!(target, fs::read_link(root.join("atom"))?);
+
+        gaurd.create_dir("plain/nested")?;
+        gaurd.create_dir("plain/nested")?;
+        fs::write(root.join("plain/nested/file"), "data")?;
+        let err = gaurd.create_dir_atomic("plain", false).unwrap_err();
+        assert!(
+            matches!(err, Error::AlreadyExistsAsPlainDir { .. }),
+            "{:?}",
+            err
+        );
+        assert!(!root.join("plain").is_symlink());
+        assert_eq!(1, payloads()?);
+        assert_eq!(
+            AtomicDirCreation::Converted,
+            gaurd.create_dir_atomic("plain", true)?
+        );
+        assert!(root.join("plain").is_symlink());
+        assert_eq!(2, payloads()?);
+        assert_eq!("data", fs::read_to_string(root.join("plain/nested/file"))?);
+
+        fs::write(root.join("file"), "data")?;
+        let err = gaurd.create_dir_atomic("file", true).unwrap_err();
+        assert!(matches!(err, Error::AlreadyExists { .. }), "{:?}", err);
+        assert!(matches!(
+            gaurd.create_dir("a.lock.sbdb"),
+            Err(Error::ReservedName { .. })
+        ));
+        gaurd.release()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_leases() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_leases")?;
+        let db = Client::builder(test_client.client.root())
+            .lease_clock_skew(Duration::ZERO)
+            .build()?;
+        let long = Duration::from_secs(60);
+        let short = Duration::from_millis(50);
+
+        // only one of several contenders gets the lease
+        let winners = AtomicU64::new(0);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (db, winners) = (&db, &winners);
+                s.spawn(move || {
+                    if db
+                        .acquire_lease("lease", &format!("owner {}", i), long)
+                        .unwrap()
+                        .is_some()
+                    {
+                        winners.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(1, winners.load(Ordering::SeqCst));
+        fs::remove_file(db.root().join("lease"))?;
+
+        // the owner can take its lease again, others only once it expired or was released
+        // dropping a lease keeps it
+        drop(db.acquire_lease("lease", "a", short)?.unwrap());
+        assert!(db.acquire_lease("lease", "b", long)?.is_none());
+        let a = db.acquire_lease("lease", "a", short)?.unwrap();
+        thread::sleep(short * 2);
+        let b = db.acquire_lease("lease", "b", short)?.unwrap();
+        assert_eq!("b", b.owner());
+        assert!(matches!(a.renew(long), Err(Error::LeaseLost { .. })));
+        a.release()?;
+        assert!(db.acquire_lease("lease", "c", long)?.is_none());
+
+        // renewing keeps the lease past its first expiry
+        b.renew(long)?;
+        thread::sleep(short * 2);
+        assert!(db.acquire_lease("lease", "c", long)?.is_none());
+        b.release()?;
+        assert!(!db.root().join("lease").exists());
+        let c = db.acquire_lease("lease", "c", short)?.unwrap();
+
+        // a lease is respected past its expiry by the clock skew tolerance
+        thread::sleep(short * 2);
+        let skewed = Client::builder(db.root()).build()?;
+        assert!(skewed.acquire_lease("lease", "d", long)?.is_none());
+        c.renew(long)?;
+
+        fs::write(db.root().join("lease"), "soon\nc")?;
+        assert!(matches!(c.renew(long), Err(Error::Decode { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_names() -> anyhow::Result<()> {
+        use crate::{GcOptions, IssueKind, RESERVED_SUFFIXES, VerifyOptions};
+
+        let test_client = TestClient::new("test_reserved_names")?;
+        let db = &test_client.client;
+        let root = db.root();
+        fs::create_dir(root.join("dir"))?;
+        let reserved = |result: crate::Result<()>, expected: &str| match result {
+            Err(Error::ReservedName { suffix, .. }) => suffix == expected,
+            _ => false,
+        };
+        for suffix in RESERVED_SUFFIXES {
+            for name in [format!("data{}", suffix), format!(".data{}", suffix)] {
+                assert!(reserved(db.write_file(&name).map(drop), suffix), "{}", name);
+                assert!(reserved(db.write_bytes(&name, b"x"), suffix), "{}", name);
+                assert!(reserved(
+                    db.write_file(format!("{}/a", name)).map(drop),
+                    suffix
+                ));
+                assert!(reserved(
+                    db.write_dir("dir")?.write_file(&name).map(drop),
+                    suffix
+                ));
+                assert!(reserved(db.tx().write(&name).begin().map(drop), suffix));
+                assert!(reserved(db.tx().read(&name).begin().map(drop), suffix));
+                #[cfg(feature = "binary")]
+                assert!(reserved(
+                    db.collection::<u32, _>(&name).put("key", &1),
+                    suffix
+                ));
+                assert!(!root.join(&name).exists(), "{}", name);
+            }
+        }
+        #[cfg(feature = "tar")]
+        assert!(reserved(
+            db.import_tar(std::io::empty(), "data.tmp.sbdb"),
+            ".tmp.sbdb"
+        ));
+
+        // nor can anything in the internal directory, which would keep the root from opening
+        let meta = fs::read(root.join(".sbdb/meta.json"))?;
+        assert!(reserved(
+            db.write_bytes(".sbdb/meta.json", b"junk"),
+            ".sbdb"
+        ));
+        assert!(reserved(db.write_dir(".sbdb").map(drop), ".sbdb"));
+        assert!(reserved(
+            db.write_dir("")?.write_file(".sbdb/meta.json").map(drop),
+            ".sbdb"
+        ));
+        assert!(reserved(
+            db.tx().write(".sbdb/meta.json").begin().map(drop),
+            ".sbdb"
+        ));
+        assert!(reserved(
+            db.tx().read(".sbdb/meta.json").begin().map(drop),
+            ".sbdb"
+        ));
+        assert!(reserved(db.remove_prefix(".sbdb/meta").map(drop), ".sbdb"));
+        assert_eq!(meta, fs::read(root.join(".sbdb/meta.json"))?);
+        Client::open(root)?;
+
+        // names that merely contain sbdb are fine
+        for name in [
+            "sbdb",
+            "data.sbdb.json",
+            "lock.sbdb.txt",
+            "sbdb.lock",
+            ".sbdbrc",
+        ] {
+            db.write_bytes(name, b"user")?;
+            assert_eq!("user", fs::read_to_string(root.join(name))?);
+        }
+
+        // files named like artifacts by someone else are reported and left alone
+        fs::write(root.join(".data.lock.sbdb"), "user data")?;
+        fs::write(root.join(".sbdb.queue.sbdb"), "user data")?;
+        let mut collisions = db
+            .verify("", &VerifyOptions::default())?
+            .issues
+            .into_iter()
+            .map(|issue| {
+                assert_eq!(IssueKind::SuffixCollision, issue.kind);
+                assert!(!issue.repairable);
+                issue.path
+            })
+            .collect::<Vec<_>>();
+        collisions.sort();
+        assert_eq!(
+            vec![
+                PathBuf::from(".data.lock.sbdb"),
+                PathBuf::from(".sbdb.queue.sbdb")
+            ],
+            collisions
+        );
+        db.gc_with(&GcOptions::default());
+        assert_eq!(
+            "user data",
+            fs::read_to_string(root.join(".data.lock.sbdb"))?
+        );
+        assert_eq!(
+            "user data",
+            fs::read_to_string(root.join(".sbdb.queue.sbdb"))?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_artifact_permissions() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_client = TestClient::new("test_artifact_permissions")?;
+        let root = test_client.client.root();
+        let mode = |rpath: &str| -> anyhow::Result<u32> {
+            Ok(fs::metadata(root.join(rpath))?.permissions().mode() & 0o777)
+        };
+        fs::create_dir_all(root.join("dir"))?;
+        fs::write(root.join("dir/a.txt"), "a")?;
+        fs::set_permissions(root.join("dir/a.txt"), fs::Permissions::from_mode(0o644))?;
+        let db = Client::builder(root).artifact_permissions(0o600).build()?;
+
+        let cow = db.write_file("dir/a.txt")?.cow()?;
+        assert_eq!(0o600, mode("dir/.a.txt.tmp.sbdb")?);
+        cow.commit()?;
+        assert_eq!(0o600, mode("dir/a.txt")?);
+        for lock in [
+            ".dir.lock.sbdb",
+            ".dir.queue.sbdb",
+            "dir/.a.txt.lock.sbdb",
+            "dir/.a.txt.queue.sbdb",
+        ] {
+            assert_eq!(0o600, mode(lock)?, "{}", lock);
+        }
+        // small writes go through an unnamed file, larger ones through a named one
+        db.write_bytes("dir/small.txt", b"small")?;
+        assert_eq!(0o600, mode("dir/small.txt")?);
+        db.write_bytes("dir/large.txt", &vec![0; crate::cow::TMPFILE_MAX_LEN + 1])?;
+        assert_eq!(0o600, mode("dir/large.txt")?);
+
+        // directories can still be entered by their owner, their contents keep their permissions
+        let gaurd = db.write_dir("dir")?;
+        let cow = gaurd.cow()?;
+        assert_eq!(0o700, mode(".dir.tmp.sbdb")?);
+        cow.commit()?;
+        gaurd.release()?;
+        assert_eq!(0o700, mode("dir")?);
+        assert_eq!(0o600, mode("dir/a.txt")?);
+        let cow = db.write_dir("atomic")?.cow_atomic()?;
+        assert_eq!(0o700, fs::metadata(&cow.path)?.permissions().mode() & 0o777);
+        cow.commit()?;
+        assert_eq!(0o700, mode("atomic")?);
+        let cow = db.write_dir("atomic")?.cow_atomic()?;
+        assert_eq!(0o700, fs::metadata(&cow.path)?.permissions().mode() & 0o777);
+        cow.commit()?;
+
+        // lock files that exist already keep their permissions
+        let db = Client::builder(root)
+            .artifact_permissions(0o600)
+            .group_writable_locks(true)
+            .build()?;
+        db.write_bytes("dir/b.txt", b"b")?;
+        assert_eq!(0o660, mode("dir/.b.txt.lock.sbdb")?);
+        assert_eq!(0o660, mode("dir/.b.txt.queue.sbdb")?);
+        assert_eq!(0o600, mode("dir/b.txt")?);
+        assert_eq!(0o600, mode(".dir.lock.sbdb")?);
+        let db = Client::builder(root).group_writable_locks(true).build()?;
+        db.write_bytes("dir/c.txt", b"c")?;
+        assert_eq!(0o664, mode("dir/.c.txt.lock.sbdb")?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "testkit")]
+    fn test_atomic_dirs_supported() -> anyhow::Result<()> {
+        use std::{io::ErrorKind, path::Path};
+
+        use crate::{
+            INTERNAL_DIR, StdVfs, Vfs,
+            testkit::{FaultVfs, VfsOp},
+        };
+
+        // whatever the platform and privileges, the probe agrees with an attempt to create a link
+        let test_client = TestClient::new("test_atomic_dirs_supported")?;
+        let db = &test_client.client;
+        let link = db.root().join("link");
+        let expected = StdVfs.symlink_dir(Path::new("."), &link).is_ok();
+        assert_eq!(expected, db.atomic_dirs_supported());
+        if expected {
+            #[cfg(windows)]
+            fs::remove_dir(&link)?;
+            #[cfg(not(windows))]
+            fs::remove_file(&link)?;
+        }
+        // only the root marker, its lock files and the meta file are left
+        assert_eq!(4, fs::read_dir(db.root().join(INTERNAL_DIR))?.count());
+
+        // without the privilege nothing is copied
+        let root =
+            std::env::temp_dir().join("test_atomic_dirs_unsupported-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        vfs.fail(VfsOp::SymlinkDir, 1, ErrorKind::PermissionDenied);
+        let db = Client::builder(&root).vfs(vfs.clone()).build()?;
+        let _cleanup = TestClient {
+            client: db.clone(),
+            root: root.clone(),
+        };
+        fs::create_dir_all(root.join("dir"))?;
+        fs::write(root.join("dir/file"), "contents")?;
+        let Err(err) = db.write_dir("dir")?.cow_atomic() else {
+            anyhow::bail!("copied an atomic directory without symlink support");
+        };
+        assert!(
+            matches!(err, Error::AtomicDirsUnsupported { .. }),
+            "{:?}",
+            err
+        );
+        let err = db
+            .write_dir("")?
+            .create_dir_atomic("new", false)
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::AtomicDirsUnsupported { .. }),
+            "{:?}",
+            err
+        );
+        assert!(!db.atomic_dirs_supported());
+        // probed once
+        assert_eq!(1, vfs.calls(VfsOp::SymlinkDir));
+        assert_eq!(0, vfs.calls(VfsOp::Copy));
+        let mut names = fs::read_dir(&root)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.retain(|name| {
+            !matches!(
+                artifact::classify(name),
+                Some(ArtifactKind::Lock | ArtifactKind::Queue)
+            )
+        });
+        names.sort();
+        assert_eq!(
+            vec![OsString::from(INTERNAL_DIR), OsString::from("dir")],
+            names
+        );
+        assert_eq!("contents", fs::read_to_string(root.join("dir/file"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_dirs() -> anyhow::Result<()> {
+        use std::sync::mpsc;
+
+        let test_client = TestClient::new("test_lock_dirs")?;
+        let root = test_client.client.root();
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("dir/file"), "")?;
+        let lock_dir = root.join("dir/.file.lockd.sbdb");
+        let names = |dir: &str| -> anyhow::Result<Vec<OsString>> {
+            let mut names = fs::read_dir(root.join(dir))?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+
+        let db = Client::builder(root)
+            .lock_backend(LockBackend::atomic_lock_dir())
+            .network_fs(crate::NetworkFsPolicy::UseLockDirs)
+            .build()?;
+        let owner = {
+            let _tx = db.tx().write("dir/file").read("dir/other").begin()?;
+            let owner = fs::read_to_string(lock_dir.join("owner"))?;
+            assert_eq!(
+                vec![
+                    OsString::from(".file.lockd.sbdb"),
+                    OsString::from(".other.lockd.sbdb"),
+                    OsString::from("file")
+                ],
+                names("dir")?
+            );
+            owner
+        };
+        let mut lines = owner.lines();
+        let host = lines.next().context("missing host")?.to_string();
+        assert_eq!(Some(std::process::id().to_string().as_str()), lines.next());
+        // released, without lock files left behind
+        assert_eq!(vec![OsString::from("file")], names("dir")?);
+
+        // a lock held elsewhere is left alone by gc and waited for
+        let hold_elsewhere = || -> anyhow::Result<()> {
+            fs::create_dir(&lock_dir)?;
+            fs::write(lock_dir.join("owner"), "elsewhere\n1\n")?;
+            Ok(())
+        };
+        hold_elsewhere()?;
+        let report = db.gc();
+        assert_eq!((0, 0), (report.errors, report.lock_files_removed));
+        assert!(lock_dir.exists());
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let _ = tx.send(db.read_file("dir/file").map(drop));
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        fs::remove_dir_all(&lock_dir)?;
+        rx.recv_timeout(Duration::from_secs(10))??;
+        waiter.join().unwrap();
+
+        // until its lease runs out
+        hold_elsewhere()?;
+        thread::sleep(Duration::from_millis(10));
+        let expired = Client::builder(root)
+            .lock_backend(LockBackend::AtomicLockDir {
+                lease: Duration::from_millis(5),
+            })
+            .build()?;
+        drop(expired.read_file("dir/file")?);
+        assert!(!lock_dir.exists());
+
+        // or its process is gone, which is only known on the same host on linux
+        if cfg!(target_os = "linux") {
+            let mut child = std::process::Command::new("true").spawn()?;
+            child.wait()?;
+            fs::create_dir(&lock_dir)?;
+            fs::write(
+                lock_dir.join("owner"),
+                format!("{}\n{}\n", host, child.id()),
+            )?;
+            drop(db.write_file("dir/file")?);
+            assert!(!lock_dir.exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_puuid() {
+        use std::collections::HashSet;
+
+        use crate::{Case, PUUID_LEN, parse_puuid, puuid_with};
+
+        let mut seen = HashSet::new();
+        let mut counts = [0usize; 36];
+        for _ in 0..1_000_000 {
+            let id = puuid();
+            assert_eq!(PUUID_LEN, id.len());
+            for b in id.bytes() {
+                let i = match b {
+                    b'A'..=b'Z' => b - b'A',
+                    b'0'..=b'9' => 26 + b - b'0',
+                    _ => panic!("{id} has {}", b as char),
+                };
+                counts[i as usize] += 1;
+            }
+            assert_eq!(Some(Case::Upper), parse_puuid(&id));
+            assert!(seen.insert(id));
+        }
+        // every character shows up about once in 36 draws
+        let expected = PUUID_LEN * 1_000_000 / 36;
+        for count in counts {
+            assert!(count.abs_diff(expected) < expected / 50, "{counts:?}");
+        }
+
+        let lower = puuid_with(8, Case::Lower);
+        assert_eq!(8, lower.len());
+        assert!(
+            lower
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        );
+        assert!(matches!(
+            parse_puuid(&lower),
+            Some(Case::Lower | Case::Upper)
+        ));
+        assert_eq!(None, parse_puuid(""));
+        assert_eq!(None, parse_puuid("aB3"));
+        assert_eq!(None, parse_puuid("AB-3"));
+
+        // other threads draw from a generator of their own
+        let other = thread::spawn(puuid).join().unwrap();
+        assert!(!seen.contains(&other));
+
+        // the middle of a payload name has to be a puuid
+        let name = |id: &str| OsString::from(format!(".x.{id}.dir.sbdb"));
+        assert!(artifact::parse(&name(&puuid())).is_some());
+        assert!(artifact::parse(&name(&"a".repeat(PUUID_LEN))).is_none());
+        assert!(artifact::parse(&name(&"-".repeat(PUUID_LEN))).is_none());
+    }
+
+    #[test]
+    fn test_client_clones() -> anyhow::Result<()> {
+        use crate::{HeldLock, LockMode};
+
+        let test_client = TestClient::new("test_client_clones")?;
+        let warnings = Arc::new(AtomicU64::new(0));
+        let db = {
+            let warnings = warnings.clone();
+            Client::builder(test_client.client.root())
+                .max_depth(7)
+                .io_deadline(Duration::from_secs(30))
+                .stream_buffer_len(1024)
+                .lock_file_cache(16)
+                .on_warning(move |_| {
+                    warnings.fetch_add(1, Ordering::Relaxed);
+                })
+                .build()?
+        };
+        db.write_dir("")?.create_dir("dir")?;
+        let held = |client: &Client| -> Vec<(String, LockMode)> {
+            client
+                .held_locks()
+                .into_iter()
+                .map(|HeldLock { path, mode }| (path.to_string_lossy().into_owned(), mode))
+                .collect()
+        };
+
+        // clones are the same client
+        let clone = db.clone();
+        assert!(Arc::ptr_eq(&db.inner, &clone.inner));
+        {
+            let _gaurd = db.write_file("dir/file")?;
+            let expected = vec![
+                (String::new(), LockMode::Read),
+                ("dir".to_string(), LockMode::Read),
+                ("dir/file".to_string(), LockMode::Write),
+            ];
+            assert_eq!(expected, held(&clone));
+            // whichever thread holds the locks
+            let tx = thread::spawn(move || clone.tx().write("dir/other").begin())
+                .join()
+                .unwrap()?;
+            assert_eq!(6, db.held_locks().len());
+            drop(tx);
+        }
+        assert!(db.held_locks().is_empty());
+
+        // a detached client has the same options, but caches of its own
+        let detached = db.detach();
+        for client in [&db.clone(), &detached] {
+            assert_eq!(
+                (7, Some(Duration::from_secs(30)), 1024),
+                (
+                    client.inner.max_depth,
+                    client.inner.io_deadline,
+                    client.inner.stream_buffer_len
+                )
+            );
+            let on_warning = client.inner.on_warning.as_ref().context("callback")?;
+            on_warning(Warning::InnerRoots {
+                path: db.root().clone(),
+                inner: Vec::new(),
+            });
+        }
+        assert_eq!(2, warnings.load(Ordering::Relaxed));
+        let (Some(cache), Some(detached_cache)) =
+            (&db.inner.lock_cache, &detached.inner.lock_cache)
+        else {
+            anyhow::bail!("lock file cache was dropped");
+        };
+        assert!(!Arc::ptr_eq(cache, detached_cache));
+        {
+            let _gaurd = detached.read_file("dir")?;
+            assert!(db.held_locks().is_empty());
+            assert_eq!(2, detached.held_locks().len());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_across_threads() -> anyhow::Result<()> {
+        use std::sync::mpsc;
+
+        use crate::{Lock, LockStatus};
+
+        let test = TestClient::new("test_tx_across_threads")?;
+        let db = &test.client;
+        fs::create_dir(test.root.join("dir"))?;
+        fs::write(test.root.join("dir/file"), "old")?;
+
+        // begun on one thread
+        let (send_tx, recv_tx) = mpsc::channel();
+        {
+            let db = db.clone();
+            thread::spawn(move || send_tx.send(db.tx().write("dir").begin()).unwrap())
+                .join()
+                .unwrap();
+        }
+        let held = || Lock::probe(test.root.join("dir"));
+        assert_eq!(LockStatus::Write, held()?);
+
+        // kept out on another
+        let (send_read, recv_read) = mpsc::channel();
+        let reader = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let read = || -> anyhow::Result<String> {
+                    let gaurd = db.read_file("dir/file")?;
+                    Ok(fs::read_to_string(&gaurd.path)?)
+                };
+                let _ = send_read.send(read());
+            })
+        };
+
+        // and committed on a third
+        let (send_committed, recv_committed) = mpsc::channel();
+        let (send_release, recv_release) = mpsc::channel::<()>();
+        let committer = thread::spawn(move || -> crate::Result<()> {
+            let tx = recv_tx.recv().unwrap()?;
+            let cow = tx.dir_cow("dir")?;
+            fs::write(cow.path.join("file"), "new").unwrap();
+            cow.commit()?;
+            send_committed.send(()).unwrap();
+            recv_release.recv().unwrap();
+            drop(tx);
+            Ok(())
+        });
+
+        recv_committed.recv_timeout(Duration::from_secs(10))?;
+        assert!(recv_read.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(LockStatus::Write, held()?);
+        send_release.send(())?;
+        committer.join().unwrap()?;
+        assert_eq!("new", recv_read.recv_timeout(Duration::from_secs(10))??);
+        reader.join().unwrap();
+        assert_eq!(LockStatus::Free, held()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixtures() -> anyhow::Result<()> {
+        use crate::fixture::{TreeBuilder, assert_tree_eq, interrupted_dir_commit, orphaned_temp};
+
+        let test = TestClient::new("test_fixtures")?;
+        let db = &test.client;
+        let tree = TreeBuilder::new()
+            .file("a.txt", "a")
+            .dir(
+                "dir",
+                TreeBuilder::new()
+                    .file("b.txt", "b")
+                    .dir("empty", TreeBuilder::new()),
+            )
+            .atomic_dir("atom", TreeBuilder::new().file("c.txt", "c"))
+            .symlink("link", "a.txt");
+        tree.build(&test.root)?;
+        assert!(fs::symlink_metadata(test.root.join("atom"))?.is_symlink());
+        assert_eq!("c", fs::read_to_string(test.root.join("atom/c.txt"))?);
+
+        // lock files and copies are not part of the tree
+        db.write_dir("dir")?.cow()?.commit()?;
+        db.write_dir("atom")?.cow_atomic()?.commit()?;
+        orphaned_temp(test.root.join("a.txt"), "tmp")?;
+        assert_tree_eq(&test.root, &tree);
+        assert_ne!(
+            TreeBuilder::read(&test.root)?,
+            tree.clone().file("a.txt", "changed")
+        );
+
+        let changed = TreeBuilder::new().file("b.txt", "changed");
+        let commit = interrupted_dir_commit(test.root.join("dir"), &changed)?;
+        assert!(!test.root.join("dir").exists());
+        assert_eq!(changed, TreeBuilder::read(&commit.copy)?);
+        let report = db.recover();
+        assert_eq!((1, 2), (report.backups_restored, report.temps_removed));
+        assert_tree_eq(&test.root, &tree);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifact_names() {
+        // every suffix is recognised, with a puuid in front where one is expected
+        for (suffix, kind, has_puuid) in artifact::SUFFIXES {
+            let name = if has_puuid {
+                format!(".a.b.{}{suffix}", puuid())
+            } else {
+                format!(".a.b{suffix}")
+            };
+            let name = OsString::from(name);
+            assert_eq!(Some(kind), artifact::classify(&name), "{name:?}");
+            assert_eq!(Some(OsString::from("a.b")), artifact::original_name(&name));
+            if has_puuid {
+                let bare = OsString::from(format!(".a.b{suffix}"));
+                assert_eq!(None, artifact::classify(&bare), "{bare:?}");
+            }
+        }
+        assert_eq!(
+            Some(ArtifactKind::LockDir),
+            artifact::classify(".x.lockbrk.sbdb".as_ref())
+        );
+
+        for name in ["a.lock.sbdb", ".lock.sbdb", "a.txt", ".a.sbdb", ".sbdb"] {
+            assert_eq!(None, artifact::classify(name.as_ref()), "{name}");
+            assert_eq!(None, artifact::original_name(name.as_ref()), "{name}");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "strict-locking")]
+    fn test_strict_locking() -> anyhow::Result<()> {
+        use crate::is_atomic_dir_link;
+
+        let test_client = TestClient::new("test_strict_locking")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("dir")?;
+        db.write_bytes("dir/a.txt", b"a")?;
+        let path = db.root().join("dir/a.txt");
+
+        // a copy of an entry that nobody locked is a bug, it panics in debug builds and fails otherwise
+        let refused = |path: &Path| match std::panic::catch_unwind(|| {
+            crate::file_cow(path).and_then(|cow| cow.commit())
+        }) {
+            Err(_) => cfg!(debug_assertions),
+            Ok(result) => matches!(result, Err(Error::NotWriteLocked { path: p }) if p == path),
+        };
+        assert!(refused(&path));
+
+        // a lock on an ancestor covers it for as long as it is held
+        {
+            let gaurd = db.write_dir("dir")?;
+            assert!(!refused(&path));
+            let cow = gaurd.cow()?;
+            assert_eq!(
+                AtomicDirCreation::Created,
+                cow.create_nested_atomic("atomic")?
+            );
+            cow.commit()?;
+        }
+        assert!(refused(&path));
+        assert!(is_atomic_dir_link(&db.root().join("dir/atomic"))?);
+
+        // the unchecked functions do not care
+        unlocked::file_cow_unchecked(&path)?.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lock-order-check")]
+    fn test_lock_order_check() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_lock_order_check")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("dir")?;
+
+        // taking locks that sort before one this thread holds is a bug, it panics in debug builds and fails
+        // otherwise, the root's lock taken first for `a` sorts before `b`
+        let violation = |gaurd: &crate::FileWriteGaurd| match std::panic::catch_unwind(
+            std::panic::AssertUnwindSafe(|| db.write_file("a").map(drop)),
+        ) {
+            Err(_) => cfg!(debug_assertions),
+            Ok(result) => matches!(
+                result,
+                Err(Error::LockOrderViolation { held, .. }) if held == gaurd.path
+            ),
+        };
+        {
+            let gaurd = db.write_file("b")?;
+            assert!(violation(&gaurd));
+        }
+        // nothing is held any more
+        db.write_file("a")?.release()?;
+
+        // locks below one that is held are taken relative to it, in a transaction too, and other threads
+        // keep their own order
+        {
+            let dir = db.write_dir("dir")?;
+            dir.write_file("b")?;
+            dir.read_file("a")?;
+            thread::scope(|s| s.spawn(|| db.write_file("a").map(drop)).join().unwrap())?;
+        }
+        let tx = db.tx().write("dir").write("a").read("b").begin()?;
+        tx.write_file("dir/a")?;
+        tx.read_file("b")?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_resolved_path() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_resolved_path")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir_atomic("atomic", false)?;
+        db.write_bytes("atomic/a.txt", b"1")?;
+        db.write_dir("")?.create_dir("plain")?;
+        assert_eq!(
+            db.root().join("plain"),
+            db.read_dir("plain")?.resolved_path()
+        );
+
+        let gaurd = db.read_dir("atomic")?;
+        let resolved = gaurd.resolved_path().to_path_buf();
+        assert_eq!(
+            Some(ArtifactKind::AtomicDir),
+            artifact::classify(resolved.file_name().unwrap())
+        );
+
+        // a new version can not be committed while the reader holds its lock
+        let (send, recv) = std::sync::mpsc::channel();
+        thread::scope(|scope| -> anyhow::Result<()> {
+            let writer = scope.spawn(|| -> anyhow::Result<PathBuf> {
+                let gaurd = db.write_dir("atomic")?;
+                let payload = gaurd.resolved_path().to_path_buf();
+                let cow = gaurd.cow_atomic()?;
+                fs::write(cow.path.join("a.txt"), "2")?;
+                cow.commit()?;
+                send.send(()).unwrap();
+                Ok(payload)
+            });
+            assert!(recv.recv_timeout(Duration::from_millis(200)).is_err());
+            assert_eq!(resolved, gaurd.resolved_path());
+            assert_eq!("1", fs::read_to_string(resolved.join("a.txt"))?);
+            drop(gaurd);
+            assert_eq!(resolved, writer.join().unwrap()?);
+            Ok(())
+        })?;
+
+        let gaurd = db.read_dir("atomic")?;
+        assert_ne!(resolved, gaurd.resolved_path());
+        assert_eq!(
+            "2",
+            fs::read_to_string(gaurd.resolved_path().join("a.txt"))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_escalation() -> anyhow::Result<()> {
+        use std::sync::mpsc;
+
+        use crate::{Lock, LockStatus};
+
+        let metrics = Arc::new(AtomicMetrics::default());
+        let test_client = TestClient::new("test_tx_escalation")?;
+        let db = Client::builder(test_client.client.root())
+            .metrics(metrics.clone())
+            .build()?;
+        db.write_dir("")?.create_dir("dir/sub")?;
+        let files: Vec<String> = (0..5).map(|i| format!("dir/f{i}")).collect();
+        let write_locks = || metrics.write_locks.load(Ordering::Relaxed);
+        let setup = write_locks();
+
+        // at the threshold every file is locked on its own
+        {
+            let tx = files
+                .iter()
+                .fold(db.tx(), |tx, f| tx.write(f))
+                .escalate_above(5)
+                .begin()?;
+            assert!(tx.stats().escalated.is_empty());
+            assert_eq!(5, tx.stats().write_locks);
+        }
+        assert_eq!(setup + 5, write_locks());
+        for i in 0..5 {
+            fs::remove_file(db.root().join(format!("dir/.f{i}.lock.sbdb")))?;
+        }
+
+        // above it the directory is locked instead, covering writes further down too
+        let tx = files
+            .iter()
+            .fold(db.tx(), |tx, f| tx.write(f))
+            .write("dir/sub/g")
+            .read("dir/sub")
+            .escalate_above(4)
+            .begin()?;
+        assert_eq!(vec![PathBuf::from("dir")], tx.stats().escalated);
+        assert_eq!(1, tx.stats().write_locks);
+        assert_eq!(1, tx.stats().read_locks);
+        assert_eq!(setup + 6, write_locks());
+        assert!(!db.root().join("dir/.f0.lock.sbdb").exists());
+        assert_eq!(LockStatus::Write, Lock::probe(db.root().join("dir"))?);
+
+        // writers of a single file in the directory wait for the transaction
+        let (send, recv) = mpsc::channel();
+        thread::scope(|scope| -> anyhow::Result<()> {
+            scope.spawn(|| {
+                let written = db.write_bytes("dir/f0", b"later");
+                send.send(written).unwrap();
+            });
+            assert!(recv.recv_timeout(Duration::from_millis(200)).is_err());
+            drop(tx);
+            Ok(recv.recv()??)
+        })?;
+        assert_eq!("later", fs::read_to_string(db.root().join("dir/f0"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_checkpoint() -> anyhow::Result<()> {
+        use std::sync::mpsc;
+
+        let test_client = TestClient::new("test_tx_checkpoint")?;
+        let db = Client::builder(test_client.client.root())
+            .track_generations()
+            .build()?;
+        db.write_dir("")?.create_dir("dir")?;
+        db.write_bytes("dir/in.txt", b"1")?;
+        db.write_bytes("dir/other.txt", b"1")?;
+        // entries without a counter of their own would report commits anywhere in their parent
+        db.write_bytes("out.txt", b"1")?;
+
+        let tx = db.tx().read("dir/in.txt").write("out.txt").begin()?;
+        assert_eq!("1", fs::read_to_string(tx.read_file("dir/in.txt")?.path)?);
+
+        // nothing changed, the locks are simply taken again, and commits next to the declared entries do
+        // not count as changes
+        let builder = tx.checkpoint()?;
+        db.write_bytes("dir/other.txt", b"2")?;
+        let tx = builder.begin()?;
+
+        // while the locks are held, writers wait for the transaction
+        let (send, recv) = mpsc::channel();
+        thread::scope(|scope| -> anyhow::Result<()> {
+            scope.spawn(|| {
+                let written = db.write_bytes("dir/in.txt", b"2");
+                send.send(written).unwrap();
+            });
+            assert!(recv.recv_timeout(Duration::from_millis(200)).is_err());
+            // after a checkpoint they get in, which the resumed transaction notices
+            let builder = tx.checkpoint()?;
+            recv.recv()??;
+            match builder.begin() {
+                Err(crate::Error::Conflict { path }) => {
+                    assert_eq!(db.root().join("dir/in.txt"), path)
+                }
+                other => panic!("expected a conflict, got {:?}", other.map(|_| ())),
+            }
+            Ok(())
+        })?;
+
+        // the conflicting transaction released its locks again, and its own commits are no conflict
+        let tx = db.tx().read("dir/in.txt").write("out.txt").begin()?;
+        tx.write_file("out.txt")?.cow()?.commit()?;
+        let builder = tx.checkpoint()?;
+        builder.begin()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_tx() -> anyhow::Result<()> {
+        use std::sync::{atomic::AtomicU32, mpsc};
+
+        use crate::{TryPolicy, TryTxError};
+
+        let test_client = TestClient::new("test_try_tx")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("reports")?;
+        db.write_bytes("reports/2024", b"old")?;
+
+        // a lock held elsewhere fails the transaction at once, and the locks taken before it are released
+        for backend in [LockBackend::Flock, LockBackend::atomic_lock_dir()] {
+            let db = Client::builder(db.root()).lock_backend(backend).build()?;
+            let gaurd = db.write_file("reports/2024")?;
+            thread::scope(|scope| {
+                scope
+                    .spawn(|| match db.tx().write("reports/2024").try_begin() {
+                        Err(Error::WouldBlock { path }) => {
+                            assert_eq!(db.root().join("reports/2024"), path)
+                        }
+                        other => panic!("expected to be blocked, got {:?}", other.map(|_| ())),
+                    })
+                    .join()
+                    .unwrap()
+            });
+            drop(gaurd);
+            db.tx().write("reports/2024").try_begin()?;
+        }
+
+        // as does the lock of a missing parent that would be created for the transaction
+        let gaurd = db.write_file("archive")?;
+        thread::scope(|scope| {
+            scope
+                .spawn(|| match db.tx().write("archive/2023/q1").try_begin() {
+                    Err(Error::WouldBlock { path }) => assert_eq!(db.root().join("archive"), path),
+                    other => panic!("expected to be blocked, got {:?}", other.map(|_| ())),
+                })
+                .join()
+                .unwrap()
+        });
+        drop(gaurd);
+        db.tx().write("archive/2023/q1").try_begin()?;
+
+        // held for two backoff periods, the third attempt gets in after waiting for both retries
+        let backoff = Duration::from_millis(100);
+        let policy = TryPolicy {
+            attempts: 3,
+            base_backoff: backoff,
+            jitter: Duration::ZERO,
+            total_budget: None,
+        };
+        let attempts = AtomicU32::new(0);
+        let spec = |tx: crate::TxBuilder| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            tx.read("reports").write("reports/2024")
+        };
+        let (send, recv) = mpsc::channel();
+        let elapsed = thread::scope(|scope| -> anyhow::Result<Duration> {
+            scope.spawn(|| {
+                let gaurd = db.write_file("reports/2024").unwrap();
+                send.send(()).unwrap();
+                thread::sleep(backoff * 2);
+                drop(gaurd);
+            });
+            recv.recv()?;
+            let start = Instant::now();
+            db.try_tx(
+                spec,
+                |tx| {
+                    let cp = tx.write_file("reports/2024")?.cow()?;
+                    fs::write(&cp.path, "new").unwrap();
+                    cp.commit()
+                },
+                &policy,
+            )?;
+            Ok(start.elapsed())
+        })?;
+        assert_eq!(3, attempts.load(Ordering::Relaxed));
+        assert_eq!("new", fs::read_to_string(db.root().join("reports/2024"))?);
+        // the retries wait one and then two backoffs, a fourth attempt would come after another four
+        assert!(elapsed >= backoff * 3, "{elapsed:?}");
+        assert!(elapsed < backoff * 7, "{elapsed:?}");
+
+        // held throughout, the attempts run out and the body is never called
+        let gaurd = db.write_file("reports/2024")?;
+        let exhausted = |policy: TryPolicy| {
+            attempts.store(0, Ordering::Relaxed);
+            thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        let start = Instant::now();
+                        let result = db.try_tx(
+                            spec,
+                            |_| -> crate::Result<()> { panic!("began a blocked transaction") },
+                            &policy,
+                        );
+                        (result, start.elapsed())
+                    })
+                    .join()
+                    .unwrap()
+            })
+        };
+        let policy = TryPolicy {
+            attempts: 2,
+            base_backoff: Duration::from_millis(10),
+            ..TryPolicy::default()
+        };
+        let (result, _) = exhausted(policy);
+        let err = result.err().context("began a blocked transaction")?;
+        let TryTxError::Contended {
+            path, attempts: n, ..
+        } = &err
+        else {
+            anyhow::bail!("unexpected error: {err:?}");
+        };
+        assert_eq!((Path::new("reports/2024"), 2), (path.as_path(), *n));
+        assert!(err.to_string().contains("blocked on reports/2024"), "{err}");
+        assert_eq!(2, attempts.load(Ordering::Relaxed));
+
+        // the budget ends the attempts before the next wait would run past it
+        let budget = Duration::from_millis(120);
+        let policy = TryPolicy {
+            attempts: 10,
+            base_backoff: Duration::from_millis(50),
+            jitter: Duration::ZERO,
+            total_budget: Some(budget),
+        };
+        let (result, elapsed) = exhausted(policy);
+        assert!(matches!(
+            result,
+            Err(TryTxError::Contended { attempts: 2, .. })
+        ));
+        assert!(elapsed < budget, "{elapsed:?}");
+        drop(gaurd);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_inside_atomic_dir() -> anyhow::Result<()> {
+        const ROUNDS: usize = 20;
+
+        let test_client = TestClient::new("test_tx_inside_atomic_dir")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir_atomic("nested", false)?;
+        db.write_dir("nested")?.create_dir("writes")?;
+        db.write_bytes("nested/writes/init.txt", b"init")?;
+
+        // each round commits a new payload of the directory while a transaction writes below it
+        thread::scope(|scope| -> anyhow::Result<()> {
+            let committer = scope.spawn(|| -> anyhow::Result<()> {
+                for i in 0..ROUNDS {
+                    let gaurd = db.write_dir("nested")?;
+                    let cp = gaurd.cow_atomic()?;
+                    fs::write(cp.path.join(format!("commit{i}.txt")), b"commit")?;
+                    cp.commit()?;
+                }
+                Ok(())
+            });
+            for i in 0..ROUNDS {
+                let rpath = format!("nested/writes/write{i}.txt");
+                let tx = db
+                    .tx()
+                    .read("nested/writes/init.txt")
+                    .write(&rpath)
+                    .begin()?;
+                assert_eq!(
+                    b"init",
+                    fs::read(tx.read_file("nested/writes/init.txt")?.path)?.as_slice()
+                );
+                fs::write(tx.write_file(&rpath)?.path, i.to_string())?;
+            }
+            committer.join().unwrap()
+        })?;
+
+        for i in 0..ROUNDS {
+            assert_eq!(
+                i.to_string(),
+                fs::read_to_string(db.root().join(format!("nested/writes/write{i}.txt")))?
+            );
+            assert!(db.root().join(format!("nested/commit{i}.txt")).is_file());
+        }
+        // the payload is only reached through the link
+        let payload = fs::read_link(db.root().join("nested"))?;
+        assert!(matches!(
+            db.tx().read(payload.join("writes/init.txt")).begin(),
+            Err(Error::ReservedName { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_prune_empty_dirs")?;
+        let db = &test_client.client;
+        let root = db.write_dir("")?;
+        root.create_dir("a/b/c")?;
+        root.create_dir("keep/empty")?;
+        root.create_dir("tmp")?;
+        root.create_dir_atomic("atom", false)?;
+        drop(root);
+        // lock files of entries that are gone are no entries
+        db.write_bytes("a/b/c/gone.txt", b"1")?;
+        fs::remove_file(db.root().join("a/b/c/gone.txt"))?;
+        db.write_bytes("atom/gone.txt", b"1")?;
+        fs::remove_file(db.root().join("atom/gone.txt"))?;
+        db.write_bytes("keep/file.txt", b"1")?;
+        // an uncommitted copy neither, but it keeps the directory around until gc removed it
+        fs::write(db.root().join("tmp/.orphan.tmp.sbdb"), "1")?;
+
+        assert!(fs::read_dir(db.root().join("a/b/c"))?.count() > 0);
+        assert!(db.read_dir("a/b/c")?.is_empty()?);
+        assert!(db.read_dir("atom")?.is_empty()?);
+        assert!(db.read_dir("tmp")?.is_empty()?);
+        assert_eq!(1, db.read_dir("a")?.entry_count()?);
+        assert_eq!(2, db.read_dir("keep")?.entry_count()?);
+        assert_eq!(db.list("")?.len(), db.read_dir("")?.entry_count()?);
+        assert_eq!(4, db.read_dir("")?.entry_count()?);
+
+        // the directory pruning starts from is kept
+        assert_eq!(1, db.prune_empty_dirs("keep")?);
+        assert_eq!(vec![OsString::from("file.txt")], db.list("keep")?);
+
+        // children go first, which leaves their parents empty
+        let payload = fs::read_link(db.root().join("atom"))?;
+        assert_eq!(4, db.prune_empty_dirs("")?);
+        assert_eq!(
+            vec![OsString::from("keep"), OsString::from("tmp")],
+            db.list("")?
+        );
+        for gone in [".a.lock.sbdb", ".a.queue.sbdb", ".atom.lock.sbdb", "atom"] {
+            assert!(
+                fs::symlink_metadata(db.root().join(gone)).is_err(),
+                "{gone}"
+            );
+        }
+        assert!(!db.root().join(payload).exists());
+
+        db.gc();
+        assert_eq!(1, db.prune_empty_dirs("")?);
+        assert_eq!(vec![OsString::from("keep")], db.list("")?);
+        assert_eq!(0, db.prune_empty_dirs("")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_prefix() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_remove_prefix")?;
+        let db = &test_client.client;
+        let root = db.write_dir("")?;
+        root.create_dir("sessions/2023-dir")?;
+        root.create_dir_atomic("sessions/2023-atom", false)?;
+        root.create_dir("logs/nested")?;
+        drop(root);
+        db.write_bytes("sessions/2023-01", b"1")?;
+        db.set_meta("sessions/2023-01", "owner", "a")?;
+        db.write_with_ttl("sessions/2023-02", b"2", Duration::from_secs(3600))?;
+        db.write_bytes("sessions/2023-dir/file.txt", b"3")?;
+        db.write_bytes("sessions/2023-atom/file.txt", b"4")?;
+        db.write_bytes("sessions/2024-01", b"5")?;
+        db.write_bytes("logs/nested/file.txt", b"6")?;
+        db.write_bytes("logs-old", b"7")?;
+        let payload = fs::read_link(db.root().join("sessions/2023-atom"))?;
+
+        // entries of a directory whose names start with the prefix
+        let report = db.remove_prefix("sessions/2023-")?;
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(
+            ["2023-01", "2023-02", "2023-atom", "2023-dir"]
+                .map(|name| Path::new("sessions").join(name))
+                .to_vec(),
+            report.removed
+        );
+        assert_eq!(vec![OsString::from("2024-01")], db.list("sessions")?);
+        // along with their sidecars and lock files, and nothing is left for gc
+        for name in fs::read_dir(db.root().join("sessions"))? {
+            let name = name?.file_name();
+            assert!(name.to_string_lossy().contains("2024-01"), "{name:?}");
+        }
+        assert!(!db.root().join("sessions").join(payload).exists());
+
+        // a whole directory, without the entries named like it
+        let report = db.remove_prefix("logs")?;
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(vec![PathBuf::from("logs")], report.removed);
+        assert_eq!(
+            vec![OsString::from("logs-old"), OsString::from("sessions")],
+            db.list("")?
+        );
+        for gone in ["logs", ".logs.lock.sbdb", ".logs.queue.sbdb"] {
+            assert!(
+                fs::symlink_metadata(db.root().join(gone)).is_err(),
+                "{gone}"
+            );
+        }
+        for name in fs::read_dir(db.root())? {
+            let name = name?.file_name();
+            assert_ne!(
+                Some(ArtifactKind::Tmp),
+                artifact::classify(&name),
+                "{name:?}"
+            );
+        }
+
+        // the tombstone of an atomic directory left behind by a crash is a link, which gc removes along
+        // with its payload
+        db.write_dir("")?.create_dir_atomic("buried", false)?;
+        let payload = db.root().join(fs::read_link(db.root().join("buried"))?);
+        let tombstone = crate::remove::tombstone(&db.root().join("buried"))?;
+        fs::rename(db.root().join("buried"), &tombstone)?;
+        let report = db.gc_with(&GcOptions {
+            min_age: Duration::ZERO,
+            ..Default::default()
+        });
+        assert_eq!(1, report.temps_removed);
+        assert_eq!(1, report.payloads_removed);
+        assert!(fs::symlink_metadata(&tombstone).is_err());
+        assert!(!payload.exists());
+
+        assert!(db.remove_prefix("missing/2023-")?.removed.is_empty());
+        assert!(db.remove_prefix("sessions/2023-")?.removed.is_empty());
+        assert!(matches!(
+            db.remove_prefix(""),
+            Err(Error::InvalidPath { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_page() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_list_page")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("dir")?;
+        let dir = db.root().join("dir");
+        for i in 0..10_000 {
+            fs::write(dir.join(format!("{:05}", i * 2)), "")?;
+        }
+        db.write_bytes("dir/00000", b"1")?;
+        let full = db.list("dir")?;
+        assert_eq!(10_000, full.len());
+
+        let mut seen = Vec::new();
+        let mut after: Option<OsString> = None;
+        let mut pages = 0;
+        loop {
+            let page = db.list_page("dir", after.as_deref(), 100)?;
+            assert!(page.names.len() <= 100);
+            seen.extend(page.names.iter().cloned());
+            after = page.names.last().cloned();
+            pages += 1;
+            if pages == 50 {
+                // one before the position is missed, one after it is listed in its place
+                fs::write(dir.join("00001"), "")?;
+                fs::write(dir.join("19999"), "")?;
+            }
+            if !page.has_more {
+                break;
+            }
+        }
+        assert_eq!(101, pages);
+        let mut expected = full;
+        expected.push(OsString::from("19999"));
+        expected.sort();
+        assert_eq!(expected, seen);
+
+        assert_eq!(
+            crate::Page {
+                names: vec![OsString::from("19996"), OsString::from("19998")],
+                has_more: true,
+            },
+            db.list_page("dir", Some("19995".as_ref()), 2)?
+        );
+        let last = db.list_page("dir", Some("19998".as_ref()), 2)?;
+        assert_eq!(vec![OsString::from("19999")], last.names);
+        assert!(!last.has_more);
+        assert!(db.list_page("dir", None, 0)?.has_more);
+        assert_eq!(db.list("")?, db.list_page("", None, usize::MAX)?.names);
+        Ok(())
+    }
+
+    #[cfg(feature = "testkit")]
+    #[test]
+    fn test_io_deadline() -> anyhow::Result<()> {
+        use std::io::ErrorKind;
+
+        use crate::testkit::{FaultVfs, VfsOp};
+
+        let root = std::env::temp_dir().join("test_io_deadline-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        let db = Client::builder(&root)
+            .vfs(vfs.clone())
+            .io_deadline(Duration::from_millis(200))
+            .build()?;
+        let _cleanup = TestClient {
+            client: db.clone(),
+            root: root.clone(),
+        };
+        // three chunks each
+        let big = vec![7u8; 2 * crate::deadline::CHUNK_LEN + 1];
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("dir/a.bin"), &big)?;
+        fs::write(root.join("dir/b.bin"), &big)?;
+        fs::write(root.join("file.bin"), &big)?;
+        // copies are made in chunks once reflinks failed
+        vfs.fail(
+            VfsOp::Copy,
+            vfs.calls(VfsOp::Copy) + 1,
+            ErrorKind::Unsupported,
+        );
+
+        // in time, the copy is complete
+        let gaurd = db.write_file("file.bin")?;
+        let cow = gaurd.cow()?;
+        assert_eq!(big, fs::read(&cow.path)?);
+        assert!(vfs.calls(VfsOp::CopyChunk) >= 3);
+        cow.commit()?;
+        drop(gaurd);
+
+        // a slow filesystem runs out of time, which removes the partial copy and leaves the entry alone
+        vfs.delay(VfsOp::CopyChunk, Duration::from_millis(100));
+        let gaurd = db.write_file("file.bin")?;
+        match gaurd.cow() {
+            Err(Error::IoTimeout { path, elapsed }) => {
+                assert_eq!(root.join("file.bin"), path);
+                assert!(elapsed >= Duration::from_millis(200));
+            }
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+        assert!(!root.join(".file.bin.tmp.sbdb").exists());
+        drop(gaurd);
+        assert!(matches!(
+            db.write_dir("dir")?.cow(),
+            Err(Error::IoTimeout { .. })
+        ));
+        assert!(!root.join(".dir.tmp.sbdb").exists());
+        assert_eq!(big, fs::read(root.join("file.bin"))?);
+        assert_eq!(big, fs::read(root.join("dir/a.bin"))?);
+
+        // writes are bounded too, a deadline of zero is always over
+        let db = Client::builder(&root).io_deadline(Duration::ZERO).build()?;
+        assert!(matches!(
+            db.write_bytes("file.bin", b"new"),
+            Err(Error::IoTimeout { .. })
+        ));
+        assert!(!root.join(".file.bin.tmp.sbdb").exists());
+        assert_eq!(big, fs::read(root.join("file.bin"))?);
+        // and the locks were released
+        Client::new(&root)?.write_bytes("file.bin", b"new")?;
+        assert_eq!("new", fs::read_to_string(root.join("file.bin"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_policy() -> anyhow::Result<()> {
+        use crate::{
+            Issue, IssueKind, OpenPolicy,
+            fixture::{TreeBuilder, dangling_atomic_dir, interrupted_dir_commit},
+        };
+
+        let test_client = TestClient::new("test_open_policy")?;
+        let root = test_client.client.root();
+        let open = |policy| Client::builder(root).open_policy(policy).build();
+        let findings = |policy| match open(policy) {
+            Err(Error::NeedsRecovery { findings }) => findings,
+            other => panic!("expected findings, got {:?}", other.map(|_| ())),
+        };
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("dir/file.txt"), "old")?;
+        open(OpenPolicy::Strict)?;
+
+        // an interrupted commit is refused as it is, and recovered by restoring the backup
+        let commit = interrupted_dir_commit(
+            root.join("dir"),
+            &TreeBuilder::new().file("file.txt", "new"),
+        )?;
+        open(OpenPolicy::Lenient)?;
+        let backup = commit.backup.strip_prefix(root)?.to_path_buf();
+        assert_eq!(
+            vec![Issue {
+                path: backup,
+                kind: IssueKind::LeftoverBackup {
+                    original_exists: false
+                },
+                repairable: false,
+            }],
+            findings(OpenPolicy::Strict)
+        );
+        assert!(commit.backup.exists());
+        assert!(!root.join("dir").exists());
+        open(OpenPolicy::Recover)?;
+        assert_eq!("old", fs::read_to_string(root.join("dir/file.txt"))?);
+        assert!(!commit.copy.exists());
+        open(OpenPolicy::Strict)?;
+
+        // an atomic directory without its payload is removed
+        dangling_atomic_dir(root.join("dir/broken"))?;
+        let issues = findings(OpenPolicy::Strict);
+        assert_eq!(1, issues.len());
+        assert_eq!(PathBuf::from("dir/broken"), issues[0].path);
+        assert_eq!(IssueKind::DanglingAtomicDir, issues[0].kind);
+        assert!(fs::symlink_metadata(root.join("dir/broken")).is_ok());
+        open(OpenPolicy::Recover)?;
+        assert!(fs::symlink_metadata(root.join("dir/broken")).is_err());
+        open(OpenPolicy::Strict)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_dir_for_read() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_snapshot_dir_for_read")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("dir/sub")?;
+        db.write_bytes("dir/a.txt", b"a")?;
+        db.write_bytes("dir/sub/b.txt", b"b")?;
+
+        let snapshot = db.snapshot_dir_for_read("dir")?;
+        // writers are not kept waiting
+        db.write_bytes("dir/a.txt", b"changed")?;
+        db.write_bytes("dir/c.txt", b"c")?;
+        db.write_dir("dir")?.cow()?.commit()?;
+        assert_eq!(
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")],
+            snapshot.walk()?
+        );
+        assert_eq!("a", fs::read_to_string(snapshot.path().join("a.txt"))?);
+        assert_eq!("b", fs::read_to_string(snapshot.path().join("sub/b.txt"))?);
+
+        // gc leaves it alone while it lives, and removes the copies of processes that are gone right away
+        let scratch = db.root().join(".sbdb/tmp");
+        let dead = scratch.join(puuid());
+        fs::create_dir_all(dead.join("data"))?;
+        fs::write(dead.join("pin"), "")?;
+        let report = db.gc();
+        assert_eq!(1, report.temps_removed);
+        assert!(!dead.exists());
+        assert!(snapshot.path().join("a.txt").exists());
+
+        let dir = snapshot.path().parent().unwrap().to_path_buf();
+        drop(snapshot);
+        assert!(!dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_changeset() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_apply_changeset")?;
+        let db = &test_client.client;
+        db.write_dir("")?.create_dir("dir")?;
+        for i in 0..30 {
+            db.write_bytes(format!("dir/{i:02}"), b"old")?;
+        }
+        let external = test_client.root.join(".sbdb/external");
+        fs::write(&external, "external")?;
+
+        // 20 puts of new files, one copied from outside, 10 replacing puts, 10 deletes and 10 renames
+        let mut changeset = Changeset::new().put_file_from("dir/new-00", &external);
+        for i in 1..20 {
+            changeset = changeset.put(format!("dir/new-{i:02}"), format!("new {i}"));
+        }
+        for i in 0..10 {
+            changeset = changeset.put(format!("dir/{i:02}"), "replaced");
+        }
+        for i in 10..19 {
+            changeset = changeset.delete(format!("dir/{i:02}"));
+        }
+        changeset = changeset.delete("dir/missing");
+        for i in 20..30 {
+            changeset = changeset.rename(format!("dir/{i:02}"), format!("dir/moved-{i:02}"));
+        }
+        assert_eq!(50, changeset.len());
+        let report = db.apply(changeset)?;
+
+        let mut expected = vec![ChangeOutcome::Created; 20];
+        expected.extend([ChangeOutcome::Replaced; 10]);
+        expected.extend([ChangeOutcome::Deleted; 9]);
+        expected.push(ChangeOutcome::Missing);
+        expected.extend([ChangeOutcome::Renamed { replaced: false }; 10]);
+        assert_eq!(expected, report.outcomes);
+        assert_eq!(
+            "external",
+            fs::read_to_string(test_client.root.join("dir/new-00"))?
+        );
+        assert_eq!(
+            "new 7",
+            fs::read_to_string(test_client.root.join("dir/new-07"))?
+        );
+        assert_eq!(
+            "replaced",
+            fs::read_to_string(test_client.root.join("dir/03"))?
+        );
+        assert_eq!(
+            "old",
+            fs::read_to_string(test_client.root.join("dir/moved-25"))?
+        );
+        assert_eq!("old", fs::read_to_string(test_client.root.join("dir/19"))?);
+        // 20 new, 10 replaced, 1 left alone and 10 moved
+        assert_eq!(41, db.list("dir")?.len());
+        // what was replaced or deleted is gone with the scratch directory
+        assert_eq!(0, fs::read_dir(test_client.root.join(".sbdb/tmp"))?.count());
+        assert!(fs::read_dir(test_client.root.join("dir"))?.all(|e| {
+            !e.unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(artifact::TMP)
+        }));
+
+        // conflicting changes are refused before anything is changed
+        let conflict = |changeset: Changeset| match db.apply(changeset) {
+            Err(Error::ConflictingChanges { path, reason }) => Some((path, reason)),
+            _ => None,
+        };
+        assert_eq!(
+            Some((PathBuf::from("dir/a"), "put twice")),
+            conflict(Changeset::new().put("dir/a", "1").put("dir/a", "2"))
+        );
+        assert_eq!(
+            Some((PathBuf::from("dir/00"), "renamed and deleted")),
+            conflict(Changeset::new().rename("dir/00", "dir/b").delete("dir/00"))
+        );
+        assert!(conflict(Changeset::new().put("dir/a", "").delete("dir")).is_some());
+        assert!(!test_client.root.join("dir/a").exists());
+        assert!(test_client.root.join("dir/00").exists());
+
+        // a rename of a missing file fails before anything is moved
+        assert!(matches!(
+            db.apply(
+                Changeset::new()
+                    .put("dir/b", "b")
+                    .rename("dir/gone", "dir/c")
+            ),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(!test_client.root.join("dir/b").exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "testkit")]
+    #[test]
+    fn test_apply_changeset_rollback() -> anyhow::Result<()> {
+        use crate::{
+            fixture::TreeBuilder,
+            testkit::{FaultVfs, VfsOp},
+        };
+
+        let root =
+            std::env::temp_dir().join("test_apply_changeset_rollback-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        let db = Client::builder(&root).vfs(vfs.clone()).build()?;
+        let _cleanup = TestClient {
+            client: db.clone(),
+            root: root.clone(),
+        };
+        db.write_dir("")?.create_dir("dir")?;
+        for i in 0..50 {
+            db.write_bytes(format!("dir/{i:02}"), format!("old {i}").as_bytes())?;
+        }
+        let before = TreeBuilder::read(&root)?;
+
+        // every put moves the file it replaces aside and then its own into place, the second rename of the
+        // 30th put fails
+        let changeset = (0..50).fold(Changeset::new(), |changeset, i| {
+            changeset.put(format!("dir/{i:02}"), format!("new {i}"))
+        });
+        vfs.fail(
+            VfsOp::Rename,
+            vfs.calls(VfsOp::Rename) + 2 * 29 + 2,
+            std::io::ErrorKind::Other,
+        );
+        assert!(matches!(
+            db.apply(changeset),
+            Err(Error::CommitFailed { backup: None, .. })
+        ));
+
+        // the first 29 are undone, and nothing is left behind
+        assert_eq!(before, TreeBuilder::read(&root)?);
+        assert_eq!(0, fs::read_dir(root.join(".sbdb/tmp"))?.count());
+        assert!(fs::read_dir(root.join("dir"))?.all(|e| {
+            !e.unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(artifact::TMP)
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_dedup")?;
+        let db = &test_client.client;
+        let big = vec![7u8; 8192];
+        let mut other = big.clone();
+        other[4096] = 8;
+        for (rpath, bytes) in [
+            ("a/1", &big[..]),
+            ("a/2", &big[..]),
+            ("b/3", &big[..]),
+            ("b/other", &other[..]),
+            ("small/1", b"small"),
+            ("small/2", b"small"),
+        ] {
+            fs::create_dir_all(db.root().join(rpath).parent().unwrap())?;
+            fs::write(db.root().join(rpath), bytes)?;
+        }
+
+        let report = db.dedup("", &DedupOptions::default())?;
+        if report.reflink_unsupported {
+            assert_eq!(Some(false), db.reflink_supported());
+            assert_eq!(0, report.files_rewritten);
+
+            // linked instead where the caller accepts it
+            let report = db.dedup(
+                "",
+                &DedupOptions {
+                    hard_link: true,
+                    ..DedupOptions::default()
+                },
+            )?;
+            assert_eq!(
+                (1, 2, 2 * 8192),
+                (
+                    report.groups,
+                    report.files_rewritten,
+                    report.bytes_reclaimed
+                )
+            );
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                let ino = |rpath| fs::metadata(db.root().join(rpath)).map(|m| m.ino());
+                assert_eq!(ino("a/1")?, ino("a/2")?);
+                assert_eq!(ino("a/1")?, ino("b/3")?);
+                assert_ne!(ino("a/1")?, ino("b/other")?);
+            }
+        } else {
+            assert_eq!(
+                (1, 2, 2 * 8192),
+                (
+                    report.groups,
+                    report.files_rewritten,
+                    report.bytes_reclaimed
+                )
+            );
+        }
+        for rpath in ["a/1", "a/2", "b/3"] {
+            assert_eq!(big, fs::read(db.root().join(rpath))?);
+        }
+        assert_eq!(other, fs::read(db.root().join("b/other"))?);
+        assert_eq!(b"small", &fs::read(db.root().join("small/2"))?[..]);
+        Ok(())
+    }
+
+    #[cfg(feature = "testkit")]
+    #[test]
+    fn test_dedup_emulated() -> anyhow::Result<()> {
+        use crate::{
+            DedupReport,
+            testkit::{FaultVfs, VfsOp},
+        };
+
+        let root = std::env::temp_dir().join("test_dedup_emulated-".to_string() + &puuid());
+        let vfs = Arc::new(FaultVfs::new());
+        vfs.emulate_reflinks();
+        let db = Client::builder(&root).vfs(vfs.clone()).build()?;
+        let _cleanup = TestClient {
+            client: db.clone(),
+            root: root.clone(),
+        };
+        db.write_dir("")?.create_dir("dir")?;
+        for i in 0..4u8 {
+            db.write_bytes(format!("dir/{i}"), &[i % 2; 5000])?;
+        }
+        db.write_bytes("dir/small", &[0; 100])?;
+
+        // the reflink of the first duplicate fails, after the one probing for support
+        vfs.fail(
+            VfsOp::Copy,
+            vfs.calls(VfsOp::Copy) + 2,
+            std::io::ErrorKind::Other,
+        );
+        assert!(db.dedup("dir", &DedupOptions::default()).is_err());
+        for i in 0..4u8 {
+            assert_eq!(
+                vec![i % 2; 5000],
+                fs::read(db.root().join(format!("dir/{i}")))?
+            );
+        }
+        assert!(fs::read_dir(root.join("dir"))?.all(|e| {
+            !e.unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(artifact::TMP)
+        }));
+
+        let report = db.dedup("dir", &DedupOptions::default())?;
+        assert_eq!(
+            DedupReport {
+                groups: 2,
+                files_rewritten: 2,
+                bytes_reclaimed: 2 * 5000,
+                ..DedupReport::default()
+            },
+            report
+        );
+        for i in 0..4u8 {
+            assert_eq!(
+                vec![i % 2; 5000],
+                fs::read(db.root().join(format!("dir/{i}")))?
+            );
+        }
+        assert_eq!(Some(true), db.reflink_supported());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_read_hold() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_max_read_hold")?;
+        let stale = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let db = Client::builder(test_client.client.root())
+            .max_read_hold(Duration::from_millis(50))
+            .on_stale_reader({
+                let stale = stale.clone();
+                move |reader| {
+                    reader.revoke();
+                    stale.lock().unwrap().push(reader.clone());
+                }
+            })
+            .build()?;
+        db.write_bytes("cached.txt", b"1")?;
+
+        // a guard leaked into a cache blocks the writer until the application drops it once revoked
+        let leaked = db.read_file("cached.txt")?;
+        assert!(!leaked.is_revoked());
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || db.write_bytes("cached.txt", b"2"))
+        };
+        let start = std::time::Instant::now();
+        while !leaked.is_revoked() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(matches!(
+            leaked.hash(HashAlgorithm::Sha256),
+            Err(Error::LeaseRevoked { .. })
+        ));
+        drop(leaked);
+        writer.join().unwrap()?;
+
+        let stale = stale.lock().unwrap();
+        assert_eq!(1, stale.len());
+        assert_eq!(db.root().join("cached.txt"), stale[0].path);
+        assert!(stale[0].in_this_process());
+        assert!(stale[0].held_for > Duration::from_millis(50));
+        // later guards are not revoked along with the leaked one
+        let gaurd = db.read_file("cached.txt")?;
+        assert!(!gaurd.is_revoked());
+        assert_eq!(b"2", &fs::read(&gaurd.path)?[..]);
+
+        // the stamp does not keep gc from removing the lock file once the entry is gone
+        drop(gaurd);
+        fs::remove_file(db.root().join("cached.txt"))?;
+        let lock = db.root().join(".cached.txt.lock.sbdb");
+        assert_eq!(crate::read_hold::STAMP_LEN, fs::metadata(&lock)?.len());
+        db.gc_with(&GcOptions {
+            min_age: Duration::ZERO,
+            ..Default::default()
+        });
+        assert!(!lock.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_store() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_blob_store")?;
+        let db = &test_client.client;
+        let blobs = db.blobs("blobs");
+        let contents = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let missing: BlobId = "00".repeat(32).parse()?;
+        assert!(!blobs.contains(&missing)?);
+
+        // concurrent puts of the same contents store it once
+        let ids = std::thread::scope(|scope| {
+            let puts = (0..8)
+                .map(|_| scope.spawn(|| blobs.put(&contents[..])))
+                .collect::<Vec<_>>();
+            puts.into_iter()
+                .map(|put| put.join().unwrap())
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        let id = ids[0];
+        assert!(ids.iter().all(|other| *other == id));
+        assert_eq!(id, id.to_string().parse()?);
+        let hex = id.to_string();
+        let dir = db.root().join("blobs").join(&hex[..2]).join(&hex[2..4]);
+        assert_eq!(
+            vec![OsString::from(&hex)],
+            db.list(dir.strip_prefix(db.root())?)?
+        );
+        assert_eq!(contents, fs::read(blobs.get(&id)?.path)?);
+        assert!(matches!(blobs.get(&missing), Err(Error::NotFound { .. })));
+
+        // only the blobs that are not referenced are swept
+        let orphans = [blobs.put(&b"orphan"[..])?, blobs.put(&b"another"[..])?];
+        let kept = blobs.put(&b"kept"[..])?;
+        assert_eq!(2, blobs.remove_unreferenced([id, kept])?);
+        assert!(blobs.contains(&id)? && blobs.contains(&kept)?);
+        assert!(!blobs.contains(&orphans[0])? && !blobs.contains(&orphans[1])?);
+        assert_eq!(0, blobs.remove_unreferenced([id, kept])?);
+        assert_eq!(b"kept", &fs::read(blobs.get(&kept)?.path)?[..]);
+        // nothing is left behind in the scratch area
+        assert_eq!(0, fs::read_dir(db.root().join(".sbdb/tmp"))?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_modes() -> anyhow::Result<()> {
+        // the roots are side by side, not inside another database
+        let parent = std::env::temp_dir().join("test_open_modes-".to_string() + &puuid());
+        let populated = parent.join("populated");
+        let base = TestClient {
+            client: Client::new(&populated)?,
+            root: parent.clone(),
+        };
+        base.client.write_bytes("a.txt", b"a")?;
+        let missing = parent.join("missing");
+        let empty = parent.join("empty");
+        fs::create_dir(&empty)?;
+
+        // a missing root is created unless it has to exist
+        assert!(matches!(
+            Client::open(&missing),
+            Err(Error::RootNotFound { path }) if path == missing
+        ));
+        assert!(!missing.exists());
+        Client::create_new(&missing)?;
+        assert!(matches!(
+            Client::create_new(&missing),
+            Err(Error::AlreadyExists { .. })
+        ));
+        Client::open(&missing)?;
+
+        // a directory that is not a database yet is only adopted by new
+        assert!(matches!(
+            Client::open(&empty),
+            Err(Error::RootNotFound { .. })
+        ));
+        assert!(matches!(
+            Client::create_new(&empty),
+            Err(Error::AlreadyExists { .. })
+        ));
+        Client::new(&empty)?;
+
+        // an existing database is opened as it is
+        assert!(matches!(
+            Client::create_new(&populated),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert_eq!(vec!["a.txt"], Client::open(&populated)?.list("")?);
+        assert_eq!(vec!["a.txt"], Client::new(&populated)?.list("")?);
+
+        // a file is not a root for any of them
+        let file = parent.join("file");
+        fs::write(&file, "")?;
+        assert!(matches!(
+            Client::open(&file),
+            Err(Error::InvalidPath { .. })
+        ));
+        assert!(Client::new(&file).is_err());
+        assert!(matches!(
+            Client::create_new(&file),
+            Err(Error::AlreadyExists { .. })
+        ));
+
+        // a root that can not be written fails to open rather than on the first write
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let internal = populated.join(crate::INTERNAL_DIR);
+            fs::set_permissions(&internal, fs::Permissions::from_mode(0o555))?;
+            // privileged users write anyway
+            let enforced = fs::write(internal.join("probe"), "").is_err();
+            let _ = fs::remove_file(internal.join("probe"));
+            if enforced {
+                assert!(matches!(Client::open(&populated), Err(Error::Io { .. })));
+                assert!(matches!(Client::new(&populated), Err(Error::Io { .. })));
+            }
+            fs::set_permissions(&internal, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_locking() -> anyhow::Result<()> {
+        use crate::LockingFidelity;
+
+        let test_client = TestClient::new("test_check_locking")?;
+        let internal = || -> anyhow::Result<usize> {
+            Ok(fs::read_dir(test_client.root.join(crate::INTERNAL_DIR))?.count())
+        };
+        let before = internal()?;
+        assert_eq!(
+            LockingFidelity::Enforced,
+            test_client.client.check_locking()
+        );
+        assert_eq!(before, internal()?);
+        Client::builder(&test_client.root)
+            .verify_locking(true)
+            .build()?;
+
+        #[cfg(feature = "testkit")]
+        {
+            use crate::testkit::{FaultVfs, VfsOp};
+
+            let builder = |vfs: &Arc<FaultVfs>| {
+                Client::builder(&test_client.root)
+                    .vfs(vfs.clone())
+                    .verify_locking(true)
+            };
+
+            // the check can fail, which is no reason to refuse to open
+            let vfs = Arc::new(FaultVfs::new());
+            vfs.fail(VfsOp::Lock, 2, std::io::ErrorKind::Other);
+            let db = builder(&vfs).build()?;
+            vfs.fail(
+                VfsOp::OpenLockFile,
+                vfs.calls(VfsOp::OpenLockFile) + 1,
+                std::io::ErrorKind::Other,
+            );
+            assert_eq!(LockingFidelity::Unknown, db.check_locking());
+
+            // a filesystem that grants every lock
+            vfs.ignore_locks();
+            assert_eq!(LockingFidelity::NotEnforced, db.check_locking());
+            assert!(matches!(
+                builder(&vfs).build(),
+                Err(Error::LockingNotEnforced { path }) if path == test_client.root
+            ));
+            assert_eq!(before, internal()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollup() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_rollup")?;
+        let db = Client::builder(test_client.client.root())
+            .track_rollups()
+            .build()?;
+        let totals = |rpath: &str| -> anyhow::Result<(u64, u64)> {
+            let rollup = db.rollup(rpath)?;
+            Ok((rollup.approx_bytes, rollup.entries))
+        };
+
+        // data that predates the rollups is only counted once they are rebuilt
+        fs::create_dir_all(db.root().join("a/b"))?;
+        fs::write(db.root().join("a/b/old"), [0; 10])?;
+        assert_eq!(crate::Rollup::default(), db.rollup("a")?);
+        let rebuilt = db.rebuild_rollups("")?;
+        assert_eq!((10, 1), (rebuilt.approx_bytes, rebuilt.entries));
+        assert_eq!(
+            Some(fs::metadata(db.root().join("a/b/old"))?.modified()?),
+            db.rollup("a/b")?.last_modified
+        );
+
+        let start = std::time::SystemTime::now();
+        db.write_bytes("a/x", &[0; 3])?;
+        db.write_bytes("a/b/y", &[0; 5])?;
+        db.write_bytes("c", &[0; 7])?;
+        let end = std::time::SystemTime::now();
+        assert_eq!((25, 4), totals("")?);
+        assert_eq!((18, 3), totals("a")?);
+        assert_eq!((15, 2), totals("a/b")?);
+        let modified = db.rollup("a/b")?.last_modified.unwrap();
+        assert!(start <= modified && modified <= end);
+        // commits elsewhere leave the time alone
+        assert_eq!(Some(modified), db.rollup("a/b")?.last_modified);
+        assert!(db.rollup("")?.last_modified.unwrap() > modified);
+
+        // replacements count the change, removals and renames through a changeset too
+        db.write_bytes("a/b/y", &[0; 2])?;
+        db.apply(Changeset::new().delete("a/x").rename("c", "a/c"))?;
+        assert_eq!((19, 3), totals("")?);
+        assert_eq!((19, 3), totals("a")?);
+        assert_eq!((12, 2), totals("a/b")?);
+
+        // a directory commit rebuilds the rollups below it
+        let gaurd = db.write_dir("a/b")?;
+        let cp = gaurd.cow()?;
+        fs::create_dir(cp.path.join("d"))?;
+        fs::write(cp.path.join("d/z"), [0; 4])?;
+        cp.commit()?;
+        gaurd.release()?;
+        assert_eq!((16, 3), totals("a/b")?);
+        assert_eq!((4, 1), totals("a/b/d")?);
+        assert_eq!((23, 4), totals("")?);
+
+        // the log is folded as it grows
+        for _ in 0..100 {
+            db.write_bytes("a/c", &[0; 7])?;
+        }
+        assert_eq!((23, 4), totals("")?);
+        assert!(fs::metadata(db.root().join(".a.rollup.sbdb"))?.len() < 64 * 65);
+
+        // changes made around the client drift until the next rebuild, which fixes the directories above
+        fs::write(db.root().join("a/b/d/raw"), [0; 100])?;
+        assert_eq!((23, 4), totals("")?);
+        let rebuilt = db.rebuild_rollups("a/b")?;
+        assert_eq!((116, 4), (rebuilt.approx_bytes, rebuilt.entries));
+        assert_eq!((104, 2), totals("a/b/d")?);
+        assert_eq!((123, 5), totals("")?);
+        assert_eq!(123, db.stats("")?.file_bytes);
+
+        // removing a directory removes its rollup
+        fs::create_dir(db.root().join("e"))?;
+        db.rebuild_rollups("")?;
+        assert!(db.root().join(".e.rollup.sbdb").exists());
+        assert_eq!(1, db.prune_empty_dirs("")?);
+        assert!(!db.root().join(".e.rollup.sbdb").exists());
+        assert_eq!((123, 5), totals("")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_maintenance_lock() -> anyhow::Result<()> {
+        use crate::{BackupState, ExistingDest, ExportOptions};
+
+        let test_client = TestClient::new("test_maintenance_lock")?;
+        let outside = TestClient::new("test_maintenance_lock_outside")?;
+        let db = &test_client.client;
+        fs::create_dir(db.root().join("d"))?;
+        for i in 0..5 {
+            db.write_bytes(format!("d/{i}"), b"data")?;
+        }
+        let gc_options = GcOptions {
+            min_age: Duration::ZERO,
+            ..Default::default()
+        };
+
+        // gc keeps removing leftovers while walks look at them, and neither fails
+        std::thread::scope(|scope| -> anyhow::Result<()> {
+            let gc = scope.spawn(|| -> anyhow::Result<()> {
+                for i in 0..30 {
+                    fs::write(db.root().join(format!("d/.{}.tmp.sbdb", i % 5)), b"copy")?;
+                    let backup = db.root().join(format!("d/.{}.{}.bak.sbdb", i % 5, puuid()));
+                    fs::create_dir(&backup)?;
+                    fs::write(backup.join("file"), b"backup")?;
+                    let report = db.gc_with(&gc_options);
+                    assert_eq!(0, report.errors);
+                    assert_eq!((1, 1), (report.temps_removed, report.backups_removed));
+                }
+                Ok(())
+            });
+            let replace = ExportOptions {
+                existing: ExistingDest::Replace,
+                ..Default::default()
+            };
+            let mut state = BackupState::new();
+            for _ in 0..30 {
+                db.export_dir("d", &outside.root.join("export"), &replace)?;
+                db.backup_incremental(&outside.root.join("backup"), &mut state)?;
+                assert_eq!(5, db.stats("d")?.files);
+            }
+            gc.join().unwrap()
+        })?;
+        assert_eq!(5, fs::read_dir(outside.root.join("export"))?.count());
+
+        // gc gives up once the lock is held for longer than it waits
+        let impatient = Client::builder(db.root())
+            .maintenance_wait(Duration::from_millis(20))
+            .build()?;
+        let walk = impatient.maintenance_read()?;
+        assert!(impatient.maintenance_read().is_ok());
+        assert!(matches!(
+            impatient.maintenance_write(),
+            Err(Error::Timeout { .. })
+        ));
+        assert_eq!(1, impatient.gc_with(&gc_options).errors);
+        // a dry run only reads
+        let dry_run = GcOptions {
+            dry_run: true,
+            ..gc_options.clone()
+        };
+        assert_eq!(0, impatient.gc_with(&dry_run).errors);
+        walk.release()?;
+        let gc = impatient.maintenance_write()?;
+        assert!(matches!(impatient.stats(""), Err(Error::Timeout { .. })));
+        drop(gc);
+        assert_eq!(0, impatient.gc_with(&gc_options).errors);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_all() -> anyhow::Result<()> {
+        let test_client = TestClient::new("test_lock_all")?;
+        let db = &test_client.client;
+        db.write_bytes("a.txt", b"1")?;
+        db.write_dir("")?.create_dir_atomic("atom", false)?;
+        db.write_bytes("atom/b.txt", b"2")?;
+        fs::create_dir(db.root().join("empty"))?;
+        // left behind by a crash
+        let backup = db.root().join(format!(".gone.{}.bak.sbdb", puuid()));
+        fs::create_dir(&backup)?;
+        fs::write(backup.join("c.txt"), b"3")?;
+        fs::write(db.root().join(".a.txt.tmp.sbdb"), b"copy")?;
+        // the root is resolved when a client is built, however it is spelled
+        assert_eq!(db.root(), Client::new(db.root().join("empty/.."))?.root());
+
+        let exclusive = db.lock_all()?;
+        let (read, reads) = std::sync::mpsc::channel();
+        thread::scope(|scope| -> anyhow::Result<()> {
+            let reader = scope.spawn(move || -> anyhow::Result<()> {
+                let gaurd = db.read_file("a.txt")?;
+                read.send(fs::read(&gaurd.path)?)?;
+                Ok(())
+            });
+            // the reader waits for the guard, while the thread holding it goes on without locks
+            thread::sleep(Duration::from_millis(100));
+            assert!(reads.try_recv().is_err());
+            exclusive.run(|root| -> anyhow::Result<()> {
+                db.write_bytes("a.txt", b"4")?;
+                let gaurd = db.read_dir("")?;
+                assert_eq!(b"4", fs::read(gaurd.read_file("a.txt")?.path)?.as_slice());
+                assert!(root.join("atom/b.txt").is_file());
+                // only the client the guard was taken with and its clones go on without locks
+                assert!(matches!(
+                    db.detach().tx().write("a.txt").try_begin(),
+                    Err(Error::WouldBlock { .. })
+                ));
+                Ok(())
+            })?;
+
+            let report = exclusive.compact()?;
+            assert_eq!(1, report.gc.backups_restored);
+            assert_eq!(0, report.gc.errors);
+            assert_eq!(1, report.dirs_removed);
+            assert!(!db.root().join(".a.txt.tmp.sbdb").exists());
+            assert_eq!(1, exclusive.rewrite_atomic_dirs_to_plain()?);
+            assert!(reads.try_recv().is_err());
+
+            exclusive.release()?;
+            assert_eq!(b"4", reads.recv()?.as_slice());
+            reader.join().unwrap()
+        })?;
+        assert!(fs::symlink_metadata(db.root().join("atom"))?.is_dir());
+        assert_eq!(b"2", fs::read(db.root().join("atom/b.txt"))?.as_slice());
+        assert_eq!(b"3", fs::read(db.root().join("gone/c.txt"))?.as_slice());
+        assert_eq!(vec!["a.txt", "atom", "gone"], db.list("")?);
+        // payloads are moved rather than copied
+        assert_eq!(0, db.gc().payloads_removed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair() -> anyhow::Result<()> {
+        use crate::{IssueKind, RepairAction, VerifyOptions, fixture::dangling_atomic_dir};
+
+        let test_client = TestClient::new("test_repair")?;
+        let db = &test_client.client;
+        let root = db.root().to_path_buf();
+        let payload = |name: &str| -> anyhow::Result<PathBuf> {
+            Ok(root.join(fs::read_link(root.join(name))?))
+        };
+        for name in ["removed", "restored", "older"] {
+            db.write_dir("")?.create_dir_atomic(name, false)?;
+            db.write_bytes(format!("{name}/file.txt"), name.as_bytes())?;
+        }
+        assert!(matches!(
+            db.repair("removed", RepairAction::RemoveLink),
+            Err(Error::InvalidPath { .. })
+        ));
+
+        // a crash between removing the payload and the link
+        fs::remove_dir_all(payload("removed")?)?;
+        let issues = db.verify("", &VerifyOptions::default())?.issues;
+        assert_eq!(1, issues.len());
+        assert_eq!(PathBuf::from("removed"), issues[0].path);
+        assert_eq!(IssueKind::DanglingAtomicDir, issues[0].kind);
+        let report = db.gc();
+        assert_eq!(1, report.dangling_atomic_dirs);
+        assert_eq!(0, report.errors);
+        let report = db.repair("removed", RepairAction::RemoveLink)?;
+        assert_eq!(PathBuf::from("removed"), report.path);
+        assert_eq!(None, report.restored_from);
+        assert!(fs::symlink_metadata(root.join("removed")).is_err());
+
+        // the payload was moved aside as a backup
+        let backup = root.join(format!(".restored.{}.bak.sbdb", puuid()));
+        fs::rename(payload("restored")?, &backup)?;
+        let report = db.repair("restored", RepairAction::RestoreFromBackup)?;
+        assert_eq!(
+            Some(PathBuf::from(backup.file_name().unwrap())),
+            report.restored_from
+        );
+        assert_eq!(
+            "restored",
+            fs::read_to_string(root.join("restored/file.txt"))?
+        );
+        assert!(crate::is_atomic_dir_link(&root.join("restored"))?);
+        assert!(!backup.exists());
+
+        // the link points past the payload, which is restored in place
+        let older = payload("older")?;
+        fs::remove_file(root.join("older"))?;
+        dangling_atomic_dir(root.join("older"))?;
+        let report = db.repair("older", RepairAction::RestoreFromBackup)?;
+        assert_eq!(
+            Some(PathBuf::from(older.file_name().unwrap())),
+            report.restored_from
+        );
+        assert_eq!(older, payload("older")?);
+        assert_eq!("older", fs::read_to_string(root.join("older/file.txt"))?);
+
+        // nothing to restore from
+        dangling_atomic_dir(root.join("empty"))?;
+        assert!(matches!(
+            db.repair("empty", RepairAction::RestoreFromBackup),
+            Err(Error::NotFound { .. })
+        ));
+        db.repair("empty", RepairAction::RecreateEmpty)?;
+        assert!(crate::is_atomic_dir_link(&root.join("empty"))?);
+        assert!(db.list("empty")?.is_empty());
+        db.write_bytes("empty/file.txt", b"new")?;
+
+        // only the lock files of the removed link are left
+        let issues = db.verify("", &VerifyOptions::default())?.issues;
+        assert!(
+            issues
+                .iter()
+                .all(|issue| issue.kind == IssueKind::OrphanedLock)
+        );
+        assert_eq!(0, db.gc().dangling_atomic_dirs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming() -> anyhow::Result<()> {
+        use std::io::BufRead;
+
+        let test_client = TestClient::new("test_streaming")?;
+        let db = Client::builder(&test_client.root)
+            .stream_buffer_len(7)
+            .build()?;
+        let contents: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        db.write_bytes("file", &contents)?;
+        let mut copy = Vec::new();
+        assert_eq!(1000, db.copy_to("file", &mut copy)?);
+        assert_eq!(contents, copy);
+        assert!(matches!(
+            db.copy_to("missing", Vec::new()),
+            Err(Error::NotFound { .. })
+        ));
+
+        db.write_bytes("lines", b"one\ntwo\n")?;
+        let gaurd = db.read_file("lines")?;
+        let lines = gaurd
+            .reader()?
+            .lines()
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(vec!["one", "two"], lines);
+        gaurd.release()?;
+
+        #[cfg(feature = "binary")]
+        {
+            use std::io::Read;
+
+            let records = db.collection::<Vec<u8>, _>("records");
+            // longer than the start of the record that is read first, and than any file name
+            let long = "k".repeat(5000);
+            records.put("a", &contents)?;
+            records.put(&long, &vec![1; 3])?;
+            let mut streamed = Vec::new();
+            for record in records.iter_streaming()? {
+                let (key, mut reader) = record?;
+                let mut value = Vec::new();
+                reader.read_to_end(&mut value)?;
+                streamed.push((key, postcard::from_bytes::<Vec<u8>>(&value)?));
+            }
+            assert_eq!(
+                vec![("a".to_string(), contents), (long, vec![1; 3])],
+                streamed
+            );
+
+            // a record stays locked while its reader is alive, after the iterator is dropped
+            let mut iter = records.iter_streaming()?;
+            let (_, reader) = iter.next().unwrap()?;
+            drop(iter);
+            let (written, writes) = std::sync::mpsc::channel();
+            let writer = {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let put = db.collection::<Vec<u8>, _>("records").put("a", &vec![2]);
+                    written.send(()).unwrap();
+                    put
+                })
+            };
+            assert!(writes.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(reader);
+            writes.recv()?;
+            writer.join().unwrap()?;
+            assert_eq!(Some(vec![2]), records.get("a")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_backups() -> anyhow::Result<()> {
+        use std::time::SystemTime;
+
+        use crate::RetentionPolicy;
+
+        let test_client = TestClient::new("test_vacuum_backups")?;
+        let db = &test_client.client;
+        let root = db.root().to_path_buf();
+        let now = SystemTime::now();
+        let backup = |orig: &str, hours_ago: u64, len: usize| -> anyhow::Result<PathBuf> {
+            let path = root.join(orig);
+            let path = path.with_file_name(format!(
+                ".{}.{}.bak.sbdb",
+                path.file_name().unwrap().to_str().unwrap(),
+                puuid()
+            ));
+            // directories are backed up by moving them aside
+            fs::create_dir(&path)?;
+            fs::write(path.join("file.txt"), vec![0; len])?;
+            File::open(&path)?.set_modified(now - Duration::from_secs(hours_ago * 60 * 60))?;
+            Ok(path)
+        };
+        let rpath = |path: &Path| path.strip_prefix(&root).unwrap().to_path_buf();
+
+        db.write_dir("")?.create_dir("docs")?;
+        db.write_bytes("docs/file.txt", b"live")?;
+        let backups = (1..=5)
+            .map(|hours| backup("docs", hours, 10))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let report = db.vacuum_backups(
+            "",
+            &RetentionPolicy {
+                keep_last: Some(2),
+                ..Default::default()
+            },
+        )?;
+        let mut removed = report.removed.clone();
+        removed.sort();
+        let mut expected = backups[2..].iter().map(|b| rpath(b)).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(expected, removed);
+        assert_eq!(2, report.backups_kept);
+        assert_eq!(30, report.bytes_freed);
+        assert!(backups[..2].iter().all(|b| b.exists()));
+        assert_eq!("live", fs::read_to_string(root.join("docs/file.txt"))?);
+
+        // the oldest go first once the newest fill the limit on bytes
+        db.write_dir("")?.create_dir("dir")?;
+        db.write_dir("dir")?.create_dir("sized")?;
+        let sized = [(1, 10), (2, 20), (3, 30), (30, 1)]
+            .into_iter()
+            .map(|(hours, len)| backup("dir/sized", hours, len))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let report = db.vacuum_backups(
+            "dir",
+            &RetentionPolicy {
+                keep_younger_than: Some(Duration::from_secs(24 * 60 * 60)),
+                max_bytes: Some(35),
+                ..Default::default()
+            },
+        )?;
+        let mut removed = report.removed.clone();
+        removed.sort();
+        let mut expected = vec![rpath(&sized[2]), rpath(&sized[3])];
+        expected.sort();
+        assert_eq!(expected, removed);
+        assert!(backups[..2].iter().all(|b| b.exists()));
+
+        // gc leaves the backups that the policy keeps, even those of entries that exist
+        let report = db.gc_with(&GcOptions {
+            vacuum_backups: Some(RetentionPolicy {
+                keep_last: Some(2),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(0, report.errors);
+        assert_eq!(0, report.backups_removed);
+        assert_eq!(0, report.backups_vacuumed);
+        assert!(backups[..2].iter().all(|b| b.exists()));
+        assert!(sized[..2].iter().all(|b| b.exists()));
+
+        // the newest backup of an entry that does not exist is left for recovery
+        let gone = (1..=5)
+            .map(|hours| backup("gone", hours, 1))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let report = db.gc_with(&GcOptions {
+            min_age: Duration::from_secs(60),
+            vacuum_backups: Some(RetentionPolicy {
+                keep_last: Some(0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(0, report.errors);
+        assert_eq!(0, report.backups_removed);
+        assert_eq!(8, report.backups_vacuumed);
+        assert!(backups[..2].iter().all(|b| !b.exists()));
+        assert!(gone[0].exists());
+        assert!(gone[1..].iter().all(|b| !b.exists()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_consistent() -> anyhow::Result<()> {
+        use std::{collections::HashSet, sync::atomic::AtomicBool};
+
+        use crate::{Lock, LockStatus};
+
+        let test = TestClient::new("test_read_consistent")?;
+        let db = &test.client;
+        db.write_bytes("config.json", b"0")?;
+        db.write_bytes("index.json", b"0")?;
+
+        // a writer flips both files together, which a reader must never see half done
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let db = db.clone();
+            let done = done.clone();
+            thread::spawn(move || -> crate::Result<usize> {
+                let mut flips = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let value = (flips % 2 + 1).to_string();
+                    let tx = db.tx().write("config.json").write("index.json").begin()?;
+                    for rpath in ["config.json", "index.json"] {
+                        let cow = tx.file_cow(rpath)?;
+                        fs::write(&cow.path, &value).unwrap();
+                        std::thread::sleep(Duration::from_millis(1));
+                        cow.commit()?;
+                    }
+                    tx.release()?;
+                    flips += 1;
+                }
+                Ok(flips)
+            })
+        };
+        let paths = [PathBuf::from("config.json"), PathBuf::from("index.json")];
+        let mut seen = HashSet::new();
+        let start = Instant::now();
+        let flipped = |seen: &HashSet<String>| seen.contains("1") && seen.contains("2");
+        while !flipped(&seen) && start.elapsed() < Duration::from_secs(10) {
+            let gaurds = db.read_consistent(&paths)?;
+            let config = fs::read_to_string(&gaurds[0].path)?;
+            let index = fs::read_to_string(&gaurds[1].path)?;
+            assert_eq!(config, index);
+            drop(gaurds);
+            let contents = db.read_all_to_vec(&paths)?;
+            assert_eq!(contents[0], contents[1]);
+            seen.insert(config);
+        }
+        done.store(true, Ordering::SeqCst);
+        assert!(writer.join().unwrap()? > 0);
+        assert!(flipped(&seen), "{seen:?}");
+
+        // shared and repeated entries are locked once and held until the last guard goes
+        fs::create_dir(test.root.join("dir"))?;
+        fs::write(test.root.join("dir/file"), "file")?;
+        let gaurds = db.read_consistent(&["dir/file", "dir", "dir/file"])?;
+        assert_eq!(test.root.join("dir"), gaurds[1].path);
+        let held = db.held_locks();
+        assert_eq!(3, held.len(), "{held:?}");
+        let mut gaurds = gaurds.into_iter();
+        gaurds.next().unwrap().release()?;
+        gaurds.next().unwrap().release()?;
+        assert_eq!(3, db.held_locks().len());
+        assert_eq!(LockStatus::Read, Lock::probe(test.root.join("dir"))?);
+        gaurds.next().unwrap().release()?;
+        assert!(db.held_locks().is_empty());
+        assert_eq!(LockStatus::Free, Lock::probe(test.root.join("dir"))?);
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_remove_while_open() -> anyhow::Result<()> {
+        use std::{fs::OpenOptions, os::windows::fs::OpenOptionsExt};
+
+        use crate::{
+            FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, open_lock_and_queue,
+            open_lock_file, vfs, winfs,
+        };
+
+        let test = TestClient::new("test_windows_remove_while_open")?;
+        let db = &test.client;
+        let vfs = vfs::std();
+        let share_all = FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE;
+
+        // a lock file that is held open elsewhere is removed, and can be created again right away
+        let lock = test.root.join(".gone.lock.sbdb");
+        let held = open_lock_file(&lock)?;
+        vfs.remove_file(&lock)?;
+        assert!(!db.list("")?.iter().any(|name| name == ".gone.lock.sbdb"));
+        let recreated = open_lock_and_queue(test.root.join("gone"))?;
+        drop((held, recreated));
+        vfs.remove_file(&lock)?;
+        vfs.remove_file(&test.root.join(".gone.queue.sbdb"))?;
+
+        // a data file that is open without sharing deletion is removed once it is closed
+        let data = test.root.join("data");
+        fs::write(&data, "data")?;
+        let open = OpenOptions::new()
+            .read(true)
+            .share_mode(FILE_SHARE_READ)
+            .open(&data)?;
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(open);
+        });
+        vfs.remove_file(&data)?;
+        closer.join().unwrap();
+        assert!(!data.exists());
+
+        // names removed while open are neither listed nor removed again by gc
+        let pending = [
+            test.root.join("pending"),
+            test.root.join(".orphan.lock.sbdb"),
+        ];
+        let mut open = Vec::new();
+        for path in &pending {
+            fs::write(path, "")?;
+            open.push(
+                OpenOptions::new()
+                    .read(true)
+                    .share_mode(share_all)
+                    .open(path)?,
+            );
+            OpenOptions::new()
+                .access_mode(0x00010000) // DELETE
+                .share_mode(share_all)
+                .custom_flags(0x04000000) // FILE_FLAG_DELETE_ON_CLOSE
+                .open(path)?;
+            assert!(winfs::is_delete_pending(path));
+        }
+        assert!(db.list("")?.is_empty());
+        let report = db.gc_with(&GcOptions {
+            min_age: Duration::ZERO,
+            ..GcOptions::default()
+        });
+        assert_eq!(0, report.errors);
+        assert_eq!(0, report.lock_files_removed);
+        drop(open);
+        assert!(pending.iter().all(|path| !path.exists()));
         Ok(())
     }
 }
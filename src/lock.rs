@@ -0,0 +1,806 @@
+//! Locking entries and the guards that hold the locks, see [`Client::read_file`] and friends. Every entry has
+//! a lock file and a queue file next to it, or a lock directory with [`LockBackend::AtomicLockDir`], and an
+//! entry is only locked along with all of its ancestors.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+#[cfg(windows)]
+use std::os::windows::prelude::*;
+
+use crate::{
+    AtomicDirCreation, CowAtomicDirGaurd, CowDirGaurd, CowFileGaurd, Ctx, Error, HeldLocks,
+    LockBackend, LockFileCache, LockFiles, LockMode, Result, StdVfs, Vfs, Warning, WarningCallback,
+    artifact, check_locked, check_nested, check_unreserved, create_atomic_below,
+    dir_cow_atomic_from, dir_cow_with, error::IoResultExt, exclusive, file_cow_with, lock_cache,
+    lockdir, marker, path_hidden_with_extension, prune, redact::Redact, report_warning, trace,
+};
+
+/// Locks for reading the entry at `rpath` and its ancestors. Nothing is locked for `held` and its
+/// ancestors, whose locks the caller already holds.
+pub(crate) fn create_read_file_locks<P: AsRef<Path>>(
+    root: &Path,
+    rpath: P,
+    held: Option<&Path>,
+    ctx: &Ctx,
+) -> Result<Vec<Lock>> {
+    let mut result = Vec::new();
+
+    for anc in rpath
+        .as_ref()
+        .ancestors()
+        .filter(|anc| held.is_none_or(|held| !held.starts_with(anc)))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        if held.is_none() {
+            check_lock_order(root, anc)?;
+        }
+        result.push(Lock::Read(ReadLock::acquire(root, anc, ctx)?))
+    }
+
+    result.reverse();
+
+    Ok(result)
+}
+
+/// Fails if this thread taking the lock of the entry at `rpath` now could deadlock with another one, with
+/// the `lock-order-check` feature. Locks taken relative to a guard or transaction that covers them are not
+/// checked, no one else can hold them.
+pub(crate) fn check_lock_order(root: &Path, rpath: &Path) -> Result<()> {
+    #[cfg(feature = "lock-order-check")]
+    crate::lock_order::check(&root.join(rpath))?;
+    #[cfg(not(feature = "lock-order-check"))]
+    let _ = (root, rpath);
+    Ok(())
+}
+
+/// Locks for writing the entry at `rpath`, see [`create_read_file_locks`].
+pub(crate) fn create_write_file_locks<P: AsRef<Path>>(
+    root: &Path,
+    rpath: P,
+    held: Option<&Path>,
+    ctx: &Ctx,
+) -> Result<Vec<Lock>> {
+    lock_write_file(root, rpath, held, true, ctx)
+}
+
+/// Like [`create_write_file_locks`], failing with [`Error::WouldBlock`] rather than waiting unless `wait`.
+pub(crate) fn lock_write_file<P: AsRef<Path>>(
+    root: &Path,
+    rpath: P,
+    held: Option<&Path>,
+    wait: bool,
+    ctx: &Ctx,
+) -> Result<Vec<Lock>> {
+    let mut result = Vec::new();
+    let is_held = |path: &Path| held.is_some_and(|held| held.starts_with(path));
+
+    for anc in rpath
+        .as_ref()
+        .ancestors()
+        .skip(1)
+        .filter(|anc| !is_held(anc))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        if held.is_none() {
+            check_lock_order(root, anc)?;
+        }
+        result.push(Lock::Read(ReadLock::lock(root, anc, wait, ctx)?))
+    }
+
+    if !is_held(rpath.as_ref()) {
+        if held.is_none() {
+            check_lock_order(root, rpath.as_ref())?;
+        }
+        result.push(Lock::Write(WriteLock::lock(
+            root,
+            rpath.as_ref(),
+            wait,
+            ctx,
+        )?));
+    }
+
+    result.reverse();
+
+    Ok(result)
+}
+
+/// Can be sent to and dropped on another thread, see [`Tx`](crate::Tx).
+pub struct FileReadGaurd {
+    pub path: PathBuf,
+    pub(crate) lock: Vec<Lock>,
+    /// Locks held along with the guards of other entries, see [`Client::read_consistent`].
+    pub(crate) shared: Vec<Arc<Lock>>,
+}
+
+impl FileReadGaurd {
+    /// Whether one of the guard's locks was revoked for being held too long, see
+    /// [`ClientBuilder::max_read_hold`](crate::ClientBuilder::max_read_hold). A revoked guard should be
+    /// dropped, its locks are held until then.
+    pub fn is_revoked(&self) -> bool {
+        is_revoked(&self.lock)
+            || self
+                .shared
+                .iter()
+                .any(|lock| matches!(&**lock, Lock::Read(lock) if lock.is_revoked()))
+    }
+
+    /// Fails with [`Error::LeaseRevoked`] once the guard is revoked, checked by its read methods.
+    pub(crate) fn check_revoked(&self) -> Result<()> {
+        match self.is_revoked() {
+            true => Err(Error::LeaseRevoked {
+                path: self.path.clone(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Release the guard's locks, reporting the first failure instead of logging it on drop. Locks shared
+    /// with other guards are only released along with the last of them.
+    pub fn release(self) -> Result<()> {
+        let shared = self.shared.into_iter().filter_map(Arc::into_inner);
+        release_all(self.lock.into_iter().chain(shared).collect())
+    }
+}
+
+fn is_revoked(locks: &[Lock]) -> bool {
+    locks
+        .iter()
+        .any(|lock| matches!(lock, Lock::Read(lock) if lock.is_revoked()))
+}
+
+pub(crate) fn check_revoked(locks: &[Lock], path: &Path) -> Result<()> {
+    match is_revoked(locks) {
+        true => Err(Error::LeaseRevoked {
+            path: path.to_path_buf(),
+        }),
+        false => Ok(()),
+    }
+}
+
+/// Can be sent to and dropped on another thread, see [`Tx`](crate::Tx).
+pub struct FileWriteGaurd {
+    pub path: PathBuf,
+    pub(crate) lock: Vec<Lock>,
+    pub(crate) ctx: Ctx,
+}
+
+impl FileWriteGaurd {
+    pub fn cow(&self) -> Result<CowFileGaurd> {
+        check_locked(&self.path)?;
+        file_cow_with(&self.path, self.ctx.clone())
+    }
+
+    /// Release the guard's locks, reporting the first failure instead of logging it on drop.
+    pub fn release(self) -> Result<()> {
+        release_all(self.lock)
+    }
+}
+
+/// Can be sent to and dropped on another thread, see [`Tx`](crate::Tx).
+pub struct DirReadGaurd {
+    pub path: PathBuf,
+    /// The payload of an atomic directory at `path` as it was when the guard was created, `path` otherwise.
+    pub(crate) resolved: PathBuf,
+    pub(crate) lock: Vec<Lock>,
+    pub(crate) ctx: Ctx,
+}
+
+impl DirReadGaurd {
+    /// The directory that `path` led to when the guard was created: the payload of an atomic directory,
+    /// which stays the same directory for as long as the guard is held, and `path` itself otherwise. Once
+    /// the guard is dropped the payload may be replaced by a commit and deleted by gc.
+    pub fn resolved_path(&self) -> &Path {
+        &self.resolved
+    }
+
+    /// Number of entries in the directory, not counting sbdb's own files, the same ones
+    /// [`Client::list`](crate::Client::list) returns.
+    pub fn entry_count(&self) -> Result<usize> {
+        self.check_revoked()?;
+        Ok(prune::contents(&self.resolved, marker::is_root(&self.path))?.entries)
+    }
+
+    /// Whether the directory holds no entries but sbdb's own files, see [`DirReadGaurd::entry_count`]. A
+    /// directory may be empty while it still holds files that only gc removes, see
+    /// [`Client::prune_empty_dirs`](crate::Client::prune_empty_dirs).
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.entry_count()? == 0)
+    }
+
+    /// Read the file at `rel` below this directory, locking only what lies between the two. The returned
+    /// guard relies on this one's locks and must not outlive it.
+    pub fn read_file<P: AsRef<Path>>(&self, rel: P) -> Result<FileReadGaurd> {
+        self.check_revoked()?;
+        let rel = check_nested(rel.as_ref())?;
+        let lock = create_read_file_locks(&self.path, &rel, Some(Path::new("")), &self.ctx)?;
+        Ok(FileReadGaurd {
+            path: self.path.join(rel),
+            lock,
+            shared: Vec::new(),
+        })
+    }
+
+    /// Whether the guard was revoked, see [`FileReadGaurd::is_revoked`].
+    pub fn is_revoked(&self) -> bool {
+        is_revoked(&self.lock)
+    }
+
+    pub(crate) fn check_revoked(&self) -> Result<()> {
+        check_revoked(&self.lock, &self.path)
+    }
+
+    /// Release the guard's locks, reporting the first failure instead of logging it on drop.
+    pub fn release(self) -> Result<()> {
+        release_all(self.lock)
+    }
+}
+
+/// Can be sent to and dropped on another thread, see [`Tx`](crate::Tx).
+pub struct DirWriteGaurd {
+    pub path: PathBuf,
+    /// See [`DirReadGaurd::resolved_path`].
+    pub(crate) resolved: PathBuf,
+    pub(crate) lock: Vec<Lock>,
+    pub(crate) ctx: Ctx,
+}
+
+impl DirWriteGaurd {
+    /// See [`DirReadGaurd::resolved_path`]. Until [`DirWriteGaurd::cow_atomic`] is committed it is the payload
+    /// that the copy is made from.
+    pub fn resolved_path(&self) -> &Path {
+        &self.resolved
+    }
+
+    /// Read the file at `rel` below this directory, locking only what lies between the two. The returned
+    /// guard relies on this one's locks and must not outlive it.
+    pub fn read_file<P: AsRef<Path>>(&self, rel: P) -> Result<FileReadGaurd> {
+        let rel = check_nested(rel.as_ref())?;
+        let lock = create_read_file_locks(&self.path, &rel, Some(Path::new("")), &self.ctx)?;
+        Ok(FileReadGaurd {
+            path: self.path.join(rel),
+            lock,
+            shared: Vec::new(),
+        })
+    }
+
+    /// Write the file at `rel` below this directory, see [`DirWriteGaurd::read_file`].
+    pub fn write_file<P: AsRef<Path>>(&self, rel: P) -> Result<FileWriteGaurd> {
+        let rel = check_nested(rel.as_ref())?;
+        check_unreserved(&self.path, &rel)?;
+        let lock = create_write_file_locks(&self.path, &rel, Some(Path::new("")), &self.ctx)?;
+        Ok(FileWriteGaurd {
+            path: self.path.join(rel),
+            lock,
+            ctx: self.ctx.clone(),
+        })
+    }
+
+    pub fn cow(&self) -> Result<CowDirGaurd> {
+        // TODO: convert atomic to normal
+        check_locked(&self.path)?;
+        let lock = covering_write_lock(&self.lock, &self.path);
+        dir_cow_with(&self.path, lock, self.ctx.clone())
+    }
+
+    /// Release the guard's locks, reporting the first failure instead of logging it on drop.
+    pub fn release(self) -> Result<()> {
+        release_all(self.lock)
+    }
+
+    /// platform specific behavior:
+    ///
+    /// This feature uses symbolic links, which windows supports, but only in developer mode
+    /// or with escalated privlages. For that reason it should probably be avoided if you would
+    /// like to have cross-platform support.
+    pub fn cow_atomic(&self) -> Result<CowAtomicDirGaurd> {
+        check_locked(&self.path)?;
+        let payload = (self.resolved != self.path).then_some(self.resolved.as_path());
+        dir_cow_atomic_from(&self.path, payload, self.ctx.clone())
+    }
+
+    /// Make `rel` below this directory an atomic directory. A new one starts out empty without copying
+    /// anything and an existing one is left as it is. A plain directory at `rel` is only converted, by
+    /// copying its contents, if `convert` is set and is [`Error::AlreadyExistsAsPlainDir`] otherwise.
+    pub fn create_dir_atomic<P: AsRef<Path>>(
+        &self,
+        rel: P,
+        convert: bool,
+    ) -> Result<AtomicDirCreation> {
+        create_atomic_below(&self.path, rel.as_ref(), convert, &self.ctx)
+    }
+
+    /// Create the plain directory `rel` below this directory, along with any missing parents.
+    pub fn create_dir<P: AsRef<Path>>(&self, rel: P) -> Result<()> {
+        let rel = check_nested(rel.as_ref())?;
+        check_unreserved(&self.path, &rel)?;
+        let path = self.path.join(rel);
+        self.ctx.check_writable(&path)?;
+        self.ctx
+            .vfs
+            .create_dir_all(&path)
+            .at("create directory", &path)
+    }
+}
+
+#[cfg(windows)]
+pub(crate) const FILE_SHARE_READ: u32 = 0x00000001;
+#[cfg(windows)]
+pub(crate) const FILE_SHARE_WRITE: u32 = 0x00000002;
+#[cfg(windows)]
+pub(crate) const FILE_SHARE_DELETE: u32 = 0x00000004;
+
+pub fn open_lock_file<P: AsRef<Path>>(path: P) -> Result<File> {
+    StdVfs
+        .open_lock_file(path.as_ref(), None)
+        .at("open lock file", path)
+}
+
+pub fn open_lock_and_queue<P: AsRef<Path>>(path: P) -> Result<(File, File)> {
+    open_lock_and_queue_with(&StdVfs, path.as_ref(), None)
+}
+
+fn open_lock_and_queue_with(vfs: &dyn Vfs, path: &Path, mode: Option<u32>) -> Result<(File, File)> {
+    let path_lock = path_hidden_with_extension(path, artifact::LOCK)?;
+    let path_queue = path_hidden_with_extension(path, artifact::QUEUE)?;
+
+    let lock = vfs
+        .open_lock_file(&path_lock, mode)
+        .at("open lock file", &path_lock)?;
+    let queue = vfs
+        .open_lock_file(&path_queue, mode)
+        .at("open lock file", &path_queue)?;
+
+    Ok((lock, queue))
+}
+
+/// Entry whose lock files lock the entry at `rpath`. That is the entry itself, except for the root, which is
+/// locked through its marker so that no lock files are created next to the root, outside of the database.
+fn lock_target(root: &Path, rpath: &Path) -> PathBuf {
+    if rpath.as_os_str().is_empty() {
+        marker::root_lock_target(root)
+    } else {
+        root.join(rpath)
+    }
+}
+
+/// Opens the lock files of the entry at `path`, reusing cached ones if the client keeps them.
+fn open_lock_files(ctx: &Ctx, path: &Path) -> Result<LockFiles> {
+    if let Some(files) = ctx.lock_cache.as_ref().and_then(|cache| cache.take(path)) {
+        return Ok(files);
+    }
+    let (lock, queue) = open_lock_and_queue_with(&*ctx.vfs, path, ctx.lock_mode)?;
+    Ok(LockFiles {
+        lock: Arc::new(lock),
+        queue: Arc::new(queue),
+    })
+}
+
+pub enum Lock {
+    Read(ReadLock),
+    Write(WriteLock),
+}
+
+impl Lock {
+    pub fn release(self) -> Result<()> {
+        match self {
+            Lock::Read(lock) => lock.release(),
+            Lock::Write(lock) => lock.release(),
+        }
+    }
+
+    /// Whether the lock of the entry at `path` is currently held, found by briefly trying to take it. The
+    /// answer may be out of date by the time it is returned. Lock files are never created. A `path` that is
+    /// the root of a database probes the root's lock, see [`lock_target`].
+    pub fn probe<P: AsRef<Path>>(path: P) -> Result<LockStatus> {
+        let path = path.as_ref();
+        let target = match marker::is_root(path) {
+            true => marker::root_lock_target(path),
+            false => path.to_path_buf(),
+        };
+        let path_lock = path_hidden_with_extension(&target, artifact::LOCK)?;
+        let mut options = OpenOptions::new();
+        options.write(true);
+        #[cfg(windows)]
+        options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+        let file = match options.open(&path_lock) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(LockStatus::Free),
+            Err(e) => return Err(Error::io("open lock file", &path_lock, e)),
+        };
+        let try_lock = |result: std::result::Result<(), fs::TryLockError>| match result {
+            Ok(()) => file
+                .unlock()
+                .at("release lock on", &path_lock)
+                .map(|_| true),
+            Err(fs::TryLockError::WouldBlock) => Ok(false),
+            Err(fs::TryLockError::Error(e)) => Err(Error::io("probe lock on", &path_lock, e)),
+        };
+        if try_lock(file.try_lock())? {
+            Ok(LockStatus::Free)
+        } else if try_lock(file.try_lock_shared())? {
+            Ok(LockStatus::Read)
+        } else {
+            Ok(LockStatus::Write)
+        }
+    }
+}
+
+/// State of a lock as seen by [`Lock::probe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockStatus {
+    Free,
+    /// Held by one or more readers.
+    Read,
+    Write,
+}
+
+/// Releases every lock, even after a failure, and returns the first error encountered.
+pub(crate) fn release_all(locks: Vec<Lock>) -> Result<()> {
+    let mut result = Ok(());
+    for lock in locks {
+        let released = lock.release();
+        if result.is_ok() {
+            result = released;
+        }
+    }
+    result
+}
+
+/// What a [`ReadLock`] or [`WriteLock`] holds, depending on the [`LockBackend`] it was acquired with.
+pub(crate) enum Held {
+    File {
+        lock: Arc<File>,
+        /// Where the lock files go once the lock was released, along with the entry they lock and the queue
+        /// file.
+        cache: Option<(Arc<LockFileCache>, PathBuf, Arc<File>)>,
+    },
+    Dir(lockdir::LockDir),
+    /// Nothing, the entry lies below a root that this thread holds exclusively, see [`Client::lock_all`].
+    Covered,
+}
+
+impl Held {
+    /// Lock the entry at `path` through the lock files of `target`, see [`lock_target`]. Unless it should
+    /// `wait` for the lock, fails with [`Error::WouldBlock`] at once if it is held elsewhere, or others are
+    /// queued for it.
+    fn acquire(path: &Path, target: &Path, mode: LockMode, wait: bool, ctx: &Ctx) -> Result<Self> {
+        if exclusive::covers(ctx, path) {
+            return Ok(Held::Covered);
+        }
+        let operation = match mode {
+            LockMode::Read => "acquire read lock on",
+            LockMode::Write => "acquire write lock on",
+        };
+        match ctx.lock_backend {
+            LockBackend::Flock => {
+                let vfs = &ctx.vfs;
+                let (lock, queue) = loop {
+                    let LockFiles { lock, queue } = open_lock_files(ctx, target)?;
+                    if wait {
+                        vfs.lock(&queue, LockMode::Write)
+                            .at("enter lock queue for", path)?;
+                        match (&ctx.read_hold, mode) {
+                            (Some(read_hold), LockMode::Write) => read_hold
+                                .write_lock(&**vfs, &lock, path, target)
+                                .at(operation, path)?,
+                            _ => vfs.lock(&lock, mode).at(operation, path)?,
+                        }
+                    } else {
+                        let queued = vfs
+                            .try_lock(&queue, LockMode::Write)
+                            .at("enter lock queue for", path)?;
+                        if !(queued && vfs.try_lock(&lock, mode).at(operation, path)?) {
+                            if queued {
+                                vfs.unlock(&queue).at("leave lock queue for", path)?;
+                            }
+                            if let Some(cache) = &ctx.lock_cache {
+                                cache.put(target, LockFiles { lock, queue });
+                            }
+                            return Err(Error::WouldBlock {
+                                path: path.to_path_buf(),
+                            });
+                        }
+                    }
+                    vfs.unlock(&queue).at("leave lock queue for", path)?;
+                    // the lock files of the root move along with it when the root is committed as a whole,
+                    // there is no parent whose lock keeps that from happening while they are opened
+                    if cfg!(unix)
+                        && target != path
+                        && !lock_cache::same_file(
+                            &lock,
+                            &path_hidden_with_extension(target, artifact::LOCK)?,
+                        )
+                    {
+                        vfs.unlock(&lock).at("release lock on", path)?;
+                        continue;
+                    }
+                    break (lock, queue);
+                };
+                Ok(Held::File {
+                    lock,
+                    cache: ctx
+                        .lock_cache
+                        .clone()
+                        .map(|cache| (cache, target.to_path_buf(), queue)),
+                })
+            }
+            LockBackend::AtomicLockDir { lease } => {
+                match lockdir::LockDir::acquire(target, mode, lease, wait) {
+                    Ok(dir) => Ok(Held::Dir(dir)),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        Err(Error::WouldBlock {
+                            path: path.to_path_buf(),
+                        })
+                    }
+                    Err(e) => Err(Error::io(operation, path, e)),
+                }
+            }
+        }
+    }
+
+    fn unlock(&mut self, vfs: &dyn Vfs) -> std::io::Result<()> {
+        match self {
+            Held::File { lock, cache } => {
+                let result = vfs.unlock(lock);
+                if result.is_err() {
+                    *cache = None;
+                }
+                result
+            }
+            Held::Dir(dir) => dir.release(),
+            Held::Covered => Ok(()),
+        }
+    }
+
+    /// Return the lock files of a released lock to the cache they came from.
+    fn recycle(&mut self) {
+        if let Held::File { lock, cache } = self
+            && let Some((cache, target, queue)) = cache.take()
+        {
+            let lock = lock.clone();
+            cache.put(&target, LockFiles { lock, queue });
+        }
+    }
+}
+
+pub struct ReadLock {
+    pub(crate) held: Held,
+    pub(crate) path: PathBuf,
+    pub(crate) on_warning: Option<WarningCallback>,
+    pub(crate) redact: Redact,
+    pub(crate) vfs: Arc<dyn Vfs>,
+    pub(crate) released: bool,
+    /// Thread that took the lock, see [`lock_order`].
+    #[cfg(feature = "lock-order-check")]
+    pub(crate) owner: Option<std::thread::ThreadId>,
+    /// Set once the lock was revoked, see [`ClientBuilder::max_read_hold`].
+    pub(crate) revoked: Option<Arc<AtomicBool>>,
+    /// Where the lock is recorded while it is held, see [`Client::held_locks`].
+    pub(crate) tracked: Option<Arc<HeldLocks>>,
+}
+
+impl ReadLock {
+    #[cfg(test)]
+    pub(crate) fn new<P: AsRef<Path>>(path: P, ctx: &Ctx) -> Result<Self> {
+        let path = path.as_ref();
+        Self::acquire(
+            path.parent().unwrap(),
+            path.file_name().unwrap().as_ref(),
+            ctx,
+        )
+    }
+
+    pub(crate) fn acquire(root: &Path, rpath: &Path, ctx: &Ctx) -> Result<Self> {
+        Self::lock(root, rpath, true, ctx)
+    }
+
+    /// Like [`ReadLock::acquire`], failing with [`Error::WouldBlock`] rather than waiting unless `wait`.
+    pub(crate) fn lock(root: &Path, rpath: &Path, wait: bool, ctx: &Ctx) -> Result<Self> {
+        let path = root.join(rpath);
+        let start = Instant::now();
+        let held = Held::acquire(&path, &lock_target(root, rpath), LockMode::Read, wait, ctx)
+            .map_err(|e| ctx.redact.error(e))?;
+        let revoked = match (&ctx.read_hold, &held) {
+            (Some(read_hold), Held::File { lock, .. }) => Some(read_hold.acquired(&path, lock)),
+            _ => None,
+        };
+        #[cfg(feature = "lock-order-check")]
+        let owner = crate::lock_order::acquired(root, rpath);
+        let tracked = ctx.held.clone();
+        if let Some(tracked) = &tracked {
+            tracked.acquired(&path, LockMode::Read);
+        }
+        let wait = start.elapsed();
+        // locks taken below a guard are relative to it rather than the root
+        let rpath = ctx.rpath(&path).unwrap_or(rpath);
+        ctx.metrics
+            .lock_acquired(&ctx.redact.path(&path).shown(), LockMode::Read, wait);
+        ctx.metrics
+            .lock_wait(LockMode::Read, rpath.components().count(), wait);
+        trace::lock_acquired(rpath, ctx.redact.display(), LockMode::Read, wait);
+        #[cfg(feature = "testkit")]
+        crate::testkit::pause(crate::testkit::PausePoint::LockAcquired);
+
+        Ok(Self {
+            held,
+            path,
+            on_warning: ctx.on_warning.clone(),
+            redact: ctx.redact.clone(),
+            vfs: ctx.vfs.clone(),
+            released: false,
+            #[cfg(feature = "lock-order-check")]
+            owner,
+            revoked,
+            tracked,
+        })
+    }
+
+    /// Whether the lock was revoked for being held too long, see
+    /// [`ClientBuilder::max_read_hold`](crate::ClientBuilder::max_read_hold).
+    pub fn is_revoked(&self) -> bool {
+        self.revoked
+            .as_ref()
+            .is_some_and(|revoked| revoked.load(Ordering::Relaxed))
+    }
+
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.held
+            .unlock(&*self.vfs)
+            .at("release lock on", &self.path)
+    }
+}
+
+impl Drop for ReadLock {
+    fn drop(&mut self) {
+        if !self.released
+            && let Err(e) = self.held.unlock(&*self.vfs)
+        {
+            let warning = Warning::Unlock {
+                path: self.path.clone(),
+                error: Error::io("release lock on", &self.path, e),
+            };
+            report_warning(self.on_warning.as_ref(), &self.redact, warning);
+        }
+        self.held.recycle();
+        #[cfg(feature = "lock-order-check")]
+        if let Some(owner) = self.owner {
+            crate::lock_order::released(owner, &self.path);
+        }
+        if let Some(tracked) = &self.tracked {
+            tracked.released(&self.path, LockMode::Read);
+        }
+    }
+}
+
+pub struct WriteLock {
+    pub(crate) held: Held,
+    pub(crate) path: PathBuf,
+    pub(crate) on_warning: Option<WarningCallback>,
+    pub(crate) redact: Redact,
+    pub(crate) vfs: Arc<dyn Vfs>,
+    pub(crate) released: bool,
+    /// Thread that took the lock, see [`lock_order`].
+    #[cfg(feature = "lock-order-check")]
+    pub(crate) owner: Option<std::thread::ThreadId>,
+    /// Where the lock is recorded while it is held, see [`Client::held_locks`].
+    pub(crate) tracked: Option<Arc<HeldLocks>>,
+    /// Dropped along with the lock, see [`WriteLock::witness`].
+    pub(crate) alive: Arc<()>,
+}
+
+impl WriteLock {
+    #[cfg(test)]
+    pub(crate) fn new<P: AsRef<Path>>(path: P, ctx: &Ctx) -> Result<Self> {
+        let path = path.as_ref();
+        Self::acquire(
+            path.parent().unwrap(),
+            path.file_name().unwrap().as_ref(),
+            ctx,
+        )
+    }
+
+    pub(crate) fn acquire(root: &Path, rpath: &Path, ctx: &Ctx) -> Result<Self> {
+        Self::lock(root, rpath, true, ctx)
+    }
+
+    /// Like [`WriteLock::acquire`], failing with [`Error::WouldBlock`] rather than waiting unless `wait`.
+    pub(crate) fn lock(root: &Path, rpath: &Path, wait: bool, ctx: &Ctx) -> Result<Self> {
+        let path = root.join(rpath);
+        let start = Instant::now();
+        let held = Held::acquire(&path, &lock_target(root, rpath), LockMode::Write, wait, ctx)
+            .map_err(|e| ctx.redact.error(e))?;
+        #[cfg(feature = "lock-order-check")]
+        let owner = crate::lock_order::acquired(root, rpath);
+        #[cfg(feature = "strict-locking")]
+        crate::strict::acquired(&path);
+        let tracked = ctx.held.clone();
+        if let Some(tracked) = &tracked {
+            tracked.acquired(&path, LockMode::Write);
+        }
+        let wait = start.elapsed();
+        // locks taken below a guard are relative to it rather than the root
+        let rpath = ctx.rpath(&path).unwrap_or(rpath);
+        ctx.metrics
+            .lock_acquired(&ctx.redact.path(&path).shown(), LockMode::Write, wait);
+        ctx.metrics
+            .lock_wait(LockMode::Write, rpath.components().count(), wait);
+        trace::lock_acquired(rpath, ctx.redact.display(), LockMode::Write, wait);
+        #[cfg(feature = "testkit")]
+        crate::testkit::pause(crate::testkit::PausePoint::LockAcquired);
+
+        Ok(Self {
+            held,
+            path,
+            on_warning: ctx.on_warning.clone(),
+            redact: ctx.redact.clone(),
+            vfs: ctx.vfs.clone(),
+            released: false,
+            #[cfg(feature = "lock-order-check")]
+            owner,
+            tracked,
+            alive: Arc::new(()),
+        })
+    }
+
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.held
+            .unlock(&*self.vfs)
+            .at("release lock on", &self.path)
+    }
+
+    /// Tells whether the lock is still held, for a copy that relies on it to be committed, see
+    /// [`CowDirGaurd::commit`].
+    pub(crate) fn witness(&self) -> Weak<()> {
+        Arc::downgrade(&self.alive)
+    }
+}
+
+/// The deepest write lock among `locks` that covers `path`, see [`WriteLock::witness`].
+pub(crate) fn covering_write_lock(locks: &[Lock], path: &Path) -> Option<Weak<()>> {
+    locks
+        .iter()
+        .filter_map(|lock| match lock {
+            Lock::Write(lock) if path.starts_with(&lock.path) => Some(lock),
+            _ => None,
+        })
+        .max_by_key(|lock| lock.path.components().count())
+        .map(WriteLock::witness)
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        if !self.released
+            && let Err(e) = self.held.unlock(&*self.vfs)
+        {
+            let warning = Warning::Unlock {
+                path: self.path.clone(),
+                error: Error::io("release lock on", &self.path, e),
+            };
+            report_warning(self.on_warning.as_ref(), &self.redact, warning);
+        }
+        self.held.recycle();
+        #[cfg(feature = "lock-order-check")]
+        if let Some(owner) = self.owner {
+            crate::lock_order::released(owner, &self.path);
+        }
+        #[cfg(feature = "strict-locking")]
+        crate::strict::released(&self.path);
+        if let Some(tracked) = &self.tracked {
+            tracked.released(&self.path, LockMode::Write);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Reuse of opened lock and queue files, enabled with [`crate::ClientBuilder::lock_file_cache`]. Without
+//! it, every lock acquisition opens two files per ancestor of the locked entry.
+//!
+//! A handle is only ever used by one lock at a time: locks on the same open file do not exclude each
+//! other, so a handle is taken out of the cache while its lock is held and put back once it is released.
+//! Lock files may be deleted by gc, or moved away by a directory commit, in other processes, so a cached
+//! handle is checked against the path before it is reused. That check happens while the parent's lock is
+//! held, which is also what gc needs to delete the file, so the answer can not go stale before the lock
+//! is taken.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use schnellru::{ByLength, LruMap};
+
+use crate::{artifact, path_hidden_with_extension};
+
+/// Opened lock and queue files of an entry.
+pub(crate) struct LockFiles {
+    pub(crate) lock: Arc<File>,
+    pub(crate) queue: Arc<File>,
+}
+
+pub(crate) struct LockFileCache {
+    /// Idle handles by the path of the entry they lock, there can be several for an entry that was locked
+    /// concurrently.
+    files: Mutex<LruMap<PathBuf, Vec<LockFiles>>>,
+    capacity: u32,
+}
+
+impl LockFileCache {
+    /// Cache holding the handles of up to `capacity` entries.
+    pub(crate) fn new(capacity: u32) -> Self {
+        LockFileCache {
+            files: Mutex::new(LruMap::new(ByLength::new(capacity))),
+            capacity,
+        }
+    }
+
+    /// An empty cache of the same capacity.
+    pub(crate) fn fresh(&self) -> Self {
+        LockFileCache::new(self.capacity)
+    }
+
+    /// Take an idle handle for the entry at `path`, if there is one that still refers to its lock files.
+    pub(crate) fn take(&self, path: &Path) -> Option<LockFiles> {
+        let files = self.lock().get(path)?.pop()?;
+        match (
+            path_hidden_with_extension(path, artifact::LOCK),
+            path_hidden_with_extension(path, artifact::QUEUE),
+        ) {
+            (Ok(lock), Ok(queue))
+                if same_file(&files.lock, &lock) && same_file(&files.queue, &queue) =>
+            {
+                Some(files)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the handles of a released lock.
+    pub(crate) fn put(&self, path: &Path, files: LockFiles) {
+        let mut cache = self.lock();
+        match cache.get(path) {
+            Some(idle) => idle.push(files),
+            None => {
+                cache.insert(path.to_path_buf(), vec![files]);
+            }
+        }
+    }
+
+    /// Forget the handles of the entry at `path`.
+    pub(crate) fn evict(&self, path: &Path) {
+        self.lock().remove(path);
+    }
+
+    /// Forget the handles of `dir` and everything below it.
+    pub(crate) fn evict_under(&self, dir: &Path) {
+        let mut cache = self.lock();
+        let stale = cache
+            .iter()
+            .map(|(path, _)| path)
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in stale {
+            cache.remove(&path);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruMap<PathBuf, Vec<LockFiles>>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Whether `file` is the file at `path`.
+#[cfg(unix)]
+pub(crate) fn same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// The standard library can not identify an open file on other platforms, so handles are never reused
+/// there.
+#[cfg(not(unix))]
+pub(crate) fn same_file(_file: &File, _path: &Path) -> bool {
+    false
+}
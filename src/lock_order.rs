@@ -0,0 +1,82 @@
+//! Order in which each thread of this process takes locks, checked with the `lock-order-check` feature.
+//! Threads that take the locks of the same entries in different orders can deadlock, each waiting for a
+//! lock the other holds, which [`crate::TxBuilder::begin`] avoids by taking all of them sorted by path.
+//! Guards taken one after the other are checked to keep to the same order, so that an inconsistent order
+//! shows up in every test that takes it rather than in the rare run where two threads interleave.
+//!
+//! A guard read locks the ancestors of its entry, the root included, so a thread that holds one may only
+//! take further locks relative to it, such as with [`crate::DirWriteGaurd::write_file`], or in a single
+//! transaction. Taking the root's read lock again could otherwise wait behind a writer queued for it,
+//! which waits for this thread.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread::{self, ThreadId},
+};
+
+use crate::{Error, INTERNAL_DIR, Result};
+
+/// Entries each thread holds locks on, in the order it took them. A lock released on another thread than
+/// the one that took it is removed from the list of the thread that took it.
+static HELD: Mutex<Option<HashMap<ThreadId, Vec<PathBuf>>>> = Mutex::new(None);
+
+/// Records that the current thread took the lock of the entry at `rpath` below `root`, returning the thread
+/// to pass to [`released`]. Locks of sbdb's own files in the internal directory are only ever taken last
+/// and are not recorded.
+pub(crate) fn acquired(root: &Path, rpath: &Path) -> Option<ThreadId> {
+    if rpath.starts_with(INTERNAL_DIR) {
+        return None;
+    }
+    let owner = thread::current().id();
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    held.get_or_insert_default()
+        .entry(owner)
+        .or_default()
+        .push(root.join(rpath));
+    Some(owner)
+}
+
+pub(crate) fn released(owner: ThreadId, path: &Path) {
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(by_thread) = held.as_mut() else {
+        return;
+    };
+    if let Some(paths) = by_thread.get_mut(&owner) {
+        if let Some(i) = paths.iter().rposition(|held| held == path) {
+            paths.remove(i);
+        }
+        if paths.is_empty() {
+            by_thread.remove(&owner);
+        }
+    }
+}
+
+/// Fails unless the lock of `path` sorts after every lock the current thread holds, other than those of
+/// its ancestors. Panics instead in debug builds, where an inconsistent order is a bug to be found rather
+/// than handled.
+pub(crate) fn check(path: &Path) -> Result<()> {
+    let violated = {
+        let held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+        held.as_ref()
+            .and_then(|by_thread| by_thread.get(&thread::current().id()))
+            .and_then(|paths| {
+                paths
+                    .iter()
+                    .find(|held| path < held.as_path() && !path.starts_with(held))
+                    .cloned()
+            })
+    };
+    let Some(held) = violated else {
+        return Ok(());
+    };
+    let err = Error::LockOrderViolation {
+        held,
+        acquiring: path.to_path_buf(),
+    };
+    if cfg!(debug_assertions) {
+        panic!("{err}");
+    }
+    Err(err)
+}
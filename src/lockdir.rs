@@ -0,0 +1,388 @@
+//! Locks held by creating a directory, for databases on network filesystems where `flock` style locks are
+//! not reliable, enabled with [`LockBackend::AtomicLockDir`]. Creating a directory is atomic even over
+//! NFS, so whoever creates `.name.lockd.sbdb` holds the lock on `name` until it deletes it again.
+//!
+//! There is no shared mode between processes: a read lock is as exclusive as a write lock, and since
+//! every operation locks the ancestors of its entry, operations from different processes run one at a time.
+//! Within a process read locks are still shared, which also keeps a thread that holds one guard from
+//! deadlocking when it takes another one below the same ancestors. Waiters poll instead of queueing, so
+//! there is no fairness between processes either.
+//!
+//! A process that crashes leaves its lock directories behind. They are taken over once the process is
+//! known to be gone, which can only be told for processes on the same host on linux, or once they are
+//! older than the lease. No lock may be held longer than the lease, otherwise another process takes it.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, OnceLock, mpsc},
+    thread,
+    time::Duration,
+};
+
+use crate::{LockMode, Vfs, artifact, path_hidden_with_extension, unused_child};
+
+/// How long a lock directory is respected by default before it is considered left behind by a crash.
+pub const DEFAULT_LOCK_LEASE: Duration = Duration::from_secs(60);
+
+/// File inside a lock directory naming the process that holds it, as its host name and pid on two lines.
+const OWNER: &str = "owner";
+
+const MIN_POLL: Duration = Duration::from_millis(1);
+const MAX_POLL: Duration = Duration::from_millis(50);
+
+/// How a client locks entries, installed with [`crate::ClientBuilder::lock_backend`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockBackend {
+    /// Advisory locks on lock files, with shared read locks and a fair queue. They are only as reliable
+    /// as the filesystem's support for `flock` on unix and `LockFileEx` on windows.
+    #[default]
+    Flock,
+    /// Lock directories, see the module documentation for their tradeoffs. Every client that uses a
+    /// database must use the same backend, locks of one are invisible to the other.
+    AtomicLockDir {
+        /// How old a lock directory has to be before it is taken over, see [`DEFAULT_LOCK_LEASE`].
+        lease: Duration,
+    },
+}
+
+impl LockBackend {
+    /// [`LockBackend::AtomicLockDir`] with the default lease.
+    pub const fn atomic_lock_dir() -> Self {
+        LockBackend::AtomicLockDir {
+            lease: DEFAULT_LOCK_LEASE,
+        }
+    }
+}
+
+/// What [`crate::ClientBuilder::build`] does when the root is on a network filesystem. Detection is only
+/// implemented on linux.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NetworkFsPolicy {
+    /// Do not check.
+    #[default]
+    Ignore,
+    /// Report [`crate::Warning::NetworkFilesystem`] and keep the configured backend.
+    Warn,
+    /// Switch to [`LockBackend::atomic_lock_dir`] unless lock directories were configured already.
+    UseLockDirs,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// A thread of this process is creating the directory.
+    Acquiring,
+    Held {
+        mode: LockMode,
+        count: usize,
+    },
+}
+
+/// Lock directories of this process, by path.
+struct Registry {
+    dirs: Mutex<HashMap<PathBuf, State>>,
+    changed: Condvar,
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| Registry {
+        dirs: Mutex::new(HashMap::new()),
+        changed: Condvar::new(),
+    })
+}
+
+/// A held lock directory, deleted once the last holder in this process releases it.
+pub(crate) struct LockDir {
+    dir: PathBuf,
+    released: bool,
+}
+
+impl LockDir {
+    /// Block until the entry at `path` is locked in `mode`, or fail with [`io::ErrorKind::WouldBlock`] if it
+    /// is held elsewhere and it should not `wait`.
+    pub(crate) fn acquire(
+        path: &Path,
+        mode: LockMode,
+        lease: Duration,
+        wait: bool,
+    ) -> io::Result<Self> {
+        let dir = path_hidden_with_extension(path, artifact::LOCK_DIR).map_err(io::Error::other)?;
+        let registry = registry();
+        {
+            let mut dirs = registry.dirs.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                match dirs.get_mut(&dir) {
+                    None => {
+                        dirs.insert(dir.clone(), State::Acquiring);
+                        break;
+                    }
+                    Some(State::Held {
+                        mode: LockMode::Read,
+                        count,
+                    }) if mode == LockMode::Read => {
+                        *count += 1;
+                        return Ok(LockDir {
+                            dir,
+                            released: false,
+                        });
+                    }
+                    Some(_) if !wait => return Err(io::ErrorKind::WouldBlock.into()),
+                    Some(_) => {
+                        dirs = registry
+                            .changed
+                            .wait(dirs)
+                            .unwrap_or_else(|e| e.into_inner())
+                    }
+                }
+            }
+        }
+
+        let created = create(&dir, lease, wait);
+        let mut dirs = registry.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        match created {
+            Ok(()) => {
+                dirs.insert(dir.clone(), State::Held { mode, count: 1 });
+                Ok(LockDir {
+                    dir,
+                    released: false,
+                })
+            }
+            Err(e) => {
+                dirs.remove(&dir);
+                registry.changed.notify_all();
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn release(&mut self) -> io::Result<()> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+        let registry = registry();
+        let mut dirs = registry.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(State::Held { count, .. }) = dirs.get_mut(&self.dir) {
+            *count -= 1;
+            if *count > 0 {
+                return Ok(());
+            }
+        }
+        dirs.remove(&self.dir);
+        // deleted while the registry is locked, so no thread of this process can find the directory
+        // before it is gone
+        let result = fs::remove_dir_all(&self.dir);
+        registry.changed.notify_all();
+        result
+    }
+}
+
+/// Create the lock directory `dir`, polling until no other process holds it if it should `wait`.
+fn create(dir: &Path, lease: Duration, wait: bool) -> io::Result<()> {
+    let mut poll = MIN_POLL;
+    loop {
+        match fs::create_dir(dir) {
+            Ok(()) => {
+                let owner = format!("{}\n{}\n", hostname(), std::process::id());
+                if let Err(e) = fs::write(dir.join(OWNER), owner) {
+                    let _ = fs::remove_dir_all(dir);
+                    return Err(e);
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if is_stale(dir, lease) {
+                    take_over(dir, lease)?;
+                    continue;
+                }
+                if !wait {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                thread::sleep(poll);
+                poll = (poll * 2).min(MAX_POLL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether the lock directory `dir` was left behind by a process that is gone.
+fn is_stale(dir: &Path, lease: Duration) -> bool {
+    let Ok(metadata) = fs::metadata(dir) else {
+        // released in the meantime
+        return false;
+    };
+    let expired = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > lease);
+    // the owner is only written after the directory was created, until then only the lease applies
+    let owner = fs::read_to_string(dir.join(OWNER)).unwrap_or_default();
+    let mut lines = owner.lines();
+    let gone = match (lines.next(), lines.next().and_then(|pid| pid.parse().ok())) {
+        (Some(host), Some(pid)) if host == hostname() => !process_exists(pid),
+        _ => false,
+    };
+    expired || gone
+}
+
+/// Delete the stale lock directory `dir`. Only one process at a time gets to check and delete it, so that
+/// none deletes a directory that another one created after taking over the stale one.
+fn take_over(dir: &Path, lease: Duration) -> io::Result<()> {
+    let breaker = path_with_suffix(dir, artifact::LOCK_BREAK);
+    match fs::create_dir(&breaker) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            // only held for a moment, unless its owner crashed
+            let expired = fs::metadata(&breaker)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > lease);
+            if expired {
+                let _ = fs::remove_dir(&breaker);
+            } else {
+                thread::sleep(MIN_POLL);
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    let result = if is_stale(dir, lease) {
+        log::warn!(path:? = dir, operation = "lock"; "taking over stale lock directory");
+        match fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    } else {
+        Ok(())
+    };
+    fs::remove_dir(&breaker)?;
+    result
+}
+
+/// `.name.lockd.sbdb` with its suffix replaced by `suffix`.
+fn path_with_suffix(dir: &Path, suffix: &str) -> PathBuf {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    let base = name.strip_suffix(artifact::LOCK_DIR).unwrap_or(&name);
+    dir.with_file_name(format!("{}{}", base, suffix))
+}
+
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        #[cfg(target_os = "linux")]
+        if let Ok(name) = fs::read_to_string("/proc/sys/kernel/hostname") {
+            return name.trim().to_string();
+        }
+        std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .unwrap_or_default()
+    })
+}
+
+/// Whether a process with id `pid` runs on this host. Only known on linux, elsewhere it is assumed to.
+fn process_exists(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        Path::new("/proc").join(pid.to_string()).exists()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// Name of the network filesystem that `path` lives on, if it is on one.
+#[cfg(target_os = "linux")]
+pub(crate) fn network_filesystem(path: &Path) -> Option<&'static str> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: path is a valid nul terminated string and stat is only read once statfs filled it in
+    let stat = unsafe {
+        if libc::statfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // the width and signedness of f_type differ between architectures, the magic numbers are 32 bits
+    match stat.f_type as u32 {
+        0x6969 => Some("nfs"),
+        0x517b => Some("smb"),
+        0xff53_4d42 => Some("cifs"),
+        0xfe53_4d42 => Some("smb2"),
+        0x5346_414f => Some("afs"),
+        0x7375_7245 => Some("coda"),
+        0x00c3_6400 => Some("ceph"),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn network_filesystem(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+/// How long [`check_locking`] waits for a second lock to be refused.
+const LOCK_CHECK_WAIT: Duration = Duration::from_millis(100);
+
+/// Whether file locks exclude each other on a filesystem, as found by [`crate::Client::check_locking`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockingFidelity {
+    /// A second exclusive lock waited for the first.
+    Enforced,
+    /// A second exclusive lock was granted while the first was held, so [`LockBackend::Flock`] excludes
+    /// no one.
+    NotEnforced,
+    /// The check itself failed.
+    Unknown,
+}
+
+/// Check that an exclusive lock on a file in `dir` keeps a second one on the same file waiting. The second
+/// lock is taken through a file opened separately, on a helper thread. The file is removed afterwards.
+pub(crate) fn check_locking(vfs: &Arc<dyn Vfs>, dir: &Path) -> LockingFidelity {
+    let path = unused_child(dir);
+    let result = try_second_lock(vfs, &path);
+    if let Err(e) = vfs.remove_file(&path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::warn!(path:? = path, operation = "cleanup"; "failed to remove lock check file: {}", e);
+    }
+    match result {
+        Ok(true) => LockingFidelity::Enforced,
+        Ok(false) => LockingFidelity::NotEnforced,
+        Err(e) => {
+            log::warn!(path:? = path, operation = "check locking"; "failed to check locking: {}", e);
+            LockingFidelity::Unknown
+        }
+    }
+}
+
+/// Whether a second exclusive lock on `path` waited for the first one.
+fn try_second_lock(vfs: &Arc<dyn Vfs>, path: &Path) -> io::Result<bool> {
+    let first = vfs.open_lock_file(path, None)?;
+    let second = vfs.open_lock_file(path, None)?;
+    vfs.lock(&first, LockMode::Write)?;
+    let (send, recv) = mpsc::channel();
+    let helper = {
+        let vfs = vfs.clone();
+        thread::spawn(move || {
+            let locked = vfs.lock(&second, LockMode::Write);
+            let _ = send.send(());
+            locked.and_then(|()| vfs.unlock(&second))
+        })
+    };
+    let waited = recv.recv_timeout(LOCK_CHECK_WAIT).is_err();
+    let unlocked = vfs.unlock(&first);
+    helper
+        .join()
+        .map_err(|_| io::Error::other("lock check thread panicked"))??;
+    unlocked?;
+    Ok(waited)
+}
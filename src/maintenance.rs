@@ -0,0 +1,109 @@
+//! The maintenance lock at `.sbdb/maint.lock`, which keeps gc from removing sbdb's own files while a walk
+//! that looks at them is running. [`Client::gc_with`] takes it for writing, exports, backups and
+//! [`Client::stats_with`] take it for reading, so a walk can not find a temporary copy or a backup that gc
+//! removes before it is opened, and gc does not wait behind the locks of a walk entry by entry.
+//!
+//! It is independent of the locks of entries and always taken before any of them, so it can not deadlock
+//! with them. It is taken without waiting in line: a process that wants it tries again until
+//! [`crate::ClientBuilder::maintenance_wait`] runs out, then fails with [`crate::Error::Timeout`]. Locks of
+//! this process count too, so gc called while the same thread holds [`Client::maintenance_read`] times
+//! out. It is always a file lock, whatever the [`crate::LockBackend`] of the client.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Client, Error, Held, INTERNAL_DIR, LockMode, ReadLock, Result, WriteLock, error::IoResultExt,
+    exclusive,
+};
+
+/// How long the maintenance lock is waited for by default, see [`crate::ClientBuilder::maintenance_wait`].
+pub const DEFAULT_MAINTENANCE_WAIT: Duration = Duration::from_secs(60);
+
+const MAINTENANCE_LOCK: &str = "maint.lock";
+
+impl Client {
+    /// Take the maintenance lock for reading, as exports, backups and stats do, keeping gc from running
+    /// until the lock is released. Take it before any other lock, see the [module documentation](self).
+    pub fn maintenance_read(&self) -> Result<ReadLock> {
+        self.redacted(|| {
+            let (held, path) = self.maintenance_lock(LockMode::Read)?;
+            Ok(ReadLock {
+                held,
+                path,
+                on_warning: self.inner.on_warning.clone(),
+                redact: self.inner.redact.clone(),
+                vfs: self.inner.vfs.clone(),
+                released: false,
+                #[cfg(feature = "lock-order-check")]
+                owner: None,
+                revoked: None,
+                tracked: None,
+            })
+        })
+    }
+
+    /// Take the maintenance lock for writing, as gc does, waiting for the walks that hold it for reading.
+    /// Take it before any other lock, see the [module documentation](self).
+    pub fn maintenance_write(&self) -> Result<WriteLock> {
+        self.redacted(|| {
+            let (held, path) = self.maintenance_lock(LockMode::Write)?;
+            Ok(WriteLock {
+                held,
+                path,
+                on_warning: self.inner.on_warning.clone(),
+                redact: self.inner.redact.clone(),
+                vfs: self.inner.vfs.clone(),
+                released: false,
+                #[cfg(feature = "lock-order-check")]
+                owner: None,
+                tracked: None,
+                alive: Arc::new(()),
+            })
+        })
+    }
+
+    pub(crate) fn maintenance_lock_path(&self) -> PathBuf {
+        self.inner.root.join(INTERNAL_DIR).join(MAINTENANCE_LOCK)
+    }
+
+    fn maintenance_lock(&self, mode: LockMode) -> Result<(Held, PathBuf)> {
+        let path = self.maintenance_lock_path();
+        if exclusive::covers(&self.ctx(), &path) {
+            return Ok((Held::Covered, path));
+        }
+        let internal = path.parent().unwrap_or(Path::new(""));
+        self.inner
+            .vfs
+            .create_dir_all(internal)
+            .at("create directory", internal)?;
+        let file = self
+            .inner
+            .vfs
+            .open_lock_file(&path, self.inner.lock_mode)
+            .at("open lock file", &path)?;
+        let start = Instant::now();
+        let poll = (self.inner.maintenance_wait / 10)
+            .clamp(Duration::from_millis(1), Duration::from_millis(50));
+        while !self
+            .inner
+            .vfs
+            .try_lock(&file, mode)
+            .at("acquire maintenance lock on", &path)?
+        {
+            if start.elapsed() >= self.inner.maintenance_wait {
+                return Err(Error::Timeout { path });
+            }
+            thread::sleep(poll);
+        }
+        let held = Held::File {
+            lock: Arc::new(file),
+            cache: None,
+        };
+        Ok((held, path))
+    }
+}
@@ -0,0 +1,92 @@
+//! Every root is marked by a file in its internal directory, so that a client can tell when it is asked to
+//! open a root inside another one. Locks only reach up to the root of the client taking them, so the locks
+//! of the two clients would not exclude each other.
+
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{Error, INTERNAL_DIR, Result, artifact, error::IoResultExt, unused_child};
+
+/// Name of the marker in the internal directory of a root.
+const ROOT_MARKER: &str = "root";
+
+/// Marks `root` as a root, returning whether it was not marked before.
+pub(crate) fn mark(root: &Path) -> Result<bool> {
+    let internal = root.join(INTERNAL_DIR);
+    fs::create_dir_all(&internal).at("create directory", &internal)?;
+    let marker = internal.join(ROOT_MARKER);
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&marker)
+    {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(Error::io("create", &marker, e)),
+    }
+}
+
+/// Fails unless files can be created in the internal directory of `root`, so that a root that can not be
+/// written is found when it is opened rather than by the first write.
+pub(crate) fn check_writable(root: &Path) -> Result<()> {
+    let internal = root.join(INTERNAL_DIR);
+    let probe = unused_child(&internal);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .at("create files in", &internal)?;
+    fs::remove_file(&probe).at("remove", &probe)
+}
+
+/// The closest directory above `root` that is marked as a root.
+pub(crate) fn outer_root(root: &Path) -> Result<Option<PathBuf>> {
+    let root = fs::canonicalize(root).at("resolve", root)?;
+    Ok(root
+        .ancestors()
+        .skip(1)
+        .find(|dir| is_root(dir))
+        .map(Path::to_path_buf))
+}
+
+/// Directories below `root` that are marked as roots, without descending into them, following symbolic
+/// links or going deeper than `max_depth`. Directories that can not be read are skipped.
+pub(crate) fn inner_roots(root: &Path, max_depth: usize) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut pending = vec![(root.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Ok(metadata) = fs::symlink_metadata(entry.path()) else {
+                continue;
+            };
+            let own_artifact =
+                artifact::parse(&name).is_some_and(|(kind, _)| artifact::is_own(kind, &metadata));
+            if !metadata.is_dir() || own_artifact || (depth == 0 && name == INTERNAL_DIR) {
+                continue;
+            }
+            if is_root(&entry.path()) {
+                found.push(entry.path());
+            } else if depth < max_depth {
+                pending.push((entry.path(), depth + 1));
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Entry whose lock files lock the root as a whole, see [`crate::lock_target`].
+pub(crate) fn root_lock_target(root: &Path) -> PathBuf {
+    root.join(INTERNAL_DIR).join(ROOT_MARKER)
+}
+
+pub(crate) fn is_root(dir: &Path) -> bool {
+    dir.join(INTERNAL_DIR).join(ROOT_MARKER).is_file()
+}
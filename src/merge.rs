@@ -0,0 +1,329 @@
+//! Three-way merges of directory trees, for bringing together two copies of a directory that were edited
+//! independently from a common base, such as the copies of two writers that did not hold the directory's
+//! lock the whole time.
+//!
+//! Every path is decided on its own: a change made on one side only is taken over, as is the same change
+//! made on both sides, and a removal wins if the other side left the entry as it was in the base. Anything
+//! else is a conflict, resolved by the [`MergePolicy`] unless a content merger passed to
+//! [`three_way_with`] resolves it first. Directories are merged entry by entry while both sides have
+//! them. Files are compared by their contents and symbolic links by their targets. sbdb's own files are
+//! ignored.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Ctx, Error, Result, artifact, copy_file, copy_recursive, error::IoResultExt, puuid,
+    unused_hidden_path,
+};
+
+/// How [`three_way`] resolves conflicts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MergePolicy {
+    PreferOurs,
+    PreferTheirs,
+    /// Leave conflicts unresolved, in which case nothing is written.
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConflictKind {
+    /// Both sides changed a file differently, or added different files at the same path.
+    BothChanged,
+    /// One side removed what the other changed.
+    ChangedAndRemoved,
+    /// One side has a directory where the other has a file or link.
+    TypeChanged,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    Ours,
+    Theirs,
+    /// Written by the content merger.
+    Merged,
+    Unresolved,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeConflict {
+    /// Path relative to the merged trees.
+    pub path: PathBuf,
+    pub kind: ConflictKind,
+    pub resolution: Resolution,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MergeReport {
+    /// Paths where a change made only on our side was taken over.
+    pub from_ours: usize,
+    /// Paths where a change made only on their side was taken over.
+    pub from_theirs: usize,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeReport {
+    /// Whether every conflict was resolved, and so the merge was written.
+    pub fn resolved(&self) -> bool {
+        self.conflicts
+            .iter()
+            .all(|conflict| conflict.resolution != Resolution::Unresolved)
+    }
+}
+
+/// Merges the contents of a file changed on both sides, given its path relative to the trees and the
+/// contents in the base, if it was there, ours and theirs. Returns `None` to leave the conflict to the
+/// policy.
+pub type ContentMerger<'a> = &'a dyn Fn(&Path, Option<&[u8]>, &[u8], &[u8]) -> Option<Vec<u8>>;
+
+/// Merge the changes that `ours` and `theirs` made to `base` into `dest`, which must not exist. The merge
+/// is put together next to `dest` and moved into place once complete. If a conflict remains unresolved,
+/// which only happens with [`MergePolicy::Fail`], nothing is written and the report says where.
+pub fn three_way(
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    dest: &Path,
+    policy: MergePolicy,
+) -> Result<MergeReport> {
+    three_way_with(base, ours, theirs, dest, policy, &|_, _, _, _| None)
+}
+
+/// Like [`three_way`], with `merger` getting the first go at files that both sides changed.
+pub fn three_way_with(
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    dest: &Path,
+    policy: MergePolicy,
+    merger: ContentMerger,
+) -> Result<MergeReport> {
+    if fs::symlink_metadata(dest).is_ok() {
+        return Err(Error::AlreadyExists {
+            path: dest.to_path_buf(),
+        });
+    }
+    let mut merge = Merge {
+        policy,
+        merger,
+        report: MergeReport::default(),
+        plan: Vec::new(),
+    };
+    merge.path(Path::new(""), node(base)?, node(ours)?, node(theirs)?)?;
+    if !merge.report.resolved() || merge.plan.is_empty() {
+        return Ok(merge.report);
+    }
+
+    let tmp = unused_hidden_path(dest, || format!(".{}.tmp", puuid()))?;
+    let ctx = Ctx::detached();
+    let result = ctx
+        .copy(|stats| {
+            for (rel, action) in &merge.plan {
+                let dst = match rel.as_os_str().is_empty() {
+                    true => tmp.clone(),
+                    false => tmp.join(rel),
+                };
+                match action {
+                    Action::Dir => fs::create_dir(&dst).at("create directory", &dst)?,
+                    Action::Write(bytes) => fs::write(&dst, bytes).at("write", &dst)?,
+                    Action::Take(Node::File(src)) => copy_file(src, &dst, &ctx, stats)?,
+                    Action::Take(Node::Dir(src)) => copy_recursive(src, &dst, &ctx, stats)?,
+                    Action::Take(Node::Link(target)) => {
+                        ctx.vfs.symlink_dir(target, &dst).at("create link", &dst)?
+                    }
+                }
+            }
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&tmp, dest).at("move merge to", dest));
+    if result.is_err() {
+        let _ = match fs::symlink_metadata(&tmp) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&tmp),
+            _ => fs::remove_file(&tmp),
+        };
+    }
+    result.map(|()| merge.report)
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    File(PathBuf),
+    Dir(PathBuf),
+    /// Holds the link's target.
+    Link(PathBuf),
+}
+
+enum Action {
+    Dir,
+    Take(Node),
+    Write(Vec<u8>),
+}
+
+struct Merge<'a> {
+    policy: MergePolicy,
+    merger: ContentMerger<'a>,
+    report: MergeReport,
+    /// What to write at each path of the merge, parents before their children.
+    plan: Vec<(PathBuf, Action)>,
+}
+
+impl Merge<'_> {
+    fn path(
+        &mut self,
+        rel: &Path,
+        base: Option<Node>,
+        ours: Option<Node>,
+        theirs: Option<Node>,
+    ) -> Result<()> {
+        if let (Some(Node::Dir(o)), Some(Node::Dir(t))) = (&ours, &theirs) {
+            self.plan.push((rel.to_path_buf(), Action::Dir));
+            let b = match &base {
+                Some(Node::Dir(b)) => Some(b.as_path()),
+                _ => None,
+            };
+            let mut names = children(o)?;
+            names.extend(children(t)?);
+            if let Some(b) = b {
+                names.extend(children(b)?);
+            }
+            for name in names {
+                let child = |dir: Option<&Path>| match dir {
+                    Some(dir) => node(&dir.join(&name)),
+                    None => Ok(None),
+                };
+                self.path(
+                    &rel.join(&name),
+                    child(b)?,
+                    child(Some(o))?,
+                    child(Some(t))?,
+                )?;
+            }
+            return Ok(());
+        }
+
+        if same(&ours, &theirs)? {
+            self.take(rel, ours);
+        } else if same(&base, &ours)? {
+            self.report.from_theirs += 1;
+            self.take(rel, theirs);
+        } else if same(&base, &theirs)? {
+            self.report.from_ours += 1;
+            self.take(rel, ours);
+        } else {
+            self.conflict(rel, base, ours, theirs)?;
+        }
+        Ok(())
+    }
+
+    fn conflict(
+        &mut self,
+        rel: &Path,
+        base: Option<Node>,
+        ours: Option<Node>,
+        theirs: Option<Node>,
+    ) -> Result<()> {
+        let kind = match (&ours, &theirs) {
+            (None, _) | (_, None) => ConflictKind::ChangedAndRemoved,
+            (Some(Node::Dir(_)), _) | (_, Some(Node::Dir(_))) => ConflictKind::TypeChanged,
+            _ => ConflictKind::BothChanged,
+        };
+        if let (Some(Node::File(o)), Some(Node::File(t))) = (&ours, &theirs) {
+            let base = match &base {
+                Some(Node::File(b)) => Some(fs::read(b).at("read", b)?),
+                _ => None,
+            };
+            let (o, t) = (fs::read(o).at("read", o)?, fs::read(t).at("read", t)?);
+            if let Some(merged) = (self.merger)(rel, base.as_deref(), &o, &t) {
+                self.plan.push((rel.to_path_buf(), Action::Write(merged)));
+                self.resolve(rel, kind, Resolution::Merged);
+                return Ok(());
+            }
+        }
+        match self.policy {
+            MergePolicy::PreferOurs => {
+                self.take(rel, ours);
+                self.resolve(rel, kind, Resolution::Ours);
+            }
+            MergePolicy::PreferTheirs => {
+                self.take(rel, theirs);
+                self.resolve(rel, kind, Resolution::Theirs);
+            }
+            MergePolicy::Fail => self.resolve(rel, kind, Resolution::Unresolved),
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, rel: &Path, node: Option<Node>) {
+        if let Some(node) = node {
+            self.plan.push((rel.to_path_buf(), Action::Take(node)));
+        }
+    }
+
+    fn resolve(&mut self, rel: &Path, kind: ConflictKind, resolution: Resolution) {
+        self.report.conflicts.push(MergeConflict {
+            path: rel.to_path_buf(),
+            kind,
+            resolution,
+        });
+    }
+}
+
+fn node(path: &Path) -> Result<Option<Node>> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io("read metadata of", path, e)),
+    };
+    Ok(Some(if metadata.is_symlink() {
+        Node::Link(fs::read_link(path).at("read link", path)?)
+    } else if metadata.is_dir() {
+        Node::Dir(path.to_path_buf())
+    } else {
+        Node::File(path.to_path_buf())
+    }))
+}
+
+/// Names of the entries of the directory `dir`, leaving out sbdb's own files.
+fn children(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut names = BTreeSet::new();
+    for entry in fs::read_dir(dir).at("read directory", dir)? {
+        let name = entry.at("read directory", dir)?.file_name();
+        if artifact::parse(&name).is_none() {
+            names.insert(PathBuf::from(name));
+        }
+    }
+    Ok(names)
+}
+
+/// Whether `a` and `b` have the same contents.
+fn same(a: &Option<Node>, b: &Option<Node>) -> Result<bool> {
+    match (a, b) {
+        (None, None) => Ok(true),
+        (Some(Node::Link(a)), Some(Node::Link(b))) => Ok(a == b),
+        (Some(Node::File(a)), Some(Node::File(b))) => {
+            let len = |path: &Path| {
+                fs::metadata(path)
+                    .at("read metadata of", path)
+                    .map(|m| m.len())
+            };
+            Ok(len(a)? == len(b)? && fs::read(a).at("read", a)? == fs::read(b).at("read", b)?)
+        }
+        (Some(Node::Dir(a)), Some(Node::Dir(b))) => {
+            let names = children(a)?;
+            if names != children(b)? {
+                return Ok(false);
+            }
+            for name in names {
+                if !same(&node(&a.join(&name))?, &node(&b.join(&name))?)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
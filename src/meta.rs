@@ -0,0 +1,329 @@
+//! The format of a database, recorded in `<root>/.sbdb/meta.json` so that a client can tell when a root was
+//! written by a newer version of sbdb, whose layout it would misread, or by an older one that needs to be
+//! migrated first. Roots without the file predate it and are at format 0.
+//!
+//! The file is written and read here rather than with `serde_json`, which is optional, and only ever holds
+//! numbers and lists of names.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{Client, Ctx, Error, INTERNAL_DIR, LockBackend, Result, file_replace_with};
+
+/// Format of the roots written by this version of sbdb. Format 2 moved the lock files of the root from
+/// next to it into its internal directory, where older versions would not look for them.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Layout features this version of sbdb knows, recorded once a client that uses them opened the root.
+const KNOWN_FEATURES: [&str; 3] = ["generations", "lock_dirs", "rollups"];
+
+const META: &str = "meta.json";
+
+/// A step that brings roots of an older format up to `version`, registered with
+/// [`crate::ClientBuilder::migration`]. Migrations run when a client opens a root whose format is older
+/// than [`FORMAT_VERSION`], in the order of their versions, each at most once per root. They run while the
+/// root is write locked, so they must work on the files below `root` directly rather than through a
+/// client, and should be idempotent, since a crash can interrupt them before they are recorded.
+pub trait Migration: Send + Sync {
+    /// Recorded in the meta file once the migration ran, so it has to stay the same across releases.
+    fn name(&self) -> &str;
+    /// The format the root is at once this and all earlier migrations ran.
+    fn version(&self) -> u32;
+    fn migrate(&self, root: &Path) -> Result<()>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Meta {
+    format_version: u32,
+    features: BTreeSet<String>,
+    migrations: Vec<String>,
+}
+
+impl Client {
+    /// Format of the database as recorded in its meta file, see [`FORMAT_VERSION`].
+    pub fn format_version(&self) -> Result<u32> {
+        self.redacted(|| Ok(read(&self.inner.root)?.map_or(0, |meta| meta.format_version)))
+    }
+
+    /// Checks the format of the root when the client is built, then runs the migrations it needs and
+    /// records the layout features the client uses. The meta file is only rewritten, under the root's write
+    /// lock, when it changes.
+    pub(crate) fn open_format(&self, migrations: &[Arc<dyn Migration>]) -> Result<()> {
+        let mut features = BTreeSet::new();
+        if self.inner.generations.is_some() {
+            features.insert("generations".to_string());
+        }
+        if self.inner.rollups.is_some() {
+            features.insert("rollups".to_string());
+        }
+        if matches!(self.inner.lock_backend, LockBackend::AtomicLockDir { .. }) {
+            features.insert("lock_dirs".to_string());
+        }
+        if let Some(meta) = read(&self.inner.root)?.as_ref() {
+            check(meta)?;
+            if meta.format_version == FORMAT_VERSION && features.is_subset(&meta.features) {
+                return Ok(());
+            }
+        }
+
+        let _gaurd = self.write_dir("")?;
+        // another client may have gotten here first
+        let mut meta = read(&self.inner.root)?.unwrap_or_default();
+        check(&meta)?;
+        if meta.format_version < FORMAT_VERSION {
+            let mut pending: Vec<_> = migrations
+                .iter()
+                .filter(|m| m.version() > meta.format_version && m.version() <= FORMAT_VERSION)
+                .filter(|m| !meta.migrations.iter().any(|name| name == m.name()))
+                .collect();
+            pending.sort_by_key(|m| m.version());
+            for migration in pending {
+                log::info!(path:? = self.inner.redact.path(&self.inner.root), operation = "migrate"; "running migration {} to format {}", migration.name(), migration.version());
+                migration.migrate(&self.inner.root)?;
+                meta.format_version = meta.format_version.max(migration.version());
+                meta.migrations.push(migration.name().to_string());
+                write(&self.inner.root, &meta)?;
+            }
+            meta.format_version = FORMAT_VERSION;
+        }
+        meta.features.extend(features);
+        write(&self.inner.root, &meta)
+    }
+}
+
+/// Fails if the root needs a newer version of sbdb.
+fn check(meta: &Meta) -> Result<()> {
+    let unknown: Vec<_> = meta
+        .features
+        .iter()
+        .filter(|feature| !KNOWN_FEATURES.contains(&feature.as_str()))
+        .cloned()
+        .collect();
+    if meta.format_version > FORMAT_VERSION || !unknown.is_empty() {
+        return Err(Error::IncompatibleFormat {
+            found: meta.format_version,
+            supported: FORMAT_VERSION,
+            unknown_features: unknown,
+        });
+    }
+    Ok(())
+}
+
+fn path(root: &Path) -> PathBuf {
+    root.join(INTERNAL_DIR).join(META)
+}
+
+fn read(root: &Path) -> Result<Option<Meta>> {
+    let path = path(root);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io("read", &path, e)),
+    };
+    parse(&text).map(Some).map_err(|message| Error::Decode {
+        path,
+        source: message.into(),
+    })
+}
+
+/// Written like every other internal file, without the client's versioning, generations and quota.
+fn write(root: &Path, meta: &Meta) -> Result<()> {
+    let list = |names: &mut dyn Iterator<Item = &String>| {
+        names.map(|name| quote(name)).collect::<Vec<_>>().join(", ")
+    };
+    let text = format!(
+        "{{\n  \"format_version\": {},\n  \"features\": [{}],\n  \"migrations\": [{}]\n}}\n",
+        meta.format_version,
+        list(&mut meta.features.iter()),
+        list(&mut meta.migrations.iter()),
+    );
+    file_replace_with(&path(root), text.as_bytes(), true, Ctx::detached())
+}
+
+/// Reads an object of numbers and lists of strings, ignoring the keys it does not know.
+fn parse(text: &str) -> std::result::Result<Meta, String> {
+    let mut parser = Parser::new(text);
+    let mut meta = Meta::default();
+    parser.expect('{')?;
+    if !parser.eat('}') {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            match key.as_str() {
+                "format_version" => meta.format_version = parser.number()?,
+                "features" => meta.features = parser.strings()?.into_iter().collect(),
+                "migrations" => meta.migrations = parser.strings()?,
+                _ if parser.rest.starts_with('[') => {
+                    parser.strings()?;
+                }
+                _ if parser.rest.starts_with('"') => {
+                    parser.string()?;
+                }
+                _ => {
+                    parser.number()?;
+                }
+            }
+            if parser.eat('}') {
+                break;
+            }
+            parser.expect(',')?;
+        }
+    }
+    parser.end()?;
+    Ok(meta)
+}
+
+/// An object of strings on a single line, as read by [`parse_object`].
+pub(crate) fn format_object(object: &BTreeMap<String, String>) -> String {
+    let fields: Vec<_> = object
+        .iter()
+        .map(|(key, value)| format!("{}: {}", quote(key), quote(value)))
+        .collect();
+    format!("{{{}}}\n", fields.join(", "))
+}
+
+/// Reads an object of strings.
+pub(crate) fn parse_object(text: &str) -> std::result::Result<BTreeMap<String, String>, String> {
+    let mut parser = Parser::new(text);
+    let mut object = BTreeMap::new();
+    parser.expect('{')?;
+    if !parser.eat('}') {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            object.insert(key, parser.string()?);
+            if parser.eat('}') {
+                break;
+            }
+            parser.expect(',')?;
+        }
+    }
+    parser.end()?;
+    Ok(object)
+}
+
+/// `value` as a JSON string.
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Just enough of a JSON parser for sbdb's own files, which hold objects of numbers, strings and lists of
+/// strings.
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser {
+            rest: text.trim_start(),
+        }
+    }
+
+    /// Fails unless all of the text was read.
+    fn end(&self) -> std::result::Result<(), String> {
+        match self.rest.is_empty() {
+            true => Ok(()),
+            false => Err("trailing characters".to_string()),
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest.trim_start();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> std::result::Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(format!("expected {}", c)),
+        }
+    }
+
+    fn string(&mut self) -> std::result::Result<String, String> {
+        let mut chars = self
+            .rest
+            .strip_prefix('"')
+            .ok_or("expected a string")?
+            .char_indices();
+        let mut value = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = self.rest[i + 2..].trim_start();
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, c @ ('"' | '\\' | '/'))) => value.push(c),
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, 'b')) => value.push('\u{8}'),
+                    Some((_, 'f')) => value.push('\u{c}'),
+                    Some((i, 'u')) => {
+                        // surrogate pairs are not needed for what sbdb writes
+                        let c = self
+                            .rest
+                            .get(i + 2..i + 6)
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or("invalid unicode escape")?;
+                        value.push(c);
+                        chars.nth(3);
+                    }
+                    _ => return Err("unsupported escape".to_string()),
+                },
+                c => value.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn number(&mut self) -> std::result::Result<u32, String> {
+        let end = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let number = self.rest[..end].parse().map_err(|_| "expected a number")?;
+        self.rest = self.rest[end..].trim_start();
+        Ok(number)
+    }
+
+    fn strings(&mut self) -> std::result::Result<Vec<String>, String> {
+        self.expect('[')?;
+        let mut strings = Vec::new();
+        if self.eat(']') {
+            return Ok(strings);
+        }
+        loop {
+            strings.push(self.string()?);
+            if self.eat(']') {
+                return Ok(strings);
+            }
+            self.expect(',')?;
+        }
+    }
+}
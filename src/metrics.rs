@@ -0,0 +1,133 @@
+use std::{
+    path::Path,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::GcReport;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "metrics",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum LockMode {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CommitKind {
+    File,
+    Dir,
+    AtomicDir,
+}
+
+impl CommitKind {
+    /// Name of the kind in sbdb's own files.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            CommitKind::File => "file",
+            CommitKind::Dir => "dir",
+            CommitKind::AtomicDir => "atomic_dir",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "file" => Some(CommitKind::File),
+            "dir" => Some(CommitKind::Dir),
+            "atomic_dir" => Some(CommitKind::AtomicDir),
+            _ => None,
+        }
+    }
+}
+
+/// Instrumentation hooks, installed with [`crate::ClientBuilder::metrics`]. Every method has an empty
+/// default implementation so implementors only need to override what they care about. Methods are called
+/// on the hot path, so implementations should be cheap and must not block.
+pub trait Metrics: Send + Sync {
+    /// A lock was acquired on `path` after waiting `wait` (including the queue lock).
+    fn lock_acquired(&self, _path: &Path, _mode: LockMode, _wait: Duration) {}
+
+    /// Called along with [`Metrics::lock_acquired`], with how many components the entry is below the root
+    /// in place of its path, `0` for the root itself. Meant for aggregating waits into histograms labelled
+    /// with values of which there are few, unlike paths, see [`crate::HistogramMetrics`] with the `metrics`
+    /// feature.
+    fn lock_wait(&self, _mode: LockMode, _depth: usize, _wait: Duration) {}
+
+    /// A file was duplicated while making a copy-on-write copy.
+    fn cow_copied(&self, _bytes: u64, _reflinked: bool) {}
+
+    /// A copy-on-write copy was committed.
+    fn commit(&self, _kind: CommitKind, _duration: Duration) {}
+
+    /// A gc pass finished.
+    fn gc_run(&self, _report: &GcReport) {}
+}
+
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Shared no-op instance, so that code without a [`crate::Client`] does not allocate.
+pub(crate) fn noop() -> Arc<dyn Metrics> {
+    static NOOP: OnceLock<Arc<dyn Metrics>> = OnceLock::new();
+    NOOP.get_or_init(|| Arc::new(NoopMetrics)).clone()
+}
+
+/// Simple counter based [`Metrics`] implementation, suitable for tests and small applications.
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+    pub read_locks: AtomicU64,
+    pub write_locks: AtomicU64,
+    pub lock_wait_nanos: AtomicU64,
+    pub files_copied: AtomicU64,
+    pub files_reflinked: AtomicU64,
+    pub bytes_copied: AtomicU64,
+    pub bytes_reflinked: AtomicU64,
+    pub file_commits: AtomicU64,
+    pub dir_commits: AtomicU64,
+    pub atomic_dir_commits: AtomicU64,
+    pub commit_nanos: AtomicU64,
+    pub gc_runs: AtomicU64,
+}
+
+impl Metrics for AtomicMetrics {
+    fn lock_acquired(&self, _path: &Path, mode: LockMode, wait: Duration) {
+        match mode {
+            LockMode::Read => self.read_locks.fetch_add(1, Ordering::Relaxed),
+            LockMode::Write => self.write_locks.fetch_add(1, Ordering::Relaxed),
+        };
+        self.lock_wait_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn cow_copied(&self, bytes: u64, reflinked: bool) {
+        if reflinked {
+            self.files_reflinked.fetch_add(1, Ordering::Relaxed);
+            self.bytes_reflinked.fetch_add(bytes, Ordering::Relaxed);
+        } else {
+            self.files_copied.fetch_add(1, Ordering::Relaxed);
+            self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn commit(&self, kind: CommitKind, duration: Duration) {
+        match kind {
+            CommitKind::File => self.file_commits.fetch_add(1, Ordering::Relaxed),
+            CommitKind::Dir => self.dir_commits.fetch_add(1, Ordering::Relaxed),
+            CommitKind::AtomicDir => self.atomic_dir_commits.fetch_add(1, Ordering::Relaxed),
+        };
+        self.commit_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn gc_run(&self, _report: &GcReport) {
+        self.gc_runs.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,81 @@
+//! Listing a directory a page at a time. Pages are ordered by name rather than by the unspecified order
+//! of `fs::read_dir`, and the next one starts after the last name of the previous one, so entries are
+//! neither repeated nor skipped when the directory changes between pages, except for those added or
+//! removed before the current position.
+
+use std::{
+    collections::BinaryHeap,
+    ffi::{OsStr, OsString},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    Client, DirReadGaurd, INTERNAL_DIR, Result, artifact, error::IoResultExt, marker,
+    normalize_rpath,
+};
+
+#[cfg(windows)]
+use crate::winfs;
+
+/// Names of a part of a directory, see [`DirReadGaurd::list_page`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Page {
+    /// Sorted names of the entries, without sbdb's own files.
+    pub names: Vec<OsString>,
+    /// Whether there are entries after the last one, which the next page starts after.
+    pub has_more: bool,
+}
+
+impl DirReadGaurd {
+    /// Up to `limit` names of entries of the directory that sort after `after`, or from the first one if it
+    /// is `None`, like [`Client::list`] returns them. Only `limit` names are kept in memory at a time while
+    /// the directory is read, however large it is.
+    pub fn list_page(&self, after: Option<&OsStr>, limit: usize) -> Result<Page> {
+        self.check_revoked()?;
+        let dir = self.resolved_path();
+        let root = marker::is_root(&self.path);
+        // the largest of the smallest names seen so far is on top, one more than the limit tells if there
+        // are more
+        let mut smallest = BinaryHeap::new();
+        for entry in fs::read_dir(dir).at("read directory", dir)? {
+            let name = entry.at("read directory", dir)?.file_name();
+            if (root && name == INTERNAL_DIR)
+                || artifact::parse(&name).is_some()
+                || after.is_some_and(|after| name.as_os_str() <= after)
+            {
+                continue;
+            }
+            #[cfg(windows)]
+            if winfs::is_delete_pending(&dir.join(&name)) {
+                continue;
+            }
+            if smallest.len() <= limit {
+                smallest.push(name);
+            } else if smallest.peek().is_some_and(|largest| name < *largest) {
+                smallest.pop();
+                smallest.push(name);
+            }
+        }
+        let mut names = smallest.into_sorted_vec();
+        let has_more = names.len() > limit;
+        names.truncate(limit);
+        Ok(Page { names, has_more })
+    }
+}
+
+impl Client {
+    /// A page of the directory at `rpath` under a read lock of its own, see [`DirReadGaurd::list_page`].
+    pub fn list_page<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        after: Option<&OsStr>,
+        limit: usize,
+    ) -> Result<Page> {
+        self.redacted(|| {
+            self.read_dir(normalize_rpath(rpath.as_ref())?)?
+                .list_page(after, limit)
+        })
+    }
+}
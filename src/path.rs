@@ -0,0 +1,130 @@
+//! Paths inside a database: checking and normalizing the paths that callers pass in, and naming the hidden
+//! files that sbdb keeps next to an entry.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{Error, INTERNAL_DIR, RESERVED_SUFFIXES, Result, artifact, puuid};
+
+/// Checks that no component of `rpath` below `root` is named like one of sbdb's own files, and that it does
+/// not lead into the internal directory at the root.
+pub(crate) fn check_unreserved(root: &Path, rpath: &Path) -> Result<()> {
+    if rpath.iter().next() == Some(OsStr::new(INTERNAL_DIR)) {
+        return Err(Error::ReservedName {
+            path: root.join(rpath),
+            suffix: INTERNAL_DIR,
+        });
+    }
+    for name in rpath.iter() {
+        let name = name.to_string_lossy();
+        if let Some(suffix) = RESERVED_SUFFIXES
+            .into_iter()
+            .find(|suffix| name.ends_with(suffix))
+        {
+            return Err(Error::ReservedName {
+                path: root.join(rpath),
+                suffix,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn strip_trailing_slash(path: PathBuf) -> PathBuf {
+    path.components().as_path().to_path_buf()
+}
+
+pub fn create_backup_ext() -> String {
+    let mut ext = String::new();
+    ext.push('.');
+    ext.push_str(&puuid());
+    ext.push_str(artifact::BACKUP);
+    ext
+}
+
+/// Normalizes `rel`, which must name an entry strictly below the directory it is relative to.
+pub(crate) fn check_nested(rel: &Path) -> Result<PathBuf> {
+    let normalized = normalize_rpath(rel)?;
+    if normalized.as_os_str().is_empty() {
+        return Err(Error::invalid_path(rel, "empty relative path"));
+    }
+    Ok(normalized)
+}
+
+/// `rpath` rebuilt from its components with the native separator, without `.` components and repeated or
+/// trailing separators, so that every spelling of an entry derives the same lock files and is locked in
+/// the same order. Both `/` and `\` separate components on windows, elsewhere `\` is part of a name.
+/// Fails if `rpath` could lead out of the directory it is relative to.
+pub(crate) fn normalize_rpath(rpath: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in rpath.components() {
+        match component {
+            std::path::Component::Normal(name) => normalized.push(name),
+            std::path::Component::CurDir => {}
+            _ => return Err(Error::invalid_path(rpath, "not a plain relative path")),
+        }
+    }
+    Ok(normalized)
+}
+
+pub(crate) fn path_hidden_with_extension<P: AsRef<Path>>(path: P, ext: &str) -> Result<PathBuf> {
+    path_modify_filename(path, |name| {
+        let mut result = OsString::new();
+        result.push(".");
+        result.push(&name);
+        result.push(ext);
+        name.clear();
+        name.push(result);
+    })
+}
+
+/// Path next to `path` with an extension made by `ext`, which is made again as long as something exists at
+/// the path, so that a colliding puuid never reuses a leftover.
+pub(crate) fn unused_hidden_path(path: &Path, ext: impl Fn() -> String) -> Result<PathBuf> {
+    loop {
+        let candidate = path_hidden_with_extension(path, &ext())?;
+        if fs::symlink_metadata(&candidate).is_err() {
+            return Ok(candidate);
+        }
+    }
+}
+
+/// Path of a new puuid in `dir`, at which nothing exists.
+pub(crate) fn unused_child(dir: &Path) -> PathBuf {
+    loop {
+        let candidate = dir.join(puuid());
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+    }
+}
+
+fn path_modify_filename<P: AsRef<Path>, F: FnOnce(&mut OsString)>(
+    path: P,
+    modify: F,
+) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let mut name = path
+        .file_name()
+        .ok_or_else(|| Error::invalid_path(path, "missing file name"))?
+        .to_os_string();
+    modify(&mut name);
+    let parent = path
+        .parent()
+        .ok_or_else(|| Error::invalid_path(path, "missing parent"))?;
+    Ok(parent.join(name))
+}
+
+/// Fails with [`Error::TreeTooDeep`] once a walk reaches `path`, `depth` directories below where it started.
+pub(crate) fn check_depth(path: &Path, depth: usize, max_depth: usize) -> Result<()> {
+    if depth > max_depth {
+        return Err(Error::TreeTooDeep {
+            path: path.to_path_buf(),
+            max_depth,
+        });
+    }
+    Ok(())
+}
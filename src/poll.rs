@@ -0,0 +1,99 @@
+//! Change notifications by polling generation counters, for platforms where the `watch` feature is not
+//! available or too heavy. Only commits of clients that track generations are seen, see
+//! [`crate::ClientBuilder::track_generations`].
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{Client, Result, normalize_rpath};
+
+/// How long [`Client::poll_changes`] sleeps between checks right after a change. The sleep doubles with
+/// every check that finds nothing, up to [`PollState::max_interval`].
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+pub const DEFAULT_MAX_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The generations [`Client::poll_changes`] last saw, carried from one call to the next.
+#[derive(Clone, Debug)]
+pub struct PollState {
+    seen: HashMap<PathBuf, u64>,
+    interval: Duration,
+    max_interval: Duration,
+}
+
+impl Default for PollState {
+    fn default() -> Self {
+        PollState {
+            seen: HashMap::new(),
+            interval: MIN_POLL_INTERVAL,
+            max_interval: DEFAULT_MAX_POLL_INTERVAL,
+        }
+    }
+}
+
+impl PollState {
+    /// Longest sleep between two checks, which bounds how late a change is noticed.
+    pub fn max_interval(mut self, max_interval: Duration) -> Self {
+        self.max_interval = max_interval.max(MIN_POLL_INTERVAL);
+        self
+    }
+
+    /// Generation last seen at `rpath`, `None` if it was not polled yet.
+    pub fn generation<P: AsRef<Path>>(&self, rpath: P) -> Option<u64> {
+        self.seen.get(rpath.as_ref()).copied()
+    }
+}
+
+/// A subscribed path that something was committed at or below.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangedPath {
+    pub path: PathBuf,
+    pub generation: u64,
+}
+
+impl Client {
+    /// Wait up to `budget` for a commit at or below one of `subscriptions`, returning every subscription
+    /// whose generation advanced since `state` last saw it, or nothing once the budget is used up. Paths
+    /// that `state` has not seen yet are only recorded, so the first call for a subscription never reports
+    /// it. Counters are read under shared locks that are released before sleeping. Changes may be over
+    /// reported like with [`Client::changed_since`], but are never missed.
+    pub fn poll_changes(
+        &self,
+        subscriptions: &[PathBuf],
+        state: &mut PollState,
+        budget: Duration,
+    ) -> Result<Vec<ChangedPath>> {
+        self.redacted(|| {
+            let deadline = Instant::now() + budget;
+            loop {
+                let mut changed = Vec::new();
+                for rpath in subscriptions {
+                    let rpath = normalize_rpath(rpath)?;
+                    let generation = self.subtree_generation(&rpath)?;
+                    match state.seen.insert(rpath.clone(), generation) {
+                        Some(seen) if seen != generation => changed.push(ChangedPath {
+                            path: rpath,
+                            generation,
+                        }),
+                        _ => {}
+                    }
+                }
+                if !changed.is_empty() {
+                    // more changes tend to follow
+                    state.interval = MIN_POLL_INTERVAL;
+                    return Ok(changed);
+                }
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(changed);
+                }
+                thread::sleep(state.interval.min(left));
+                state.interval = (state.interval * 2).min(state.max_interval);
+            }
+        })
+    }
+}
@@ -0,0 +1,14 @@
+//! The types that most code using sbdb names, to be imported all at once with `use sbdb::prelude::*`: the
+//! client and its builder, the guards, transactions, errors and the options of the operations that take
+//! them. Everything here is also exported at the root of the crate, where the rest of the API lives.
+
+#[cfg(feature = "tar")]
+pub use crate::TarOptions;
+pub use crate::{
+    AtomicCowOptions, Client, ClientBuilder, CowAtomicDirGaurd, CowDirGaurd, CowFileGaurd,
+    DedupOptions, DirReadGaurd, DirWriteGaurd, Error, ExportOptions, FileReadGaurd, FileWriteGaurd,
+    GcOptions, GcReport, Result, RetentionPolicy, StatsOptions, TryPolicy, Tx, TxBuilder,
+    VerifyOptions, VersioningPolicy,
+};
+#[cfg(feature = "serde_json")]
+pub use crate::{AuditOptions, JsonOptions};
@@ -0,0 +1,171 @@
+//! Counting the entries of a directory and removing directories that hold none. A directory keeps the lock
+//! files of the entries it once held, and maybe their counters and sidecars, so it is empty as far as
+//! [`crate::Client::list`] is concerned long before `fs::read_dir` says so.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    ArtifactKind, Client, Error, INTERNAL_DIR, ReplicatedOp, Result, artifact, check_depth,
+    error::IoResultExt, is_atomic_dir_link, path_hidden_with_extension, resolve_atomic_dir,
+};
+
+/// What a directory holds.
+pub(crate) struct Contents {
+    /// Entries other than sbdb's own files, those [`crate::Client::list`] returns.
+    pub(crate) entries: usize,
+    /// Whether sbdb's own files include some that only gc may remove, such as temporaries and backups.
+    pub(crate) pending: bool,
+}
+
+/// Reads the contents of `dir`, which holds the internal directory if it is the `root`.
+pub(crate) fn contents(dir: &Path, root: bool) -> Result<Contents> {
+    let mut contents = Contents {
+        entries: 0,
+        pending: false,
+    };
+    for entry in fs::read_dir(dir).at("read directory", dir)? {
+        let entry = entry.at("read directory", dir)?;
+        let name = entry.file_name();
+        if root && name == INTERNAL_DIR {
+            continue;
+        }
+        let Some((kind, _)) = artifact::parse(&name) else {
+            contents.entries += 1;
+            continue;
+        };
+        let metadata = entry.metadata().at("read metadata of", entry.path())?;
+        // files of entries that are gone, which go along with the directory
+        let removable = matches!(
+            kind,
+            ArtifactKind::Lock
+                | ArtifactKind::Queue
+                | ArtifactKind::Generation
+                | ArtifactKind::Ttl
+                | ArtifactKind::Meta
+                | ArtifactKind::Rollup
+        );
+        if !removable || !artifact::is_own(kind, &metadata) {
+            contents.pending = true;
+        }
+    }
+    Ok(contents)
+}
+
+impl Client {
+    /// Remove the directories below `rpath` that hold no entries, deepest first, so that directories left
+    /// empty by that are removed too. `rpath` itself is kept. Each directory is checked and removed while its
+    /// parent is write locked, along with the lock files of the entries it once held and its own. Atomic
+    /// directories are removed with their payload. Directories that still hold files which only gc removes,
+    /// such as an uncommitted copy or a backup, are left for a later run after gc. Returns how many
+    /// directories were removed.
+    pub fn prune_empty_dirs<P: AsRef<Path>>(&self, rpath: P) -> Result<usize> {
+        self.redacted(|| {
+            let rpath = crate::normalize_rpath(rpath.as_ref())?;
+            // every directory is listed after its parent, so walking the list backwards visits children first
+            let mut dirs = Vec::new();
+            let mut pending = vec![rpath.clone()];
+            while let Some(dir) = pending.pop() {
+                let path = self.inner.root.join(&dir);
+                check_depth(&path, dir.components().count(), self.inner.max_depth)?;
+                let children = self.child_dirs(&dir)?;
+                pending.extend(children.iter().cloned());
+                dirs.extend(children);
+            }
+            let mut removed = 0;
+            for dir in dirs.iter().rev() {
+                if self.prune_dir(dir)? {
+                    removed += 1;
+                }
+            }
+            Ok(removed)
+        })
+    }
+
+    /// The plain and atomic directories in the directory at `rpath`, relative to the root.
+    fn child_dirs(&self, rpath: &Path) -> Result<Vec<PathBuf>> {
+        let gaurd = match self.read_dir(rpath) {
+            Ok(gaurd) => gaurd,
+            // removed since its parent was listed
+            Err(Error::NotFound { .. }) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let dir = gaurd.resolved_path();
+        let mut children = Vec::new();
+        for entry in fs::read_dir(dir).at("read directory", dir)? {
+            let entry = entry.at("read directory", dir)?;
+            let name = entry.file_name();
+            if (rpath.as_os_str().is_empty() && name == INTERNAL_DIR)
+                || artifact::parse(&name).is_some()
+            {
+                continue;
+            }
+            let file_type = entry.file_type().at("read metadata of", entry.path())?;
+            if file_type.is_dir() || (file_type.is_symlink() && is_atomic_dir_link(&entry.path())?)
+            {
+                children.push(rpath.join(name));
+            }
+        }
+        Ok(children)
+    }
+
+    /// Removes the directory at `rpath` if it holds no entries, returning whether it did.
+    fn prune_dir(&self, rpath: &Path) -> Result<bool> {
+        let (Some(parent), Some(name)) = (rpath.parent(), rpath.file_name()) else {
+            return Ok(false);
+        };
+        // no one else can enter the directory while its parent is write locked
+        let dir = self.write_dir(parent)?;
+        let gaurd = dir.write_file(name)?;
+        let path = &gaurd.path;
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(Error::io("read metadata of", path, e)),
+        };
+        let resolved = resolve_atomic_dir(path)?;
+        let atomic = resolved != *path;
+        if !(metadata.is_dir() || atomic) {
+            return Ok(false);
+        }
+        let contents = contents(&resolved, false)?;
+        if contents.entries > 0 || contents.pending {
+            return Ok(false);
+        }
+
+        gaurd.ctx.check_writable(path)?;
+        let before = gaurd.ctx.usage_before(path);
+        // the link goes first, a payload without one is removed by gc should this fail halfway
+        self.inner
+            .vfs
+            .remove_dir_all(path)
+            .at("remove empty directory", path)?;
+        if atomic {
+            self.inner
+                .vfs
+                .remove_dir_all(&resolved)
+                .at("remove empty directory", &resolved)?;
+        }
+        gaurd.ctx.replicate(ReplicatedOp::Remove, path);
+        gaurd.ctx.roll_up(path, before);
+        crate::entry_meta::remove(path)?;
+        let path = path.clone();
+        gaurd.release()?;
+
+        if let Some(cache) = &self.inner.lock_cache {
+            cache.evict_under(&path);
+        }
+        for ext in [artifact::TTL, artifact::LOCK, artifact::QUEUE] {
+            let sidecar = path_hidden_with_extension(&path, ext)?;
+            match self.inner.vfs.remove_file(&sidecar) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(Error::io("remove", &sidecar, e));
+                }
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    Client, Error, Result, check_unreserved, error::IoResultExt, file_replace_with, puuid,
+};
+
+/// Subdirectory holding the items that were popped but not acknowledged yet.
+const IN_FLIGHT: &str = "in-flight";
+
+/// A directory where every file is an item of a queue, shared by any number of producers and consumers
+/// across processes. Items are named after the time they were pushed, so they are popped in about the
+/// order they were pushed in, and exactly in that order when pushed by the same process.
+///
+/// A popped item is moved aside until it is acknowledged with [`DbQueue::ack`], so that an item whose
+/// consumer crashed can be put back with [`DbQueue::requeue_stale`].
+#[derive(Clone, Debug)]
+pub struct DbQueue {
+    client: Client,
+    rpath: PathBuf,
+}
+
+/// Name of an item of a [`DbQueue`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QueueItemId(String);
+
+impl QueueItemId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for QueueItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Client {
+    /// Open the queue stored in the directory `rpath`. The directory is created by the first push.
+    pub fn queue<P: AsRef<Path>>(&self, rpath: P) -> DbQueue {
+        DbQueue {
+            client: self.clone(),
+            rpath: rpath.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl DbQueue {
+    /// Add an item holding `bytes` to the back of the queue. Pushes only read lock the queue directory, so
+    /// they do not wait for each other.
+    pub fn push(&self, bytes: &[u8]) -> Result<QueueItemId> {
+        check_unreserved(self.client.root(), &self.rpath)?;
+        let dir = self.client.root().join(&self.rpath);
+        fs::create_dir_all(&dir).at("create directory", &dir)?;
+        let id = QueueItemId(format!("{:020}-{}", timestamp(), puuid()));
+        let gaurd = self.client.write_file(self.rpath.join(&id.0))?;
+        file_replace_with(&gaurd.path, bytes, true, gaurd.ctx.clone())?;
+        Ok(id)
+    }
+
+    /// Take the item at the front of the queue, `None` if it is empty. The item stays in flight until it is
+    /// acknowledged.
+    pub fn pop(&self) -> Result<Option<(QueueItemId, Vec<u8>)>> {
+        if !self.client.root().join(&self.rpath).is_dir() {
+            return Ok(None);
+        }
+        let gaurd = self.client.write_dir(&self.rpath)?;
+        let mut front: Option<String> = None;
+        for entry in fs::read_dir(&gaurd.path).at("read directory", &gaurd.path)? {
+            let entry = entry.at("read directory", &gaurd.path)?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            // locks and temporaries are hidden, items never are
+            if name.starts_with('.') || !entry.file_type().is_ok_and(|t| t.is_file()) {
+                continue;
+            }
+            if front.as_ref().is_none_or(|front| name < *front) {
+                front = Some(name);
+            }
+        }
+        let Some(name) = front else {
+            return Ok(None);
+        };
+
+        let in_flight = gaurd.path.join(IN_FLIGHT);
+        fs::create_dir_all(&in_flight).at("create directory", &in_flight)?;
+        let path = in_flight.join(&name);
+        fs::rename(gaurd.path.join(&name), &path).at("move item", &path)?;
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .at("open item", &path)?;
+        // moving the item kept the time it was pushed at, requeueing goes by when it was popped
+        file.set_modified(SystemTime::now())
+            .at("set modification time of", &path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).at("read item", &path)?;
+        Ok(Some((QueueItemId(name), bytes)))
+    }
+
+    /// Remove an item that was popped, once it was processed. Returns false if it was not in flight, which
+    /// happens when it was requeued in the meantime.
+    pub fn ack(&self, id: &QueueItemId) -> Result<bool> {
+        let path = self.in_flight_path(id);
+        let _gaurd = self.client.write_dir(&self.rpath)?;
+        match fs::remove_file(&path).at("remove item", &path) {
+            Ok(()) => Ok(true),
+            Err(Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Put items back at their place in the queue that were popped more than `older_than` ago without
+    /// being acknowledged, presumably because their consumer crashed. Returns how many were requeued.
+    pub fn requeue_stale(&self, older_than: Duration) -> Result<usize> {
+        let dir = self.client.root().join(&self.rpath).join(IN_FLIGHT);
+        if !dir.is_dir() {
+            return Ok(0);
+        }
+        let gaurd = self.client.write_dir(&self.rpath)?;
+        let mut requeued = 0;
+        for entry in fs::read_dir(&dir).at("read directory", &dir)? {
+            let entry = entry.at("read directory", &dir)?;
+            let path = entry.path();
+            let stale = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= older_than);
+            if stale {
+                let orig = gaurd.path.join(entry.file_name());
+                fs::rename(&path, &orig).at("requeue item", &path)?;
+                requeued += 1;
+            }
+        }
+        Ok(requeued)
+    }
+
+    fn in_flight_path(&self, id: &QueueItemId) -> PathBuf {
+        self.client
+            .root()
+            .join(&self.rpath)
+            .join(IN_FLIGHT)
+            .join(&id.0)
+    }
+}
+
+/// Nanoseconds since the epoch, strictly increasing within the process even where the clock is coarse
+/// or goes back.
+fn timestamp() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let previous = LAST
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap_or_else(|last| last);
+    now.max(previous + 1)
+}
@@ -0,0 +1,151 @@
+//! A cap on how many bytes of data a database holds, see [`crate::ClientBuilder::quota`]. The usage is
+//! counted once when the client is built and then kept up to date by the commits the client makes, which
+//! measure what they add and what they replace while the entry is write locked.
+
+use std::{fs, path::Path, sync::Mutex};
+
+use crate::{Client, Error, Result, artifact, is_atomic_dir_link};
+
+pub(crate) struct Quota {
+    limit: u64,
+    used: Mutex<u64>,
+}
+
+impl Quota {
+    pub(crate) fn new(limit: u64) -> Self {
+        Quota {
+            limit,
+            used: Mutex::new(0),
+        }
+    }
+
+    fn used(&self) -> u64 {
+        *self.used.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_used(&self, used: u64) {
+        *self.used.lock().unwrap_or_else(|e| e.into_inner()) = used;
+    }
+
+    /// Account for replacing the entry at `old` with the one at `new`, failing with
+    /// [`Error::QuotaExceeded`] without changing the usage if that takes it over the limit. Commits that
+    /// shrink the data always pass, even when the usage is over the limit already. Returns the change,
+    /// which is to be [`Quota::refund`]ed should the commit fail.
+    pub(crate) fn charge(&self, new: &Path, old: &Path) -> Result<i64> {
+        let (new, old) = (data_size(new), data_size(old));
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        if new > old && used.saturating_add(new - old) > self.limit {
+            return Err(Error::QuotaExceeded {
+                used: *used,
+                quota: self.limit,
+                attempted: new - old,
+            });
+        }
+        *used = used.saturating_add_signed(new as i64 - old as i64);
+        Ok(new as i64 - old as i64)
+    }
+
+    pub(crate) fn refund(&self, charged: i64) {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        *used = used.saturating_add_signed(-charged);
+    }
+
+    /// Account for `bytes` of data having been removed.
+    pub(crate) fn release(&self, bytes: u64) {
+        self.refund(bytes as i64);
+    }
+}
+
+impl Client {
+    /// Bytes of data the database holds as far as the quota is concerned, `None` without a
+    /// [`crate::ClientBuilder::quota`].
+    pub fn usage(&self) -> Option<u64> {
+        self.inner.quota.as_ref().map(|quota| quota.used())
+    }
+
+    /// Count the bytes of data in the database again, like [`Client::stats`] does, and make that the usage
+    /// the quota is checked against. The usage only follows the commits of this client, so it drifts when
+    /// other clients or processes write to the database, and should be recomputed every now and then.
+    /// Commits made while the database is walked may be counted twice or not at all.
+    pub fn recompute_usage(&self) -> Result<u64> {
+        self.redacted(|| {
+            let used = self.stats("")?.file_bytes;
+            if let Some(quota) = &self.inner.quota {
+                quota.set_used(used);
+            }
+            Ok(used)
+        })
+    }
+}
+
+/// Bytes of data at `path`, counted the way [`Client::stats`] counts them: sbdb's own files are left out
+/// and atomic directories are followed to their payloads. Entries that disappear while they are walked
+/// are skipped.
+pub(crate) fn data_size(path: &Path) -> u64 {
+    data_usage(path).0
+}
+
+/// Bytes and number of files of data at `path`, see [`data_size`].
+pub(crate) fn data_usage(path: &Path) -> (u64, u64) {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return (0, 0);
+    };
+    if metadata.is_file() {
+        return (metadata.len(), 1);
+    }
+    if !metadata.is_dir() {
+        return (0, 0);
+    }
+    let (mut bytes, mut files) = (0, 0);
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let child = entry.path();
+            let Ok(metadata) = fs::symlink_metadata(&child) else {
+                continue;
+            };
+            if let Some((kind, _)) = artifact::parse(&entry.file_name())
+                && artifact::is_own(kind, &metadata)
+            {
+                continue;
+            }
+            if metadata.is_dir()
+                || (metadata.is_symlink() && is_atomic_dir_link(&child).unwrap_or(false))
+            {
+                pending.push(child);
+            } else if metadata.is_file() {
+                bytes += metadata.len();
+                files += 1;
+            }
+        }
+    }
+    (bytes, files)
+}
+
+/// Bytes that an unprivileged process can still write to the filesystem holding `path`, `None` where that
+/// is not known.
+#[cfg(target_os = "linux")]
+pub(crate) fn available_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is a valid nul terminated string and stat is only read once statvfs filled it in
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // the widths of the fields differ between architectures
+    #[allow(clippy::useless_conversion)]
+    Some(u64::from(stat.f_bavail).saturating_mul(u64::from(stat.f_frsize)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,177 @@
+//! Read locks held for longer than [`crate::ClientBuilder::max_read_hold`], such as by a guard that leaked
+//! into a cache. A reader stamps the lock file of its entry with its process and the time it acquired the
+//! lock. A writer waiting for the lock checks the stamp between attempts to take it, and once the entry is
+//! held by readers only and the stamp is older than the budget, it reports the readers to the
+//! [`crate::ClientBuilder::on_stale_reader`] callback.
+//!
+//! Readers overwrite each other's stamps, so the stamp is that of the reader that acquired the lock last.
+//! Readers can not join while a writer waits, so once the last one is past the budget, every reader still
+//! holding the lock is. Readers of processes that do not set a budget leave no stamp.
+//!
+//! Locks of the operating system can not be broken. Revoking a guard only has its read methods fail with
+//! [`crate::Error::LeaseRevoked`], the lock is held until the guard is dropped, so an application that
+//! keeps guards has to check [`crate::FileReadGaurd::is_revoked`] and drop the ones that were revoked.
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{LockMode, Vfs, artifact, path_hidden_with_extension};
+
+/// Called from the thread of a waiting writer, see [`crate::ClientBuilder::on_stale_reader`].
+pub type StaleReaderCallback = Arc<dyn Fn(&StaleReader) + Send + Sync>;
+
+/// Length of a stamp: the process id, the seconds and the nanoseconds since the epoch, padded so that every
+/// stamp overwrites the previous one in full.
+pub(crate) const STAMP_LEN: u64 = 42;
+
+/// Read locks of this process that were acquired with a budget, by entry.
+static READERS: Mutex<Vec<Reader>> = Mutex::new(Vec::new());
+
+struct Reader {
+    path: PathBuf,
+    acquired: SystemTime,
+    revoked: Weak<AtomicBool>,
+}
+
+/// Readers of an entry that held its lock for longer than [`crate::ClientBuilder::max_read_hold`] while a
+/// writer waits for it.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct StaleReader {
+    /// The entry the readers hold locked.
+    pub path: PathBuf,
+    /// Process of the reader that acquired the lock last.
+    pub pid: u32,
+    /// When the reader that acquired the lock last did so, every other reader did so before.
+    pub acquired: SystemTime,
+    pub held_for: Duration,
+}
+
+impl StaleReader {
+    /// Whether the readers include the reader that acquired the lock last, which belongs to this process.
+    pub fn in_this_process(&self) -> bool {
+        self.pid == std::process::id()
+    }
+
+    /// Revoke the guards of this process that hold the lock, returning how many read locks were revoked.
+    /// Their read methods fail with [`crate::Error::LeaseRevoked`] from now on, but the lock is only
+    /// released once they are dropped. Guards of other processes are out of reach.
+    pub fn revoke(&self) -> usize {
+        let mut readers = READERS.lock().unwrap();
+        readers.retain(|reader| reader.revoked.strong_count() > 0);
+        readers
+            .iter()
+            .filter(|reader| reader.path == self.path && reader.acquired <= self.acquired)
+            .filter_map(|reader| reader.revoked.upgrade())
+            .filter(|revoked| !revoked.swap(true, Ordering::Relaxed))
+            .count()
+    }
+}
+
+pub(crate) struct ReadHold {
+    max: Duration,
+    on_stale: Option<StaleReaderCallback>,
+}
+
+impl ReadHold {
+    pub(crate) fn new(max: Duration, on_stale: Option<StaleReaderCallback>) -> Self {
+        ReadHold { max, on_stale }
+    }
+
+    /// Stamp the lock file `lock` of the entry at `path`, which was just read locked, and register the lock
+    /// to be revoked, returning its flag.
+    pub(crate) fn acquired(&self, path: &Path, lock: &File) -> Arc<AtomicBool> {
+        let acquired = SystemTime::now();
+        let since_epoch = acquired.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let stamp = format!(
+            "{:>10} {:>20}.{:09}\n",
+            std::process::id(),
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos()
+        );
+        if let Err(e) = write_stamp(lock, stamp.as_bytes()) {
+            log::warn!(path:? = path, operation = "stamp read lock"; "failed to stamp lock file: {}", e);
+        }
+
+        let revoked = Arc::new(AtomicBool::new(false));
+        let mut readers = READERS.lock().unwrap();
+        readers.retain(|reader| reader.revoked.strong_count() > 0);
+        readers.push(Reader {
+            path: path.to_path_buf(),
+            acquired,
+            revoked: Arc::downgrade(&revoked),
+        });
+        revoked
+    }
+
+    /// Write lock `lock`, the lock file of the entry at `path` in place of the entry `target`, reporting
+    /// readers that hold it for longer than the budget once for every stamp while waiting.
+    pub(crate) fn write_lock(
+        &self,
+        vfs: &dyn Vfs,
+        lock: &File,
+        path: &Path,
+        target: &Path,
+    ) -> std::io::Result<()> {
+        let poll = (self.max / 10).clamp(Duration::from_millis(1), Duration::from_millis(100));
+        let mut reported = None;
+        while !vfs.try_lock(lock, LockMode::Write)? {
+            if let Some((pid, acquired)) = read_stamp(target)
+                && reported != Some(acquired)
+                && let Ok(held_for) = acquired.elapsed()
+                && held_for > self.max
+                // a writer holding the lock is not a stale reader
+                && vfs.try_lock(lock, LockMode::Read)?
+            {
+                vfs.unlock(lock)?;
+                reported = Some(acquired);
+                let stale = StaleReader {
+                    path: path.to_path_buf(),
+                    pid,
+                    acquired,
+                    held_for,
+                };
+                log::warn!(path:? = path, operation = "acquire write lock on"; "read locked for {:?} by process {}", held_for, pid);
+                if let Some(on_stale) = &self.on_stale {
+                    on_stale(&stale);
+                }
+            }
+            thread::sleep(poll);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn write_stamp(lock: &File, stamp: &[u8]) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(lock, stamp, 0)
+}
+
+#[cfg(windows)]
+fn write_stamp(lock: &File, stamp: &[u8]) -> std::io::Result<()> {
+    std::os::windows::fs::FileExt::seek_write(lock, stamp, 0).map(drop)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_stamp(_lock: &File, _stamp: &[u8]) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Process and time of the stamp in the lock file of `target`, `None` without a stamp or with one that a
+/// concurrent reader was writing.
+fn read_stamp(target: &Path) -> Option<(u32, SystemTime)> {
+    let bytes = fs::read(path_hidden_with_extension(target, artifact::LOCK).ok()?).ok()?;
+    let stamp = std::str::from_utf8(bytes.get(..STAMP_LEN as usize)?).ok()?;
+    let (pid, time) = stamp.trim().split_once(' ')?;
+    let (secs, nanos) = time.trim().split_once('.')?;
+    let since_epoch = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    Some((pid.trim().parse().ok()?, UNIX_EPOCH + since_epoch))
+}
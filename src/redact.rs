@@ -0,0 +1,141 @@
+//! How paths below a database root are shown in errors, log records, metrics and traces, see
+//! [`crate::ClientBuilder::redact_paths`]. Only a client knows its root, so paths are shown this way where
+//! its context is at hand, and as they are by the free functions.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use sha2::Digest;
+
+use crate::{Error, Warning};
+
+/// Length of the hashes shown in place of paths with [`PathDisplay::Hashed`], in hex digits.
+const HASH_LEN: usize = 12;
+
+/// How paths of entries in a database are shown, see [`crate::ClientBuilder::redact_paths`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PathDisplay {
+    /// As they are, with the root they are below.
+    #[default]
+    Absolute,
+    /// Relative to the root, `.` for the root itself.
+    RootRelative,
+    /// As the first 12 hex digits of the SHA-256 of the path relative to the root, with its components
+    /// joined by `/`. The same entry has the same hash in every process and below every root.
+    Hashed,
+}
+
+/// How a client shows the paths below its root.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Redact {
+    /// Root of the client, `None` where paths are shown as they are.
+    root: Option<PathBuf>,
+    display: PathDisplay,
+}
+
+impl Redact {
+    pub(crate) fn new(root: &Path, display: PathDisplay) -> Self {
+        Redact {
+            root: (display != PathDisplay::Absolute).then(|| root.to_path_buf()),
+            display,
+        }
+    }
+
+    pub(crate) fn display(&self) -> PathDisplay {
+        self.display
+    }
+
+    /// `path` as it is to be shown. Paths outside of the root, such as the destination of an export, are
+    /// shown as they are.
+    pub(crate) fn path<'a>(&'a self, path: &'a Path) -> DisplayPath<'a> {
+        DisplayPath {
+            path,
+            root: self.root.as_deref(),
+            display: self.display,
+        }
+    }
+
+    /// `error` with the paths it names as they are to be shown.
+    pub(crate) fn error(&self, error: Error) -> Error {
+        match self.root {
+            None => error,
+            Some(_) => error.map_paths(&|path| self.path(&path).shown().into_owned()),
+        }
+    }
+
+    /// `warning` with the paths it names, and those of its error, as they are to be shown.
+    pub(crate) fn warning(&self, warning: Warning) -> Warning {
+        match self.root {
+            None => warning,
+            Some(_) => warning.map_paths(&|path| self.path(&path).shown().into_owned()),
+        }
+    }
+}
+
+/// A path with the root it is shown relative to, see [`Redact::path`]. Formats like [`Path::display`], and
+/// with `{:?}` like the [`Path`] itself.
+pub(crate) struct DisplayPath<'a> {
+    path: &'a Path,
+    root: Option<&'a Path>,
+    display: PathDisplay,
+}
+
+impl<'a> DisplayPath<'a> {
+    /// `rpath`, which is relative to a root, shown with `display`, or root-relative if that would show it
+    /// as it is.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn relative(rpath: &'a Path, display: PathDisplay) -> Self {
+        DisplayPath {
+            path: rpath,
+            root: Some(Path::new("")),
+            display: display.max(PathDisplay::RootRelative),
+        }
+    }
+
+    /// The path as it is shown.
+    pub(crate) fn shown(&self) -> Cow<'a, Path> {
+        let Some(rpath) = self.root.and_then(|root| self.path.strip_prefix(root).ok()) else {
+            return Cow::Borrowed(self.path);
+        };
+        match self.display {
+            PathDisplay::Absolute => Cow::Borrowed(self.path),
+            PathDisplay::RootRelative if rpath.as_os_str().is_empty() => {
+                Cow::Borrowed(Path::new("."))
+            }
+            PathDisplay::RootRelative => Cow::Borrowed(rpath),
+            PathDisplay::Hashed => Cow::Owned(PathBuf::from(hash(rpath))),
+        }
+    }
+}
+
+impl fmt::Display for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.shown().display().fmt(f)
+    }
+}
+
+impl fmt::Debug for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.shown().fmt(f)
+    }
+}
+
+fn hash(rpath: &Path) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for (i, component) in rpath.components().enumerate() {
+        if i > 0 {
+            hasher.update(b"/");
+        }
+        hasher.update(component.as_os_str().as_encoded_bytes());
+    }
+    let mut hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    hash.truncate(HASH_LEN);
+    hash
+}
@@ -0,0 +1,102 @@
+//! Whether files can be reflinked, remembered per filesystem. Without it every copy on a filesystem that
+//! does not support reflinks first makes the attempt, which costs a syscall per file.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// How long copies skip the reflink attempt on a filesystem that did not support it, before trying again
+/// in case it was remounted.
+const REPROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+enum Support {
+    Supported,
+    /// Since when copies skip the attempt.
+    Unsupported(Instant),
+}
+
+pub(crate) struct ReflinkSupport {
+    devices: Mutex<HashMap<u64, Support>>,
+    reprobe_interval: Duration,
+}
+
+impl Default for ReflinkSupport {
+    fn default() -> Self {
+        Self::new(REPROBE_INTERVAL)
+    }
+}
+
+impl ReflinkSupport {
+    pub(crate) fn new(reprobe_interval: Duration) -> Self {
+        ReflinkSupport {
+            devices: Mutex::new(HashMap::new()),
+            reprobe_interval,
+        }
+    }
+
+    /// Whether a copy on the filesystem `device` should go straight to a regular copy. Once the interval
+    /// has passed a single copy is let through to try again.
+    pub(crate) fn skip(&self, device: u64) -> bool {
+        let mut devices = self.lock();
+        match devices.get_mut(&device) {
+            Some(Support::Unsupported(since)) => {
+                if since.elapsed() < self.reprobe_interval {
+                    true
+                } else {
+                    *since = Instant::now();
+                    false
+                }
+            }
+            Some(Support::Supported) | None => false,
+        }
+    }
+
+    /// Record the outcome of an attempt to reflink on `device`. A single success is enough to never skip
+    /// the filesystem again.
+    pub(crate) fn record(&self, device: u64, reflinked: bool) {
+        let mut devices = self.lock();
+        if reflinked {
+            devices.insert(device, Support::Supported);
+        } else {
+            devices
+                .entry(device)
+                .or_insert_with(|| Support::Unsupported(Instant::now()));
+        }
+    }
+
+    /// What is known about `device`, `None` if no copy was made on it yet.
+    pub(crate) fn supported(&self, device: u64) -> Option<bool> {
+        self.lock()
+            .get(&device)
+            .map(|support| matches!(support, Support::Supported))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Support>> {
+        self.devices.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Shared instance for copies made without a [`crate::Client`].
+pub(crate) fn global() -> Arc<ReflinkSupport> {
+    static GLOBAL: OnceLock<Arc<ReflinkSupport>> = OnceLock::new();
+    GLOBAL.get_or_init(Default::default).clone()
+}
+
+/// Filesystem that `path` lives on, `None` where the standard library can not tell.
+pub(crate) fn device(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        std::fs::metadata(path).ok().map(|metadata| metadata.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
@@ -0,0 +1,166 @@
+//! Removing every entry whose path starts with a prefix at once, see [`Client::remove_prefix`]. Entries are
+//! renamed to tombstones named like temporaries, `.name.<puuid>.tmp.sbdb`, while their directory is write
+//! locked, and deleted once it is released. A tombstone left behind by a crash is removed by gc, and unlike
+//! a backup is never restored.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Client, DirWriteGaurd, Error, INTERNAL_DIR, ReplicatedOp, Result, artifact, error::IoResultExt,
+    is_atomic_dir_link, path_hidden_with_extension, puuid, resolve_atomic_dir, ttl,
+};
+
+/// What [`Client::remove_prefix`] removed.
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct RemoveReport {
+    /// Entries that were removed, relative to the root.
+    pub removed: Vec<PathBuf>,
+    /// Entries that could not be removed, or whose tombstone could not be deleted, relative to the root.
+    /// Entries with a tombstone are gone all the same, and gc deletes it later.
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+impl Client {
+    /// Remove every entry whose path starts with `prefix`, such as all sessions of a year with
+    /// `sessions/2023-`, while the directory holding them is write locked, so that readers see either all
+    /// of them or none. If `prefix` names a directory, that directory is removed as a whole, not the other
+    /// entries whose names start with its name. Otherwise the entries of its parent whose names start with
+    /// the last component of `prefix` are removed, matched literally. An entry that can not be removed is
+    /// reported in [`RemoveReport::failed`] without stopping the others.
+    ///
+    /// Entries are renamed away while the directory is locked, and their data is deleted after it was
+    /// released, so a large directory disappears at once rather than file by file. Their expiry, metadata
+    /// and lock files are removed along with them. Nothing is removed if the parent of `prefix` does not
+    /// exist.
+    pub fn remove_prefix<P: AsRef<Path>>(&self, prefix: P) -> Result<RemoveReport> {
+        self.redacted(|| {
+            let prefix = crate::check_nested(prefix.as_ref())?;
+            crate::check_unreserved(&self.inner.root, &prefix)?;
+            let (Some(parent), Some(name)) = (prefix.parent(), prefix.file_name()) else {
+                return Err(Error::invalid_path(&prefix, "no entry to remove"));
+            };
+            let mut report = RemoveReport::default();
+            if !self.inner.root.join(parent).is_dir() {
+                return Ok(report);
+            }
+            let dir = self.write_dir(parent)?;
+            dir.ctx.check_writable(&dir.path)?;
+            let path = dir.path.join(name);
+            let names = match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => vec![name.to_owned()],
+                Ok(metadata) if metadata.is_symlink() && is_atomic_dir_link(&path)? => {
+                    vec![name.to_owned()]
+                }
+                _ => matching(&dir, parent, name.as_encoded_bytes())?,
+            };
+
+            let mut buried = Vec::new();
+            for name in names {
+                let rpath = parent.join(&name);
+                match self.bury(&dir, &name) {
+                    Ok(tombstones) => buried.push((rpath, tombstones)),
+                    Err(e) => report.failed.push((rpath, self.inner.redact.error(e))),
+                }
+            }
+            dir.release()?;
+
+            for (rpath, tombstones) in buried {
+                let deleted = tombstones.iter().try_for_each(|tombstone| {
+                    let removed = match fs::symlink_metadata(tombstone) {
+                        // links to directories are removed without following them
+                        Ok(metadata) if metadata.is_dir() || metadata.is_symlink() => {
+                            self.inner.vfs.remove_dir_all(tombstone)
+                        }
+                        _ => self.inner.vfs.remove_file(tombstone),
+                    };
+                    removed.at("remove", tombstone)
+                });
+                if let Err(e) = deleted {
+                    report
+                        .failed
+                        .push((rpath.clone(), self.inner.redact.error(e)));
+                }
+                report.removed.push(rpath);
+            }
+            Ok(report)
+        })
+    }
+
+    /// Rename the entry `name` of the write locked `dir` to a tombstone, and remove its expiry, metadata and
+    /// lock files. Returns what is left to delete: the tombstone, and the payload if the entry is an atomic
+    /// directory.
+    fn bury(&self, dir: &DirWriteGaurd, name: &OsString) -> Result<Vec<PathBuf>> {
+        let gaurd = dir.write_file(name)?;
+        let path = &gaurd.path;
+        let resolved = resolve_atomic_dir(path)?;
+        let bytes = crate::quota::data_size(path);
+        let before = gaurd.ctx.usage_before(path);
+        // lock files below the entry that are kept open would keep it from being renamed on windows
+        if let Some(cache) = &self.inner.lock_cache {
+            cache.evict_under(path);
+        }
+        let tombstone = tombstone(path)?;
+        self.inner.vfs.rename(path, &tombstone).at("remove", path)?;
+        // the payload is out of reach once its link is gone
+        let mut tombstones = vec![tombstone];
+        if resolved != *path {
+            tombstones.push(resolved);
+        }
+        gaurd.ctx.release_quota(bytes);
+        gaurd.ctx.replicate(ReplicatedOp::Remove, path);
+        gaurd.ctx.roll_up(path, before);
+        let sidecar = ttl::sidecar(path)?;
+        match self.inner.vfs.remove_file(&sidecar) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::io("remove expiry", &sidecar, e));
+            }
+            _ => {}
+        }
+        crate::entry_meta::remove(path)?;
+        let path = path.clone();
+        gaurd.release()?;
+
+        if let Some(cache) = &self.inner.lock_cache {
+            cache.evict(&path);
+        }
+        for ext in [artifact::LOCK, artifact::QUEUE] {
+            let lock = path_hidden_with_extension(&path, ext)?;
+            match self.inner.vfs.remove_file(&lock) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(Error::io("remove", &lock, e));
+                }
+                _ => {}
+            }
+        }
+        Ok(tombstones)
+    }
+}
+
+/// Names of the entries of the write locked `dir` at `rpath` that start with `prefix`, sorted.
+fn matching(dir: &DirWriteGaurd, rpath: &Path, prefix: &[u8]) -> Result<Vec<OsString>> {
+    let resolved = dir.resolved_path();
+    let mut names = Vec::new();
+    for entry in fs::read_dir(resolved).at("read directory", resolved)? {
+        let name = entry.at("read directory", resolved)?.file_name();
+        if (rpath.as_os_str().is_empty() && name == INTERNAL_DIR)
+            || artifact::parse(&name).is_some()
+        {
+            continue;
+        }
+        if name.as_encoded_bytes().starts_with(prefix) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Name that the entry at `path` is renamed to before it is deleted.
+pub(crate) fn tombstone(path: &Path) -> Result<PathBuf> {
+    path_hidden_with_extension(path, &format!(".{}{}", puuid(), artifact::TMP))
+}
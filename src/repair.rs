@@ -0,0 +1,155 @@
+//! Repair of atomic directories whose payload is gone, see [`Client::repair`]. Their link still exists, so
+//! gc leaves them alone and every path below them fails to open, until someone decides what they should
+//! hold.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    Client, CommitKind, Error, ReplicatedOp, Result,
+    artifact::{self, ArtifactKind},
+    error::IoResultExt,
+    is_atomic_dir_link, normalize_rpath, puuid, replace_with_link, unused_hidden_path,
+};
+
+/// What [`Client::repair`] does to an atomic directory whose payload is gone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RepairAction {
+    /// Remove the link, so that the directory reads as absent.
+    RemoveLink,
+    /// Point the link at the most recently modified backup or earlier payload of the directory, which a
+    /// crash or gc may have left next to it. Fails with [`Error::NotFound`] if there is neither.
+    RestoreFromBackup,
+    /// Point the link at a new empty payload.
+    RecreateEmpty,
+}
+
+/// What [`Client::repair`] did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RepairReport {
+    /// The repaired directory, relative to the root.
+    pub path: PathBuf,
+    pub action: RepairAction,
+    /// The backup or payload the directory was restored from, relative to the root, with
+    /// [`RepairAction::RestoreFromBackup`].
+    pub restored_from: Option<PathBuf>,
+}
+
+impl Client {
+    /// Repair the atomic directory at `rpath`, whose link leads to a payload that does not exist, as
+    /// [`crate::IssueKind::DanglingAtomicDir`] reports, by taking `action` while it is write locked. Fails
+    /// with [`Error::InvalidPath`] if it is anything else, such as an atomic directory that is fine.
+    pub fn repair<P: AsRef<Path>>(&self, rpath: P, action: RepairAction) -> Result<RepairReport> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let gaurd = self.write_file(&rpath)?;
+            let path = &gaurd.path;
+            let dangling = fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink())
+                && is_atomic_dir_link(path)?
+                && !path.is_dir();
+            if !dangling {
+                return Err(Error::invalid_path(path, "not a dangling atomic directory"));
+            }
+            let ctx = &gaurd.ctx;
+            let mut report = RepairReport {
+                path: rpath.clone(),
+                action,
+                restored_from: None,
+            };
+            match action {
+                RepairAction::RemoveLink => {
+                    ctx.check_writable(path)?;
+                    let before = ctx.usage_before(path);
+                    // does not follow the link, and removes links to directories, which are directories on windows
+                    self.inner.vfs.remove_dir_all(path).at("remove", path)?;
+                    ctx.replicate(ReplicatedOp::Remove, path);
+                    ctx.roll_up(path, before);
+                    crate::entry_meta::remove(path)?;
+                }
+                RepairAction::RestoreFromBackup => {
+                    let source = latest_backup(path)?.ok_or_else(|| Error::NotFound {
+                        operation: "find a backup of",
+                        path: path.clone(),
+                    })?;
+                    report.restored_from =
+                        source.file_name().map(|name| rpath.with_file_name(name));
+                    ctx.commit(CommitKind::AtomicDir, path, || {
+                        let payload = match is_payload(&source) {
+                            true => source.clone(),
+                            false => {
+                                let payload = new_payload(path)?;
+                                self.inner
+                                    .vfs
+                                    .rename(&source, &payload)
+                                    .at("restore backup", &source)?;
+                                payload
+                            }
+                        };
+                        relink(self, path, &payload)
+                    })?;
+                }
+                RepairAction::RecreateEmpty => {
+                    ctx.commit(CommitKind::AtomicDir, path, || {
+                        let payload = new_payload(path)?;
+                        self.inner
+                            .vfs
+                            .create_dir_all(&payload)
+                            .at("create directory", &payload)?;
+                        ctx.set_artifact_permissions(&payload, true)?;
+                        relink(self, path, &payload)
+                    })?;
+                }
+            }
+            Ok(report)
+        })
+    }
+}
+
+/// The most recently modified backup or payload belonging to the atomic directory at `link`.
+fn latest_backup(link: &Path) -> Result<Option<PathBuf>> {
+    let (Some(parent), Some(name)) = (link.parent(), link.file_name()) else {
+        return Ok(None);
+    };
+    let mut latest: Option<(SystemTime, PathBuf)> = None;
+    for entry in fs::read_dir(parent).at("read directory", parent)? {
+        let entry = entry.at("read directory", parent)?;
+        let Some((kind, orig)) = artifact::parse(&entry.file_name()) else {
+            continue;
+        };
+        let metadata = fs::symlink_metadata(entry.path()).at("read metadata of", entry.path())?;
+        if orig != name
+            || !matches!(kind, ArtifactKind::Backup | ArtifactKind::AtomicDir)
+            || !artifact::is_own(kind, &metadata)
+        {
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        if latest.as_ref().is_none_or(|(latest, _)| modified > *latest) {
+            latest = Some((modified, entry.path()));
+        }
+    }
+    Ok(latest.map(|(_, path)| path))
+}
+
+fn is_payload(path: &Path) -> bool {
+    path.file_name()
+        .and_then(artifact::parse)
+        .is_some_and(|(kind, _)| kind == ArtifactKind::AtomicDir)
+}
+
+/// Path for a new payload of the atomic directory at `link`.
+fn new_payload(link: &Path) -> Result<PathBuf> {
+    unused_hidden_path(link, || format!(".{}{}", puuid(), artifact::ATOMIC_DIR))
+}
+
+/// Atomically point the link at `link` to the sibling `payload`.
+fn relink(client: &Client, link: &Path, payload: &Path) -> Result<()> {
+    let target = payload
+        .file_name()
+        .ok_or_else(|| Error::invalid_path(payload, "missing file name"))?;
+    replace_with_link(&*client.inner.vfs, Path::new(target), link, true)
+}
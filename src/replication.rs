@@ -0,0 +1,372 @@
+//! Shipping commits from a primary root to a warm standby. A primary built with
+//! [`crate::ClientBuilder::replication_log`] records every commit it makes in `.sbdb/replog/<seq>/`, as the
+//! committed path and a copy of what it committed, reflinked where the filesystem supports it. The copy is
+//! taken while the entry is still write locked, and sequence numbers are handed out under the log's own
+//! lock, so the log has the commits of each entry in the order they were made. Removals made by gc
+//! expiring entries and by collections are recorded as well.
+//!
+//! A replica is marked with [`Client::mark_replica`] and then only changes through
+//! [`Client::apply_commits`], which redoes each recorded commit through the normal import paths under the
+//! replica's own locks. Commits through any other client of a replica root fail with
+//! [`Error::ReadOnlyReplica`]. Atomic directories of the primary arrive as plain directories, and sidecar
+//! files such as expiries and metadata are not shipped.
+//!
+//! Lock ordering: the log's lock is taken while the committed entry is write locked and nothing is locked
+//! while it is held.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{
+    Client, ClientInner, CommitKind, Ctx, Error, INTERNAL_DIR, ImportMode, ReadLock, Result,
+    WriteLock, copy_file, copy_visible,
+    error::IoResultExt,
+    file_replace_with,
+    hash::portable_path,
+    meta::{format_object, parse_object},
+    quota,
+};
+
+const REPLOG_DIR: &str = "replog";
+/// Sequence number of the last complete record of the primary's log.
+const HEAD: &str = "head";
+const RECORD: &str = "record";
+const DATA: &str = "data";
+/// Marks a replica and holds the sequence number it applied last.
+const REPLICA: &str = "replica";
+
+/// What a commit did to its entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReplicatedOp {
+    Write(CommitKind),
+    Remove,
+}
+
+/// A commit of the primary, as returned by [`Client::read_commits_since`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitRecord {
+    pub seq: u64,
+    pub op: ReplicatedOp,
+    /// Path of the committed entry relative to the root.
+    pub path: PathBuf,
+    /// What was committed, in the primary's log. When the log is shipped to another machine, the data is
+    /// copied along and this is pointed at the copy before the record is applied.
+    pub data: PathBuf,
+}
+
+pub(crate) struct ReplicationLog {
+    root: PathBuf,
+}
+
+impl ReplicationLog {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        ReplicationLog { root }
+    }
+
+    /// Appends a record for the commit of `orig`, which must still be write locked.
+    pub(crate) fn record(&self, op: ReplicatedOp, orig: &Path, ctx: &Ctx) -> Result<()> {
+        let Ok(rpath) = orig.strip_prefix(&self.root) else {
+            return Ok(());
+        };
+        if rpath.starts_with(INTERNAL_DIR) {
+            return Ok(());
+        }
+        let path = portable_path(rpath)?;
+        let internal = self.root.join(INTERNAL_DIR);
+        let dir = internal.join(REPLOG_DIR);
+        fs::create_dir_all(&dir).at("create directory", &dir)?;
+        let _lock = WriteLock::acquire(&self.root, &log_rpath(HEAD), ctx)?;
+        let seq = read_seq(&dir.join(HEAD))?.unwrap_or(0) + 1;
+        // left behind by a crash before the head was advanced
+        let entry = dir.join(seq_name(seq));
+        match fs::remove_dir_all(&entry) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::io("remove stale record", &entry, e));
+            }
+            _ => {}
+        }
+        fs::create_dir(&entry).at("create directory", &entry)?;
+
+        let data = entry.join(DATA);
+        let kind = match op {
+            ReplicatedOp::Write(kind) => {
+                match kind {
+                    CommitKind::File => ctx.copy(|stats| copy_file(orig, &data, ctx, stats))?,
+                    _ => ctx.copy(|stats| copy_visible(orig, &data, &internal, ctx, stats))?,
+                }
+                kind.as_str()
+            }
+            ReplicatedOp::Remove => "",
+        };
+        let record = BTreeMap::from([
+            ("op".to_string(), op_str(op).to_string()),
+            ("kind".to_string(), kind.to_string()),
+            ("path".to_string(), path),
+        ]);
+        let detached = Ctx::detached();
+        file_replace_with(
+            &entry.join(RECORD),
+            format_object(&record).as_bytes(),
+            false,
+            detached.clone(),
+        )?;
+        file_replace_with(&dir.join(HEAD), seq.to_string().as_bytes(), false, detached)
+    }
+}
+
+impl Client {
+    /// Sequence number of the last commit in this primary's log, 0 before the first one. A replica seeded
+    /// with a copy of the root taken while no commits were made is marked with it, see
+    /// [`Client::mark_replica`].
+    pub fn replication_cursor(&self) -> Result<u64> {
+        self.redacted(|| {
+            let dir = self.replog_dir()?;
+            let _lock = ReadLock::acquire(&self.inner.root, &log_rpath(HEAD), &self.ctx())?;
+            Ok(read_seq(&dir.join(HEAD))?.unwrap_or(0))
+        })
+    }
+
+    /// The commits after `cursor` that are still in this primary's log, oldest first. Fails with
+    /// [`Error::ReplicationGap`] if some of them were truncated already.
+    pub fn read_commits_since(&self, cursor: u64) -> Result<Vec<CommitRecord>> {
+        self.redacted(|| {
+            let dir = self.replog_dir()?;
+            let _lock = ReadLock::acquire(&self.inner.root, &log_rpath(HEAD), &self.ctx())?;
+            let head = read_seq(&dir.join(HEAD))?.unwrap_or(0);
+            if head <= cursor {
+                return Ok(Vec::new());
+            }
+            let mut seqs = logged_seqs(&dir)?;
+            seqs.retain(|seq| *seq > cursor && *seq <= head);
+            if seqs.first() != Some(&(cursor + 1)) {
+                return Err(Error::ReplicationGap {
+                    expected: cursor + 1,
+                    found: seqs.first().copied().unwrap_or(head + 1),
+                });
+            }
+            seqs.into_iter()
+                .map(|seq| read_record(&dir.join(seq_name(seq)), seq))
+                .collect()
+        })
+    }
+
+    /// Remove the records up to `acknowledged` from this primary's log, once every replica applied them.
+    /// Returns how many were removed.
+    pub fn truncate_replication_log(&self, acknowledged: u64) -> Result<usize> {
+        self.redacted(|| {
+            let dir = self.replog_dir()?;
+            let _lock = WriteLock::acquire(&self.inner.root, &log_rpath(HEAD), &self.ctx())?;
+            let mut removed = 0;
+            for seq in logged_seqs(&dir)? {
+                if seq <= acknowledged {
+                    let entry = dir.join(seq_name(seq));
+                    fs::remove_dir_all(&entry).at("remove record", &entry)?;
+                    removed += 1;
+                }
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Make this root a replica that has applied the primary's commits up to `applied`, after which it only
+    /// changes through [`Client::apply_commits`]. Commits of this client, its clones and clients built
+    /// afterwards fail with [`Error::ReadOnlyReplica`], clients built before do not notice.
+    pub fn mark_replica(&self, applied: u64) -> Result<()> {
+        self.redacted(|| {
+            let _lock = WriteLock::acquire(&self.inner.root, &replica_rpath(), &self.ctx())?;
+            write_applied(&self.inner.root, applied)?;
+            self.inner.read_only.store(true, Ordering::Relaxed);
+            Ok(())
+        })
+    }
+
+    /// Turn a replica back into a root that can be committed to, such as when it takes over from the
+    /// primary. Returns the sequence number it applied last.
+    pub fn unmark_replica(&self) -> Result<Option<u64>> {
+        self.redacted(|| {
+            let _lock = WriteLock::acquire(&self.inner.root, &replica_rpath(), &self.ctx())?;
+            let applied = self.applied_seq()?;
+            let path = self.inner.root.join(replica_rpath());
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(Error::io("remove", &path, e));
+                }
+                _ => {}
+            }
+            self.inner.read_only.store(false, Ordering::Relaxed);
+            Ok(applied)
+        })
+    }
+
+    /// Sequence number of the last commit this replica applied, `None` if the root is not a replica.
+    pub fn applied_seq(&self) -> Result<Option<u64>> {
+        self.redacted(|| read_seq(&self.inner.root.join(replica_rpath())))
+    }
+
+    /// Apply the primary's `records` to this replica in order, each through the import paths under the
+    /// replica's locks, and return the sequence number applied last. Records applied before are skipped,
+    /// so a batch interrupted by a crash can be applied again. Fails with [`Error::ReplicationGap`] if a
+    /// record is missing in between.
+    pub fn apply_commits(&self, records: &[CommitRecord]) -> Result<u64> {
+        self.redacted(|| {
+            let _lock = WriteLock::acquire(&self.inner.root, &replica_rpath(), &self.ctx())?;
+            let Some(mut applied) = self.applied_seq()? else {
+                return Err(Error::invalid_path(
+                    &self.inner.root,
+                    "not a replica, see Client::mark_replica",
+                ));
+            };
+            let writer = Client::with_inner(ClientInner {
+                read_only: Arc::new(AtomicBool::new(false)),
+                ..(*self.inner).clone()
+            });
+            let mode = ImportMode::Copy {
+                overwrite: true,
+                sync: false,
+            };
+            for record in records {
+                if record.seq <= applied {
+                    continue;
+                }
+                if record.seq != applied + 1 {
+                    return Err(Error::ReplicationGap {
+                        expected: applied + 1,
+                        found: record.seq,
+                    });
+                }
+                let dest = self.inner.root.join(&record.path);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent).at("create directory", parent)?;
+                }
+                match record.op {
+                    ReplicatedOp::Write(CommitKind::File) => {
+                        writer.import_file(&record.data, &record.path, mode)?
+                    }
+                    ReplicatedOp::Write(_) => {
+                        writer.import_dir(&record.data, &record.path, mode)?
+                    }
+                    ReplicatedOp::Remove => writer.remove_replicated(&record.path)?,
+                }
+                applied = record.seq;
+                write_applied(&self.inner.root, applied)?;
+            }
+            Ok(applied)
+        })
+    }
+
+    fn remove_replicated(&self, rpath: &Path) -> Result<()> {
+        let gaurd = self.write_file(rpath)?;
+        let path = &gaurd.path;
+        let bytes = quota::data_size(path);
+        let before = gaurd.ctx.usage_before(path);
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => self.inner.vfs.remove_dir_all(path),
+            Ok(_) => self.inner.vfs.remove_file(path),
+            Err(_) => return Ok(()),
+        }
+        .at("remove", path)?;
+        gaurd.ctx.release_quota(bytes);
+        gaurd.ctx.roll_up(path, before);
+        Ok(())
+    }
+
+    /// Directory of the log, created so that its lock can be taken.
+    fn replog_dir(&self) -> Result<PathBuf> {
+        let dir = self.inner.root.join(INTERNAL_DIR).join(REPLOG_DIR);
+        fs::create_dir_all(&dir).at("create directory", &dir)?;
+        Ok(dir)
+    }
+}
+
+/// Sequence numbers of the records in the log at `dir`, whether complete or not.
+fn logged_seqs(dir: &Path) -> Result<Vec<u64>> {
+    let mut seqs = Vec::new();
+    for entry in fs::read_dir(dir).at("read directory", dir)? {
+        let entry = entry.at("read directory", dir)?;
+        if let Some(seq) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            seqs.push(seq);
+        }
+    }
+    seqs.sort_unstable();
+    Ok(seqs)
+}
+
+/// Whether the root at `root` was marked as a replica.
+pub(crate) fn is_replica(root: &Path) -> bool {
+    root.join(replica_rpath()).is_file()
+}
+
+fn log_rpath(name: &str) -> PathBuf {
+    Path::new(INTERNAL_DIR).join(REPLOG_DIR).join(name)
+}
+
+fn replica_rpath() -> PathBuf {
+    Path::new(INTERNAL_DIR).join(REPLICA)
+}
+
+fn seq_name(seq: u64) -> String {
+    format!("{:020}", seq)
+}
+
+fn op_str(op: ReplicatedOp) -> &'static str {
+    match op {
+        ReplicatedOp::Write(_) => "write",
+        ReplicatedOp::Remove => "remove",
+    }
+}
+
+fn read_seq(path: &Path) -> Result<Option<u64>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io("read", path, e)),
+    };
+    text.trim().parse().map(Some).map_err(|e| Error::Decode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })
+}
+
+fn write_applied(root: &Path, applied: u64) -> Result<()> {
+    file_replace_with(
+        &root.join(replica_rpath()),
+        applied.to_string().as_bytes(),
+        true,
+        Ctx::detached(),
+    )
+}
+
+fn read_record(entry: &Path, seq: u64) -> Result<CommitRecord> {
+    let path = entry.join(RECORD);
+    let text = fs::read_to_string(&path).at("read", &path)?;
+    let record = parse_object(&text).map_err(|message| Error::Decode {
+        path: path.clone(),
+        source: message.into(),
+    })?;
+    let field = |key: &str| record.get(key).map(String::as_str);
+    let op = match (field("op"), field("kind").and_then(CommitKind::parse)) {
+        (Some("write"), Some(kind)) => ReplicatedOp::Write(kind),
+        (Some("remove"), _) => ReplicatedOp::Remove,
+        _ => return Err(Error::WrongFormat { path }),
+    };
+    let Some(rpath) = field("path") else {
+        return Err(Error::WrongFormat { path });
+    };
+    Ok(CommitRecord {
+        seq,
+        op,
+        path: rpath.split('/').collect(),
+        data: entry.join(DATA),
+    })
+}
@@ -0,0 +1,104 @@
+//! Transactions that give up rather than wait, see [`Client::try_tx`]. Every attempt takes its locks with
+//! [`TxBuilder::try_begin`], which fails as soon as one of them is held elsewhere, and the attempts are
+//! spaced out by a growing backoff with jitter, so that contenders that collided do not collide again.
+
+use std::{
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{Client, Error, Result, Tx, TxBuilder};
+
+/// How often and for how long [`Client::try_tx`] tries to take the locks of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TryPolicy {
+    /// Attempts in total, including the first one. At least one is always made.
+    pub attempts: u32,
+    /// Wait before the first retry, which doubles with every further one.
+    pub base_backoff: Duration,
+    /// Up to this much is added to every wait at random.
+    pub jitter: Duration,
+    /// Give up once the next wait would end this long after the first attempt, however many attempts are
+    /// left.
+    pub total_budget: Option<Duration>,
+}
+
+impl Default for TryPolicy {
+    fn default() -> Self {
+        TryPolicy {
+            attempts: 3,
+            base_backoff: Duration::from_millis(10),
+            jitter: Duration::from_millis(10),
+            total_budget: None,
+        }
+    }
+}
+
+/// Why [`Client::try_tx`] did not run its transaction.
+#[derive(Debug, thiserror::Error)]
+pub enum TryTxError {
+    /// Every attempt found an entry locked elsewhere, by another process or another guard of this one.
+    /// `path`, relative to the root, is the entry the last attempt was blocked on.
+    #[error("blocked on {} by another holder of its lock after {attempts} attempts", path.display())]
+    Contended {
+        path: PathBuf,
+        attempts: u32,
+        elapsed: Duration,
+    },
+    #[error(transparent)]
+    Failed(#[from] Error),
+}
+
+impl Client {
+    /// Run `body` in a transaction that `spec` declares on a fresh [`Client::tx`], taking its locks with
+    /// [`TxBuilder::try_begin`] and trying again as `policy` allows while any of them is held elsewhere.
+    /// `body` is only called once every lock is held, with the same transaction a first attempt would have
+    /// begun. Any other failure to begin, or of `body`, ends the attempts in [`TryTxError::Failed`].
+    pub fn try_tx<T, S, B>(&self, spec: S, body: B, policy: &TryPolicy) -> Result<T, TryTxError>
+    where
+        S: Fn(TxBuilder) -> TxBuilder,
+        B: FnOnce(Tx) -> Result<T>,
+    {
+        let start = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let path = match spec(self.tx()).try_begin() {
+                Ok(tx) => return Ok(self.redacted(|| body(tx))?),
+                Err(Error::WouldBlock { path }) => path,
+                Err(e) => return Err(self.inner.redact.error(e).into()),
+            };
+            let backoff = backoff(policy, attempts);
+            let exhausted = attempts >= policy.attempts
+                || policy
+                    .total_budget
+                    .is_some_and(|budget| start.elapsed() + backoff > budget);
+            if exhausted {
+                return Err(TryTxError::Contended {
+                    path: path
+                        .strip_prefix(&self.inner.root)
+                        .map_or(path.clone(), Path::to_path_buf),
+                    attempts,
+                    elapsed: start.elapsed(),
+                });
+            }
+            thread::sleep(backoff);
+        }
+    }
+}
+
+/// Wait after the failed attempt number `attempt`, counting from one.
+fn backoff(policy: &TryPolicy, attempt: u32) -> Duration {
+    let doubled = policy
+        .base_backoff
+        .saturating_mul(1 << (attempt - 1).min(31));
+    let jitter = match policy.jitter.is_zero() {
+        true => Duration::ZERO,
+        false => rand::rng().random_range(Duration::ZERO..=policy.jitter),
+    };
+    doubled.saturating_add(jitter)
+}
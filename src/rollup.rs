@@ -0,0 +1,334 @@
+//! Per directory totals for dashboards and the like, enabled with [`crate::ClientBuilder::track_rollups`],
+//! see [`Client::rollup`]. Every commit and removal appends the change it made, in bytes and files, along
+//! with its time to the rollup of each directory above the entry, so that the totals of a directory can be
+//! read without walking it.
+//!
+//! The rollup of a directory is stored next to it in `.name.rollup.sbdb`, the root's in `.sbdb/rollup`. It
+//! is a log of fixed width lines, each a time and the changes in bytes and files, which is folded into a
+//! single line once it grows long. Appending and folding take the lock of the rollup file only, never the
+//! locks of the directories above an entry, so commits wait for nothing they did not wait for before.
+//!
+//! The time of the last change is exact. The sizes start from the last [`Client::rebuild_rollups`] of a
+//! directory and are only kept up by clients that track rollups, so they drift when files change
+//! otherwise, e.g. through another client or a crash between a commit and its record, until the next
+//! rebuild. A directory commit counts as a change of everything in the directory, whose rollups are
+//! rebuilt along with it.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(windows)]
+use std::os::windows::prelude::*;
+
+use crate::{
+    Client, INTERNAL_DIR, Result, artifact, error::IoResultExt, is_atomic_dir_link,
+    normalize_rpath, path_hidden_with_extension, quota,
+};
+
+#[cfg(windows)]
+use crate::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+/// Length of a line: the time in nanoseconds since the epoch and the signed changes in bytes and files.
+const LINE_LEN: u64 = 65;
+
+/// Lines a rollup is folded at.
+const FOLD_LINES: u64 = 64;
+
+/// Totals of a directory, see [`Client::rollup`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Rollup {
+    /// When the last change at or below the directory was recorded, `None` if none was.
+    pub last_modified: Option<SystemTime>,
+    /// Bytes of data below the directory, counted like [`Client::stats`] does. Approximate, see the
+    /// [module documentation](self).
+    pub approx_bytes: u64,
+    /// Files below the directory at any depth, approximate like `approx_bytes`.
+    pub entries: u64,
+}
+
+/// A line of a rollup, or the sum of several.
+#[derive(Clone, Copy, Debug, Default)]
+struct Line {
+    modified: u64,
+    bytes: i64,
+    files: i64,
+}
+
+impl Line {
+    fn add(&mut self, other: Line) {
+        self.modified = self.modified.max(other.modified);
+        self.bytes = self.bytes.saturating_add(other.bytes);
+        self.files = self.files.saturating_add(other.files);
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        // fixed width, so that a folded line overwrites exactly the first one
+        format!(
+            "{:020} {:+021} {:+021}\n",
+            self.modified, self.bytes, self.files
+        )
+        .into_bytes()
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Line> {
+        let mut fields = std::str::from_utf8(bytes).ok()?.trim_end().split(' ');
+        let line = Line {
+            modified: fields.next()?.parse().ok()?,
+            bytes: fields.next()?.parse().ok()?,
+            files: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(line)
+    }
+}
+
+impl From<Line> for Rollup {
+    fn from(line: Line) -> Self {
+        Rollup {
+            last_modified: (line.modified > 0)
+                .then(|| UNIX_EPOCH + Duration::from_nanos(line.modified)),
+            approx_bytes: line.bytes.max(0) as u64,
+            entries: line.files.max(0) as u64,
+        }
+    }
+}
+
+/// Bytes and files of data at a path.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Usage {
+    bytes: u64,
+    files: u64,
+}
+
+impl Usage {
+    pub(crate) fn of(path: &Path) -> Self {
+        let (bytes, files) = quota::data_usage(path);
+        Usage { bytes, files }
+    }
+}
+
+pub(crate) struct Rollups {
+    root: PathBuf,
+}
+
+impl Rollups {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        Rollups { root }
+    }
+
+    /// Records the change of the entry at `orig`, which held `before` and is still write locked, in the
+    /// rollups of the directories above it. The rollups of a directory at `orig` are rebuilt.
+    pub(crate) fn changed(&self, orig: &Path, before: Usage) -> Result<()> {
+        let Ok(rpath) = orig.strip_prefix(&self.root) else {
+            return Ok(());
+        };
+        if rpath.starts_with(INTERNAL_DIR) {
+            return Ok(());
+        }
+        let now = now();
+        let after = match is_dir(orig) {
+            true => rebuild(&self.root, rpath, orig, now)?,
+            false => {
+                // the rollup of a directory that was removed
+                if !rpath.as_os_str().is_empty() {
+                    let path = rollup_path(&self.root, rpath)?;
+                    if let Err(e) = fs::remove_file(&path)
+                        && e.kind() != std::io::ErrorKind::NotFound
+                    {
+                        return Err(crate::Error::io("remove rollup", &path, e));
+                    }
+                }
+                let after = Usage::of(orig);
+                Line {
+                    modified: now,
+                    bytes: after.bytes as i64,
+                    files: after.files as i64,
+                }
+            }
+        };
+        let change = Line {
+            modified: now,
+            bytes: after.bytes - before.bytes as i64,
+            files: after.files - before.files as i64,
+        };
+        append_above(&self.root, rpath, change)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn is_dir(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_symlink() => is_atomic_dir_link(path).unwrap_or(false),
+        Ok(metadata) => metadata.is_dir(),
+        Err(_) => false,
+    }
+}
+
+fn rollup_path(root: &Path, rpath: &Path) -> Result<PathBuf> {
+    if rpath.as_os_str().is_empty() {
+        Ok(root.join(INTERNAL_DIR).join("rollup"))
+    } else {
+        path_hidden_with_extension(root.join(rpath), artifact::ROLLUP)
+    }
+}
+
+fn open_rollup(root: &Path, path: &Path) -> Result<File> {
+    let internal = root.join(INTERNAL_DIR);
+    if path.starts_with(&internal) {
+        fs::create_dir_all(&internal).at("create directory", &internal)?;
+    }
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(windows)]
+    options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+    options.open(path).at("open rollup", path)
+}
+
+/// Sum of the lines of the rollup. A line cut short by a crash is left out.
+fn read_lines(mut file: &File, path: &Path) -> Result<Line> {
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0)).at("read", path)?;
+    file.read_to_end(&mut contents).at("read", path)?;
+    let mut sum = Line::default();
+    for line in contents.chunks_exact(LINE_LEN as usize) {
+        if let Some(line) = Line::parse(line) {
+            sum.add(line);
+        }
+    }
+    Ok(sum)
+}
+
+/// Replaces the lines of the rollup with `line`. A crash in between leaves lines that are counted twice,
+/// which only the sizes suffer from.
+fn write_line(mut file: &File, path: &Path, line: Line) -> Result<()> {
+    file.seek(SeekFrom::Start(0)).at("write", path)?;
+    file.write_all(&line.to_bytes()).at("write", path)?;
+    file.set_len(LINE_LEN).at("write", path)
+}
+
+/// Appends `change` to the rollups of the directories above `rpath`, folding those that grew long.
+fn append_above(root: &Path, rpath: &Path, change: Line) -> Result<()> {
+    for ancestor in rpath.ancestors().skip(1) {
+        let path = rollup_path(root, ancestor)?;
+        let file = open_rollup(root, &path)?;
+        file.lock().at("lock", &path)?;
+        let result = (|| {
+            let mut file = &file;
+            let len = file.metadata().at("read metadata of", &path)?.len();
+            // a line cut short by a crash would misalign every line after it
+            let len = len - len % LINE_LEN;
+            file.set_len(len).at("write", &path)?;
+            file.seek(SeekFrom::Start(len)).at("write", &path)?;
+            file.write_all(&change.to_bytes()).at("write", &path)?;
+            if len + LINE_LEN >= FOLD_LINES * LINE_LEN {
+                let sum = read_lines(file, &path)?;
+                write_line(file, &path, sum)?;
+            }
+            Ok(())
+        })();
+        file.unlock().at("unlock", &path)?;
+        result?;
+    }
+    Ok(())
+}
+
+/// Rollup of the directory `rpath`, empty if none was recorded.
+fn read(root: &Path, rpath: &Path) -> Result<Line> {
+    let path = rollup_path(root, rpath)?;
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Line::default()),
+        Err(e) => return Err(crate::Error::io("open rollup", &path, e)),
+    };
+    file.lock_shared().at("lock", &path)?;
+    let result = read_lines(&file, &path);
+    file.unlock().at("unlock", &path)?;
+    result
+}
+
+/// Counts the data in the directory `rpath` at `path`, which is locked, writes the totals to its rollup and
+/// those of the directories below it, and returns them. The time of the last change of a directory is the
+/// latest of `floor`, the one its rollup recorded and the modification times of the files below it.
+fn rebuild(root: &Path, rpath: &Path, path: &Path, floor: u64) -> Result<Line> {
+    let mut totals = read(root, rpath)?;
+    totals.modified = totals.modified.max(floor);
+    totals.bytes = 0;
+    totals.files = 0;
+    for entry in fs::read_dir(path).at("read directory", path)? {
+        let entry = entry.at("read directory", path)?;
+        let name = entry.file_name();
+        let child = entry.path();
+        if rpath.as_os_str().is_empty() && name == INTERNAL_DIR {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(&child).at("read metadata of", &child)?;
+        if let Some((kind, _)) = artifact::parse(&name)
+            && artifact::is_own(kind, &metadata)
+        {
+            continue;
+        }
+        if metadata.is_dir() || (metadata.is_symlink() && is_atomic_dir_link(&child)?) {
+            totals.add(rebuild(root, &rpath.join(&name), &child, 0)?);
+        } else if metadata.is_file() {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos() as u64);
+            totals.add(Line {
+                modified,
+                bytes: metadata.len() as i64,
+                files: 1,
+            });
+        }
+    }
+    let rollup = rollup_path(root, rpath)?;
+    let file = open_rollup(root, &rollup)?;
+    file.lock().at("lock", &rollup)?;
+    let result = write_line(&file, &rollup, totals);
+    file.unlock().at("unlock", &rollup)?;
+    result?;
+    Ok(totals)
+}
+
+impl Client {
+    /// Totals of the directory at `rpath` as recorded by the clients that track rollups, see the
+    /// [module documentation](self). Read without walking or locking the directory. A directory that
+    /// nothing was recorded for, e.g. one that does not exist, has an empty rollup.
+    pub fn rollup<P: AsRef<Path>>(&self, rpath: P) -> Result<Rollup> {
+        self.redacted(|| Ok(read(&self.inner.root, &normalize_rpath(rpath.as_ref())?)?.into()))
+    }
+
+    /// Count the data in the directory at `rpath` again and make that the rollups of it and of every
+    /// directory below it, so that their sizes are exact again. The change is recorded in the rollups of
+    /// the directories above. The directory is read locked meanwhile, so no commits below it are missed.
+    pub fn rebuild_rollups<P: AsRef<Path>>(&self, rpath: P) -> Result<Rollup> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let gaurd = self.read_dir(&rpath)?;
+            let before = read(&self.inner.root, &rpath)?;
+            let after = rebuild(&self.inner.root, &rpath, &gaurd.path, 0)?;
+            append_above(
+                &self.inner.root,
+                &rpath,
+                Line {
+                    modified: after.modified,
+                    bytes: after.bytes - before.bytes,
+                    files: after.files - before.files,
+                },
+            )?;
+            gaurd.release()?;
+            Ok(after.into())
+        })
+    }
+}
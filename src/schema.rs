@@ -0,0 +1,188 @@
+//! Values whose schema evolves, see [`VersionedCodec`]. Every file starts with a header of magic bytes and
+//! the schema version the value was written at, followed by the value as JSON. Older values are brought up
+//! to the current version as JSON by the migrations of a [`MigrationChain`] before they are deserialized.
+
+use std::{marker::PhantomData, path::Path};
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{Client, Error, Result, file_replace_with, read_value_file};
+
+pub type MigrationError = Box<dyn std::error::Error + Send + Sync>;
+
+type Migration = Box<dyn Fn(Value) -> std::result::Result<Value, MigrationError> + Send + Sync>;
+
+const MAGIC: [u8; 4] = *b"SBSV";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+/// Migrations of the values of type `T` from each schema version to the next, see
+/// [`VersionedCodec::read_versioned`].
+pub struct MigrationChain<T> {
+    first: u32,
+    migrations: Vec<Migration>,
+    upgrade_in_place: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> MigrationChain<T> {
+    /// A chain without migrations, where `first` is the oldest and current version.
+    pub fn new(first: u32) -> Self {
+        MigrationChain {
+            first,
+            migrations: Vec::new(),
+            upgrade_in_place: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Add the migration of values from the current version to the next, which becomes the current one.
+    pub fn then<F>(mut self, migration: F) -> Self
+    where
+        F: Fn(Value) -> std::result::Result<Value, MigrationError> + Send + Sync + 'static,
+    {
+        self.migrations.push(Box::new(migration));
+        self
+    }
+
+    /// Add a migration like [`MigrationChain::then`] between two types, for those that are easier to write
+    /// against the old and new structs than against JSON.
+    pub fn then_typed<Old, New, F>(self, migration: F) -> Self
+    where
+        Old: DeserializeOwned,
+        New: Serialize,
+        F: Fn(Old) -> std::result::Result<New, MigrationError> + Send + Sync + 'static,
+    {
+        self.then(move |value| {
+            let new = migration(serde_json::from_value(value)?)?;
+            Ok(serde_json::to_value(new)?)
+        })
+    }
+
+    /// Rewrite files read at an older version at the current one, off by default. The file is then written
+    /// under its write lock, which is only taken once the read lock is released, so the file is read again
+    /// under it.
+    pub fn upgrade_in_place(mut self, upgrade: bool) -> Self {
+        self.upgrade_in_place = upgrade;
+        self
+    }
+
+    /// The version that values are migrated to, the first one plus the number of migrations.
+    pub fn current(&self) -> u32 {
+        self.first + self.migrations.len() as u32
+    }
+
+    /// Migrate `value`, stored at `version` in the file at `path`, to the current version.
+    fn migrate(&self, path: &Path, version: u32, mut value: Value) -> Result<Value> {
+        if version > self.current() {
+            return Err(Error::VersionTooNew {
+                path: path.to_path_buf(),
+                found: version,
+                supported: self.current(),
+            });
+        }
+        if version < self.first {
+            return Err(Error::MigrationFailed {
+                path: path.to_path_buf(),
+                from: version,
+                source: format!("no migration from versions before {}", self.first).into(),
+            });
+        }
+        for (from, migration) in
+            (version..).zip(&self.migrations[(version - self.first) as usize..])
+        {
+            value = migration(value).map_err(|source| Error::MigrationFailed {
+                path: path.to_path_buf(),
+                from,
+                source,
+            })?;
+        }
+        Ok(value)
+    }
+}
+
+/// Reads and writes values of type `T` along with the version of their schema, see the
+/// [module documentation](self).
+pub struct VersionedCodec<T> {
+    client: Client,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl Client {
+    pub fn versioned_codec<T: Serialize + DeserializeOwned>(&self) -> VersionedCodec<T> {
+        VersionedCodec {
+            client: self.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> VersionedCodec<T> {
+    /// Atomically replace the file at `rpath` with `value` at schema `version`, creating it if it does not
+    /// exist yet.
+    pub fn write_versioned<P: AsRef<Path>>(&self, rpath: P, value: &T, version: u32) -> Result<()> {
+        let gaurd = self.client.write_file(rpath)?;
+        let bytes = encode(&gaurd.path, value, version)?;
+        file_replace_with(&gaurd.path, &bytes, false, gaurd.ctx.clone())
+    }
+
+    /// Read the value at `rpath`, migrated to the current version of `migrations` if it was written at an
+    /// older one. Values of a newer version fail with [`Error::VersionTooNew`].
+    pub fn read_versioned<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        migrations: &MigrationChain<T>,
+    ) -> Result<T> {
+        let rpath = rpath.as_ref();
+        let gaurd = self.client.read_file(rpath)?;
+        let (version, value) = read(&gaurd.path, migrations)?;
+        if version == migrations.current() || !migrations.upgrade_in_place {
+            return decode(&gaurd.path, value);
+        }
+        gaurd.release()?;
+
+        let gaurd = self.client.write_file(rpath)?;
+        let (version, value) = read(&gaurd.path, migrations)?;
+        let value = decode(&gaurd.path, value)?;
+        if version != migrations.current() {
+            let bytes = encode(&gaurd.path, &value, migrations.current())?;
+            file_replace_with(&gaurd.path, &bytes, false, gaurd.ctx.clone())?;
+        }
+        Ok(value)
+    }
+}
+
+/// Version of the file at `path` and its value migrated to the current version.
+fn read<T>(path: &Path, migrations: &MigrationChain<T>) -> Result<(u32, Value)> {
+    let bytes = read_value_file(path)?;
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Err(Error::WrongFormat {
+            path: path.to_path_buf(),
+        });
+    }
+    let version = u32::from_le_bytes(bytes[MAGIC.len()..HEADER_LEN].try_into().unwrap());
+    let value = serde_json::from_slice(&bytes[HEADER_LEN..]).map_err(|e| decode_error(path, e))?;
+    Ok((version, migrations.migrate(path, version, value)?))
+}
+
+fn decode<T: DeserializeOwned>(path: &Path, value: Value) -> Result<T> {
+    serde_json::from_value(value).map_err(|e| decode_error(path, e))
+}
+
+fn decode_error(path: &Path, e: serde_json::Error) -> Error {
+    Error::Decode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    }
+}
+
+fn encode<T: Serialize>(path: &Path, value: &T, version: u32) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    serde_json::to_writer(&mut bytes, value).map_err(|e| Error::Encode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })?;
+    Ok(bytes)
+}
@@ -0,0 +1,234 @@
+//! Scratch directories in `<root>/.sbdb/tmp`, on the same filesystem as the database, where data can be
+//! put together before it is moved in with a rename, or copied out to be read at leisure.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    Client, Error, GcReport, INTERNAL_DIR, ImportMode, Result, Warning, copy_visible,
+    error::IoResultExt, is_older_than, normalize_rpath, unused_child,
+};
+
+/// Directory of the internal directory that holds scratch directories and other staging areas.
+pub(crate) const SCRATCH_DIR: &str = "tmp";
+
+/// How long gc leaves entries of the scratch area alone, unless [`crate::GcOptions::min_age`] is longer.
+/// Scratch directories are not locked, so gc can only tell from their age that they were left behind.
+pub const SCRATCH_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// File in a scratch directory that its owner keeps locked, so that gc leaves the directory alone while
+/// the owner lives, however old it is, and removes it as soon as the owner is gone.
+const PIN: &str = "pin";
+
+/// A directory created by [`Client::scratch`], removed with everything in it when dropped unless it was
+/// promoted into the database.
+#[derive(Debug)]
+pub struct ScratchDir {
+    client: Client,
+    pub path: PathBuf,
+}
+
+impl Client {
+    /// Create an empty directory to assemble data in before it is moved into the database with
+    /// [`ScratchDir::promote`]. It lives in the database's internal directory, so it is on the same
+    /// filesystem and the move is a rename, and is never locked, listed or counted as data. Directories
+    /// left behind by a crash are removed by gc once they are older than [`SCRATCH_GRACE`].
+    pub fn scratch(&self) -> Result<ScratchDir> {
+        self.redacted(|| {
+            let path = unused_child(&self.inner.root.join(INTERNAL_DIR).join(SCRATCH_DIR));
+            fs::create_dir_all(&path).at("create directory", &path)?;
+            Ok(ScratchDir {
+                client: self.clone(),
+                path,
+            })
+        })
+    }
+
+    /// Copy the directory at `rpath` into the scratch area, read locking it only while it is copied, so that
+    /// a long pass over the copy neither sees commits made in the meantime nor keeps writers waiting.
+    /// Internal files are left out and atomic directories are copied as plain ones, like
+    /// [`Client::snapshot`] does. The copy is reflinked where the filesystem supports it, otherwise the data
+    /// is copied in full, which is reported as [`Warning::FullCopy`]. Gc leaves the copy alone while the
+    /// returned snapshot is alive, and removes it once the process that made it is gone.
+    pub fn snapshot_dir_for_read<P: AsRef<Path>>(&self, rpath: P) -> Result<ReadSnapshot> {
+        self.redacted(|| {
+            let rpath = normalize_rpath(rpath.as_ref())?;
+            let dir = unused_child(&self.inner.root.join(INTERNAL_DIR).join(SCRATCH_DIR));
+            fs::create_dir_all(&dir).at("create directory", &dir)?;
+            let pin = match pin(&dir) {
+                Ok(pin) => pin,
+                Err(e) => {
+                    let _ = fs::remove_dir_all(&dir);
+                    return Err(e);
+                }
+            };
+            // removes the directory again should the copy fail
+            let snapshot = ReadSnapshot {
+                data: dir.join("data"),
+                dir,
+                pin: Some(pin),
+            };
+
+            let gaurd = self.read_dir(&rpath)?;
+            let internal = self.inner.root.join(INTERNAL_DIR);
+            let ctx = self.ctx();
+            let mut copied = 0;
+            ctx.copy(|stats| {
+                let result = copy_visible(
+                    gaurd.resolved_path(),
+                    &snapshot.data,
+                    &internal,
+                    &ctx,
+                    stats,
+                );
+                copied = stats.copied;
+                result
+            })?;
+            let path = gaurd.path.clone();
+            gaurd.release()?;
+            if copied > 0 {
+                self.warn(Warning::FullCopy {
+                    path,
+                    bytes: copied,
+                });
+            }
+            Ok(snapshot)
+        })
+    }
+
+    /// Removes what was left in the scratch area, see [`SCRATCH_GRACE`] and [`PIN`].
+    pub(crate) fn gc_scratch(&self, min_age: Duration, dry_run: bool, report: &mut GcReport) {
+        let dir = self.inner.root.join(INTERNAL_DIR).join(SCRATCH_DIR);
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match is_pinned(&path) {
+                Some(true) => continue,
+                Some(false) => {}
+                None if !is_older_than(&path, min_age.max(SCRATCH_GRACE)) => continue,
+                None => {}
+            }
+            if !dry_run && let Err(e) = self.inner.vfs.remove_dir_all(&path) {
+                report.errors += 1;
+                self.warn(Warning::Gc {
+                    error: Error::io("remove", &path, e),
+                    path,
+                });
+                continue;
+            }
+            report.temps_removed += 1;
+        }
+    }
+}
+
+impl ScratchDir {
+    /// Move everything in the scratch directory into the database at `dest_rpath`, like
+    /// [`Client::import_dir`] moving it, replacing what is there. Readers see either the previous
+    /// directory or all of the promoted one.
+    pub fn promote<P: AsRef<Path>>(self, dest_rpath: P) -> Result<()> {
+        self.client.import_dir(
+            &self.path,
+            dest_rpath,
+            ImportMode::Move {
+                overwrite: true,
+                sync: false,
+            },
+        )
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        match fs::remove_dir_all(&self.path) {
+            // promoted
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                log::warn!(path:? = self.path, operation = "cleanup"; "failed to remove scratch directory: {}", e)
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Creates the locked [`PIN`] of the scratch directory `dir`. It is locked before it gets its name, so gc
+/// never sees it unlocked while its owner lives.
+fn pin(dir: &Path) -> Result<File> {
+    let new = dir.join(format!("{PIN}.new"));
+    let pin = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&new)
+        .at("create", &new)?;
+    pin.lock().at("lock", &new)?;
+    let path = dir.join(PIN);
+    fs::rename(&new, &path).at("rename", &new)?;
+    Ok(pin)
+}
+
+/// Whether the owner of the scratch directory `dir` is still alive, `None` if it has no [`PIN`].
+fn is_pinned(dir: &Path) -> Option<bool> {
+    let pin = match File::open(dir.join(PIN)) {
+        Ok(pin) => pin,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(_) => return Some(true),
+    };
+    match pin.try_lock() {
+        Ok(()) => {
+            let _ = pin.unlock();
+            Some(false)
+        }
+        Err(_) => Some(true),
+    }
+}
+
+/// A copy of a directory made by [`Client::snapshot_dir_for_read`], removed when dropped.
+#[derive(Debug)]
+pub struct ReadSnapshot {
+    dir: PathBuf,
+    data: PathBuf,
+    /// Locked until the snapshot is dropped, see [`PIN`].
+    pin: Option<File>,
+}
+
+impl ReadSnapshot {
+    /// The copy of the directory.
+    pub fn path(&self) -> &Path {
+        &self.data
+    }
+
+    /// Paths of everything in the copy but directories, relative to [`ReadSnapshot::path`] and sorted.
+    pub fn walk(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(rpath) = pending.pop() {
+            let dir = self.data.join(&rpath);
+            for entry in fs::read_dir(&dir).at("read directory", &dir)? {
+                let entry = entry.at("read directory", &dir)?;
+                let file_type = entry.file_type().at("read metadata of", entry.path())?;
+                let child = rpath.join(entry.file_name());
+                match file_type.is_dir() {
+                    true => pending.push(child),
+                    false => paths.push(child),
+                }
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+impl Drop for ReadSnapshot {
+    fn drop(&mut self) {
+        // closing the pin releases it, which has to happen before it can be removed on windows
+        drop(self.pin.take());
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            log::warn!(path:? = self.dir, operation = "cleanup"; "failed to remove read snapshot: {}", e)
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Named point-in-time copies of the database or one of its subtrees. Snapshots live below
+//! `.sbdb/snapshots/<name>/<id>`, which gc never visits, and are made with reflinks where the filesystem
+//! supports them, so taking one is cheap.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, ReadLock, Result, WriteLock, artifact, commit_dir_with,
+    copy_recursive, copy_visible, error::IoResultExt, key, normalize_rpath,
+    path_hidden_with_extension, puuid, scratch::SCRATCH_DIR, unused_child,
+};
+
+const SNAPSHOTS: &str = "snapshots";
+
+/// Identifies a snapshot created by [`Client::snapshot`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SnapshotId {
+    pub name: String,
+    pub id: String,
+}
+
+impl fmt::Display for SnapshotId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.name, self.id)
+    }
+}
+
+impl Client {
+    /// Copy the subtree at `rpath` into a new snapshot. The subtree is write locked while it is copied,
+    /// internal files are left out and atomic directories are stored as plain directories.
+    pub fn snapshot<P: AsRef<Path>>(&self, rpath: P, name: &str) -> Result<SnapshotId> {
+        self.redacted(|| {
+            let rpath = &normalize_rpath(rpath.as_ref())?;
+            let rpath_str = rpath
+                .to_str()
+                .ok_or_else(|| Error::invalid_path(rpath, "path is not valid utf-8"))?;
+            let id = SnapshotId {
+                name: name.to_string(),
+                id: puuid(),
+            };
+            let dir = self.inner.root.join(self.snapshot_rpath(&id));
+
+            let result = (|| {
+                let gaurd = self.write_dir(rpath)?;
+                let internal = self.inner.root.join(INTERNAL_DIR);
+                let data = dir.join("data");
+                let ctx = self.ctx();
+                ctx.copy(|stats| copy_visible(&gaurd.path, &data, &internal, &ctx, stats))?;
+                // written last, snapshots without it are incomplete and not listed
+                fs::write(dir.join("name"), name).at("write", dir.join("name"))?;
+                fs::write(dir.join("path"), rpath_str).at("write", dir.join("path"))
+            })();
+            if let Err(e) = result {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+            Ok(id)
+        })
+    }
+
+    /// Snapshots taken of `rpath`, oldest first.
+    pub fn list_snapshots<P: AsRef<Path>>(&self, rpath: P) -> Result<Vec<SnapshotId>> {
+        self.redacted(|| {
+            let snapshots = self.inner.root.join(INTERNAL_DIR).join(SNAPSHOTS);
+            let mut result = Vec::new();
+            let names = match fs::read_dir(&snapshots) {
+                Ok(names) => names,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(Error::io("read directory", &snapshots, e)),
+            };
+            for name_dir in names {
+                let name_dir = name_dir.at("read directory", &snapshots)?.path();
+                if !name_dir.is_dir() {
+                    continue;
+                }
+                for snapshot in fs::read_dir(&name_dir).at("read directory", &name_dir)? {
+                    let snapshot = snapshot.at("read directory", &name_dir)?;
+                    let dir = snapshot.path();
+                    let Ok(path) = fs::read_to_string(dir.join("path")) else {
+                        continue;
+                    };
+                    if Path::new(&path) != rpath.as_ref() {
+                        continue;
+                    }
+                    let name = fs::read_to_string(dir.join("name")).at("read", dir.join("name"))?;
+                    let created = fs::metadata(dir.join("path"))
+                        .and_then(|m| m.modified())
+                        .unwrap_or(SystemTime::UNIX_EPOCH);
+                    let id = SnapshotId {
+                        name,
+                        id: snapshot.file_name().to_string_lossy().into_owned(),
+                    };
+                    result.push((created, id));
+                }
+            }
+            result.sort_by_key(|(created, _)| *created);
+            Ok(result.into_iter().map(|(_, id)| id).collect())
+        })
+    }
+
+    /// Replace the subtree at `rpath` with the contents of a snapshot. Subtrees are swapped in with the
+    /// same strategy as [`crate::CowDirGaurd::commit`]. The root cannot be renamed, so when restoring it
+    /// its entries are swapped one at a time instead; this happens under a write lock on the root, so it
+    /// is not observable by other clients, but a crash part way through leaves the previous entries
+    /// in `.sbdb/tmp`.
+    pub fn restore_snapshot<P: AsRef<Path>>(&self, rpath: P, id: &SnapshotId) -> Result<()> {
+        self.redacted(|| {
+            let ctx = self.ctx();
+            let snapshot_rpath = self.snapshot_rpath(id);
+            let data = self.inner.root.join(&snapshot_rpath).join("data");
+            let _snapshot = ReadLock::acquire(&self.inner.root, &snapshot_rpath, &ctx)?;
+            if !self.inner.root.join(&snapshot_rpath).join("path").exists() {
+                return Err(Error::NotFound {
+                    operation: "restore snapshot",
+                    path: self.inner.root.join(&snapshot_rpath),
+                });
+            }
+
+            let gaurd = self.write_dir(&rpath)?;
+            if !rpath.as_ref().as_os_str().is_empty() {
+                let tmp = path_hidden_with_extension(&gaurd.path, artifact::TMP)?;
+                if tmp.exists() {
+                    fs::remove_dir_all(&tmp).at("remove stale copy", &tmp)?;
+                }
+                ctx.copy(|stats| copy_recursive(&data, &tmp, &ctx, stats))?;
+                return commit_dir_with(tmp, gaurd.path.clone(), gaurd.ctx.clone());
+            }
+
+            let tmp = self.inner.root.join(INTERNAL_DIR).join(SCRATCH_DIR);
+            let staging = unused_child(&tmp);
+            let backup = unused_child(&tmp);
+            ctx.copy(|stats| copy_recursive(&data, &staging, &ctx, stats))?;
+            fs::create_dir_all(&backup).at("create directory", &backup)?;
+            let internal = self.inner.root.join(INTERNAL_DIR);
+            ctx.commit(crate::CommitKind::Dir, &self.inner.root, || {
+                for entry in fs::read_dir(&self.inner.root).at("read directory", &self.inner.root)? {
+                    let entry = entry.at("read directory", &self.inner.root)?;
+                    let name = entry.file_name();
+                    match crate::artifact::parse(&name) {
+                        // every entry is replaced, so their counters fall back to the root's and their
+                        // rollups are rebuilt
+                        Some((
+                            crate::artifact::ArtifactKind::Generation
+                            | crate::artifact::ArtifactKind::Rollup,
+                            _,
+                        )) => {
+                            fs::remove_file(entry.path()).at("remove", entry.path())?;
+                            continue;
+                        }
+                        Some(_) => continue,
+                        None if entry.path() == internal => continue,
+                        None => {}
+                    }
+                    fs::rename(entry.path(), backup.join(&name)).at("back up", entry.path())?;
+                }
+                for entry in fs::read_dir(&staging).at("read directory", &staging)? {
+                    let entry = entry.at("read directory", &staging)?;
+                    let target = self.inner.root.join(entry.file_name());
+                    fs::rename(entry.path(), &target).at("commit copy", entry.path())?;
+                }
+                Ok(())
+            })?;
+            for dir in [&staging, &backup] {
+                if let Err(e) = fs::remove_dir_all(dir) {
+                    log::warn!(path:? = self.inner.redact.path(dir), operation = "cleanup"; "failed to remove restore leftovers: {}", e);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn delete_snapshot(&self, id: &SnapshotId) -> Result<()> {
+        self.redacted(|| {
+            let snapshot_rpath = self.snapshot_rpath(id);
+            let dir = self.inner.root.join(&snapshot_rpath);
+            {
+                let _snapshot = WriteLock::acquire(&self.inner.root, &snapshot_rpath, &self.ctx())?;
+                fs::remove_dir_all(&dir).at("remove", &dir)?;
+            }
+            // best effort, the lock files of a removed snapshot are no longer needed
+            for ext in [artifact::LOCK, artifact::QUEUE] {
+                if let Ok(path) = path_hidden_with_extension(&dir, ext) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+            if let Some(parent) = dir.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+            Ok(())
+        })
+    }
+
+    fn snapshot_rpath(&self, id: &SnapshotId) -> PathBuf {
+        Path::new(INTERNAL_DIR)
+            .join(SNAPSHOTS)
+            .join(key::encode(&id.name))
+            .join(&id.id)
+    }
+}
@@ -0,0 +1,255 @@
+//! Sizes of a database, for telling how much of it is data and how much sbdb's own files.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, RESERVED_SUFFIXES, Result,
+    artifact::{self, ArtifactKind},
+    is_atomic_dir_link, ttl,
+};
+
+/// How many of the largest files [`Client::stats`] reports.
+pub const DEFAULT_LARGEST: usize = 10;
+
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct StatsOptions {
+    /// Report this many of the largest files, [`DEFAULT_LARGEST`] by default.
+    pub largest: usize,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        StatsOptions {
+            largest: DEFAULT_LARGEST,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+pub struct DbStats {
+    /// Files that are part of the data, including those in atomic directories.
+    pub files: u64,
+    pub file_bytes: u64,
+    /// Directories that are part of the data, including atomic directories but not the subtree itself.
+    pub dirs: u64,
+    /// How many directories deep the deepest directory is below the subtree.
+    pub max_depth: usize,
+    /// sbdb's own files by suffix, e.g. `.lock.sbdb`. Payloads of atomic directories are only counted
+    /// here while they are not linked, the current one is counted as the directory's data.
+    pub artifacts: BTreeMap<&'static str, EntryStats>,
+    /// Files in the `.sbdb` directory of the root, such as previous versions and the audit log. Only
+    /// counted for the whole database.
+    pub internal: EntryStats,
+    /// The largest files of the data, largest first.
+    pub largest: Vec<FileSize>,
+    /// Entries that expire, soonest first, see [`Client::write_with_ttl`].
+    pub ttls: Vec<EntryTtl>,
+}
+
+impl DbStats {
+    /// Bytes taken up by anything but the data.
+    pub fn overhead_bytes(&self) -> u64 {
+        self.artifacts
+            .values()
+            .map(|stats| stats.bytes)
+            .sum::<u64>()
+            + self.internal.bytes
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+pub struct EntryStats {
+    /// Number of entries, or of files for [`DbStats::internal`].
+    pub count: u64,
+    /// Sizes of the files, those of directories summed up over everything below them.
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+pub struct FileSize {
+    /// Path relative to the database root.
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+pub struct EntryTtl {
+    /// Path relative to the database root.
+    pub path: PathBuf,
+    /// Time left until the entry expires, zero if it expired but was not removed by gc yet.
+    pub remaining: Duration,
+}
+
+impl Client {
+    /// Count and measure what is stored in the subtree at `rpath`, see [`Client::stats_with`].
+    pub fn stats<P: AsRef<Path>>(&self, rpath: P) -> Result<DbStats> {
+        self.redacted(|| self.stats_with(rpath, &StatsOptions::default()))
+    }
+
+    /// Walk the subtree at `rpath` and tell its data apart from sbdb's own files the same way
+    /// [`Client::gc`] does. Each directory is read locked while it is measured, entries that disappear in
+    /// the meantime are skipped. The maintenance lock is held for reading throughout, see
+    /// [`Client::maintenance_read`].
+    pub fn stats_with<P: AsRef<Path>>(&self, rpath: P, options: &StatsOptions) -> Result<DbStats> {
+        self.redacted(|| {
+            let _maintenance = self.maintenance_read()?;
+            let mut stats = DbStats::default();
+            let mut pending = vec![(rpath.as_ref().to_path_buf(), 0)];
+            while let Some((rpath, depth)) = pending.pop() {
+                stats.max_depth = stats.max_depth.max(depth);
+                for child in self.stats_dir(&rpath, options, &mut stats)? {
+                    pending.push((child, depth + 1));
+                }
+            }
+            stats
+                .largest
+                .sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+            stats.ttls.sort_by(|a, b| {
+                a.remaining
+                    .cmp(&b.remaining)
+                    .then_with(|| a.path.cmp(&b.path))
+            });
+            Ok(stats)
+        })
+    }
+
+    fn stats_dir(
+        &self,
+        rpath: &Path,
+        options: &StatsOptions,
+        stats: &mut DbStats,
+    ) -> Result<Vec<PathBuf>> {
+        let gaurd = self.read_dir(rpath)?;
+        let path = &gaurd.path;
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            // removed before it was locked
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::io("read directory", path, e)),
+        };
+
+        let mut children = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let child_path = entry.path();
+            let Ok(metadata) = fs::symlink_metadata(&child_path) else {
+                continue;
+            };
+            let file_type = metadata.file_type();
+
+            if rpath.as_os_str().is_empty() && name == INTERNAL_DIR {
+                let (count, bytes) = tree_size(&child_path);
+                stats.internal = EntryStats { count, bytes };
+                continue;
+            }
+
+            if let Some((kind, orig_name)) = artifact::parse(&name)
+                && artifact::is_own(kind, &metadata)
+            {
+                let linked = kind == ArtifactKind::AtomicDir
+                    && fs::read_link(path.join(&orig_name))
+                        .is_ok_and(|target| target == Path::new(&name));
+                if linked {
+                    continue;
+                }
+                if kind == ArtifactKind::Ttl
+                    && let Ok(Some(expires)) = ttl::read_expiry(&child_path)
+                {
+                    stats.ttls.push(EntryTtl {
+                        path: rpath.join(&orig_name),
+                        remaining: ttl::remaining(expires),
+                    });
+                }
+                let name = name.to_string_lossy();
+                let Some(suffix) = RESERVED_SUFFIXES
+                    .into_iter()
+                    .find(|suffix| name.ends_with(suffix))
+                else {
+                    continue;
+                };
+                let artifact = stats.artifacts.entry(suffix).or_default();
+                artifact.count += 1;
+                artifact.bytes += match file_type.is_dir() {
+                    true => tree_size(&child_path).1,
+                    false => metadata.len(),
+                };
+                continue;
+            }
+
+            if file_type.is_dir()
+                || (file_type.is_symlink() && is_atomic_dir_link(&child_path).unwrap_or(false))
+            {
+                stats.dirs += 1;
+                children.push(rpath.join(&name));
+            } else if file_type.is_file() {
+                stats.files += 1;
+                stats.file_bytes += metadata.len();
+                record_largest(
+                    &mut stats.largest,
+                    options.largest,
+                    FileSize {
+                        path: rpath.join(&name),
+                        bytes: metadata.len(),
+                    },
+                );
+            }
+        }
+        Ok(children)
+    }
+}
+
+/// Keeps the `n` largest files in `largest`, which is only sorted once the walk is done.
+fn record_largest(largest: &mut Vec<FileSize>, n: usize, file: FileSize) {
+    if largest.len() < n {
+        largest.push(file);
+    } else if let Some(smallest) = largest.iter_mut().min_by_key(|file| file.bytes)
+        && file.bytes > smallest.bytes
+    {
+        *smallest = file;
+    }
+}
+
+/// Number of files below `path` and their total size, skipping whatever disappears while it is walked.
+fn tree_size(path: &Path) -> (u64, u64) {
+    let (mut count, mut bytes) = (0, 0);
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.path().symlink_metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else if metadata.is_file() {
+                count += 1;
+                bytes += metadata.len();
+            }
+        }
+    }
+    (count, bytes)
+}
@@ -0,0 +1,63 @@
+//! Reading files without holding them in memory as a whole. Values of hundreds of megabytes can be read
+//! through a [`FileReadGaurd::reader`], or copied out with [`Client::copy_to`], in a buffer of
+//! [`crate::ClientBuilder::stream_buffer_len`] bytes. Copy-on-write copies never hold files in memory
+//! either, where they can not be reflinked they are copied by the operating system or in chunks, see
+//! [`crate::ClientBuilder::io_deadline`].
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use crate::{Client, FileReadGaurd, Result, error::IoResultExt};
+
+/// Size of the buffer that files are streamed through by default, see
+/// [`crate::ClientBuilder::stream_buffer_len`].
+pub const DEFAULT_STREAM_BUFFER_LEN: usize = 64 << 10;
+
+impl Client {
+    /// Copy the file at `rpath` to `writer` in chunks of [`crate::ClientBuilder::stream_buffer_len`]
+    /// bytes while it is read locked, returning the number of bytes copied. Unlike reading it whole,
+    /// memory use does not grow with the size of the file.
+    pub fn copy_to<P: AsRef<Path>, W: Write>(&self, rpath: P, writer: W) -> Result<u64> {
+        self.redacted(|| {
+            let gaurd = self.read_file(rpath)?;
+            gaurd.check_revoked()?;
+            let file = File::open(&gaurd.path).at("open", &gaurd.path)?;
+            copy_chunked(&gaurd.path, file, writer, self.inner.stream_buffer_len)
+        })
+    }
+}
+
+impl FileReadGaurd {
+    /// Buffered reader of the file. It borrows the guard, so the file stays read locked while it is used.
+    pub fn reader(&self) -> Result<impl BufRead + '_> {
+        self.check_revoked()?;
+        let file = File::open(&self.path).at("open", &self.path)?;
+        Ok(BufReader::new(file))
+    }
+}
+
+/// Copies `reader`, the contents of the file at `path`, to `writer` through a buffer of `buffer_len` bytes.
+pub(crate) fn copy_chunked<R: Read, W: Write>(
+    path: &Path,
+    mut reader: R,
+    mut writer: W,
+    buffer_len: usize,
+) -> Result<u64> {
+    let mut buffer = vec![0; buffer_len.max(1)];
+    let mut copied = 0;
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).at("read", path),
+        };
+        writer.write_all(&buffer[..len]).at("write copy of", path)?;
+        copied += len as u64;
+    }
+    writer.flush().at("write copy of", path)?;
+    Ok(copied)
+}
@@ -0,0 +1,47 @@
+//! Write locks held by this process, which the copy-on-write helpers are checked against with the
+//! `strict-locking` feature, see [`crate::unlocked`].
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{Error, Result};
+
+/// Entries write locked by this process, with how many times each is held.
+static HELD: Mutex<BTreeMap<PathBuf, usize>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn acquired(path: &Path) {
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    *held.entry(path.to_path_buf()).or_default() += 1;
+}
+
+pub(crate) fn released(path: &Path) {
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(count) = held.get_mut(path) {
+        *count -= 1;
+        if *count == 0 {
+            held.remove(path);
+        }
+    }
+}
+
+/// Fails unless this process holds a write lock on `path` or one of its ancestors. Panics instead in debug
+/// builds, where a copy made without one is a bug to be found rather than handled.
+pub(crate) fn check(path: &Path) -> Result<()> {
+    let covered = {
+        let held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+        path.ancestors().any(|ancestor| held.contains_key(ancestor))
+    };
+    if covered {
+        return Ok(());
+    }
+    let err = Error::NotWriteLocked {
+        path: path.to_path_buf(),
+    };
+    if cfg!(debug_assertions) {
+        panic!("{err}");
+    }
+    Err(err)
+}
@@ -0,0 +1,738 @@
+//! Building blocks for tests that check locking across processes, enabled with the `testkit` feature. A
+//! test binary spawns copies of itself as [`Worker`]s that hammer a shared database and record when they
+//! held which locks; the parent then loads every worker's [`Outcome`] and checks that no two conflicting
+//! critical sections overlapped.
+//!
+//! The library sleeps at a few [`PausePoint`]s while holding locks, which widens the windows in which a
+//! broken lock would let another process in. Pauses are configured per process, either with
+//! [`set_pause`] or through the [`PAUSE_ENV`] variable that [`Worker::command`] sets.
+//!
+//! Failures that the real filesystem rarely produces, such as a rename failing halfway through a directory
+//! commit, can be injected deterministically by building a client on a [`FaultVfs`].
+//!
+//! Tests of applications built on sbdb get a database of their own from [`TestClient`], lay out and check
+//! the entries in it with [`TreeBuilder`] and [`assert_tree_eq`], and exercise their recovery on what
+//! [`orphaned_temp`], [`dangling_atomic_dir`] and [`interrupted_dir_commit`] leave behind.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::Write as _,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    Client, Error, GcOptions, LockBackend, LockMode, Result, StdVfs, Vfs, error::IoResultExt,
+};
+
+pub use crate::fixture::{
+    InterruptedCommit, Node, TestClient, TreeBuilder, assert_tree_eq, dangling_atomic_dir,
+    interrupted_dir_commit, orphaned_temp,
+};
+
+/// Role of the worker, its presence selects worker mode.
+pub const WORKER_ENV: &str = "SBDB_TESTKIT_WORKER";
+pub const ROOT_ENV: &str = "SBDB_TESTKIT_ROOT";
+pub const OUTPUT_ENV: &str = "SBDB_TESTKIT_OUTPUT";
+pub const ITERATIONS_ENV: &str = "SBDB_TESTKIT_ITERATIONS";
+/// Pauses as a comma separated list of `point=milliseconds`, e.g. `dir_commit=5,lock_acquired=1`.
+pub const PAUSE_ENV: &str = "SBDB_TESTKIT_PAUSE";
+/// Lease in milliseconds if the worker locks with [`LockBackend::AtomicLockDir`], unset for the default
+/// backend.
+pub const LOCK_LEASE_ENV: &str = "SBDB_TESTKIT_LOCK_LEASE";
+
+/// Directory holding the files that readers, writers and transactions operate on, see [`prepare`].
+const FILES_DIR: &str = "files";
+const FILES: [&str; 2] = ["a", "b"];
+/// Directory replaced as a whole by [`Role::DirCommitter`]. Every file in it holds the same value.
+const DIR: &str = "dir";
+const DIR_FILES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PausePoint {
+    /// Right after a read or write lock was acquired.
+    LockAcquired,
+    /// Before the rename that commits a file.
+    FileCommit,
+    /// Between the two renames of a directory commit, while the directory does not exist.
+    DirCommit,
+    /// Before gc removes an artifact.
+    GcRemove,
+}
+
+impl PausePoint {
+    const ALL: [PausePoint; 4] = [
+        PausePoint::LockAcquired,
+        PausePoint::FileCommit,
+        PausePoint::DirCommit,
+        PausePoint::GcRemove,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            PausePoint::LockAcquired => "lock_acquired",
+            PausePoint::FileCommit => "file_commit",
+            PausePoint::DirCommit => "dir_commit",
+            PausePoint::GcRemove => "gc_remove",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|point| point.as_str() == s)
+    }
+}
+
+fn pauses() -> &'static Mutex<HashMap<PausePoint, Duration>> {
+    static PAUSES: OnceLock<Mutex<HashMap<PausePoint, Duration>>> = OnceLock::new();
+    PAUSES.get_or_init(|| {
+        let mut pauses = HashMap::new();
+        if let Ok(spec) = std::env::var(PAUSE_ENV) {
+            for entry in spec.split(',').filter(|entry| !entry.is_empty()) {
+                let parsed = entry.split_once('=').and_then(|(point, ms)| {
+                    Some((PausePoint::parse(point)?, ms.parse::<u64>().ok()?))
+                });
+                match parsed {
+                    Some((point, ms)) => {
+                        pauses.insert(point, Duration::from_millis(ms));
+                    }
+                    None => log::warn!(operation = "testkit"; "invalid pause: {}", entry),
+                }
+            }
+        }
+        Mutex::new(pauses)
+    })
+}
+
+/// Sleep for `duration` whenever this process passes `point`. A zero duration removes the pause.
+pub fn set_pause(point: PausePoint, duration: Duration) {
+    let mut pauses = pauses().lock().unwrap();
+    if duration.is_zero() {
+        pauses.remove(&point);
+    } else {
+        pauses.insert(point, duration);
+    }
+}
+
+pub(crate) fn pause(point: PausePoint) {
+    let duration = pauses().lock().unwrap().get(&point).copied();
+    if let Some(duration) = duration {
+        thread::sleep(duration);
+    }
+}
+
+/// Operations of a [`Vfs`], for selecting the calls that a [`FaultVfs`] fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VfsOp {
+    OpenLockFile,
+    Lock,
+    Unlock,
+    Rename,
+    RemoveFile,
+    RemoveDirAll,
+    CreateDirAll,
+    ReadDir,
+    SymlinkDir,
+    SymlinkFile,
+    Copy,
+    CopyChunk,
+    HardLink,
+    OpenTmpfile,
+    LinkTmpfile,
+}
+
+#[derive(Default)]
+struct FaultState {
+    calls: HashMap<VfsOp, usize>,
+    faults: HashMap<(VfsOp, usize), io::ErrorKind>,
+    delays: HashMap<VfsOp, Duration>,
+    ignore_locks: bool,
+    emulate_reflinks: bool,
+}
+
+/// A [`Vfs`] that passes calls through to another one, except for those selected with
+/// [`FaultVfs::fail`]. Install it with [`crate::ClientBuilder::vfs`].
+pub struct FaultVfs {
+    inner: Arc<dyn Vfs>,
+    state: Mutex<FaultState>,
+}
+
+impl Default for FaultVfs {
+    fn default() -> Self {
+        Self::wrap(Arc::new(StdVfs))
+    }
+}
+
+impl FaultVfs {
+    /// Injects failures into the real filesystem.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wrap(inner: Arc<dyn Vfs>) -> Self {
+        FaultVfs {
+            inner,
+            state: Mutex::new(FaultState::default()),
+        }
+    }
+
+    /// Fail the `nth` call of `op`, counting from one, with an error of `kind` instead of passing it
+    /// through. Use [`FaultVfs::calls`] to target calls relative to the ones made so far.
+    pub fn fail(&self, op: VfsOp, nth: usize, kind: io::ErrorKind) {
+        self.state.lock().unwrap().faults.insert((op, nth), kind);
+    }
+
+    /// Sleep for `delay` before passing each call of `op` through, as a slow or hung filesystem would.
+    pub fn delay(&self, op: VfsOp, delay: Duration) {
+        self.state.lock().unwrap().delays.insert(op, delay);
+    }
+
+    /// Have locks and unlocks succeed without locking anything, as on filesystems that do not enforce locks.
+    /// Calls are still counted and can still be failed.
+    pub fn ignore_locks(&self) {
+        self.state.lock().unwrap().ignore_locks = true;
+    }
+
+    fn ignores_locks(&self) -> bool {
+        self.state.lock().unwrap().ignore_locks
+    }
+
+    /// Have reflinks succeed by copying, as they would on a filesystem that supports them, so that what
+    /// relies on them can be tested on any filesystem. Calls are still counted as [`VfsOp::Copy`] and can
+    /// still be failed.
+    pub fn emulate_reflinks(&self) {
+        self.state.lock().unwrap().emulate_reflinks = true;
+    }
+
+    /// Number of calls of `op` so far, including failed ones.
+    pub fn calls(&self, op: VfsOp) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .get(&op)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn check(&self, op: VfsOp) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let calls = state.calls.entry(op).or_default();
+        *calls += 1;
+        let nth = *calls;
+        let fault = state.faults.remove(&(op, nth));
+        let delay = state.delays.get(&op).copied();
+        drop(state);
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        match fault {
+            Some(kind) => Err(io::Error::new(
+                kind,
+                format!("injected failure of {:?} call {}", op, nth),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Vfs for FaultVfs {
+    fn open_lock_file(&self, path: &Path, mode: Option<u32>) -> io::Result<File> {
+        self.check(VfsOp::OpenLockFile)?;
+        self.inner.open_lock_file(path, mode)
+    }
+
+    fn lock(&self, file: &File, mode: LockMode) -> io::Result<()> {
+        self.check(VfsOp::Lock)?;
+        if self.ignores_locks() {
+            return Ok(());
+        }
+        self.inner.lock(file, mode)
+    }
+
+    fn try_lock(&self, file: &File, mode: LockMode) -> io::Result<bool> {
+        self.check(VfsOp::Lock)?;
+        if self.ignores_locks() {
+            return Ok(true);
+        }
+        self.inner.try_lock(file, mode)
+    }
+
+    fn unlock(&self, file: &File) -> io::Result<()> {
+        self.check(VfsOp::Unlock)?;
+        if self.ignores_locks() {
+            return Ok(());
+        }
+        self.inner.unlock(file)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(VfsOp::Rename)?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check(VfsOp::RemoveFile)?;
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check(VfsOp::RemoveDirAll)?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check(VfsOp::CreateDirAll)?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.check(VfsOp::ReadDir)?;
+        self.inner.read_dir(path)
+    }
+
+    fn symlink_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.check(VfsOp::SymlinkDir)?;
+        self.inner.symlink_dir(target, link)
+    }
+
+    fn symlink_file(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.check(VfsOp::SymlinkFile)?;
+        self.inner.symlink_file(target, link)
+    }
+
+    fn reflink_or_copy(&self, from: &Path, to: &Path) -> io::Result<Option<u64>> {
+        self.check(VfsOp::Copy)?;
+        self.inner.reflink_or_copy(from, to)
+    }
+
+    fn reflink(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(VfsOp::Copy)?;
+        if self.state.lock().unwrap().emulate_reflinks {
+            return self.inner.copy(from, to).map(drop);
+        }
+        self.inner.reflink(from, to)
+    }
+
+    fn copy_chunk(&self, from: &File, to: &File, len: u64) -> io::Result<u64> {
+        self.check(VfsOp::CopyChunk)?;
+        self.inner.copy_chunk(from, to, len)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        self.check(VfsOp::Copy)?;
+        self.inner.copy(from, to)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        self.check(VfsOp::HardLink)?;
+        self.inner.hard_link(original, link)
+    }
+
+    fn open_tmpfile(&self, dir: &Path) -> io::Result<File> {
+        self.check(VfsOp::OpenTmpfile)?;
+        self.inner.open_tmpfile(dir)
+    }
+
+    fn link_tmpfile(&self, file: &File, path: &Path) -> io::Result<()> {
+        self.check(VfsOp::LinkTmpfile)?;
+        self.inner.link_tmpfile(file, path)
+    }
+}
+
+/// Lay out the entries that workers operate on in a fresh database at `root`.
+pub fn prepare<P: AsRef<Path>>(root: P) -> Result<()> {
+    let root = root.as_ref();
+    for name in FILES {
+        write_value(&root.join(file_rpath(name)), 0)?;
+    }
+    for i in 0..DIR_FILES {
+        write_value(&root.join(DIR).join(i.to_string()), 0)?;
+    }
+    Ok(())
+}
+
+fn file_rpath(name: &str) -> PathBuf {
+    Path::new(FILES_DIR).join(name)
+}
+
+fn write_value(path: &Path, value: u64) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).at("create directory", parent)?;
+    }
+    fs::write(path, value.to_string()).at("write", path)
+}
+
+fn read_value(path: &Path) -> Result<u64> {
+    let contents = fs::read_to_string(path).at("read", path)?;
+    contents.parse().map_err(|_| Error::WrongFormat {
+        path: path.to_path_buf(),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Reads the shared files, alternating between [`Client::read_file`] and [`Client::read_dir`] on their
+    /// directory.
+    Reader,
+    /// Increments the shared files one at a time with [`Client::write_file`], or all of them at once by
+    /// committing a copy of their directory.
+    Writer,
+    /// Increments all shared files in a single transaction.
+    TxWriter,
+    /// Reads every file of the directory replaced by [`Role::DirCommitter`] and checks that they agree.
+    DirReader,
+    /// Replaces a directory whose files all hold the same value with a copy holding the next value.
+    DirCommitter,
+    /// Runs gc with no minimum age.
+    Gc,
+}
+
+impl Role {
+    const ALL: [Role; 6] = [
+        Role::Reader,
+        Role::Writer,
+        Role::TxWriter,
+        Role::DirReader,
+        Role::DirCommitter,
+        Role::Gc,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Writer => "writer",
+            Role::TxWriter => "tx_writer",
+            Role::DirReader => "dir_reader",
+            Role::DirCommitter => "dir_committer",
+            Role::Gc => "gc",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| role.as_str() == s)
+    }
+}
+
+/// A critical section as seen by a worker: the time at which it held its lock on `path`, in nanoseconds
+/// since the epoch. The lock was held for at least this long.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Observation {
+    pub mode: LockMode,
+    pub path: PathBuf,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Observation {
+    /// Whether the locks held by both could not have been granted at the same time. A write lock excludes
+    /// everything at or below its path, while a read lock on a directory does not exclude writes below it.
+    pub fn conflicts_with(&self, other: &Observation) -> bool {
+        let excludes = |a: &Observation, b: &Observation| {
+            a.mode == LockMode::Write && b.path.starts_with(&a.path)
+        };
+        excludes(self, other) || excludes(other, self)
+    }
+
+    pub fn overlaps(&self, other: &Observation) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// Everything recorded by one or more workers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Outcome {
+    pub observations: Vec<Observation>,
+    /// Errors, warnings and inconsistent data seen by the workers. A correct run has none.
+    pub anomalies: Vec<String>,
+}
+
+impl Outcome {
+    /// Read the output of a worker. A missing file means the worker never finished.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).at("read", path)?;
+        let wrong_format = || Error::WrongFormat {
+            path: path.to_path_buf(),
+        };
+        let mut outcome = Outcome::default();
+        for line in contents.lines() {
+            let mut fields = line.splitn(4, '\t');
+            let mode = match fields.next() {
+                Some("anomaly") => {
+                    outcome
+                        .anomalies
+                        .push(fields.collect::<Vec<_>>().join("\t"));
+                    continue;
+                }
+                Some("read") => LockMode::Read,
+                Some("write") => LockMode::Write,
+                _ => return Err(wrong_format()),
+            };
+            let mut number = || {
+                fields
+                    .next()
+                    .and_then(|field| field.parse::<u64>().ok())
+                    .ok_or_else(wrong_format)
+            };
+            let (start, end) = (number()?, number()?);
+            let rpath = fields.next().ok_or_else(wrong_format)?;
+            outcome.observations.push(Observation {
+                mode,
+                path: PathBuf::from(rpath),
+                start,
+                end,
+            });
+        }
+        Ok(outcome)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut contents = String::new();
+        for observation in &self.observations {
+            let mode = match observation.mode {
+                LockMode::Read => "read",
+                LockMode::Write => "write",
+            };
+            let _ = writeln!(
+                contents,
+                "{}\t{}\t{}\t{}",
+                mode,
+                observation.start,
+                observation.end,
+                observation.path.display()
+            );
+        }
+        for anomaly in &self.anomalies {
+            let _ = writeln!(contents, "anomaly\t{}", anomaly.replace('\n', " "));
+        }
+        fs::write(path, contents).at("write", path)
+    }
+
+    fn observe<P: AsRef<Path>>(&mut self, mode: LockMode, rpath: P, start: u64) {
+        self.observations.push(Observation {
+            mode,
+            path: rpath.as_ref().to_path_buf(),
+            start,
+            end: now(),
+        });
+    }
+
+    pub fn merge(&mut self, other: Outcome) {
+        self.observations.extend(other.observations);
+        self.anomalies.extend(other.anomalies);
+    }
+
+    /// Pairs of critical sections that overlapped although their locks exclude each other.
+    pub fn violations(&self) -> Vec<(&Observation, &Observation)> {
+        let mut sorted = self.observations.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|observation| observation.start);
+        let mut violations = Vec::new();
+        for (i, a) in sorted.iter().enumerate() {
+            for b in &sorted[i + 1..] {
+                if b.start >= a.end {
+                    break;
+                }
+                if a.conflicts_with(b) {
+                    violations.push((*a, *b));
+                }
+            }
+        }
+        violations
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// One process of a multi-process test.
+#[derive(Clone, Debug)]
+pub struct Worker {
+    pub role: Role,
+    /// Root of a database laid out by [`prepare`].
+    pub root: PathBuf,
+    /// Where the worker writes its [`Outcome`].
+    pub output: PathBuf,
+    pub iterations: u32,
+    pub pauses: Vec<(PausePoint, Duration)>,
+    pub lock_backend: LockBackend,
+}
+
+impl Worker {
+    /// The worker configured by the environment of this process, if it was started by
+    /// [`Worker::command`].
+    pub fn from_env() -> Option<Self> {
+        let role = Role::parse(&std::env::var(WORKER_ENV).ok()?)?;
+        Some(Worker {
+            role,
+            root: std::env::var_os(ROOT_ENV)?.into(),
+            output: std::env::var_os(OUTPUT_ENV)?.into(),
+            iterations: std::env::var(ITERATIONS_ENV).ok()?.parse().ok()?,
+            // applied by the pause points themselves
+            pauses: Vec::new(),
+            lock_backend: match std::env::var(LOCK_LEASE_ENV) {
+                Ok(lease) => LockBackend::AtomicLockDir {
+                    lease: Duration::from_millis(lease.parse().ok()?),
+                },
+                Err(_) => LockBackend::Flock,
+            },
+        })
+    }
+
+    /// A command running `program` with this worker's configuration in its environment. The program is
+    /// expected to call [`Worker::from_env`] and [`Worker::run`], usually it is the test binary itself.
+    pub fn command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+        let mut pauses = String::new();
+        for (point, duration) in &self.pauses {
+            if !pauses.is_empty() {
+                pauses.push(',');
+            }
+            let _ = write!(pauses, "{}={}", point.as_str(), duration.as_millis());
+        }
+        let mut command = Command::new(program);
+        command
+            .env(WORKER_ENV, self.role.as_str())
+            .env(ROOT_ENV, &self.root)
+            .env(OUTPUT_ENV, &self.output)
+            .env(ITERATIONS_ENV, self.iterations.to_string())
+            .env(PAUSE_ENV, pauses);
+        match self.lock_backend {
+            LockBackend::Flock => command.env_remove(LOCK_LEASE_ENV),
+            LockBackend::AtomicLockDir { lease } => {
+                command.env(LOCK_LEASE_ENV, lease.as_millis().to_string())
+            }
+        };
+        command
+    }
+
+    /// Perform the role's operations and write the outcome. Failed operations are recorded as anomalies
+    /// and end the run early.
+    pub fn run(&self) -> Result<()> {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let client = {
+            let warnings = warnings.clone();
+            Client::builder(&self.root)
+                .on_warning(move |warning| warnings.lock().unwrap().push(format!("{:?}", warning)))
+                .lock_backend(self.lock_backend)
+                .build()?
+        };
+        let mut outcome = Outcome::default();
+        for i in 0..self.iterations as usize {
+            if let Err(e) = self.step(&client, i, &mut outcome) {
+                outcome
+                    .anomalies
+                    .push(format!("{:?} failed: {}", self.role, e));
+                break;
+            }
+        }
+        outcome.anomalies.extend(warnings.lock().unwrap().drain(..));
+        outcome.save(&self.output)
+    }
+
+    fn step(&self, client: &Client, i: usize, outcome: &mut Outcome) -> Result<()> {
+        let root = &self.root;
+        match self.role {
+            Role::Reader => {
+                // the directory every other time, so that its read lock races the file writers as well
+                if i.is_multiple_of(2) {
+                    let gaurd = client.read_dir(FILES_DIR)?;
+                    let start = now();
+                    for name in FILES {
+                        read_value(&gaurd.path.join(name))?;
+                    }
+                    outcome.observe(LockMode::Read, FILES_DIR, start);
+                    gaurd.release()?;
+                } else {
+                    let rpath = file_rpath(FILES[i / 2 % FILES.len()]);
+                    let gaurd = client.read_file(&rpath)?;
+                    let start = now();
+                    read_value(&gaurd.path)?;
+                    outcome.observe(LockMode::Read, &rpath, start);
+                    gaurd.release()?;
+                }
+            }
+            Role::Writer => {
+                if i % 3 == 2 {
+                    let gaurd = client.write_dir(FILES_DIR)?;
+                    let start = now();
+                    let copy = gaurd.cow()?;
+                    for name in FILES {
+                        let path = copy.path.join(name);
+                        write_value(&path, read_value(&path)? + 1)?;
+                    }
+                    copy.commit()?;
+                    outcome.observe(LockMode::Write, FILES_DIR, start);
+                    gaurd.release()?;
+                } else {
+                    let rpath = file_rpath(FILES[i % 3]);
+                    let gaurd = client.write_file(&rpath)?;
+                    let start = now();
+                    let copy = gaurd.cow()?;
+                    write_value(&copy.path, read_value(&gaurd.path)? + 1)?;
+                    copy.commit()?;
+                    outcome.observe(LockMode::Write, &rpath, start);
+                    gaurd.release()?;
+                }
+            }
+            Role::TxWriter => {
+                let rpaths = FILES.map(file_rpath);
+                let tx = rpaths
+                    .iter()
+                    .fold(client.tx(), |tx, rpath| tx.write(rpath))
+                    .begin()?;
+                let start = now();
+                for rpath in &rpaths {
+                    let copy = tx.file_cow(rpath)?;
+                    write_value(&copy.path, read_value(&root.join(rpath))? + 1)?;
+                    copy.commit()?;
+                }
+                for rpath in &rpaths {
+                    outcome.observe(LockMode::Write, rpath, start);
+                }
+                tx.release()?;
+            }
+            Role::DirReader => {
+                let gaurd = client.read_dir(DIR)?;
+                let start = now();
+                let values = (0..DIR_FILES)
+                    .map(|i| read_value(&gaurd.path.join(i.to_string())))
+                    .collect::<Result<Vec<_>>>()?;
+                if values.iter().any(|value| *value != values[0]) {
+                    outcome
+                        .anomalies
+                        .push(format!("torn directory read: {:?}", values));
+                }
+                outcome.observe(LockMode::Read, DIR, start);
+                gaurd.release()?;
+            }
+            Role::DirCommitter => {
+                let gaurd = client.write_dir(DIR)?;
+                let start = now();
+                let copy = gaurd.cow()?;
+                let next = read_value(&copy.path.join("0"))? + 1;
+                for i in 0..DIR_FILES {
+                    write_value(&copy.path.join(i.to_string()), next)?;
+                }
+                copy.commit()?;
+                outcome.observe(LockMode::Write, DIR, start);
+                gaurd.release()?;
+            }
+            Role::Gc => {
+                let report = client.gc_with(&GcOptions::default());
+                if report.errors > 0 {
+                    outcome.anomalies.push(format!("gc failed: {:?}", report));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Thin layer over `tracing` so that instrumentation compiles to nothing without the `tracing` feature.
+//! Paths recorded here are always relative to the database root, or hashed, see
+//! [`crate::ClientBuilder::redact_paths`].
+
+use std::{path::Path, time::Duration};
+
+use crate::{CommitKind, LockMode, PathDisplay};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn tx_span(reads: usize, writes: usize) -> Span {
+    tracing::info_span!(
+        "sbdb.tx",
+        reads,
+        writes,
+        lock_wait_us = tracing::field::Empty
+    )
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn copy_span(parent: &Span) -> Span {
+    tracing::debug_span!(
+        parent: parent,
+        "sbdb.copy",
+        files = tracing::field::Empty,
+        bytes = tracing::field::Empty
+    )
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn commit_span(parent: &Span, kind: CommitKind) -> Span {
+    tracing::info_span!(parent: parent, "sbdb.commit", kind = ?kind, outcome = tracing::field::Empty)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn lock_acquired(rpath: &Path, redact: PathDisplay, mode: LockMode, wait: Duration) {
+    tracing::debug!(
+        path = %crate::redact::DisplayPath::relative(rpath, redact),
+        mode = ?mode,
+        wait_us = wait.as_micros() as u64,
+        "lock acquired"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn current() -> Self {
+        Span
+    }
+
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+
+    pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn tx_span(_reads: usize, _writes: usize) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn copy_span(_parent: &Span) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn commit_span(_parent: &Span, _kind: CommitKind) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn lock_acquired(_rpath: &Path, _redact: PathDisplay, _mode: LockMode, _wait: Duration) {
+}
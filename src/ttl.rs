@@ -0,0 +1,140 @@
+//! Entries that expire on their own. The expiry of an entry is kept next to it in a sidecar file,
+//! `.name.ttl.sbdb`, as milliseconds since the epoch, and gc removes entries whose expiry has passed when
+//! asked to with [`crate::GcOptions::expire_ttl`].
+
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    Client, Ctx, DirWriteGaurd, Error, ReplicatedOp, Result, artifact, error::IoResultExt,
+    file_replace_with, path_hidden_with_extension,
+};
+
+impl Client {
+    /// Atomically replace the file at `rpath` with `bytes` like [`Client::write_bytes`], and have it removed
+    /// by gc once `ttl` has passed. The expiry is written first, so should the write fail the entry may be
+    /// left with the new expiry. Writing the file some other way keeps its expiry, [`Client::persist`]
+    /// removes it.
+    pub fn write_with_ttl<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        bytes: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            write_expiry(&gaurd.path, SystemTime::now() + ttl)?;
+            file_replace_with(&gaurd.path, bytes, false, gaurd.ctx.clone())
+        })
+    }
+
+    /// Time left until the entry at `rpath` expires, zero once it expired but was not removed yet. `None` if
+    /// it does not expire.
+    pub fn ttl<P: AsRef<Path>>(&self, rpath: P) -> Result<Option<Duration>> {
+        self.redacted(|| {
+            let gaurd = self.read_file(rpath)?;
+            Ok(read_expiry(&sidecar(&gaurd.path)?)?.map(remaining))
+        })
+    }
+
+    /// Keep the entry at `rpath` until it is removed, returning whether it had an expiry.
+    pub fn persist<P: AsRef<Path>>(&self, rpath: P) -> Result<bool> {
+        self.redacted(|| {
+            let gaurd = self.write_file(rpath)?;
+            let sidecar = sidecar(&gaurd.path)?;
+            match fs::remove_file(&sidecar) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(Error::io("remove expiry", &sidecar, e)),
+            }
+        })
+    }
+
+    /// Remove the expired entry `name` of the directory that gc holds `dir`'s write lock on, together with
+    /// its expiry, metadata and lock files. Takes the entry's own write lock first, which no one else can
+    /// hold while the directory is write locked. Returns how many lock files were removed.
+    pub(crate) fn expire(&self, dir: &DirWriteGaurd, name: &OsStr) -> Result<usize> {
+        let gaurd = dir.write_file(name)?;
+        let path = &gaurd.path;
+        gaurd.ctx.check_writable(path)?;
+        let bytes = crate::quota::data_size(path);
+        let before = gaurd.ctx.usage_before(path);
+        let removed = match fs::symlink_metadata(path) {
+            // links to directories are removed without following them
+            Ok(metadata) if metadata.is_dir() || metadata.is_symlink() => {
+                self.inner.vfs.remove_dir_all(path)
+            }
+            Ok(_) => self.inner.vfs.remove_file(path),
+            Err(_) => Ok(()),
+        };
+        removed.at("remove expired", path)?;
+        gaurd.ctx.release_quota(bytes);
+        gaurd.ctx.replicate(ReplicatedOp::Remove, path);
+        gaurd.ctx.roll_up(path, before);
+        let sidecar = sidecar(path)?;
+        self.inner
+            .vfs
+            .remove_file(&sidecar)
+            .at("remove expiry", &sidecar)?;
+        crate::entry_meta::remove(path)?;
+        let path = path.clone();
+        gaurd.release()?;
+
+        if let Some(cache) = &self.inner.lock_cache {
+            cache.evict(&path);
+        }
+        let mut removed = 0;
+        for ext in [artifact::LOCK, artifact::QUEUE] {
+            let lock = path_hidden_with_extension(&path, ext)?;
+            match self.inner.vfs.remove_file(&lock) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::io("remove", &lock, e)),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Path of the file holding the expiry of the entry at `path`.
+pub(crate) fn sidecar(path: &Path) -> Result<std::path::PathBuf> {
+    path_hidden_with_extension(path, artifact::TTL)
+}
+
+/// The expiry held by the sidecar file at `path`, `None` if there is none.
+pub(crate) fn read_expiry(path: &Path) -> Result<Option<SystemTime>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io("read expiry", path, e)),
+    };
+    let millis: u64 = text.trim().parse().map_err(|e| Error::Decode {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+    })?;
+    Ok(Some(UNIX_EPOCH + Duration::from_millis(millis)))
+}
+
+/// Sidecars are not commits of the entry, so they are written without the client's versioning, generations
+/// and quota.
+fn write_expiry(path: &Path, expires: SystemTime) -> Result<()> {
+    let millis = expires
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    file_replace_with(
+        &sidecar(path)?,
+        millis.to_string().as_bytes(),
+        false,
+        Ctx::detached(),
+    )
+}
+
+pub(crate) fn remaining(expires: SystemTime) -> Duration {
+    expires
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO)
+}
@@ -0,0 +1,470 @@
+//! Transactions, which lock several entries at once, see [`Client::tx`]. The entries are locked in sorted
+//! order, so that transactions that share entries can not deadlock.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    AtomicCowOptions, CowAtomicDirGaurd, CowDirGaurd, CowFileGaurd, Ctx, Error, FileReadGaurd,
+    FileWriteGaurd, Lock, ReadLock, Result, WriteLock, check_lock_order, check_locked,
+    check_unreserved, covering_write_lock, create_read_file_locks, create_write_file_locks,
+    dir_cow_atomic_with, dir_cow_with, error::IoResultExt, file_cow_with, generation,
+    lock_write_file, normalize_rpath, release_all, trace,
+};
+
+pub enum TxEntryKind {
+    Read,
+    Write,
+}
+
+pub struct TxEntry {
+    kind: TxEntryKind,
+    path: PathBuf,
+}
+
+/// Declares the entries a transaction reads and writes, which [`TxBuilder::begin`] locks all at once.
+///
+/// Entries are locked by their logical paths, the ones they are declared with, and links are never resolved
+/// to decide what to lock. Every declared entry has its ancestors read locked, and the locks are taken sorted
+/// by path component by component, so an ancestor is always locked before the entries below it and every
+/// transaction takes the locks it shares with another one in the same order. Since the ancestors are found
+/// from the path alone, a symbolic link leading back up can not make the inference loop.
+///
+/// The payload of an atomic directory is covered by the lock of the directory's link: every commit that
+/// replaces the payload holds the write lock of the link's path, which waits for the transactions reading or
+/// writing anything below it, and the lock files of the entries below it move along with the payload. The
+/// payload can not be named directly, see [`TxBuilder::read`]. An alias is locked under its own path, not
+/// its target's, so a transaction declaring an entry through an alias does not exclude one declaring it
+/// through the target, see [`Client::set_alias`](crate::Client::set_alias).
+pub struct TxBuilder {
+    root: PathBuf,
+    pub(crate) reads: HashSet<PathBuf>,
+    writes: HashSet<PathBuf>,
+    /// Reads declared with [`TxBuilder::read`], without the ancestors that `reads` adds for them.
+    declared_reads: HashSet<PathBuf>,
+    /// First path that could not be declared, reported by [`TxBuilder::begin`].
+    invalid: Option<Error>,
+    escalate_above: Option<usize>,
+    /// Generations that declared entries had when a transaction was checkpointed, see [`Tx::checkpoint`].
+    expected: Vec<(PathBuf, u64)>,
+    pub(crate) ctx: Ctx,
+}
+
+impl TxBuilder {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            reads: HashSet::new(),
+            writes: HashSet::new(),
+            declared_reads: HashSet::new(),
+            invalid: None,
+            escalate_above: None,
+            expected: Vec::new(),
+            ctx: Ctx::detached(),
+        }
+    }
+
+    /// Write lock a directory instead of the entries in it when more than `n` of them are declared as
+    /// writes, which takes one lock rather than `n` and opens two lock files rather than `2 * n`. The
+    /// transaction may then write anything in the directory, and keeps everyone else from reading or writing
+    /// any of it, including entries it never declared, until it is done. [`Tx::stats`] tells which
+    /// directories were locked instead.
+    pub fn escalate_above(mut self, n: usize) -> Self {
+        self.escalate_above = Some(n);
+        self
+    }
+
+    /// Declare a read of the entry at `path`, which does nothing if it is declared as a write already. Like
+    /// a write, it must not be named like one of sbdb's own files, which would let it read the payload of an
+    /// atomic directory without the lock that covers it.
+    pub fn read<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let Some(path) = self.normalize(path.as_ref()) else {
+            return self;
+        };
+        if let Err(e) = check_unreserved(&self.root, &path) {
+            self.invalid.get_or_insert(e);
+            return self;
+        }
+        if self.writes.contains(&path) {
+            return self;
+        }
+        for anscestor in path.ancestors() {
+            self.reads.insert(anscestor.to_path_buf());
+        }
+        self.declared_reads.insert(path);
+        self
+    }
+
+    /// Declare a write to the entry at `path`, which must not be named like one of sbdb's own files. A read
+    /// of the same entry declared before is dropped, the write lock covers it. The entry need not exist,
+    /// missing directories above it are created when the transaction begins, see [`Tx::dir_cow`].
+    pub fn write<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let Some(path) = self.normalize(path.as_ref()) else {
+            return self;
+        };
+        if let Err(e) = check_unreserved(&self.root, &path) {
+            self.invalid.get_or_insert(e);
+            return self;
+        }
+        for anscestor in path.ancestors().skip(1) {
+            self.reads.insert(anscestor.to_path_buf());
+        }
+        self.reads.remove(&path);
+        self.declared_reads.remove(&path);
+        self.writes.insert(path);
+        self
+    }
+
+    fn normalize(&mut self, path: &Path) -> Option<PathBuf> {
+        normalize_rpath(path)
+            .map_err(|e| {
+                self.invalid.get_or_insert(e);
+            })
+            .ok()
+    }
+
+    pub fn begin(self) -> Result<Tx> {
+        self.begin_with(true)
+    }
+
+    /// Like [`TxBuilder::begin`], without waiting: fails with [`Error::WouldBlock`] naming the first entry
+    /// whose lock is held elsewhere, once the locks taken before it are released again. See
+    /// [`Client::try_tx`](crate::Client::try_tx) for retrying.
+    pub fn try_begin(self) -> Result<Tx> {
+        self.begin_with(false)
+    }
+
+    fn begin_with(mut self, wait: bool) -> Result<Tx> {
+        if let Some(e) = self.invalid.take() {
+            return Err(e);
+        }
+        let declared = Declared {
+            reads: self.reads.clone(),
+            declared_reads: std::mem::take(&mut self.declared_reads),
+            writes: self.writes.clone(),
+            escalate_above: self.escalate_above,
+        };
+        let mut escalated = Vec::new();
+        if let Some(n) = self.escalate_above {
+            let mut siblings: HashMap<&Path, usize> = HashMap::new();
+            for write in self.writes.iter() {
+                if let Some(parent) = write.parent() {
+                    *siblings.entry(parent).or_default() += 1;
+                }
+            }
+            escalated.extend(
+                siblings
+                    .into_iter()
+                    .filter(|(_, count)| *count > n)
+                    .map(|(parent, _)| parent.to_path_buf()),
+            );
+            escalated.sort();
+            // the entries are dropped below, along with any other write the directory covers
+            self.writes.extend(escalated.iter().cloned());
+        }
+        let mut remove_writes = Vec::new();
+        for write in self.writes.iter() {
+            for anscestor in write.ancestors().skip(1) {
+                if self.writes.contains(anscestor) {
+                    remove_writes.push(write.clone());
+                    break;
+                }
+            }
+        }
+        for remove in remove_writes {
+            self.writes.remove(&remove);
+        }
+
+        // a path that is written, or lies below one that is, is only write locked, taking both locks of
+        // the same file would have this process wait for itself
+        self.reads.retain(|p| {
+            p.ancestors()
+                .all(|anscestor| !self.writes.contains(anscestor))
+        });
+
+        let mut entries = Vec::new();
+
+        let reads: Vec<PathBuf> = self.reads.into_iter().collect();
+        for path in reads.iter() {
+            entries.push(TxEntry {
+                kind: TxEntryKind::Read,
+                path: path.clone(),
+            });
+        }
+        let writes: Vec<PathBuf> = self.writes.into_iter().collect();
+        for path in writes.iter() {
+            entries.push(TxEntry {
+                kind: TxEntryKind::Write,
+                path: path.clone(),
+            });
+        }
+
+        entries.sort_by(|e1, e2| e1.path.cmp(&e2.path));
+
+        // the lock files of a new entry are created in its parent, before any of the transaction's locks
+        // are taken
+        for write in writes.iter() {
+            create_missing_parents(&self.root, write, wait, &self.ctx)?;
+        }
+
+        let span = trace::tx_span(entries.len() - writes.len(), writes.len());
+        let mut lock = Vec::with_capacity(entries.len());
+
+        {
+            let _enter = span.enter();
+            let start = Instant::now();
+            for e in entries {
+                check_lock_order(&self.root, &e.path)?;
+                lock.push(match e.kind {
+                    TxEntryKind::Read => {
+                        Lock::Read(ReadLock::lock(&self.root, &e.path, wait, &self.ctx)?)
+                    }
+                    TxEntryKind::Write => {
+                        Lock::Write(WriteLock::lock(&self.root, &e.path, wait, &self.ctx)?)
+                    }
+                });
+            }
+            span.record("lock_wait_us", start.elapsed().as_micros() as u64);
+        }
+
+        lock.reverse();
+
+        for (rpath, expected) in self.expected.iter() {
+            if generation::generation(&self.root, rpath)? != *expected {
+                release_all(lock)?;
+                return Err(Error::Conflict {
+                    path: self.root.join(rpath),
+                });
+            }
+        }
+
+        let stats = TxStats {
+            read_locks: reads.len(),
+            write_locks: writes.len(),
+            escalated,
+        };
+        Ok(Tx {
+            root: self.root,
+            reads,
+            writes,
+            lock,
+            stats,
+            declared,
+            ctx: Ctx { span, ..self.ctx },
+        })
+    }
+}
+
+/// What was declared on the [`TxBuilder`] of a transaction, to declare it again in [`Tx::checkpoint`].
+struct Declared {
+    reads: HashSet<PathBuf>,
+    declared_reads: HashSet<PathBuf>,
+    writes: HashSet<PathBuf>,
+    escalate_above: Option<usize>,
+}
+
+/// Locks of a transaction, released when it is dropped.
+///
+/// A transaction, like every guard, is [`Send`] and [`Sync`]: it may be begun on one thread and used,
+/// committed or dropped on another. Locks belong to the process and the open lock file rather than to
+/// the thread that took them, so they are released correctly wherever the last owner drops them.
+pub struct Tx {
+    pub(crate) root: PathBuf,
+    pub(crate) reads: Vec<PathBuf>,
+    pub(crate) writes: Vec<PathBuf>,
+    lock: Vec<Lock>,
+    stats: TxStats,
+    declared: Declared,
+    pub(crate) ctx: Ctx,
+}
+
+/// Locks that a transaction took, see [`Tx::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TxStats {
+    pub read_locks: usize,
+    pub write_locks: usize,
+    /// Directories that were write locked in place of the entries declared in them, relative to the root
+    /// and sorted, see [`TxBuilder::escalate_above`].
+    pub escalated: Vec<PathBuf>,
+}
+
+impl Tx {
+    pub fn stats(&self) -> &TxStats {
+        &self.stats
+    }
+
+    pub fn file_cow<P: AsRef<Path>>(&self, orig: P) -> Result<CowFileGaurd> {
+        let orig = normalize_rpath(orig.as_ref())?;
+        self.check_write(&orig)?;
+        let path = self.root.join(orig);
+        check_locked(&path)?;
+        file_cow_with(path, self.ctx.clone())
+    }
+
+    /// Copy the directory at `orig`, or start an empty one that creates it on commit if nothing exists
+    /// there, so that a new directory appears with all of its contents at once.
+    pub fn dir_cow<P: AsRef<Path>>(&self, orig: P) -> Result<CowDirGaurd> {
+        let orig = normalize_rpath(orig.as_ref())?;
+        self.check_write(&orig)?;
+        let path = self.root.join(orig);
+        check_locked(&path)?;
+        let lock = covering_write_lock(&self.lock, &path);
+        dir_cow_with(path, lock, self.ctx.clone())
+    }
+
+    /// Copy the atomic directory at `orig`, or start an empty one if nothing exists there. A plain directory
+    /// at `orig` is [`Error::NotAtomicDir`], see [`Tx::dir_cow_atomic_with`] to convert it instead.
+    pub fn dir_cow_atomic<P: AsRef<Path>>(&self, orig: P) -> Result<CowAtomicDirGaurd> {
+        self.dir_cow_atomic_with(orig, &AtomicCowOptions::default())
+    }
+
+    /// See [`Tx::dir_cow_atomic`]. A plain directory at `orig` is copied if `options.convert_if_plain` is
+    /// set, and committing the copy converts it in the same way as
+    /// [`DirWriteGaurd::create_dir_atomic`](crate::DirWriteGaurd::create_dir_atomic) does: the plain
+    /// directory is moved aside as a backup, which is put back if the link can not be switched and is left
+    /// for [`Client::recover`](crate::Client::recover) if the process dies in between.
+    pub fn dir_cow_atomic_with<P: AsRef<Path>>(
+        &self,
+        orig: P,
+        options: &AtomicCowOptions,
+    ) -> Result<CowAtomicDirGaurd> {
+        let orig = normalize_rpath(orig.as_ref())?;
+        self.check_write(&orig)?;
+        let path = self.root.join(orig);
+        check_locked(&path)?;
+        if !options.convert_if_plain && fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+            return Err(Error::NotAtomicDir { path });
+        }
+        dir_cow_atomic_with(path, self.ctx.clone())
+    }
+
+    /// Read the file at `rpath`, which must have been declared for reading or lie below a declared write.
+    /// Only the locks below the deepest one the transaction already holds are acquired, the returned guard
+    /// relies on the transaction's locks and must not outlive it.
+    pub fn read_file<P: AsRef<Path>>(&self, rpath: P) -> Result<FileReadGaurd> {
+        let rpath = &normalize_rpath(rpath.as_ref())?;
+        self.check_read(rpath)?;
+        let held = match self.writes.iter().find(|write| rpath.starts_with(write)) {
+            Some(write) => write.as_path(),
+            None => rpath,
+        };
+        let lock = create_read_file_locks(&self.root, rpath, Some(held), &self.ctx)?;
+        Ok(FileReadGaurd {
+            path: self.root.join(rpath),
+            lock,
+            shared: Vec::new(),
+        })
+    }
+
+    /// Write the file at `rpath`, which must be a declared write or lie below one, see [`Tx::read_file`].
+    pub fn write_file<P: AsRef<Path>>(&self, rpath: P) -> Result<FileWriteGaurd> {
+        let rpath = &normalize_rpath(rpath.as_ref())?;
+        check_unreserved(&self.root, rpath)?;
+        self.check_write(rpath)?;
+        let held = self
+            .writes
+            .iter()
+            .find(|write| rpath.starts_with(write))
+            .map(PathBuf::as_path);
+        let lock = create_write_file_locks(&self.root, rpath, held, &self.ctx)?;
+        Ok(FileWriteGaurd {
+            path: self.root.join(rpath),
+            lock,
+            ctx: self.ctx.clone(),
+        })
+    }
+
+    /// Release all of the transaction's locks, reporting the first failure. Dropping the transaction also
+    /// releases them, but failures are then only reported as [`Warning::Unlock`](crate::Warning::Unlock).
+    pub fn release(self) -> Result<()> {
+        release_all(self.lock)
+    }
+
+    /// Release all of the transaction's locks, without committing anything, and return a builder with the
+    /// same declarations, so that a slow phase that touches no files does not keep everyone else waiting.
+    /// Beginning the returned builder takes the locks again and fails with [`Error::Conflict`] if an entry
+    /// that was declared changed while they were released.
+    ///
+    /// Changes are detected with generation counters, see
+    /// [`Client::subtree_generation`](crate::Client::subtree_generation), so only commits by clients that
+    /// [track generations](crate::ClientBuilder::track_generations) are noticed. Declarations can not be
+    /// added to the returned builder, a transaction that needs more locks has to begin anew.
+    pub fn checkpoint(self) -> Result<TxBuilder> {
+        let mut expected = Vec::new();
+        for rpath in self
+            .declared
+            .declared_reads
+            .iter()
+            .chain(self.declared.writes.iter())
+        {
+            expected.push((rpath.clone(), generation::generation(&self.root, rpath)?));
+        }
+        release_all(self.lock)?;
+        Ok(TxBuilder {
+            root: self.root,
+            reads: self.declared.reads,
+            writes: self.declared.writes,
+            declared_reads: self.declared.declared_reads,
+            invalid: None,
+            escalate_above: self.declared.escalate_above,
+            expected,
+            ctx: Ctx {
+                span: trace::Span::current(),
+                ..self.ctx
+            },
+        })
+    }
+
+    pub(crate) fn check_read(&self, rpath: &Path) -> Result<()> {
+        if self.reads.iter().any(|read| rpath == read)
+            || self.writes.iter().any(|write| rpath.starts_with(write))
+        {
+            Ok(())
+        } else {
+            Err(Error::UndeclaredRead {
+                path: self.root.join(rpath),
+            })
+        }
+    }
+
+    pub(crate) fn check_write(&self, rpath: &Path) -> Result<()> {
+        if self.writes.iter().any(|write| rpath.starts_with(write)) {
+            Ok(())
+        } else {
+            Err(Error::UndeclaredWrite {
+                path: self.root.join(rpath),
+            })
+        }
+    }
+}
+
+/// Create the missing directories above the entry at `rpath`, under the write lock of the topmost one, so
+/// that the entry can be locked and created. Directories created for a transaction that commits nothing
+/// are left behind empty. Fails with [`Error::WouldBlock`] rather than waiting for the lock unless `wait`.
+fn create_missing_parents(root: &Path, rpath: &Path, wait: bool, ctx: &Ctx) -> Result<()> {
+    let Some(parent) = rpath.parent() else {
+        return Ok(());
+    };
+    let Some(topmost) = parent
+        .ancestors()
+        .filter(|anc| !anc.as_os_str().is_empty())
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .find(|anc| fs::symlink_metadata(root.join(anc)).is_err())
+    else {
+        return Ok(());
+    };
+    let path = root.join(parent);
+    ctx.check_writable(&path)?;
+    let lock = lock_write_file(root, topmost, None, wait, ctx)?;
+    ctx.vfs
+        .create_dir_all(&path)
+        .at("create directory", &path)?;
+    release_all(lock)
+}
@@ -0,0 +1,26 @@
+//! Copy-on-write copies of arbitrary paths, made without taking or checking any lock. Whoever calls these
+//! has to keep others from writing the original some other way, which is what the guards of a
+//! [`crate::Client`] are for.
+//!
+//! The functions of the same names without the `_unchecked` suffix at the root of the crate make the same
+//! copies. With the `strict-locking` feature they check that this process holds a write lock covering the
+//! original first, as do the copy-on-write helpers of guards and transactions.
+
+use std::path::Path;
+
+use crate::{
+    CowAtomicDirGaurd, CowDirGaurd, CowFileGaurd, Ctx, Result, dir_cow_atomic_with, dir_cow_with,
+    file_cow_with,
+};
+
+pub fn file_cow_unchecked<P: AsRef<Path>>(orig: P) -> Result<CowFileGaurd> {
+    file_cow_with(orig, Ctx::detached())
+}
+
+pub fn dir_cow_unchecked<P: AsRef<Path>>(orig: P) -> Result<CowDirGaurd> {
+    dir_cow_with(orig, None, Ctx::detached())
+}
+
+pub fn dir_cow_atomic_unchecked<P: AsRef<Path>>(current: P) -> Result<CowAtomicDirGaurd> {
+    dir_cow_atomic_with(current, Ctx::detached())
+}
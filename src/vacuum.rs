@@ -0,0 +1,185 @@
+//! Removing backups by age, count and size, see [`Client::vacuum_backups`]. Without a retention policy gc
+//! removes all the backups of entries that exist, once they are older than its minimum age, and keeps those
+//! of entries that do not for recovery. With one it leaves backups to the policy, which is then what bounds
+//! how many of them pile up.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    ArtifactKind, Client, INTERNAL_DIR, Result, artifact, check_depth, error::IoResultExt,
+    is_atomic_dir_link, normalize_rpath, quota,
+};
+
+/// Which backups of each entry [`Client::vacuum_backups`] keeps. Every limit that is set removes the
+/// backups it does not keep, nothing is removed without any.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RetentionPolicy {
+    /// Keep this many of the most recent backups.
+    pub keep_last: Option<usize>,
+    /// Keep backups modified less than this long ago.
+    pub keep_younger_than: Option<Duration>,
+    /// Keep the most recent backups for as long as their total size stays within this many bytes, so that
+    /// the oldest are removed first.
+    pub max_bytes: Option<u64>,
+}
+
+/// What [`Client::vacuum_backups`] removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VacuumReport {
+    pub dirs_scanned: usize,
+    pub backups_kept: usize,
+    /// Backups that were removed, relative to the root.
+    pub removed: Vec<PathBuf>,
+    pub bytes_freed: u64,
+}
+
+/// Backups in a directory by the name of the entry they were made of.
+type Backups = BTreeMap<OsString, Vec<Backup>>;
+
+/// A backup found next to the entry it was made of.
+struct Backup {
+    name: OsString,
+    modified: SystemTime,
+    bytes: u64,
+}
+
+impl Client {
+    /// Remove the backups below `rpath` that `policy` does not keep, which is applied to the backups of each
+    /// entry separately, newest first. Backups are found by their names, see [`crate::artifact`], and the
+    /// most recent backup of an entry that does not exist is always kept, since it is what
+    /// [`Client::recover`] would restore. The backups of an entry are removed while it is write locked, so
+    /// that they are not removed from under a commit or restore that is using them.
+    pub fn vacuum_backups<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        policy: &RetentionPolicy,
+    ) -> Result<VacuumReport> {
+        self.redacted(|| self.vacuum(rpath.as_ref(), policy, false))
+    }
+
+    /// See [`Client::vacuum_backups`], only reporting what would be removed if `dry_run`.
+    pub(crate) fn vacuum(
+        &self,
+        rpath: &Path,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<VacuumReport> {
+        let mut report = VacuumReport::default();
+        let now = SystemTime::now();
+        let mut pending = vec![normalize_rpath(rpath)?];
+        while let Some(rpath) = pending.pop() {
+            let dir = self.inner.root.join(&rpath);
+            check_depth(&dir, rpath.components().count(), self.inner.max_depth)?;
+            let (backups, children) = {
+                let gaurd = self.read_dir(&rpath)?;
+                list_backups(&gaurd.path, rpath.as_os_str().is_empty())?
+            };
+            report.dirs_scanned += 1;
+            pending.extend(children.into_iter().map(|name| rpath.join(name)));
+
+            for (orig_name, mut backups) in backups {
+                backups.sort_by_key(|backup| std::cmp::Reverse(backup.modified));
+                let orig_exists = fs::symlink_metadata(dir.join(&orig_name)).is_ok();
+                let expired = expired(&backups, policy, now, orig_exists);
+                report.backups_kept += backups.len() - expired.len();
+                if expired.is_empty() {
+                    continue;
+                }
+                let gaurd = match dry_run {
+                    true => None,
+                    false => Some(self.write_file(rpath.join(&orig_name))?),
+                };
+                for backup in expired.into_iter().map(|i| &backups[i]) {
+                    let path = dir.join(&backup.name);
+                    if let Some(gaurd) = &gaurd {
+                        let Ok(metadata) = fs::symlink_metadata(&path) else {
+                            // restored or removed since the directory was listed
+                            continue;
+                        };
+                        gaurd.ctx.check_writable(&path)?;
+                        match metadata.is_dir() {
+                            true => self.inner.vfs.remove_dir_all(&path),
+                            false => self.inner.vfs.remove_file(&path),
+                        }
+                        .at("remove", &path)?;
+                    }
+                    report.removed.push(rpath.join(&backup.name));
+                    report.bytes_freed += backup.bytes;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The backups in `dir` and the directories to descend into. `dir` holds the internal directory if it is
+/// the `root`.
+fn list_backups(dir: &Path, root: bool) -> Result<(Backups, Vec<OsString>)> {
+    let mut backups = Backups::new();
+    let mut children = Vec::new();
+    for entry in fs::read_dir(dir).at("read directory", dir)? {
+        let entry = entry.at("read directory", dir)?;
+        let name = entry.file_name();
+        if root && name == INTERNAL_DIR {
+            continue;
+        }
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path).at("read metadata of", &path)?;
+        let Some((kind, orig_name)) = artifact::parse(&name) else {
+            // only atomic dirs are followed, anything else could lead outside of the database
+            if metadata.is_dir()
+                || (metadata.is_symlink() && is_atomic_dir_link(&path)? && path.is_dir())
+            {
+                children.push(name);
+            }
+            continue;
+        };
+        // named like a backup by someone else, reported by verify
+        if kind != ArtifactKind::Backup || !artifact::is_own(kind, &metadata) {
+            continue;
+        }
+        backups.entry(orig_name).or_default().push(Backup {
+            name,
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            bytes: quota::data_size(&path),
+        });
+    }
+    Ok((backups, children))
+}
+
+/// Indices of the `backups` of one entry, sorted newest first, that `policy` does not keep.
+fn expired(
+    backups: &[Backup],
+    policy: &RetentionPolicy,
+    now: SystemTime,
+    orig_exists: bool,
+) -> Vec<usize> {
+    let mut expired = Vec::new();
+    let mut bytes = 0u64;
+    let mut full = false;
+    for (i, backup) in backups.iter().enumerate() {
+        if i == 0 && !orig_exists {
+            continue;
+        }
+        let age = now.duration_since(backup.modified).unwrap_or_default();
+        let keep = policy.keep_last.is_none_or(|n| i < n)
+            && policy.keep_younger_than.is_none_or(|max| age < max)
+            && !full;
+        if keep && let Some(max) = policy.max_bytes {
+            bytes += backup.bytes;
+            full = bytes > max;
+        }
+        if !keep || full {
+            expired.push(i);
+        }
+    }
+    expired
+}
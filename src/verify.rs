@@ -0,0 +1,224 @@
+//! Structural checks of a database, for detecting interrupted operations and modifications made without
+//! going through sbdb.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    Client, Error, INTERNAL_DIR, Result,
+    artifact::{self, ArtifactKind},
+    error::IoResultExt,
+    is_atomic_dir_link, is_older_than,
+};
+
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct VerifyOptions {
+    /// Artifacts modified more recently than this are assumed to belong to operations still in progress
+    /// and are not reported.
+    pub min_age: Duration,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+#[non_exhaustive]
+pub struct VerifyReport {
+    pub dirs_scanned: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    /// Whether any issue can not be fixed by [`Client::gc`].
+    pub fn needs_attention(&self) -> bool {
+        self.issues.iter().any(|issue| !issue.repairable)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize)
+)]
+pub struct Issue {
+    /// Path of the offending entry relative to the database root.
+    pub path: PathBuf,
+    pub kind: IssueKind,
+    /// Whether [`Client::gc`] removes the entry. Anything else needs a human to look at it.
+    pub repairable: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    any(feature = "serde_json", feature = "binary"),
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum IssueKind {
+    /// An atomic directory link whose payload does not exist, see [`crate::Client::repair`].
+    DanglingAtomicDir,
+    /// An atomic directory payload that its link does not point to.
+    OrphanedPayload,
+    /// The copy of an operation that never completed.
+    LeftoverTemp,
+    /// The backup of a directory commit. Without its original it is the only copy of the data.
+    LeftoverBackup { original_exists: bool },
+    /// A lock or queue file whose original does not exist.
+    OrphanedLock,
+    /// A generation counter or rollup whose original does not exist.
+    OrphanedCounter,
+    /// An entry whose name is reserved for sbdb's own files but that sbdb did not create, such as a lock
+    /// file with contents.
+    SuffixCollision,
+}
+
+impl Client {
+    /// Walk the subtree at `rpath` and report anything left behind by interrupted operations or made by
+    /// other tools. Each directory is read locked while it is inspected, so the walk can run alongside
+    /// other clients.
+    pub fn verify<P: AsRef<Path>>(
+        &self,
+        rpath: P,
+        options: &VerifyOptions,
+    ) -> Result<VerifyReport> {
+        self.redacted(|| {
+            let mut report = VerifyReport::default();
+            let mut pending = vec![rpath.as_ref().to_path_buf()];
+            while let Some(rpath) = pending.pop() {
+                pending.extend(self.verify_dir(&rpath, options, &mut report)?);
+            }
+            Ok(report)
+        })
+    }
+
+    fn verify_dir(
+        &self,
+        rpath: &Path,
+        options: &VerifyOptions,
+        report: &mut VerifyReport,
+    ) -> Result<Vec<PathBuf>> {
+        let gaurd = self.read_dir(rpath)?;
+        let path = &gaurd.path;
+        report.dirs_scanned += 1;
+
+        let mut children = Vec::new();
+        for entry in fs::read_dir(path).at("read directory", path)? {
+            let entry = entry.at("read directory", path)?;
+            let name = entry.file_name();
+            let child_path = entry.path();
+            let metadata = entry.metadata().at("read metadata of", &child_path)?;
+            let file_type = metadata.file_type();
+            if rpath.as_os_str().is_empty() && name == INTERNAL_DIR {
+                continue;
+            }
+            let mut issue = |kind, repairable| {
+                report.issues.push(Issue {
+                    path: rpath.join(&name),
+                    kind,
+                    repairable,
+                })
+            };
+
+            let Some((kind, orig_name)) = artifact::parse(&name) else {
+                if name.to_string_lossy().ends_with(".sbdb") {
+                    issue(IssueKind::SuffixCollision, false);
+                } else if file_type.is_dir() {
+                    children.push(rpath.join(&name));
+                } else if file_type.is_symlink() && is_atomic_dir_link(&child_path)? {
+                    if child_path.is_dir() {
+                        children.push(rpath.join(&name));
+                    } else {
+                        issue(IssueKind::DanglingAtomicDir, false);
+                    }
+                }
+                continue;
+            };
+
+            let orig_path = path.join(&orig_name);
+            let orig_exists = fs::symlink_metadata(&orig_path).is_ok();
+            if !artifact::is_own(kind, &metadata) {
+                issue(IssueKind::SuffixCollision, false);
+                continue;
+            }
+            if !is_older_than(&child_path, options.min_age) {
+                continue;
+            }
+            match kind {
+                ArtifactKind::Lock | ArtifactKind::Queue if !orig_exists => {
+                    issue(IssueKind::OrphanedLock, true)
+                }
+                ArtifactKind::Generation | ArtifactKind::Rollup if !orig_exists => {
+                    issue(IssueKind::OrphanedCounter, true)
+                }
+                ArtifactKind::Tmp | ArtifactKind::TmpLink => issue(IssueKind::LeftoverTemp, true),
+                ArtifactKind::Backup => issue(
+                    IssueKind::LeftoverBackup {
+                        original_exists: orig_exists,
+                    },
+                    orig_exists,
+                ),
+                ArtifactKind::AtomicDir => {
+                    let linked =
+                        fs::read_link(&orig_path).is_ok_and(|target| target == Path::new(&name));
+                    if !linked {
+                        issue(IssueKind::OrphanedPayload, true);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(children)
+    }
+}
+
+impl Client {
+    /// Backups of directory commits without their original, and atomic directories without their payload,
+    /// see [`crate::OpenPolicy`].
+    pub(crate) fn recovery_findings(&self) -> Result<Vec<Issue>> {
+        let report = self.verify("", &VerifyOptions::default())?;
+        Ok(report
+            .issues
+            .into_iter()
+            .filter(|issue| {
+                matches!(
+                    issue.kind,
+                    IssueKind::LeftoverBackup {
+                        original_exists: false
+                    } | IssueKind::DanglingAtomicDir
+                )
+            })
+            .collect())
+    }
+
+    /// See [`crate::OpenPolicy::Recover`].
+    pub(crate) fn recover_on_open(&self) -> Result<()> {
+        self.recover();
+        let mut findings = Vec::new();
+        for issue in self.recovery_findings()? {
+            if issue.kind != IssueKind::DanglingAtomicDir {
+                findings.push(issue);
+                continue;
+            }
+            let gaurd = self.write_file(&issue.path)?;
+            // still dangling now that it is locked
+            if is_atomic_dir_link(&gaurd.path)? && !gaurd.path.is_dir() {
+                log::warn!(path:? = gaurd.ctx.redact.path(&gaurd.path), operation = "recover"; "removing atomic directory whose payload is gone");
+                // does not follow the link, and removes links to directories, which are directories on windows
+                self.inner
+                    .vfs
+                    .remove_dir_all(&gaurd.path)
+                    .at("remove", &gaurd.path)?;
+            }
+        }
+        match findings.is_empty() {
+            true => Ok(()),
+            false => Err(Error::NeedsRecovery { findings }),
+        }
+    }
+}
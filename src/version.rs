@@ -0,0 +1,218 @@
+//! Per-file history. With a [`VersioningPolicy`] installed, a commit that replaces a matching file keeps
+//! the replaced file below `.sbdb/versions/<encoded rpath>/<timestamp>-<id>`, which gc never visits. The
+//! replaced file is hard linked rather than copied, so keeping a version costs a directory entry.
+
+use std::{
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    Client, CowFileGaurd, Error, INTERNAL_DIR, Result, artifact, copy_file, error::IoResultExt,
+    key, path_hidden_with_extension, puuid,
+};
+
+const VERSIONS: &str = "versions";
+
+/// Which files keep a history and how long it is, installed with [`crate::ClientBuilder::versioning`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct VersioningPolicy {
+    /// Number of previous versions kept per file, older ones are removed when a new one is added.
+    pub keep: usize,
+    pub paths: PathMatcher,
+}
+
+impl VersioningPolicy {
+    pub fn new(keep: usize, paths: PathMatcher) -> Self {
+        VersioningPolicy { keep, paths }
+    }
+}
+
+/// Selects files by their path relative to the database root.
+#[derive(Clone)]
+pub struct PathMatcher(Arc<dyn Fn(&Path) -> bool + Send + Sync>);
+
+impl PathMatcher {
+    pub fn new<F: Fn(&Path) -> bool + Send + Sync + 'static>(f: F) -> Self {
+        PathMatcher(Arc::new(f))
+    }
+
+    pub fn all() -> Self {
+        PathMatcher::new(|_| true)
+    }
+
+    /// Matches `prefix` itself and everything below it, comparing whole components.
+    pub fn prefix<P: AsRef<Path>>(prefix: P) -> Self {
+        let prefix = prefix.as_ref().to_path_buf();
+        PathMatcher::new(move |rpath| rpath.starts_with(&prefix))
+    }
+
+    pub fn matches(&self, rpath: &Path) -> bool {
+        (self.0)(rpath)
+    }
+}
+
+impl fmt::Debug for PathMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PathMatcher")
+    }
+}
+
+/// A previous version of a file, see [`Client::versions`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub id: String,
+    /// When the version was replaced.
+    pub created: SystemTime,
+    pub len: u64,
+}
+
+pub(crate) struct Versioning {
+    root: PathBuf,
+    policy: VersioningPolicy,
+}
+
+impl Versioning {
+    pub(crate) fn new(root: PathBuf, policy: VersioningPolicy) -> Self {
+        Versioning { root, policy }
+    }
+
+    /// Keeps the file currently at `orig` as a new version and returns its path, unless the policy does
+    /// not cover it or there is no file to keep. Must be called while `orig` is write locked.
+    pub(crate) fn preserve(&self, orig: &Path) -> Result<Option<PathBuf>> {
+        let Ok(rpath) = orig.strip_prefix(&self.root) else {
+            return Ok(None);
+        };
+        if self.policy.keep == 0
+            || rpath.starts_with(INTERNAL_DIR)
+            || !self.policy.paths.matches(rpath)
+            || !orig.is_file()
+        {
+            return Ok(None);
+        }
+        let dir = version_dir(&self.root, rpath)?;
+        fs::create_dir_all(&dir).at("create directory", &dir)?;
+        let version = dir.join(new_id());
+        fs::hard_link(orig, &version).at("keep version of", orig)?;
+        Ok(Some(version))
+    }
+
+    /// Removes all but the newest versions in `dir`. Failing to do so does not undo the commit that
+    /// added a version, so errors are only logged.
+    pub(crate) fn prune(&self, dir: &Path) {
+        let result = (|| {
+            let mut ids = Vec::new();
+            for entry in fs::read_dir(dir).at("read directory", dir)? {
+                ids.push(entry.at("read directory", dir)?.file_name());
+            }
+            ids.sort();
+            let excess = ids.len().saturating_sub(self.policy.keep);
+            for id in &ids[..excess] {
+                let path = dir.join(id);
+                fs::remove_file(&path).at("remove", &path)?;
+            }
+            Ok::<_, Error>(())
+        })();
+        if let Err(e) = result {
+            log::warn!(path:? = dir, operation = "prune versions"; "failed to prune versions: {}", e);
+        }
+    }
+}
+
+/// Ids start with a fixed width timestamp so that they sort oldest first.
+fn new_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:020}-{}", nanos, puuid())
+}
+
+fn parse_id(id: &str) -> Option<SystemTime> {
+    let (nanos, _) = id.split_once('-')?;
+    let nanos = nanos.parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+fn version_dir(root: &Path, rpath: &Path) -> Result<PathBuf> {
+    let rpath = rpath.components().collect::<PathBuf>();
+    let rpath_str = rpath
+        .to_str()
+        .ok_or_else(|| Error::invalid_path(&rpath, "path is not valid utf-8"))?;
+    Ok(root
+        .join(INTERNAL_DIR)
+        .join(VERSIONS)
+        .join(key::encode(rpath_str)))
+}
+
+impl Client {
+    /// Versions kept of the file at `rpath`, oldest first.
+    pub fn versions<P: AsRef<Path>>(&self, rpath: P) -> Result<Vec<VersionInfo>> {
+        self.redacted(|| {
+            let _gaurd = self.read_file(&rpath)?;
+            let dir = version_dir(&self.inner.root, rpath.as_ref())?;
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(Error::io("read directory", &dir, e)),
+            };
+            let mut result = Vec::new();
+            for entry in entries {
+                let entry = entry.at("read directory", &dir)?;
+                let id = entry.file_name().to_string_lossy().into_owned();
+                let Some(created) = parse_id(&id) else {
+                    continue;
+                };
+                let len = entry.metadata().at("read metadata of", entry.path())?.len();
+                result.push(VersionInfo { id, created, len });
+            }
+            result.sort_by(|a, b| a.id.cmp(&b.id));
+            Ok(result)
+        })
+    }
+
+    /// Contents of a version of the file at `rpath`.
+    pub fn read_version<P: AsRef<Path>>(&self, rpath: P, id: &str) -> Result<Vec<u8>> {
+        self.redacted(|| {
+            let _gaurd = self.read_file(&rpath)?;
+            let path = self.version_path(rpath.as_ref(), id)?;
+            fs::read(&path).at("read version", &path)
+        })
+    }
+
+    /// Replace the file at `rpath` with one of its versions. This is committed like any other write, so
+    /// the contents being replaced are kept as a new version.
+    pub fn restore_version<P: AsRef<Path>>(&self, rpath: P, id: &str) -> Result<()> {
+        self.redacted(|| {
+            let gaurd = self.write_file(&rpath)?;
+            let version = self.version_path(rpath.as_ref(), id)?;
+            if !version.is_file() {
+                return Err(Error::NotFound {
+                    operation: "restore version",
+                    path: version,
+                });
+            }
+            let path = path_hidden_with_extension(&gaurd.path, artifact::TMP)?;
+            gaurd
+                .ctx
+                .copy(|stats| copy_file(&version, &path, &gaurd.ctx, stats))?;
+            CowFileGaurd {
+                path,
+                orig: gaurd.path.clone(),
+                ctx: gaurd.ctx.clone(),
+            }
+            .commit()
+        })
+    }
+
+    fn version_path(&self, rpath: &Path, id: &str) -> Result<PathBuf> {
+        let mut components = Path::new(id).components();
+        if !matches!(components.next(), Some(Component::Normal(_))) || components.next().is_some() {
+            return Err(Error::invalid_path(id, "not a version id"));
+        }
+        Ok(version_dir(&self.inner.root, rpath)?.join(id))
+    }
+}
@@ -0,0 +1,280 @@
+//! The filesystem operations that locking, copying and committing go through, so that tests can inject
+//! failures into them, see `testkit::FaultVfs`. Everything else talks to `std::fs` directly.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+#[cfg(windows)]
+use std::os::windows::prelude::*;
+
+use reflink_copy::{reflink, reflink_or_copy};
+
+use crate::LockMode;
+
+#[cfg(windows)]
+use crate::{
+    FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+    artifact::{self, ArtifactKind},
+    winfs,
+};
+
+/// Filesystem used by a [`crate::Client`], installed with `ClientBuilder::vfs` when the `testkit` feature
+/// is enabled. Implementations other than [`StdVfs`] are expected to wrap it.
+pub trait Vfs: Send + Sync {
+    /// Open or create the lock file at `path` without truncating it. A file that is created gets the
+    /// permissions `mode` if given, regardless of the umask, existing files keep theirs.
+    fn open_lock_file(&self, path: &Path, mode: Option<u32>) -> io::Result<File>;
+
+    /// Block until `file` is locked in `mode`.
+    fn lock(&self, file: &File, mode: LockMode) -> io::Result<()>;
+
+    /// Lock `file` in `mode` if that is possible right away, returning whether it was locked.
+    fn try_lock(&self, file: &File, mode: LockMode) -> io::Result<bool>;
+
+    fn unlock(&self, file: &File) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir>;
+
+    /// Create a symbolic link at `link` pointing to the directory `target`.
+    fn symlink_dir(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    /// Create a symbolic link at `link` pointing to the file `target`.
+    fn symlink_file(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    /// Copy `from` to `to`, returning the number of bytes copied, or `None` if the file was reflinked.
+    fn reflink_or_copy(&self, from: &Path, to: &Path) -> io::Result<Option<u64>>;
+
+    /// Reflink `from` to `to`, which must not exist yet, and fail without copying anything if that is not
+    /// possible.
+    fn reflink(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Copy up to `len` bytes from the current position of `from` to that of `to`, returning how many were
+    /// copied, zero at the end of `from`. Copies are made in such chunks when a deadline is set, see
+    /// `ClientBuilder::io_deadline`.
+    fn copy_chunk(&self, from: &File, to: &File, len: u64) -> io::Result<u64>;
+
+    /// Copy `from` to `to` without attempting to reflink it, returning the number of bytes copied. Like
+    /// [`Vfs::reflink_or_copy`] this fails if `to` already exists.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64>;
+
+    /// Create a hard link at `link` to the file `original`.
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+
+    /// Open a new file without a name in the directory `dir`, for writing. Fails with
+    /// [`io::ErrorKind::Unsupported`] where the platform or filesystem has no such files.
+    fn open_tmpfile(&self, dir: &Path) -> io::Result<File>;
+
+    /// Give a file opened with [`Vfs::open_tmpfile`] the name `path`, which must not exist yet.
+    fn link_tmpfile(&self, file: &File, path: &Path) -> io::Result<()>;
+}
+
+/// The real filesystem.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdVfs;
+
+impl Vfs for StdVfs {
+    fn open_lock_file(&self, path: &Path, mode: Option<u32>) -> io::Result<File> {
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            return open_lock_file_with_mode(path, mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        #[cfg(windows)]
+        options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+        #[cfg(windows)]
+        return winfs::open_unless_pending(path, &options);
+        #[cfg(not(windows))]
+        options.open(path)
+    }
+
+    fn lock(&self, file: &File, mode: LockMode) -> io::Result<()> {
+        match mode {
+            LockMode::Read => file.lock_shared(),
+            LockMode::Write => file.lock(),
+        }
+    }
+
+    fn try_lock(&self, file: &File, mode: LockMode) -> io::Result<bool> {
+        let result = match mode {
+            LockMode::Read => file.try_lock_shared(),
+            LockMode::Write => file.try_lock(),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(fs::TryLockError::WouldBlock) => Ok(false),
+            Err(fs::TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    fn unlock(&self, file: &File) -> io::Result<()> {
+        file.unlock()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        // others keep lock files open, data files only until they are done with them
+        #[cfg(windows)]
+        return match path.file_name().and_then(artifact::parse) {
+            Some((ArtifactKind::Lock | ArtifactKind::Queue, _)) => winfs::remove_lock_file(path),
+            _ => winfs::remove_file(path),
+        };
+        #[cfg(not(windows))]
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        fs::read_dir(path)
+    }
+
+    fn symlink_dir(&self, target: &Path, link: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, link);
+        #[cfg(windows)]
+        return std::os::windows::fs::symlink_dir(target, link);
+    }
+
+    fn symlink_file(&self, target: &Path, link: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, link);
+        #[cfg(windows)]
+        return std::os::windows::fs::symlink_file(target, link);
+    }
+
+    fn reflink_or_copy(&self, from: &Path, to: &Path) -> io::Result<Option<u64>> {
+        reflink_or_copy(from, to)
+    }
+
+    fn reflink(&self, from: &Path, to: &Path) -> io::Result<()> {
+        reflink(from, to)
+    }
+
+    fn copy_chunk(&self, from: &File, mut to: &File, len: u64) -> io::Result<u64> {
+        io::copy(&mut io::Read::take(from, len), &mut to)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let mut src = File::open(from)?;
+        let mut dst = OpenOptions::new().write(true).create_new(true).open(to)?;
+        let bytes = io::copy(&mut src, &mut dst)?;
+        dst.set_permissions(src.metadata()?.permissions())?;
+        Ok(bytes)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_tmpfile(&self, dir: &Path) -> io::Result<File> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .open(dir)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_tmpfile(&self, _dir: &Path) -> io::Result<File> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unnamed files are only supported on linux",
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn link_tmpfile(&self, file: &File, path: &Path) -> io::Result<()> {
+        use std::{
+            ffi::CString,
+            os::{fd::AsRawFd, unix::ffi::OsStrExt},
+        };
+
+        // linking the descriptor itself with AT_EMPTY_PATH needs CAP_DAC_READ_SEARCH, going through procfs
+        // does not
+        let fd = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: both arguments are valid nul terminated strings that outlive the call
+        let result = unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                fd.as_ptr(),
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn link_tmpfile(&self, _file: &File, _path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unnamed files are only supported on linux",
+        ))
+    }
+}
+
+/// Creating the file exclusively tells whether it is new, only then are its permissions set. Setting them
+/// explicitly is what gets around the umask.
+#[cfg(unix)]
+fn open_lock_file_with_mode(path: &Path, mode: u32) -> io::Result<File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(path)
+        {
+            Ok(file) => {
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+                return Ok(file);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        match OpenOptions::new().write(true).open(path) {
+            // removed by gc in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            opened => return opened,
+        }
+    }
+}
+
+/// Shared instance of the real filesystem, so that code without a [`crate::Client`] does not allocate.
+pub(crate) fn std() -> Arc<dyn Vfs> {
+    static STD: OnceLock<Arc<dyn Vfs>> = OnceLock::new();
+    STD.get_or_init(|| Arc::new(StdVfs)).clone()
+}
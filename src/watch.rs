@@ -0,0 +1,271 @@
+//! Notifications of commits made by any process, built on the `notify` crate. Raw filesystem events are
+//! translated into changes of database entries: temporary copies, lock files and other internal files
+//! never show up, and the renames that make up a commit are reported as a single change.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use notify::{
+    EventKind, RecursiveMode, Watcher as _,
+    event::{ModifyKind, RenameMode},
+};
+
+use crate::{
+    Client, INTERNAL_DIR, Result,
+    artifact::{self, ArtifactKind},
+    error::IoResultExt,
+};
+
+/// Changes of the same entry that are closer together than this are reported once.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    /// A file was committed, or an entry was created or written to directly.
+    Modified,
+    /// A directory or atomic directory was committed.
+    DirCommitted,
+    /// A directory commit started, it is followed by [`ChangeKind::DirCommitted`] once it completes.
+    Committing,
+    Removed,
+}
+
+/// Receives the changes below a path, see [`Client::watch`]. Dropping it stops watching.
+pub struct Watcher {
+    events: mpsc::Receiver<(PathBuf, ChangeKind)>,
+    watcher: Arc<Mutex<Option<notify::RecommendedWatcher>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watcher {
+    /// Changes as `(path, kind)`, with paths relative to the database root. Disconnects after
+    /// [`Watcher::stop`].
+    pub fn events(&self) -> &mpsc::Receiver<(PathBuf, ChangeKind)> {
+        &self.events
+    }
+
+    /// Stop watching, delivering the changes that are still being debounced first.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // dropping the watcher disconnects the raw events, which ends the thread
+        self.watcher.lock().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Where to watch and which changes to keep.
+struct Target {
+    root: PathBuf,
+    rpath: PathBuf,
+    recursive: bool,
+}
+
+impl Target {
+    fn path(&self) -> PathBuf {
+        self.root.join(&self.rpath)
+    }
+
+    fn contains(&self, rpath: &Path) -> bool {
+        if self.recursive {
+            rpath.starts_with(&self.rpath)
+        } else {
+            rpath == self.rpath || rpath.parent() == Some(&self.rpath)
+        }
+    }
+
+    /// Translates a raw event into changes of database entries.
+    fn classify(&self, event: &notify::Event) -> Vec<(PathBuf, ChangeKind)> {
+        let mut changes = Vec::new();
+        for path in &event.paths {
+            let Ok(rpath) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+            if rpath.starts_with(INTERNAL_DIR) {
+                continue;
+            }
+            // anything inside temporary copies, backups or atomic dir payloads
+            let Some(parent) = rpath.parent() else {
+                continue;
+            };
+            if parent
+                .iter()
+                .any(|component| artifact::parse(component).is_some())
+            {
+                continue;
+            }
+            let Some(name) = rpath.file_name() else {
+                continue;
+            };
+
+            let change = match artifact::parse(name) {
+                Some((ArtifactKind::Backup, orig)) => match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                        Some((parent.join(orig), ChangeKind::Committing))
+                    }
+                    _ => None,
+                },
+                Some(_) => None,
+                None => {
+                    let committed = if path.is_dir() {
+                        ChangeKind::DirCommitted
+                    } else {
+                        ChangeKind::Modified
+                    };
+                    match event.kind {
+                        // the rename that completes a commit
+                        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => Some(committed),
+                        // backends that do not tell both sides of a rename apart
+                        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+                            Some(if path.exists() {
+                                committed
+                            } else {
+                                ChangeKind::Removed
+                            })
+                        }
+                        EventKind::Create(_)
+                        | EventKind::Modify(ModifyKind::Data(_))
+                        | EventKind::Modify(ModifyKind::Any) => Some(ChangeKind::Modified),
+                        // removals of entries that exist again were superseded by a commit
+                        EventKind::Remove(_) if !path.exists() => Some(ChangeKind::Removed),
+                        _ => None,
+                    }
+                    .map(|kind| (rpath.to_path_buf(), kind))
+                }
+            };
+            if let Some((rpath, kind)) = change
+                && self.contains(&rpath)
+            {
+                changes.push((rpath, kind));
+            }
+        }
+        changes
+    }
+}
+
+fn watch_target(watcher: &mut notify::RecommendedWatcher, target: &Target) -> Result<()> {
+    let path = target.path();
+    if let Some(parent) = path.parent()
+        && !target.rpath.as_os_str().is_empty()
+    {
+        // commits replace the target itself, which is only visible from its parent
+        watcher
+            .watch(parent, RecursiveMode::NonRecursive)
+            .map_err(|e| notify_error(parent, e))?;
+    }
+    if path.is_dir() {
+        let mode = if target.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher
+            .watch(&path, mode)
+            .map_err(|e| notify_error(&path, e))?;
+    }
+    Ok(())
+}
+
+fn notify_error(path: &Path, error: notify::Error) -> crate::Error {
+    let source = match error.kind {
+        notify::ErrorKind::Io(e) => e,
+        kind => std::io::Error::other(format!("{:?}", kind)),
+    };
+    crate::Error::io("watch", path, source)
+}
+
+impl Client {
+    /// Watch `rpath` for changes made by any client, including other processes. With `recursive` set,
+    /// changes anywhere below `rpath` are reported, otherwise only those of `rpath` and its direct
+    /// children.
+    pub fn watch<P: AsRef<Path>>(&self, rpath: P, recursive: bool) -> Result<Watcher> {
+        self.redacted(|| {
+            let target = Target {
+                root: self
+                    .inner
+                    .root
+                    .canonicalize()
+                    .at("resolve", &self.inner.root)?,
+                rpath: rpath.as_ref().components().collect(),
+                recursive,
+            };
+            let (raw_tx, raw_rx) = mpsc::channel();
+            let mut watcher =
+                notify::recommended_watcher(raw_tx).map_err(|e| notify_error(&target.root, e))?;
+            watch_target(&mut watcher, &target)?;
+            let watcher = Arc::new(Mutex::new(Some(watcher)));
+
+            let (tx, events) = mpsc::channel();
+            let thread = {
+                let watcher = watcher.clone();
+                thread::spawn(move || {
+                    let mut pending: Vec<(PathBuf, ChangeKind)> = Vec::new();
+                    let mut oldest = None;
+                    loop {
+                        let timeout = oldest.map_or(Duration::MAX, |oldest: Instant| {
+                            DEBOUNCE.saturating_sub(oldest.elapsed())
+                        });
+                        match raw_rx.recv_timeout(timeout) {
+                            Ok(Ok(event)) => {
+                                for change in target.classify(&event) {
+                                    // a replaced target is a new directory that needs to be watched again
+                                    if change.0 == target.rpath
+                                        && change.1 == ChangeKind::DirCommitted
+                                        && let Some(watcher) = watcher.lock().unwrap().as_mut()
+                                        && let Err(e) = watch_target(watcher, &target)
+                                    {
+                                        log::warn!(path:? = target.path(), operation = "watch"; "failed to watch again: {}", e);
+                                    }
+                                    if !pending.contains(&change) {
+                                        pending.push(change);
+                                    }
+                                }
+                                if !pending.is_empty() {
+                                    oldest.get_or_insert_with(Instant::now);
+                                }
+                            }
+                            Ok(Err(e)) => {
+                                log::warn!(path:? = target.path(), operation = "watch"; "watch error: {}", e);
+                            }
+                            Err(mpsc::RecvTimeoutError::Timeout) => {}
+                            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                                for change in pending.drain(..) {
+                                    let _ = tx.send(change);
+                                }
+                                return;
+                            }
+                        }
+                        if oldest.is_some_and(|oldest| oldest.elapsed() >= DEBOUNCE) {
+                            oldest = None;
+                            for change in pending.drain(..) {
+                                if tx.send(change).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                })
+            };
+
+            Ok(Watcher {
+                events,
+                watcher,
+                thread: Some(thread),
+            })
+        })
+    }
+}
@@ -0,0 +1,165 @@
+//! Removing files on windows, where a file that is open elsewhere is not unlinked right away like on unix.
+//! Lock files are opened with `FILE_SHARE_DELETE` and can be removed with posix semantics, which takes
+//! their name away at once, or else with `FILE_FLAG_DELETE_ON_CLOSE`, which leaves the name "delete
+//! pending" until the last handle is closed. Data files may be open without sharing deletion, which fails
+//! their removal with a sharing violation until they are closed.
+
+use std::{
+    ffi::c_void,
+    fs::{self, File, OpenOptions},
+    io,
+    os::windows::prelude::*,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use crate::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+const DELETE: u32 = 0x00010000;
+const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x04000000;
+const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x00200000;
+/// `FileDispositionInfoEx` of `FILE_INFO_BY_HANDLE_CLASS`.
+const FILE_DISPOSITION_INFO_EX: i32 = 21;
+const FILE_DISPOSITION_FLAG_DELETE: u32 = 0x00000001;
+const FILE_DISPOSITION_FLAG_POSIX_SEMANTICS: u32 = 0x00000002;
+const FILE_DISPOSITION_FLAG_IGNORE_READONLY_ATTRIBUTE: u32 = 0x00000010;
+const ERROR_ACCESS_DENIED: i32 = 5;
+const ERROR_SHARING_VIOLATION: i32 = 32;
+const ERROR_NOT_SUPPORTED: i32 = 50;
+const ERROR_INVALID_PARAMETER: i32 = 87;
+const ERROR_DELETE_PENDING: i32 = 303;
+const STATUS_DELETE_PENDING: i32 = 0xC0000056_u32 as i32;
+
+/// Attempts to remove a file that is open elsewhere, or to open a name that is delete pending, and the wait
+/// before the first retry, which doubles with every further one.
+const ATTEMPTS: u32 = 6;
+const BACKOFF: Duration = Duration::from_millis(10);
+
+#[repr(C)]
+struct FileDispositionInfoEx {
+    flags: u32,
+}
+
+#[link(name = "kernel32")]
+unsafe extern "system" {
+    fn SetFileInformationByHandle(
+        file: *mut c_void,
+        class: i32,
+        info: *const c_void,
+        size: u32,
+    ) -> i32;
+}
+
+#[link(name = "ntdll")]
+unsafe extern "system" {
+    fn RtlGetLastNtStatus() -> i32;
+}
+
+/// Remove the lock file at `path`, which other processes may hold open. Where the filesystem has no posix
+/// semantics, e.g. FAT or windows before 10 1607, the name stays delete pending until they close it.
+pub(crate) fn remove_lock_file(path: &Path) -> io::Result<()> {
+    let file = open(path, DELETE, FILE_FLAG_OPEN_REPARSE_POINT)?;
+    match posix_delete(&file) {
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(ERROR_INVALID_PARAMETER | ERROR_NOT_SUPPORTED)
+            ) =>
+        {
+            drop(file);
+            open(
+                path,
+                DELETE,
+                FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_DELETE_ON_CLOSE,
+            )
+            .map(drop)
+        }
+        result => result,
+    }
+}
+
+/// Remove the file at `path`, trying again for a while if it is open elsewhere without sharing deletion.
+pub(crate) fn remove_file(path: &Path) -> io::Result<()> {
+    retry(
+        || fs::remove_file(path),
+        |e| e.raw_os_error() == Some(ERROR_SHARING_VIOLATION),
+    )
+}
+
+/// Open `path` with `options`, waiting for a while if its name is delete pending, since it can neither be
+/// opened nor created again until the last handle to the removed file is closed.
+pub(crate) fn open_unless_pending(path: &Path, options: &OpenOptions) -> io::Result<File> {
+    retry(
+        || options.open(path),
+        |e| e.raw_os_error() == Some(ERROR_ACCESS_DENIED) && is_delete_pending(path),
+    )
+}
+
+/// Whether the name `path` belongs to a file that was removed while it was open, which is listed until
+/// the last handle is closed but can not be opened or read. Such a name is as good as gone.
+pub(crate) fn is_delete_pending(path: &Path) -> bool {
+    match open(
+        path,
+        0,
+        FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS,
+    ) {
+        Ok(_) => false,
+        Err(e) => match e.raw_os_error() {
+            Some(ERROR_DELETE_PENDING) => true,
+            Some(ERROR_ACCESS_DENIED) => {
+                // SAFETY: only reads the status of the calling thread's last call, the failed open
+                let status = unsafe { RtlGetLastNtStatus() };
+                status == STATUS_DELETE_PENDING
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Call `f` until it does not fail with an error that is `transient`, or the attempts are used up.
+fn retry<T>(
+    mut f: impl FnMut() -> io::Result<T>,
+    transient: impl Fn(&io::Error) -> bool,
+) -> io::Result<T> {
+    let mut backoff = BACKOFF;
+    for _ in 1..ATTEMPTS {
+        match f() {
+            Err(e) if transient(&e) => thread::sleep(backoff),
+            result => return result,
+        }
+        backoff *= 2;
+    }
+    f()
+}
+
+fn open(path: &Path, access: u32, flags: u32) -> io::Result<File> {
+    OpenOptions::new()
+        .access_mode(access)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+        .custom_flags(flags)
+        .open(path)
+}
+
+/// Unlink the name of `file` right away, even though it is open elsewhere.
+fn posix_delete(file: &File) -> io::Result<()> {
+    let info = FileDispositionInfoEx {
+        flags: FILE_DISPOSITION_FLAG_DELETE
+            | FILE_DISPOSITION_FLAG_POSIX_SEMANTICS
+            | FILE_DISPOSITION_FLAG_IGNORE_READONLY_ATTRIBUTE,
+    };
+    // SAFETY: the handle is open for the duration of the call and `info` is the structure of the class
+    let set = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FILE_DISPOSITION_INFO_EX,
+            (&raw const info).cast(),
+            size_of::<FileDispositionInfoEx>() as u32,
+        )
+    };
+    match set {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,80 @@
+//! Names downstream code imports. Types and functions that moved into modules of their own must still be
+//! found at the root of the crate, and the prelude must bring in enough to use a database on its own. Run
+//! with `cargo test --features testkit`.
+
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+    time::Duration,
+};
+
+#[test]
+fn root_paths_resolve() {
+    use sbdb::{
+        AtomicCowOptions, AtomicDirCreation, Client, ClientBuilder, CowAtomicDirGaurd, CowDirGaurd,
+        CowFileGaurd, DirReadGaurd, DirWriteGaurd, FileReadGaurd, FileWriteGaurd, GcHandle,
+        GcOptions, GcReport, Lock, LockStatus, ReadLock, Tx, TxBuilder, TxEntry, TxEntryKind,
+        TxStats, WriteLock, create_backup_ext, dir_cow, dir_cow_atomic, file_cow,
+        open_lock_and_queue, open_lock_file,
+    };
+
+    let _: fn(PathBuf) -> sbdb::Result<CowFileGaurd> = file_cow::<PathBuf>;
+    let _: fn(PathBuf) -> sbdb::Result<CowDirGaurd> = dir_cow::<PathBuf>;
+    let _: fn(PathBuf) -> sbdb::Result<CowAtomicDirGaurd> = dir_cow_atomic::<PathBuf>;
+    let _: fn(PathBuf) -> sbdb::Result<File> = open_lock_file::<PathBuf>;
+    let _: fn(PathBuf) -> sbdb::Result<(File, File)> = open_lock_and_queue::<PathBuf>;
+    let _: fn(PathBuf) -> sbdb::Result<LockStatus> = Lock::probe::<PathBuf>;
+    let _: fn(&Client, PathBuf) -> sbdb::Result<FileReadGaurd> = Client::read_file::<PathBuf>;
+    let _: fn(&Client, PathBuf) -> sbdb::Result<FileWriteGaurd> = Client::write_file::<PathBuf>;
+    let _: fn(&Client, PathBuf) -> sbdb::Result<DirReadGaurd> = Client::read_dir::<PathBuf>;
+    let _: fn(&Client, PathBuf) -> sbdb::Result<DirWriteGaurd> = Client::write_dir::<PathBuf>;
+    let _: fn(&Client, &GcOptions) -> GcReport = Client::gc_with;
+    let _: fn(&Client, Duration, GcOptions) -> GcHandle = Client::spawn_gc;
+    let _: fn(PathBuf) -> ClientBuilder = Client::builder::<PathBuf>;
+    let _: fn(TxBuilder) -> sbdb::Result<Tx> = TxBuilder::begin;
+    let _: fn(&Tx) -> &TxStats = Tx::stats;
+    let _: fn(Lock) -> sbdb::Result<()> = Lock::release;
+    let _: fn(ReadLock) -> sbdb::Result<()> = ReadLock::release;
+    let _: fn(WriteLock) -> sbdb::Result<()> = WriteLock::release;
+    let _: fn(Vec<TxEntry>, TxEntryKind, AtomicDirCreation, AtomicCowOptions) = |_, _, _, _| ();
+    assert!(create_backup_ext().starts_with('.'));
+}
+
+#[test]
+fn prelude_is_enough() -> anyhow::Result<()> {
+    use sbdb::prelude::*;
+
+    let test = sbdb::testkit::TestClient::new("api-paths")?;
+    let root = &test.root;
+    let db: Client = ClientBuilder::new(root).build()?;
+    fs::create_dir(root.join("dir"))?;
+    fs::write(root.join("file"), "0")?;
+
+    let gaurd: FileWriteGaurd = db.write_file("file")?;
+    let cow: CowFileGaurd = gaurd.cow()?;
+    fs::write(&cow.path, "1")?;
+    cow.commit()?;
+    gaurd.release()?;
+
+    let tx: Tx = db.tx().read("file").write("dir").begin()?;
+    let read: FileReadGaurd = tx.read_file("file")?;
+    let cow: CowDirGaurd = tx.dir_cow("dir")?;
+    fs::write(cow.path.join("copy"), fs::read(&read.path)?)?;
+    cow.commit()?;
+    drop(read);
+    tx.release()?;
+    assert_eq!("1", fs::read_to_string(root.join("dir/copy"))?);
+
+    // options can only be built from their defaults outside of the crate, so that fields can be added
+    let mut options = GcOptions::default();
+    options.dry_run = true;
+    let report: GcReport = db.gc_with(&options);
+    assert_eq!(0, report.errors);
+    let mut options = VerifyOptions::default();
+    options.min_age = Duration::ZERO;
+    assert!(db.verify("", &options)?.issues.is_empty());
+
+    let err: Error = db.read_file("../outside").err().expect("escapes the root");
+    let _: Result<()> = Err(err);
+    Ok(())
+}
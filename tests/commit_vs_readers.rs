@@ -0,0 +1,98 @@
+//! Directory commits racing readers of the files below them. A commit moves the directory aside and its copy
+//! into place, so for a moment there is nothing at its name. Readers lock every ancestor of what they read,
+//! the directory included, and a commit made through [`sbdb::Client::write_dir`] holds that directory's
+//! write lock throughout, so a reader must never find the file missing or half written. Run with
+//! `cargo test --features testkit`.
+
+use std::{
+    fs,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use sbdb::{Client, testkit::TestClient};
+
+const COMMITTERS: usize = 2;
+const COMMITS: usize = 150;
+const READERS: usize = 6;
+
+/// Contents of the file after the commit `n`, long enough that a partial write would show.
+fn contents(n: usize) -> String {
+    format!("{n:08}\n").repeat(512)
+}
+
+/// Checks that `read` is one of the [`contents`] in full.
+fn check(read: &str) {
+    let first = read.get(..9).expect("contents are never empty");
+    assert_eq!(contents(0).len(), read.len(), "partial contents");
+    assert_eq!(first.repeat(512), read, "mixed contents");
+}
+
+#[test]
+fn readers_never_see_a_committed_dir_missing() -> anyhow::Result<()> {
+    let test = TestClient::new("commit-vs-readers")?;
+    let (db, root) = (test.client.clone(), &test.root);
+    fs::create_dir_all(root.join("dir/a"))?;
+    fs::write(root.join("dir/a/b.txt"), contents(0))?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicUsize::new(0));
+    let readers = (0..READERS)
+        .map(|i| {
+            // some readers share the committers' client, the others lock through their own, which may keep
+            // the lock files it opened
+            let db = match i % 3 {
+                0 => db.clone(),
+                1 => Client::new(root)?,
+                _ => Client::builder(root).lock_file_cache(16).build()?,
+            };
+            let (done, reads) = (done.clone(), reads.clone());
+            Ok(thread::spawn(move || -> anyhow::Result<()> {
+                while !done.load(Ordering::Relaxed) {
+                    let gaurd = db.read_file("dir/a/b.txt")?;
+                    check(&fs::read_to_string(&gaurd.path)?);
+                    gaurd.release()?;
+                    let gaurd = db.read_dir("dir")?;
+                    let inner = gaurd.read_file("a/b.txt")?;
+                    check(&fs::read_to_string(&inner.path)?);
+                    inner.release()?;
+                    gaurd.release()?;
+                    reads.fetch_add(2, Ordering::Relaxed);
+                }
+                Ok(())
+            }))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let committers = (0..COMMITTERS)
+        .map(|i| {
+            let db = db.clone();
+            thread::spawn(move || -> anyhow::Result<()> {
+                for n in 0..COMMITS {
+                    let gaurd = db.write_dir("dir")?;
+                    let cow = gaurd.cow()?;
+                    fs::write(cow.path.join("a/b.txt"), contents(n * COMMITTERS + i))?;
+                    cow.commit()?;
+                    gaurd.release()?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let committed = committers
+        .into_iter()
+        .map(|committer| committer.join().expect("committer panicked"))
+        .collect::<anyhow::Result<Vec<_>>>();
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().expect("reader panicked")?;
+    }
+    committed?;
+    assert!(reads.load(Ordering::Relaxed) > 0);
+    check(&fs::read_to_string(root.join("dir/a/b.txt"))?);
+    Ok(())
+}
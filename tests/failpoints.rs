@@ -0,0 +1,272 @@
+//! Crash consistency of commits. Every scenario spawns a copy of this binary that commits one entry and
+//! fails at a fail point on the way, then recovers the database in the parent and checks that the entry
+//! holds either its old or its new contents. Run with `cargo test --features failpoints`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use sbdb::{
+    Client,
+    failpoint::{self, Action},
+    puuid,
+};
+
+const ROOT_ENV: &str = "SBDB_FAILPOINTS_TEST_ROOT";
+const TARGET_ENV: &str = "SBDB_FAILPOINTS_TEST_TARGET";
+/// Exit code of a child whose commit returned an error.
+const EXIT_FAILED: i32 = 3;
+
+const FILES: usize = 4;
+const OLD: &str = "old";
+const NEW: &str = "new";
+
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    File,
+    Dir,
+    AtomicDir,
+}
+
+impl Target {
+    const ALL: [Target; 3] = [Target::File, Target::Dir, Target::AtomicDir];
+
+    fn name(self) -> &'static str {
+        match self {
+            Target::File => "file",
+            Target::Dir => "dir",
+            Target::AtomicDir => "atomic",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|target| target.name() == s)
+    }
+
+    /// Replaces the contents of the target with [`NEW`].
+    fn commit(self, client: &Client) -> sbdb::Result<()> {
+        match self {
+            Target::File => {
+                let gaurd = client.write_file(self.name())?;
+                let copy = gaurd.cow()?;
+                fs::write(&copy.path, NEW).unwrap();
+                copy.commit()
+            }
+            Target::Dir => {
+                let gaurd = client.write_dir(self.name())?;
+                let copy = gaurd.cow()?;
+                write_files(&copy.path, NEW);
+                copy.commit()
+            }
+            Target::AtomicDir => {
+                let gaurd = client.write_dir(self.name())?;
+                let copy = gaurd.cow_atomic()?;
+                write_files(&copy.path, NEW);
+                copy.commit()
+            }
+        }
+    }
+
+    /// Contents of the target, which must be the same in every one of its files.
+    fn contents(self, root: &Path) -> String {
+        let path = root.join(self.name());
+        match self {
+            Target::File => fs::read_to_string(path).unwrap(),
+            Target::Dir | Target::AtomicDir => {
+                let contents = (0..FILES)
+                    .map(|i| fs::read_to_string(path.join(i.to_string())).unwrap())
+                    .collect::<Vec<_>>();
+                assert!(
+                    contents.iter().all(|c| *c == contents[0]),
+                    "{:?} has mixed contents {:?}",
+                    self,
+                    contents
+                );
+                contents[0].clone()
+            }
+        }
+    }
+}
+
+fn write_files(dir: &Path, contents: &str) {
+    for i in 0..FILES {
+        fs::write(dir.join(i.to_string()), contents).unwrap();
+    }
+}
+
+/// Entry point of the child processes, does nothing when run as a regular test.
+#[test]
+fn child() {
+    let (Ok(root), Some(target)) = (
+        std::env::var(ROOT_ENV),
+        std::env::var(TARGET_ENV)
+            .ok()
+            .and_then(|t| Target::parse(&t)),
+    ) else {
+        return;
+    };
+    let client = Client::new(root).unwrap();
+    if let Err(e) = target.commit(&client) {
+        // any other error panics, which the parent tells apart by the exit code
+        assert!(injected(&e), "commit failed: {e}");
+        std::process::exit(EXIT_FAILED);
+    }
+}
+
+/// Whether `e` is the error of a fail point, possibly wrapped by the commit it failed.
+fn injected(e: &sbdb::Error) -> bool {
+    match e {
+        sbdb::Error::Io {
+            operation: "fail point",
+            ..
+        } => true,
+        sbdb::Error::CommitFailed { source, .. } => injected(source),
+        _ => false,
+    }
+}
+
+struct TestRoot(PathBuf);
+
+impl TestRoot {
+    /// A database holding every target with [`OLD`] contents.
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("{}-{}", name, puuid()));
+        fs::create_dir_all(root.join(Target::Dir.name())).unwrap();
+        write_files(&root.join(Target::Dir.name()), OLD);
+        fs::write(root.join(Target::File.name()), OLD).unwrap();
+        let client = Client::new(&root).unwrap();
+        client
+            .write_dir("")
+            .unwrap()
+            .create_dir_atomic(Target::AtomicDir.name(), false)
+            .unwrap();
+        let copy = client
+            .write_dir(Target::AtomicDir.name())
+            .unwrap()
+            .cow_atomic()
+            .unwrap();
+        write_files(&copy.path, OLD);
+        copy.commit().unwrap();
+        TestRoot(root)
+    }
+}
+
+impl Drop for TestRoot {
+    fn drop(&mut self) {
+        let removed = fs::remove_dir_all(&self.0);
+        // a failing test keeps its own panic rather than aborting with a second one
+        if !std::thread::panicking() {
+            removed.expect("failed to delete test db");
+        }
+    }
+}
+
+/// Commits `target` in a child process that takes `action` at the fail point `point`.
+fn run_child(root: &Path, target: Target, point: Option<(&str, Action)>) -> ExitStatus {
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command
+        .args(["child", "--exact", "--quiet"])
+        .env(ROOT_ENV, root)
+        .env(TARGET_ENV, target.name())
+        .stdout(Stdio::null());
+    if let Some((point, action)) = point {
+        let action = match action {
+            Action::Error => "error",
+            Action::Abort => "abort",
+        };
+        command.env(failpoint::FAILPOINTS_ENV, format!("{}={}", point, action));
+    }
+    command.status().unwrap()
+}
+
+/// Fails the commit of `target` at `point` and checks that recovery restores the old contents.
+fn crash_and_recover(name: &str, target: Target, point: &str, action: Action) -> sbdb::GcReport {
+    let root = TestRoot::new(name);
+    let status = run_child(&root.0, target, Some((point, action)));
+    match action {
+        Action::Error => assert_eq!(Some(EXIT_FAILED), status.code(), "{}", status),
+        Action::Abort => assert!(!status.success(), "{}", status),
+    }
+
+    let client = Client::new(&root.0).unwrap();
+    let report = client.recover();
+    assert_eq!(0, report.errors);
+    // every fail point comes before the commit takes effect
+    for target in Target::ALL {
+        assert_eq!(OLD, target.contents(&root.0));
+    }
+    let verify = client.verify("", &Default::default()).unwrap();
+    assert!(verify.issues.is_empty(), "{:#?}", verify.issues);
+    report
+}
+
+#[test]
+fn children_commit_without_fail_points() {
+    let root = TestRoot::new("failpoints_none");
+    for target in Target::ALL {
+        assert!(run_child(&root.0, target, None).success());
+        assert_eq!(NEW, target.contents(&root.0));
+    }
+}
+
+#[test]
+fn file_commit_before_rename() {
+    for action in [Action::Error, Action::Abort] {
+        let report = crash_and_recover(
+            "failpoints_file",
+            Target::File,
+            failpoint::FILE_COW_COMMIT_BEFORE_RENAME,
+            action,
+        );
+        assert_eq!(1, report.temps_removed);
+    }
+}
+
+#[test]
+fn dir_commit_after_backup_rename() {
+    for action in [Action::Error, Action::Abort] {
+        let report = crash_and_recover(
+            "failpoints_dir",
+            Target::Dir,
+            failpoint::COW_DIR_COMMIT_AFTER_BAK_RENAME,
+            action,
+        );
+        assert_eq!(1, report.backups_restored);
+        assert_eq!(1, report.temps_removed);
+    }
+}
+
+#[test]
+fn atomic_commit_after_temporary_link() {
+    for action in [Action::Error, Action::Abort] {
+        let report = crash_and_recover(
+            "failpoints_atomic",
+            Target::AtomicDir,
+            failpoint::COW_ATOMIC_COMMIT_AFTER_TMPLNK,
+            action,
+        );
+        assert_eq!(1, report.temps_removed);
+        assert_eq!(1, report.payloads_removed);
+    }
+}
+
+#[test]
+fn gc_alone_leaves_interrupted_dir_commit() {
+    let root = TestRoot::new("failpoints_gc");
+    let status = run_child(
+        &root.0,
+        Target::Dir,
+        Some((failpoint::COW_DIR_COMMIT_AFTER_BAK_RENAME, Action::Abort)),
+    );
+    assert!(!status.success());
+
+    let client = Client::new(&root.0).unwrap();
+    assert!(!root.0.join(Target::Dir.name()).exists());
+    // plain gc never touches the backup, so the directory stays missing until it is recovered
+    assert_eq!(0, client.gc().backups_restored);
+    assert!(!root.0.join(Target::Dir.name()).exists());
+    assert_eq!(1, client.recover().backups_restored);
+    assert_eq!(OLD, Target::Dir.contents(&root.0));
+}
@@ -0,0 +1,185 @@
+//! Lock correctness across processes. Every scenario spawns copies of this binary that run a single
+//! worker, selected by the environment variables set by [`Worker::command`]. Run with
+//! `cargo test --features testkit`.
+
+use std::{fs, path::Path, process::Stdio, time::Duration};
+
+use sbdb::{
+    LockBackend,
+    testkit::{self, Outcome, PausePoint, Role, TestClient, Worker},
+};
+
+/// Entry point of the worker processes, does nothing when run as a regular test.
+#[test]
+fn worker() {
+    if let Some(worker) = Worker::from_env() {
+        worker.run().unwrap();
+    }
+}
+
+/// A database laid out for the workers by [`testkit::prepare`].
+fn test_client(name: &str) -> TestClient {
+    let test = TestClient::new(name).unwrap();
+    testkit::prepare(&test.root).unwrap();
+    test
+}
+
+/// Runs one process per role and collects what they observed.
+fn run(root: &Path, roles: &[Role], iterations: u32, pauses: &[(PausePoint, Duration)]) -> Outcome {
+    run_with(root, roles, iterations, pauses, LockBackend::Flock)
+}
+
+/// Same as [`run`], with every worker locking through `lock_backend`.
+fn run_with(
+    root: &Path,
+    roles: &[Role],
+    iterations: u32,
+    pauses: &[(PausePoint, Duration)],
+    lock_backend: LockBackend,
+) -> Outcome {
+    let exe = std::env::current_exe().unwrap();
+    let outputs = fs::canonicalize(root).unwrap().with_extension("outputs");
+    fs::create_dir_all(&outputs).unwrap();
+
+    let children = roles
+        .iter()
+        .enumerate()
+        .map(|(i, role)| {
+            let worker = Worker {
+                role: *role,
+                root: root.to_path_buf(),
+                output: outputs.join(i.to_string()),
+                iterations,
+                pauses: pauses.to_vec(),
+                lock_backend,
+            };
+            let child = worker
+                .command(&exe)
+                .args(["worker", "--exact", "--quiet"])
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap();
+            (worker, child)
+        })
+        .collect::<Vec<_>>();
+
+    let mut outcome = Outcome::default();
+    for (worker, mut child) in children {
+        let status = child.wait().unwrap();
+        assert!(
+            status.success(),
+            "{:?} worker exited with {}",
+            worker.role,
+            status
+        );
+        outcome.merge(Outcome::load(&worker.output).unwrap());
+    }
+    fs::remove_dir_all(&outputs).unwrap();
+    outcome
+}
+
+fn assert_correct(outcome: &Outcome) {
+    assert!(outcome.anomalies.is_empty(), "{:#?}", outcome.anomalies);
+    let violations = outcome.violations();
+    assert!(violations.is_empty(), "{:#?}", violations);
+}
+
+fn read_value(root: &Path, rpath: &str) -> u64 {
+    fs::read_to_string(root.join(rpath))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+/// Readers and writers of two files, checked for overlaps and lost increments.
+fn exclusion(name: &str, lock_backend: LockBackend) {
+    let test = test_client(name);
+    let iterations = 30;
+    let outcome = run_with(
+        &test.root,
+        &[
+            Role::Reader,
+            Role::Reader,
+            Role::Writer,
+            Role::Writer,
+            Role::TxWriter,
+        ],
+        iterations,
+        &[(PausePoint::LockAcquired, Duration::from_millis(1))],
+        lock_backend,
+    );
+    assert_correct(&outcome);
+    assert!(outcome.observations.len() >= 5 * iterations as usize);
+
+    // every increment survived, so no two writers worked from the same value. Writers increment both
+    // files in every third iteration and a single one otherwise.
+    let per_writer = (0..iterations)
+        .map(|i| if i % 3 == 2 { 2 } else { 1 })
+        .sum::<u64>();
+    let per_tx_writer = 2 * iterations as u64;
+    assert_eq!(
+        2 * per_writer + per_tx_writer,
+        read_value(&test.root, "files/a") + read_value(&test.root, "files/b")
+    );
+}
+
+#[test]
+fn readers_and_writers_exclude_each_other() {
+    exclusion("multiprocess_exclusion", LockBackend::Flock);
+}
+
+#[test]
+fn lock_dirs_exclude_each_other() {
+    exclusion("multiprocess_lock_dirs", LockBackend::atomic_lock_dir());
+}
+
+#[test]
+fn dir_commit_races_readers() {
+    let test = test_client("multiprocess_dir_commit");
+    let iterations = 20;
+    let outcome = run(
+        &test.root,
+        &[
+            Role::DirReader,
+            Role::DirReader,
+            Role::DirReader,
+            Role::DirCommitter,
+            Role::DirCommitter,
+        ],
+        iterations,
+        // widen the window in which the directory does not exist
+        &[(PausePoint::DirCommit, Duration::from_millis(5))],
+    );
+    assert_correct(&outcome);
+    assert_eq!(2 * iterations as u64, read_value(&test.root, "dir/0"));
+}
+
+#[test]
+fn gc_races_committers() {
+    let test = test_client("multiprocess_gc");
+    let iterations = 20;
+    let outcome = run(
+        &test.root,
+        &[
+            Role::Gc,
+            Role::Gc,
+            Role::Writer,
+            Role::DirCommitter,
+            Role::DirReader,
+        ],
+        iterations,
+        &[
+            (PausePoint::GcRemove, Duration::from_millis(2)),
+            (PausePoint::FileCommit, Duration::from_millis(2)),
+            (PausePoint::DirCommit, Duration::from_millis(2)),
+        ],
+    );
+    assert_correct(&outcome);
+    assert_eq!(iterations as u64, read_value(&test.root, "dir/0"));
+
+    // with every committer gone, gc removes everything that was left behind
+    let client = &test.client;
+    client.gc();
+    let report = client.verify("", &Default::default()).unwrap();
+    assert!(report.issues.is_empty(), "{:#?}", report.issues);
+}
@@ -0,0 +1,80 @@
+//! Directories removed with [`sbdb::Client::remove_prefix`] while readers list them. The directory is renamed
+//! away while its parent is write locked, and readers lock every ancestor of what they read, so a reader
+//! must find either all of its entries or none of them. Run with `cargo test --features testkit`.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use sbdb::{Error, testkit::TestClient};
+
+const ROUNDS: usize = 100;
+const FILES: usize = 50;
+const READERS: usize = 4;
+
+#[test]
+fn readers_see_a_removed_dir_whole_or_not_at_all() -> anyhow::Result<()> {
+    let test = TestClient::new("remove-vs-readers")?;
+    let (db, root) = (test.client.clone(), &test.root);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let (empty, full) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let readers = (0..READERS)
+        .map(|_| {
+            let db = db.clone();
+            let (done, empty, full) = (done.clone(), empty.clone(), full.clone());
+            thread::spawn(move || -> anyhow::Result<()> {
+                while !done.load(Ordering::Relaxed) {
+                    let entries = match db.read_dir("sessions") {
+                        Ok(gaurd) => match gaurd.entry_count() {
+                            Err(Error::NotFound { .. }) => 0,
+                            count => count?,
+                        },
+                        Err(Error::NotFound { .. }) => 0,
+                        Err(e) => return Err(e.into()),
+                    };
+                    match entries {
+                        0 => empty.fetch_add(1, Ordering::Relaxed),
+                        FILES => full.fetch_add(1, Ordering::Relaxed),
+                        _ => anyhow::bail!("saw {entries} of {FILES} entries"),
+                    };
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let removed = (|| -> anyhow::Result<()> {
+        for _ in 0..ROUNDS {
+            // the directory is filled out of sight, and moved into place while the root is write locked
+            let staging = root.join("staging");
+            fs::create_dir(&staging)?;
+            for i in 0..FILES {
+                fs::write(staging.join(format!("{i:03}")), "")?;
+            }
+            let gaurd = db.write_dir("")?;
+            fs::rename(&staging, root.join("sessions"))?;
+            gaurd.release()?;
+
+            let report = db.remove_prefix("sessions")?;
+            assert!(report.failed.is_empty(), "{:?}", report.failed);
+            assert_eq!(vec![PathBuf::from("sessions")], report.removed);
+        }
+        Ok(())
+    })();
+
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().expect("reader panicked")?;
+    }
+    removed?;
+    assert!(empty.load(Ordering::Relaxed) + full.load(Ordering::Relaxed) > 0);
+    assert!(!root.join("sessions").exists());
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Memory use of streaming reads. Every allocation of this binary is counted, so that reading a file far
+//! larger than the stream buffer can be checked to never hold more than a buffer's worth of it. Run with
+//! `cargo test --features testkit`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs::File,
+    io::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use sbdb::{Client, testkit::TestClient};
+
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Largest amount of memory allocated at once while running `f`, beyond what was allocated before.
+fn peak_allocated<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let result = f();
+    (result, PEAK.load(Ordering::Relaxed).saturating_sub(before))
+}
+
+/// Discards what is written to it, counting the bytes.
+#[derive(Default)]
+struct CountingSink(u64);
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const BUFFER_LEN: usize = 16 << 10;
+
+/// Allowance for what the client allocates besides the buffer, such as paths and lock files.
+const OVERHEAD: usize = 256 << 10;
+
+// a single test, so that no other test allocates while memory is counted
+#[test]
+fn streaming_is_bounded() -> anyhow::Result<()> {
+    let test = TestClient::new("streaming")?;
+    let db = Client::builder(&test.root)
+        .stream_buffer_len(BUFFER_LEN)
+        .build()?;
+
+    // sparse, so that it takes no space on disk
+    const LEN: u64 = 1 << 30;
+    File::create(test.root.join("huge"))?.set_len(LEN)?;
+    let mut sink = CountingSink::default();
+    let (copied, peak) = peak_allocated(|| db.copy_to("huge", &mut sink));
+    assert_eq!(LEN, copied?);
+    assert_eq!(LEN, sink.0);
+    assert!(peak < BUFFER_LEN + OVERHEAD, "peak of {peak} bytes");
+
+    let gaurd = db.read_file("huge")?;
+    let mut reader = gaurd.reader()?;
+    let (copied, peak) = peak_allocated(|| io::copy(&mut reader, &mut io::sink()));
+    assert_eq!(LEN, copied?);
+    assert!(peak < BUFFER_LEN + OVERHEAD, "peak of {peak} bytes");
+    drop(reader);
+    gaurd.release()?;
+
+    #[cfg(feature = "binary")]
+    {
+        const VALUE_LEN: usize = 32 << 20;
+        let records = db.collection::<Vec<u8>, _>("records");
+        records.put("a", &vec![7; VALUE_LEN])?;
+        records.put("b", &vec![8; VALUE_LEN])?;
+        let (read, peak) = peak_allocated(|| -> sbdb::Result<Vec<(String, u64)>> {
+            let mut read = Vec::new();
+            for record in records.iter_streaming()? {
+                let (key, mut reader) = record?;
+                let len = io::copy(&mut reader, &mut io::sink()).expect("read record");
+                read.push((key, len));
+            }
+            Ok(read)
+        });
+        // the length prefix of postcard's encoding of the bytes
+        let encoded = VALUE_LEN as u64 + 4;
+        assert_eq!(
+            vec![("a".to_string(), encoded), ("b".to_string(), encoded)],
+            read?
+        );
+        assert!(peak < BUFFER_LEN + OVERHEAD, "peak of {peak} bytes");
+    }
+    Ok(())
+}